@@ -3,7 +3,8 @@ mod opts;
 
 use crate::opts::Opts;
 
-use etk_analyze::cfg::ControlFlowGraph;
+use etk_analyze::cache::{self, Cache};
+use etk_analyze::cfg::{ControlFlowGraph, STACK_LIMIT};
 
 use etk_asm::disasm::Disassembler;
 
@@ -12,10 +13,12 @@ use etk_cli::errors::WithSources;
 use etk_dasm::blocks::basic::Separator;
 use etk_dasm::blocks::AnnotatedBlock;
 
+use serde::{Deserialize, Serialize};
+
 use snafu::{Backtrace, Snafu};
 
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 
 #[derive(Debug, Snafu)]
 enum Error {
@@ -24,6 +27,58 @@ enum Error {
         source: std::io::Error,
         backtrace: Backtrace,
     },
+
+    #[snafu(context(false))]
+    Cache { source: cache::Error },
+}
+
+/// Everything `ecfg` prints about a program, stored in the cache.
+#[derive(Debug, Serialize, Deserialize)]
+struct Analysis {
+    graph: String,
+    stack_exceeded: bool,
+    loops: Vec<String>,
+}
+
+impl Analysis {
+    fn new(code: &[u8]) -> Result<Self, Error> {
+        let mut disasm = Disassembler::new();
+        disasm.write_all(code)?;
+
+        let mut separator = Separator::new();
+
+        separator.push_all(disasm.ops());
+
+        let blocks = separator
+            .take()
+            .into_iter()
+            .chain(separator.finish())
+            .map(|x| AnnotatedBlock::annotate(&x));
+
+        let mut cfg = ControlFlowGraph::new(blocks);
+        cfg.refine_shallow();
+
+        let loops = cfg
+            .loops()
+            .into_iter()
+            .map(|found| {
+                let body: Vec<_> = found.body.iter().map(|o| format!("0x{:x}", o)).collect();
+                let latches: Vec<_> = found.latches.iter().map(|o| format!("0x{:x}", o)).collect();
+                format!(
+                    "loop at 0x{:x}: blocks {} (back edges from {})",
+                    found.header,
+                    body.join(", "),
+                    latches.join(", ")
+                )
+            })
+            .collect();
+
+        Ok(Self {
+            graph: cfg.render().to_string(),
+            stack_exceeded: cfg.max_stack_height().is_exceeded(),
+            loops,
+        })
+    }
 }
 
 fn main() {
@@ -42,29 +97,43 @@ fn run() -> Result<(), Error> {
     let opts: Opts = clap::Parser::parse();
 
     let mut input = opts.src.open()?;
-    let mut disasm = Disassembler::new();
-
-    std::io::copy(&mut input, &mut disasm)?;
+    let mut code = Vec::new();
+    input.read_to_end(&mut code)?;
 
     let mut out: Box<dyn Write> = match opts.out_file {
         Some(path) => Box::new(File::create(path)?),
         None => Box::new(std::io::stdout()),
     };
 
-    let mut separator = Separator::new();
-
-    separator.push_all(disasm.ops());
+    let analysis = match opts.cache {
+        Some(dir) => {
+            let cache = Cache::open(dir)?;
+            match cache.get("ecfg", &code)? {
+                Some(analysis) => analysis,
+                None => {
+                    let analysis = Analysis::new(&code)?;
+                    cache.insert("ecfg", &code, &analysis)?;
+                    analysis
+                }
+            }
+        }
+        None => Analysis::new(&code)?,
+    };
 
-    let blocks = separator
-        .take()
-        .into_iter()
-        .chain(separator.finish())
-        .map(|x| AnnotatedBlock::annotate(&x));
+    if analysis.stack_exceeded {
+        eprintln!(
+            "warning: the stack can grow beyond the limit of {} items",
+            STACK_LIMIT
+        );
+    }
 
-    let mut cfg = ControlFlowGraph::new(blocks);
-    cfg.refine_shallow();
+    if opts.loops {
+        for found in analysis.loops.iter() {
+            eprintln!("{}", found);
+        }
+    }
 
-    writeln!(out, "{}", cfg.render()).unwrap();
+    writeln!(out, "{}", analysis.graph).unwrap();
 
     Ok(())
 }
@@ -15,4 +15,13 @@ pub struct Opts {
         help = "path to output file (defaults to stdout)"
     )]
     pub out_file: Option<PathBuf>,
+
+    #[structopt(long = "loops", help = "list the natural loops and the blocks in each")]
+    pub loops: bool,
+
+    #[structopt(
+        long = "cache",
+        help = "reuse results for previously analyzed code from a directory"
+    )]
+    pub cache: Option<PathBuf>,
 }
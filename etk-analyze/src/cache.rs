@@ -0,0 +1,233 @@
+//! A persistent cache of analysis results, keyed by the hash of the analyzed
+//! code.
+//!
+//! Analyzing the same bytecode twice, which is common across large corpora of
+//! deployed contracts, can then reuse the earlier result.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_analyze::cache::Cache;
+//! # use etk_analyze::cache::Error;
+//!
+//! # let dir = tempfile::tempdir().unwrap();
+//! let cache = Cache::open(dir.path())?;
+//!
+//! let code = [0x60, 0x01, 0x00];
+//! let size: usize = cache.get_or_insert_with("size", &code, || code.len())?;
+//! assert_eq!(size, 3);
+//!
+//! // The second lookup reads the result written by the first.
+//! let size: usize = cache.get_or_insert_with("size", &code, || unreachable!())?;
+//! assert_eq!(size, 3);
+//! # Result::<(), Error>::Ok(())
+//! ```
+mod error {
+    use snafu::{Backtrace, Snafu};
+
+    use std::path::PathBuf;
+
+    /// Errors that may arise while reading or writing the cache.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// An entry or directory of the cache couldn't be read or written.
+        #[snafu(display("cache I/O failed on `{}`", path.display()))]
+        #[non_exhaustive]
+        Io {
+            /// The path of the entry or directory.
+            path: PathBuf,
+
+            /// The underlying source of this error.
+            source: std::io::Error,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// An entry of the cache isn't valid JSON, or doesn't match the type
+        /// it was read as.
+        #[snafu(display("cache entry `{}` is invalid", path.display()))]
+        #[non_exhaustive]
+        Json {
+            /// The path of the entry.
+            path: PathBuf,
+
+            /// The underlying source of this error.
+            source: serde_json::Error,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+}
+
+pub use self::error::Error;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use sha3::{Digest, Keccak256};
+
+use snafu::ResultExt;
+
+use std::io;
+use std::path::PathBuf;
+
+/// A directory holding the results of analyses, one JSON file per analysis
+/// of each distinct code.
+///
+/// Entries live in `<dir>/<version>/<hash>/<analysis>.json`, where `version`
+/// is the version of this crate, so results from older versions of the
+/// analyses are never reused, and `hash` is the keccak256 hash of the code.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Open the cache in `dir`, creating the directory if it doesn't exist.
+    pub fn open<P>(dir: P) -> Result<Self, Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let dir = dir.into().join(env!("CARGO_PKG_VERSION"));
+        std::fs::create_dir_all(&dir).context(error::Io { path: dir.clone() })?;
+        Ok(Self { dir })
+    }
+
+    /// The hex-encoded keccak256 hash identifying `code` in the cache.
+    pub fn key(code: &[u8]) -> String {
+        hex::encode(Keccak256::digest(code))
+    }
+
+    /// Read the result of `analysis` for `code`, or `None` if it hasn't been
+    /// cached.
+    pub fn get<T>(&self, analysis: &str, code: &[u8]) -> Result<Option<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let path = self.path(analysis, code);
+
+        let text = match std::fs::read_to_string(&path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(error::Io { path }),
+        };
+
+        let value = serde_json::from_str(&text).context(error::Json { path })?;
+        Ok(Some(value))
+    }
+
+    /// Store `value` as the result of `analysis` for `code`, replacing any
+    /// earlier result.
+    ///
+    /// The entry is written to a temporary file first and then moved into
+    /// place, so concurrent readers never see a partial result.
+    pub fn insert<T>(&self, analysis: &str, code: &[u8], value: &T) -> Result<(), Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        let path = self.path(analysis, code);
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir).context(error::Io { path: dir })?;
+
+        let json = serde_json::to_string(value).context(error::Json { path: &path })?;
+
+        let partial = path.with_extension(format!("json.{}", std::process::id()));
+        std::fs::write(&partial, json).context(error::Io { path: &partial })?;
+        std::fs::rename(&partial, &path).context(error::Io { path })?;
+
+        Ok(())
+    }
+
+    /// Read the result of `analysis` for `code`, or compute it with `f` and
+    /// store it if it hasn't been cached.
+    pub fn get_or_insert_with<T, F>(&self, analysis: &str, code: &[u8], f: F) -> Result<T, Error>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> T,
+    {
+        if let Some(value) = self.get(analysis, code)? {
+            return Ok(value);
+        }
+
+        let value = f();
+        self.insert(analysis, code, &value)?;
+        Ok(value)
+    }
+
+    fn path(&self, analysis: &str, code: &[u8]) -> PathBuf {
+        let mut path = self.dir.join(Self::key(code));
+        path.push(format!("{}.json", analysis));
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    #[test]
+    fn key_is_keccak() {
+        assert_eq!(
+            Cache::key(&[]),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470",
+        );
+    }
+
+    #[test]
+    fn insert_and_get() -> Result<(), Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path())?;
+
+        assert_eq!(cache.get::<Vec<usize>>("blocks", &[0x00])?, None);
+
+        cache.insert("blocks", &[0x00], &vec![0usize])?;
+        cache.insert("blocks", &[0x58, 0x00], &vec![0usize, 1])?;
+
+        assert_eq!(cache.get("blocks", &[0x00])?, Some(vec![0usize]));
+        assert_eq!(cache.get("blocks", &[0x58, 0x00])?, Some(vec![0usize, 1]));
+        assert_eq!(cache.get::<Vec<usize>>("loops", &[0x00])?, None);
+
+        // Results survive reopening the cache.
+        let cache = Cache::open(dir.path())?;
+        assert_eq!(cache.get("blocks", &[0x00])?, Some(vec![0usize]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_or_insert_with() -> Result<(), Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path())?;
+
+        let mut runs = 0;
+        for _ in 0..3 {
+            let value: String = cache.get_or_insert_with("name", &[0x00], || {
+                runs += 1;
+                "stop".to_owned()
+            })?;
+            assert_eq!(value, "stop");
+        }
+
+        assert_eq!(runs, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_wrong_type() -> Result<(), Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path())?;
+
+        cache.insert("name", &[0x00], "stop")?;
+        let err = cache.get::<usize>("name", &[0x00]).unwrap_err();
+        assert_matches!(err, Error::Json { .. });
+
+        Ok(())
+    }
+}
@@ -2,16 +2,95 @@ use crate::blocks::annotated::ExitExt;
 
 use etk_dasm::blocks::annotated::{AnnotatedBlock, Exit};
 
+use petgraph::algo::dominators::simple_fast;
 use petgraph::dot::Dot;
 use petgraph::graph::{Graph, NodeIndex};
+use petgraph::Direction;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryInto;
 use std::fmt;
 
 use z3::ast::{Ast, BV};
 use z3::SatResult;
 
+/// The maximum number of items the EVM stack can hold.
+pub const STACK_LIMIT: usize = 1024;
+
+/// The result of [`ControlFlowGraph::max_stack_height`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MaxStackHeight {
+    /// No path through the graph grows the stack beyond the given height.
+    Bounded(usize),
+
+    /// At least one path through the graph grows the stack beyond
+    /// [`STACK_LIMIT`].
+    Exceeded,
+}
+
+impl MaxStackHeight {
+    /// Return `true` if the stack can grow beyond [`STACK_LIMIT`].
+    pub fn is_exceeded(&self) -> bool {
+        matches!(self, Self::Exceeded)
+    }
+}
+
+/// The dominator tree of the blocks reachable from offset zero, returned by
+/// [`ControlFlowGraph::dominators`].
+///
+/// A block dominates another if every path from the entry to the other block
+/// goes through it. Blocks are identified by their offset.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Dominators {
+    entry: Option<usize>,
+    idoms: BTreeMap<usize, usize>,
+}
+
+impl Dominators {
+    /// The closest block that dominates the block at `offset`, other than
+    /// itself. `None` for the entry, and for unreachable blocks.
+    pub fn immediate_dominator(&self, offset: usize) -> Option<usize> {
+        self.idoms.get(&offset).copied()
+    }
+
+    /// Return `true` if the block at `dominator` dominates the block at
+    /// `offset`. Every reachable block dominates itself.
+    pub fn dominates(&self, dominator: usize, offset: usize) -> bool {
+        if !self.is_reachable(offset) {
+            return false;
+        }
+
+        let mut current = offset;
+        while current != dominator {
+            current = match self.idoms.get(&current) {
+                Some(idom) => *idom,
+                None => return false,
+            };
+        }
+
+        true
+    }
+
+    /// Return `true` if the block at `offset` can be reached from the entry.
+    pub fn is_reachable(&self, offset: usize) -> bool {
+        self.entry == Some(offset) || self.idoms.contains_key(&offset)
+    }
+}
+
+/// A natural loop, found by [`ControlFlowGraph::loops`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Loop {
+    /// Offset of the block that starts every iteration, which dominates the
+    /// rest of the loop.
+    pub header: usize,
+
+    /// Offsets of the blocks that jump back to the header.
+    pub latches: Vec<usize>,
+
+    /// Offsets of every block in the loop, including the header.
+    pub body: BTreeSet<usize>,
+}
+
 #[derive(Debug, Clone)]
 enum Node {
     Terminate,
@@ -284,6 +363,133 @@ impl ControlFlowGraph {
         }
     }
 
+    /// Compute the maximum height the stack can reach while executing the code
+    /// section described by this graph, starting with an empty stack at offset
+    /// zero.
+    ///
+    /// Legacy code has exactly one code section. Where several paths reach the
+    /// same block with different stack heights, the largest height is used.
+    /// Call [`ControlFlowGraph::refine_shallow`] first to get a tighter bound.
+    pub fn max_stack_height(&self) -> MaxStackHeight {
+        let entry = match self.by_offset.get(&0) {
+            Some(idx) => *idx,
+            None => return MaxStackHeight::Bounded(0),
+        };
+
+        let mut heights: HashMap<NodeIndex, usize> = HashMap::new();
+        let mut pending = vec![(entry, 0usize)];
+        let mut max = 0;
+
+        while let Some((idx, height)) = pending.pop() {
+            if matches!(heights.get(&idx), Some(seen) if *seen >= height) {
+                continue;
+            }
+
+            heights.insert(idx, height);
+
+            let block = match &self.graph[idx] {
+                Node::Block(b) => b,
+                _ => continue,
+            };
+
+            max = std::cmp::max(max, height + block.max_stack_growth);
+            if max > STACK_LIMIT {
+                return MaxStackHeight::Exceeded;
+            }
+
+            let exit_height =
+                (height + block.outputs.stack.len()).saturating_sub(block.inputs.stack.len());
+
+            for neighbor in self.graph.neighbors(idx) {
+                pending.push((neighbor, exit_height));
+            }
+        }
+
+        MaxStackHeight::Bounded(max)
+    }
+
+    /// Compute the dominator tree of the blocks reachable from offset zero.
+    ///
+    /// Call [`ControlFlowGraph::refine_shallow`] first, since the unrefined
+    /// graph assumes every jump can reach every `jumpdest`.
+    pub fn dominators(&self) -> Dominators {
+        let entry = match self.by_offset.get(&0) {
+            Some(idx) => *idx,
+            None => return Dominators::default(),
+        };
+
+        let dominators = simple_fast(&self.graph, entry);
+        let idoms = self
+            .by_offset
+            .iter()
+            .filter_map(|(offset, idx)| {
+                let idom = dominators.immediate_dominator(*idx)?;
+                Some((*offset, self.graph[idom].unwrap_block().offset))
+            })
+            .collect();
+
+        Dominators {
+            entry: Some(0),
+            idoms,
+        }
+    }
+
+    /// Find the natural loops of the graph, ordered by the offset of their
+    /// headers.
+    ///
+    /// An edge from a block to one of its dominators is a back edge, and the
+    /// loop it closes contains every block that can reach the back edge
+    /// without going through the header. Back edges to the same header are
+    /// combined into one loop, so loops are either nested or disjoint.
+    pub fn loops(&self) -> Vec<Loop> {
+        let dominators = self.dominators();
+        let mut loops: BTreeMap<usize, Loop> = BTreeMap::new();
+
+        for (offset, idx) in self.by_offset.iter() {
+            for neighbor in self.graph.neighbors(*idx) {
+                let header = match &self.graph[neighbor] {
+                    Node::Block(b) if dominators.dominates(b.offset, *offset) => b.offset,
+                    _ => continue,
+                };
+
+                let found = loops.entry(header).or_insert_with(|| Loop {
+                    header,
+                    latches: Vec::new(),
+                    body: [header].iter().copied().collect(),
+                });
+                found.latches.push(*offset);
+
+                let mut pending = vec![*idx];
+                while let Some(idx) = pending.pop() {
+                    let block = self.graph[idx].unwrap_block();
+                    if !found.body.insert(block.offset) {
+                        continue;
+                    }
+
+                    // Unreachable blocks aren't part of the loop, even if
+                    // they jump into it.
+                    for pred in self.graph.neighbors_directed(idx, Direction::Incoming) {
+                        match &self.graph[pred] {
+                            Node::Block(b) if dominators.dominates(header, b.offset) => {
+                                pending.push(pred)
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+            }
+        }
+
+        loops
+            .into_values()
+            .map(|mut found| {
+                found.latches.sort_unstable();
+                found.latches.dedup();
+                found
+            })
+            .collect()
+    }
+
     pub fn render(&self) -> impl '_ + fmt::Display {
         Dot::new(&self.graph)
     }
@@ -399,6 +605,195 @@ mod tests {
         }
     }
 
+    fn build(source: &str) -> ControlFlowGraph {
+        let mut program = Disassembler::new();
+        Ingest::new(&mut program).ingest("./test", source).unwrap();
+
+        let mut separator = Separator::new();
+        separator.push_all(program.ops());
+
+        let blocks = separator
+            .take()
+            .into_iter()
+            .chain(separator.finish().into_iter())
+            .map(|x| AnnotatedBlock::annotate(&x));
+
+        let mut cfg = ControlFlowGraph::new(blocks);
+        cfg.refine_shallow();
+        cfg
+    }
+
+    #[test]
+    fn max_stack_height_empty() {
+        let cfg = build("");
+        assert_eq!(cfg.max_stack_height(), MaxStackHeight::Bounded(0));
+    }
+
+    #[test]
+    fn max_stack_height_straight_line() {
+        let cfg = build(
+            r#"
+            push1 1
+            push1 2
+            push1 3
+            add
+            add
+            stop
+        "#,
+        );
+        assert_eq!(cfg.max_stack_height(), MaxStackHeight::Bounded(3));
+    }
+
+    #[test]
+    fn max_stack_height_diamond() {
+        let cfg = build(
+            r#"
+            push1 0
+            calldataload
+            push1 target
+            jumpi
+
+            pc
+            pc
+            push1 exit
+            jump
+
+            target:
+                jumpdest
+                pc
+                push1 exit
+                jump
+
+            exit:
+                jumpdest
+                stop
+        "#,
+        );
+        assert_eq!(cfg.max_stack_height(), MaxStackHeight::Bounded(3));
+    }
+
+    #[test]
+    fn max_stack_height_growing_loop() {
+        let cfg = build(
+            r#"
+            jumpdest
+            pc
+            push1 0
+            jump
+        "#,
+        );
+        assert!(cfg.max_stack_height().is_exceeded());
+    }
+
+    #[test]
+    fn dominators_diamond() {
+        let cfg = build(
+            r#"
+            pc
+            calldataload
+            push1 target
+            jumpi
+
+            push1 exit
+            jump
+
+            target:
+                jumpdest
+                push1 exit
+                jump
+
+            exit:
+                jumpdest
+                stop
+        "#,
+        );
+
+        let dominators = cfg.dominators();
+        assert_eq!(dominators.immediate_dominator(0), None);
+        assert_eq!(dominators.immediate_dominator(5), Some(0));
+        assert_eq!(dominators.immediate_dominator(8), Some(0));
+        assert_eq!(dominators.immediate_dominator(12), Some(0));
+
+        assert!(dominators.dominates(0, 12));
+        assert!(dominators.dominates(12, 12));
+        assert!(!dominators.dominates(5, 12));
+        assert!(!dominators.dominates(8, 12));
+
+        assert!(cfg.loops().is_empty());
+    }
+
+    #[test]
+    fn loops_nested() {
+        let cfg = build(
+            r#"
+            outer:
+                jumpdest
+            inner:
+                jumpdest
+                push1 0
+                calldataload
+                push1 inner
+                jumpi
+
+                push1 0
+                calldataload
+                push1 outer
+                jumpi
+
+                stop
+        "#,
+        );
+
+        let dominators = cfg.dominators();
+        assert_eq!(dominators.immediate_dominator(1), Some(0));
+        assert_eq!(dominators.immediate_dominator(8), Some(1));
+        assert_eq!(dominators.immediate_dominator(14), Some(8));
+
+        let loops = cfg.loops();
+        assert_eq!(
+            loops,
+            [
+                Loop {
+                    header: 0,
+                    latches: vec![8],
+                    body: [0, 1, 8].iter().copied().collect(),
+                },
+                Loop {
+                    header: 1,
+                    latches: vec![1],
+                    body: [1].iter().copied().collect(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn loops_unreachable() {
+        let cfg = build(
+            r#"
+            top:
+                jumpdest
+                push1 0
+                calldataload
+                push1 top
+                jumpi
+                stop
+
+                push1 top
+                jump
+        "#,
+        );
+
+        let dominators = cfg.dominators();
+        assert!(!dominators.is_reachable(8));
+        assert!(!dominators.dominates(0, 8));
+
+        let loops = cfg.loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].latches, [0]);
+        assert_eq!(loops[0].body, [0].iter().copied().collect());
+    }
+
     #[test]
     fn empty() {
         let source = "";
@@ -0,0 +1,487 @@
+//! Stack height validation for the code sections of EOF containers.
+//!
+//! Every code section of an EOF container declares, in the type section, how
+//! many stack items it takes, how many it returns, and the largest height its
+//! stack reaches ([EIP-5450]). [`max_stack_height`] computes that height by
+//! following every path through a section, and [`validate_stack_heights`]
+//! compares it with the declared one.
+//!
+//! [EIP-5450]: https://eips.ethereum.org/EIPS/eip-5450
+
+use crate::cfg::{MaxStackHeight, STACK_LIMIT};
+
+use etk_ops::cancun::{Op, Operation};
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+
+/// The type of a code section, as declared in the type section.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SectionType {
+    /// Number of stack items the section takes.
+    pub inputs: u8,
+
+    /// Number of stack items the section returns, or `0x80` if it never
+    /// returns.
+    pub outputs: u8,
+
+    /// Largest height the stack reaches while executing the section,
+    /// including its inputs.
+    pub max_stack_height: u16,
+}
+
+/// A code section whose declared maximum stack height doesn't match the
+/// computed one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Mismatch {
+    /// Index of the code section.
+    pub section: usize,
+
+    /// Height declared in the type section.
+    pub declared: u16,
+
+    /// Height computed from the code.
+    pub computed: MaxStackHeight,
+}
+
+/// Errors that prevent computing the stack height of a code section.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// The instruction at `offset` continues past the end of the section.
+    Truncated {
+        /// Offset of the instruction within the section.
+        offset: usize,
+    },
+
+    /// The relative jump at `offset` lands outside the section.
+    InvalidTarget {
+        /// Offset of the instruction within the section.
+        offset: usize,
+    },
+
+    /// The `callf` or `jumpf` at `offset` names a code section that doesn't
+    /// exist.
+    UnknownSection {
+        /// Offset of the instruction within the section.
+        offset: usize,
+
+        /// Index of the missing code section.
+        section: u16,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Truncated { offset } => write!(f, "instruction at {} is truncated", offset),
+            Self::InvalidTarget { offset } => {
+                write!(f, "relative jump at {} leaves the section", offset)
+            }
+            Self::UnknownSection { offset, section } => write!(
+                f,
+                "instruction at {} refers to missing code section {}",
+                offset, section
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// How execution continues after an instruction.
+#[derive(Debug)]
+enum Flow {
+    /// Continues with the next instruction.
+    Next,
+
+    /// Continues with the next instruction, or jumps to one of the targets.
+    Branch(Vec<usize>),
+
+    /// Jumps to the target.
+    Jump(usize),
+
+    /// Leaves the section.
+    Exit,
+}
+
+/// The effect of one instruction of an EOF code section.
+#[derive(Debug)]
+struct Step {
+    size: usize,
+    pops: usize,
+    pushes: usize,
+
+    /// Items the stack of a called section needs, on top of its inputs. They
+    /// count towards the stack limit, but not towards the height of this
+    /// section.
+    extra: usize,
+
+    flow: Flow,
+}
+
+/// Decode the instruction at `offset` in `code`, the code of a section of a
+/// container whose code sections have `types`.
+///
+/// Instructions added by EOF are decoded here, since the opcode tables don't
+/// have them yet. The rest are read from the Cancun table.
+fn step(types: &[SectionType], code: &[u8], offset: usize) -> Result<Step, Error> {
+    let immediate = |len: usize| {
+        code.get(offset + 1..offset + 1 + len)
+            .ok_or(Error::Truncated { offset })
+    };
+
+    let relative = |bytes: &[u8], next: usize| -> Result<usize, Error> {
+        let rel = i16::from_be_bytes(bytes.try_into().unwrap());
+        let target = next as isize + isize::from(rel);
+        if target < 0 || target as usize >= code.len() {
+            return Err(Error::InvalidTarget { offset });
+        }
+        Ok(target as usize)
+    };
+
+    let section = |bytes: &[u8]| -> Result<SectionType, Error> {
+        let section = u16::from_be_bytes(bytes.try_into().unwrap());
+        types
+            .get(usize::from(section))
+            .copied()
+            .ok_or(Error::UnknownSection { offset, section })
+    };
+
+    let simple = |size, pops, pushes| Step {
+        size,
+        pops,
+        pushes,
+        extra: 0,
+        flow: Flow::Next,
+    };
+
+    let step = match code[offset] {
+        // rjump
+        0xe0 => {
+            let target = relative(immediate(2)?, offset + 3)?;
+            Step {
+                flow: Flow::Jump(target),
+                ..simple(3, 0, 0)
+            }
+        }
+
+        // rjumpi
+        0xe1 => {
+            let target = relative(immediate(2)?, offset + 3)?;
+            Step {
+                flow: Flow::Branch(vec![target]),
+                ..simple(3, 1, 0)
+            }
+        }
+
+        // rjumpv: the largest index of the table, then an offset per index.
+        0xe2 => {
+            let count = usize::from(immediate(1)?[0]) + 1;
+            let size = 2 + 2 * count;
+            let next = offset + size;
+            let table = immediate(size - 1)?;
+            let targets = table[1..]
+                .chunks(2)
+                .map(|rel| relative(rel, next))
+                .collect::<Result<_, _>>()?;
+            Step {
+                flow: Flow::Branch(targets),
+                ..simple(size, 1, 0)
+            }
+        }
+
+        // callf: the callee takes its inputs and returns its outputs, and
+        // needs room for its own stack.
+        0xe3 => {
+            let callee = section(immediate(2)?)?;
+            let inputs = usize::from(callee.inputs);
+            Step {
+                extra: usize::from(callee.max_stack_height).saturating_sub(inputs),
+                ..simple(3, inputs, usize::from(callee.outputs))
+            }
+        }
+
+        // retf
+        0xe4 => Step {
+            flow: Flow::Exit,
+            ..simple(1, 0, 0)
+        },
+
+        // jumpf: the target takes over the frame, with its own stack.
+        0xe5 => {
+            let target = section(immediate(2)?)?;
+            let inputs = usize::from(target.inputs);
+            Step {
+                extra: usize::from(target.max_stack_height).saturating_sub(inputs),
+                flow: Flow::Exit,
+                ..simple(3, inputs, inputs)
+            }
+        }
+
+        // dupn, swapn, and exchange reach below the top of the stack.
+        0xe6 => {
+            let n = usize::from(immediate(1)?[0]) + 1;
+            simple(2, n, n + 1)
+        }
+        0xe7 => {
+            let n = usize::from(immediate(1)?[0]) + 2;
+            simple(2, n, n)
+        }
+        0xe8 => {
+            let imm = immediate(1)?[0];
+            let n = usize::from(imm >> 4) + usize::from(imm & 0x0f) + 3;
+            simple(2, n, n)
+        }
+
+        // dataload, dataloadn, datasize, and datacopy
+        0xd0 => simple(1, 1, 1),
+        0xd1 => {
+            immediate(2)?;
+            simple(3, 0, 1)
+        }
+        0xd2 => simple(1, 0, 1),
+        0xd3 => simple(1, 3, 0),
+
+        // eofcreate and returncontract
+        0xec => {
+            immediate(1)?;
+            simple(2, 4, 1)
+        }
+        0xee => {
+            immediate(1)?;
+            Step {
+                flow: Flow::Exit,
+                ..simple(2, 2, 0)
+            }
+        }
+
+        // returndataload, extcall, extdelegatecall, and extstaticcall
+        0xf7 => simple(1, 1, 1),
+        0xf8 => simple(1, 4, 1),
+        0xf9 | 0xfb => simple(1, 3, 1),
+
+        byte => {
+            let op = Op::<()>::from(byte);
+            let size = op.size();
+            immediate(size - 1)?;
+            Step {
+                flow: if op.is_exit() { Flow::Exit } else { Flow::Next },
+                ..simple(size, op.pops(), op.pushes())
+            }
+        }
+    };
+
+    Ok(step)
+}
+
+/// Compute the maximum height the stack can reach while executing the code
+/// section `index` of a container whose code sections have `types` and
+/// `code`.
+///
+/// Execution starts at offset zero with the section's inputs on the stack.
+/// Calls to other sections with `callf` and `jumpf` use their declared types.
+pub fn max_stack_height(
+    types: &[SectionType],
+    code: &[&[u8]],
+    index: usize,
+) -> Result<MaxStackHeight, Error> {
+    let section = code[index];
+    let inputs = usize::from(types[index].inputs);
+
+    if section.is_empty() {
+        return Ok(MaxStackHeight::Bounded(inputs));
+    }
+
+    let mut heights: HashMap<usize, usize> = HashMap::new();
+    let mut pending = vec![(0, inputs)];
+    let mut max = inputs;
+
+    while let Some((offset, height)) = pending.pop() {
+        if matches!(heights.get(&offset), Some(seen) if *seen >= height) {
+            continue;
+        }
+
+        heights.insert(offset, height);
+
+        let step = step(types, section, offset)?;
+        let after = height.saturating_sub(step.pops) + step.pushes;
+
+        max = max.max(after);
+        if max > STACK_LIMIT || height + step.extra > STACK_LIMIT {
+            return Ok(MaxStackHeight::Exceeded);
+        }
+
+        let next = offset + step.size;
+        match step.flow {
+            Flow::Exit => (),
+            Flow::Jump(target) => pending.push((target, after)),
+            Flow::Next | Flow::Branch(_) if next >= section.len() => {
+                return Err(Error::Truncated { offset });
+            }
+            Flow::Next => pending.push((next, after)),
+            Flow::Branch(targets) => {
+                pending.push((next, after));
+                pending.extend(targets.into_iter().map(|t| (t, after)));
+            }
+        }
+    }
+
+    Ok(MaxStackHeight::Bounded(max))
+}
+
+/// Compute the maximum stack height of every code section of a container,
+/// returning the sections whose declared height is different.
+pub fn validate_stack_heights(
+    types: &[SectionType],
+    code: &[&[u8]],
+) -> Result<Vec<Mismatch>, Error> {
+    let mut mismatches = Vec::new();
+
+    for (section, ty) in types.iter().enumerate() {
+        let computed = max_stack_height(types, code, section)?;
+        if computed != MaxStackHeight::Bounded(usize::from(ty.max_stack_height)) {
+            mismatches.push(Mismatch {
+                section,
+                declared: ty.max_stack_height,
+                computed,
+            });
+        }
+    }
+
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn ty(inputs: u8, outputs: u8, max_stack_height: u16) -> SectionType {
+        SectionType {
+            inputs,
+            outputs,
+            max_stack_height,
+        }
+    }
+
+    #[test]
+    fn rjumpi() {
+        // push0; rjumpi +2; push0; push0; stop
+        let code = hex!("5f e10002 5f 5f 00");
+        let types = [ty(0, 0x80, 2)];
+
+        let height = max_stack_height(&types, &[&code], 0).unwrap();
+        assert_eq!(height, MaxStackHeight::Bounded(2));
+        assert!(validate_stack_heights(&types, &[&code]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rjumpv() {
+        // push0; rjumpv +1, +2; push0; push0; stop
+        let code = hex!("5f e2 01 0001 0002 5f 5f 00");
+        let height = max_stack_height(&[ty(0, 0x80, 2)], &[&code], 0).unwrap();
+        assert_eq!(height, MaxStackHeight::Bounded(2));
+    }
+
+    #[test]
+    fn inputs() {
+        // pop; stop
+        let code = hex!("50 00");
+        let height = max_stack_height(&[ty(2, 0x80, 2)], &[&code], 0).unwrap();
+        assert_eq!(height, MaxStackHeight::Bounded(2));
+    }
+
+    #[test]
+    fn growing_loop() {
+        // push0; rjump -4
+        let code = hex!("5f e0fffc");
+        let height = max_stack_height(&[ty(0, 0x80, 0)], &[&code], 0).unwrap();
+        assert_eq!(height, MaxStackHeight::Exceeded);
+    }
+
+    #[test]
+    fn callf() {
+        // push0; push0; callf 1; stop
+        let main = hex!("5f 5f e30001 00");
+        // add; push0; pop; retf
+        let callee = hex!("01 5f 50 e4");
+        let types = [ty(0, 0x80, 2), ty(2, 1, 2)];
+        let code: [&[u8]; 2] = [&main, &callee];
+
+        assert_eq!(
+            max_stack_height(&types, &code, 0).unwrap(),
+            MaxStackHeight::Bounded(2)
+        );
+        assert_eq!(
+            max_stack_height(&types, &code, 1).unwrap(),
+            MaxStackHeight::Bounded(2)
+        );
+        assert!(validate_stack_heights(&types, &code).unwrap().is_empty());
+    }
+
+    #[test]
+    fn callf_exceeds_limit() {
+        // push0; push0; callf 1; stop
+        let main = hex!("5f 5f e30001 00");
+        let types = [ty(0, 0x80, 2), ty(0, 0, 1023)];
+        let code: [&[u8]; 2] = [&main, &[]];
+
+        let height = max_stack_height(&types, &code, 0).unwrap();
+        assert_eq!(height, MaxStackHeight::Exceeded);
+    }
+
+    #[test]
+    fn jumpf() {
+        // push0; jumpf 1
+        let main = hex!("5f e50001");
+        let types = [ty(0, 0x80, 1), ty(1, 0x80, 3)];
+        let code: [&[u8]; 2] = [&main, &hex!("5f 5f 00")];
+
+        assert_eq!(
+            max_stack_height(&types, &code, 0).unwrap(),
+            MaxStackHeight::Bounded(1)
+        );
+        assert!(validate_stack_heights(&types, &code).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_section() {
+        let main = hex!("e30005 00");
+        let err = max_stack_height(&[ty(0, 0x80, 0)], &[&main], 0).unwrap_err();
+        assert_eq!(
+            err,
+            Error::UnknownSection {
+                offset: 0,
+                section: 5
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_target() {
+        // rjump +16
+        let code = hex!("e00010 00");
+        let err = max_stack_height(&[ty(0, 0x80, 0)], &[&code], 0).unwrap_err();
+        assert_matches!(err, Error::InvalidTarget { offset: 0 });
+    }
+
+    #[test]
+    fn mismatch() {
+        let code = hex!("5f e10002 5f 5f 00");
+        let types = [ty(0, 0x80, 3)];
+
+        let mismatches = validate_stack_heights(&types, &[&code]).unwrap();
+        assert_eq!(
+            mismatches,
+            [Mismatch {
+                section: 0,
+                declared: 3,
+                computed: MaxStackHeight::Bounded(2),
+            }]
+        );
+    }
+}
@@ -8,5 +8,8 @@
 #![deny(missing_debug_implementations)]
 
 mod blocks;
+#[cfg(feature = "cache")]
+pub mod cache;
 pub mod cfg;
+pub mod eof;
 mod sym;
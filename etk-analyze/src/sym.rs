@@ -79,6 +79,7 @@ impl<'z> Visit for Z3Visit<'z> {
             Sym::ChainId => BV::new_const(self.context, "chainid", 256),
             Sym::SelfBalance => BV::fresh_const(self.context, "selfbalance", 256),
             Sym::BaseFee => BV::new_const(self.context, "basefee", 256),
+            Sym::BlobBaseFee => BV::new_const(self.context, "blobbasefee", 256),
             Sym::GetPc(pc) => BV::from_u64(self.context, *pc as u64, 256),
             Sym::MSize => BV::fresh_const(self.context, "msize", 256),
             Sym::Gas => BV::fresh_const(self.context, "gas", 256),
@@ -297,6 +298,11 @@ impl<'z> Visit for Z3Visit<'z> {
                 BV::fresh_const(self.context, "sload", 256)
             }
 
+            Sym::TLoad => {
+                let _addr = self.arguments.pop().unwrap();
+                BV::fresh_const(self.context, "tload", 256)
+            }
+
             Sym::Balance => {
                 let _addr = self.arguments.pop().unwrap();
                 BV::fresh_const(self.context, "balance", 256)
@@ -313,6 +319,17 @@ impl<'z> Visit for Z3Visit<'z> {
                 apply.as_bv().unwrap()
             }
 
+            Sym::BlobHash => {
+                let index = self.arguments.pop().unwrap();
+
+                let sort = Sort::bitvector(self.context, 256);
+
+                let func = FuncDecl::new(self.context, "blobhash", &[&sort], &sort);
+
+                let apply = func.apply(&[&Dynamic::from_ast(&index)]);
+                apply.as_bv().unwrap()
+            }
+
             Sym::AddMod => {
                 let modulus = self.arguments.pop().unwrap();
                 let rhs = self.arguments.pop().unwrap();
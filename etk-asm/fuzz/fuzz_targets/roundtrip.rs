@@ -0,0 +1,14 @@
+//! Disassembling any code and assembling the result gives back the same code.
+//!
+//! Run with `cargo +nightly fuzz run roundtrip` from the `etk-asm` directory.
+#![no_main]
+
+use etk_asm::verify::verify_roundtrip;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|code: &[u8]| {
+    if let Err(diff) = verify_roundtrip(code) {
+        panic!("{}", diff);
+    }
+});
@@ -0,0 +1,106 @@
+//! Helpers for the code shapes used by account abstraction.
+//!
+//! [EIP-7702] accounts delegate to other code through a _delegation
+//! designator_, which is a magic prefix followed by an address. It isn't
+//! executable, so it is built directly rather than assembled.
+//!
+//! [ERC-4337] accounts implement `validateUserOp`. The instruction macros in
+//! [`MACROS`] provide stubs for it, and the generators here assemble them.
+//!
+//! [EIP-7702]: https://eips.ethereum.org/EIPS/eip-7702
+//! [ERC-4337]: https://eips.ethereum.org/EIPS/eip-4337
+use crate::ingest::Ingest;
+
+/// The bytes every delegation designator starts with.
+pub const DELEGATION_PREFIX: [u8; 3] = [0xef, 0x01, 0x00];
+
+/// Source of the account abstraction instruction macros, suitable for
+/// importing into a program.
+pub const MACROS: &str = include_str!("aa.etk");
+
+/// Build the delegation designator pointing at `address`.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::aa::delegation_designator;
+/// # use hex_literal::hex;
+///
+/// let code = delegation_designator([0x11; 20]);
+/// assert_eq!(code, hex!("ef0100 1111111111111111111111111111111111111111"));
+/// ```
+pub fn delegation_designator(address: [u8; 20]) -> [u8; 23] {
+    let mut code = [0; 23];
+    code[..DELEGATION_PREFIX.len()].copy_from_slice(&DELEGATION_PREFIX);
+    code[DELEGATION_PREFIX.len()..].copy_from_slice(&address);
+    code
+}
+
+/// Get the address a delegation designator points at, or `None` if `code`
+/// isn't a delegation designator.
+pub fn delegation_target(code: &[u8]) -> Option<[u8; 20]> {
+    if code.len() != 23 || !code.starts_with(&DELEGATION_PREFIX) {
+        return None;
+    }
+
+    let mut address = [0; 20];
+    address.copy_from_slice(&code[DELEGATION_PREFIX.len()..]);
+    Some(address)
+}
+
+/// Assemble the runtime code of a minimal ERC-4337 account, which accepts
+/// every user operation sent by `entry_point`.
+///
+/// The account performs no signature check, so it is only suitable for
+/// testing. See `validate_user_op_stub` in [`MACROS`].
+pub fn validate_user_op_stub(entry_point: [u8; 20]) -> Vec<u8> {
+    let src = format!(
+        "{}\n%validate_user_op_stub(0x{})\n",
+        MACROS,
+        hex::encode(entry_point)
+    );
+
+    let mut output = Vec::new();
+    let mut ingest = Ingest::new(&mut output);
+    ingest
+        .ingest("aa.etk", &src)
+        .expect("account abstraction macros should assemble");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn delegation_target_roundtrip() {
+        let address = hex!("0000000071727de22e5e9d8baf0edac6f37da032");
+        let code = delegation_designator(address);
+        assert_eq!(delegation_target(&code), Some(address));
+    }
+
+    #[test]
+    fn delegation_target_rejects_other_code() {
+        assert_eq!(delegation_target(&[]), None);
+        assert_eq!(delegation_target(&hex!("ef0100")), None);
+        assert_eq!(delegation_target(&[0x5b; 23]), None);
+
+        let mut long = delegation_designator([0x22; 20]).to_vec();
+        long.push(0x00);
+        assert_eq!(delegation_target(&long), None);
+    }
+
+    #[test]
+    fn validate_user_op_stub_assembles() {
+        let entry_point = hex!("0000000071727de22e5e9d8baf0edac6f37da032");
+        let expected = hex!(
+            "5f3560e01c6319822f7c146011575f5ffd"
+            "5b6f71727de22e5e9d8baf0edac6f37da0323314602b575f5ffd"
+            "5b5f5f5f5f604435335af15060205ff3"
+        );
+
+        assert_eq!(validate_user_op_stub(entry_point), expected);
+    }
+}
@@ -0,0 +1,1101 @@
+//! Encoding and decoding values with the Solidity [contract ABI].
+//!
+//! Contract creation transactions carry the arguments of the constructor
+//! after the creation code. A [`Constructor`], read from the JSON description
+//! of a contract's ABI, separates the arguments from the code with
+//! [`Constructor::split`], and decodes them into readable [`Argument`]s with
+//! [`Constructor::decode`].
+//!
+//! In the other direction, [`encode_static`] encodes constant arguments of
+//! fixed size types, which is what `%abi_encode` appends to init code.
+//!
+//! [contract ABI]: https://docs.soliditylang.org/en/latest/abi-spec.html
+mod error {
+    use num_bigint::BigInt;
+
+    use snafu::{Backtrace, Snafu};
+
+    /// Errors that may arise while reading an ABI or decoding values.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// The ABI couldn't be parsed as JSON.
+        #[cfg(feature = "fs")]
+        #[snafu(display("the ABI isn't valid JSON"))]
+        #[non_exhaustive]
+        Json {
+            /// The underlying source of this error.
+            source: serde_json::Error,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The ABI uses a type that can't be decoded.
+        #[snafu(display("unsupported ABI type `{}`", ty))]
+        #[non_exhaustive]
+        UnsupportedType {
+            /// The type, as written in the ABI.
+            ty: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The encoded data ended before a value could be read.
+        #[snafu(display(
+            "reading 0x{:x} bytes at offset 0x{:x} runs past the end of the data (0x{:x} bytes)",
+            size,
+            offset,
+            len
+        ))]
+        #[non_exhaustive]
+        OutOfBounds {
+            /// Where the read began.
+            offset: usize,
+
+            /// How many bytes were read.
+            size: usize,
+
+            /// The length of the encoded data.
+            len: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A value isn't a valid encoding of its type.
+        #[snafu(display("invalid encoding of `{}` at offset 0x{:x}", ty, offset))]
+        #[non_exhaustive]
+        InvalidValue {
+            /// The type being decoded.
+            ty: String,
+
+            /// Where the value began.
+            offset: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The length of the arguments can't be worked out from the ABI alone.
+        #[snafu(display(
+            "the constructor has dynamically sized arguments, so the length of the creation code must be given"
+        ))]
+        #[non_exhaustive]
+        DynamicArguments {
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A type can't be encoded without knowing the length of its values.
+        #[snafu(display("`{}` isn't a fixed size type", ty))]
+        #[non_exhaustive]
+        DynamicType {
+            /// The dynamically sized type.
+            ty: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The number of values doesn't match the types being encoded.
+        #[snafu(display("expected {} value(s) but got {}", expected, got))]
+        #[non_exhaustive]
+        ValueCount {
+            /// How many values the types need.
+            expected: usize,
+
+            /// How many values were given.
+            got: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A value doesn't fit in its type.
+        #[snafu(display("the value {} doesn't fit in `{}`", value, ty))]
+        #[non_exhaustive]
+        ValueOutOfRange {
+            /// The type being encoded.
+            ty: String,
+
+            /// The value that doesn't fit.
+            value: BigInt,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The creation code is longer than the data it should be a prefix of.
+        #[snafu(display(
+            "the creation code (0x{:x} bytes) is longer than the transaction data (0x{:x} bytes)",
+            code,
+            len
+        ))]
+        #[non_exhaustive]
+        CodeTooLong {
+            /// The length of the creation code.
+            code: usize,
+
+            /// The length of the transaction data.
+            len: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+}
+
+pub use self::error::Error;
+
+use num_bigint::{BigInt, BigUint, Sign};
+
+#[cfg(feature = "fs")]
+use serde::Deserialize;
+
+#[cfg(feature = "fs")]
+use snafu::ResultExt;
+use snafu::{ensure, OptionExt};
+
+use std::convert::TryFrom;
+use std::fmt;
+
+const WORD: usize = 32;
+
+/// The type of a single ABI parameter.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParamType {
+    /// `address`
+    Address,
+
+    /// `bool`
+    Bool,
+
+    /// `uintN`, with the number of bits.
+    Uint(usize),
+
+    /// `intN`, with the number of bits.
+    Int(usize),
+
+    /// `bytesN`, with the number of bytes.
+    FixedBytes(usize),
+
+    /// `bytes`
+    Bytes,
+
+    /// `string`
+    String,
+
+    /// `T[]`
+    Array(Box<ParamType>),
+
+    /// `T[N]`
+    FixedArray(Box<ParamType>, usize),
+
+    /// `(T1, T2, ...)`
+    Tuple(Vec<ParamType>),
+}
+
+impl ParamType {
+    /// Returns `true` if values of this type are encoded out of line, with an
+    /// offset in their place.
+    pub fn is_dynamic(&self) -> bool {
+        match self {
+            Self::Bytes | Self::String | Self::Array(_) => true,
+            Self::FixedArray(inner, _) => inner.is_dynamic(),
+            Self::Tuple(items) => items.iter().any(Self::is_dynamic),
+            _ => false,
+        }
+    }
+
+    /// The number of bytes this type occupies in the head of an encoding.
+    fn head_size(&self) -> usize {
+        if self.is_dynamic() {
+            return WORD;
+        }
+
+        match self {
+            Self::FixedArray(inner, len) => inner.head_size() * len,
+            Self::Tuple(items) => items.iter().map(Self::head_size).sum(),
+            _ => WORD,
+        }
+    }
+
+    fn parse(ty: &str, components: &[RawParam]) -> Result<Self, Error> {
+        let ty = ty.trim();
+
+        if let Some(prefix) = ty.strip_suffix(']') {
+            let open = prefix.rfind('[').context(error::UnsupportedType { ty })?;
+            let inner = Box::new(Self::parse(&prefix[..open], components)?);
+            let len = &prefix[open + 1..];
+
+            if len.is_empty() {
+                return Ok(Self::Array(inner));
+            }
+
+            let len = len.parse().ok().context(error::UnsupportedType { ty })?;
+            return Ok(Self::FixedArray(inner, len));
+        }
+
+        let bits = |digits: &str, default: usize| -> Option<usize> {
+            let bits = match digits {
+                "" => default,
+                _ => digits.parse().ok()?,
+            };
+
+            if bits == 0 || bits > 256 || bits % 8 != 0 {
+                return None;
+            }
+
+            Some(bits)
+        };
+
+        if let Some(inner) = ty.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+            return parse_types(inner).map(Self::Tuple);
+        }
+
+        let parsed = match ty {
+            "address" => Some(Self::Address),
+            "bool" => Some(Self::Bool),
+            "bytes" => Some(Self::Bytes),
+            "string" => Some(Self::String),
+            "tuple" => {
+                let items = components
+                    .iter()
+                    .map(|c| Self::parse(&c.ty, &c.components))
+                    .collect::<Result<_, _>>()?;
+                Some(Self::Tuple(items))
+            }
+            _ => {
+                if let Some(digits) = ty.strip_prefix("uint") {
+                    bits(digits, 256).map(Self::Uint)
+                } else if let Some(digits) = ty.strip_prefix("int") {
+                    bits(digits, 256).map(Self::Int)
+                } else if let Some(digits) = ty.strip_prefix("bytes") {
+                    match digits.parse() {
+                        Ok(n) if (1..=WORD).contains(&n) => Some(Self::FixedBytes(n)),
+                        _ => None,
+                    }
+                } else {
+                    None
+                }
+            }
+        };
+
+        parsed.context(error::UnsupportedType { ty })
+    }
+}
+
+impl fmt::Display for ParamType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Address => write!(f, "address"),
+            Self::Bool => write!(f, "bool"),
+            Self::Uint(bits) => write!(f, "uint{}", bits),
+            Self::Int(bits) => write!(f, "int{}", bits),
+            Self::FixedBytes(len) => write!(f, "bytes{}", len),
+            Self::Bytes => write!(f, "bytes"),
+            Self::String => write!(f, "string"),
+            Self::Array(inner) => write!(f, "{}[]", inner),
+            Self::FixedArray(inner, len) => write!(f, "{}[{}]", inner, len),
+            Self::Tuple(items) => {
+                write!(f, "(")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// A decoded ABI value.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Value {
+    /// An `address`.
+    Address([u8; 20]),
+
+    /// A `bool`.
+    Bool(bool),
+
+    /// An unsigned integer.
+    Uint(BigUint),
+
+    /// A signed integer.
+    Int(BigInt),
+
+    /// A `bytesN` or `bytes`.
+    Bytes(Vec<u8>),
+
+    /// A `string`.
+    String(String),
+
+    /// The items of a fixed or dynamic array.
+    Array(Vec<Value>),
+
+    /// The items of a tuple.
+    Tuple(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter, open, items: &[Value], close| {
+            write!(f, "{}", open)?;
+            for (index, item) in items.iter().enumerate() {
+                if index > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", item)?;
+            }
+            write!(f, "{}", close)
+        };
+
+        match self {
+            Self::Address(address) => write!(f, "0x{}", hex::encode(address)),
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::Uint(value) => write!(f, "{}", value),
+            Self::Int(value) => write!(f, "{}", value),
+            Self::Bytes(bytes) => write!(f, "0x{}", hex::encode(bytes)),
+            Self::String(text) => write!(f, "{:?}", text),
+            Self::Array(items) => list(f, "[", items, "]"),
+            Self::Tuple(items) => list(f, "(", items, ")"),
+        }
+    }
+}
+
+/// A named parameter of a function or constructor.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Param {
+    /// The name of the parameter, which may be empty.
+    pub name: String,
+
+    /// The type of the parameter.
+    pub ty: ParamType,
+}
+
+/// A decoded constructor argument.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Argument {
+    /// The parameter the argument was passed for.
+    pub param: Param,
+
+    /// The decoded value.
+    pub value: Value,
+}
+
+impl fmt::Display for Argument {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.param.ty)?;
+        if !self.param.name.is_empty() {
+            write!(f, " {}", self.param.name)?;
+        }
+        write!(f, " = {}", self.value)
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "fs", derive(Deserialize))]
+struct RawParam {
+    #[cfg_attr(feature = "fs", serde(default))]
+    #[cfg_attr(not(feature = "fs"), allow(dead_code))]
+    name: String,
+
+    #[cfg_attr(feature = "fs", serde(rename = "type"))]
+    ty: String,
+
+    #[cfg_attr(feature = "fs", serde(default))]
+    components: Vec<RawParam>,
+}
+
+#[cfg(feature = "fs")]
+#[derive(Debug, Deserialize)]
+struct RawEntry {
+    #[serde(rename = "type", default)]
+    kind: String,
+
+    #[serde(default)]
+    inputs: Vec<RawParam>,
+}
+
+#[cfg(feature = "fs")]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawBytecode {
+    Hex(String),
+    Object { object: String },
+}
+
+#[cfg(feature = "fs")]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawAbi {
+    Entries(Vec<RawEntry>),
+    Artifact {
+        abi: Vec<RawEntry>,
+
+        #[serde(default)]
+        bytecode: Option<RawBytecode>,
+    },
+}
+
+/// The parameters of a contract's constructor, read from its ABI.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::abi::Constructor;
+///
+/// let abi = r#"[{"type":"constructor","inputs":[{"name":"supply","type":"uint256"}]}]"#;
+/// let constructor = Constructor::from_json(abi).unwrap();
+///
+/// let mut creation = vec![0x60, 0x00, 0x60, 0x00, 0xf3];
+/// creation.extend_from_slice(&[0; 31]);
+/// creation.push(100);
+///
+/// let (code, args) = constructor.split(&creation, None).unwrap();
+/// assert_eq!(code, &[0x60, 0x00, 0x60, 0x00, 0xf3]);
+///
+/// let args = constructor.decode(args).unwrap();
+/// assert_eq!(args[0].to_string(), "uint256 supply = 100");
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Constructor {
+    inputs: Vec<Param>,
+    code_len: Option<usize>,
+}
+
+impl Constructor {
+    /// Read the constructor from the JSON description of a contract's ABI.
+    ///
+    /// `json` may either be the ABI itself (an array of functions, events,
+    /// and so on), or a compiler artifact with an `abi` field. If the artifact
+    /// also has a `bytecode` field, its length is used to split the creation
+    /// code from the arguments.
+    ///
+    /// An ABI without a constructor describes one that takes no arguments.
+    #[cfg(feature = "fs")]
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let raw: RawAbi = serde_json::from_str(json).context(error::Json)?;
+
+        let (entries, bytecode) = match raw {
+            RawAbi::Entries(entries) => (entries, None),
+            RawAbi::Artifact { abi, bytecode } => (abi, bytecode),
+        };
+
+        let inputs = match entries.into_iter().find(|e| e.kind == "constructor") {
+            Some(entry) => entry
+                .inputs
+                .iter()
+                .map(|p| {
+                    Ok(Param {
+                        name: p.name.clone(),
+                        ty: ParamType::parse(&p.ty, &p.components)?,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+            None => Vec::new(),
+        };
+
+        // Placeholders for unlinked libraries aren't valid hexadecimal, but
+        // are the same width as the address that replaces them.
+        let code_len = bytecode
+            .map(|b| match b {
+                RawBytecode::Hex(text) => text,
+                RawBytecode::Object { object } => object,
+            })
+            .map(|text| text.trim_start_matches("0x").len() / 2)
+            .filter(|len| *len > 0);
+
+        Ok(Self { inputs, code_len })
+    }
+
+    /// The parameters of the constructor.
+    pub fn inputs(&self) -> &[Param] {
+        &self.inputs
+    }
+
+    /// The length of the creation code, if it was given in the artifact.
+    pub fn code_len(&self) -> Option<usize> {
+        self.code_len
+    }
+
+    /// Split the data of a creation transaction into the creation code and the
+    /// encoded constructor arguments.
+    ///
+    /// The length of the creation code is `code_len` if given, otherwise the
+    /// length of the artifact's bytecode. Without either, the length of the
+    /// arguments is calculated from their types, which is only possible when
+    /// none of them are dynamically sized.
+    pub fn split<'a>(
+        &self,
+        creation: &'a [u8],
+        code_len: Option<usize>,
+    ) -> Result<(&'a [u8], &'a [u8]), Error> {
+        let code_len = match code_len.or(self.code_len) {
+            Some(code_len) => code_len,
+            None => {
+                ensure!(
+                    !self.inputs.iter().any(|p| p.ty.is_dynamic()),
+                    error::DynamicArguments
+                );
+
+                let args: usize = self.inputs.iter().map(|p| p.ty.head_size()).sum();
+                creation.len().saturating_sub(args)
+            }
+        };
+
+        ensure!(
+            code_len <= creation.len(),
+            error::CodeTooLong {
+                code: code_len,
+                len: creation.len(),
+            }
+        );
+
+        Ok(creation.split_at(code_len))
+    }
+
+    /// Decode the encoded constructor arguments in `args`.
+    pub fn decode(&self, args: &[u8]) -> Result<Vec<Argument>, Error> {
+        let types: Vec<_> = self.inputs.iter().map(|p| p.ty.clone()).collect();
+        let values = decode(&types, args)?;
+
+        Ok(self
+            .inputs
+            .iter()
+            .cloned()
+            .zip(values)
+            .map(|(param, value)| Argument { param, value })
+            .collect())
+    }
+}
+
+/// Parse a comma separated list of types, as written in a function signature
+/// (for example `uint256,(address,bool)[2]`). Surrounding parentheses are
+/// optional.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::abi::{parse_types, ParamType};
+///
+/// let types = parse_types("(uint8,bytes32[2])").unwrap();
+/// assert_eq!(types[0], ParamType::Uint(8));
+/// assert_eq!(types[1].to_string(), "bytes32[2]");
+/// ```
+pub fn parse_types(text: &str) -> Result<Vec<ParamType>, Error> {
+    let text = text.trim();
+    let text = match text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+        // Only strip the parentheses if they enclose the whole list.
+        Some(inner) if split_types(inner).is_some() => inner,
+        _ => text,
+    };
+
+    let parts = split_types(text).context(error::UnsupportedType { ty: text })?;
+    parts
+        .into_iter()
+        .map(|ty| ParamType::parse(ty, &[]))
+        .collect()
+}
+
+/// Split `text` at the commas that aren't inside parentheses, or return
+/// `None` if the parentheses aren't balanced.
+fn split_types(text: &str) -> Option<Vec<&str>> {
+    if text.trim().is_empty() {
+        return Some(Vec::new());
+    }
+
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+
+    for (index, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.checked_sub(1)?,
+            ',' if depth == 0 => {
+                parts.push(&text[start..index]);
+                start = index + 1;
+            }
+            _ => (),
+        }
+    }
+
+    if depth != 0 {
+        return None;
+    }
+
+    parts.push(&text[start..]);
+    Some(parts)
+}
+
+/// Encode values of fixed size types, as if they were a tuple.
+///
+/// Every fixed size value is made of one or more 32 byte words, so `values`
+/// holds one integer per word, in order. For example, `uint8[2]` takes two
+/// values. `bytesN` values are `N` byte integers, so `0x01` as a `bytes2` is
+/// encoded as `0x0001`.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::abi::{encode_static, parse_types};
+///
+/// let types = parse_types("uint16,bool").unwrap();
+/// let encoded = encode_static(&types, &[0x1234.into(), 1.into()]).unwrap();
+///
+/// assert_eq!(encoded.len(), 64);
+/// assert_eq!(&encoded[30..32], &[0x12, 0x34]);
+/// assert_eq!(encoded[63], 1);
+/// ```
+pub fn encode_static(types: &[ParamType], values: &[BigInt]) -> Result<Vec<u8>, Error> {
+    let mut leaves = Vec::new();
+    for ty in types {
+        flatten(ty, &mut leaves)?;
+    }
+
+    ensure!(
+        leaves.len() == values.len(),
+        error::ValueCount {
+            expected: leaves.len(),
+            got: values.len(),
+        }
+    );
+
+    let mut encoded = Vec::with_capacity(WORD * values.len());
+    for (ty, value) in leaves.into_iter().zip(values) {
+        encoded.extend_from_slice(&encode_word(ty, value)?);
+    }
+
+    Ok(encoded)
+}
+
+/// Collect the one word types making up `ty`, in the order they're encoded.
+fn flatten<'a>(ty: &'a ParamType, leaves: &mut Vec<&'a ParamType>) -> Result<(), Error> {
+    match ty {
+        ParamType::Bytes | ParamType::String | ParamType::Array(_) => {
+            return error::DynamicType { ty: ty.to_string() }.fail()
+        }
+        ParamType::FixedArray(inner, len) => {
+            for _ in 0..*len {
+                flatten(inner, leaves)?;
+            }
+        }
+        ParamType::Tuple(items) => {
+            for item in items {
+                flatten(item, leaves)?;
+            }
+        }
+        _ => leaves.push(ty),
+    }
+
+    Ok(())
+}
+
+fn encode_word(ty: &ParamType, value: &BigInt) -> Result<[u8; WORD], Error> {
+    let one = BigInt::from(1u8);
+    let (min, max, shift) = match ty {
+        ParamType::Address => (BigInt::default(), &one << 160, 0),
+        ParamType::Bool => (BigInt::default(), BigInt::from(2u8), 0),
+        ParamType::Uint(bits) => (BigInt::default(), &one << *bits, 0),
+        ParamType::Int(bits) => (-(&one << (bits - 1)), &one << (bits - 1), 0),
+        ParamType::FixedBytes(len) => (BigInt::default(), &one << (len * 8), WORD - len),
+        _ => unreachable!("only one word types are encoded"),
+    };
+
+    ensure!(
+        *value >= min && *value < max,
+        error::ValueOutOfRange {
+            ty: ty.to_string(),
+            value: value.clone(),
+        }
+    );
+
+    // Negative numbers are encoded in two's complement, and `bytesN` values
+    // are aligned to the left of the word.
+    let word = if value.sign() == Sign::Minus {
+        (&one << (WORD * 8)) + value
+    } else {
+        value << (shift * 8)
+    };
+
+    let (_, bytes) = word.to_bytes_be();
+    let mut encoded = [0; WORD];
+    encoded[WORD - bytes.len()..].copy_from_slice(&bytes);
+    Ok(encoded)
+}
+
+/// Decode a sequence of values, encoded as if they were a tuple.
+pub fn decode(types: &[ParamType], data: &[u8]) -> Result<Vec<Value>, Error> {
+    decode_tuple(types, data, 0)
+}
+
+fn decode_tuple(types: &[ParamType], data: &[u8], start: usize) -> Result<Vec<Value>, Error> {
+    let mut head = start;
+    let mut values = Vec::with_capacity(types.len());
+
+    for ty in types {
+        let value = if ty.is_dynamic() {
+            let offset = read_usize(data, head)?;
+            let position = start.checked_add(offset).context(error::InvalidValue {
+                ty: ty.to_string(),
+                offset: head,
+            })?;
+            decode_value(ty, data, position)?
+        } else {
+            decode_value(ty, data, head)?
+        };
+
+        head += ty.head_size();
+        values.push(value);
+    }
+
+    Ok(values)
+}
+
+fn decode_value(ty: &ParamType, data: &[u8], offset: usize) -> Result<Value, Error> {
+    let invalid = || error::InvalidValue {
+        ty: ty.to_string(),
+        offset,
+    };
+
+    let value = match ty {
+        ParamType::Address => {
+            let word = read(data, offset, WORD)?;
+            ensure!(word[..12].iter().all(|b| *b == 0), invalid());
+            let mut address = [0; 20];
+            address.copy_from_slice(&word[12..]);
+            Value::Address(address)
+        }
+        ParamType::Bool => match read_uint(data, offset)? {
+            v if v == BigUint::from(0u8) => Value::Bool(false),
+            v if v == BigUint::from(1u8) => Value::Bool(true),
+            _ => return invalid().fail(),
+        },
+        ParamType::Uint(bits) => {
+            let value = read_uint(data, offset)?;
+            ensure!(value.bits() <= *bits as u64, invalid());
+            Value::Uint(value)
+        }
+        ParamType::Int(bits) => {
+            let word = read(data, offset, WORD)?;
+            let mut value = BigInt::from_bytes_be(Sign::Plus, word);
+            if word[0] & 0x80 != 0 {
+                value -= BigInt::from(1u8) << (WORD * 8);
+            }
+
+            let limit = BigInt::from(1u8) << (bits - 1);
+            ensure!(value >= -limit.clone() && value < limit, invalid());
+            Value::Int(value)
+        }
+        ParamType::FixedBytes(len) => {
+            let word = read(data, offset, WORD)?;
+            ensure!(word[*len..].iter().all(|b| *b == 0), invalid());
+            Value::Bytes(word[..*len].to_vec())
+        }
+        ParamType::Bytes => Value::Bytes(read_bytes(data, offset)?.to_vec()),
+        ParamType::String => {
+            let bytes = read_bytes(data, offset)?.to_vec();
+            Value::String(String::from_utf8(bytes).ok().with_context(invalid)?)
+        }
+        ParamType::Array(inner) => {
+            let len = read_usize(data, offset)?;
+
+            // Every item takes at least one word, which bounds the length
+            // before anything is allocated.
+            let remaining = data.len().saturating_sub(offset + WORD) / WORD;
+            ensure!(len <= remaining, invalid());
+
+            let types = vec![(**inner).clone(); len];
+            Value::Array(decode_tuple(&types, data, offset + WORD)?)
+        }
+        ParamType::FixedArray(inner, len) => {
+            let remaining = data.len().saturating_sub(offset) / WORD;
+            ensure!(*len <= remaining, invalid());
+
+            let types = vec![(**inner).clone(); *len];
+            Value::Array(decode_tuple(&types, data, offset)?)
+        }
+        ParamType::Tuple(items) => Value::Tuple(decode_tuple(items, data, offset)?),
+    };
+
+    Ok(value)
+}
+
+fn read(data: &[u8], offset: usize, size: usize) -> Result<&[u8], Error> {
+    offset
+        .checked_add(size)
+        .and_then(|end| data.get(offset..end))
+        .context(error::OutOfBounds {
+            offset,
+            size,
+            len: data.len(),
+        })
+}
+
+fn read_uint(data: &[u8], offset: usize) -> Result<BigUint, Error> {
+    Ok(BigUint::from_bytes_be(read(data, offset, WORD)?))
+}
+
+fn read_usize(data: &[u8], offset: usize) -> Result<usize, Error> {
+    let word = read(data, offset, WORD)?;
+    let (high, low) = word.split_at(WORD - 8);
+
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(low);
+    let value = u64::from_be_bytes(bytes);
+
+    match usize::try_from(value) {
+        Ok(value) if high.iter().all(|b| *b == 0) => Ok(value),
+        _ => error::InvalidValue {
+            ty: "uint256",
+            offset,
+        }
+        .fail(),
+    }
+}
+
+fn read_bytes(data: &[u8], offset: usize) -> Result<&[u8], Error> {
+    let len = read_usize(data, offset)?;
+    read(data, offset + WORD, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    #[cfg(feature = "fs")]
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[cfg(feature = "fs")]
+    fn constructor(inputs: &str) -> Constructor {
+        let json = format!(r#"[{{"type":"constructor","inputs":{}}}]"#, inputs);
+        Constructor::from_json(&json).unwrap()
+    }
+
+    #[cfg(feature = "fs")]
+    fn strings(args: Vec<Argument>) -> Vec<String> {
+        args.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn parse_constructor_types() {
+        let constructor = constructor(
+            r#"[
+                {"name":"a","type":"uint"},
+                {"name":"b","type":"bytes4[2][]"},
+                {"name":"c","type":"tuple","components":[
+                    {"name":"x","type":"int8"},
+                    {"name":"y","type":"string"}
+                ]}
+            ]"#,
+        );
+
+        let types: Vec<_> = constructor
+            .inputs()
+            .iter()
+            .map(|p| p.ty.to_string())
+            .collect();
+        assert_eq!(types, ["uint256", "bytes4[2][]", "(int8,string)"]);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn parse_unsupported_type() {
+        let json = r#"[{"type":"constructor","inputs":[{"type":"uint7"}]}]"#;
+        assert_matches!(
+            Constructor::from_json(json),
+            Err(Error::UnsupportedType { ty, .. }) if ty == "uint7"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn decode_static() {
+        let constructor = constructor(
+            r#"[
+                {"name":"owner","type":"address"},
+                {"name":"paused","type":"bool"},
+                {"name":"delta","type":"int16"},
+                {"name":"tag","type":"bytes2"}
+            ]"#,
+        );
+
+        let data = hex!(
+            "0000000000000000000000001111111111111111111111111111111111111111"
+            "0000000000000000000000000000000000000000000000000000000000000001"
+            "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe"
+            "abcd000000000000000000000000000000000000000000000000000000000000"
+        );
+
+        assert_eq!(
+            strings(constructor.decode(&data).unwrap()),
+            [
+                "address owner = 0x1111111111111111111111111111111111111111",
+                "bool paused = true",
+                "int16 delta = -2",
+                "bytes2 tag = 0xabcd",
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn decode_dynamic() {
+        let constructor = constructor(
+            r#"[
+                {"name":"name","type":"string"},
+                {"name":"values","type":"uint8[]"},
+                {"type":"uint256"}
+            ]"#,
+        );
+
+        let data = hex!(
+            "0000000000000000000000000000000000000000000000000000000000000060"
+            "00000000000000000000000000000000000000000000000000000000000000a0"
+            "0000000000000000000000000000000000000000000000000000000000000007"
+            "0000000000000000000000000000000000000000000000000000000000000003"
+            "6574680000000000000000000000000000000000000000000000000000000000"
+            "0000000000000000000000000000000000000000000000000000000000000002"
+            "0000000000000000000000000000000000000000000000000000000000000001"
+            "0000000000000000000000000000000000000000000000000000000000000002"
+        );
+
+        assert_eq!(
+            strings(constructor.decode(&data).unwrap()),
+            [
+                "string name = \"eth\"",
+                "uint8[] values = [1, 2]",
+                "uint256 = 7"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn decode_invalid() {
+        let constructor = constructor(r#"[{"name":"flag","type":"bool"}]"#);
+
+        let data = hex!("0000000000000000000000000000000000000000000000000000000000000002");
+        assert_matches!(
+            constructor.decode(&data),
+            Err(Error::InvalidValue { ty, offset: 0, .. }) if ty == "bool"
+        );
+
+        assert_matches!(
+            constructor.decode(&data[1..]),
+            Err(Error::OutOfBounds {
+                offset: 0,
+                size: 32,
+                len: 31,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let types = parse_types("uint8, int16, address, bytes2[2], (bool,uint256)").unwrap();
+        let values: Vec<BigInt> = vec![
+            7.into(),
+            (-2).into(),
+            0x11.into(),
+            0xabcd.into(),
+            0x01.into(),
+            1.into(),
+            5.into(),
+        ];
+
+        let encoded = encode_static(&types, &values).unwrap();
+        assert_eq!(encoded.len(), 7 * WORD);
+
+        let decoded: Vec<_> = decode(&types, &encoded)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            decoded,
+            [
+                "7",
+                "-2",
+                "0x0000000000000000000000000000000000000011",
+                "[0xabcd, 0x0001]",
+                "(true, 5)",
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_errors() {
+        let uint8 = [ParamType::Uint(8)];
+        assert_matches!(
+            encode_static(&uint8, &[256.into()]),
+            Err(Error::ValueOutOfRange { ty, .. }) if ty == "uint8"
+        );
+        assert_matches!(
+            encode_static(&uint8, &[]),
+            Err(Error::ValueCount {
+                expected: 1,
+                got: 0,
+                ..
+            })
+        );
+        assert_matches!(
+            encode_static(&parse_types("string").unwrap(), &[]),
+            Err(Error::DynamicType { ty, .. }) if ty == "string"
+        );
+        assert_matches!(parse_types("(uint8"), Err(Error::UnsupportedType { .. }));
+        assert!(parse_types("()").unwrap().is_empty());
+        assert_eq!(
+            parse_types("(uint8),(bool)").unwrap(),
+            [
+                ParamType::Tuple(vec![ParamType::Uint(8)]),
+                ParamType::Tuple(vec![ParamType::Bool]),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn split_creation() {
+        let creation = hex!(
+            "6000600055"
+            "000000000000000000000000000000000000000000000000000000000000002a"
+        );
+
+        let fixed = constructor(r#"[{"name":"x","type":"uint256"}]"#);
+        let (code, args) = fixed.split(&creation, None).unwrap();
+        assert_eq!(code, hex!("6000600055"));
+        assert_eq!(strings(fixed.decode(args).unwrap()), ["uint256 x = 42"]);
+
+        let dynamic = constructor(r#"[{"name":"x","type":"bytes"}]"#);
+        assert_matches!(
+            dynamic.split(&creation, None),
+            Err(Error::DynamicArguments { .. })
+        );
+        assert_eq!(dynamic.split(&creation, Some(5)).unwrap().0, code);
+
+        let artifact = r#"{"abi":[],"bytecode":{"object":"0x6000600055"}}"#;
+        let artifact = Constructor::from_json(artifact).unwrap();
+        assert_eq!(artifact.code_len(), Some(5));
+        assert_eq!(artifact.split(&creation, None).unwrap().0, code);
+
+        assert_matches!(
+            fixed.split(&creation, Some(100)),
+            Err(Error::CodeTooLong {
+                code: 100,
+                len: 37,
+                ..
+            })
+        );
+    }
+}
@@ -0,0 +1,950 @@
+//! Static analysis of assembled instructions.
+//!
+//! The [`Assembler`] runs [`check_stack`] on every program it assembles, and
+//! reports problems as [`Warning`]s. [`golf`] is advisory, and only suggests
+//! ways to make code smaller. [`check_control_flow`] summarizes whether every
+//! jump and path through a program can be accounted for.
+//!
+//! [`Assembler`]: crate::asm::Assembler
+use crate::disasm::Offset;
+use crate::ops::is_push;
+use crate::warning::Warning;
+
+use etk_ops::prague::{Op, Operation, Push0};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+/// Maximum number of items on the EVM stack.
+pub const STACK_LIMIT: usize = 1024;
+
+/// Track the stack height along every control flow edge reachable from the
+/// start of `ops`, and report any instruction that would underflow or
+/// overflow the stack.
+///
+/// Execution is assumed to begin at offset zero with an empty stack. Jumps are
+/// followed when their destination is pushed by the instruction immediately
+/// before them; other jumps end the path, since their destination isn't known.
+/// Gaps between instructions (for example, raw bytes from `%include_hex`) are
+/// never executed.
+pub fn check_stack(ops: &[Offset<Op<[u8]>>]) -> Vec<Warning> {
+    check_stack_limit(ops, STACK_LIMIT)
+}
+
+/// Like [`check_stack`], but the stack can hold at most `limit` items instead
+/// of [`STACK_LIMIT`].
+pub fn check_stack_limit(ops: &[Offset<Op<[u8]>>], limit: usize) -> Vec<Warning> {
+    let index: HashMap<usize, usize> = ops
+        .iter()
+        .enumerate()
+        .map(|(idx, op)| (op.offset, idx))
+        .collect();
+
+    let mut pending = match index.get(&0) {
+        Some(idx) => vec![(*idx, 0)],
+        None => return Vec::new(),
+    };
+
+    // The lowest and highest stack heights each instruction has been reached
+    // with. Heights in between can't underflow or overflow unless one of
+    // those two does.
+    let mut seen: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut issues = BTreeMap::new();
+
+    while let Some((idx, height)) = pending.pop() {
+        match seen.get_mut(&idx) {
+            Some((min, max)) if *min <= height && height <= *max => continue,
+            Some((min, max)) => {
+                *min = (*min).min(height);
+                *max = (*max).max(height);
+            }
+            None => {
+                seen.insert(idx, (height, height));
+            }
+        }
+
+        let Offset { offset, item: op } = &ops[idx];
+
+        if op.pops() > height {
+            issues
+                .entry(*offset)
+                .or_insert_with(|| Warning::StackUnderflow {
+                    offset: *offset,
+                    op: op.code(),
+                    height,
+                });
+            continue;
+        }
+
+        let after = height - op.pops() + op.pushes();
+        if after > limit {
+            issues
+                .entry(*offset)
+                .or_insert_with(|| Warning::StackOverflow {
+                    offset: *offset,
+                    op: op.code(),
+                });
+            continue;
+        }
+
+        if op.is_exit() {
+            continue;
+        }
+
+        let next = ops
+            .get(idx + 1)
+            .filter(|next| next.offset == offset + op.size())
+            .map(|_| idx + 1);
+
+        match op {
+            Op::Jump(_) | Op::JumpI(_) => {
+                let target = static_target(ops, idx)
+                    .and_then(|target| index.get(&target))
+                    .filter(|target| ops[**target].item.is_jump_target());
+
+                if let Some(target) = target {
+                    pending.push((*target, after));
+                }
+
+                if let (Op::JumpI(_), Some(next)) = (op, next) {
+                    pending.push((next, after));
+                }
+            }
+            _ => {
+                if let Some(next) = next {
+                    pending.push((next, after));
+                }
+            }
+        }
+    }
+
+    issues.into_values().collect()
+}
+
+/// Items on the stack, with the top last, each identified by a number given
+/// when it was pushed. Items from before the code being followed are added at
+/// the bottom as they're used.
+#[derive(Debug, Default)]
+struct Items {
+    stack: Vec<usize>,
+    next: usize,
+}
+
+impl Items {
+    fn fresh(&mut self) -> usize {
+        self.next += 1;
+        self.next
+    }
+
+    fn reserve(&mut self, len: usize) {
+        while self.stack.len() < len {
+            let item = self.fresh();
+            self.stack.insert(0, item);
+        }
+    }
+
+    /// The item `depth` items below the top.
+    fn get(&mut self, depth: usize) -> usize {
+        self.reserve(depth + 1);
+        self.stack[self.stack.len() - depth - 1]
+    }
+
+    /// How many items below the top `item` is, if it's still on the stack.
+    fn depth(&self, item: usize) -> Option<usize> {
+        self.stack.iter().rev().position(|i| *i == item)
+    }
+
+    fn execute(&mut self, op: &Op<[u8]>) {
+        let code = op.code_byte();
+
+        match code {
+            0x80..=0x8f => {
+                let item = self.get(usize::from(code - 0x80));
+                self.stack.push(item);
+            }
+            0x90..=0x9f => {
+                let depth = usize::from(code - 0x90) + 1;
+                self.reserve(depth + 1);
+                let len = self.stack.len();
+                self.stack.swap(len - 1, len - 1 - depth);
+            }
+            _ => {
+                self.reserve(op.pops());
+                self.stack.truncate(self.stack.len() - op.pops());
+                for _ in 0..op.pushes() {
+                    let item = self.fresh();
+                    self.stack.push(item);
+                }
+            }
+        }
+    }
+}
+
+/// Check that the stack items named by `# keep` comments are where the
+/// comments claim.
+///
+/// Each entry of `keeps` is the position of a comment in the code, and the
+/// names it gives to the items on the stack, from the top (`None` for items
+/// that aren't named). The first comment giving a name picks the item it
+/// refers to. Later comments giving the same name are checked against where
+/// the instructions in between moved that item, and reported as
+/// [`Warning::KeepMismatch`] if it isn't there.
+///
+/// Items are only followed through straight-line code: names are forgotten
+/// at a `jumpdest`, after an exit or jump, and at gaps between instructions.
+pub fn check_keep(
+    ops: &[Offset<Op<[u8]>>],
+    keeps: &[(usize, Vec<Option<String>>)],
+) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let mut keeps = keeps.iter().peekable();
+    let mut items = Items::default();
+    let mut names: HashMap<&str, usize> = HashMap::new();
+
+    for (idx, Offset { offset, item: op }) in ops.iter().enumerate() {
+        while let Some((position, slots)) = keeps.next_if(|(position, _)| position <= offset) {
+            check_slots(&mut items, &mut names, *position, slots, &mut warnings);
+        }
+
+        let contiguous = idx
+            .checked_sub(1)
+            .map(|prev| ops[prev].offset + ops[prev].item.size() == *offset)
+            .unwrap_or(true);
+
+        if !contiguous || op.is_jump_target() {
+            items = Items::default();
+            names.clear();
+        }
+
+        items.execute(op);
+
+        if op.is_exit() || matches!(op, Op::Jump(_) | Op::RJump(_)) {
+            items = Items::default();
+            names.clear();
+        }
+    }
+
+    for (position, slots) in keeps {
+        check_slots(&mut items, &mut names, *position, slots, &mut warnings);
+    }
+
+    warnings
+}
+
+/// Name the items on the stack at `offset` after one `# keep` comment,
+/// reporting the names that were already given to other items.
+fn check_slots<'a>(
+    items: &mut Items,
+    names: &mut HashMap<&'a str, usize>,
+    offset: usize,
+    slots: &'a [Option<String>],
+    warnings: &mut Vec<Warning>,
+) {
+    for (depth, name) in slots.iter().enumerate() {
+        let name = match name {
+            Some(name) => name.as_str(),
+            None => continue,
+        };
+
+        let item = items.get(depth);
+        let previous = names.insert(name, item);
+
+        if let Some(previous) = previous.filter(|p| *p != item) {
+            warnings.push(Warning::KeepMismatch {
+                offset,
+                name: name.to_owned(),
+                expected: depth + 1,
+                found: items.depth(previous).map(|d| d + 1),
+            });
+        }
+    }
+}
+
+/// The destination of the jump at `idx`, if it was pushed by the instruction
+/// immediately before it.
+fn static_target(ops: &[Offset<Op<[u8]>>], idx: usize) -> Option<usize> {
+    let prev = ops.get(idx.checked_sub(1)?)?;
+    if prev.offset + prev.item.size() != ops[idx].offset || !is_push(&prev.item.code()) {
+        return None;
+    }
+
+    immediate_usize(prev.item.immediate()?)
+}
+
+/// The result of [`check_control_flow`].
+///
+/// A program is verified when every reachable jump has a known, valid
+/// destination, every `jumpdest` is reachable, and no path runs past the end
+/// of the code without an exit.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ControlFlowReport {
+    /// Number of reachable `jump` and `jumpi` instructions.
+    pub jumps: usize,
+
+    /// Offsets of reachable jumps whose destination isn't pushed immediately
+    /// before them.
+    pub dynamic_jumps: Vec<usize>,
+
+    /// Offsets of reachable jumps, and their destinations, where the
+    /// destination isn't a `jumpdest`.
+    pub invalid_jumps: Vec<(usize, usize)>,
+
+    /// Offsets of `jumpdest`s that no path from the start of the code
+    /// reaches.
+    pub unreachable_jumpdests: Vec<usize>,
+
+    /// Offsets of instructions after which execution runs past the end of
+    /// the code (or into data), instead of stopping with an exit.
+    pub falls_off_end: Vec<usize>,
+}
+
+impl ControlFlowReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_verified(&self) -> bool {
+        self.dynamic_jumps.is_empty()
+            && self.invalid_jumps.is_empty()
+            && self.unreachable_jumpdests.is_empty()
+            && self.falls_off_end.is_empty()
+    }
+}
+
+impl fmt::Display for ControlFlowReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn offsets(f: &mut fmt::Formatter, offsets: &[usize]) -> fmt::Result {
+            if offsets.is_empty() {
+                return writeln!(f, "none");
+            }
+
+            let text: Vec<_> = offsets.iter().map(|o| format!("{:#x}", o)).collect();
+            writeln!(f, "{}", text.join(", "))
+        }
+
+        let verdict = if self.is_verified() {
+            "verified"
+        } else {
+            "not verified"
+        };
+        writeln!(f, "control flow integrity: {}", verdict)?;
+
+        write!(
+            f,
+            " - jumps with unknown destinations (of {}): ",
+            self.jumps
+        )?;
+        offsets(f, &self.dynamic_jumps)?;
+
+        write!(f, " - jumps to a non-jumpdest: ")?;
+        if self.invalid_jumps.is_empty() {
+            writeln!(f, "none")?;
+        } else {
+            let text: Vec<_> = self
+                .invalid_jumps
+                .iter()
+                .map(|(jump, target)| format!("{:#x} -> {:#x}", jump, target))
+                .collect();
+            writeln!(f, "{}", text.join(", "))?;
+        }
+
+        write!(f, " - unreachable jumpdests: ")?;
+        offsets(f, &self.unreachable_jumpdests)?;
+
+        write!(f, " - paths running past the end of the code: ")?;
+        offsets(f, &self.falls_off_end)
+    }
+}
+
+/// Follow every control flow edge reachable from the start of `ops`, and
+/// report whether all jumps, `jumpdest`s, and paths can be accounted for.
+///
+/// Jumps are static, and followed, when their destination is pushed by the
+/// instruction immediately before them, like in [`check_stack`]. Since the
+/// destinations of dynamic jumps aren't known, a `jumpdest` reachable only
+/// through one is reported as unreachable.
+pub fn check_control_flow(ops: &[Offset<Op<[u8]>>]) -> ControlFlowReport {
+    let index: HashMap<usize, usize> = ops
+        .iter()
+        .enumerate()
+        .map(|(idx, op)| (op.offset, idx))
+        .collect();
+
+    let mut pending: Vec<usize> = index.get(&0).copied().into_iter().collect();
+    let mut seen = HashSet::new();
+
+    let mut jumps = 0;
+    let mut dynamic_jumps = BTreeSet::new();
+    let mut invalid_jumps = BTreeSet::new();
+    let mut falls_off_end = BTreeSet::new();
+
+    while let Some(idx) = pending.pop() {
+        if !seen.insert(idx) {
+            continue;
+        }
+
+        let Offset { offset, item: op } = &ops[idx];
+        if op.is_exit() {
+            continue;
+        }
+
+        let next = ops
+            .get(idx + 1)
+            .filter(|next| next.offset == offset + op.size())
+            .map(|_| idx + 1);
+
+        if let Op::Jump(_) | Op::JumpI(_) = op {
+            jumps += 1;
+
+            match static_target(ops, idx) {
+                Some(target) => match index.get(&target) {
+                    Some(target) if ops[*target].item.is_jump_target() => pending.push(*target),
+                    _ => {
+                        invalid_jumps.insert((*offset, target));
+                    }
+                },
+                None => {
+                    dynamic_jumps.insert(*offset);
+                }
+            }
+
+            if let Op::Jump(_) = op {
+                continue;
+            }
+        }
+
+        match next {
+            Some(next) => pending.push(next),
+            None => {
+                falls_off_end.insert(*offset);
+            }
+        }
+    }
+
+    let unreachable_jumpdests = ops
+        .iter()
+        .enumerate()
+        .filter(|(idx, op)| op.item.is_jump_target() && !seen.contains(idx))
+        .map(|(_, op)| op.offset)
+        .collect();
+
+    ControlFlowReport {
+        jumps,
+        dynamic_jumps: dynamic_jumps.into_iter().collect(),
+        invalid_jumps: invalid_jumps.into_iter().collect(),
+        unreachable_jumpdests,
+        falls_off_end: falls_off_end.into_iter().collect(),
+    }
+}
+
+/// A known size optimization found by [`golf`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Suggestion {
+    /// A constant is pushed while the same value is already near the top of
+    /// the stack.
+    #[non_exhaustive]
+    DupConstant {
+        /// Location of the push.
+        offset: usize,
+
+        /// The push instruction.
+        push: Op<()>,
+
+        /// The instruction that duplicates the existing value.
+        dup: Op<()>,
+    },
+
+    /// Two consecutive `iszero` instructions.
+    #[non_exhaustive]
+    DoubleIsZero {
+        /// Location of the first `iszero`.
+        offset: usize,
+
+        /// Whether the pair is immediately followed by a `jumpi`, which
+        /// treats any non-zero value as true.
+        before_jumpi: bool,
+    },
+
+    /// A push with leading zero bytes in its immediate.
+    #[non_exhaustive]
+    OversizedPush {
+        /// Location of the push.
+        offset: usize,
+
+        /// The push instruction.
+        push: Op<()>,
+
+        /// The smallest push of the same value.
+        minimum: Op<()>,
+    },
+
+    /// A `jumpdest` whose location is never pushed, so no static jump can
+    /// reach it.
+    #[non_exhaustive]
+    UnusedJumpDest {
+        /// Location of the `jumpdest`.
+        offset: usize,
+    },
+}
+
+impl Suggestion {
+    /// Location of the code the suggestion applies to.
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::DupConstant { offset, .. }
+            | Self::DoubleIsZero { offset, .. }
+            | Self::OversizedPush { offset, .. }
+            | Self::UnusedJumpDest { offset } => *offset,
+        }
+    }
+
+    /// How many bytes following the suggestion would save.
+    pub fn savings(&self) -> usize {
+        match self {
+            Self::DupConstant { push, dup, .. } => push.size() - dup.size(),
+            Self::DoubleIsZero { .. } => 2,
+            Self::OversizedPush { push, minimum, .. } => push.size() - minimum.size(),
+            Self::UnusedJumpDest { .. } => 1,
+        }
+    }
+}
+
+impl fmt::Display for Suggestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:x}: ", self.offset())?;
+
+        match self {
+            Self::DupConstant { push, dup, .. } => write!(
+                f,
+                "value is already on the stack, replace `{}` with `{}`",
+                push, dup
+            )?,
+            Self::DoubleIsZero {
+                before_jumpi: true, ..
+            } => write!(f, "`iszero; iszero` before `jumpi` can be removed")?,
+            Self::DoubleIsZero {
+                before_jumpi: false,
+                ..
+            } => write!(
+                f,
+                "`iszero; iszero` can be removed if the result is only used as a condition"
+            )?,
+            Self::OversizedPush { push, minimum, .. } => {
+                write!(f, "replace `{}` with `{}`", push, minimum)?
+            }
+            Self::UnusedJumpDest { .. } => write!(
+                f,
+                "`jumpdest` is never a static jump target, and can be removed"
+            )?,
+        }
+
+        write!(f, " (saves {} byte(s))", self.savings())
+    }
+}
+
+/// Scan `ops` for well-known inefficiencies, without modifying anything.
+///
+/// Suggestions are only hints: for example, a `jumpdest` reached by a computed
+/// jump is reported as unused.
+pub fn golf(ops: &[Offset<Op<[u8]>>]) -> Vec<Suggestion> {
+    let mut suggestions = Vec::new();
+
+    let pushed: HashSet<usize> = ops
+        .iter()
+        .filter(|op| is_push(&op.item.code()))
+        .filter_map(|op| immediate_usize(op.item.immediate()?))
+        .collect();
+
+    // Constants known to be on the stack, with the top of the stack last.
+    // Instructions that may be reached from elsewhere clear it.
+    let mut stack: Vec<Option<Vec<u8>>> = Vec::new();
+
+    for (idx, Offset { offset, item: op }) in ops.iter().enumerate() {
+        let contiguous = idx
+            .checked_sub(1)
+            .map(|prev| ops[prev].offset + ops[prev].item.size() == *offset)
+            .unwrap_or(true);
+
+        if !contiguous || op.is_jump_target() {
+            stack.clear();
+        }
+
+        let next = ops.get(idx + 1).map(|next| &next.item);
+
+        match op {
+            Op::JumpDest(_) if !pushed.contains(offset) => {
+                suggestions.push(Suggestion::UnusedJumpDest { offset: *offset });
+            }
+            Op::IsZero(_) if matches!(next, Some(Op::IsZero(_))) => {
+                let before_jumpi =
+                    matches!(ops.get(idx + 2), Some(o) if matches!(o.item, Op::JumpI(_)));
+                suggestions.push(Suggestion::DoubleIsZero {
+                    offset: *offset,
+                    before_jumpi,
+                });
+            }
+            _ => (),
+        }
+
+        if let Some(imm) = op.immediate().filter(|_| is_push(&op.code())) {
+            let value = trim(imm);
+
+            // `push0` is already as small as a dup, and cheaper to execute.
+            let depth = stack
+                .iter()
+                .rev()
+                .take(16)
+                .position(|v| !value.is_empty() && v.as_deref() == Some(value));
+
+            if let Some(depth) = depth {
+                suggestions.push(Suggestion::DupConstant {
+                    offset: *offset,
+                    push: op.code(),
+                    dup: Op::from(0x80 + depth as u8),
+                });
+            } else if value.len() < imm.len() {
+                let minimum = match value.len() {
+                    0 => Op::from(Push0),
+                    len => Op::push(len).unwrap(),
+                };
+
+                suggestions.push(Suggestion::OversizedPush {
+                    offset: *offset,
+                    push: op.code(),
+                    minimum,
+                });
+            }
+        }
+
+        simulate(&mut stack, op);
+
+        if op.is_exit() || matches!(op, Op::Jump(_) | Op::RJump(_)) {
+            stack.clear();
+        }
+    }
+
+    suggestions
+}
+
+/// Update the constants known to be on the stack after executing `op`.
+fn simulate(stack: &mut Vec<Option<Vec<u8>>>, op: &Op<[u8]>) {
+    let code = op.code_byte();
+    let len = stack.len();
+
+    match code {
+        0x5f => stack.push(Some(Vec::new())),
+        0x60..=0x7f => stack.push(Some(trim(op.immediate().unwrap()).to_vec())),
+        0x80..=0x8f => {
+            let depth = usize::from(code - 0x80) + 1;
+            let value = len.checked_sub(depth).and_then(|i| stack[i].clone());
+            stack.push(value);
+        }
+        0x90..=0x9f => {
+            let depth = usize::from(code - 0x90) + 1;
+            match len.checked_sub(depth + 1) {
+                Some(other) => stack.swap(other, len - 1),
+                None => {
+                    if let Some(top) = stack.last_mut() {
+                        *top = None;
+                    }
+                }
+            }
+        }
+        _ => {
+            stack.truncate(len.saturating_sub(op.pops()));
+            stack.extend(std::iter::repeat(None).take(op.pushes()));
+        }
+    }
+}
+
+fn trim(imm: &[u8]) -> &[u8] {
+    let zeros = imm.iter().take_while(|b| **b == 0).count();
+    &imm[zeros..]
+}
+
+fn immediate_usize(imm: &[u8]) -> Option<usize> {
+    imm.iter().try_fold(0usize, |acc, byte| {
+        acc.checked_mul(256)?.checked_add(usize::from(*byte))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disasm::Disassembler;
+
+    use etk_ops::prague::*;
+
+    use hex_literal::hex;
+
+    use std::io::Write;
+
+    use super::*;
+
+    fn disassemble(code: &[u8]) -> Vec<Offset<Op<[u8]>>> {
+        let mut dasm = Disassembler::<Op<[u8]>>::default();
+        dasm.write_all(code).unwrap();
+        let ops: Vec<_> = dasm.ops().collect();
+        dasm.finish().unwrap();
+        ops
+    }
+
+    fn check(code: &[u8]) -> Vec<Warning> {
+        check_stack(&disassemble(code))
+    }
+
+    #[test]
+    fn empty() {
+        assert!(check(&[]).is_empty());
+    }
+
+    #[test]
+    fn balanced() {
+        assert!(check(&hex!("600160020100")).is_empty());
+    }
+
+    #[test]
+    fn underflow() {
+        assert_eq!(
+            check(&hex!("600101")),
+            [Warning::StackUnderflow {
+                offset: 2,
+                op: Op::from(Add),
+                height: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn underflow_after_jumpi() {
+        // push1 1; push1 7; jumpi; pop; stop; jumpdest; stop
+        assert_eq!(
+            check(&hex!("600160075750005b00")),
+            [Warning::StackUnderflow {
+                offset: 5,
+                op: Op::from(Pop),
+                height: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn overflow_in_loop() {
+        // jumpdest; pc; push1 0; jump
+        assert_eq!(
+            check(&hex!("5b58600056")),
+            [Warning::StackOverflow {
+                offset: 2,
+                op: Op::from(Push1(())),
+            }]
+        );
+    }
+
+    #[test]
+    fn dynamic_jump() {
+        assert!(check(&hex!("3456")).is_empty());
+    }
+
+    #[test]
+    fn jump_to_non_jumpdest() {
+        // push1 3; jump; pop
+        assert!(check(&hex!("60035650")).is_empty());
+    }
+
+    fn keep(position: usize, slots: &[&str]) -> (usize, Vec<Option<String>>) {
+        let slots = slots
+            .iter()
+            .map(|s| Some(s.to_string()).filter(|s| s != "_"))
+            .collect();
+        (position, slots)
+    }
+
+    #[test]
+    fn keep_follows_items() {
+        // caller callvalue swap1 dup2 pop
+        let ops = disassemble(&hex!("33 34 90 81 50"));
+        let keeps = [
+            keep(2, &["value", "from"]),
+            keep(3, &["from", "value"]),
+            keep(5, &["from", "value"]),
+        ];
+        assert!(check_keep(&ops, &keeps).is_empty());
+
+        let keeps = [keep(2, &["value", "from"]), keep(4, &["from"])];
+        assert_eq!(
+            check_keep(&ops, &keeps),
+            [Warning::KeepMismatch {
+                offset: 4,
+                name: "from".into(),
+                expected: 1,
+                found: Some(2),
+            }]
+        );
+    }
+
+    #[test]
+    fn keep_consumed_item() {
+        // caller callvalue add
+        let ops = disassemble(&hex!("33 34 01"));
+        let keeps = [keep(2, &["value"]), keep(3, &["value"])];
+        assert_eq!(
+            check_keep(&ops, &keeps),
+            [Warning::KeepMismatch {
+                offset: 3,
+                name: "value".into(),
+                expected: 1,
+                found: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn keep_forgets_at_jumpdest() {
+        // caller jumpdest callvalue
+        let ops = disassemble(&hex!("33 5b 34"));
+        let keeps = [keep(1, &["a"]), keep(3, &["_", "a"])];
+        assert!(check_keep(&ops, &keeps).is_empty());
+    }
+
+    #[test]
+    fn control_flow_verified() {
+        // push1 4; jump; invalid; jumpdest; stop
+        let report = check_control_flow(&disassemble(&hex!("600456fe5b00")));
+        assert!(report.is_verified(), "{}", report);
+        assert_eq!(report.jumps, 1);
+
+        // jumpdest; push1 0; jumpi
+        let report = check_control_flow(&disassemble(&hex!("5b600057")));
+        assert_eq!(report.jumps, 1);
+        assert!(report.invalid_jumps.is_empty());
+        assert_eq!(report.falls_off_end, [3]);
+        assert!(!report.is_verified());
+    }
+
+    #[test]
+    fn control_flow_problems() {
+        // callvalue; jump; push1 2; jump
+        let report = check_control_flow(&disassemble(&hex!("3456600256")));
+        assert_eq!(
+            report,
+            ControlFlowReport {
+                jumps: 1,
+                dynamic_jumps: vec![1],
+                ..Default::default()
+            }
+        );
+
+        // push1 6; jumpi; jumpdest; caller
+        let report = check_control_flow(&disassemble(&hex!("6006575b33")));
+        assert_eq!(
+            report,
+            ControlFlowReport {
+                jumps: 1,
+                invalid_jumps: vec![(2, 6)],
+                falls_off_end: vec![4],
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            report.to_string(),
+            "control flow integrity: not verified\n \
+             - jumps with unknown destinations (of 1): none\n \
+             - jumps to a non-jumpdest: 0x2 -> 0x6\n \
+             - unreachable jumpdests: none\n \
+             - paths running past the end of the code: 0x4\n"
+        );
+    }
+
+    #[test]
+    fn golf_dup_constant() {
+        // push2 0x1234; caller; push2 0x1234
+        assert_eq!(
+            golf(&disassemble(&hex!("61123433611234"))),
+            [Suggestion::DupConstant {
+                offset: 4,
+                push: Op::from(Push2(())),
+                dup: Op::from(Dup2),
+            }]
+        );
+    }
+
+    #[test]
+    fn golf_dup_constant_after_swap() {
+        // push1 1; push1 2; swap1; pop; push1 2
+        assert_eq!(
+            golf(&disassemble(&hex!("60016002905060026000"))),
+            [
+                Suggestion::DupConstant {
+                    offset: 6,
+                    push: Op::from(Push1(())),
+                    dup: Op::from(Dup1),
+                },
+                Suggestion::OversizedPush {
+                    offset: 8,
+                    push: Op::from(Push1(())),
+                    minimum: Op::from(Push0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn golf_no_dup_across_jumpdest() {
+        // push1 5; push1 5; jump; jumpdest; push1 5
+        let suggestions = golf(&disassemble(&hex!("60056005565b6005")));
+        assert_eq!(
+            suggestions,
+            [Suggestion::DupConstant {
+                offset: 2,
+                push: Op::from(Push1(())),
+                dup: Op::from(Dup1),
+            }]
+        );
+    }
+
+    #[test]
+    fn golf_double_iszero() {
+        // caller; iszero; iszero; push1 7; jumpi; stop; jumpdest
+        let suggestions = golf(&disassemble(&hex!("331515600757005b")));
+        assert_eq!(
+            suggestions,
+            [Suggestion::DoubleIsZero {
+                offset: 1,
+                before_jumpi: false,
+            }]
+        );
+
+        // caller; iszero; iszero; jumpi
+        let suggestions = golf(&disassemble(&hex!("33151557")));
+        assert_eq!(
+            suggestions,
+            [Suggestion::DoubleIsZero {
+                offset: 1,
+                before_jumpi: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn golf_oversized_push() {
+        let suggestions = golf(&disassemble(&hex!("62000102")));
+        assert_eq!(
+            suggestions,
+            [Suggestion::OversizedPush {
+                offset: 0,
+                push: Op::from(Push3(())),
+                minimum: Op::from(Push2(())),
+            }]
+        );
+        assert_eq!(suggestions[0].savings(), 1);
+    }
+
+    #[test]
+    fn golf_unused_jumpdest() {
+        // push1 3; jump; jumpdest; jumpdest
+        let suggestions = golf(&disassemble(&hex!("6003565b5b")));
+        assert_eq!(suggestions, [Suggestion::UnusedJumpDest { offset: 4 }]);
+        assert_eq!(
+            suggestions[0].to_string(),
+            "0x4: `jumpdest` is never a static jump target, and can be removed (saves 1 byte(s))"
+        );
+    }
+}
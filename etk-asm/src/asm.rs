@@ -4,9 +4,12 @@
 //! [`mod@crate::ingest`] module for a higher-level interface.
 
 mod error {
-    use crate::ops::Expression;
+    use crate::debug::Location;
+    use crate::diagnostic::Diagnostic;
+    use crate::ops::{AbstractOp, Expression};
+    use crate::warning::Warning;
     use crate::ParseError;
-    use etk_ops::cancun::Op;
+    use etk_ops::prague::Op;
     use num_bigint::BigInt;
     use snafu::{Backtrace, Snafu};
 
@@ -22,6 +25,12 @@ mod error {
             /// The name of the conflicting label.
             label: String,
 
+            /// Where the label was declared again, if known.
+            source_location: Option<Location>,
+
+            /// Where the label was first declared, if known.
+            previous: Option<Location>,
+
             /// The location of the error.
             backtrace: Backtrace,
         },
@@ -33,16 +42,24 @@ mod error {
             /// The name of the conflicting macro.
             name: String,
 
+            /// Where the macro was declared again, if known.
+            source_location: Option<Location>,
+
+            /// Where the macro was first declared, if known.
+            previous: Option<Location>,
+
             /// The location of the error.
             backtrace: Backtrace,
         },
 
         /// A push instruction was too small for the result of the expression.
         #[snafu(display(
-            "the expression `{}={}` was too large for the specifier {}",
+            "the expression `{}={}` was too large for the specifier {} ({}){}",
             expr,
             value,
-            spec
+            spec,
+            super::width_hint(*needed),
+            reason.as_ref().map(|r| format!(": {}", r)).unwrap_or_default()
         ))]
         #[non_exhaustive]
         ExpressionTooLarge {
@@ -55,6 +72,17 @@ mod error {
             /// The specifier.
             spec: Op<()>,
 
+            /// The size in bytes of the smallest push that would fit the
+            /// value. More than 32 when no push can hold it.
+            needed: usize,
+
+            /// Why the push couldn't be made larger, when its size was chosen
+            /// by the assembler (as with `%push`).
+            reason: Option<String>,
+
+            /// The statement that caused the error, if known.
+            source_location: Option<Location>,
+
             /// The location of the error.
             backtrace: Backtrace,
         },
@@ -72,6 +100,101 @@ mod error {
             /// The evaluated value of the expression.
             value: BigInt,
 
+            /// The statement that caused the error, if known.
+            source_location: Option<Location>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The offset of a relative jump doesn't fit in a signed 16-bit
+        /// immediate.
+        #[snafu(display(
+            "the offset `{}={}` is out of range for the specifier {}",
+            expr,
+            value,
+            spec
+        ))]
+        #[non_exhaustive]
+        OffsetOutOfRange {
+            /// The expression giving the offset or destination.
+            expr: Expression,
+
+            /// The offset from the end of the jump.
+            value: BigInt,
+
+            /// The specifier.
+            spec: Op<()>,
+
+            /// The statement that caused the error, if known.
+            source_location: Option<Location>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// An instruction of EOF was used without an EOF target.
+        #[snafu(display(
+            "`{}` can only be used in an EOF container, so the target must be an EOF profile",
+            spec
+        ))]
+        #[non_exhaustive]
+        EofInstruction {
+            /// The specifier.
+            spec: Op<()>,
+
+            /// The statement that caused the error, if known.
+            source_location: Option<Location>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// An expression used `rel(..)` where there's no instruction for the
+        /// offset to be relative to.
+        #[snafu(display(
+            "`{}` uses `rel(..)`, which can only appear in the immediate of an instruction with a fixed size",
+            expr
+        ))]
+        #[non_exhaustive]
+        RelativeOutsideImmediate {
+            /// The expression using `rel(..)`.
+            expr: Expression,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A capability flag names an instruction that doesn't exist.
+        #[snafu(display(
+            "`has_{}` isn't a capability, since `{}` isn't an instruction",
+            name,
+            name
+        ))]
+        #[non_exhaustive]
+        UnknownCapability {
+            /// The mnemonic following `has_`.
+            name: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A capability flag was used outside of an `%if` condition, where
+        /// the target isn't known.
+        #[snafu(display(
+            "`{}` uses the capability flag `has_{}`, which can only be used in `%if` conditions",
+            expr,
+            name
+        ))]
+        #[non_exhaustive]
+        CapabilityOutsideCondition {
+            /// The expression using the flag.
+            expr: Expression,
+
+            /// The mnemonic following `has_`.
+            name: String,
+
             /// The location of the error.
             backtrace: Backtrace,
         },
@@ -102,6 +225,36 @@ mod error {
             /// The macro that was used without being defined.
             name: String,
 
+            /// The statement that caused the error, if known.
+            source_location: Option<Location>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// An instruction macro was invoked with the wrong number of
+        /// arguments.
+        #[snafu(display(
+            "macro `{}` invoked with {} argument(s), but expected {}",
+            name,
+            got,
+            expected
+        ))]
+        #[non_exhaustive]
+        MacroArgumentCount {
+            /// The name of the macro.
+            name: String,
+
+            /// The number of arguments closest to `got` that the macro
+            /// accepts.
+            expected: usize,
+
+            /// The number of arguments provided.
+            got: usize,
+
+            /// The statement that caused the error, if known.
+            source_location: Option<Location>,
+
             /// The location of the error.
             backtrace: Backtrace,
         },
@@ -127,1382 +280,6291 @@ mod error {
             source: ParseError,
         },
 
-        /// An instruction macro was used without being defined.
-        #[snafu(display("variable `{}` inside macro, was never defined", var))]
+        /// A macro variable was used without being defined.
+        #[snafu(display("variable `{}` inside macro, was never defined (in `{}`)", var, expr))]
         #[non_exhaustive]
         UndeclaredVariableMacro {
             /// The variable that was used without being defined.
             var: String,
 
+            /// The expression using the variable.
+            expr: Expression,
+
             /// The location of the error.
             backtrace: Backtrace,
         },
-    }
-}
 
-pub use self::error::Error;
-use crate::ops::expression::Error::{UndefinedVariable, UnknownLabel, UnknownMacro};
-use crate::ops::{self, AbstractOp, Assemble, Expression, MacroDefinition};
-use indexmap::IndexMap;
-use num_bigint::BigInt;
-use rand::Rng;
-use std::collections::{hash_map, HashMap, HashSet};
+        /// An operand is outside of the domain of an operation, like a
+        /// division by zero.
+        #[snafu(display(
+            "`{}` is not a valid operand of `{}` (in `{}`)",
+            value,
+            operation,
+            expr
+        ))]
+        #[non_exhaustive]
+        InvalidOperand {
+            /// The operation.
+            operation: String,
 
-/// An item to be assembled, which can be either an [`AbstractOp`],
-/// the inclusion of a new scope or a raw byte sequence.
-#[derive(Debug, Clone)]
-pub enum RawOp {
-    /// An instruction to be assembled.
-    Op(AbstractOp),
+            /// The invalid operand.
+            value: BigInt,
 
-    /// A new scope to be created with its corresponding list of operations.
-    Scope(Vec<RawOp>),
+            /// The expression containing the operation.
+            expr: Expression,
 
-    /// Raw bytes, for example from `%include_hex`, to be included verbatim in
-    /// the output.
-    Raw(Vec<u8>),
-}
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
 
-impl From<AbstractOp> for RawOp {
-    fn from(op: AbstractOp) -> Self {
-        Self::Op(op)
-    }
-}
+        /// A string passed to `hex()` in an expression isn't valid
+        /// hexadecimal.
+        #[snafu(display("`{}` is not valid hexadecimal (in `{}`)", text, expr))]
+        #[non_exhaustive]
+        InvalidHex {
+            /// The invalid string.
+            text: String,
 
-impl From<Vec<u8>> for RawOp {
-    fn from(vec: Vec<u8>) -> Self {
-        Self::Raw(vec)
-    }
-}
+            /// The expression containing the string.
+            expr: Expression,
 
-impl From<&AbstractOp> for RawOp {
-    fn from(op: &AbstractOp) -> Self {
-        Self::Op(op.clone())
-    }
-}
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
 
-/// Assembles a series of [`RawOp`] into raw bytes, tracking and resolving macros and labels,
-/// and handling variable-sized pushes.
-///
-/// ## Example
-///
-/// ```rust
-/// use etk_asm::asm::Assembler;
-/// use etk_asm::ops::AbstractOp;
-/// use etk_ops::cancun::{Op, GetPc};
-/// # use etk_asm::asm::Error;
-/// #
-/// # use hex_literal::hex;
-/// let mut asm = Assembler::new();
-/// let code = vec![AbstractOp::new(GetPc)];
-/// let result = asm.assemble(&code)?;
-/// # assert_eq!(result, hex!("58"));
-/// # Result::<(), Error>::Ok(())
-/// ```
-#[derive(Debug, Default)]
-pub struct Assembler {
-    /// Assembled ops.
-    ready: Vec<RawOp>,
+        /// A label was used in an expression included as data, where its
+        /// position isn't known yet.
+        #[snafu(display(
+            "label `{}` can't be used in data, since its position isn't final (in `{}`)",
+            label,
+            expr
+        ))]
+        #[non_exhaustive]
+        LabelInData {
+            /// The label.
+            label: String,
 
-    /// Number of bytes used by the operations in `ready``.
-    concrete_len: usize,
+            /// The expression using the label.
+            expr: Expression,
 
-    /// Labels associated with an `AbstractOp::Label`.
-    declared_labels: IndexMap<String, Option<LabelDef>>,
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
 
-    /// Macros associated with an `AbstractOp::Macro`.
-    declared_macros: HashMap<String, MacroDefinition>,
+        /// An entry of a jump table doesn't fit in two bytes.
+        #[snafu(display("the jump table entry `{}={}` doesn't fit in two bytes", expr, value))]
+        #[non_exhaustive]
+        JumpTableEntryTooLarge {
+            /// The oversized expression.
+            expr: Expression,
 
-    /// Labels that have been referred to (ex. with push) but
-    /// have not been declared with an `AbstractOp::Label`.
-    undeclared_labels: HashSet<String>,
+            /// The evaluated value of the expression.
+            value: BigInt,
 
-    /// Pushes that are variable-sized and need to be backpatched.
-    variable_sized_push: Vec<AbstractOp>,
-}
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
 
-/// A label definition.
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub struct LabelDef {
-    position: usize,
-    updated: bool,
-}
+        /// The table of an `rjumpv` doesn't have between 1 and 256 entries.
+        #[snafu(display(
+            "`rjumpv` has {} entries, but its table holds between 1 and 256",
+            entries
+        ))]
+        #[non_exhaustive]
+        RJumpVTableSize {
+            /// The number of entries.
+            entries: usize,
 
-impl LabelDef {
-    /// Create a new `LabelDef`.
-    pub fn new(position: usize) -> Self {
-        Self {
-            position,
-            updated: false,
-        }
-    }
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
 
-    /// Get the position of the label.
-    pub fn position(&self) -> usize {
-        self.position
-    }
-}
+        /// An entry of a data table, like `%word`, doesn't fit in its size.
+        #[snafu(display("the data entry `{}={}` doesn't fit in {} bytes", expr, value, size))]
+        #[non_exhaustive]
+        DataEntryTooLarge {
+            /// The oversized expression.
+            expr: Expression,
 
-impl Assembler {
-    /// Create a new `Assembler`.
-    pub fn new() -> Self {
-        Self::default()
-    }
+            /// The evaluated value of the expression.
+            value: BigInt,
 
-    /// Feed instructions into the `Assembler`.
-    ///
-    /// Returns the code of the assembled program.
-    pub fn assemble<O>(&mut self, ops: &[O]) -> Result<Vec<u8>, Error>
-    where
-        O: Into<RawOp> + Clone,
-    {
-        self.declare_macros(ops)?;
+            /// The size of each entry, in bytes.
+            size: usize,
 
-        for op in ops {
-            self.push(op.clone().into())?;
-        }
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
 
-        let output = self.backpatch_and_emit()?;
-        self.ready.clear();
-        Ok(output)
-    }
+        /// The opcode of a raw instruction (`op(..)`) isn't a constant from
+        /// `0x00` to `0xff`.
+        #[snafu(display("the opcode `{}` isn't a constant from 0x00 to 0xff", expr))]
+        #[non_exhaustive]
+        InvalidOpcode {
+            /// The invalid expression.
+            expr: Expression,
 
-    /// Pre-define macros, via `AbstractOp`, into the `Assembler`.
-    ///
-    /// This is used to define macros that are used in the same scope.
-    fn declare_macros<O>(&mut self, ops: &[O]) -> Result<(), Error>
-    where
-        O: Into<RawOp> + Clone,
-    {
-        for op in ops {
-            let rop = op.clone().into();
-            if let RawOp::Op(AbstractOp::MacroDefinition(ref defn)) = rop {
-                match self.declared_macros.entry(defn.name().to_owned()) {
-                    hash_map::Entry::Occupied(_) => {
-                        return error::DuplicateMacro { name: defn.name() }.fail()
-                    }
-                    hash_map::Entry::Vacant(v) => {
-                        v.insert(defn.to_owned());
-                    }
-                }
-            }
-        }
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
 
-        Ok(())
-    }
+        /// The immediate size of a raw instruction (`op(..)`) isn't a constant
+        /// from 1 to 32.
+        #[snafu(display("the immediate size `{}` isn't a constant from 1 to 32", expr))]
+        #[non_exhaustive]
+        InvalidImmediateSize {
+            /// The invalid expression.
+            expr: Expression,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The immediate of a raw instruction (`op(..)`) doesn't fit in its
+        /// size.
+        #[snafu(display("the immediate `{}={}` doesn't fit in {} byte(s)", expr, value, size))]
+        #[non_exhaustive]
+        ImmediateTooLarge {
+            /// The oversized expression.
+            expr: Expression,
+
+            /// The evaluated value of the expression.
+            value: BigInt,
+
+            /// The size of the immediate, in bytes.
+            size: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The arguments of `%abi_encode` couldn't be encoded.
+        #[snafu(display("couldn't ABI encode the arguments"))]
+        #[non_exhaustive]
+        AbiEncode {
+            /// The underlying source of this error.
+            source: crate::abi::Error,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// `%dispatch` was given an argument without a matching target.
+        #[snafu(display(
+            "`%dispatch` expects pairs of selectors and targets, but got {} argument(s)",
+            got
+        ))]
+        #[non_exhaustive]
+        DispatchArguments {
+            /// The number of arguments given.
+            got: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A selector given to `%dispatch` isn't a constant that fits in four
+        /// bytes.
+        #[snafu(display("the selector `{}` isn't a constant that fits in four bytes", expr))]
+        #[non_exhaustive]
+        InvalidSelector {
+            /// The selector's expression.
+            expr: Expression,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A selector appears in more than one arm of `%dispatch`.
+        #[snafu(display("the selector 0x{:08x} is dispatched more than once", selector))]
+        #[non_exhaustive]
+        DuplicateSelector {
+            /// The repeated selector.
+            selector: u32,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The selectors of `%dispatch` can't be told apart by a window of
+        /// bits, so [`Strategy::Mask`](crate::dispatch::Strategy::Mask)
+        /// can't be used.
+        #[snafu(display(
+            "no window of up to eight bits is different in every selector, so `%dispatch` \
+             can't use a mask"
+        ))]
+        #[non_exhaustive]
+        NoSelectorMask {
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// No multiplier hashes every selector of `%dispatch` into a different
+        /// entry of a table of at most 256 entries, so
+        /// [`Strategy::PerfectHash`](crate::dispatch::Strategy::PerfectHash)
+        /// can't be used.
+        #[snafu(display(
+            "no multiplier hashes every selector into a table of up to 256 entries, so \
+             `%dispatch` can't use a perfect hash"
+        ))]
+        #[non_exhaustive]
+        NoPerfectHash {
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A trampoline was given too few or too many arguments.
+        #[snafu(display(
+            "`%{}` expects a target, and optionally gas and a bubble flag, but got {} argument(s)",
+            name,
+            got
+        ))]
+        #[non_exhaustive]
+        TrampolineArguments {
+            /// The name of the trampoline macro.
+            name: String,
+
+            /// The number of arguments given.
+            got: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The gas or bubble flag given to a trampoline isn't a constant.
+        #[snafu(display("the trampoline option `{}` isn't a constant", expr))]
+        #[non_exhaustive]
+        InvalidTrampolineOption {
+            /// The option's expression.
+            expr: Expression,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A pinned label was assembled at a different position.
+        #[snafu(display(
+            "label `{}` is pinned to 0x{:x}, but was assembled at 0x{:x}",
+            label,
+            pinned,
+            actual
+        ))]
+        #[non_exhaustive]
+        PinnedLabelMoved {
+            /// The name of the pinned label.
+            label: String,
+
+            /// The position the label was pinned to.
+            pinned: usize,
+
+            /// The position the label was assembled at.
+            actual: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// An extern label was used other than as the entire operand of a
+        /// fixed size push.
+        #[snafu(display(
+            "extern label `{}` can only be pushed on its own with a fixed size push, not `{}`",
+            label,
+            op
+        ))]
+        #[non_exhaustive]
+        InvalidExternUse {
+            /// The extern label.
+            label: String,
+
+            /// The instruction using the label.
+            op: AbstractOp,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A name declared patchable with `%patchable` isn't an expression
+        /// macro without parameters.
+        #[snafu(display(
+            "`{}` must be an expression macro without parameters to be patchable",
+            name
+        ))]
+        #[non_exhaustive]
+        InvalidPatchable {
+            /// The name declared patchable.
+            name: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A captured region uses an extern label, which can't be resolved
+        /// before the region's code is needed.
+        #[snafu(display("extern label `{}` can't be used in captured region `{}`", label, name))]
+        #[non_exhaustive]
+        ExternInCapture {
+            /// The name of the captured region.
+            name: String,
+
+            /// The extern label.
+            label: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A patchable constant was used other than as the entire operand of
+        /// a fixed size push.
+        #[snafu(display(
+            "patchable constant `{}` can only be pushed on its own with a fixed size push, not `{}`",
+            name,
+            op
+        ))]
+        #[non_exhaustive]
+        InvalidPatchableUse {
+            /// The name of the constant.
+            name: String,
+
+            /// The instruction using the constant.
+            op: AbstractOp,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A macro invoked itself, directly or through other macros.
+        #[snafu(display(
+            "macro `{}` invokes itself (`{}`)",
+            name,
+            cycle.join("` -> `")
+        ))]
+        #[non_exhaustive]
+        RecursiveMacro {
+            /// The name of the macro.
+            name: String,
+
+            /// The invocations leading back to the macro, starting and ending
+            /// with it.
+            cycle: Vec<String>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// Macros were nested more deeply than allowed.
+        #[snafu(display("macro `{}` is nested more than {} levels deep", name, limit))]
+        #[non_exhaustive]
+        MacroDepthLimit {
+            /// The name of the macro that exceeded the limit.
+            name: String,
+
+            /// The maximum nesting depth.
+            limit: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A warning was promoted to an error, by strict mode or because its
+        /// lint was promoted.
+        #[snafu(display("{} [{}]", warning, warning.lint()))]
+        #[non_exhaustive]
+        PromotedWarning {
+            /// The promoted warning.
+            warning: Warning,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+
+    impl Error {
+        /// Extended help for this error, describing its likely causes and how
+        /// to fix them, if there is any.
+        ///
+        /// ## Example
+        ///
+        /// ```rust
+        /// use etk_asm::asm::{Assembler, RawOp};
+        /// use etk_asm::ops::AbstractOp;
+        ///
+        /// let mut asm = Assembler::new();
+        /// let label = RawOp::Op(AbstractOp::Label("a".into()));
+        ///
+        /// let err = asm.assemble(&[label.clone(), label]).unwrap_err();
+        /// assert!(err.explanation().is_some());
+        /// ```
+        pub fn explanation(&self) -> Option<&'static str> {
+            let text = match self {
+                Self::DuplicateLabel { .. } => DUPLICATE_LABEL,
+                Self::DuplicateMacro { .. } => DUPLICATE_MACRO,
+                Self::ExpressionTooLarge { .. } => EXPRESSION_TOO_LARGE,
+                Self::ExpressionNegative { .. } => EXPRESSION_NEGATIVE,
+                Self::UndeclaredLabels { .. } => UNDECLARED_LABELS,
+                Self::UndeclaredInstructionMacro { .. } => UNDECLARED_INSTRUCTION_MACRO,
+                Self::MacroArgumentCount { .. } => MACRO_ARGUMENT_COUNT,
+                Self::RecursiveMacro { .. } => RECURSIVE_MACRO,
+                Self::MacroDepthLimit { .. } => MACRO_DEPTH_LIMIT,
+                _ => return None,
+            };
+
+            Some(text)
+        }
+
+        /// The statement that caused this error, if known.
+        pub fn location(&self) -> Option<Location> {
+            match self {
+                Self::DuplicateLabel {
+                    source_location, ..
+                }
+                | Self::DuplicateMacro {
+                    source_location, ..
+                }
+                | Self::ExpressionTooLarge {
+                    source_location, ..
+                }
+                | Self::ExpressionNegative {
+                    source_location, ..
+                }
+                | Self::OffsetOutOfRange {
+                    source_location, ..
+                }
+                | Self::EofInstruction {
+                    source_location, ..
+                }
+                | Self::UndeclaredInstructionMacro {
+                    source_location, ..
+                }
+                | Self::MacroArgumentCount {
+                    source_location, ..
+                } => *source_location,
+                _ => None,
+            }
+        }
+
+        /// The first declaration of a label or macro declared again, if
+        /// known.
+        pub fn previous(&self) -> Option<Location> {
+            match self {
+                Self::DuplicateLabel { previous, .. } | Self::DuplicateMacro { previous, .. } => {
+                    *previous
+                }
+                _ => None,
+            }
+        }
+
+        /// Describe this error, pointing at the statements that caused it.
+        ///
+        /// See the [`diagnostic`](crate::diagnostic) module for details.
+        pub fn diagnostic(&self) -> Diagnostic {
+            let mut diagnostic = Diagnostic::new(self.to_string());
+
+            if let Some(location) = self.location() {
+                let message = match self.previous() {
+                    Some(_) => "declared again here",
+                    None => "",
+                };
+                diagnostic = diagnostic.with_primary(location, message);
+            }
+
+            if let Some(previous) = self.previous() {
+                diagnostic = diagnostic.with_secondary(previous, "first declared here");
+            }
+
+            diagnostic
+        }
+
+        /// Fill in the statement that caused this error, unless it's already
+        /// known.
+        pub(crate) fn locate(mut self, at: Option<Location>) -> Self {
+            match self {
+                Self::DuplicateLabel {
+                    ref mut source_location,
+                    ..
+                }
+                | Self::DuplicateMacro {
+                    ref mut source_location,
+                    ..
+                }
+                | Self::ExpressionTooLarge {
+                    ref mut source_location,
+                    ..
+                }
+                | Self::ExpressionNegative {
+                    ref mut source_location,
+                    ..
+                }
+                | Self::OffsetOutOfRange {
+                    ref mut source_location,
+                    ..
+                }
+                | Self::EofInstruction {
+                    ref mut source_location,
+                    ..
+                }
+                | Self::UndeclaredInstructionMacro {
+                    ref mut source_location,
+                    ..
+                }
+                | Self::MacroArgumentCount {
+                    ref mut source_location,
+                    ..
+                } if source_location.is_none() => *source_location = at,
+                _ => (),
+            }
+
+            self
+        }
+    }
+
+    const DUPLICATE_LABEL: &str = "\
+A label was declared more than once in the same scope.
+
+Each label names exactly one position in the code, so it can only be declared
+once. This usually happens when a block of code is copied, or when a file is
+included (with `%include`) twice.
+
+To fix it, rename one of the labels and the jumps that refer to it. For a file
+that is included more than once, use `%import` instead, or include it into its
+own namespace so its labels are prefixed. Labels inside an instruction macro are
+local to each expansion, so moving the repeated code into a macro also works.";
+
+    const DUPLICATE_MACRO: &str = "\
+An instruction or expression macro was declared more than once.
+
+Macro names are shared by the whole program, including imported files, so two
+files that each declare a macro with the same name conflict. This can also
+happen when the same file is imported twice.
+
+To fix it, rename one of the macros and its invocations, or remove the
+duplicate declaration. Declaring a macro with the name of a built-in one is
+allowed, and replaces the built-in.";
+
+    const EXPRESSION_TOO_LARGE: &str = "\
+The value of an expression doesn't fit in the push instruction it was given to.
+
+A `pushN` instruction holds exactly N bytes, so `push1` can only hold values up
+to 255. The expression might be larger than expected because a label moved
+further into the code, or because a constant changed.
+
+The error gives the smallest push that would fit the value. To fix it, use a
+larger push (like `push2` instead of `push1`), or use `%push`, which picks the
+smallest push that fits the value. Values needing more than 32 bytes don't fit
+in any push, not even with `%push`. If the value is a label, check that the code
+before it didn't grow unexpectedly.";
+
+    const EXPRESSION_NEGATIVE: &str = "\
+An expression given to a push instruction evaluated to a negative number.
+
+Push instructions hold unsigned values, so negative numbers can't be
+represented. This often comes from subtracting two labels in the wrong order,
+like `start - end` instead of `end - start`.
+
+To fix it, reorder the operands. To get a negative value on the stack,
+push its absolute value and subtract it from zero at runtime, for example with
+`push1 x`, `push0`, and `sub`.";
+
+    const UNDECLARED_LABELS: &str = "\
+A label was used, but never declared.
+
+Labels must be declared (like `start:`) somewhere in the program. The label
+might be misspelled, or might be declared in a file that was never included.
+Labels inside an instruction macro are local to each expansion, so they can't
+be used from outside the macro. Labels of a file included into a namespace must
+be prefixed with the namespace, like `lib.entry`.
+
+To fix it, check the spelling of the label, include the file that declares it,
+or declare it with `%extern` if an enclosing scope provides it.";
+
+    const UNDECLARED_INSTRUCTION_MACRO: &str = "\
+An instruction macro was invoked, but never declared.
+
+Instruction macros must be declared with `%macro` before the program is
+assembled, either in the same file or in an imported one. The name might be
+misspelled, or the file declaring it might not be imported.
+
+To fix it, check the spelling of the macro, or `%import` the file that declares
+it. Built-in macros, like `%push` and `%include`, don't need to be declared.";
+
+    const MACRO_ARGUMENT_COUNT: &str = "\
+A macro was invoked with the wrong number of arguments.
+
+Every parameter in a macro's declaration must be given exactly one argument.
+An extra or missing comma in the invocation is a common cause.
+
+To fix it, compare the invocation with the macro's declaration, and add or
+remove arguments so they match.";
+
+    const RECURSIVE_MACRO: &str = "\
+A macro invoked itself, either directly or through other macros.
+
+Macros are expanded in place while assembling, and there's no way for an
+expansion to stop, so a macro that invokes itself would expand forever. This
+applies to instruction macros and expression macros alike, including a macro
+given to another as an argument. The
+error lists the chain of invocations that leads back to the macro.
+
+To fix it, remove one of the invocations in the chain, for example by moving
+the code both macros need into a third macro that invokes neither. To run the
+same code many times, jump back to a label instead.";
+
+    const MACRO_DEPTH_LIMIT: &str = "\
+Macros were invoked inside other macros more deeply than the assembler allows.
+
+The limit protects against runaway expansions. Macros that invoke themselves
+are reported as soon as the cycle is found, so this error means a long chain
+of different macros.
+
+To fix it, flatten some of the macros into their callers.
+Libraries that really need deeper nesting of instruction macros can raise the
+limit with `Assembler::set_macro_depth_limit`.";
+}
+
+pub use self::error::Error;
+use crate::abi;
+use crate::analysis;
+use crate::debug::{DebugInfo, Expansion, Location, MacroInfo, Mapping, Symbol};
+use crate::disasm::{Disassembler, Offset};
+use crate::dispatch::{self, Strategy};
+use crate::gas;
+use crate::info::TARGET_FORK;
+use crate::object::{Relocation, RelocationKind, Segment};
+use crate::ops::expression::Error::{
+    ArgumentCount, InvalidHex, OutOfDomain, RecursionLimit, RecursiveMacro, UndefinedVariable,
+    UnknownCapability, UnknownLabel, UnknownMacro, UnknownPosition, UnknownTarget,
+};
+use crate::ops::{
+    self, AbstractOp, Assemble, CallKind, Context, Expression, ExpressionMacroDefinition,
+    ExpressionMacroInvocation, InstructionMacroDefinition, MacroDefinition, Terminal,
+};
+use crate::optimize::{self, Optimization, OptimizationLevel, Pass};
+use crate::patch::Patches;
+use crate::profile::Profile;
+use crate::synth::{self, CostModel, Substitution};
+use crate::trace::{Event, Trace};
+use crate::warning::{Lint, Warning};
+use etk_ops::prague::{Op, Operation, RJumpV};
+use indexmap::IndexMap;
+use num_bigint::{BigInt, Sign};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{hash_map, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::Write;
+
+/// An item to be assembled, which can be either an [`AbstractOp`],
+/// the inclusion of a new scope or a raw byte sequence.
+#[derive(Debug, Clone)]
+pub enum RawOp {
+    /// An instruction to be assembled.
+    Op(AbstractOp),
+
+    /// A new scope to be created with its corresponding list of operations.
+    Scope(Vec<RawOp>),
+
+    /// A new scope, like [`RawOp::Scope`], whose labels are also visible
+    /// outside of it as `namespace.label`.
+    NamedScope(String, Vec<RawOp>),
+
+    /// A region, for example from `%capture`, assembled on its own before
+    /// anything else. Its code isn't included in the output, but is the value
+    /// of the expression macro `name()`.
+    Capture(String, Vec<RawOp>),
+
+    /// Raw bytes, for example from `%include_hex`, to be included verbatim in
+    /// the output.
+    Raw(Vec<u8>),
+
+    /// An expression, for example from `%bytes`, whose value is included
+    /// verbatim in the output. See [`Expression::eval_bytes_with_context`].
+    Bytes(Expression),
+
+    /// Constant values ABI encoded as the fixed size types in the signature,
+    /// for example from `%abi_encode`. See [`abi::encode_static`].
+    AbiEncode(String, Vec<Expression>),
+
+    /// A table of two byte entries, for example from `%jumptable`, usually
+    /// holding the positions of labels. Entries are evaluated once every
+    /// label's position is final.
+    JumpTable(Vec<Expression>),
+
+    /// An `rjumpv` instruction, jumping to the entry of its table chosen by
+    /// the top of the stack. Like the other relative jumps, a bare label is
+    /// the offset to that label from the end of the instruction. Entries are
+    /// evaluated once every label's position is final.
+    RJumpV(Vec<Expression>),
+
+    /// A table of big-endian entries of the given size in bytes, for example
+    /// 32 from `%word` or 2 from `%dw`. Like [`RawOp::JumpTable`], labels can
+    /// be used, since entries are evaluated once every label's position is
+    /// final.
+    Data(usize, Vec<Expression>),
+
+    /// A label, used in this scope, that is expected to be declared by a
+    /// parent scope or provided when linking.
+    Extern(String),
+
+    /// An expression macro without parameters, whose pushes are kept as
+    /// written and listed in [`Assembler::patches`], so they can be changed
+    /// without reassembling.
+    Patchable(String),
+
+    /// An instruction given by its opcode, for example from `op(0xfe)`, so
+    /// instructions without a mnemonic can be used. The immediate, if any, is
+    /// given by its size in bytes and its value, which is evaluated once every
+    /// label's position is final.
+    Opcode(Expression, Option<(Expression, Expression)>),
+
+    /// The source of the instructions that follow, up to the next location.
+    Location(Location),
+
+    /// Marks falling through into the next label as intended, so it isn't
+    /// reported as [`Warning::Fallthrough`].
+    Fallthrough,
+
+    /// Names the items on the stack at this point, from the top, with `None`
+    /// for items that aren't named. See [`analysis::check_keep`].
+    Keep(Vec<Option<String>>),
+}
+
+impl From<AbstractOp> for RawOp {
+    fn from(op: AbstractOp) -> Self {
+        Self::Op(op)
+    }
+}
+
+impl From<Vec<u8>> for RawOp {
+    fn from(vec: Vec<u8>) -> Self {
+        Self::Raw(vec)
+    }
+}
+
+impl From<&AbstractOp> for RawOp {
+    fn from(op: &AbstractOp) -> Self {
+        Self::Op(op.clone())
+    }
+}
+
+/// A use of an extern label, left as a zeroed placeholder in the assembled
+/// code.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ExternReference {
+    /// The name of the extern label.
+    pub label: String,
+
+    /// Position of the placeholder (the immediate of the push) in the
+    /// assembled code.
+    pub offset: usize,
+
+    /// The push instruction using the label, which determines the width of
+    /// the placeholder.
+    pub spec: Op<()>,
+}
+
+/// The size in bytes of the smallest push that can hold `value`.
+/// Drop the entries of `map` for the first `count` instructions of `ready`,
+/// and shift the rest to match.
+fn shift_indices<T>(map: &mut HashMap<usize, T>, count: usize) {
+    *map = map
+        .drain()
+        .filter(|(idx, _)| *idx >= count)
+        .map(|(idx, value)| (idx - count, value))
+        .collect();
+}
+
+pub(crate) fn push_width(value: &BigInt) -> usize {
+    let bytes = (value.bits() + 7) / 8;
+    usize::try_from(bytes).unwrap_or(usize::MAX).max(1)
+}
+
+/// Describe a push of `needed` bytes, for errors.
+fn width_hint(needed: usize) -> String {
+    if needed <= 32 {
+        format!("it needs {} bytes, or push{}", needed, needed)
+    } else {
+        format!("it needs {} bytes, more than push32 can hold", needed)
+    }
+}
+
+/// Why the size of the push `op` can't grow to fit its value, if the
+/// assembler chose it.
+fn growth_limit(op: &AbstractOp) -> Option<String> {
+    match op {
+        AbstractOp::Push(_) => Some(
+            "`%push` picks the smallest push that fits, but push32 is the largest push".to_owned(),
+        ),
+        _ => None,
+    }
+}
+
+/// How deeply instruction macros may be nested, unless changed with
+/// [`Assembler::set_macro_depth_limit`].
+pub const DEFAULT_MACRO_DEPTH_LIMIT: usize = 64;
+
+/// Assembles a series of [`RawOp`] into raw bytes, tracking and resolving macros and labels,
+/// and handling variable-sized pushes.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::asm::Assembler;
+/// use etk_asm::ops::AbstractOp;
+/// use etk_ops::cancun::{Op, GetPc};
+/// # use etk_asm::asm::Error;
+/// #
+/// # use hex_literal::hex;
+/// let mut asm = Assembler::new();
+/// let code = vec![AbstractOp::new(GetPc)];
+/// let result = asm.assemble(&code)?;
+/// # assert_eq!(result, hex!("58"));
+/// # Result::<(), Error>::Ok(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Assembler {
+    /// Assembled ops.
+    ready: Vec<RawOp>,
+
+    /// Number of bytes used by the operations in `ready``.
+    concrete_len: usize,
+
+    /// Labels associated with an `AbstractOp::Label`.
+    declared_labels: IndexMap<String, Option<LabelDef>>,
+
+    /// Macros associated with an `AbstractOp::Macro`.
+    declared_macros: HashMap<String, MacroDefinition>,
+
+    /// Labels that have been referred to (ex. with push) but
+    /// have not been declared with an `AbstractOp::Label`.
+    undeclared_labels: HashSet<String>,
+
+    /// Pushes that are variable-sized and need to be backpatched, with their
+    /// positions.
+    variable_sized_push: Vec<(usize, AbstractOp)>,
+
+    /// Labels that have been referred to by an expression.
+    referenced_labels: HashSet<String>,
+
+    /// The unconditional exit or jump that ends the reachable code, if no
+    /// label or `jumpdest` has been seen since.
+    unreachable_after: Option<Op<()>>,
+
+    /// Whether execution can continue past the last instruction into
+    /// whatever follows it.
+    falls_through: bool,
+
+    /// Whether falling through into the next label was marked as intended.
+    fallthrough_allowed: bool,
+
+    /// Labels declared since the last instruction that are reached by
+    /// falling through, and are the destination of an earlier jump.
+    fallthrough_labels: Vec<String>,
+
+    /// Warnings collected so far.
+    warnings: Vec<Warning>,
+
+    /// Whether warnings are promoted to errors.
+    strict: bool,
+
+    /// Kinds of warnings promoted to errors, even when not strict.
+    promoted: Vec<Lint>,
+
+    /// How to choose sequences replacing pushes of constants, if at all.
+    synthesis: Option<CostModel>,
+
+    /// The chain the code is assembled for, or `None` for the latest fork.
+    target: Option<Profile>,
+
+    /// The strategy `%dispatch` uses, or `None` to choose the cheapest.
+    dispatch_strategy: Option<Strategy>,
+
+    /// How `%dispatch` compares strategies, or `None` for gas.
+    dispatch_model: Option<CostModel>,
+
+    /// Pushes replaced so far.
+    substitutions: Vec<Substitution>,
+
+    /// Optimization passes to run.
+    passes: Vec<Pass>,
+
+    /// Changes made by optimization passes so far.
+    optimizations: Vec<Optimization>,
+
+    /// Length of `ready` when the last unreachable instruction was removed,
+    /// so consecutive instructions are reported together.
+    dead_code_end: Option<usize>,
+
+    /// Index into `ready` of the first instruction the peephole pass may
+    /// rewrite. Labels, data, and macro expansion boundaries move it forward.
+    peephole_start: usize,
+
+    /// Names of the instruction macros being expanded, outermost first.
+    macro_stack: Vec<String>,
+
+    /// How deeply instruction macros may be nested, if not the default.
+    macro_depth_limit: Option<usize>,
+
+    /// Positions that labels must be assembled at.
+    pinned_labels: IndexMap<String, usize>,
+
+    /// Labels used by code assembled elsewhere, which aren't reported as
+    /// unused.
+    exported_labels: HashSet<String>,
+
+    /// Whether every label may be used by code assembled elsewhere, like the
+    /// labels of an object or of a captured region.
+    exports_all_labels: bool,
+
+    /// Labels declared with `RawOp::Extern`.
+    externs: HashSet<String>,
+
+    /// Placeholders left for extern labels.
+    extern_references: Vec<ExternReference>,
+
+    /// Placeholders left in the code of scopes, keyed by the position of the
+    /// scope's code in `ready`.
+    scope_externs: HashMap<usize, Vec<ExternReference>>,
+
+    /// Expression macros declared with `RawOp::Patchable`.
+    patchables: HashSet<String>,
+
+    /// Immediates of pushes of patchable constants.
+    patches: Patches,
+
+    /// Patchable pushes in the code of scopes, keyed by the position of the
+    /// scope's code in `ready`.
+    scope_patches: HashMap<usize, Patches>,
+
+    /// Instruction macro expansions, with their arguments, as ranges of
+    /// indices into `ready`.
+    expansions: Vec<(String, Vec<String>, usize, usize)>,
+
+    /// Whether the output must be the same on every run and machine, so
+    /// labels local to instruction macros get counted suffixes instead of
+    /// random ones.
+    reproducible: bool,
+
+    /// The last suffix given to a label local to an instruction macro, when
+    /// suffixes aren't random.
+    last_label_suffix: u64,
+
+    /// Whether macro definitions and the contents of expansions are recorded
+    /// in `debug_info`.
+    macro_details: bool,
+
+    /// Decisions made while assembling the last program, if tracing is
+    /// enabled.
+    trace: Option<Trace>,
+
+    /// Sources of the ops in `ready`, each starting at an index into `ready`.
+    locations: Vec<(usize, Location)>,
+
+    /// Stack items named by `# keep` comments, each before an index into
+    /// `ready`.
+    keeps: Vec<(usize, Vec<Option<String>>)>,
+
+    /// Where each label was declared, for pointing at both declarations of
+    /// a duplicate.
+    label_locations: HashMap<String, Location>,
+
+    /// Where each instruction macro was declared.
+    macro_locations: HashMap<String, Location>,
+
+    /// Expansions and source maps of scopes, keyed by the position of the
+    /// scope's code in `ready`.
+    scope_debug_info: HashMap<usize, DebugInfo>,
+
+    /// Labels, expansions, and source map of the last assembled program.
+    debug_info: DebugInfo,
+
+    /// Position of the first assembled byte, when the code continues code
+    /// assembled elsewhere.
+    origin: usize,
+
+    /// Code of the current program removed from `ready` so far, or `None`
+    /// between programs.
+    emitted: Option<Emitted>,
+}
+
+/// Code removed from `ready`, with what's needed to finish the program once
+/// the rest is emitted.
+#[derive(Debug, Clone, Default)]
+struct Emitted {
+    /// Number of bytes emitted.
+    len: usize,
+
+    /// Instructions emitted, for checking the stack.
+    code: Vec<Offset<Op<[u8]>>>,
+
+    /// Stack items named by `# keep` comments, each before a position.
+    keeps: Vec<(usize, Vec<Option<String>>)>,
+
+    /// Placeholders left for extern labels.
+    extern_references: Vec<ExternReference>,
+
+    /// Immediates of pushes of patchable constants.
+    patches: Patches,
+
+    /// Expansions and source map of the code emitted.
+    debug_info: DebugInfo,
+}
+
+/// A label definition.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LabelDef {
+    position: usize,
+    updated: bool,
+}
+
+impl LabelDef {
+    /// Create a new `LabelDef`.
+    pub fn new(position: usize) -> Self {
+        Self {
+            position,
+            updated: false,
+        }
+    }
+
+    /// Get the position of the label.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+/// The state of an [`Assembler`], recorded by [`Assembler::checkpoint`] and
+/// restored by [`Assembler::rollback`].
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    state: Box<Assembler>,
+}
+
+impl Assembler {
+    /// Create a new `Assembler`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Promote warnings to errors.
+    ///
+    /// When enabled, [`Assembler::assemble`] fails with
+    /// [`Error::PromotedWarning`] if any warnings were found.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Promote warnings of the kinds in `lints` to errors, like
+    /// [`Assembler::set_strict`] does for every warning.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use etk_asm::asm::{Assembler, Error};
+    /// use etk_asm::ops::AbstractOp;
+    /// use etk_asm::warning::Lint;
+    /// use etk_ops::cancun::{JumpDest, Push2};
+    ///
+    /// let ops = [
+    ///     AbstractOp::Label("a".into()),
+    ///     AbstractOp::new(JumpDest),
+    ///     AbstractOp::new(Push2([0u8, 1].into())),
+    /// ];
+    ///
+    /// let mut asm = Assembler::new();
+    /// asm.set_promoted(&[Lint::OversizedPush]);
+    /// let err = asm.assemble(&ops).unwrap_err();
+    /// assert!(matches!(err, Error::PromotedWarning { .. }));
+    /// ```
+    pub fn set_promoted(&mut self, lints: &[Lint]) {
+        self.promoted = lints.to_vec();
+    }
+
+    /// Warnings collected while assembling.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Replace pushes of constants with cheaper instruction sequences,
+    /// according to `model`. Disabled (`None`) by default.
+    ///
+    /// See the [`synth`] module for details.
+    pub fn set_synthesis(&mut self, model: Option<CostModel>) {
+        self.synthesis = model;
+    }
+
+    /// Assemble for the chain described by `profile`, or `None` (the default)
+    /// for the latest fork. Synthesis only uses the instructions it allows,
+    /// and the instructions of EOF are only accepted when it's an EOF profile.
+    pub fn set_target(&mut self, profile: Option<Profile>) {
+        self.target = profile;
+    }
+
+    /// Make `%dispatch` use `strategy`, or `None` (the default) to use the
+    /// cheapest strategy for its selectors.
+    ///
+    /// See the [`dispatch`](crate::dispatch) module for details.
+    pub fn set_dispatch_strategy(&mut self, strategy: Option<Strategy>) {
+        self.dispatch_strategy = strategy;
+    }
+
+    /// Compare the strategies `%dispatch` can use according to `model`, when
+    /// it isn't set with [`Assembler::set_dispatch_strategy`]. Defaults to
+    /// [`CostModel::Gas`].
+    pub fn set_dispatch_model(&mut self, model: CostModel) {
+        self.dispatch_model = Some(model);
+    }
+
+    /// Pushes replaced while assembling. See [`Assembler::set_synthesis`].
+    pub fn substitutions(&self) -> &[Substitution] {
+        &self.substitutions
+    }
+
+    /// Run the optimization `passes` while assembling. Disabled (empty) by
+    /// default.
+    ///
+    /// See the [`optimize`](crate::optimize) module for details.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use etk_asm::asm::Assembler;
+    /// use etk_asm::ops::AbstractOp;
+    /// use etk_asm::optimize::Pass;
+    /// use etk_ops::cancun::{Caller, Stop};
+    /// # use etk_asm::asm::Error;
+    ///
+    /// let code = vec![
+    ///     AbstractOp::new(Stop),
+    ///     AbstractOp::new(Caller),
+    /// ];
+    ///
+    /// let mut asm = Assembler::new();
+    /// asm.set_optimizations(&[Pass::DeadCode]);
+    /// assert_eq!(asm.assemble(&code)?, [0x00]);
+    /// assert_eq!(asm.optimizations()[0].bytes_saved, 1);
+    /// # Result::<(), Error>::Ok(())
+    /// ```
+    pub fn set_optimizations(&mut self, passes: &[Pass]) {
+        self.passes = passes.to_vec();
+    }
+
+    /// Run the optimization passes enabled at `level` while assembling.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use etk_asm::asm::Assembler;
+    /// use etk_asm::ops::{AbstractOp, Imm};
+    /// use etk_asm::optimize::OptimizationLevel;
+    /// use etk_ops::cancun::{Caller, Pop, Push1};
+    /// # use etk_asm::asm::Error;
+    ///
+    /// let code = vec![
+    ///     AbstractOp::new(Push1(Imm::from([1u8]))),
+    ///     AbstractOp::new(Pop),
+    ///     AbstractOp::new(Caller),
+    /// ];
+    ///
+    /// let mut asm = Assembler::new();
+    /// asm.set_optimization_level(OptimizationLevel::Basic);
+    /// assert_eq!(asm.assemble(&code)?, [0x33]);
+    /// # Result::<(), Error>::Ok(())
+    /// ```
+    pub fn set_optimization_level(&mut self, level: OptimizationLevel) {
+        self.set_optimizations(level.passes());
+    }
+
+    /// Allow instruction macros to be nested up to `limit` levels deep.
+    /// Defaults to [`DEFAULT_MACRO_DEPTH_LIMIT`].
+    ///
+    /// A macro that invokes itself fails with [`Error::RecursiveMacro`]
+    /// whatever the limit, and going past the limit fails with
+    /// [`Error::MacroDepthLimit`].
+    pub fn set_macro_depth_limit(&mut self, limit: usize) {
+        self.macro_depth_limit = Some(limit);
+    }
+
+    /// Record the instruction macros declared in the program, and the
+    /// arguments and generated instructions of each expansion, in
+    /// [`Assembler::debug_info`]. Disabled by default.
+    ///
+    /// See [`MacroInfo`] and [`Expansion`] for details.
+    pub fn set_macro_details(&mut self, enabled: bool) {
+        self.macro_details = enabled;
+    }
+
+    /// Make the labels local to instruction macros, as listed in
+    /// [`Assembler::debug_info`], the same on every run, by numbering them in
+    /// order instead of with random suffixes. Disabled by default.
+    ///
+    /// The assembled code is the same either way.
+    pub fn set_reproducible(&mut self, enabled: bool) {
+        self.reproducible = enabled;
+    }
+
+    /// Assemble code that starts `origin` bytes into the program, so labels
+    /// and `rel` refer to positions in the whole program.
+    pub(crate) fn set_origin(&mut self, origin: usize) {
+        self.origin = origin;
+        self.concrete_len = origin;
+    }
+
+    /// Declare `label` at `position`, for labels of code assembled elsewhere.
+    pub(crate) fn declare_position(&mut self, label: String, position: usize) {
+        self.referenced_labels.insert(label.clone());
+        self.declared_labels
+            .insert(label, Some(LabelDef::new(position)));
+    }
+
+    /// Record the decisions made while assembling, like the positions of
+    /// labels and the sizes of pushes, in [`Assembler::trace`]. Disabled by
+    /// default.
+    ///
+    /// See the [`trace`](crate::trace) module for details.
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.trace = enabled.then(Trace::default);
+    }
+
+    /// Decisions made while assembling the last program, or `None` if
+    /// tracing is disabled. See [`Assembler::set_tracing`].
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    /// Changes made by optimization passes while assembling. See
+    /// [`Assembler::set_optimizations`].
+    pub fn optimizations(&self) -> &[Optimization] {
+        &self.optimizations
+    }
+
+    /// Require `label` to be assembled at `position`, for example to match
+    /// the layout of previously deployed code.
+    ///
+    /// [`Assembler::assemble`] fails with [`Error::PinnedLabelMoved`] if the
+    /// label ends up anywhere else, or with [`Error::UndeclaredLabels`] if it
+    /// is never declared.
+    pub fn pin_label<S>(&mut self, label: S, position: usize)
+    where
+        S: Into<String>,
+    {
+        self.pinned_labels.insert(label.into(), position);
+    }
+
+    /// Mark `label` as used by code assembled elsewhere, like a label given
+    /// to `%export`, so it isn't reported as unused.
+    pub fn export_label<S>(&mut self, label: S)
+    where
+        S: Into<String>,
+    {
+        self.exported_labels.insert(label.into());
+    }
+
+    /// Placeholders left in the assembled code for extern labels that weren't
+    /// declared in any enclosing scope.
+    ///
+    /// Each placeholder must be patched (for example, by a linker) before the
+    /// code can be run.
+    pub fn extern_references(&self) -> &[ExternReference] {
+        &self.extern_references
+    }
+
+    /// Immediates of pushes of constants declared with `RawOp::Patchable`,
+    /// which can be changed without reassembling.
+    pub fn patches(&self) -> &Patches {
+        &self.patches
+    }
+
+    /// Position of `label` in the assembled program, or `None` if it wasn't
+    /// declared.
+    ///
+    /// Labels of scopes included with a namespace are named like `ns.label`.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use etk_asm::asm::Assembler;
+    /// use etk_asm::ops::AbstractOp;
+    /// use etk_ops::cancun::{Caller, JumpDest};
+    /// # use etk_asm::asm::Error;
+    ///
+    /// let code = vec![
+    ///     AbstractOp::new(Caller),
+    ///     AbstractOp::Label("dest".into()),
+    ///     AbstractOp::new(JumpDest),
+    /// ];
+    ///
+    /// let mut asm = Assembler::new();
+    /// asm.assemble(&code)?;
+    /// assert_eq!(asm.label("dest"), Some(1));
+    /// assert_eq!(asm.label("missing"), None);
+    /// # Result::<(), Error>::Ok(())
+    /// ```
+    pub fn label(&self, label: &str) -> Option<usize> {
+        self.declared_labels
+            .get(label)
+            .copied()
+            .flatten()
+            .map(|d| d.position)
+    }
+
+    /// Every label in the assembled program and its position, in the order
+    /// they were declared.
+    pub fn labels(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.declared_labels
+            .iter()
+            .filter_map(|(label, def)| Some((label.as_str(), def.as_ref()?.position)))
+    }
+
+    /// Labels, instruction macro expansions, and source map of the assembled
+    /// program.
+    ///
+    /// See the [`debug`](crate::debug) module for details.
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    /// Record the state of the `Assembler`, including its labels, macros,
+    /// and warnings, so it can be restored with [`Assembler::rollback`].
+    ///
+    /// A failed [`Assembler::assemble`] can leave instructions behind that
+    /// break the next call, so interactive tools, like a REPL, take a
+    /// checkpoint before trying instructions they aren't sure about.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use etk_asm::asm::Assembler;
+    /// use etk_asm::ops::{AbstractOp, Imm};
+    /// use etk_ops::cancun::{Caller, Push1};
+    /// # use etk_asm::asm::Error;
+    /// # use hex_literal::hex;
+    ///
+    /// let mut asm = Assembler::new();
+    /// let checkpoint = asm.checkpoint();
+    ///
+    /// let missing = AbstractOp::new(Push1(Imm::with_label("missing")));
+    /// assert!(asm.assemble(&[missing]).is_err());
+    ///
+    /// asm.rollback(checkpoint);
+    /// let code = asm.assemble(&[AbstractOp::new(Caller)])?;
+    /// assert_eq!(code, hex!("33"));
+    /// # Result::<(), Error>::Ok(())
+    /// ```
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            state: Box::new(self.clone()),
+        }
+    }
+
+    /// Return to the state recorded by [`Assembler::checkpoint`], undoing
+    /// everything assembled since, and any settings changed since.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        *self = *checkpoint.state;
+    }
+
+    /// Feed instructions into the `Assembler`.
+    ///
+    /// Returns the code of the assembled program.
+    pub fn assemble<O>(&mut self, ops: &[O]) -> Result<Vec<u8>, Error>
+    where
+        O: Into<RawOp> + Clone,
+    {
+        self.push_all(ops)?;
+        self.finish()
+    }
+
+    /// Feed instructions into the `Assembler`, without ending the program.
+    ///
+    /// The code that's ready can be removed with [`Assembler::take`], and
+    /// [`Assembler::finish`] returns the rest. Macros have to be defined in
+    /// the same call as their first use, or an earlier one.
+    pub fn push_all<O>(&mut self, ops: &[O]) -> Result<(), Error>
+    where
+        O: Into<RawOp> + Clone,
+    {
+        if self.emitted.is_none() {
+            if let Some(ref mut trace) = self.trace {
+                trace.events.clear();
+            }
+            self.emitted = Some(Emitted::default());
+        }
+
+        self.declare_macros(ops)?;
+        self.declare_captures(ops)?;
+        self.declare_externs(ops);
+        self.declare_patchables(ops)?;
+
+        for op in ops {
+            self.push(op.clone().into())
+                .map_err(|e| e.locate(self.location()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove and return the code at the start of the program that can't
+    /// change anymore.
+    ///
+    /// Code is ready once every label it uses is declared, and no push
+    /// before it is still waiting for a label, so a long program can be
+    /// written out while it's fed in. The code returned by `take`, followed
+    /// by the code returned by [`Assembler::finish`], is what
+    /// [`Assembler::assemble`] returns for the same instructions.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use etk_asm::asm::Assembler;
+    /// use etk_asm::ops::{AbstractOp, Imm};
+    /// use etk_ops::cancun::{Caller, Jump, JumpDest, Push1};
+    /// # use etk_asm::asm::Error;
+    /// #
+    /// # use hex_literal::hex;
+    /// let mut asm = Assembler::new();
+    /// asm.push_all(&[
+    ///     AbstractOp::new(Caller),
+    ///     AbstractOp::new(Push1(Imm::with_label("end"))),
+    ///     AbstractOp::new(Jump),
+    /// ])?;
+    ///
+    /// // The push waits for `end`.
+    /// assert_eq!(asm.take()?, hex!("33"));
+    ///
+    /// asm.push_all(&[AbstractOp::Label("end".into()), AbstractOp::new(JumpDest)])?;
+    /// assert_eq!(asm.take()?, hex!("6004565b"));
+    /// assert!(asm.finish()?.is_empty());
+    /// # Result::<(), Error>::Ok(())
+    /// ```
+    pub fn take(&mut self) -> Result<Vec<u8>, Error> {
+        match self.settled_len() {
+            0 => Ok(Vec::new()),
+            count => self.emit_bytecode(count),
+        }
+    }
+
+    /// End the program fed in with [`Assembler::push_all`].
+    ///
+    /// Returns the code not removed by [`Assembler::take`] yet.
+    pub fn finish(&mut self) -> Result<Vec<u8>, Error> {
+        let output = self.backpatch_and_emit()?;
+        self.finish_emitted();
+        self.ready.clear();
+        self.variable_sized_push.clear();
+        self.dead_code_end = None;
+        self.peephole_start = 0;
+        self.falls_through = false;
+        self.fallthrough_allowed = false;
+        self.fallthrough_labels.clear();
+        self.scope_externs.clear();
+        self.scope_patches.clear();
+        self.expansions.clear();
+        self.locations.clear();
+        self.keeps.clear();
+        self.scope_debug_info.clear();
+        self.check_pinned_labels()?;
+
+        self.debug_info.labels = self
+            .labels()
+            .map(|(name, offset)| Symbol {
+                name: name.to_owned(),
+                offset,
+            })
+            .collect();
+
+        for symbol in self.debug_info.labels.clone() {
+            self.record(|| Event::LabelResolved {
+                label: symbol.name,
+                position: symbol.offset,
+            });
+        }
+
+        self.check_unused_labels();
+        let promoted = self
+            .warnings
+            .iter()
+            .find(|w| self.strict || self.promoted.contains(&w.lint()));
+        if let Some(warning) = promoted {
+            return error::PromotedWarning {
+                warning: warning.clone(),
+            }
+            .fail();
+        }
+
+        Ok(output)
+    }
+
+    /// Feed instructions into the `Assembler`, like [`Assembler::assemble`],
+    /// but allow extern labels that no enclosing scope declares.
+    ///
+    /// Returns a relocatable object named `name`: a
+    /// [`Segment`](crate::object::Segment) with the labels of the program,
+    /// and a [`RelocationKind::External`](crate::object::RelocationKind::External)
+    /// relocation in place of each use of an undeclared extern label. Objects
+    /// are combined into a program by [`link`](crate::link::link).
+    pub fn assemble_object<S, O>(&mut self, name: S, ops: &[O]) -> Result<Segment, Error>
+    where
+        S: Into<String>,
+        O: Into<RawOp> + Clone,
+    {
+        // Other objects may refer to any of the labels.
+        self.exports_all_labels = true;
+        let code = self.assemble(ops);
+        self.exports_all_labels = false;
+        let code = code?;
+
+        let relocations = self
+            .extern_references
+            .iter()
+            .map(|r| Relocation {
+                offset: r.offset,
+                width: r.spec.extra_len(),
+                kind: RelocationKind::External,
+                segment: String::new(),
+                label: Some(r.label.clone()),
+            })
+            .collect();
+
+        Ok(Segment {
+            name: name.into(),
+            code,
+            labels: self.labels().map(|(l, p)| (l.to_owned(), p)).collect(),
+            relocations,
+        })
+    }
+
+    /// Pre-define macros, via `AbstractOp`, into the `Assembler`.
+    ///
+    /// This is used to define macros that are used in the same scope.
+    fn declare_macros<O>(&mut self, ops: &[O]) -> Result<(), Error>
+    where
+        O: Into<RawOp> + Clone,
+    {
+        let mut location = None;
+        for op in ops {
+            let rop = op.clone().into();
+            if let RawOp::Location(l) = rop {
+                location = Some(l);
+            }
+
+            if let RawOp::Op(AbstractOp::MacroDefinition(ref defn)) = rop {
+                match self.declared_macros.entry(defn.name().to_owned()) {
+                    hash_map::Entry::Occupied(_) => {
+                        return error::DuplicateMacro {
+                            name: defn.name(),
+                            source_location: location,
+                            previous: self.macro_locations.get(defn.name()).copied(),
+                        }
+                        .fail()
+                    }
+                    hash_map::Entry::Vacant(v) => {
+                        v.insert(defn.to_owned());
+                        if let Some(location) = location {
+                            self.macro_locations
+                                .insert(defn.name().to_owned(), location);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pre-declare extern labels, so they can be used before `RawOp::Extern`.
+    fn declare_externs<O>(&mut self, ops: &[O])
+    where
+        O: Into<RawOp> + Clone,
+    {
+        for op in ops {
+            if let RawOp::Extern(label) = op.clone().into() {
+                self.externs.insert(label);
+            }
+        }
+    }
+
+    /// Assemble the regions captured in `ops`, in order, and declare their
+    /// code as expression macros, so it's known before anything uses it.
+    fn declare_captures<O>(&mut self, ops: &[O]) -> Result<(), Error>
+    where
+        O: Into<RawOp> + Clone,
+    {
+        let mut location = None;
+        for op in ops {
+            let (name, region) = match op.clone().into() {
+                RawOp::Location(l) => {
+                    location = Some(l);
+                    continue;
+                }
+                RawOp::Capture(name, region) => (name, region),
+                _ => continue,
+            };
+
+            if self.declared_macros.contains_key(&name) {
+                return error::DuplicateMacro {
+                    previous: self.macro_locations.get(&name).copied(),
+                    name,
+                    source_location: location,
+                }
+                .fail();
+            }
+
+            // The region can use the macros declared around it, including
+            // earlier captures.
+            let mut asm = self.nested();
+            asm.declared_macros = self.declared_macros.clone();
+            asm.exports_all_labels = true;
+            asm.trace = None;
+            let code = asm.assemble(&region)?;
+
+            if let Some(reference) = asm.extern_references.first() {
+                return error::ExternInCapture {
+                    name,
+                    label: reference.label.clone(),
+                }
+                .fail();
+            }
+
+            self.warnings.append(&mut asm.warnings);
+            self.substitutions.append(&mut asm.substitutions);
+            self.optimizations.append(&mut asm.optimizations);
+
+            let defn = ExpressionMacroDefinition {
+                name: name.clone(),
+                parameters: Vec::new(),
+                content: Terminal::Bytes(code).into(),
+            };
+
+            self.declared_macros.insert(name.clone(), defn.into());
+            if let Some(location) = location {
+                self.macro_locations.insert(name, location);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pre-declare patchable constants, so pushes before `RawOp::Patchable`
+    /// are kept too.
+    fn declare_patchables<O>(&mut self, ops: &[O]) -> Result<(), Error>
+    where
+        O: Into<RawOp> + Clone,
+    {
+        for op in ops {
+            if let RawOp::Patchable(name) = op.clone().into() {
+                match self.declared_macros.get(&name) {
+                    Some(MacroDefinition::Expression(defn)) if defn.parameters.is_empty() => {
+                        self.patchables.insert(name);
+                    }
+                    _ => return error::InvalidPatchable { name }.fail(),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Feed a single instruction into the `Assembler`.
+    fn push<O>(&mut self, rop: O) -> Result<usize, Error>
+    where
+        O: Into<RawOp>,
+    {
+        let mut rop = rop.into();
+        self.declare_label(&rop)?;
+
+        if let RawOp::Op(ref mut op) = rop {
+            relative_label(op);
+        }
+
+        match rop {
+            RawOp::Op(AbstractOp::Label(label)) => {
+                self.unreachable_after = None;
+                self.peephole_start = self.ready.len();
+
+                // Labels local to a macro can't be marked with a comment.
+                if self.falls_through
+                    && !self.fallthrough_allowed
+                    && self.macro_stack.is_empty()
+                    && self.undeclared_labels.contains(&label)
+                {
+                    self.fallthrough_labels.push(label.clone());
+                }
+
+                self.undeclared_labels.retain(|l| *l != label);
+
+                let position = self.concrete_len;
+                self.record(|| Event::LabelDeclared {
+                    label: label.clone(),
+                    position,
+                });
+
+                let old = self
+                    .declared_labels
+                    .insert(
+                        label,
+                        Some(LabelDef {
+                            position: self.concrete_len,
+                            updated: false,
+                        }),
+                    )
+                    .expect("label should exist");
+                assert_eq!(old, None, "label should have been undefined");
+            }
+            RawOp::Op(AbstractOp::MacroDefinition(_)) => {}
+            RawOp::Op(AbstractOp::Macro(ref m)) => {
+                self.expand_macro(&m.name, &m.parameters)?;
+            }
+            RawOp::Op(ref op) => {
+                if self.eliminate_dead_code(op) {
+                    return Ok(self.concrete_len);
+                }
+
+                if let Some(spec) = op.specifier() {
+                    self.check_eof(spec)?;
+                }
+
+                self.check_reachable(op);
+                self.check_fallthrough(op);
+
+                if let Some(Ok(labels)) = op.expr().map(|e| e.labels(&self.declared_macros)) {
+                    self.referenced_labels.extend(labels);
+                }
+
+                if self.extern_label(op)?.is_some() {
+                    // The placeholder is emitted in `emit_bytecode`.
+                    self.concrete_len += op.size().unwrap();
+                    self.ready.push(rop.clone());
+                    return Ok(self.concrete_len);
+                }
+
+                let patchable = self.patchable(op)?.is_some();
+
+                match op
+                    .clone()
+                    .concretize((&self.declared_labels, &self.declared_macros).into())
+                {
+                    Ok(cop) if patchable => {
+                        // Kept as written, so the immediate can be patched.
+                        self.concrete_len += cop.size();
+                        self.ready.push(rop.clone());
+                        self.peephole_start = self.ready.len();
+                    }
+                    Ok(ref cop) if self.peephole(op, cop) => (),
+                    Ok(cop) => match self.synthesize(op, &cop) {
+                        Some(replacement) => {
+                            for rop in replacement {
+                                self.concrete_len += rop.size();
+                                self.ready.push(RawOp::Op(rop.into()));
+                            }
+                        }
+                        None => {
+                            self.check_push_size(op, &cop);
+                            self.concrete_len += cop.size();
+                            self.ready.push(rop.clone())
+                        }
+                    },
+                    Err(ops::Error::ExpressionTooLarge { value, spec, .. }) => {
+                        return error::ExpressionTooLarge {
+                            expr: op.expr().unwrap().clone(),
+                            needed: push_width(&value),
+                            reason: growth_limit(op),
+                            value,
+                            spec,
+                            source_location: self.location(),
+                        }
+                        .fail()
+                    }
+                    Err(ops::Error::ExpressionNegative { value, .. }) => {
+                        return error::ExpressionNegative {
+                            expr: op.expr().unwrap().clone(),
+                            value,
+                            source_location: self.location(),
+                        }
+                        .fail()
+                    }
+                    Err(ops::Error::OffsetOutOfRange { value, spec, .. }) => {
+                        return error::OffsetOutOfRange {
+                            expr: op.expr().unwrap().clone(),
+                            value,
+                            spec,
+                            source_location: self.location(),
+                        }
+                        .fail()
+                    }
+                    Err(ops::Error::ContextIncomplete {
+                        source: UnknownLabel { .. },
+                    }) => {
+                        let labels = op
+                            .expr()
+                            .unwrap()
+                            .labels(&self.declared_macros)
+                            .unwrap()
+                            .into_iter()
+                            .collect::<Vec<String>>();
+
+                        if let AbstractOp::Push(_) = op {
+                            // Here, we set the size of the push to 2 bytes (min possible value),
+                            //  as we don't know the final value of the label yet.
+                            let position = self.concrete_len;
+                            self.record(|| Event::PushDeferred {
+                                expr: op.expr().unwrap().to_string(),
+                                position,
+                                size: 2,
+                            });
+                            self.concrete_len += 2;
+                            self.variable_sized_push.push((position, op.clone()));
+                        } else {
+                            self.concrete_len += op.size().unwrap();
+                        }
+
+                        // Labels declared earlier, like the start of a scope
+                        // measured against its end, are already known.
+                        let declared = &self.declared_labels;
+                        let undeclared = labels
+                            .into_iter()
+                            .filter(|l| !matches!(declared.get(l), Some(Some(_))));
+                        self.undeclared_labels.extend(undeclared);
+                        self.ready.push(rop.clone());
+                    }
+                    Err(ops::Error::ContextIncomplete {
+                        source: UnknownPosition { .. },
+                    }) if op.size().is_some() => {
+                        // `rel(..)` is evaluated in `emit_bytecode`, once the
+                        // position of the instruction is known.
+                        self.concrete_len += op.size().unwrap();
+                        self.ready.push(rop.clone());
+                    }
+                    Err(ops::Error::ContextIncomplete {
+                        source: UnknownMacro { name, .. },
+                    }) => {
+                        return error::UndeclaredInstructionMacro {
+                            name,
+                            source_location: self.location(),
+                        }
+                        .fail()
+                    }
+                    Err(ops::Error::ContextIncomplete {
+                        source: UndefinedVariable { name, .. },
+                    }) => {
+                        return error::UndeclaredVariableMacro {
+                            var: name,
+                            expr: op.expr().unwrap().clone(),
+                        }
+                        .fail()
+                    }
+                    Err(ops::Error::ContextIncomplete { source }) => {
+                        return Err(invalid_expression(source, op.expr().unwrap()))
+                    }
+                }
+            }
+            RawOp::Raw(raw) => {
+                self.unreachable_after = None;
+                self.falls_through = false;
+                self.fallthrough_labels.clear();
+                self.concrete_len += raw.len();
+                self.ready.push(RawOp::Raw(raw.to_vec()));
+                self.peephole_start = self.ready.len();
+            }
+            RawOp::Bytes(expr) => {
+                let raw = self.eval_bytes(&expr)?;
+                self.unreachable_after = None;
+                self.falls_through = false;
+                self.fallthrough_labels.clear();
+                self.concrete_len += raw.len();
+                self.ready.push(RawOp::Raw(raw));
+                self.peephole_start = self.ready.len();
+            }
+            RawOp::AbiEncode(types, values) => {
+                let raw = self.abi_encode(&types, &values)?;
+                self.unreachable_after = None;
+                self.falls_through = false;
+                self.fallthrough_labels.clear();
+                self.concrete_len += raw.len();
+                self.ready.push(RawOp::Raw(raw));
+                self.peephole_start = self.ready.len();
+            }
+            RawOp::JumpTable(entries) => {
+                self.reference_entries(&entries)?;
+                self.unreachable_after = None;
+                self.falls_through = false;
+                self.fallthrough_labels.clear();
+                self.concrete_len += 2 * entries.len();
+                self.ready.push(RawOp::JumpTable(entries));
+                self.peephole_start = self.ready.len();
+            }
+            RawOp::RJumpV(entries) => {
+                self.check_eof(Op::from(RJumpV(())))?;
+                ensure!(
+                    (1..=256).contains(&entries.len()),
+                    error::RJumpVTableSize {
+                        entries: entries.len()
+                    }
+                );
+                self.reference_entries(&entries)?;
+                self.unreachable_after = None;
+                self.falls_through = true;
+                self.fallthrough_labels.clear();
+                self.concrete_len += 2 + 2 * entries.len();
+                self.ready.push(RawOp::RJumpV(entries));
+                self.peephole_start = self.ready.len();
+            }
+            RawOp::Data(size, entries) => {
+                self.reference_entries(&entries)?;
+                self.unreachable_after = None;
+                self.falls_through = false;
+                self.fallthrough_labels.clear();
+                self.concrete_len += size * entries.len();
+                self.ready.push(RawOp::Data(size, entries));
+                self.peephole_start = self.ready.len();
+            }
+            RawOp::Opcode(code, immediate) => {
+                let (opcode, size) = self.eval_opcode(&code, &immediate)?;
+
+                if let Some((_, value)) = &immediate {
+                    let labels = value
+                        .labels(&self.declared_macros)
+                        .map_err(|e| invalid_expression(e, value))?;
+
+                    for label in labels {
+                        if !matches!(self.declared_labels.get(&label), Some(Some(_))) {
+                            self.undeclared_labels.insert(label.clone());
+                        }
+                        self.referenced_labels.insert(label);
+                    }
+                }
+
+                self.warnings.push(Warning::RawOpcode { opcode });
+                self.unreachable_after = None;
+                self.falls_through = false;
+                self.fallthrough_labels.clear();
+                self.concrete_len += 1 + size;
+                self.ready.push(RawOp::Opcode(code, immediate));
+                self.peephole_start = self.ready.len();
+            }
+            RawOp::Scope(scope) => {
+                self.push_scope(None, &scope)?;
+                self.peephole_start = self.ready.len();
+            }
+            RawOp::NamedScope(namespace, scope) => {
+                self.push_scope(Some(&namespace), &scope)?;
+                self.peephole_start = self.ready.len();
+            }
+            RawOp::Extern(_) | RawOp::Patchable(_) | RawOp::Capture(..) => {}
+            RawOp::Location(location) => self.locations.push((self.ready.len(), location)),
+            RawOp::Fallthrough => self.fallthrough_allowed = true,
+            RawOp::Keep(slots) => self.keeps.push((self.ready.len(), slots)),
+        }
+
+        Ok(self.concrete_len)
+    }
+
+    /// Evaluate an expression included as data. Labels can't be used, since
+    /// backpatching may still move them.
+    fn eval_bytes(&self, expr: &Expression) -> Result<Vec<u8>, Error> {
+        let labels = IndexMap::new();
+        let ctx = (&labels, &self.declared_macros).into();
+
+        match expr.eval_bytes_with_context(ctx) {
+            Ok(raw) => Ok(raw),
+            Err(UnknownLabel { label, .. }) => error::LabelInData {
+                label,
+                expr: expr.clone(),
+            }
+            .fail(),
+            Err(e) => Err(invalid_expression(e, expr)),
+        }
+    }
+
+    /// Evaluate the values of `%abi_encode`, and encode them as `types`.
+    /// Like [`Assembler::eval_bytes`], labels can't be used.
+    fn abi_encode(&self, types: &str, values: &[Expression]) -> Result<Vec<u8>, Error> {
+        let labels = IndexMap::new();
+        let mut evaluated = Vec::with_capacity(values.len());
+
+        for expr in values {
+            let ctx = (&labels, &self.declared_macros).into();
+            match expr.eval_with_context(ctx) {
+                Ok(value) => evaluated.push(value),
+                Err(UnknownLabel { label, .. }) => {
+                    return error::LabelInData {
+                        label,
+                        expr: expr.clone(),
+                    }
+                    .fail()
+                }
+                Err(e) => return Err(invalid_expression(e, expr)),
+            }
+        }
+
+        let types = abi::parse_types(types).context(error::AbiEncode)?;
+        abi::encode_static(&types, &evaluated).context(error::AbiEncode)
+    }
+
+    /// Record the labels used by the entries of a table, which are evaluated
+    /// once every label's position is final.
+    fn reference_entries(&mut self, entries: &[Expression]) -> Result<(), Error> {
+        for entry in entries {
+            let labels = entry
+                .labels(&self.declared_macros)
+                .map_err(|e| invalid_expression(e, entry))?;
+
+            for label in labels {
+                if !matches!(self.declared_labels.get(&label), Some(Some(_))) {
+                    self.undeclared_labels.insert(label.clone());
+                }
+                self.referenced_labels.insert(label);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Evaluate an entry of a jump table, once the positions of labels are
+    /// final.
+    fn eval_jump_table_entry(&self, expr: &Expression) -> Result<u16, Error> {
+        let value = self.eval_table_entry(expr)?;
+
+        u16::try_from(&value).map_err(|_| {
+            error::JumpTableEntryTooLarge {
+                expr: expr.clone(),
+                value: value.clone(),
+            }
+            .build()
+        })
+    }
+
+    /// Evaluate an entry of a data table, like `%word`, as `size` big-endian
+    /// bytes, once the positions of labels are final.
+    fn eval_data_entry(&self, expr: &Expression, size: usize) -> Result<Vec<u8>, Error> {
+        let value = self.eval_table_entry(expr)?;
+
+        if value.bits() > 8 * size as u64 {
+            return error::DataEntryTooLarge {
+                expr: expr.clone(),
+                value,
+                size,
+            }
+            .fail();
+        }
+
+        let (_, bytes) = value.to_bytes_be();
+        let mut entry = vec![0; size - bytes.len()];
+        entry.extend(bytes);
+        Ok(entry)
+    }
+
+    /// Evaluate an entry of a table as a non-negative integer.
+    fn eval_table_entry(&self, expr: &Expression) -> Result<BigInt, Error> {
+        let ctx = (&self.declared_labels, &self.declared_macros).into();
+
+        let value = match expr.eval_with_context(ctx) {
+            Ok(value) => value,
+            Err(UnknownLabel { .. }) => {
+                return error::UndeclaredLabels {
+                    labels: self.undeclared_labels.iter().cloned().collect::<Vec<_>>(),
+                }
+                .fail()
+            }
+            Err(e) => return Err(invalid_expression(e, expr)),
+        };
+
+        if value.sign() == Sign::Minus {
+            return error::ExpressionNegative {
+                expr: expr.clone(),
+                value,
+                source_location: None,
+            }
+            .fail();
+        }
+
+        Ok(value)
+    }
+
+    /// Encode an `rjumpv` starting at `position`, once every label's position
+    /// is final. A bare label is the offset to it from the end of the
+    /// instruction.
+    fn eval_rjumpv(&self, entries: &[Expression], position: usize) -> Result<Vec<u8>, Error> {
+        let end = position + 2 + 2 * entries.len();
+        let spec = Op::from(RJumpV(()));
+
+        let mut bytes = vec![spec.code_byte(), (entries.len() - 1) as u8];
+
+        let ctx: Context = (&self.declared_labels, &self.declared_macros).into();
+
+        for entry in entries {
+            let expr = relative(entry).unwrap_or_else(|| entry.clone());
+            let value = match expr.eval_with_context(ctx.with_position(end)) {
+                Ok(value) => value,
+                Err(e) => return Err(invalid_expression(e, entry)),
+            };
+
+            // Like the other relative jumps, a non-negative number is the
+            // encoded offset (like `0xfffd` for -3.)
+            let encoded = value.sign() != Sign::Minus
+                && matches!(entry, Expression::Terminal(Terminal::Number(_)));
+            let offset = if encoded {
+                u16::try_from(&value).ok().map(u16::to_be_bytes)
+            } else {
+                i16::try_from(&value).ok().map(i16::to_be_bytes)
+            };
+
+            let offset = offset.with_context(|| error::OffsetOutOfRange {
+                expr: entry.clone(),
+                value: value.clone(),
+                spec,
+                source_location: None,
+            })?;
+
+            bytes.extend_from_slice(&offset);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Evaluate the opcode of a raw instruction, and the size of its
+    /// immediate. Neither may depend on labels.
+    fn eval_opcode(
+        &self,
+        code: &Expression,
+        immediate: &Option<(Expression, Expression)>,
+    ) -> Result<(u8, usize), Error> {
+        let labels = IndexMap::new();
+
+        let opcode = code
+            .eval_with_context((&labels, &self.declared_macros).into())
+            .ok()
+            .and_then(|value| u8::try_from(&value).ok())
+            .context(error::InvalidOpcode { expr: code.clone() })?;
+
+        let size = match immediate {
+            Some((size, _)) => size
+                .eval_with_context((&labels, &self.declared_macros).into())
+                .ok()
+                .and_then(|value| usize::try_from(&value).ok())
+                .filter(|size| (1..=32).contains(size))
+                .context(error::InvalidImmediateSize { expr: size.clone() })?,
+            None => 0,
+        };
+
+        Ok((opcode, size))
+    }
+
+    /// Encode a raw instruction starting at `position`, once every label's
+    /// position is final.
+    fn eval_raw_instruction(
+        &self,
+        code: &Expression,
+        immediate: &Option<(Expression, Expression)>,
+        position: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let (opcode, size) = self.eval_opcode(code, immediate)?;
+        let mut bytes = vec![opcode];
+
+        let expr = match immediate {
+            Some((_, expr)) => expr,
+            None => return Ok(bytes),
+        };
+
+        let ctx: Context = (&self.declared_labels, &self.declared_macros).into();
+        let value = match expr.eval_with_context(ctx.with_position(position + 1 + size)) {
+            Ok(value) => value,
+            Err(e) => return Err(invalid_expression(e, expr)),
+        };
+
+        let (sign, imm) = value.to_bytes_be();
+
+        if sign == Sign::Minus {
+            return error::ExpressionNegative {
+                expr: expr.clone(),
+                value,
+                source_location: None,
+            }
+            .fail();
+        }
+
+        if imm.len() > size {
+            return error::ImmediateTooLarge {
+                expr: expr.clone(),
+                value,
+                size,
+            }
+            .fail();
+        }
+
+        bytes.resize(1 + size - imm.len(), 0);
+        bytes.extend(imm);
+        Ok(bytes)
+    }
+
+    /// The statement currently being assembled, if known.
+    fn location(&self) -> Option<Location> {
+        self.locations.last().map(|(_, location)| *location)
+    }
+
+    fn record<F>(&mut self, event: F)
+    where
+        F: FnOnce() -> Event,
+    {
+        if let Some(ref mut trace) = self.trace {
+            trace.events.push(event());
+        }
+    }
+
+    /// A new `Assembler` for code nested in this one's, with the same
+    /// settings.
+    fn nested(&self) -> Self {
+        let mut asm = Self::new();
+        asm.synthesis = self.synthesis;
+        asm.target = self.target.clone();
+        asm.dispatch_strategy = self.dispatch_strategy;
+        asm.dispatch_model = self.dispatch_model;
+        asm.passes = self.passes.clone();
+        asm.macro_depth_limit = self.macro_depth_limit;
+        asm.macro_details = self.macro_details;
+        asm.reproducible = self.reproducible;
+        asm.exported_labels = self.exported_labels.clone();
+        asm.trace = self.trace.as_ref().map(|_| Trace::default());
+        asm
+    }
+
+    fn push_scope(&mut self, namespace: Option<&str>, scope: &[RawOp]) -> Result<(), Error> {
+        self.unreachable_after = None;
+        self.falls_through = false;
+        self.fallthrough_labels.clear();
+        let mut asm = self.nested();
+        let scope_result = asm.assemble(scope)?;
+
+        if let Some(trace) = asm.trace.take() {
+            let position = self.concrete_len;
+            self.record(|| Event::ScopeAssembled {
+                namespace: namespace.map(str::to_owned),
+                position,
+                trace,
+            });
+        }
+
+        self.warnings.append(&mut asm.warnings);
+        self.substitutions.append(&mut asm.substitutions);
+        self.optimizations.append(&mut asm.optimizations);
+
+        // Resolved in `emit_bytecode`, once the positions of labels are final.
+        if !asm.extern_references.is_empty() {
+            self.referenced_labels
+                .extend(asm.extern_references.iter().map(|r| r.label.clone()));
+            self.scope_externs
+                .insert(self.ready.len(), asm.extern_references);
+        }
+
+        if !asm.patches.is_empty() {
+            self.scope_patches.insert(self.ready.len(), asm.patches);
+        }
+
+        // The scope's labels are private, or already declared above.
+        asm.debug_info.labels.clear();
+        self.scope_debug_info
+            .insert(self.ready.len(), asm.debug_info);
+
+        if let Some(namespace) = namespace {
+            for (label, def) in asm.declared_labels {
+                let location = asm.label_locations.get(&label).copied();
+                let label = format!("{}.{}", namespace, label);
+                if self.declared_labels.contains_key(&label) {
+                    return error::DuplicateLabel {
+                        previous: self.label_locations.get(&label).copied(),
+                        label,
+                        source_location: location,
+                    }
+                    .fail();
+                }
+
+                if let Some(location) = location {
+                    self.label_locations.insert(label.clone(), location);
+                }
+
+                let def = def.expect("labels in an assembled scope should be defined");
+                self.undeclared_labels.remove(&label);
+
+                // The scope already reported its own unused labels.
+                self.referenced_labels.insert(label.clone());
+
+                self.declared_labels
+                    .insert(label, Some(LabelDef::new(self.concrete_len + def.position)));
+            }
+        }
+
+        self.concrete_len += scope_result.len();
+        self.ready.push(RawOp::Raw(scope_result));
+        Ok(())
+    }
+
+    fn backpatch_labels(&mut self) -> Result<(), Error> {
+        // Every push starts out two bytes long. Growing one moves the labels
+        // after it, which can grow another push (even an earlier one, like
+        // the difference of two labels), so repeat until the sizes settle.
+        let mut positions: Vec<_> = self.variable_sized_push.iter().map(|(p, _)| *p).collect();
+        let mut sizes = vec![2; positions.len()];
+
+        let mut grown = true;
+        while grown {
+            grown = false;
+
+            for (idx, (_, op)) in self.variable_sized_push.iter().enumerate() {
+                let imm = match op {
+                    AbstractOp::Push(imm) => imm,
+                    _ => continue,
+                };
+
+                let ctx = (&self.declared_labels, &self.declared_macros).into();
+                let val = match imm.tree.eval_with_context(ctx) {
+                    Ok(val) => val,
+                    Err(_) => continue,
+                };
+
+                let val_bits = BigInt::bits(&val).max(1);
+                let size = 2 + ((val_bits - 1) / 8) as usize;
+                if size <= sizes[idx] {
+                    continue;
+                }
+
+                let shift = size - sizes[idx];
+                let position = positions[idx];
+                sizes[idx] = size;
+                grown = true;
+
+                if let Some(ref mut trace) = self.trace {
+                    trace.events.push(Event::PushGrown {
+                        expr: imm.tree.to_string(),
+                        size,
+                        shift,
+                    });
+                }
+
+                self.concrete_len += shift;
+
+                for label_value in self.declared_labels.values_mut() {
+                    let labeldef = label_value.as_mut().unwrap();
+                    if labeldef.position > position {
+                        labeldef.position += shift;
+                        labeldef.updated = true;
+                    }
+                }
+
+                for other in positions.iter_mut() {
+                    if *other > position {
+                        *other += shift;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Backpatch variable-sized operations and emit the rest of the program.
+    ///
+    /// This function performs the final steps in the assembly process. It ensures that all labels
+    /// and variable-sized ops in the code have been properly resolved and finalized. This includes
+    /// handling variable-sized push instructions, where the actual size of the push may not be known
+    /// until all labels and expressions have been evaluated.
+    ///
+    /// Handle Variable-sized Pushes: The size of a push operation may depend on the value being pushed, especially
+    /// when labels are involved. As labels could be resolved to different addresses during the
+    /// assembly process, the final value of a label (and thus the size of the push) might only be
+    /// known at this stage. This function recalculates the size of each push operation based on the
+    /// final resolved values of labels and expressions. If a push operation requires more space than
+    /// initially estimated, the function adjusts the code accordingly.
+    fn backpatch_and_emit(&mut self) -> Result<Vec<u8>, Error> {
+        if !self.undeclared_labels.is_empty() {
+            return error::UndeclaredLabels {
+                labels: self
+                    .undeclared_labels
+                    .iter()
+                    .map(|l| l.to_owned())
+                    .collect::<Vec<String>>(),
+            }
+            .fail();
+        }
+        self.backpatch_labels()?;
+        self.emit_bytecode(self.ready.len())
+    }
+
+    /// Number of instructions at the start of `ready` whose code can't change
+    /// anymore.
+    fn settled_len(&self) -> usize {
+        // Growing a push only moves the labels after it.
+        let limit = self
+            .variable_sized_push
+            .first()
+            .map(|(position, _)| *position);
+        let settled = |expr: &Expression| match expr.labels(&self.declared_macros) {
+            Ok(labels) => labels.iter().all(|l| self.is_settled(l, limit)),
+            Err(_) => false,
+        };
+
+        let mut count = self
+            .ready
+            .iter()
+            .enumerate()
+            .take_while(|(idx, rop)| match rop {
+                RawOp::Op(op) if matches!(self.extern_label(op), Ok(Some(_))) => true,
+                RawOp::Op(op) => op.expr().map_or(true, settled),
+                RawOp::Raw(_) => self
+                    .scope_externs
+                    .get(idx)
+                    .into_iter()
+                    .flatten()
+                    .all(|r| self.is_settled(&r.label, limit)),
+                RawOp::JumpTable(entries) | RawOp::RJumpV(entries) | RawOp::Data(_, entries) => {
+                    entries.iter().all(settled)
+                }
+                RawOp::Opcode(code, immediate) => {
+                    settled(code)
+                        && immediate
+                            .iter()
+                            .all(|(size, value)| settled(size) && settled(value))
+                }
+                _ => unreachable!("scopes, data, externs, and locations should be expanded"),
+            })
+            .count();
+
+        // The peephole pass may still replace the last instruction.
+        let peephole = self.passes.contains(&Pass::Peephole);
+        if peephole && count == self.ready.len() && count > self.peephole_start {
+            count -= 1;
+        }
+
+        // Expansions are emitted whole, so their gas can be measured. They're
+        // in the order they started, so outer ones come first.
+        for (_, _, start, end) in self.expansions.iter() {
+            if *start < count && count < *end {
+                count = *start;
+            }
+        }
+
+        count
+    }
+
+    /// Whether `label` is declared, and backpatching can't move it anymore.
+    fn is_settled(&self, label: &str, limit: Option<usize>) -> bool {
+        match self.declared_labels.get(label) {
+            Some(Some(def)) => limit.map_or(true, |limit| def.position <= limit),
+            _ => false,
+        }
+    }
+
+    /// Emit the first `count` instructions of `ready`, and remove them.
+    fn emit_bytecode(&mut self, count: usize) -> Result<Vec<u8>, Error> {
+        let mut emitted = self.emitted.take().unwrap_or_default();
+        let result = self.emit_into(&mut emitted, count);
+        self.emitted = Some(emitted);
+        result
+    }
+
+    fn emit_into(&mut self, emitted: &mut Emitted, count: usize) -> Result<Vec<u8>, Error> {
+        let base = emitted.len;
+        let mut output = Vec::new();
+        let code = &mut emitted.code;
+        let extern_references = &mut emitted.extern_references;
+        let patches = &mut emitted.patches;
+        let debug_info = &mut emitted.debug_info;
+        let mut starts = Vec::with_capacity(count + 1);
+        let mut locations = self.locations.iter().peekable();
+        let mut location = None;
+        for (idx, op) in self.ready[..count].iter().enumerate() {
+            starts.push(base + output.len());
+
+            while let Some((_, next)) = locations.next_if(|(start, _)| *start <= idx) {
+                location = Some(*next);
+            }
+
+            let op = match op {
+                RawOp::Op(ref op) => op,
+                RawOp::Raw(raw) => {
+                    let mut raw = raw.clone();
+                    let references = self.scope_externs.get(&idx).into_iter().flatten();
+                    for reference in references {
+                        match self.resolve_extern(reference, &mut raw) {
+                            Ok(true) => (),
+                            Ok(false) => extern_references.push(ExternReference {
+                                offset: base + output.len() + reference.offset,
+                                ..reference.clone()
+                            }),
+                            Err(e) => return Err(e.locate(location)),
+                        }
+                    }
+                    if let Some(nested) = self.scope_patches.get(&idx) {
+                        patches.append(nested, base + output.len());
+                    }
+                    if let Some(nested) = self.scope_debug_info.get(&idx) {
+                        debug_info.append(nested, base + output.len());
+                    }
+                    output.extend(raw);
+                    continue;
+                }
+                RawOp::JumpTable(entries) => {
+                    for entry in entries {
+                        let entry = self
+                            .eval_jump_table_entry(entry)
+                            .map_err(|e| e.locate(location))?;
+                        output.extend_from_slice(&entry.to_be_bytes());
+                    }
+                    continue;
+                }
+                RawOp::RJumpV(entries) => {
+                    let bytes = self
+                        .eval_rjumpv(entries, base + output.len())
+                        .map_err(|e| e.locate(location))?;
+                    let offset = base + output.len();
+                    code.push(Offset::new(offset, Op::from_slice(&bytes).unwrap()));
+                    output.extend(bytes);
+                    continue;
+                }
+                RawOp::Data(size, entries) => {
+                    for entry in entries {
+                        let entry = self
+                            .eval_data_entry(entry, *size)
+                            .map_err(|e| e.locate(location))?;
+                        output.extend(entry);
+                    }
+                    continue;
+                }
+                RawOp::Opcode(code, immediate) => {
+                    let bytes = self
+                        .eval_raw_instruction(code, immediate, self.origin + base + output.len())
+                        .map_err(|e| e.locate(location))?;
+                    output.extend(bytes);
+                    continue;
+                }
+                RawOp::Scope(_)
+                | RawOp::NamedScope(..)
+                | RawOp::Capture(..)
+                | RawOp::Bytes(_)
+                | RawOp::AbiEncode(..)
+                | RawOp::Extern(_)
+                | RawOp::Patchable(_)
+                | RawOp::Location(_)
+                | RawOp::Fallthrough
+                | RawOp::Keep(_) => {
+                    unreachable!("scopes, data, externs, and locations should be expanded")
+                }
+            };
+
+            if let Some(location) = location {
+                debug_info.source_map.push(Mapping {
+                    offset: base + output.len(),
+                    location,
+                });
+            }
+
+            if let Some(label) = self.extern_label(op).expect("extern uses were checked") {
+                let spec = op.specifier().unwrap();
+                let mut bytes = vec![0; spec.size()];
+                bytes[0] = spec.code_byte();
+
+                extern_references.push(ExternReference {
+                    label,
+                    offset: base + output.len() + 1,
+                    spec,
+                });
+
+                code.push(Offset::new(
+                    base + output.len(),
+                    Op::from_slice(&bytes).unwrap(),
+                ));
+                output.extend(bytes);
+                continue;
+            }
+
+            let mut ctx: Context = (&self.declared_labels, &self.declared_macros).into();
+            if let Some(size) = op.size() {
+                ctx = ctx.with_position(self.origin + base + output.len() + size);
+            }
+
+            match op.clone().concretize(ctx) {
+                Ok(cop) => {
+                    if let (Some(trace), Some(_)) = (&mut self.trace, op.expr()) {
+                        trace.events.push(Event::Emitted {
+                            op: op.to_string(),
+                            position: base + output.len(),
+                            size: cop.size(),
+                        });
+                    }
+
+                    if let Some(name) = self.patchable(op).expect("patchable uses were checked") {
+                        let start = base + output.len() + 1;
+                        patches.insert(name, start..base + output.len() + cop.size());
+                    }
+
+                    code.push(Offset::new(base + output.len(), cop.clone()));
+                    cop.assemble(&mut output);
+                }
+                Err(ops::Error::ExpressionTooLarge { value, spec, .. }) => {
+                    return error::ExpressionTooLarge {
+                        expr: op.expr().unwrap().clone(),
+                        needed: push_width(&value),
+                        reason: growth_limit(op),
+                        value,
+                        spec,
+                        source_location: location,
+                    }
+                    .fail();
+                }
+                Err(ops::Error::ExpressionNegative { value, .. }) => {
+                    return error::ExpressionNegative {
+                        expr: op.expr().unwrap().clone(),
+                        value,
+                        source_location: location,
+                    }
+                    .fail();
+                }
+                Err(ops::Error::OffsetOutOfRange { value, spec, .. }) => {
+                    return error::OffsetOutOfRange {
+                        expr: op.expr().unwrap().clone(),
+                        value,
+                        spec,
+                        source_location: location,
+                    }
+                    .fail();
+                }
+                Err(ops::Error::ContextIncomplete {
+                    source: UnknownLabel { .. },
+                }) => {
+                    return error::UndeclaredLabels {
+                        labels: self.undeclared_labels.iter().cloned().collect::<Vec<_>>(),
+                    }
+                    .fail();
+                }
+                Err(ops::Error::ContextIncomplete {
+                    source: UnknownMacro { name, .. },
+                }) => {
+                    return error::UndeclaredInstructionMacro {
+                        name,
+                        source_location: location,
+                    }
+                    .fail();
+                }
+                Err(ops::Error::ContextIncomplete {
+                    source: UndefinedVariable { name, .. },
+                }) => {
+                    return error::UndeclaredVariableMacro {
+                        var: name,
+                        expr: op.expr().unwrap().clone(),
+                    }
+                    .fail();
+                }
+                Err(ops::Error::ContextIncomplete { source }) => {
+                    return Err(invalid_expression(source, op.expr().unwrap()));
+                }
+            }
+        }
+
+        starts.push(base + output.len());
+
+        let (done, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.expansions)
+            .into_iter()
+            .partition(|(_, _, _, end)| *end <= count);
+        debug_info
+            .expansions
+            .extend(done.into_iter().map(|(name, arguments, start, end)| {
+                let (start, end) = (starts[start], starts[end]);
+                let expanded = &output[start - base..end - base];
+                let ops = if self.macro_details {
+                    disassemble(expanded)
+                } else {
+                    Vec::new()
+                };
+                Expansion {
+                    name,
+                    start,
+                    end,
+                    gas: gas::static_gas_of(expanded),
+                    arguments,
+                    ops,
+                }
+            }));
+        self.expansions = rest
+            .into_iter()
+            .map(|(name, arguments, start, end)| (name, arguments, start - count, end - count))
+            .collect();
+
+        let (done, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.keeps)
+            .into_iter()
+            .partition(|(idx, _)| *idx <= count);
+        emitted
+            .keeps
+            .extend(done.into_iter().map(|(idx, slots)| (starts[idx], slots)));
+        self.keeps = rest
+            .into_iter()
+            .map(|(idx, slots)| (idx - count, slots))
+            .collect();
+
+        // The statement of the first instruction left carries over.
+        let before = self.locations.iter().filter(|(idx, _)| *idx <= count);
+        let carried = before.count().saturating_sub(1);
+        self.locations.drain(..carried);
+        for (idx, _) in self.locations.iter_mut() {
+            *idx = idx.saturating_sub(count);
+        }
+
+        shift_indices(&mut self.scope_externs, count);
+        shift_indices(&mut self.scope_patches, count);
+        shift_indices(&mut self.scope_debug_info, count);
+        self.ready.drain(..count);
+        self.peephole_start = self.peephole_start.saturating_sub(count);
+        self.dead_code_end = self.dead_code_end.and_then(|end| end.checked_sub(count));
+
+        emitted.len += output.len();
+        Ok(output)
+    }
+
+    /// Record the debug info, placeholders, and patches of the program just
+    /// emitted, and check its stack.
+    fn finish_emitted(&mut self) {
+        let emitted = self.emitted.take().unwrap_or_default();
+        let mut debug_info = emitted.debug_info;
+
+        // Expansions of scopes are nested in the scope's code, so sorting
+        // keeps outer ones first.
+        let expansions = &mut debug_info.expansions;
+        expansions.sort_by_key(|e| (e.start, std::cmp::Reverse(e.end)));
+
+        if self.macro_details {
+            let mut macros: Vec<_> = self
+                .declared_macros
+                .values()
+                .filter_map(|defn| match defn {
+                    MacroDefinition::Instruction(m) => Some(macro_info(m)),
+                    MacroDefinition::Expression(_) => None,
+                })
+                .collect();
+            macros.sort_by(|a, b| a.name.cmp(&b.name));
+
+            // Macros declared in scopes were appended with their code.
+            macros.append(&mut debug_info.macros);
+            debug_info.macros = macros;
+        }
+
+        self.debug_info = debug_info;
+        self.extern_references = emitted.extern_references;
+        self.patches = emitted.patches;
+        self.warnings.extend(analysis::check_stack(&emitted.code));
+        self.warnings
+            .extend(analysis::check_keep(&emitted.code, &emitted.keeps));
+    }
+
+    /// Get the extern label used by `op`, or `None` if it doesn't use one.
+    fn extern_label(&self, op: &AbstractOp) -> Result<Option<String>, Error> {
+        let labels = match op.expr().map(|e| e.labels(&self.declared_macros)) {
+            Some(Ok(labels)) => labels,
+            _ => return Ok(None),
+        };
+
+        let label = match labels.into_iter().find(|l| self.externs.contains(l)) {
+            Some(label) => label,
+            None => return Ok(None),
+        };
+
+        let is_push = matches!(op.specifier(), Some(spec) if ops::is_push(&spec));
+        let is_bare =
+            matches!(op.expr(), Some(Expression::Terminal(Terminal::Label(l))) if *l == label);
+
+        if is_push && is_bare {
+            Ok(Some(label))
+        } else {
+            error::InvalidExternUse {
+                label,
+                op: op.clone(),
+            }
+            .fail()
+        }
+    }
+
+    /// Get the patchable constant pushed by `op`, or `None` if it doesn't use
+    /// one.
+    fn patchable(&self, op: &AbstractOp) -> Result<Option<String>, Error> {
+        let expr = match op.expr() {
+            Some(expr) => expr,
+            None => return Ok(None),
+        };
+
+        let name = match self.patchables.iter().find(|n| expr.uses_macro(n)) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+
+        let is_push = matches!(op.specifier(), Some(spec) if ops::is_push(&spec));
+        let is_bare = matches!(
+            expr,
+            Expression::Macro(invc) if invc.name == *name && invc.parameters.is_empty()
+        );
+
+        if is_push && is_bare {
+            Ok(Some(name.clone()))
+        } else {
+            error::InvalidPatchableUse {
+                name: name.clone(),
+                op: op.clone(),
+            }
+            .fail()
+        }
+    }
+
+    /// Patch the placeholder for `reference` in the code of a scope, if the
+    /// label is declared here. Returns `false` if the label is an extern in
+    /// this scope too.
+    fn resolve_extern(&self, reference: &ExternReference, code: &mut [u8]) -> Result<bool, Error> {
+        let position = match self.declared_labels.get(&reference.label) {
+            Some(Some(def)) => def.position,
+            _ if self.externs.contains(&reference.label) => return Ok(false),
+            _ => {
+                return error::UndeclaredLabels {
+                    labels: vec![reference.label.clone()],
+                }
+                .fail()
+            }
+        };
+
+        let width = reference.spec.extra_len();
+        let bytes = position.to_be_bytes();
+        let zeros = bytes.iter().take_while(|b| **b == 0).count();
+        let bytes = &bytes[zeros..];
+
+        if bytes.len() > width {
+            return error::ExpressionTooLarge {
+                expr: Expression::from(Terminal::Label(reference.label.clone())),
+                value: BigInt::from(position),
+                spec: reference.spec,
+                needed: bytes.len(),
+                reason: Some("the push was chosen where the extern label is used".to_owned()),
+                source_location: None,
+            }
+            .fail();
+        }
+
+        let placeholder = &mut code[reference.offset..reference.offset + width];
+        let start = width - bytes.len();
+        placeholder[start..].copy_from_slice(bytes);
+        Ok(true)
+    }
+
+    fn check_pinned_labels(&self) -> Result<(), Error> {
+        let undeclared: Vec<_> = self
+            .pinned_labels
+            .keys()
+            .filter(|l| !self.declared_labels.contains_key(*l))
+            .cloned()
+            .collect();
+
+        if !undeclared.is_empty() {
+            return error::UndeclaredLabels { labels: undeclared }.fail();
+        }
+
+        for (label, pinned) in self.pinned_labels.iter() {
+            let actual = self.declared_labels[label]
+                .expect("labels should be defined after assembling")
+                .position;
+
+            if actual != *pinned {
+                return error::PinnedLabelMoved {
+                    label: label.clone(),
+                    pinned: *pinned,
+                    actual,
+                }
+                .fail();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_reachable(&mut self, op: &AbstractOp) {
+        if let Some(after) = self.unreachable_after.take() {
+            if !matches!(op.specifier(), Some(Op::JumpDest(_))) {
+                self.warnings.push(Warning::UnreachableCode {
+                    after,
+                    op: op.clone(),
+                });
+            }
+        }
+
+        match op.specifier() {
+            Some(spec) if spec.is_exit() || matches!(spec, Op::Jump(_) | Op::RJump(_)) => {
+                self.unreachable_after = Some(spec);
+            }
+            _ => (),
+        }
+    }
+
+    /// Warn about labels on `jumpdest`s that are reached by falling through,
+    /// and track whether execution can fall through `op`.
+    fn check_fallthrough(&mut self, op: &AbstractOp) {
+        let labels = std::mem::take(&mut self.fallthrough_labels);
+        if matches!(op.specifier(), Some(Op::JumpDest(_))) {
+            self.warnings.extend(
+                labels
+                    .into_iter()
+                    .map(|label| Warning::Fallthrough { label }),
+            );
+        }
+
+        self.fallthrough_allowed = false;
+        self.falls_through = match op.specifier() {
+            Some(spec) => !spec.is_exit() && !matches!(spec, Op::Jump(_) | Op::RJump(_)),
+            None => true,
+        };
+    }
+
+    /// Remove `op` if it can never be executed and [`Pass::DeadCode`] is
+    /// enabled. Returns `true` if it was removed.
+    fn eliminate_dead_code(&mut self, op: &AbstractOp) -> bool {
+        if !self.passes.contains(&Pass::DeadCode) {
+            return false;
+        }
+
+        let after = match self.unreachable_after {
+            Some(after) => after,
+            None => return false,
+        };
+
+        if matches!(op.specifier(), Some(Op::JumpDest(_))) {
+            return false;
+        }
+
+        // A variable sized push of an undeclared label would be assembled
+        // with at least two bytes.
+        let bytes_saved = op
+            .size()
+            .or_else(|| {
+                op.clone()
+                    .concretize((&self.declared_labels, &self.declared_macros).into())
+                    .ok()
+                    .map(|cop| cop.size())
+            })
+            .unwrap_or(2);
+
+        match self.optimizations.last_mut() {
+            Some(last) if self.dead_code_end == Some(self.ready.len()) => {
+                last.removed.push(op.clone());
+                last.bytes_saved += bytes_saved;
+            }
+            _ => self.optimizations.push(Optimization {
+                pass: Pass::DeadCode,
+                after: Some(after),
+                removed: vec![op.clone()],
+                replacement: vec![],
+                bytes_saved,
+            }),
+        }
+
+        self.dead_code_end = Some(self.ready.len());
+        true
+    }
+
+    /// Replace `op` and the instruction before it with a cheaper sequence, if
+    /// [`Pass::Peephole`] is enabled and one is known. Returns `true` if `op`
+    /// was replaced.
+    fn peephole(&mut self, op: &AbstractOp, cop: &Op<[u8]>) -> bool {
+        if !self.passes.contains(&Pass::Peephole) || self.ready.len() <= self.peephole_start {
+            return false;
+        }
+
+        let prev = match self.ready.last() {
+            Some(RawOp::Op(prev)) => prev.clone(),
+            _ => return false,
+        };
+
+        // Labels may still move, so only instructions that don't depend on
+        // them are rewritten.
+        let fixed = |op: &AbstractOp| op.expr().is_none() || self.is_constant(op);
+        if !fixed(&prev) || !fixed(op) {
+            return false;
+        }
+
+        let prev_cop = match prev
+            .clone()
+            .concretize((&self.declared_labels, &self.declared_macros).into())
+        {
+            Ok(prev_cop) => prev_cop,
+            Err(_) => return false,
+        };
+
+        let replacement = match optimize::peephole(&prev_cop, cop) {
+            Some(replacement) => replacement,
+            None => return false,
+        };
+
+        self.ready.pop();
+        self.concrete_len -= prev_cop.size();
+
+        let mut bytes_saved = prev_cop.size() + cop.size();
+        for rop in replacement.iter() {
+            bytes_saved -= rop.size();
+            self.concrete_len += rop.size();
+            self.ready.push(RawOp::Op(rop.clone().into()));
+        }
+
+        // Source locations of removed instructions would otherwise apply to
+        // the instructions that take their place.
+        let len = self.ready.len();
+        self.locations.retain(|(start, _)| *start <= len);
+        self.keeps.retain(|(start, _)| *start <= len);
+
+        self.optimizations.push(Optimization {
+            pass: Pass::Peephole,
+            after: None,
+            removed: vec![prev, op.clone()],
+            replacement: replacement.into_iter().map(AbstractOp::from).collect(),
+            bytes_saved,
+        });
+
+        true
+    }
+
+    /// Returns `true` if `op` has an expression that doesn't depend on labels.
+    fn is_constant(&self, op: &AbstractOp) -> bool {
+        matches!(
+            op.expr().map(|e| e.labels(&self.declared_macros)),
+            Some(Ok(labels)) if labels.is_empty()
+        )
+    }
+
+    fn synthesize(&mut self, op: &AbstractOp, cop: &Op<[u8]>) -> Option<Vec<Op<[u8]>>> {
+        let model = self.synthesis?;
+
+        if !self.is_constant(op) {
+            return None;
+        }
+
+        let latest;
+        let target = match self.target {
+            Some(ref profile) => profile,
+            None => {
+                latest = Profile::builtin(TARGET_FORK).expect("target fork should have a profile");
+                &latest
+            }
+        };
+
+        let replacement = synth::synthesize(cop, model, target)?;
+        self.substitutions.push(Substitution {
+            original: cop.clone(),
+            replacement: replacement.clone(),
+        });
+        Some(replacement)
+    }
+
+    /// Fails if `spec` is an instruction of EOF, and the target isn't an EOF
+    /// profile.
+    fn check_eof(&self, spec: Op<()>) -> Result<(), Error> {
+        let eof = self.target.as_ref().map_or(false, |t| t.eof);
+        ensure!(
+            eof || !ops::is_eof(&spec),
+            error::EofInstruction {
+                spec,
+                source_location: self.location(),
+            }
+        );
+        Ok(())
+    }
+
+    fn check_push_size(&mut self, op: &AbstractOp, cop: &Op<[u8]>) {
+        // Only fixed size pushes of constants are checked, since labels may
+        // move and `%push` is already sized to fit.
+        if !matches!(op, AbstractOp::Op(_)) || !self.is_constant(op) || !ops::is_push(&cop.code()) {
+            return;
+        }
+
+        let imm = match cop.immediate() {
+            Some(i) => i,
+            None => return,
+        };
+
+        let needed = std::cmp::max(1, imm.iter().skip_while(|b| **b == 0).count());
+        if needed < imm.len() {
+            self.warnings.push(Warning::OversizedPush {
+                spec: cop.code(),
+                value: BigInt::from_bytes_be(Sign::Plus, imm),
+                minimum: Op::push(needed).unwrap(),
+            });
+        }
+    }
+
+    fn check_unused_labels(&mut self) {
+        if self.exports_all_labels {
+            return;
+        }
+
+        for label in self.declared_labels.keys() {
+            // Labels added by `Ingest`, like the one marking the runtime code,
+            // can't be written in a source.
+            if label.starts_with('%') {
+                continue;
+            }
+
+            // The bounds of a `%scope` mark a section of the code, even when
+            // they aren't used.
+            if self.is_scope_bound(label) {
+                continue;
+            }
+
+            if self.exported_labels.contains(label) {
+                continue;
+            }
+
+            if !self.referenced_labels.contains(label) {
+                self.warnings.push(Warning::UnusedLabel {
+                    label: label.clone(),
+                });
+            }
+        }
+    }
+
+    /// Whether `label` is `name.start` or `name.end`, and the other bound of
+    /// `name` is declared too.
+    fn is_scope_bound(&self, label: &str) -> bool {
+        let other = match label.rsplit_once('.') {
+            Some((name, "start")) => format!("{}.end", name),
+            Some((name, "end")) => format!("{}.start", name),
+            _ => return false,
+        };
+
+        self.declared_labels.contains_key(&other)
+    }
+
+    fn declare_label(&mut self, rop: &RawOp) -> Result<(), Error> {
+        if let RawOp::Op(AbstractOp::Label(label)) = rop {
+            if self.declared_labels.contains_key(label) || self.externs.contains(label) {
+                return error::DuplicateLabel {
+                    label: label.to_owned(),
+                    source_location: self.location(),
+                    previous: self.label_locations.get(label).copied(),
+                }
+                .fail();
+            }
+            self.declared_labels.insert(label.to_owned(), None);
+            if let Some(location) = self.location() {
+                self.label_locations.insert(label.to_owned(), location);
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluate the arms of `%dispatch`, given as pairs of selectors and
+    /// targets, and build the macro that dispatches them.
+    fn dispatch_macro(
+        &self,
+        parameters: &[Expression],
+    ) -> Result<InstructionMacroDefinition, Error> {
+        if parameters.len() % 2 != 0 {
+            return error::DispatchArguments {
+                got: parameters.len(),
+            }
+            .fail();
+        }
+
+        let labels = IndexMap::new();
+        let mut arms = Vec::with_capacity(parameters.len() / 2);
+
+        for arm in parameters.chunks(2) {
+            let ctx = (&labels, &self.declared_macros).into();
+            let selector = arm[0]
+                .eval_with_context(ctx)
+                .ok()
+                .and_then(|value| u32::try_from(&value).ok());
+
+            match selector {
+                Some(selector) => arms.push((selector, arm[1].clone())),
+                None => {
+                    return error::InvalidSelector {
+                        expr: arm[0].clone(),
+                    }
+                    .fail()
+                }
+            }
+        }
+
+        arms.sort_by_key(|(selector, _)| *selector);
+
+        if let Some(arms) = arms.windows(2).find(|w| w[0].0 == w[1].0) {
+            return error::DuplicateSelector {
+                selector: arms[0].0,
+            }
+            .fail();
+        }
+
+        let selectors: Vec<_> = arms.iter().map(|(selector, _)| *selector).collect();
+        let model = self.dispatch_model.unwrap_or(CostModel::Gas);
+        let strategy = self
+            .dispatch_strategy
+            .unwrap_or_else(|| dispatch::choose(&selectors, model));
+
+        if dispatch::estimate(strategy, &selectors).is_none() {
+            return match strategy {
+                Strategy::PerfectHash => error::NoPerfectHash.fail(),
+                _ => error::NoSelectorMask.fail(),
+            };
+        }
+
+        Ok(InstructionMacroDefinition::dispatch(&arms, strategy))
+    }
+
+    /// Evaluate the options of a trampoline, given as a target followed by
+    /// the optional gas (zero forwards all of it) and bubble flag, and build
+    /// the macro that forwards calls to the target.
+    fn trampoline_macro(
+        &self,
+        name: &str,
+        kind: CallKind,
+        parameters: &[Expression],
+    ) -> Result<InstructionMacroDefinition, Error> {
+        if parameters.is_empty() || parameters.len() > 3 {
+            return error::TrampolineArguments {
+                name,
+                got: parameters.len(),
+            }
+            .fail();
+        }
+
+        let labels = IndexMap::new();
+        let option = |expr: &Expression| {
+            let ctx = (&labels, &self.declared_macros).into();
+            expr.eval_with_context(ctx)
+                .ok()
+                .context(error::InvalidTrampolineOption { expr: expr.clone() })
+        };
+
+        let mut gas = None;
+        if let Some(expr) = parameters.get(1) {
+            if option(expr)?.sign() != Sign::NoSign {
+                gas = Some(expr.clone());
+            }
+        }
+
+        let bubble = match parameters.get(2) {
+            Some(expr) => option(expr)?.sign() != Sign::NoSign,
+            None => true,
+        };
+
+        Ok(InstructionMacroDefinition::trampoline(
+            name,
+            kind,
+            parameters[0].clone(),
+            gas,
+            bubble,
+        ))
+    }
+
+    /// A suffix that makes the name of a label local to an instruction macro
+    /// expansion unique. When reproducible, or without `rand` (for example,
+    /// in WebAssembly), it counts up instead of being random.
+    fn label_suffix(&mut self) -> u64 {
+        #[cfg(feature = "rand")]
+        if !self.reproducible {
+            return rand::random();
+        }
+
+        self.last_label_suffix += 1;
+        self.last_label_suffix
+    }
+
+    fn expand_macro(
+        &mut self,
+        name: &str,
+        parameters: &[Expression],
+    ) -> Result<Option<usize>, Error> {
+        let arguments = if self.macro_details || self.trace.is_some() {
+            parameters.iter().map(Expression::to_string).collect()
+        } else {
+            Vec::new()
+        };
+
+        let (defn, parameters) = match self.declared_macros.get(name) {
+            Some(defn) => (Some(defn.clone()), parameters),
+            None if name == "dispatch" => {
+                let defn = self.dispatch_macro(parameters)?;
+                (Some(defn.into()), &[][..])
+            }
+            None if CallKind::from_trampoline(name).is_some() => {
+                let kind = CallKind::from_trampoline(name).unwrap();
+                let defn = self.trampoline_macro(name, kind, parameters)?;
+                (Some(defn.into()), &[][..])
+            }
+            None => (
+                InstructionMacroDefinition::builtin(name).map(Into::into),
+                parameters,
+            ),
+        };
+
+        // Remap labels to macro scope.
+        match defn {
+            Some(MacroDefinition::Instruction(mut m)) => {
+                if let Some(start) = self.macro_stack.iter().position(|n| n == name) {
+                    let mut cycle = self.macro_stack[start..].to_vec();
+                    cycle.push(name.to_owned());
+                    return error::RecursiveMacro { name, cycle }.fail();
+                }
+
+                let limit = self.macro_depth_limit.unwrap_or(DEFAULT_MACRO_DEPTH_LIMIT);
+                if self.macro_stack.len() >= limit {
+                    return error::MacroDepthLimit { name, limit }.fail();
+                }
+
+                let required = m.parameters.len() - m.defaults.len();
+                let expected = if parameters.len() < required {
+                    Some(required)
+                } else if m.variadic.is_none() && parameters.len() > m.parameters.len() {
+                    Some(m.parameters.len())
+                } else {
+                    None
+                };
+
+                if let Some(expected) = expected {
+                    return error::MacroArgumentCount {
+                        name,
+                        expected,
+                        got: parameters.len(),
+                        source_location: self.location(),
+                    }
+                    .fail();
+                }
+
+                let (fixed, rest) = parameters.split_at(parameters.len().min(m.parameters.len()));
+                let values = fixed
+                    .iter()
+                    .chain(m.defaults.iter().skip(fixed.len() - required))
+                    .cloned();
+
+                let parameters: HashMap<String, Expression> =
+                    m.parameters.into_iter().zip(values).collect();
+
+                // Repeat each statement using the variadic parameter once per
+                // remaining argument.
+                if let Some(variadic) = m.variadic.take() {
+                    let mut contents = Vec::with_capacity(m.contents.len());
+                    for mut op in m.contents.drain(..) {
+                        // Nested invocations receive the remaining arguments
+                        // in place of the variadic parameter.
+                        if let AbstractOp::Macro(ref mut invc) = op {
+                            invc.parameters = invc
+                                .parameters
+                                .drain(..)
+                                .flat_map(|param| match param {
+                                    Expression::Terminal(Terminal::Variable(ref v))
+                                        if *v == variadic =>
+                                    {
+                                        rest.to_vec()
+                                    }
+                                    param => vec![param],
+                                })
+                                .collect();
+                            contents.push(op);
+                            continue;
+                        }
+
+                        if !op.expr().map_or(false, |e| e.uses_variable(&variadic)) {
+                            contents.push(op);
+                            continue;
+                        }
+
+                        for arg in rest {
+                            let mut op = op.clone();
+                            op.expr_mut().unwrap().fill_variable(&variadic, arg);
+                            contents.push(op);
+                        }
+                    }
+                    m.contents = contents;
+                }
+
+                let mut labels = HashMap::<String, String>::new();
+
+                // First pass, find locally defined labels and rename them.
+                for op in m.contents.iter_mut() {
+                    match op {
+                        AbstractOp::Label(ref mut label) => {
+                            let suffix = self.label_suffix();
+                            let mangled = format!("{}_{}_{}", m.name, label, suffix);
+                            let old = labels.insert(label.to_owned(), mangled.clone());
+                            if old.is_some() {
+                                return error::DuplicateLabel {
+                                    label: label.to_string(),
+                                    source_location: None,
+                                    previous: None,
+                                }
+                                .fail();
+                            }
+                            *label = mangled;
+                        }
+                        _ => continue,
+                    }
+                }
+
+                // Labels local to an expansion are an implementation detail of
+                // the macro, so they are never reported as unused.
+                self.referenced_labels.extend(labels.values().cloned());
+
+                // Second pass, update local label invocations and fill in
+                // parameters, including in the arguments of nested macros.
+                for op in m.contents.iter_mut() {
+                    let exprs: Vec<&mut Expression> = match op {
+                        AbstractOp::Macro(invc) => invc.parameters.iter_mut().collect(),
+                        op => op.expr_mut().into_iter().collect(),
+                    };
+
+                    for expr in exprs {
+                        for (label, mangled) in labels.iter() {
+                            expr.replace_label(label, mangled);
+                        }
+
+                        for (name, value) in parameters.iter() {
+                            expr.fill_variable(name, value)
+                        }
+                    }
+                }
+
+                let position = self.concrete_len;
+                self.record(|| Event::MacroExpanded {
+                    name: name.to_owned(),
+                    arguments: arguments.clone(),
+                    position,
+                });
+
+                let expansion = self.expansions.len();
+                self.expansions.push((
+                    name.to_owned(),
+                    arguments,
+                    self.ready.len(),
+                    self.ready.len(),
+                ));
+                self.peephole_start = self.ready.len();
+
+                self.macro_stack.push(name.to_owned());
+                let result = m.contents.iter().try_for_each(|op| self.push(op).map(drop));
+                self.macro_stack.pop();
+                result?;
+
+                self.expansions[expansion].3 = self.ready.len();
+                self.peephole_start = self.ready.len();
+                Ok(Some(self.concrete_len))
+            }
+            _ => error::UndeclaredInstructionMacro {
+                name,
+                source_location: self.location(),
+            }
+            .fail(),
+        }
+    }
+}
+
+/// Describe the instructions in `code`, like `push1 0x01`.
+fn disassemble(code: &[u8]) -> Vec<String> {
+    let mut dasm = Disassembler::<Op<[u8]>>::default();
+    dasm.write_all(code)
+        .expect("writing to a disassembler can't fail");
+    dasm.records()
+        .map(|r| match r.immediate {
+            Some(immediate) => format!("{} {}", r.mnemonic, immediate),
+            None => r.mnemonic,
+        })
+        .collect()
+}
+
+/// Describe the instruction macro `m` for the debug info.
+fn macro_info(m: &InstructionMacroDefinition) -> MacroInfo {
+    let required = m.parameters.len() - m.defaults.len();
+    let mut parameters: Vec<_> = m
+        .parameters
+        .iter()
+        .enumerate()
+        .map(|(idx, name)| match idx.checked_sub(required) {
+            Some(default) => format!("{} = {}", name, m.defaults[default]),
+            None => name.clone(),
+        })
+        .collect();
+    parameters.extend(m.variadic.iter().map(|v| format!("{}...", v)));
+
+    MacroInfo {
+        name: m.name.clone(),
+        parameters,
+        contents: m.contents.iter().map(AbstractOp::to_string).collect(),
+    }
+}
+
+/// Rewrite the destination of the relative jump `op` from `label` to
+/// `rel(label)`, if it's given as a bare label.
+fn relative_label(op: &mut AbstractOp) {
+    if !matches!(op.specifier(), Some(spec) if ops::is_relative_jump(&spec)) {
+        return;
+    }
+
+    if let Some(expr) = op.expr().and_then(relative) {
+        *op.expr_mut().unwrap() = expr;
+    }
+}
+
+/// The `rel(label)` a bare label stands for in the offset of a relative jump.
+fn relative(expr: &Expression) -> Option<Expression> {
+    match expr {
+        Expression::Terminal(Terminal::Label(label)) => {
+            Some(Expression::Macro(ExpressionMacroInvocation {
+                name: "rel".into(),
+                parameters: vec![Terminal::Label(label.clone()).into()],
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Convert an error from evaluating `expr` into an assembler error.
+pub(crate) fn invalid_expression(source: ops::expression::Error, expr: &Expression) -> Error {
+    let expr = expr.clone();
+    match source {
+        UnknownLabel { label, .. } => error::UndeclaredLabels {
+            labels: vec![label],
+        }
+        .build(),
+        UnknownMacro { name, .. } => error::UndeclaredExpressionMacro { name }.build(),
+        UndefinedVariable { name, .. } => {
+            error::UndeclaredVariableMacro { var: name, expr }.build()
+        }
+        OutOfDomain {
+            operation, value, ..
+        } => error::InvalidOperand {
+            operation,
+            value,
+            expr,
+        }
+        .build(),
+        ArgumentCount {
+            name,
+            expected,
+            got,
+            ..
+        } => error::MacroArgumentCount {
+            name,
+            expected,
+            got,
+            source_location: None,
+        }
+        .build(),
+        InvalidHex { text, .. } => error::InvalidHex { text, expr }.build(),
+        RecursionLimit { name, limit, .. } => error::MacroDepthLimit { name, limit }.build(),
+        RecursiveMacro { name, cycle, .. } => error::RecursiveMacro { name, cycle }.build(),
+        UnknownPosition { .. } => error::RelativeOutsideImmediate { expr }.build(),
+        UnknownCapability { name, .. } => error::UnknownCapability { name }.build(),
+        UnknownTarget { name, .. } => error::CapabilityOutsideCondition { expr, name }.build(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::{
+        Expression, ExpressionMacroDefinition, ExpressionMacroInvocation, Imm,
+        InstructionMacroDefinition, InstructionMacroInvocation, Terminal,
+    };
+    use assert_matches::assert_matches;
+    use etk_ops::cancun::*;
+    use etk_ops::prague::{self, Op};
+    use hex_literal::hex;
+    use num_bigint::{BigInt, Sign};
+
+    #[test]
+    fn assemble_variable_push_const_while_pending() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![
+            AbstractOp::Op(prague::Push1(Imm::with_label("label1")).into()),
+            AbstractOp::Push(Terminal::Number(0xaabb.into()).into()),
+            AbstractOp::Label("label1".into()),
+        ];
+        let result = asm.assemble(&code)?;
+        assert_eq!(result, hex!("600561aabb"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_pushes_abab() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![
+            AbstractOp::new(JumpDest),
+            AbstractOp::Push(Imm::with_label("label1")),
+            AbstractOp::Push(Imm::with_label("label2")),
+            AbstractOp::Label("label1".into()),
+            AbstractOp::new(GetPc),
+            AbstractOp::Label("label2".into()),
+            AbstractOp::new(GetPc),
+        ];
+        let result = asm.assemble(&code)?;
+        assert_eq!(result, hex!("5b600560065858"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_pushes_abba() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![
+            AbstractOp::new(JumpDest),
+            AbstractOp::Push(Imm::with_label("label1")),
+            AbstractOp::Push(Imm::with_label("label2")),
+            AbstractOp::Label("label2".into()),
+            AbstractOp::new(GetPc),
+            AbstractOp::Label("label1".into()),
+            AbstractOp::new(GetPc),
+        ];
+        let result = asm.assemble(&code)?;
+        assert_eq!(result, hex!("5b600660055858"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_push1_multiple() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![
+            AbstractOp::new(JumpDest),
+            AbstractOp::Push(Imm::with_label("auto")),
+            AbstractOp::Push(Imm::with_label("auto")),
+            AbstractOp::Label("auto".into()),
+        ];
+        let result = asm.assemble(&code)?;
+        assert_eq!(result, hex!("5b60056005"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_push_grown_keeps_earlier_labels() -> Result<(), Error> {
+        let mut code = vec![
+            AbstractOp::Label("start".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::Push(Imm::with_label("end")),
+            AbstractOp::Push(Imm::with_label("start")),
+        ];
+        code.extend((0..256).map(|_| AbstractOp::new(JumpDest)));
+        code.push(AbstractOp::Label("end".into()));
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&code)?;
+
+        // Growing `%push(end)` used to move every label, so `start` was
+        // pushed as 1 (`6001`) instead of 0.
+        let mut expected = hex!("5b6101066000").to_vec();
+        expected.extend([0x5b; 256]);
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_push_grown_by_later_push() -> Result<(), Error> {
+        let mut code = vec![
+            AbstractOp::Push(Imm::with_label("b")),
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label("a".into()).into(),
+                Terminal::Number(0x100.into()).into(),
+            ))),
+            AbstractOp::Label("a".into()),
+        ];
+        code.extend((0..251).map(|_| AbstractOp::new(JumpDest)));
+        code.push(AbstractOp::Label("b".into()));
+        code.push(AbstractOp::new(JumpDest));
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&code)?;
+
+        // `b` starts at 0xff, and only moves past it once the second push
+        // grows. Checking each push once missed that `%push(b)` grows too, so
+        // `b` and `a` were pushed a byte short (`610100610105`).
+        let mut expected = hex!("610101610106").to_vec();
+        expected.extend([0x5b; 252]);
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_rollback() -> Result<(), Error> {
+        let missing = [AbstractOp::new(Push1(Imm::with_label("missing")))];
+
+        let mut asm = Assembler::new();
+        asm.assemble(&[AbstractOp::Label("a".into()), AbstractOp::new(JumpDest)])?;
+        let checkpoint = asm.checkpoint();
+
+        assert!(asm.assemble(&missing).is_err());
+        let err = asm.assemble(&[AbstractOp::new(Caller)]).unwrap_err();
+        assert_matches!(err, Error::UndeclaredLabels { labels, .. } if labels == ["missing"]);
+
+        asm.rollback(checkpoint.clone());
+        assert_eq!(asm.assemble(&[AbstractOp::new(Caller)])?, hex!("33"));
+        assert_eq!(asm.label("a"), Some(0));
+
+        asm.rollback(checkpoint);
+        let err = asm.assemble(&[AbstractOp::Label("a".into())]).unwrap_err();
+        assert_matches!(err, Error::DuplicateLabel { label, .. } if label == "a");
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_take() -> Result<(), Error> {
+        let first = vec![
+            AbstractOp::Label("start".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push1(Imm::with_label("start"))),
+            AbstractOp::Push(Imm::with_label("end")),
+            AbstractOp::new(Jump),
+        ];
+        let second = vec![AbstractOp::Label("end".into()), AbstractOp::new(JumpDest)];
+
+        let mut asm = Assembler::new();
+        asm.push_all(&first)?;
+        assert_eq!(asm.take()?, hex!("5b6000"));
+
+        // The push may still grow, so the code after it isn't ready either.
+        asm.push_all(&second)?;
+        assert!(asm.take()?.is_empty());
+        assert_eq!(asm.finish()?, hex!("6006565b"));
+        assert_eq!(asm.label("end"), Some(6));
+
+        let all: Vec<_> = first.into_iter().chain(second).collect();
+        assert_eq!(Assembler::new().assemble(&all)?, hex!("5b60006006565b"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_take_expansion() -> Result<(), Error> {
+        let defn = InstructionMacroDefinition::new(
+            "two".into(),
+            vec![],
+            vec![AbstractOp::new(Caller), AbstractOp::new(Caller)],
+        );
+
+        let mut asm = Assembler::new();
+        asm.push_all(&[
+            AbstractOp::from(defn),
+            AbstractOp::new(Caller),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "two".into(),
+                parameters: vec![],
+            }),
+            AbstractOp::new(Push1(Imm::with_label("later"))),
+        ])?;
+        assert_eq!(asm.take()?, hex!("333333"));
+
+        asm.push_all(&[AbstractOp::Label("later".into()), AbstractOp::new(JumpDest)])?;
+        assert_eq!(asm.take()?, hex!("60055b"));
+        assert!(asm.finish()?.is_empty());
+
+        // Offsets count from the start of the program, not the last take.
+        let expansion = &asm.debug_info().expansions[0];
+        assert_eq!((expansion.start, expansion.end), (1, 3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_trace() -> Result<(), Error> {
+        let mut code = vec![AbstractOp::Push(Imm::with_label("far"))];
+        code.extend((0..300).map(|_| AbstractOp::new(JumpDest)));
+        code.push(AbstractOp::Label("far".into()));
+
+        let mut asm = Assembler::new();
+        asm.assemble(&code)?;
+        assert_eq!(asm.trace(), None);
+
+        let mut asm = Assembler::new();
+        asm.set_tracing(true);
+        let result = asm.assemble(&code)?;
+        assert_eq!(&result[..3], hex!("61012f"));
+
+        let trace = asm.trace().unwrap();
+        assert_eq!(
+            trace.events,
+            [
+                Event::PushDeferred {
+                    expr: "Label(far)".into(),
+                    position: 0,
+                    size: 2,
+                },
+                Event::LabelDeclared {
+                    label: "far".into(),
+                    position: 302,
+                },
+                Event::PushGrown {
+                    expr: "Label(far)".into(),
+                    size: 3,
+                    shift: 1,
+                },
+                Event::Emitted {
+                    op: "%push(Label(far))".into(),
+                    position: 0,
+                    size: 3,
+                },
+                Event::LabelResolved {
+                    label: "far".into(),
+                    position: 303,
+                },
+            ]
+        );
+
+        assert_eq!(trace.labels_at(2)["far"], 302);
+        assert_eq!(trace.labels_at(3)["far"], 303);
+
+        // Assembling again replaces the trace.
+        asm.assemble(&[AbstractOp::new(JumpDest)])?;
+        let events = &asm.trace().unwrap().events;
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Event::PushDeferred { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_push_const() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![AbstractOp::Push(
+            Terminal::Number((0x00aaaaaaaaaaaaaaaaaaaaaaaa as u128).into()).into(),
+        )];
+        let result = asm.assemble(&code)?;
+        assert_eq!(result, hex!("6baaaaaaaaaaaaaaaaaaaaaaaa"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_push_too_large() {
+        let v = BigInt::from_bytes_be(Sign::Plus, &[1u8; 33]);
+
+        let mut asm = Assembler::new();
+        let code = vec![AbstractOp::Push(Terminal::Number(v).into())];
+        let err = asm.assemble(&code).unwrap_err();
+
+        assert_matches!(
+            err,
+            Error::ExpressionTooLarge {
+                needed: 33,
+                reason: Some(_),
+                ..
+            }
+        );
+        assert!(
+            err.to_string().contains("more than push32 can hold"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn assemble_variable_push_negative() {
+        let mut asm = Assembler::new();
+        let code = vec![AbstractOp::Push(Terminal::Number((-1).into()).into())];
+        let err = asm.assemble(&code).unwrap_err();
+
+        assert_matches!(err, Error::ExpressionNegative { .. });
+    }
+
+    #[test]
+    fn assemble_variable_push_const0() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![AbstractOp::Push(
+            Terminal::Number((0x00 as u128).into()).into(),
+        )];
+        let result = asm.assemble(&code)?;
+        assert_eq!(result, hex!("6000"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_push1_known() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![
+            AbstractOp::new(JumpDest),
+            AbstractOp::Label("auto".into()),
+            AbstractOp::Push(Imm::with_label("auto")),
+        ];
+        let result = asm.assemble(&code)?;
+        assert_eq!(result, hex!("5b6001"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_push1() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![
+            AbstractOp::Push(Imm::with_label("auto")),
+            AbstractOp::Label("auto".into()),
+            AbstractOp::new(JumpDest),
+        ];
+        let result = asm.assemble(&code)?;
+        assert_eq!(result, hex!("60025b"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_push1_reuse() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![
+            AbstractOp::Push(Imm::with_label("auto")),
+            AbstractOp::Label("auto".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push1(Imm::with_label("auto"))),
+        ];
+        let result = asm.assemble(&code)?;
+        assert_eq!(result, hex!("60025b6002"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_push2() -> Result<(), Error> {
+        let mut code = vec![];
+        code.push(AbstractOp::Push(Imm::with_label("auto")));
+        for _ in 0..255 {
+            code.push(AbstractOp::new(GetPc));
+        }
+
+        code.push(AbstractOp::Label("auto".into()));
+        code.push(AbstractOp::new(JumpDest));
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&code)?;
+
+        let mut expected = vec![0x61, 0x01, 0x02];
+        expected.extend_from_slice(&[0x58; 255]);
+        expected.push(0x5b);
+        assert_eq!(result, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_variable_push3() -> Result<(), Error> {
+        let mut code = vec![];
+        code.push(AbstractOp::Push(Imm::with_label("auto")));
+        for _ in 0..65537 {
+            code.push(AbstractOp::new(GetPc));
+        }
+
+        code.push(AbstractOp::Label("auto".into()));
+        code.push(AbstractOp::new(JumpDest));
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&code)?;
+
+        let mut expected = vec![0x62, 0x01, 0x00, 0x05];
+        expected.extend_from_slice(&[0x58; 65537]);
+        expected.push(0x5b);
+
+        assert_eq!(result, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_undeclared_label() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![AbstractOp::new(Push1(Imm::with_label("hi")))];
+        let err = asm.assemble(&code).unwrap_err();
+        assert_matches!(err, Error::UndeclaredLabels { labels, .. } if labels == vec!["hi"]);
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_jumpdest_no_label() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let code = vec![AbstractOp::new(JumpDest)];
+        let result = asm.assemble(&code)?;
+        assert!(asm.declared_labels.is_empty());
+        assert_eq!(result, hex!("5b"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_jumpdest_with_label() -> Result<(), Error> {
+        let mut asm = Assembler::new();
+        let ops = vec![AbstractOp::Label("lbl".into()), AbstractOp::new(JumpDest)];
+
+        let result = asm.assemble(&ops)?;
+        assert_eq!(asm.declared_labels.len(), 1);
+        assert_eq!(
+            asm.declared_labels.get("lbl"),
+            Some(&Some(LabelDef {
+                position: 0,
+                updated: false
+            }))
+        );
+        assert_eq!(result, hex!("5b"));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_jumpdest_jump_with_label() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::Label("lbl".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push1(Imm::with_label("lbl"))),
+        ];
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("5b6000"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_labeled_pc() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::new(Push1(Imm::with_label("lbl"))),
+            AbstractOp::Label("lbl".into()),
+            AbstractOp::new(GetPc),
+        ];
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("600258"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_jump_jumpdest_with_label() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::new(Push1(Imm::with_label("lbl"))),
+            AbstractOp::Label("lbl".into()),
+            AbstractOp::new(JumpDest),
+        ];
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("60025b"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_label_too_large() {
+        let mut ops: Vec<_> = vec![AbstractOp::new(GetPc); 255];
+        ops.push(AbstractOp::Label("b".into()));
+        ops.push(AbstractOp::new(JumpDest));
+        ops.push(AbstractOp::Label("a".into()));
+        ops.push(AbstractOp::new(JumpDest));
+        ops.push(AbstractOp::new(Push1(Imm::with_label("a"))));
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::ExpressionTooLarge { expr: Expression::Terminal(Terminal::Label(ref label)), needed: 2, reason: None, .. } if label == "a");
+        assert!(
+            err.to_string().contains("it needs 2 bytes, or push2"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn assemble_label_just_right() -> Result<(), Error> {
+        let mut ops: Vec<_> = vec![AbstractOp::new(GetPc); 255];
+        ops.push(AbstractOp::Label("b".into()));
+        ops.push(AbstractOp::new(JumpDest));
+        ops.push(AbstractOp::Label("a".into()));
+        ops.push(AbstractOp::new(JumpDest));
+        ops.push(AbstractOp::new(Push1(Imm::with_label("b"))));
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+
+        let mut expected = vec![0x58; 255];
+        expected.push(0x5b);
+        expected.push(0x5b);
+        expected.push(0x60);
+        expected.push(0xff);
+
+        assert_eq!(result, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_instruction_macro_label_underscore() -> Result<(), Error> {
+        let ops = vec![
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec![],
+                vec![AbstractOp::Label("a".into())],
+            )
+            .into(),
+            InstructionMacroDefinition::new(
+                "my".into(),
+                vec![],
+                vec![AbstractOp::Label("macro_a".into())],
+            )
+            .into(),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![],
+            }),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my".into(),
+                parameters: vec![],
+            }),
+        ];
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert!(result.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_reproducible() -> Result<(), Error> {
+        let ops = vec![
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec![],
+                vec![
+                    AbstractOp::Label("a".into()),
+                    AbstractOp::new(JumpDest),
+                    AbstractOp::new(Push1(Imm::with_label("a"))),
+                ],
+            )
+            .into(),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![],
+            }),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![],
+            }),
+        ];
+
+        let assemble = || -> Result<DebugInfo, Error> {
+            let mut asm = Assembler::new();
+            asm.set_reproducible(true);
+            assert_eq!(asm.assemble(&ops)?, hex!("5b6000 5b6003"));
+            Ok(asm.debug_info().clone())
+        };
+
+        let first = assemble()?;
+        assert_eq!(first, assemble()?);
+
+        let labels: Vec<_> = first.labels.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(labels, ["my_macro_a_1", "my_macro_a_2"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_instruction_macro_twice() -> Result<(), Error> {
+        let ops = vec![
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec![],
+                vec![
+                    AbstractOp::Label("a".into()),
+                    AbstractOp::new(JumpDest),
+                    AbstractOp::new(Push1(Imm::with_label("a"))),
+                    AbstractOp::new(Push1(Imm::with_label("b"))),
+                ],
+            )
+            .into(),
+            AbstractOp::Label("b".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push1(Imm::with_label("b"))),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![],
+            }),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![],
+            }),
+        ];
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("5b60005b600360005b60086000"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_instruction_macro() -> Result<(), Error> {
+        let ops = vec![
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec![],
+                vec![
+                    AbstractOp::Label("a".into()),
+                    AbstractOp::new(JumpDest),
+                    AbstractOp::new(Push1(Imm::with_label("a"))),
+                    AbstractOp::new(Push1(Imm::with_label("b"))),
+                ],
+            )
+            .into(),
+            AbstractOp::Label("b".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push1(Imm::with_label("b"))),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![],
+            }),
+        ];
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("5b60005b60036000"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_instruction_macro_delayed_definition() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::Label("b".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push1(Imm::with_label("b"))),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![],
+            }),
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec![],
+                vec![
+                    AbstractOp::Label("a".into()),
+                    AbstractOp::new(JumpDest),
+                    AbstractOp::new(Push1(Imm::with_label("a"))),
+                    AbstractOp::new(Push1(Imm::with_label("b"))),
+                ],
+            )
+            .into(),
+        ];
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("5b60005b60036000"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_instruction_macro_with_variable_push() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![],
+            }),
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec![],
+                vec![
+                    AbstractOp::new(JumpDest),
+                    AbstractOp::Push(Imm::with_label("label1")),
+                    AbstractOp::Push(Imm::with_label("label2")),
+                    AbstractOp::Label("label1".into()),
+                    AbstractOp::new(GetPc),
+                    AbstractOp::Label("label2".into()),
+                    AbstractOp::new(GetPc),
+                ],
+            )
+            .into(),
+        ];
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("5b600560065858"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_undeclared_instruction_macro() -> Result<(), Error> {
+        let ops = vec![AbstractOp::Macro(
+            InstructionMacroInvocation::with_zero_parameters("my_macro".into()),
+        )];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::UndeclaredInstructionMacro { name, .. } if name == "my_macro");
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_duplicate_instruction_macro() -> Result<(), Error> {
+        let ops: Vec<AbstractOp> = vec![
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec![],
+                vec![AbstractOp::new(Caller)],
+            )
+            .into(),
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec![],
+                vec![AbstractOp::new(Caller)],
+            )
+            .into(),
+        ];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::DuplicateMacro { name, .. } if name == "my_macro");
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_duplicate_labels_in_instruction_macro() -> Result<(), Error> {
+        let ops = vec![
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec![],
+                vec![AbstractOp::Label("a".into()), AbstractOp::Label("a".into())],
+            )
+            .into(),
+            AbstractOp::Macro(InstructionMacroInvocation::with_zero_parameters(
+                "my_macro".into(),
+            )),
+        ];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::DuplicateLabel { label, .. } if label == "a");
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_explanation() {
+        let mut asm = Assembler::new();
+        asm.push(RawOp::Op(AbstractOp::Label("a".into()))).unwrap();
+        let err = asm
+            .push(RawOp::Op(AbstractOp::Label("a".into())))
+            .unwrap_err();
+        let explanation = err.explanation().unwrap();
+        assert!(explanation.starts_with("A label was declared more than once"));
+
+        let err = Assembler::new()
+            .assemble(&[AbstractOp::new(Push1(Imm::with_label("b")))])
+            .unwrap_err();
+        assert_matches!(err, Error::UndeclaredLabels { .. });
+        assert!(err.explanation().is_some());
+
+        let mut asm = Assembler::new();
+        asm.set_strict(true);
+        let err = asm
+            .assemble(&[AbstractOp::Label("c".into()), AbstractOp::new(JumpDest)])
+            .unwrap_err();
+        assert_matches!(err, Error::PromotedWarning { .. });
+        assert_eq!(err.explanation(), None);
+    }
+
+    // TODO: do we allow label shadowing in macros?
+    #[test]
+    fn assemble_conflicting_labels_in_instruction_macro() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::Label("a".into()),
+            AbstractOp::new(Caller),
+            InstructionMacroDefinition::new(
+                "my_macro()".into(),
+                vec![],
+                vec![
+                    AbstractOp::Label("a".into()),
+                    AbstractOp::new(Push1(Imm::with_label("a"))),
+                ],
+            )
+            .into(),
+            AbstractOp::Macro(InstructionMacroInvocation::with_zero_parameters(
+                "my_macro()".into(),
+            )),
+            AbstractOp::new(Push1(Imm::with_label("a"))),
+        ];
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+
+        assert_eq!(result, hex!("3360016000"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_instruction_macro_with_parameters() -> Result<(), Error> {
+        let ops = vec![
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec!["foo".into(), "bar".into()],
+                vec![
+                    AbstractOp::new(Push1(Imm::with_variable("foo"))),
+                    AbstractOp::new(Push1(Imm::with_variable("bar"))),
+                ],
+            )
+            .into(),
+            AbstractOp::Label("b".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push1(Imm::with_label("b"))),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![
+                    BigInt::from_bytes_be(Sign::Plus, &vec![0x42]).into(),
+                    Terminal::Label("b".to_string()).into(),
+                ],
+            }),
+        ];
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("5b600060426000"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_expression_push() -> Result<(), Error> {
+        let ops = vec![AbstractOp::new(Push1(Imm::with_expression(
+            Expression::Plus(1.into(), 1.into()),
+        )))];
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("6002"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_expression_negative() -> Result<(), Error> {
+        let ops = vec![AbstractOp::new(Push1(Imm::with_expression(
+            BigInt::from(-1).into(),
+        )))];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::ExpressionNegative { value, .. } if value == BigInt::from(-1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_expression_bitwise() -> Result<(), Error> {
+        // (1 << 8) | (0xff & 0x0f)
+        let ops = vec![AbstractOp::new(Push2(Imm::with_expression(
+            Expression::Or(
+                Expression::ShiftLeft(1u64.into(), 8u64.into()).into(),
+                Expression::And(0xffu64.into(), 0x0fu64.into()).into(),
+            ),
+        )))];
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("61010f"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_expression_division_by_zero() {
+        // 1 % (label - label)
+        let ops = vec![
+            AbstractOp::Label("label".into()),
+            AbstractOp::new(Push1(Imm::with_expression(Expression::Modulo(
+                1u64.into(),
+                Expression::Minus(
+                    Terminal::Label("label".into()).into(),
+                    Terminal::Label("label".into()).into(),
+                )
+                .into(),
+            )))),
+        ];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(
+            err,
+            Error::InvalidOperand { operation, value, .. }
+                if operation == "%" && value == BigInt::from(0)
+        );
+    }
+
+    #[test]
+    fn assemble_expression_invalid_hex() {
+        let ops = vec![AbstractOp::Push(Imm::with_macro(
+            ExpressionMacroInvocation {
+                name: "hex".into(),
+                parameters: vec![Terminal::Bytes(b"abc".to_vec()).into()],
+            },
+        ))];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::InvalidHex { text, .. } if text == "abc");
+    }
+
+    #[test]
+    fn assemble_bytes_label() {
+        let ops = vec![
+            RawOp::Op(AbstractOp::Label("label".into())),
+            RawOp::Bytes(Terminal::Label("label".into()).into()),
+        ];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::LabelInData { label, .. } if label == "label");
+    }
+
+    #[test]
+    fn assemble_jump_table() -> Result<(), Error> {
+        let ops = vec![
+            RawOp::Op(AbstractOp::Label("a".into())),
+            RawOp::Op(AbstractOp::new(JumpDest)),
+            RawOp::Op(AbstractOp::Label("b".into())),
+            RawOp::Op(AbstractOp::new(JumpDest)),
+            RawOp::Op(AbstractOp::Label("table".into())),
+            RawOp::JumpTable(vec![
+                Terminal::Label("a".into()).into(),
+                Terminal::Label("c".into()).into(),
+                Expression::Plus(
+                    Terminal::Label("b".into()).into(),
+                    Terminal::Number(1.into()).into(),
+                ),
+            ]),
+            RawOp::Op(AbstractOp::Label("c".into())),
+            RawOp::Op(AbstractOp::new(JumpDest)),
+            RawOp::Op(AbstractOp::new(Push1(Imm::with_label("table")))),
+        ];
+        let mut asm = Assembler::new();
+        let code = asm.assemble(&ops)?;
+        assert_eq!(code, hex!("5b5b000000080002 5b 6002"));
+        assert_eq!(asm.label("c"), Some(8));
+
+        Ok(())
+    }
 
-    /// Feed a single instruction into the `Assembler`.
-    fn push<O>(&mut self, rop: O) -> Result<usize, Error>
-    where
-        O: Into<RawOp>,
-    {
-        let rop = rop.into();
-        self.declare_label(&rop)?;
+    #[test]
+    fn assemble_jump_table_errors() {
+        let ops = vec![RawOp::JumpTable(vec![
+            Terminal::Number(0x10000.into()).into()
+        ])];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::JumpTableEntryTooLarge { value, .. } if value == BigInt::from(0x10000));
 
-        match rop {
-            RawOp::Op(AbstractOp::Label(label)) => {
-                self.undeclared_labels.retain(|l| *l != label);
+        let ops = vec![RawOp::JumpTable(vec![Terminal::Label("a".into()).into()])];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::UndeclaredLabels { labels, .. } if labels == vec!["a"]);
+    }
 
-                let old = self
-                    .declared_labels
-                    .insert(
-                        label,
-                        Some(LabelDef {
-                            position: self.concrete_len,
-                            updated: false,
-                        }),
-                    )
-                    .expect("label should exist");
-                assert_eq!(old, None, "label should have been undefined");
-            }
-            RawOp::Op(AbstractOp::MacroDefinition(_)) => {}
-            RawOp::Op(AbstractOp::Macro(ref m)) => {
-                self.expand_macro(&m.name, &m.parameters)?;
-            }
-            RawOp::Op(ref op) => {
-                match op
-                    .clone()
-                    .concretize((&self.declared_labels, &self.declared_macros).into())
-                {
-                    Ok(cop) => {
-                        self.concrete_len += cop.size();
-                        self.ready.push(rop.clone())
-                    }
-                    Err(ops::Error::ExpressionTooLarge { value, spec, .. }) => {
-                        return error::ExpressionTooLarge {
-                            expr: op.expr().unwrap().clone(),
-                            value,
-                            spec,
-                        }
-                        .fail()
-                    }
-                    Err(ops::Error::ExpressionNegative { value, .. }) => {
-                        return error::ExpressionNegative {
-                            expr: op.expr().unwrap().clone(),
-                            value,
-                        }
-                        .fail()
-                    }
-                    Err(ops::Error::ContextIncomplete {
-                        source: UnknownLabel { .. },
-                    }) => {
-                        let labels = op
-                            .expr()
-                            .unwrap()
-                            .labels(&self.declared_macros)
-                            .unwrap()
-                            .into_iter()
-                            .collect::<Vec<String>>();
+    #[test]
+    fn assemble_data() -> Result<(), Error> {
+        let ops = vec![
+            RawOp::Op(AbstractOp::new(Push1(Imm::with_label("end")))),
+            RawOp::Data(2, vec![Terminal::Label("end".into()).into()]),
+            RawOp::Data(32, vec![Terminal::Number(0x0102.into()).into()]),
+            RawOp::Op(AbstractOp::Label("end".into())),
+            RawOp::Op(AbstractOp::new(JumpDest)),
+        ];
+        let mut asm = Assembler::new();
+        let code = asm.assemble(&ops)?;
 
-                        if let AbstractOp::Push(_) = op {
-                            // Here, we set the size of the push to 2 bytes (min possible value),
-                            //  as we don't know the final value of the label yet.
-                            self.concrete_len += 2;
-                            self.variable_sized_push.push(op.clone());
-                        } else {
-                            self.concrete_len += op.size().unwrap();
-                        }
+        let mut expected = hex!("6024 0024").to_vec();
+        expected.extend([0; 30]);
+        expected.extend(hex!("0102 5b"));
+        assert_eq!(code, expected);
 
-                        self.undeclared_labels.extend(labels);
-                        self.ready.push(rop.clone());
-                    }
-                    Err(ops::Error::ContextIncomplete {
-                        source: UnknownMacro { name, .. },
-                    }) => return error::UndeclaredInstructionMacro { name }.fail(),
-                    Err(ops::Error::ContextIncomplete {
-                        source: UndefinedVariable { name, .. },
-                    }) => return error::UndeclaredVariableMacro { var: name }.fail(),
-                }
-            }
-            RawOp::Raw(raw) => {
-                self.concrete_len += raw.len();
-                self.ready.push(RawOp::Raw(raw.to_vec()));
-            }
-            RawOp::Scope(scope) => {
-                let mut asm = Self::new();
-                let scope_result = asm.assemble(&scope)?;
-                self.concrete_len += scope_result.len();
-                self.ready.push(RawOp::Raw(scope_result));
-            }
-        }
+        Ok(())
+    }
 
-        Ok(self.concrete_len)
+    #[test]
+    fn assemble_data_errors() {
+        let ops = vec![RawOp::Data(
+            2,
+            vec![Terminal::Number(0x10000.into()).into()],
+        )];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(
+            err,
+            Error::DataEntryTooLarge { value, size: 2, .. } if value == BigInt::from(0x10000)
+        );
+
+        let ops = vec![RawOp::Data(32, vec![Terminal::Label("a".into()).into()])];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::UndeclaredLabels { labels, .. } if labels == vec!["a"]);
     }
 
-    fn backpatch_labels(&mut self) -> Result<(), Error> {
-        for op in self.variable_sized_push.iter() {
-            if let AbstractOp::Push(imm) = op {
-                let exp = imm
-                    .tree
-                    .eval_with_context((&self.declared_labels, &self.declared_macros).into());
-
-                if let Ok(val) = exp {
-                    let val_bits = BigInt::bits(&val).max(1);
-                    let imm_size = 1 + ((val_bits - 1) / 8);
-
-                    if imm_size > 1 {
-                        for label_value in self.declared_labels.values_mut() {
-                            let labeldef = label_value.as_ref().unwrap();
-                            self.concrete_len += imm_size as usize - 1;
-                            *label_value = Some(LabelDef {
-                                position: labeldef.position + imm_size as usize - 1,
-                                updated: true,
-                            });
-                        }
-                    }
-                }
-            }
+    fn dispatch(arms: &[(u64, &str)]) -> RawOp {
+        let parameters = arms
+            .iter()
+            .flat_map(|(selector, target)| {
+                vec![
+                    Terminal::Number((*selector).into()).into(),
+                    Terminal::Label((*target).into()).into(),
+                ]
+            })
+            .collect();
+
+        RawOp::Op(AbstractOp::Macro(InstructionMacroInvocation {
+            name: "dispatch".into(),
+            parameters,
+        }))
+    }
+
+    #[test]
+    fn assemble_dispatch_binary_search() -> Result<(), Error> {
+        let targets = ["a", "b", "c", "d", "e"];
+        let mut ops = vec![dispatch(&[
+            (5, "e"),
+            (1, "a"),
+            (4, "d"),
+            (2, "b"),
+            (3, "c"),
+        ])];
+        ops.push(RawOp::Op(AbstractOp::new(Stop)));
+        for target in targets {
+            ops.push(RawOp::Op(AbstractOp::Label(target.into())));
+            ops.push(RawOp::Op(AbstractOp::new(JumpDest)));
+        }
+
+        let mut asm = Assembler::new();
+        asm.set_dispatch_strategy(Some(Strategy::BinarySearch));
+        let code = asm.assemble(&ops)?;
+
+        let mut dasm = crate::disasm::Disassembler::<Op<[u8]>>::default();
+        std::io::Write::write_all(&mut dasm, &code).unwrap();
+        let ops: Vec<_> = dasm.ops().map(|off| off.item).collect();
+
+        // The pivot is compared first, then the upper half, then the lower.
+        let compared: Vec<_> = ops
+            .windows(2)
+            .filter(|w| w[0] == Op::from(prague::Dup1))
+            .map(|w| w[1].immediate().unwrap()[0])
+            .collect();
+        assert_eq!(compared, [3, 3, 4, 5, 1, 2]);
+        assert_eq!(
+            ops.iter().filter(|op| **op == Op::from(prague::Gt)).count(),
+            1
+        );
+
+        for (target, jumpi) in targets.iter().zip([4, 5, 1, 2, 3]) {
+            let position = asm.label(target).unwrap();
+            let pushed = ops
+                .windows(2)
+                .filter(|w| w[1] == Op::from(prague::JumpI))
+                .nth(jumpi)
+                .map(|w| w[0].immediate().unwrap()[0] as usize);
+            assert_eq!(pushed, Some(position), "{}", target);
         }
 
         Ok(())
     }
 
-    /// Backpatch variable-sized operations and emit the assembled program.
-    ///
-    /// This function performs the final steps in the assembly process. It ensures that all labels
-    /// and variable-sized ops in the code have been properly resolved and finalized. This includes
-    /// handling variable-sized push instructions, where the actual size of the push may not be known
-    /// until all labels and expressions have been evaluated.
-    ///
-    /// Handle Variable-sized Pushes: The size of a push operation may depend on the value being pushed, especially
-    /// when labels are involved. As labels could be resolved to different addresses during the
-    /// assembly process, the final value of a label (and thus the size of the push) might only be
-    /// known at this stage. This function recalculates the size of each push operation based on the
-    /// final resolved values of labels and expressions. If a push operation requires more space than
-    /// initially estimated, the function adjusts the code accordingly.
-    fn backpatch_and_emit(&mut self) -> Result<Vec<u8>, Error> {
-        if !self.undeclared_labels.is_empty() {
-            return error::UndeclaredLabels {
-                labels: self
-                    .undeclared_labels
-                    .iter()
-                    .map(|l| l.to_owned())
-                    .collect::<Vec<String>>(),
-            }
-            .fail();
+    #[test]
+    fn assemble_dispatch_mask() -> Result<(), Error> {
+        let mut ops = vec![dispatch(&[(3, "c"), (1, "a"), (2, "b")])];
+        ops.push(RawOp::Op(AbstractOp::new(Stop)));
+        for target in ["a", "b", "c"] {
+            ops.push(RawOp::Op(AbstractOp::Label(target.into())));
+            ops.push(RawOp::Op(AbstractOp::new(JumpDest)));
         }
-        self.backpatch_labels()?;
-        let output = match self.emit_bytecode() {
-            Ok(value) => value,
-            Err(value) => return value,
-        };
 
-        Ok(output)
-    }
+        let mut asm = Assembler::new();
+        asm.set_dispatch_strategy(Some(Strategy::Mask));
+        let code = asm.assemble(&ops)?;
+
+        // The two lowest bits of each selector index a table at 0x10.
+        let expected = hex!(
+            "5f3560e01c"
+            "8060031660041b60100156"
+            "5b5b5b5b5b5b5b5b5b5b5b5b61005056"
+            "5b8060011461005257 5b5b5b 61005056"
+            "5b8060021461005357 5b5b5b 61005056"
+            "5b8060031461005457 5b5b5b 61005056"
+            "5b 00 5b5b5b"
+        );
+        assert_eq!(code, expected);
 
-    fn emit_bytecode(&mut self) -> Result<Vec<u8>, Result<Vec<u8>, Error>> {
-        let mut output = Vec::new();
-        for op in self.ready.iter() {
-            let op = match op {
-                RawOp::Op(ref op) => op,
-                RawOp::Raw(raw) => {
-                    output.extend(raw);
-                    continue;
-                }
-                RawOp::Scope(_) => unreachable!("scopes should be expanded"),
-            };
+        // With so few selectors, comparing them one by one is cheaper.
+        let mut asm = Assembler::new();
+        let code = asm.assemble(&ops)?;
+        assert_eq!(code.len(), 5 + 3 * 7 + 4);
 
-            match op
-                .clone()
-                .concretize((&self.declared_labels, &self.declared_macros).into())
-            {
-                Ok(cop) => cop.assemble(&mut output),
-                Err(ops::Error::ContextIncomplete {
-                    source: UnknownLabel { .. },
-                }) => {
-                    return Err(error::UndeclaredLabels {
-                        labels: self.undeclared_labels.iter().cloned().collect::<Vec<_>>(),
-                    }
-                    .fail());
-                }
-                Err(ops::Error::ContextIncomplete {
-                    source: UnknownMacro { name, .. },
-                }) => {
-                    return Err(error::UndeclaredInstructionMacro { name }.fail());
-                }
-                Err(ops::Error::ContextIncomplete {
-                    source: UndefinedVariable { name, .. },
-                }) => {
-                    return Err(error::UndeclaredVariableMacro { var: name }.fail());
-                }
-                Err(_) => unreachable!("all ops should be concretizable"),
-            }
-        }
-        Ok(output)
+        Ok(())
     }
 
-    fn declare_label(&mut self, rop: &RawOp) -> Result<(), Error> {
-        if let RawOp::Op(AbstractOp::Label(label)) = rop {
-            if self.declared_labels.contains_key(label) {
-                return error::DuplicateLabel {
-                    label: label.to_owned(),
-                }
-                .fail();
-            }
-            self.declared_labels.insert(label.to_owned(), None);
+    #[test]
+    fn assemble_dispatch_perfect_hash() -> Result<(), Error> {
+        let arms = [(0x1, "a"), (0x100, "b"), (0x1_0000, "c"), (0x100_0000, "d")];
+        let mut ops = vec![dispatch(&arms)];
+        ops.push(RawOp::Op(AbstractOp::new(Stop)));
+        for target in ["a", "b", "c", "d"] {
+            ops.push(RawOp::Op(AbstractOp::Label(target.into())));
+            ops.push(RawOp::Op(AbstractOp::new(JumpDest)));
         }
+
+        let mut asm = Assembler::new();
+        asm.set_dispatch_strategy(Some(Strategy::PerfectHash));
+        let code = asm.assemble(&ops)?;
+
+        // The two highest bits of the lowest four bytes of each selector times
+        // 0xdaa66d2b index a table at 0x16, in the reverse order.
+        let expected = hex!(
+            "5f3560e01c"
+            "8063daa66d2b02601a1c603016 601601 56"
+            "5b806301000000146100 5b57 61005656"
+            "5b8062010000146100 5a57 5b 61005656"
+            "5b80610100146100 5957 5b5b 61005656"
+            "5b8060011461005857 5b5b5b 61005656"
+            "5b 00 5b5b5b5b"
+        );
+        assert_eq!(code, expected);
+
         Ok(())
     }
 
-    fn expand_macro(
-        &mut self,
-        name: &str,
-        parameters: &[Expression],
-    ) -> Result<Option<usize>, Error> {
-        // Remap labels to macro scope.
-        match self.declared_macros.get(name).cloned() {
-            Some(MacroDefinition::Instruction(mut m)) => {
-                if m.parameters.len() != parameters.len() {
-                    panic!("invalid number of parameters for macro {}", name);
-                }
+    #[test]
+    fn assemble_dispatch_errors() {
+        let mut asm = Assembler::new();
+        let err = asm
+            .assemble(&[dispatch(&[(1, "a"), (1, "a")])])
+            .unwrap_err();
+        assert_matches!(err, Error::DuplicateSelector { selector: 1, .. });
 
-                let parameters: HashMap<String, Expression> = m
-                    .parameters
-                    .into_iter()
-                    .zip(parameters.iter().cloned())
-                    .collect();
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&[dispatch(&[(1 << 32, "a")])]).unwrap_err();
+        assert_matches!(err, Error::InvalidSelector { .. });
 
-                let mut labels = HashMap::<String, String>::new();
-                let mut rng = rand::thread_rng();
+        let arms: Vec<_> = (0..257).map(|selector| (selector, "a")).collect();
+        let mut asm = Assembler::new();
+        asm.set_dispatch_strategy(Some(Strategy::Mask));
+        let err = asm.assemble(&[dispatch(&arms)]).unwrap_err();
+        assert_matches!(err, Error::NoSelectorMask { .. });
 
-                // First pass, find locally defined labels and rename them.
-                for op in m.contents.iter_mut() {
-                    match op {
-                        AbstractOp::Label(ref mut label) => {
-                            let mangled = format!("{}_{}_{}", m.name, label, rng.gen::<u64>());
-                            let old = labels.insert(label.to_owned(), mangled.clone());
-                            if old.is_some() {
-                                return error::DuplicateLabel {
-                                    label: label.to_string(),
-                                }
-                                .fail();
-                            }
-                            *label = mangled;
-                        }
-                        _ => continue,
-                    }
-                }
+        let mut asm = Assembler::new();
+        asm.set_dispatch_strategy(Some(Strategy::PerfectHash));
+        let err = asm.assemble(&[dispatch(&arms)]).unwrap_err();
+        assert_matches!(err, Error::NoPerfectHash { .. });
+
+        let ops = [RawOp::Op(AbstractOp::Macro(InstructionMacroInvocation {
+            name: "dispatch".into(),
+            parameters: vec![Terminal::Number(1.into()).into()],
+        }))];
+        let mut asm = Assembler::new();
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::DispatchArguments { got: 1, .. });
+    }
 
-                // Second pass, update local label invocations.
-                for op in m.contents.iter_mut() {
-                    if let Some(expr) = op.expr_mut() {
-                        for lbl in expr.labels(&self.declared_macros).unwrap() {
-                            if labels.contains_key(&lbl) {
-                                expr.replace_label(&lbl, &labels[&lbl]);
-                            }
-                        }
-                    }
+    fn trampoline(name: &str, parameters: &[Expression]) -> RawOp {
+        RawOp::Op(AbstractOp::Macro(InstructionMacroInvocation {
+            name: name.into(),
+            parameters: parameters.to_vec(),
+        }))
+    }
 
-                    // Attempt to fill in parameters
-                    if let Some(expr) = op.expr_mut() {
-                        for (name, value) in parameters.iter() {
-                            expr.fill_variable(name, value)
-                        }
-                    }
-                }
+    #[test]
+    fn assemble_trampoline() -> Result<(), Error> {
+        let target: Expression = Terminal::Number(0x1234.into()).into();
 
-                for op in m.contents.iter() {
-                    self.push(op)?;
-                }
-                Ok(Some(self.concrete_len))
-            }
-            _ => error::UndeclaredInstructionMacro { name }.fail(),
-        }
-    }
-}
+        let mut asm = Assembler::new();
+        let code = asm.assemble(&[trampoline("delegatecall_trampoline", &[target.clone()])])?;
+        assert_eq!(
+            code,
+            hex!("365f5f37 5f5f365f 611234 5a f4 3d5f5f3e 6017 57 3d5ffd 5b 3d5ff3")
+        );
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::ops::{
-        Expression, ExpressionMacroDefinition, ExpressionMacroInvocation, Imm,
-        InstructionMacroDefinition, InstructionMacroInvocation, Terminal,
-    };
-    use assert_matches::assert_matches;
-    use etk_ops::cancun::*;
-    use hex_literal::hex;
-    use num_bigint::{BigInt, Sign};
+        let mut asm = Assembler::new();
+        let code = asm.assemble(&[trampoline(
+            "call_trampoline",
+            &[
+                target,
+                Terminal::Number(50000.into()).into(),
+                Terminal::Number(0.into()).into(),
+            ],
+        )])?;
+        assert_eq!(
+            code,
+            hex!("365f5f37 5f5f365f 34 611234 61c350 f1 6016 57 5f5ffd 5b 00")
+        );
+
+        Ok(())
+    }
 
     #[test]
-    fn assemble_variable_push_const_while_pending() -> Result<(), Error> {
+    fn assemble_trampoline_errors() {
         let mut asm = Assembler::new();
-        let code = vec![
-            AbstractOp::Op(Push1(Imm::with_label("label1")).into()),
-            AbstractOp::Push(Terminal::Number(0xaabb.into()).into()),
-            AbstractOp::Label("label1".into()),
+        let err = asm
+            .assemble(&[trampoline("staticcall_trampoline", &[])])
+            .unwrap_err();
+        assert_matches!(err, Error::TrampolineArguments { got: 0, .. });
+
+        let target: Expression = Terminal::Number(1.into()).into();
+        let gas: Expression = Terminal::Label("a".into()).into();
+        let mut asm = Assembler::new();
+        let err = asm
+            .assemble(&[trampoline("staticcall_trampoline", &[target, gas])])
+            .unwrap_err();
+        assert_matches!(err, Error::InvalidTrampolineOption { .. });
+    }
+
+    #[test]
+    fn assemble_raw_opcode() -> Result<(), Error> {
+        let ops = vec![
+            RawOp::Opcode(Terminal::Number(0xef.into()).into(), None),
+            RawOp::Opcode(
+                Terminal::Number(0xe9.into()).into(),
+                Some((
+                    Terminal::Number(2.into()).into(),
+                    Terminal::Label("a".into()).into(),
+                )),
+            ),
+            RawOp::Op(AbstractOp::Label("a".into())),
+            RawOp::Opcode(Terminal::Number(0x00.into()).into(), None),
         ];
-        let result = asm.assemble(&code)?;
-        assert_eq!(result, hex!("600561aabb"));
+        let mut asm = Assembler::new();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("ef e90004 00"));
+
+        assert_eq!(
+            asm.warnings()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "`op(0xef)` isn't a known instruction, and is assembled without checks",
+                "`op(0xe9)` isn't a known instruction, and is assembled without checks",
+                "`op(0x00)` is assembled without checks (`stop` is the same instruction)",
+            ]
+        );
+
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_pushes_abab() -> Result<(), Error> {
+    fn assemble_raw_opcode_errors() {
+        let number = |n: u32| -> Expression { Terminal::Number(n.into()).into() };
+
         let mut asm = Assembler::new();
-        let code = vec![
-            AbstractOp::new(JumpDest),
-            AbstractOp::Push(Imm::with_label("label1")),
-            AbstractOp::Push(Imm::with_label("label2")),
-            AbstractOp::Label("label1".into()),
-            AbstractOp::new(GetPc),
-            AbstractOp::Label("label2".into()),
-            AbstractOp::new(GetPc),
+        let err = asm
+            .assemble(&[RawOp::Opcode(number(0x100), None)])
+            .unwrap_err();
+        assert_matches!(err, Error::InvalidOpcode { .. });
+
+        let mut asm = Assembler::new();
+        let err = asm
+            .assemble(&[RawOp::Opcode(number(0xe9), Some((number(33), number(1))))])
+            .unwrap_err();
+        assert_matches!(err, Error::InvalidImmediateSize { .. });
+
+        let mut asm = Assembler::new();
+        let err = asm
+            .assemble(&[RawOp::Opcode(
+                number(0xe9),
+                Some((number(1), number(0x100))),
+            )])
+            .unwrap_err();
+        assert_matches!(err, Error::ImmediateTooLarge { size: 1, .. });
+    }
+
+    #[test]
+    fn assemble_relative_jump() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::Label("top".into()),
+            AbstractOp::Op(prague::RJumpI(Imm::with_label("end")).into()),
+            AbstractOp::Op(prague::RJump(Imm::with_label("top")).into()),
+            AbstractOp::Label("end".into()),
+            AbstractOp::Op(prague::RJump(Imm::with_expression(BigInt::from(-3).into())).into()),
         ];
-        let result = asm.assemble(&code)?;
-        assert_eq!(result, hex!("5b600560065858"));
+        let mut asm = Assembler::new();
+        asm.set_target(Profile::builtin("eof"));
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("e10003 e0fffa e0fffd"));
+
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_pushes_abba() -> Result<(), Error> {
+    fn assemble_relative_jump_out_of_range() {
+        let mut ops = vec![AbstractOp::Op(prague::RJump(Imm::with_label("far")).into())];
+        ops.extend(std::iter::repeat(AbstractOp::new(JumpDest)).take(0x8000));
+        ops.push(AbstractOp::Label("far".into()));
         let mut asm = Assembler::new();
-        let code = vec![
-            AbstractOp::new(JumpDest),
-            AbstractOp::Push(Imm::with_label("label1")),
-            AbstractOp::Push(Imm::with_label("label2")),
-            AbstractOp::Label("label2".into()),
-            AbstractOp::new(GetPc),
-            AbstractOp::Label("label1".into()),
-            AbstractOp::new(GetPc),
-        ];
-        let result = asm.assemble(&code)?;
-        assert_eq!(result, hex!("5b600660055858"));
-        Ok(())
+        asm.set_target(Profile::builtin("eof"));
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::OffsetOutOfRange { value, .. } if value == BigInt::from(0x8000));
+
+        let ops = vec![AbstractOp::Op(
+            prague::RJump(Imm::with_expression(BigInt::from(-0x8001).into())).into(),
+        )];
+        let mut asm = Assembler::new();
+        asm.set_target(Profile::builtin("eof"));
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::OffsetOutOfRange { .. });
     }
 
     #[test]
-    fn assemble_variable_push1_multiple() -> Result<(), Error> {
+    fn assemble_eof_needs_eof_target() {
+        let ops = vec![AbstractOp::Op(prague::RJump(Imm::from([0, 0])).into())];
+
         let mut asm = Assembler::new();
-        let code = vec![
-            AbstractOp::new(JumpDest),
-            AbstractOp::Push(Imm::with_label("auto")),
-            AbstractOp::Push(Imm::with_label("auto")),
-            AbstractOp::Label("auto".into()),
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::EofInstruction { spec, .. } if spec == Op::from(prague::RJump(())));
+
+        for fork in ["prague", "cancun"] {
+            let mut asm = Assembler::new();
+            asm.set_target(Profile::builtin(fork));
+            let err = asm.assemble(&ops).unwrap_err();
+            assert_matches!(err, Error::EofInstruction { .. });
+        }
+
+        let mut asm = Assembler::new();
+        asm.set_target(Profile::builtin("prague"));
+        let err = asm
+            .assemble(&[RawOp::RJumpV(vec![Terminal::Number(0.into()).into()])])
+            .unwrap_err();
+        assert_matches!(err, Error::EofInstruction { .. });
+    }
+
+    #[test]
+    fn assemble_rjumpv() -> Result<(), Error> {
+        let ops = vec![
+            RawOp::Op(AbstractOp::Label("top".into())),
+            RawOp::RJumpV(vec![
+                Terminal::Label("end".into()).into(),
+                Terminal::Label("top".into()).into(),
+                Terminal::Number(BigInt::from(-1)).into(),
+                Terminal::Number(BigInt::from(0xfffd)).into(),
+            ]),
+            RawOp::Op(AbstractOp::new(Stop)),
+            RawOp::Op(AbstractOp::Label("end".into())),
         ];
-        let result = asm.assemble(&code)?;
-        assert_eq!(result, hex!("5b60056005"));
+        let mut asm = Assembler::new();
+        asm.set_target(Profile::builtin("eof"));
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("e203 0001 fff6 ffff fffd 00"));
+
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push_const() -> Result<(), Error> {
+    fn assemble_relative_offset() -> Result<(), Error> {
+        let rel = |expr: Expression| {
+            Imm::with_expression(Expression::Macro(ExpressionMacroInvocation {
+                name: "rel".into(),
+                parameters: vec![expr],
+            }))
+        };
+
+        let ops = vec![
+            AbstractOp::Label("top".into()),
+            AbstractOp::new(Push1(rel(Terminal::Label("end".into()).into()))),
+            AbstractOp::Op(
+                prague::RJumpI(rel(Expression::Plus(
+                    Terminal::Label("top".into()).into(),
+                    1.into(),
+                )))
+                .into(),
+            ),
+            AbstractOp::Label("end".into()),
+            AbstractOp::Op(prague::RJump(rel(BigInt::from(0).into())).into()),
+        ];
         let mut asm = Assembler::new();
-        let code = vec![AbstractOp::Push(
-            Terminal::Number((0x00aaaaaaaaaaaaaaaaaaaaaaaa as u128).into()).into(),
-        )];
-        let result = asm.assemble(&code)?;
-        assert_eq!(result, hex!("6baaaaaaaaaaaaaaaaaaaaaaaa"));
+        asm.set_target(Profile::builtin("eof"));
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("6003 e1fffc e0fff8"));
+
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push_too_large() {
-        let v = BigInt::from_bytes_be(Sign::Plus, &[1u8; 33]);
+    fn assemble_rjumpv_errors() {
+        let mut asm = Assembler::new();
+        asm.set_target(Profile::builtin("eof"));
+        let err = asm.assemble(&[RawOp::RJumpV(vec![])]).unwrap_err();
+        assert_matches!(err, Error::RJumpVTableSize { entries: 0, .. });
 
+        let entries = vec![Terminal::Number(BigInt::from(0)).into(); 257];
         let mut asm = Assembler::new();
-        let code = vec![AbstractOp::Push(Terminal::Number(v).into())];
-        let err = asm.assemble(&code).unwrap_err();
+        asm.set_target(Profile::builtin("eof"));
+        let err = asm.assemble(&[RawOp::RJumpV(entries)]).unwrap_err();
+        assert_matches!(err, Error::RJumpVTableSize { entries: 257, .. });
 
-        assert_matches!(err, Error::ExpressionTooLarge { .. });
+        let mut ops = vec![RawOp::RJumpV(vec![Terminal::Label("far".into()).into()])];
+        ops.extend(std::iter::repeat(RawOp::Op(AbstractOp::new(JumpDest))).take(0x8000));
+        ops.push(RawOp::Op(AbstractOp::Label("far".into())));
+        let mut asm = Assembler::new();
+        asm.set_target(Profile::builtin("eof"));
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::OffsetOutOfRange { value, .. } if value == BigInt::from(0x8000));
+
+        let mut asm = Assembler::new();
+        asm.set_target(Profile::builtin("eof"));
+        let err = asm
+            .assemble(&[RawOp::RJumpV(vec![
+                Terminal::Number(BigInt::from(0x10000)).into()
+            ])])
+            .unwrap_err();
+        assert_matches!(err, Error::OffsetOutOfRange { .. });
     }
 
     #[test]
-    fn assemble_variable_push_negative() {
+    fn assemble_relative_offset_errors() {
+        let rel = |label: &str| {
+            Imm::with_expression(Expression::Macro(ExpressionMacroInvocation {
+                name: "rel".into(),
+                parameters: vec![Terminal::Label(label.into()).into()],
+            }))
+        };
+
+        let ops = vec![
+            AbstractOp::Label("top".into()),
+            AbstractOp::new(Push1(rel("top"))),
+        ];
         let mut asm = Assembler::new();
-        let code = vec![AbstractOp::Push(Terminal::Number((-1).into()).into())];
-        let err = asm.assemble(&code).unwrap_err();
+        asm.set_target(Profile::builtin("eof"));
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::ExpressionNegative { value, .. } if value == BigInt::from(-2));
 
-        assert_matches!(err, Error::ExpressionNegative { .. });
+        let ops = vec![
+            AbstractOp::Label("top".into()),
+            AbstractOp::Push(rel("top")),
+        ];
+        let mut asm = Assembler::new();
+        asm.set_target(Profile::builtin("eof"));
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::RelativeOutsideImmediate { .. });
+
+        let mut ops = vec![AbstractOp::Op(prague::RJump(rel("far")).into())];
+        ops.extend(std::iter::repeat(AbstractOp::new(JumpDest)).take(0x8000));
+        ops.push(AbstractOp::Label("far".into()));
+        let mut asm = Assembler::new();
+        asm.set_target(Profile::builtin("eof"));
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::OffsetOutOfRange { value, .. } if value == BigInt::from(0x8000));
     }
 
     #[test]
-    fn assemble_variable_push_const0() -> Result<(), Error> {
+    fn assemble_expression_undeclared_label() -> Result<(), Error> {
         let mut asm = Assembler::new();
-        let code = vec![AbstractOp::Push(
-            Terminal::Number((0x00 as u128).into()).into(),
-        )];
-        let result = asm.assemble(&code)?;
-        assert_eq!(result, hex!("6000"));
+        let ops = vec![AbstractOp::new(Push1(Imm::with_expression(
+            Terminal::Label(String::from("hi")).into(),
+        )))];
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::UndeclaredLabels { labels, .. } if labels == vec!["hi"]);
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push1_known() -> Result<(), Error> {
+    fn assemble_variable_push_before_push2() -> Result<(), Error> {
         let mut asm = Assembler::new();
-        let code = vec![
-            AbstractOp::new(JumpDest),
-            AbstractOp::Label("auto".into()),
-            AbstractOp::Push(Imm::with_label("auto")),
+        let ops = vec![
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label("foo".into()).into(),
+                BigInt::from(256).into(),
+            ))),
+            AbstractOp::new(Push2(Imm::with_label("foo1"))),
+            AbstractOp::Label("foo".into()),
+            AbstractOp::Label("foo1".into()),
         ];
-        let result = asm.assemble(&code)?;
-        assert_eq!(result, hex!("5b6001"));
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("610106610006"));
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push1() -> Result<(), Error> {
+    fn assemble_variable_push_before_push2_inverted() -> Result<(), Error> {
         let mut asm = Assembler::new();
-        let code = vec![
-            AbstractOp::Push(Imm::with_label("auto")),
-            AbstractOp::Label("auto".into()),
-            AbstractOp::new(JumpDest),
+        let ops = vec![
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label("z".into()).into(),
+                BigInt::from(256).into(),
+            ))),
+            AbstractOp::new(Push2(Imm::with_label("a"))),
+            AbstractOp::Label("z".into()),
+            AbstractOp::Label("a".into()),
         ];
-        let result = asm.assemble(&code)?;
-        assert_eq!(result, hex!("60025b"));
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("610106610006"));
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push1_reuse() -> Result<(), Error> {
+    fn assemble_variable_push_mixed_labels() -> Result<(), Error> {
         let mut asm = Assembler::new();
-        let code = vec![
-            AbstractOp::Push(Imm::with_label("auto")),
-            AbstractOp::Label("auto".into()),
-            AbstractOp::new(JumpDest),
-            AbstractOp::new(Push1(Imm::with_label("auto"))),
+        let ops = vec![
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label("foo".into()).into(),
+                BigInt::from(256).into(),
+            ))),
+            AbstractOp::new(Push2(Imm::with_label("foo"))),
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label("bar".into()).into(),
+                BigInt::from(256).into(),
+            ))),
+            AbstractOp::new(Gas),
+            AbstractOp::Label("foo".into()),
+            AbstractOp::new(Gas),
+            AbstractOp::Label("bar".into()),
         ];
-        let result = asm.assemble(&code)?;
-        assert_eq!(result, hex!("60025b6002"));
+        let result = asm.assemble(&ops)?;
+
+        assert_eq!(result, hex!("61010a61000a61010b5a5a"));
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push2() -> Result<(), Error> {
-        let mut code = vec![];
-        code.push(AbstractOp::Push(Imm::with_label("auto")));
-        for _ in 0..255 {
-            code.push(AbstractOp::new(GetPc));
-        }
-
-        code.push(AbstractOp::Label("auto".into()));
-        code.push(AbstractOp::new(JumpDest));
-
+    fn assemble_double_update_variable_push() -> Result<(), Error> {
         let mut asm = Assembler::new();
-        let result = asm.assemble(&code)?;
-
-        let mut expected = vec![0x61, 0x01, 0x02];
-        expected.extend_from_slice(&[0x58; 255]);
-        expected.push(0x5b);
-        assert_eq!(result, expected);
-
+        let ops = vec![
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label("foo".into()).into(),
+                BigInt::from(256).into(),
+            ))),
+            AbstractOp::new(Push2(Imm::with_label("foo1"))),
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label("foo".into()).into(),
+                BigInt::from(256).into(),
+            ))),
+            AbstractOp::Label("foo".into()),
+            AbstractOp::Label("foo1".into()),
+        ];
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("610109610009610109"));
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push3() -> Result<(), Error> {
-        let mut code = vec![];
-        code.push(AbstractOp::Push(Imm::with_label("auto")));
-        for _ in 0..65537 {
-            code.push(AbstractOp::new(GetPc));
-        }
-
-        code.push(AbstractOp::Label("auto".into()));
-        code.push(AbstractOp::new(JumpDest));
-
+    fn assemble_double_update_variable_push2() -> Result<(), Error> {
         let mut asm = Assembler::new();
-        let result = asm.assemble(&code)?;
-
-        let mut expected = vec![0x62, 0x01, 0x00, 0x05];
-        expected.extend_from_slice(&[0x58; 65537]);
-        expected.push(0x5b);
-
-        assert_eq!(result, expected);
-
+        let ops = vec![
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label("foo".into()).into(),
+                BigInt::from(256).into(),
+            ))),
+            AbstractOp::new(Push2(Imm::with_label("foo1"))),
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label("foo".into()).into(),
+                BigInt::from(256).into(),
+            ))),
+            AbstractOp::new(Push2(Imm::with_label("foo1"))),
+            AbstractOp::Label("foo".into()),
+            AbstractOp::Label("foo1".into()),
+        ];
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("61010c61000c61010c61000c"));
         Ok(())
     }
 
     #[test]
-    fn assemble_undeclared_label() -> Result<(), Error> {
+    fn assemble_variable_push_expression_with_undeclared_labels() -> Result<(), Error> {
         let mut asm = Assembler::new();
-        let code = vec![AbstractOp::new(Push1(Imm::with_label("hi")))];
-        let err = asm.assemble(&code).unwrap_err();
-        assert_matches!(err, Error::UndeclaredLabels { labels, .. } if labels == vec!["hi"]);
+        let ops = vec![
+            AbstractOp::new(JumpDest),
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label("foo".into()).into(),
+                Terminal::Label("bar".into()).into(),
+            ))),
+            AbstractOp::new(Gas),
+        ];
+        let err = asm.assemble(&ops).unwrap_err();
+        // The expressions have short-circuit evaluation, so only the first label is caught in the error.
+        assert_matches!(err, Error::UndeclaredLabels { labels, .. } if (labels.contains(&"foo".to_string())));
         Ok(())
     }
 
     #[test]
-    fn assemble_jumpdest_no_label() -> Result<(), Error> {
+    fn assemble_variable_push2_comparison_with_undeclared_labels() -> Result<(), Error> {
         let mut asm = Assembler::new();
-        let code = vec![AbstractOp::new(JumpDest)];
-        let result = asm.assemble(&code)?;
-        assert!(asm.declared_labels.is_empty());
-        assert_eq!(result, hex!("5b"));
-        Ok(())
-    }
 
-    #[test]
-    fn assemble_jumpdest_with_label() -> Result<(), Error> {
+        // %push(lbl1 - lbl2)
+        // push2 lbl1 + lbl2
+        // pc # repeat 126 times.
+        // lbl1:
+        // lbl2:
+        let mut ops = vec![AbstractOp::new(GetPc); 130];
+        ops[0] = AbstractOp::Push(Imm::with_expression(Expression::Minus(
+            Terminal::Label(String::from("lbl1")).into(),
+            Terminal::Label(String::from("lbl2")).into(),
+        )));
+        ops[1] = AbstractOp::new(Push2(
+            Expression::Plus(
+                Terminal::Label(String::from("lbl1")).into(),
+                Terminal::Label(String::from("lbl2")).into(),
+            )
+            .into(),
+        ));
+        ops[128] = AbstractOp::Label("lbl1".into());
+        ops[129] = AbstractOp::Label("lbl2".into());
+
+        let expected = asm.assemble(&ops)?;
+
         let mut asm = Assembler::new();
-        let ops = vec![AbstractOp::Label("lbl".into()), AbstractOp::new(JumpDest)];
 
+        // %push(lbl1 - lbl2)
+        // %push(lbl1 + lbl2)
+        // pc # repeat 126 times.
+        // lbl1:
+        // lbl2:
+        ops[1] = AbstractOp::Push(Imm::with_expression(Expression::Plus(
+            Terminal::Label(String::from("lbl1")).into(),
+            Terminal::Label(String::from("lbl2")).into(),
+        )));
         let result = asm.assemble(&ops)?;
-        assert_eq!(asm.declared_labels.len(), 1);
-        assert_eq!(
-            asm.declared_labels.get("lbl"),
-            Some(&Some(LabelDef {
-                position: 0,
-                updated: false
-            }))
-        );
-        assert_eq!(result, hex!("5b"));
+
+        // Sanity check the expected result: should use push1 then push2.
+        assert_eq!(expected[0], 0x60);
+        assert_eq!(expected[2], 0x61);
+
+        // Assert that the two results are identical.
+        assert_eq!(expected, result);
         Ok(())
     }
 
     #[test]
-    fn assemble_jumpdest_jump_with_label() -> Result<(), Error> {
+    fn assemble_variable_push1_expression() -> Result<(), Error> {
+        let mut asm = Assembler::new();
         let ops = vec![
-            AbstractOp::Label("lbl".into()),
             AbstractOp::new(JumpDest),
-            AbstractOp::new(Push1(Imm::with_label("lbl"))),
+            AbstractOp::Label("auto".into()),
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                1.into(),
+                Terminal::Label(String::from("auto")).into(),
+            ))),
         ];
-
-        let mut asm = Assembler::new();
         let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("5b6000"));
-
+        assert_eq!(result, hex!("5b6002"));
         Ok(())
     }
 
     #[test]
-    fn assemble_labeled_pc() -> Result<(), Error> {
+    fn assemble_expression_with_labels() -> Result<(), Error> {
+        let mut asm = Assembler::new();
         let ops = vec![
-            AbstractOp::new(Push1(Imm::with_label("lbl"))),
-            AbstractOp::Label("lbl".into()),
-            AbstractOp::new(GetPc),
+            AbstractOp::new(JumpDest),
+            AbstractOp::Push(Imm::with_expression(Expression::Plus(
+                Terminal::Label(String::from("foo")).into(),
+                Terminal::Label(String::from("bar")).into(),
+            ))),
+            AbstractOp::new(Gas),
+            AbstractOp::Label("foo".into()),
+            AbstractOp::Label("bar".into()),
         ];
-
-        let mut asm = Assembler::new();
         let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("600258"));
-
+        assert_eq!(result, hex!("5b60085a"));
         Ok(())
     }
 
     #[test]
-    fn assemble_jump_jumpdest_with_label() -> Result<(), Error> {
+    fn assemble_expression_macro_push() -> Result<(), Error> {
         let ops = vec![
-            AbstractOp::new(Push1(Imm::with_label("lbl"))),
-            AbstractOp::Label("lbl".into()),
-            AbstractOp::new(JumpDest),
+            ExpressionMacroDefinition {
+                name: "foo".into(),
+                parameters: vec![],
+                content: Imm::with_expression(Expression::Plus(1.into(), 1.into())),
+            }
+            .into(),
+            AbstractOp::new(Push1(Imm::with_macro(ExpressionMacroInvocation {
+                name: "foo".into(),
+                parameters: vec![],
+            }))),
         ];
 
         let mut asm = Assembler::new();
         let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("60025b"));
+        assert_eq!(result, hex!("6002"));
 
         Ok(())
     }
 
     #[test]
-    fn assemble_label_too_large() {
-        let mut ops: Vec<_> = vec![AbstractOp::new(GetPc); 255];
-        ops.push(AbstractOp::Label("b".into()));
-        ops.push(AbstractOp::new(JumpDest));
-        ops.push(AbstractOp::Label("a".into()));
-        ops.push(AbstractOp::new(JumpDest));
-        ops.push(AbstractOp::new(Push1(Imm::with_label("a"))));
+    fn assemble_instruction_macro_with_undeclared_variables() {
+        let ops = vec![
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec!["foo".into()],
+                vec![AbstractOp::new(Push1(Imm::with_variable("bar")))],
+            )
+            .into(),
+            AbstractOp::Label("b".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push1(Imm::with_label("b"))),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![BigInt::from_bytes_be(Sign::Plus, &vec![0x42]).into()],
+            }),
+        ];
+
         let mut asm = Assembler::new();
         let err = asm.assemble(&ops).unwrap_err();
-        assert_matches!(err, Error::ExpressionTooLarge { expr: Expression::Terminal(Terminal::Label(label)), .. } if label == "a");
+
+        assert_matches!(
+            err,
+            Error::UndeclaredVariableMacro { var, expr, .. }
+                if var == "bar" && expr == Expression::from(Terminal::Variable("bar".into()))
+        );
     }
 
     #[test]
-    fn assemble_label_just_right() -> Result<(), Error> {
-        let mut ops: Vec<_> = vec![AbstractOp::new(GetPc); 255];
-        ops.push(AbstractOp::Label("b".into()));
-        ops.push(AbstractOp::new(JumpDest));
-        ops.push(AbstractOp::Label("a".into()));
-        ops.push(AbstractOp::new(JumpDest));
-        ops.push(AbstractOp::new(Push1(Imm::with_label("b"))));
+    fn assemble_instruction_macro_two_delayed_definitions_mirrored() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::new(GetPc),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "macro1".into(),
+                parameters: vec![],
+            }),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "macro0".into(),
+                parameters: vec![],
+            }),
+            InstructionMacroDefinition::new(
+                "macro0".into(),
+                vec![],
+                vec![AbstractOp::new(JumpDest)],
+            )
+            .into(),
+            InstructionMacroDefinition::new("macro1".into(), vec![], vec![AbstractOp::new(Caller)])
+                .into(),
+        ];
+
         let mut asm = Assembler::new();
         let result = asm.assemble(&ops)?;
-
-        let mut expected = vec![0x58; 255];
-        expected.push(0x5b);
-        expected.push(0x5b);
-        expected.push(0x60);
-        expected.push(0xff);
-
-        assert_eq!(result, expected);
+        assert_eq!(result, hex!("58335b"));
 
         Ok(())
     }
 
     #[test]
-    fn assemble_instruction_macro_label_underscore() -> Result<(), Error> {
+    fn assemble_instruction_macro_two_delayed_definitions() -> Result<(), Error> {
         let ops = vec![
-            InstructionMacroDefinition {
-                name: "my_macro".into(),
-                parameters: vec![],
-                contents: vec![AbstractOp::Label("a".into())],
-            }
-            .into(),
-            InstructionMacroDefinition {
-                name: "my".into(),
-                parameters: vec![],
-                contents: vec![AbstractOp::Label("macro_a".into())],
-            }
-            .into(),
+            AbstractOp::new(GetPc),
             AbstractOp::Macro(InstructionMacroInvocation {
-                name: "my_macro".into(),
+                name: "macro0".into(),
                 parameters: vec![],
             }),
             AbstractOp::Macro(InstructionMacroInvocation {
-                name: "my".into(),
+                name: "macro1".into(),
                 parameters: vec![],
             }),
+            InstructionMacroDefinition::new(
+                "macro0".into(),
+                vec![],
+                vec![AbstractOp::new(JumpDest)],
+            )
+            .into(),
+            InstructionMacroDefinition::new("macro1".into(), vec![], vec![AbstractOp::new(Caller)])
+                .into(),
         ];
 
         let mut asm = Assembler::new();
         let result = asm.assemble(&ops)?;
-        assert_eq!(result, []);
+        assert_eq!(result, hex!("585b33"));
 
         Ok(())
     }
 
     #[test]
-    fn assemble_instruction_macro_twice() -> Result<(), Error> {
+    fn assemble_warns_unused_label() -> Result<(), Error> {
         let ops = vec![
-            InstructionMacroDefinition {
-                name: "my_macro".into(),
-                parameters: vec![],
-                contents: vec![
-                    AbstractOp::Label("a".into()),
-                    AbstractOp::new(JumpDest),
-                    AbstractOp::new(Push1(Imm::with_label("a"))),
-                    AbstractOp::new(Push1(Imm::with_label("b"))),
-                ],
-            }
-            .into(),
+            AbstractOp::Label("a".into()),
+            AbstractOp::new(JumpDest),
             AbstractOp::Label("b".into()),
             AbstractOp::new(JumpDest),
             AbstractOp::new(Push1(Imm::with_label("b"))),
-            AbstractOp::Macro(InstructionMacroInvocation {
-                name: "my_macro".into(),
-                parameters: vec![],
-            }),
-            AbstractOp::Macro(InstructionMacroInvocation {
-                name: "my_macro".into(),
-                parameters: vec![],
-            }),
         ];
 
         let mut asm = Assembler::new();
-        let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("5b60005b600360005b60086000"));
+        asm.assemble(&ops)?;
+        assert_eq!(asm.warnings(), [Warning::UnusedLabel { label: "a".into() }]);
 
         Ok(())
     }
 
     #[test]
-    fn assemble_instruction_macro() -> Result<(), Error> {
+    fn assemble_warns_unreachable_code() -> Result<(), Error> {
         let ops = vec![
-            InstructionMacroDefinition {
-                name: "my_macro".into(),
-                parameters: vec![],
-                contents: vec![
-                    AbstractOp::Label("a".into()),
-                    AbstractOp::new(JumpDest),
-                    AbstractOp::new(Push1(Imm::with_label("a"))),
-                    AbstractOp::new(Push1(Imm::with_label("b"))),
-                ],
-            }
-            .into(),
-            AbstractOp::Label("b".into()),
+            AbstractOp::new(Stop),
+            AbstractOp::new(GetPc),
+            AbstractOp::new(Caller),
+            AbstractOp::new(Return),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(GetPc),
+        ];
+
+        let mut asm = Assembler::new();
+        asm.assemble(&ops)?;
+        assert_eq!(
+            asm.warnings(),
+            [Warning::UnreachableCode {
+                after: Op::from(prague::Stop),
+                op: AbstractOp::new(GetPc),
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_eliminates_dead_code() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::new(Push1(Imm::with_label("a"))),
+            AbstractOp::new(Jump),
+            AbstractOp::new(GetPc),
+            AbstractOp::new(Caller),
+            AbstractOp::Label("a".into()),
             AbstractOp::new(JumpDest),
-            AbstractOp::new(Push1(Imm::with_label("b"))),
-            AbstractOp::Macro(InstructionMacroInvocation {
-                name: "my_macro".into(),
-                parameters: vec![],
-            }),
+            AbstractOp::new(Stop),
+            AbstractOp::new(Push1(Imm::from([1u8]))),
         ];
 
         let mut asm = Assembler::new();
+        asm.set_optimizations(&[Pass::DeadCode]);
         let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("5b60005b60036000"));
+        assert_eq!(result, hex!("6003565b00"));
+        assert!(asm.warnings().is_empty());
+        assert_eq!(
+            asm.optimizations(),
+            [
+                Optimization {
+                    pass: Pass::DeadCode,
+                    after: Some(Op::from(prague::Jump)),
+                    removed: vec![AbstractOp::new(GetPc), AbstractOp::new(Caller)],
+                    replacement: vec![],
+                    bytes_saved: 2,
+                },
+                Optimization {
+                    pass: Pass::DeadCode,
+                    after: Some(Op::from(prague::Stop)),
+                    removed: vec![AbstractOp::new(Push1(Imm::from([1u8])))],
+                    replacement: vec![],
+                    bytes_saved: 2,
+                },
+            ]
+        );
 
         Ok(())
     }
 
     #[test]
-    fn assemble_instruction_macro_delayed_definition() -> Result<(), Error> {
+    fn assemble_peephole() -> Result<(), Error> {
         let ops = vec![
-            AbstractOp::Label("b".into()),
-            AbstractOp::new(JumpDest),
-            AbstractOp::new(Push1(Imm::with_label("b"))),
-            AbstractOp::Macro(InstructionMacroInvocation {
-                name: "my_macro".into(),
-                parameters: vec![],
-            }),
-            InstructionMacroDefinition {
-                name: "my_macro".into(),
-                parameters: vec![],
-                contents: vec![
-                    AbstractOp::Label("a".into()),
-                    AbstractOp::new(JumpDest),
-                    AbstractOp::new(Push1(Imm::with_label("a"))),
-                    AbstractOp::new(Push1(Imm::with_label("b"))),
-                ],
-            }
-            .into(),
+            AbstractOp::new(Push1(Imm::with_label("a"))),
+            AbstractOp::new(Push1(Imm::from([2u8]))),
+            AbstractOp::new(Pop),
+            AbstractOp::new(Push1(Imm::from([3u8]))),
+            AbstractOp::new(Push1(Imm::from([3u8]))),
+            AbstractOp::new(Swap1),
+            AbstractOp::new(Swap1),
+            AbstractOp::new(Dup1),
+            AbstractOp::Label("a".into()),
+            AbstractOp::new(Pop),
         ];
 
         let mut asm = Assembler::new();
+        asm.set_optimizations(&[Pass::Peephole]);
         let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("5b60005b60036000"));
+
+        // The label moves back by the six bytes saved before it.
+        assert_eq!(result, hex!("60066003808050"));
+        assert_eq!(asm.label("a"), Some(6));
+        assert_eq!(
+            asm.optimizations(),
+            [
+                Optimization {
+                    pass: Pass::Peephole,
+                    after: None,
+                    removed: vec![
+                        AbstractOp::new(Push1(Imm::from([2u8]))),
+                        AbstractOp::new(Pop),
+                    ],
+                    replacement: vec![],
+                    bytes_saved: 3,
+                },
+                Optimization {
+                    pass: Pass::Peephole,
+                    after: None,
+                    removed: vec![
+                        AbstractOp::new(Push1(Imm::from([3u8]))),
+                        AbstractOp::new(Push1(Imm::from([3u8]))),
+                    ],
+                    replacement: vec![
+                        AbstractOp::new(Push1(Imm::from([3u8]))),
+                        AbstractOp::new(Dup1),
+                    ],
+                    bytes_saved: 1,
+                },
+                Optimization {
+                    pass: Pass::Peephole,
+                    after: None,
+                    removed: vec![AbstractOp::new(Swap1), AbstractOp::new(Swap1)],
+                    replacement: vec![],
+                    bytes_saved: 2,
+                },
+            ]
+        );
 
         Ok(())
     }
 
     #[test]
-    fn assemble_instruction_macro_with_variable_push() -> Result<(), Error> {
+    fn assemble_warns_fallthrough() -> Result<(), Error> {
         let ops = vec![
-            AbstractOp::Macro(InstructionMacroInvocation {
-                name: "my_macro".into(),
-                parameters: vec![],
-            }),
-            InstructionMacroDefinition {
-                name: "my_macro".into(),
-                parameters: vec![],
-                contents: vec![
-                    AbstractOp::new(JumpDest),
-                    AbstractOp::Push(Imm::with_label("label1")),
-                    AbstractOp::Push(Imm::with_label("label2")),
-                    AbstractOp::Label("label1".into()),
-                    AbstractOp::new(GetPc),
-                    AbstractOp::Label("label2".into()),
-                    AbstractOp::new(GetPc),
-                ],
-            }
-            .into(),
+            RawOp::Op(AbstractOp::new(Caller)),
+            RawOp::Op(AbstractOp::new(Push1(Imm::with_label("a")))),
+            RawOp::Op(AbstractOp::new(JumpI)),
+            RawOp::Op(AbstractOp::Label("a".into())),
+            RawOp::Op(AbstractOp::new(JumpDest)),
+            RawOp::Op(AbstractOp::new(Caller)),
+            RawOp::Op(AbstractOp::new(Push1(Imm::with_label("b")))),
+            RawOp::Op(AbstractOp::new(JumpI)),
+            RawOp::Fallthrough,
+            RawOp::Op(AbstractOp::Label("b".into())),
+            RawOp::Op(AbstractOp::new(JumpDest)),
+            RawOp::Op(AbstractOp::Label("loop".into())),
+            RawOp::Op(AbstractOp::new(JumpDest)),
+            RawOp::Op(AbstractOp::new(Push1(Imm::with_label("loop")))),
+            RawOp::Op(AbstractOp::new(Jump)),
         ];
 
         let mut asm = Assembler::new();
-        let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("5b600560065858"));
+        asm.assemble(&ops)?;
+        assert_eq!(asm.warnings(), [Warning::Fallthrough { label: "a".into() }]);
 
         Ok(())
     }
 
     #[test]
-    fn assemble_undeclared_instruction_macro() -> Result<(), Error> {
-        let ops = vec![AbstractOp::Macro(
-            InstructionMacroInvocation::with_zero_parameters("my_macro".into()),
-        )];
+    fn assemble_warns_oversized_push() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::new(Push2(Imm::from([0u8, 1]))),
+            AbstractOp::new(Push2(Imm::from([1u8, 0]))),
+        ];
+
         let mut asm = Assembler::new();
-        let err = asm.assemble(&ops).unwrap_err();
-        assert_matches!(err, Error::UndeclaredInstructionMacro { name, .. } if name == "my_macro");
+        asm.assemble(&ops)?;
+        assert_eq!(
+            asm.warnings(),
+            [Warning::OversizedPush {
+                spec: Op::from(prague::Push2(())),
+                value: BigInt::from(1),
+                minimum: Op::from(prague::Push1(())),
+            }]
+        );
 
         Ok(())
     }
 
     #[test]
-    fn assemble_duplicate_instruction_macro() -> Result<(), Error> {
-        let ops: Vec<AbstractOp> = vec![
-            InstructionMacroDefinition {
-                name: "my_macro".into(),
-                parameters: vec![],
-                contents: vec![AbstractOp::new(Caller)],
-            }
-            .into(),
-            InstructionMacroDefinition {
-                name: "my_macro".into(),
-                parameters: vec![],
-                contents: vec![AbstractOp::new(Caller)],
-            }
-            .into(),
-        ];
+    fn assemble_strict_promotes_warnings() {
+        let ops = vec![AbstractOp::Label("a".into()), AbstractOp::new(JumpDest)];
+
         let mut asm = Assembler::new();
+        asm.set_strict(true);
         let err = asm.assemble(&ops).unwrap_err();
-        assert_matches!(err, Error::DuplicateMacro { name, .. } if name == "my_macro");
+        assert_matches!(
+            err,
+            Error::PromotedWarning { warning: Warning::UnusedLabel { label, .. }, .. } if label == "a"
+        );
+    }
+
+    #[test]
+    fn assemble_strict_exported_labels() -> Result<(), Error> {
+        let ops = vec![AbstractOp::Label("a".into()), AbstractOp::new(JumpDest)];
+
+        let mut asm = Assembler::new();
+        asm.set_strict(true);
+        asm.export_label("a");
+        assert_eq!(asm.assemble(&ops)?, hex!("5b"));
+
+        let mut asm = Assembler::new();
+        asm.set_strict(true);
+        let segment = asm.assemble_object("code", &ops)?;
+        assert_eq!(segment.labels["a"], 0);
+        assert!(asm.warnings().is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn assemble_duplicate_labels_in_instruction_macro() -> Result<(), Error> {
+    fn assemble_promotes_lints() -> Result<(), Error> {
         let ops = vec![
-            InstructionMacroDefinition {
-                name: "my_macro".into(),
-                parameters: vec![],
-                contents: vec![AbstractOp::Label("a".into()), AbstractOp::Label("a".into())],
-            }
-            .into(),
-            AbstractOp::Macro(InstructionMacroInvocation::with_zero_parameters(
-                "my_macro".into(),
-            )),
+            AbstractOp::Label("a".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push2(Imm::from([0u8, 1]))),
         ];
+
+        let mut asm = Assembler::new();
+        asm.set_promoted(&[Lint::RawOpcode, Lint::StackHeight]);
+        asm.assemble(&ops)?;
+        assert_eq!(asm.warnings().len(), 2);
+
         let mut asm = Assembler::new();
+        asm.set_promoted(&[Lint::OversizedPush]);
         let err = asm.assemble(&ops).unwrap_err();
-        assert_matches!(err, Error::DuplicateLabel { label, .. } if label == "a");
+        assert_matches!(
+            err,
+            Error::PromotedWarning {
+                warning: Warning::OversizedPush { .. },
+                ..
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "`push2` is wider than needed for `1` (`push1` would suffice) [oversized-push]"
+        );
 
         Ok(())
     }
 
-    // TODO: do we allow label shadowing in macros?
     #[test]
-    fn assemble_conflicting_labels_in_instruction_macro() -> Result<(), Error> {
+    fn assemble_synthesizes_constants() -> Result<(), Error> {
         let ops = vec![
+            AbstractOp::new(Push32(Imm::from([0xff; 32]))),
             AbstractOp::Label("a".into()),
-            AbstractOp::new(Caller),
-            InstructionMacroDefinition {
-                name: "my_macro()".into(),
-                parameters: vec![],
-                contents: vec![
-                    AbstractOp::Label("a".into()),
-                    AbstractOp::new(Push1(Imm::with_label("a"))),
-                ],
-            }
-            .into(),
-            AbstractOp::Macro(InstructionMacroInvocation::with_zero_parameters(
-                "my_macro()".into(),
-            )),
-            AbstractOp::new(Push1(Imm::with_label("a"))),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push2(Imm::with_label("a"))),
         ];
+
         let mut asm = Assembler::new();
+        asm.set_synthesis(Some(CostModel::Bytes));
         let result = asm.assemble(&ops)?;
 
-        assert_eq!(result, hex!("3360016000"));
+        assert_eq!(result, hex!("5f195b610002"));
+        assert_eq!(
+            asm.substitutions(),
+            [Substitution {
+                original: Op::from(prague::Push32([0xff; 32])),
+                replacement: vec![Op::from(prague::Push0), Op::from(prague::Not)],
+            }]
+        );
 
         Ok(())
     }
 
     #[test]
-    fn assemble_instruction_macro_with_parameters() -> Result<(), Error> {
-        let ops = vec![
-            InstructionMacroDefinition {
-                name: "my_macro".into(),
-                parameters: vec!["foo".into(), "bar".into()],
-                contents: vec![
-                    AbstractOp::new(Push1(Imm::with_variable("foo"))),
-                    AbstractOp::new(Push1(Imm::with_variable("bar"))),
-                ],
-            }
-            .into(),
-            AbstractOp::Label("b".into()),
-            AbstractOp::new(JumpDest),
-            AbstractOp::new(Push1(Imm::with_label("b"))),
-            AbstractOp::Macro(InstructionMacroInvocation {
-                name: "my_macro".into(),
-                parameters: vec![
-                    BigInt::from_bytes_be(Sign::Plus, &vec![0x42]).into(),
-                    Terminal::Label("b".to_string()).into(),
-                ],
-            }),
-        ];
+    fn assemble_synthesizes_for_target() -> Result<(), Error> {
+        let ops = vec![AbstractOp::new(Push32(Imm::from([0xff; 32])))];
 
         let mut asm = Assembler::new();
+        asm.set_synthesis(Some(CostModel::Bytes));
+        asm.set_target(Profile::builtin("london"));
         let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("5b600060426000"));
+
+        assert_eq!(result, hex!("600019"));
 
         Ok(())
     }
 
     #[test]
-    fn assemble_expression_push() -> Result<(), Error> {
-        let ops = vec![AbstractOp::new(Push1(Imm::with_expression(
-            Expression::Plus(1.into(), 1.into()),
-        )))];
+    fn assemble_warns_stack_underflow() -> Result<(), Error> {
+        let ops = vec![AbstractOp::new(GetPc), AbstractOp::new(Add)];
 
         let mut asm = Assembler::new();
-        let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("6002"));
+        asm.assemble(&ops)?;
+        assert_eq!(
+            asm.warnings(),
+            [Warning::StackUnderflow {
+                offset: 1,
+                op: Op::from(prague::Add),
+                height: 1,
+            }]
+        );
 
         Ok(())
     }
 
     #[test]
-    fn assemble_expression_negative() -> Result<(), Error> {
-        let ops = vec![AbstractOp::new(Push1(Imm::with_expression(
-            BigInt::from(-1).into(),
-        )))];
+    fn assemble_builtin_pc_relative() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::new(Caller),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "pc_relative".into(),
+                parameters: vec![Terminal::Label("data".into()).into()],
+            }),
+            AbstractOp::new(Stop),
+            AbstractOp::Label("data".into()),
+            AbstractOp::new(Caller),
+        ];
+
         let mut asm = Assembler::new();
-        let err = asm.assemble(&ops).unwrap_err();
-        assert_matches!(err, Error::ExpressionNegative { value, .. } if value == BigInt::from(-1));
+        let code = asm.assemble(&ops)?;
+        assert_eq!(code, hex!("33586005010033"));
 
         Ok(())
     }
 
     #[test]
-    fn assemble_expression_undeclared_label() -> Result<(), Error> {
+    fn assemble_builtin_pc_relative_backward() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::Label("data".into()),
+            AbstractOp::new(Caller),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "pc_relative".into(),
+                parameters: vec![Terminal::Label("data".into()).into()],
+            }),
+        ];
+
         let mut asm = Assembler::new();
-        let ops = vec![AbstractOp::new(Push1(Imm::with_expression(
-            Terminal::Label(String::from("hi")).into(),
-        )))];
-        let err = asm.assemble(&ops).unwrap_err();
-        assert_matches!(err, Error::UndeclaredLabels { labels, .. } if labels == vec!["hi"]);
+        let code = asm.assemble(&ops)?;
+        assert_eq!(
+            code,
+            hex!("3358 7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff 01")
+        );
+
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push_before_push2() -> Result<(), Error> {
-        let mut asm = Assembler::new();
+    fn assemble_declared_macro_shadows_builtin() -> Result<(), Error> {
         let ops = vec![
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                Terminal::Label("foo".into()).into(),
-                BigInt::from(256).into(),
-            ))),
-            AbstractOp::new(Push2(Imm::with_label("foo1"))),
-            AbstractOp::Label("foo".into()),
-            AbstractOp::Label("foo1".into()),
+            InstructionMacroDefinition::new(
+                "pc_relative".into(),
+                vec!["target".into()],
+                vec![AbstractOp::new(Caller)],
+            )
+            .into(),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "pc_relative".into(),
+                parameters: vec![Terminal::Number(1.into()).into()],
+            }),
         ];
-        let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("610106610006"));
+
+        let mut asm = Assembler::new();
+        let code = asm.assemble(&ops)?;
+        assert_eq!(code, hex!("33"));
+
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push_before_push2_inverted() -> Result<(), Error> {
-        let mut asm = Assembler::new();
+    fn assemble_pinned_label() -> Result<(), Error> {
         let ops = vec![
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                Terminal::Label("z".into()).into(),
-                BigInt::from(256).into(),
-            ))),
-            AbstractOp::new(Push2(Imm::with_label("a"))),
-            AbstractOp::Label("z".into()),
+            AbstractOp::Push(Terminal::Label("a".into()).into()),
+            AbstractOp::new(Jump),
             AbstractOp::Label("a".into()),
+            AbstractOp::new(JumpDest),
         ];
-        let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("610106610006"));
+
+        let mut asm = Assembler::new();
+        asm.pin_label("a", 3);
+        let code = asm.assemble(&ops)?;
+        assert_eq!(code, hex!("6003565b"));
+
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push_mixed_labels() -> Result<(), Error> {
+    fn assemble_pinned_label_moved() {
+        let ops = vec![
+            AbstractOp::new(Caller),
+            AbstractOp::Label("a".into()),
+            AbstractOp::new(JumpDest),
+        ];
+
+        let mut asm = Assembler::new();
+        asm.pin_label("a", 0);
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(
+            err,
+            Error::PinnedLabelMoved { label, pinned: 0, actual: 1, .. } if label == "a"
+        );
+    }
+
+    #[test]
+    fn assemble_pinned_label_undeclared() {
+        let ops = vec![AbstractOp::new(Caller)];
+
         let mut asm = Assembler::new();
+        asm.pin_label("a", 0);
+        let err = asm.assemble(&ops).unwrap_err();
+        assert_matches!(
+            err,
+            Error::UndeclaredLabels { labels, .. } if labels == vec!["a".to_string()]
+        );
+    }
+
+    #[test]
+    fn assemble_instruction_macro_with_defaults() -> Result<(), Error> {
         let ops = vec![
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                Terminal::Label("foo".into()).into(),
-                BigInt::from(256).into(),
-            ))),
-            AbstractOp::new(Push2(Imm::with_label("foo"))),
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                Terminal::Label("bar".into()).into(),
-                BigInt::from(256).into(),
-            ))),
-            AbstractOp::new(Gas),
-            AbstractOp::Label("foo".into()),
-            AbstractOp::new(Gas),
-            AbstractOp::Label("bar".into()),
+            InstructionMacroDefinition::new(
+                "my_macro".into(),
+                vec!["foo".into(), "bar".into()],
+                vec![
+                    AbstractOp::new(Push1(Imm::with_variable("foo"))),
+                    AbstractOp::new(Push1(Imm::with_variable("bar"))),
+                ],
+            )
+            .with_defaults(vec![Terminal::Number(0xbb.into()).into()])
+            .into(),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![Terminal::Number(0xaa.into()).into()],
+            }),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "my_macro".into(),
+                parameters: vec![
+                    Terminal::Number(0xaa.into()).into(),
+                    Terminal::Number(0xcc.into()).into(),
+                ],
+            }),
         ];
+
+        let mut asm = Assembler::new();
         let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("60aa60bb60aa60cc"));
 
-        assert_eq!(result, hex!("61010a61000a61010b5a5a"));
         Ok(())
     }
 
     #[test]
-    fn assemble_double_update_variable_push() -> Result<(), Error> {
-        let mut asm = Assembler::new();
+    fn assemble_instruction_macro_variadic() -> Result<(), Error> {
         let ops = vec![
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                Terminal::Label("foo".into()).into(),
-                BigInt::from(256).into(),
-            ))),
-            AbstractOp::new(Push2(Imm::with_label("foo1"))),
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                Terminal::Label("foo".into()).into(),
-                BigInt::from(256).into(),
-            ))),
-            AbstractOp::Label("foo".into()),
-            AbstractOp::Label("foo1".into()),
+            InstructionMacroDefinition::new(
+                "push_all".into(),
+                vec!["first".into()],
+                vec![
+                    AbstractOp::new(Push1(Imm::with_variable("first"))),
+                    AbstractOp::Push(Imm::with_variable("rest")),
+                    AbstractOp::new(Caller),
+                ],
+            )
+            .with_variadic(Some("rest".into()))
+            .into(),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "push_all".into(),
+                parameters: vec![
+                    Terminal::Number(1.into()).into(),
+                    Terminal::Number(2.into()).into(),
+                    Terminal::Number(0x0300.into()).into(),
+                ],
+            }),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "push_all".into(),
+                parameters: vec![Terminal::Number(4.into()).into()],
+            }),
         ];
+
+        let mut asm = Assembler::new();
         let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("610109610009610109"));
+        assert_eq!(result, hex!("6001 6002 610300 33 6004 33"));
+
         Ok(())
     }
 
     #[test]
-    fn assemble_double_update_variable_push2() -> Result<(), Error> {
-        let mut asm = Assembler::new();
+    fn assemble_extern_placeholder() -> Result<(), Error> {
         let ops = vec![
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                Terminal::Label("foo".into()).into(),
-                BigInt::from(256).into(),
-            ))),
-            AbstractOp::new(Push2(Imm::with_label("foo1"))),
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                Terminal::Label("foo".into()).into(),
-                BigInt::from(256).into(),
-            ))),
-            AbstractOp::new(Push2(Imm::with_label("foo1"))),
-            AbstractOp::Label("foo".into()),
-            AbstractOp::Label("foo1".into()),
+            RawOp::Op(AbstractOp::new(Push2(Imm::with_label("ext")))),
+            RawOp::Extern("ext".into()),
+            RawOp::Op(AbstractOp::new(Jump)),
         ];
-        let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("61010c61000c61010c61000c"));
+
+        let mut asm = Assembler::new();
+        let code = asm.assemble(&ops)?;
+        assert_eq!(code, hex!("61000056"));
+        assert_eq!(
+            asm.extern_references(),
+            [ExternReference {
+                label: "ext".into(),
+                offset: 1,
+                spec: Op::from(prague::Push2(())),
+            }]
+        );
+
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push_expression_with_undeclared_labels() -> Result<(), Error> {
-        let mut asm = Assembler::new();
+    fn assemble_object_with_extern() -> Result<(), Error> {
         let ops = vec![
-            AbstractOp::new(JumpDest),
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                Terminal::Label("foo".into()).into(),
-                Terminal::Label("bar".into()).into(),
-            ))),
-            AbstractOp::new(Gas),
+            RawOp::Extern("ext".into()),
+            RawOp::Op(AbstractOp::Label("here".into())),
+            RawOp::Op(AbstractOp::new(Push2(Imm::with_label("ext")))),
         ];
-        let err = asm.assemble(&ops).unwrap_err();
-        // The expressions have short-circuit evaluation, so only the first label is caught in the error.
-        assert_matches!(err, Error::UndeclaredLabels { labels, .. } if (labels.contains(&"foo".to_string())));
-        Ok(())
-    }
 
-    #[test]
-    fn assemble_variable_push2_comparison_with_undeclared_labels() -> Result<(), Error> {
         let mut asm = Assembler::new();
+        let object = asm.assemble_object("main", &ops)?;
+        assert_eq!(object.name, "main");
+        assert_eq!(object.code, hex!("610000"));
+        assert_eq!(object.labels.get("here"), Some(&0));
+        assert_eq!(
+            object.relocations,
+            [Relocation {
+                offset: 1,
+                width: 2,
+                kind: RelocationKind::External,
+                segment: String::new(),
+                label: Some("ext".into()),
+            }]
+        );
 
-        // %push(lbl1 - lbl2)
-        // push2 lbl1 + lbl2
-        // pc # repeat 126 times.
-        // lbl1:
-        // lbl2:
-        let mut ops = vec![AbstractOp::new(GetPc); 130];
-        ops[0] = AbstractOp::Push(Imm::with_expression(Expression::Minus(
-            Terminal::Label(String::from("lbl1")).into(),
-            Terminal::Label(String::from("lbl2")).into(),
-        )));
-        ops[1] = AbstractOp::new(Push2(
-            Expression::Plus(
-                Terminal::Label(String::from("lbl1")).into(),
-                Terminal::Label(String::from("lbl2")).into(),
-            )
-            .into(),
-        ));
-        ops[128] = AbstractOp::Label("lbl1".into());
-        ops[129] = AbstractOp::Label("lbl2".into());
+        Ok(())
+    }
 
-        let expected = asm.assemble(&ops)?;
+    #[test]
+    fn assemble_extern_resolved_by_parent() -> Result<(), Error> {
+        let ops = vec![
+            RawOp::Op(AbstractOp::Push(Imm::with_label("target"))),
+            RawOp::Scope(vec![
+                RawOp::Extern("target".into()),
+                RawOp::Op(AbstractOp::new(Push1(Imm::with_label("target")))),
+                RawOp::Op(AbstractOp::new(Jump)),
+            ]),
+            RawOp::Op(AbstractOp::Label("target".into())),
+            RawOp::Op(AbstractOp::new(JumpDest)),
+        ];
 
         let mut asm = Assembler::new();
+        let code = asm.assemble(&ops)?;
+        assert_eq!(code, hex!("60056005565b"));
+        assert!(asm.extern_references().is_empty());
+        assert!(asm.warnings().is_empty());
 
-        // %push(lbl1 - lbl2)
-        // %push(lbl1 + lbl2)
-        // pc # repeat 126 times.
-        // lbl1:
-        // lbl2:
-        ops[1] = AbstractOp::Push(Imm::with_expression(Expression::Plus(
-            Terminal::Label(String::from("lbl1")).into(),
-            Terminal::Label(String::from("lbl2")).into(),
-        )));
-        let result = asm.assemble(&ops)?;
-
-        // Sanity check the expected result: should use push1 then push2.
-        assert_eq!(expected[0], 0x60);
-        assert_eq!(expected[2], 0x61);
-
-        // Assert that the two results are identical.
-        assert_eq!(expected, result);
         Ok(())
     }
 
     #[test]
-    fn assemble_variable_push1_expression() -> Result<(), Error> {
-        let mut asm = Assembler::new();
+    fn assemble_extern_forwarded_by_parent() -> Result<(), Error> {
         let ops = vec![
-            AbstractOp::new(JumpDest),
-            AbstractOp::Label("auto".into()),
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                1.into(),
-                Terminal::Label(String::from("auto")).into(),
-            ))),
+            RawOp::Extern("target".into()),
+            RawOp::Op(AbstractOp::new(Caller)),
+            RawOp::Scope(vec![
+                RawOp::Extern("target".into()),
+                RawOp::Op(AbstractOp::new(Push1(Imm::with_label("target")))),
+            ]),
         ];
-        let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("5b6002"));
+
+        let mut asm = Assembler::new();
+        let code = asm.assemble(&ops)?;
+        assert_eq!(code, hex!("336000"));
+        assert_eq!(
+            asm.extern_references(),
+            [ExternReference {
+                label: "target".into(),
+                offset: 2,
+                spec: Op::from(prague::Push1(())),
+            }]
+        );
+
         Ok(())
     }
 
     #[test]
-    fn assemble_expression_with_labels() -> Result<(), Error> {
-        let mut asm = Assembler::new();
+    fn assemble_extern_undeclared_by_parent() {
+        let ops = vec![RawOp::Scope(vec![
+            RawOp::Extern("target".into()),
+            RawOp::Op(AbstractOp::new(Push1(Imm::with_label("target")))),
+        ])];
+
+        let err = Assembler::new().assemble(&ops).unwrap_err();
+        assert_matches!(
+            err,
+            Error::UndeclaredLabels { labels, .. } if labels == vec!["target".to_string()]
+        );
+    }
+
+    #[test]
+    fn assemble_extern_in_expression() {
         let ops = vec![
-            AbstractOp::new(JumpDest),
-            AbstractOp::Push(Imm::with_expression(Expression::Plus(
-                Terminal::Label(String::from("foo")).into(),
-                Terminal::Label(String::from("bar")).into(),
-            ))),
-            AbstractOp::new(Gas),
-            AbstractOp::Label("foo".into()),
-            AbstractOp::Label("bar".into()),
+            RawOp::Extern("ext".into()),
+            RawOp::Op(AbstractOp::new(Push2(Imm::with_expression(
+                Expression::Plus(Terminal::Label("ext".into()).into(), 1u64.into()),
+            )))),
         ];
-        let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("5b60085a"));
-        Ok(())
+
+        let err = Assembler::new().assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::InvalidExternUse { label, .. } if label == "ext");
+
+        let ops = vec![
+            RawOp::Extern("ext".into()),
+            RawOp::Op(AbstractOp::Push(Imm::with_label("ext"))),
+        ];
+
+        let err = Assembler::new().assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::InvalidExternUse { label, .. } if label == "ext");
     }
 
     #[test]
-    fn assemble_expression_macro_push() -> Result<(), Error> {
+    fn assemble_extern_declared_locally() {
         let ops = vec![
+            RawOp::Extern("ext".into()),
+            RawOp::Op(AbstractOp::Label("ext".into())),
+        ];
+
+        let err = Assembler::new().assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::DuplicateLabel { label, .. } if label == "ext");
+    }
+
+    fn chain_id() -> RawOp {
+        RawOp::Op(
             ExpressionMacroDefinition {
-                name: "foo".into(),
+                name: "chain_id".into(),
                 parameters: vec![],
-                content: Imm::with_expression(Expression::Plus(1.into(), 1.into())),
+                content: Imm::with_expression(Terminal::from(1u64).into()),
             }
             .into(),
-            AbstractOp::new(Push1(Imm::with_macro(ExpressionMacroInvocation {
-                name: "foo".into(),
-                parameters: vec![],
-            }))),
+        )
+    }
+
+    fn push_chain_id() -> Imm {
+        Imm::with_macro(ExpressionMacroInvocation {
+            name: "chain_id".into(),
+            parameters: vec![],
+        })
+    }
+
+    #[test]
+    fn assemble_patchable_push() -> Result<(), Error> {
+        let ops = vec![
+            RawOp::Op(AbstractOp::new(Caller)),
+            RawOp::Op(AbstractOp::new(Push2(push_chain_id()))),
+            RawOp::Scope(vec![
+                chain_id(),
+                RawOp::Patchable("chain_id".into()),
+                RawOp::Op(AbstractOp::new(Push4(push_chain_id()))),
+            ]),
+            chain_id(),
+            RawOp::Patchable("chain_id".into()),
         ];
 
         let mut asm = Assembler::new();
-        let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("6002"));
+        let code = asm.assemble(&ops)?;
+        assert_eq!(code, hex!("33 610001 6300000001"));
+        assert_eq!(asm.patches().ranges("chain_id"), [2..4, 5..9]);
 
         Ok(())
     }
 
     #[test]
-    fn assemble_instruction_macro_with_undeclared_variables() {
+    fn assemble_patchable_invalid() {
+        let ops = vec![RawOp::Patchable("chain_id".into())];
+        let err = Assembler::new().assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::InvalidPatchable { name, .. } if name == "chain_id");
+
         let ops = vec![
-            InstructionMacroDefinition {
-                name: "my_macro".into(),
-                parameters: vec!["foo".into()],
-                contents: vec![AbstractOp::new(Push1(Imm::with_variable("bar")))],
-            }
-            .into(),
-            AbstractOp::Label("b".into()),
-            AbstractOp::new(JumpDest),
-            AbstractOp::new(Push1(Imm::with_label("b"))),
-            AbstractOp::Macro(InstructionMacroInvocation {
-                name: "my_macro".into(),
-                parameters: vec![BigInt::from_bytes_be(Sign::Plus, &vec![0x42]).into()],
-            }),
+            chain_id(),
+            RawOp::Patchable("chain_id".into()),
+            RawOp::Op(AbstractOp::Push(push_chain_id())),
+        ];
+        let err = Assembler::new().assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::InvalidPatchableUse { name, .. } if name == "chain_id");
+
+        let ops = vec![
+            chain_id(),
+            RawOp::Patchable("chain_id".into()),
+            RawOp::Op(AbstractOp::new(Push2(Imm::with_expression(
+                Expression::Plus(push_chain_id().tree.into(), 1u64.into()),
+            )))),
+        ];
+        let err = Assembler::new().assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::InvalidPatchableUse { name, .. } if name == "chain_id");
+    }
+
+    #[test]
+    fn assemble_capture() -> Result<(), Error> {
+        let call = |name: &str, parameters| {
+            Expression::Macro(ExpressionMacroInvocation {
+                name: name.into(),
+                parameters,
+            })
+        };
+
+        let len = call("len", vec![call("region", vec![])]);
+        let ops = vec![
+            AbstractOp::new(Push1(Imm::with_expression(len))).into(),
+            RawOp::Bytes(call("region", vec![])),
+            RawOp::Capture(
+                "region".into(),
+                vec![
+                    AbstractOp::Label("a".into()).into(),
+                    AbstractOp::new(JumpDest).into(),
+                    AbstractOp::new(Push1(Imm::with_label("a"))).into(),
+                ],
+            ),
+            AbstractOp::Label("a".into()).into(),
+            AbstractOp::new(JumpDest).into(),
         ];
 
+        // The region has its own labels, and is only in the output where
+        // it's copied.
         let mut asm = Assembler::new();
-        let err = asm.assemble(&ops).unwrap_err();
+        let result = asm.assemble(&ops)?;
+        assert_eq!(result, hex!("6003" "5b6000" "5b"));
+        assert_eq!(asm.label("a"), Some(5));
 
-        assert_matches!(err, Error::UndeclaredVariableMacro { var, .. } if var == "bar");
+        Ok(())
     }
 
     #[test]
-    fn assemble_instruction_macro_two_delayed_definitions_mirrored() -> Result<(), Error> {
+    fn assemble_capture_errors() {
         let ops = vec![
-            AbstractOp::new(GetPc),
-            AbstractOp::Macro(InstructionMacroInvocation {
-                name: "macro1".into(),
-                parameters: vec![],
-            }),
+            RawOp::Capture("region".into(), vec![]),
+            RawOp::Capture("region".into(), vec![]),
+        ];
+        let err = Assembler::new().assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::DuplicateMacro { name, .. } if name == "region");
+
+        let ops = vec![RawOp::Capture(
+            "region".into(),
+            vec![
+                RawOp::Extern("ext".into()),
+                AbstractOp::new(Push2(Imm::with_label("ext"))).into(),
+            ],
+        )];
+        let err = Assembler::new().assemble(&ops).unwrap_err();
+        assert_matches!(err, Error::ExternInCapture { label, .. } if label == "ext");
+    }
+
+    #[test]
+    fn assemble_instruction_macro_argument_count() {
+        let defn = InstructionMacroDefinition::new(
+            "my_macro".into(),
+            vec!["foo".into(), "bar".into()],
+            vec![AbstractOp::new(Push1(Imm::with_variable("foo")))],
+        )
+        .with_defaults(vec![Terminal::Number(1.into()).into()]);
+
+        for &(count, expected) in [(0, 1), (3, 2)].iter() {
+            let ops = vec![
+                defn.clone().into(),
+                AbstractOp::Macro(InstructionMacroInvocation {
+                    name: "my_macro".into(),
+                    parameters: vec![Terminal::Number(1.into()).into(); count],
+                }),
+            ];
+
+            let err = Assembler::new().assemble(&ops).unwrap_err();
+            assert_matches!(
+                err,
+                Error::MacroArgumentCount { name, expected: e, got, .. }
+                    if name == "my_macro" && e == expected && got == count
+            );
+        }
+    }
+
+    #[test]
+    fn assemble_debug_info() -> Result<(), Error> {
+        let inner: RawOp = AbstractOp::from(InstructionMacroDefinition::new(
+            "inner".into(),
+            vec![],
+            vec![AbstractOp::new(Caller)],
+        ))
+        .into();
+
+        let invoke = |name: &str| -> RawOp {
             AbstractOp::Macro(InstructionMacroInvocation {
-                name: "macro0".into(),
-                parameters: vec![],
-            }),
-            InstructionMacroDefinition {
-                name: "macro0".into(),
+                name: name.into(),
                 parameters: vec![],
-                contents: vec![AbstractOp::new(JumpDest)],
-            }
-            .into(),
-            InstructionMacroDefinition {
-                name: "macro1".into(),
-                parameters: vec![],
-                contents: vec![AbstractOp::new(Caller)],
-            }
+            })
+            .into()
+        };
+
+        let ops = vec![
+            inner.clone(),
+            AbstractOp::from(InstructionMacroDefinition::new(
+                "outer".into(),
+                vec![],
+                vec![
+                    AbstractOp::new(Address),
+                    AbstractOp::Macro(InstructionMacroInvocation {
+                        name: "inner".into(),
+                        parameters: vec![],
+                    }),
+                    AbstractOp::new(Caller),
+                ],
+            ))
             .into(),
+            AbstractOp::Label("start".into()).into(),
+            AbstractOp::new(GetPc).into(),
+            invoke("outer"),
+            RawOp::NamedScope(
+                "ns".into(),
+                vec![inner, AbstractOp::Label("a".into()).into(), invoke("inner")],
+            ),
         ];
 
         let mut asm = Assembler::new();
         let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("58335b"));
+        assert_eq!(result, hex!("5830333333"));
+
+        let info = asm.debug_info();
+        let labels: Vec<_> = info
+            .labels
+            .iter()
+            .map(|s| (s.name.as_str(), s.offset))
+            .collect();
+        assert_eq!(labels, [("start", 0), ("ns.a", 4)]);
+
+        let expansions: Vec<_> = info
+            .expansions
+            .iter()
+            .map(|e| (e.name.as_str(), e.start, e.end))
+            .collect();
+        assert_eq!(
+            expansions,
+            [("outer", 1, 4), ("inner", 2, 3), ("inner", 4, 5)]
+        );
+
+        // address; caller; caller
+        let gas: Vec<_> = info.expansions.iter().map(|e| e.gas).collect();
+        assert_eq!(gas, [6, 2, 2]);
 
         Ok(())
     }
 
     #[test]
-    fn assemble_instruction_macro_two_delayed_definitions() -> Result<(), Error> {
-        let ops = vec![
-            AbstractOp::new(GetPc),
-            AbstractOp::Macro(InstructionMacroInvocation {
-                name: "macro0".into(),
-                parameters: vec![],
-            }),
-            AbstractOp::Macro(InstructionMacroInvocation {
-                name: "macro1".into(),
-                parameters: vec![],
-            }),
-            InstructionMacroDefinition {
-                name: "macro0".into(),
-                parameters: vec![],
-                contents: vec![AbstractOp::new(JumpDest)],
-            }
+    fn assemble_macro_details() -> Result<(), Error> {
+        let ops: Vec<RawOp> = vec![
+            AbstractOp::from(
+                InstructionMacroDefinition::new(
+                    "pair".into(),
+                    vec!["x".into(), "y".into()],
+                    vec![
+                        AbstractOp::Push(Terminal::Variable("x".into()).into()),
+                        AbstractOp::Push(Terminal::Variable("y".into()).into()),
+                    ],
+                )
+                .with_defaults(vec![Terminal::Number(2.into()).into()]),
+            )
             .into(),
-            InstructionMacroDefinition {
-                name: "macro1".into(),
-                parameters: vec![],
-                contents: vec![AbstractOp::new(Caller)],
-            }
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "pair".into(),
+                parameters: vec![Terminal::Number(1.into()).into()],
+            })
             .into(),
+            AbstractOp::new(Caller).into(),
         ];
 
         let mut asm = Assembler::new();
+        asm.assemble(&ops)?;
+        assert!(asm.debug_info().macros.is_empty());
+        assert!(asm.debug_info().expansions[0].ops.is_empty());
+
+        let mut asm = Assembler::new();
+        asm.set_macro_details(true);
         let result = asm.assemble(&ops)?;
-        assert_eq!(result, hex!("585b33"));
+        assert_eq!(result, hex!("6001600233"));
+
+        let info = asm.debug_info();
+        assert_eq!(info.macros.len(), 1);
+        assert_eq!(info.macros[0].name, "pair");
+        assert_eq!(info.macros[0].parameters, ["x", "y = 2"]);
+        assert_eq!(info.macros[0].contents.len(), 2);
+
+        let expansion = &info.expansions[0];
+        assert_eq!(expansion.arguments, ["1"]);
+        assert_eq!(expansion.ops, ["push1 0x01", "push1 0x02"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_labels_after_backpatch() -> Result<(), Error> {
+        let ops = vec![
+            RawOp::from(AbstractOp::Push(Imm::with_label("end"))),
+            AbstractOp::Label("start".into()).into(),
+            RawOp::Raw(vec![0; 256]),
+            RawOp::NamedScope(
+                "ns".into(),
+                vec![
+                    AbstractOp::Label("inner".into()).into(),
+                    AbstractOp::new(JumpDest).into(),
+                ],
+            ),
+            AbstractOp::Label("end".into()).into(),
+        ];
+
+        let mut asm = Assembler::new();
+        asm.assemble(&ops)?;
+
+        // The push of `end` grows to two bytes, moving every label.
+        assert_eq!(asm.label("end"), Some(0x104));
+        assert_eq!(asm.label("ns.inner"), Some(0x103));
+        assert_eq!(asm.label("inner"), None);
 
+        let labels: Vec<_> = asm.labels().collect();
+        assert_eq!(labels, [("start", 3), ("ns.inner", 0x103), ("end", 0x104)]);
         Ok(())
     }
 }
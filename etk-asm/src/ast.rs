@@ -1,14 +1,51 @@
+use std::ops::Range;
 use std::path::PathBuf;
 
-use crate::ops::{Abstract, AbstractOp, ExpressionMacroDefinition, InstructionMacroDefinition};
-use etk_ops::cancun::Op;
+use crate::ops::{
+    Abstract, AbstractOp, Expression, ExpressionMacroDefinition, InstructionMacroDefinition,
+};
+use etk_ops::prague::Op;
+
+/// The label standing for `codesize()` until it's resolved to the size of
+/// the enclosing scope or file. It can't be written in a source.
+pub(crate) const CODE_SIZE: &str = "%size";
+
+/// The label marking the end of a file whose size is used with
+/// `codesize()`.
+pub(crate) const CODE_END: &str = "%end";
+
+/// The label marking where the runtime code starts in a file with
+/// `%runtime_start`. It can't be written in a source.
+pub(crate) const RUNTIME_START: &str = "%runtime";
 
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) enum Node {
     Op(AbstractOp),
     Import(PathBuf),
-    Include(PathBuf),
+    Include(PathBuf, Option<String>),
     IncludeHex(PathBuf),
+    IncludeBin(PathBuf),
+    IncludeSolRuntime(PathBuf, String),
+    IncludeArtifact(PathBuf, String),
+    Bytes(Expression),
+    AbiEncode(String, Vec<Expression>),
+    JumpTable(Vec<Expression>),
+    RJumpV(Vec<Expression>),
+    Data(usize, Vec<Expression>),
+    Opcode(Expression, Option<(Expression, Expression)>),
+    Extern(String),
+    Patchable(String),
+    Export(String),
+    RuntimeStart,
+    Fallthrough,
+    Keep(Vec<Option<String>>),
+    Scope(String, Vec<(Node, Range<usize>)>),
+    Capture(String, Vec<(Node, Range<usize>)>),
+    If(
+        Expression,
+        Vec<(Node, Range<usize>)>,
+        Vec<(Node, Range<usize>)>,
+    ),
 }
 impl From<Op<Abstract>> for Node {
     fn from(op: Op<Abstract>) -> Self {
@@ -0,0 +1,363 @@
+//! Building many entry points at once.
+//!
+//! Requires the `fs` feature.
+//!
+//! A [`Batch`] assembles a set of entry files with the same options. Entries
+//! are built concurrently, and sources shared between them (for example, a
+//! library of macros imported by every entry) are only parsed once.
+//!
+//! With a cache directory (see [`Batch::set_cache_dir`]), entries whose
+//! sources haven't changed since they were last built aren't assembled again.
+use crate::cache::{BuildCache, CachedBuild};
+use crate::ingest::{Error, Ingest, ParseCache};
+use crate::optimize::Pass;
+use crate::synth::{CostModel, Substitution};
+use crate::warning::Warning;
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// The result of building one entry file.
+#[derive(Debug)]
+pub struct Artifact {
+    /// Path to the entry file.
+    pub entry: PathBuf,
+
+    /// The assembled code, or the error that prevented assembling it.
+    pub code: Result<Vec<u8>, Error>,
+
+    /// Warnings found while assembling the entry.
+    pub warnings: Vec<Warning>,
+
+    /// Pushes replaced while assembling the entry.
+    pub substitutions: Vec<Substitution>,
+
+    /// Whether the code was reused from the build cache, instead of being
+    /// assembled.
+    pub cached: bool,
+}
+
+/// Options shared by every entry, and the caches built while assembling them.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::batch::Batch;
+/// # let dir = tempfile::tempdir().unwrap();
+/// # let first = dir.path().join("first.etk");
+/// # let second = dir.path().join("second.etk");
+/// # std::fs::write(&first, "push1 1").unwrap();
+/// # std::fs::write(&second, "push1 2").unwrap();
+///
+/// let mut batch = Batch::new();
+/// batch.set_strict(true);
+///
+/// let artifacts = batch.build(&[first, second]);
+/// assert_eq!(artifacts[0].code.as_ref().unwrap(), &[0x60, 0x01]);
+/// assert_eq!(artifacts[1].code.as_ref().unwrap(), &[0x60, 0x02]);
+/// ```
+#[derive(Debug, Default)]
+pub struct Batch {
+    strict: bool,
+    synthesis: Option<CostModel>,
+    passes: Vec<Pass>,
+    jobs: Option<usize>,
+    cache: Arc<ParseCache>,
+    build_cache: Option<BuildCache>,
+}
+
+impl Batch {
+    /// Create a new `Batch` with default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Promote warnings to errors.
+    ///
+    /// See [`Ingest::set_strict`].
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Replace pushes of constants with cheaper instruction sequences.
+    ///
+    /// See [`Ingest::set_synthesis`].
+    pub fn set_synthesis(&mut self, model: Option<CostModel>) {
+        self.synthesis = model;
+    }
+
+    /// Run the optimization `passes` on every entry.
+    ///
+    /// See [`Ingest::set_optimizations`].
+    pub fn set_optimizations(&mut self, passes: &[Pass]) {
+        self.passes = passes.to_vec();
+    }
+
+    /// Limit the number of entries built at the same time. Defaults to the
+    /// available parallelism.
+    pub fn set_jobs(&mut self, jobs: Option<usize>) {
+        self.jobs = jobs;
+    }
+
+    /// Reuse builds stored in `dir` when none of their sources changed, and
+    /// store new builds there. Defaults to no cache.
+    pub fn set_cache_dir(&mut self, dir: Option<PathBuf>) {
+        self.build_cache = dir.map(BuildCache::new);
+    }
+
+    /// The build cache, if a cache directory is set.
+    pub fn build_cache(&self) -> Option<&BuildCache> {
+        self.build_cache.as_ref()
+    }
+
+    /// Returns `true` if building `entry` would reuse a cached build.
+    pub fn is_cached<P>(&self, entry: P) -> bool
+    where
+        P: AsRef<Path>,
+    {
+        self.build_cache
+            .as_ref()
+            .and_then(|c| c.lookup(entry.as_ref(), &self.options()))
+            .is_some()
+    }
+
+    /// Assemble every file in `entries`.
+    ///
+    /// Returns one artifact per entry, in the same order. An entry failing to
+    /// build doesn't prevent the others from building.
+    pub fn build<P>(&self, entries: &[P]) -> Vec<Artifact>
+    where
+        P: Clone + Into<PathBuf> + Sync,
+    {
+        let jobs = self
+            .jobs
+            .or_else(|| thread::available_parallelism().ok().map(Into::into))
+            .unwrap_or(1)
+            .clamp(1, entries.len().max(1));
+
+        let next = AtomicUsize::new(0);
+
+        let mut built: Vec<(usize, Artifact)> = thread::scope(|s| {
+            let workers: Vec<_> = (0..jobs)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut built = Vec::new();
+                        loop {
+                            let idx = next.fetch_add(1, Ordering::Relaxed);
+                            match entries.get(idx) {
+                                Some(entry) => built.push((idx, self.build_one(entry.clone()))),
+                                None => break built,
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().expect("building an entry panicked"))
+                .collect()
+        });
+
+        built.sort_by_key(|(idx, _)| *idx);
+        built.into_iter().map(|(_, artifact)| artifact).collect()
+    }
+
+    fn build_one<P>(&self, entry: P) -> Artifact
+    where
+        P: Into<PathBuf>,
+    {
+        let entry = entry.into();
+        let options = self.options();
+
+        if let Some(cached) = self
+            .build_cache
+            .as_ref()
+            .and_then(|c| c.lookup(&entry, &options))
+        {
+            return Artifact {
+                entry,
+                code: Ok(cached.code),
+                warnings: Vec::new(),
+                substitutions: cached.substitutions,
+                cached: true,
+            };
+        }
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_strict(self.strict);
+        ingest.set_synthesis(self.synthesis);
+        ingest.set_optimizations(&self.passes);
+        ingest.set_cache(self.cache.clone());
+
+        let result = ingest.ingest_file(entry.clone());
+        let warnings = ingest.warnings().to_vec();
+        let substitutions = ingest.substitutions().to_vec();
+        let dependencies = ingest.dependencies().to_vec();
+
+        if let (Some(cache), Ok(()), true) = (&self.build_cache, &result, warnings.is_empty()) {
+            let build = CachedBuild {
+                code: output,
+                substitutions,
+            };
+
+            // Failing to store a build only makes the next build slower, so
+            // it isn't reported.
+            cache.store(&entry, &options, &dependencies, &build).ok();
+
+            return Artifact {
+                entry,
+                code: Ok(build.code),
+                warnings,
+                substitutions: build.substitutions,
+                cached: false,
+            };
+        }
+
+        Artifact {
+            entry,
+            code: result.map(|_| output),
+            warnings,
+            substitutions,
+            cached: false,
+        }
+    }
+
+    fn options(&self) -> String {
+        format!(
+            "strict={} synthesis={:?} passes={:?}",
+            self.strict, self.synthesis, self.passes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use crate::asm::Error as AsmError;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn build_shares_imports() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("lib.etk"),
+            "%macro twice(x)\n push1 $x\n push1 $x\n%end\n",
+        )
+        .unwrap();
+
+        let entries: Vec<_> = (1..=4)
+            .map(|n| {
+                let path = dir.path().join(format!("entry{}.etk", n));
+                let src = format!("%import(\"lib.etk\")\n%twice({})\n", n);
+                fs::write(&path, src).unwrap();
+                path
+            })
+            .collect();
+
+        let mut batch = Batch::new();
+        batch.set_jobs(Some(2));
+        let artifacts = batch.build(&entries);
+
+        assert_eq!(artifacts.len(), 4);
+        for (n, artifact) in artifacts.iter().enumerate() {
+            let n = n as u8 + 1;
+            assert_eq!(artifact.entry, entries[usize::from(n) - 1]);
+            assert_eq!(artifact.code.as_ref().unwrap(), &[0x60, n, 0x60, n]);
+        }
+
+        // One parse for each entry, and one for the shared library.
+        assert_eq!(batch.cache.len(), 5);
+    }
+
+    #[test]
+    fn build_reports_per_entry() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let good = dir.path().join("good.etk");
+        fs::write(&good, "a:\njumpdest\n").unwrap();
+
+        let bad = dir.path().join("bad.etk");
+        fs::write(&bad, "push1 missing\n").unwrap();
+
+        let artifacts = Batch::new().build(&[good, bad]);
+
+        assert_eq!(artifacts[0].code.as_ref().unwrap(), &hex!("5b"));
+        assert_eq!(artifacts[0].warnings.len(), 1);
+
+        assert_matches!(
+            artifacts[1].code,
+            Err(Error::Assemble {
+                source: AsmError::UndeclaredLabels { .. },
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn build_with_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.etk"), "push1 1\n").unwrap();
+
+        let entry = dir.path().join("entry.etk");
+        fs::write(&entry, "%import(\"lib.etk\")\npush1 2\n").unwrap();
+
+        let mut batch = Batch::new();
+        batch.set_cache_dir(Some(dir.path().join("cache")));
+        assert!(!batch.is_cached(&entry));
+
+        let artifacts = batch.build(&[entry.clone()]);
+        assert!(!artifacts[0].cached);
+        assert_eq!(artifacts[0].code.as_ref().unwrap(), &hex!("60016002"));
+        assert!(batch.is_cached(&entry));
+
+        let artifacts = batch.build(&[entry.clone()]);
+        assert!(artifacts[0].cached);
+        assert_eq!(artifacts[0].code.as_ref().unwrap(), &hex!("60016002"));
+
+        // Different options are built separately.
+        batch.set_strict(true);
+        assert!(!batch.is_cached(&entry));
+        batch.set_strict(false);
+
+        // Changing an imported file invalidates the build.
+        fs::write(dir.path().join("lib.etk"), "push1 3\n").unwrap();
+        assert!(!batch.is_cached(&entry));
+
+        let artifacts = batch.build(&[entry.clone()]);
+        assert!(!artifacts[0].cached);
+        assert_eq!(artifacts[0].code.as_ref().unwrap(), &hex!("60036002"));
+
+        batch.build_cache().unwrap().clear().unwrap();
+        assert!(!batch.is_cached(&entry));
+    }
+
+    #[test]
+    fn build_with_cache_skips_warnings() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = dir.path().join("entry.etk");
+        fs::write(&entry, "a:\njumpdest\n").unwrap();
+
+        let mut batch = Batch::new();
+        batch.set_cache_dir(Some(dir.path().join("cache")));
+
+        let artifacts = batch.build(&[entry.clone()]);
+        assert_eq!(artifacts[0].warnings.len(), 1);
+        assert!(!batch.is_cached(&entry));
+    }
+
+    #[test]
+    fn build_nothing() {
+        let entries: [PathBuf; 0] = [];
+        assert!(Batch::new().build(&entries).is_empty());
+    }
+}
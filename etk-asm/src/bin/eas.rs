@@ -1,21 +1,201 @@
 use etk_cli::errors::WithSources;
 use etk_cli::io::HexWrite;
 
+use etk_asm::analysis;
+use etk_asm::build_info;
+use etk_asm::debug::DebugInfo;
+use etk_asm::disasm::{Disassembler, Offset};
+use etk_asm::dispatch::Strategy;
 use etk_asm::ingest::{Error, Ingest};
+use etk_asm::link;
+use etk_asm::optimize::{OptimizationLevel, Pass};
+use etk_asm::policy::Policy;
+use etk_asm::profile::{CodeKind, Profile};
+use etk_asm::synth::CostModel;
+use etk_asm::warning::Lint;
+
+use etk_ops::prague::Op;
 
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use clap::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "eas")]
 struct Opt {
-    #[structopt(parse(from_os_str))]
-    input: PathBuf,
+    #[structopt(parse(from_os_str), required_unless_present = "build-info")]
+    input: Option<PathBuf>,
     #[structopt(parse(from_os_str))]
     out: Option<PathBuf>,
+    #[structopt(
+        long = "build-info",
+        help = "print the version, forks, features, and opcode table hash as JSON"
+    )]
+    build_info: bool,
+    #[structopt(
+        short = 'I',
+        long = "include-path",
+        parse(from_os_str),
+        help = "search a directory for included and imported files (may be repeated)"
+    )]
+    include_paths: Vec<PathBuf>,
+    #[structopt(long = "strict", help = "treat warnings as errors")]
+    strict: bool,
+    #[structopt(
+        long = "deny",
+        possible_values = &[
+            "unused-label",
+            "unreachable-code",
+            "oversized-push",
+            "stack-height",
+            "fallthrough",
+            "keep-mismatch",
+            "raw-opcode",
+        ],
+        help = "treat one kind of warning as an error (may be repeated)"
+    )]
+    deny: Vec<String>,
+    #[structopt(long = "explain", help = "describe how to fix errors in detail")]
+    explain: bool,
+    #[structopt(
+        long = "synthesize",
+        possible_values = &["bytes", "gas"],
+        help = "replace pushes of constants with cheaper sequences"
+    )]
+    synthesize: Option<String>,
+    #[structopt(
+        long = "dispatch",
+        possible_values = &["linear", "binary-search", "mask", "perfect-hash", "gas", "bytes"],
+        help = "choose how %dispatch finds a selector, or what to minimize when choosing"
+    )]
+    dispatch: Option<String>,
+    #[structopt(
+        long = "optimize",
+        possible_values = &["dead-code", "peephole"],
+        help = "run an optimization pass (may be repeated)"
+    )]
+    optimize: Vec<String>,
+    #[structopt(
+        short = 'O',
+        long = "opt-level",
+        possible_values = &["0", "1", "2"],
+        default_value = "0",
+        help = "run the optimization passes enabled at a level"
+    )]
+    opt_level: String,
+    #[structopt(long = "golf", help = "suggest ways to make the output smaller")]
+    golf: bool,
+    #[structopt(
+        long = "cfi-report",
+        parse(from_os_str),
+        help = "write a control flow integrity report to a file"
+    )]
+    cfi_report: Option<PathBuf>,
+    #[structopt(
+        long = "debug-info",
+        parse(from_os_str),
+        help = "write labels and macro expansions to a JSON file"
+    )]
+    debug_info: Option<PathBuf>,
+    #[structopt(
+        long = "macro-details",
+        help = "include macro definitions and expanded instructions in the debug info"
+    )]
+    macro_details: bool,
+    #[structopt(
+        long = "reproducible",
+        help = "number macro labels instead of randomizing them, and fail on features whose output can differ between machines"
+    )]
+    reproducible: bool,
+    #[structopt(
+        long = "srcmap",
+        parse(from_os_str),
+        help = "write a source map in solc's compressed format to a file"
+    )]
+    srcmap: Option<PathBuf>,
+    #[structopt(
+        long = "macro-stats",
+        help = "report the size and static gas of each instruction macro"
+    )]
+    macro_stats: bool,
+    #[structopt(
+        long = "symbols",
+        parse(from_os_str),
+        help = "write the position of every label to a JSON file"
+    )]
+    symbols: Option<PathBuf>,
+    #[structopt(
+        long = "artifact",
+        parse(from_os_str),
+        help = "write the code, source map, and opcodes as solc-style JSON to a file"
+    )]
+    artifact: Option<PathBuf>,
+    #[structopt(
+        long = "link-references",
+        help = "leave undeclared extern labels as placeholders, listed in the artifact"
+    )]
+    link_references: bool,
+    #[structopt(
+        long = "library-placeholders",
+        requires = "link-references",
+        help = "write extern labels as solc-style __$...$__ library placeholders"
+    )]
+    library_placeholders: bool,
+    #[structopt(
+        long = "hashes",
+        parse(from_os_str),
+        help = "write the signature and selector of every selector(...) to a JSON file"
+    )]
+    hashes: Option<PathBuf>,
+    #[structopt(
+        long = "patches",
+        parse(from_os_str),
+        help = "write the bytes of every push of a %patchable constant to a JSON file"
+    )]
+    patches: Option<PathBuf>,
+    #[structopt(
+        long = "runtime",
+        parse(from_os_str),
+        help = "write the runtime code after %runtime_start to a file, as hex"
+    )]
+    runtime: Option<PathBuf>,
+    #[structopt(
+        long = "trace",
+        parse(from_os_str),
+        help = "write the label positions and push sizes chosen while assembling to a JSON file"
+    )]
+    trace: Option<PathBuf>,
+    #[structopt(
+        long = "verify",
+        parse(from_os_str),
+        help = "fail if the output differs from the code in a file"
+    )]
+    verify: Option<PathBuf>,
+    #[structopt(
+        long = "profile",
+        help = "check the output against the limits of a chain (a built-in profile or a JSON file)"
+    )]
+    profile: Option<String>,
+    #[structopt(
+        long = "initcode",
+        help = "check the profile's initcode limits instead"
+    )]
+    initcode: bool,
+    #[structopt(
+        long = "policy",
+        parse(from_os_str),
+        help = "check the source and output against the rules in a TOML policy file"
+    )]
+    policy: Option<PathBuf>,
+    #[cfg(feature = "solc")]
+    #[structopt(
+        long = "solc",
+        parse(from_os_str),
+        help = "path to the solidity compiler used by %include_sol_runtime"
+    )]
+    solc: Option<PathBuf>,
 }
 
 fn create(path: PathBuf) -> File {
@@ -25,30 +205,257 @@ fn create(path: PathBuf) -> File {
     }
 }
 
+fn load_profile(name: &str) -> Profile {
+    match Profile::load(name) {
+        Err(why) => {
+            eprintln!("{}", WithSources(why));
+            std::process::exit(1);
+        }
+        Ok(profile) => profile,
+    }
+}
+
+fn load_policy(path: &Path) -> Policy {
+    match Policy::load(path) {
+        Err(why) => {
+            eprintln!("{}", WithSources(why));
+            std::process::exit(1);
+        }
+        Ok(policy) => policy,
+    }
+}
+
 fn main() {
-    let err = match run() {
+    let opt: Opt = clap::Parser::parse();
+    let explain = opt.explain;
+
+    let err = match run(opt) {
         Ok(_) => return,
         Err(e) => e,
     };
 
+    let explanation = err.explanation();
     eprintln!("{}", WithSources(err));
+
+    match explanation {
+        Some(text) if explain => eprintln!("{}", text),
+        Some(_) => eprintln!("note: run with `--explain` for more information about this error"),
+        None => (),
+    }
+
     std::process::exit(1);
 }
 
-fn run() -> Result<(), Error> {
-    let opt: Opt = clap::Parser::parse();
+fn run(opt: Opt) -> Result<(), Error> {
+    if opt.build_info {
+        println!("{}", serde_json::to_string_pretty(&build_info()).unwrap());
+        return Ok(());
+    }
+
+    let input = opt.input.expect("clap should require an input");
 
     let mut out: Box<dyn Write> = match opt.out {
         Some(o) => Box::new(create(o)),
         None => Box::new(std::io::stdout()),
     };
 
-    let hex_out = HexWrite::new(&mut out);
+    let mut raw = Vec::new();
+
+    let mut ingest = Ingest::new(&mut raw);
+    ingest.set_strict(opt.strict);
+    let denied: Vec<_> = opt
+        .deny
+        .iter()
+        .map(|name| Lint::from_name(name).expect("clap should reject other lints"))
+        .collect();
+    ingest.set_promoted(&denied);
+    for path in opt.include_paths {
+        ingest.add_include_path(path);
+    }
+    ingest.set_symbol_file(opt.symbols);
+    ingest.set_artifact_file(opt.artifact);
+    ingest.set_link_references(opt.link_references);
+    ingest.set_library_placeholders(opt.library_placeholders);
+    ingest.set_hashes_file(opt.hashes);
+    ingest.set_patches_file(opt.patches);
+    ingest.set_trace_file(opt.trace);
+    ingest.set_macro_details(opt.macro_details);
+    ingest.set_reproducible(opt.reproducible);
+    if let Some(profile) = opt.profile {
+        ingest.set_profile(Some(load_profile(&profile)));
+    }
+    if opt.initcode {
+        ingest.set_code_kind(CodeKind::Initcode);
+    }
+    if let Some(policy) = opt.policy {
+        ingest.set_policy(Some(load_policy(&policy)));
+    }
+    #[cfg(feature = "solc")]
+    if let Some(solc) = opt.solc {
+        ingest.set_solc(solc);
+    }
+    ingest.set_synthesis(opt.synthesize.as_deref().map(|m| match m {
+        "bytes" => CostModel::Bytes,
+        "gas" => CostModel::Gas,
+        _ => unreachable!("clap should reject other cost models"),
+    }));
+    match opt.dispatch.as_deref() {
+        Some("linear") => ingest.set_dispatch_strategy(Some(Strategy::Linear)),
+        Some("binary-search") => ingest.set_dispatch_strategy(Some(Strategy::BinarySearch)),
+        Some("mask") => ingest.set_dispatch_strategy(Some(Strategy::Mask)),
+        Some("perfect-hash") => ingest.set_dispatch_strategy(Some(Strategy::PerfectHash)),
+        Some("gas") => ingest.set_dispatch_model(CostModel::Gas),
+        Some("bytes") => ingest.set_dispatch_model(CostModel::Bytes),
+        None => (),
+        Some(_) => unreachable!("clap should reject other strategies"),
+    }
+    let level = match opt.opt_level.as_str() {
+        "0" => OptimizationLevel::None,
+        "1" => OptimizationLevel::Basic,
+        "2" => OptimizationLevel::Full,
+        _ => unreachable!("clap should reject other levels"),
+    };
+    let mut passes = level.passes().to_vec();
+    for pass in opt.optimize.iter() {
+        let pass = match pass.as_str() {
+            "dead-code" => Pass::DeadCode,
+            "peephole" => Pass::Peephole,
+            _ => unreachable!("clap should reject other passes"),
+        };
+
+        if !passes.contains(&pass) {
+            passes.push(pass);
+        }
+    }
+    ingest.set_optimizations(&passes);
+    let result = match opt.verify {
+        Some(expected) => ingest.verify_against(input, expected),
+        None => ingest.ingest_file(input).map(|_| None),
+    };
+
+    for warning in ingest.warnings() {
+        eprintln!("warning: {} [{}]", warning, warning.lint());
+    }
+
+    for substitution in ingest.substitutions() {
+        eprintln!("note: {}", substitution);
+    }
+
+    for optimization in ingest.optimizations() {
+        eprintln!("note: {}", optimization);
+    }
+
+    let saved: usize = ingest.optimizations().iter().map(|o| o.bytes_saved).sum();
+    if saved > 0 {
+        eprintln!("note: optimizations saved {} byte(s)", saved);
+    }
+
+    if let Err(ref err) = result {
+        let diagnostic = ingest.diagnostic(err);
+        if !diagnostic.labels.is_empty() {
+            eprintln!("{}", diagnostic.render(&ingest.sources()));
+        }
+    }
+
+    let mismatch = result?;
+
+    let debug_info = ingest.debug_info().clone();
+    let links = ingest.link_references().clone();
+    let runtime = ingest.runtime().map(<[u8]>::to_vec);
+    drop(ingest);
+
+    if let Some(path) = opt.debug_info {
+        serde_json::to_writer_pretty(create(path), &debug_info).unwrap();
+    }
+
+    if let Some(path) = opt.srcmap {
+        create(path)
+            .write_all(debug_info.srcmap(&raw).as_bytes())
+            .unwrap();
+    }
+
+    if opt.macro_stats {
+        macro_stats(&debug_info);
+    }
+
+    if opt.golf {
+        golf(&raw);
+    }
+
+    if let Some(path) = opt.cfi_report {
+        let report = analysis::check_control_flow(&disassemble(&raw));
+        write!(create(path), "{}", report).unwrap();
+    }
+
+    if let Some(mismatch) = mismatch {
+        eprintln!("error: {}", mismatch);
+        std::process::exit(1);
+    }
+
+    if let Some(path) = opt.runtime {
+        let runtime = match runtime {
+            Some(runtime) => runtime,
+            None => {
+                eprintln!("error: `--runtime` needs a `%runtime_start` in the input");
+                std::process::exit(1);
+            }
+        };
 
-    let mut ingest = Ingest::new(hex_out);
-    ingest.ingest_file(opt.input)?;
+        let mut file = create(path);
+        HexWrite::new(&mut file).write_all(&runtime).unwrap();
+        file.write_all(b"\n").unwrap();
+    }
 
-    out.write_all(b"\n").unwrap();
+    if opt.library_placeholders {
+        let hex = link::placeholder_hex(&raw, &links)
+            .expect("library placeholders should be 20 byte pushes");
+        writeln!(out, "{}", hex).unwrap();
+    } else {
+        HexWrite::new(&mut out).write_all(&raw).unwrap();
+        out.write_all(b"\n").unwrap();
+    }
 
     Ok(())
 }
+
+fn disassemble(code: &[u8]) -> Vec<Offset<Op<[u8]>>> {
+    let mut dasm = Disassembler::<Op<[u8]>>::default();
+    dasm.write_all(code).unwrap();
+    dasm.ops().collect()
+}
+
+fn golf(code: &[u8]) {
+    for suggestion in analysis::golf(&disassemble(code)) {
+        eprintln!("suggestion: {}", suggestion);
+    }
+}
+
+fn macro_stats(debug_info: &DebugInfo) {
+    let costs = debug_info.macro_costs();
+    let width = costs
+        .iter()
+        .map(|c| c.name.len())
+        .chain(Some("macro".len()))
+        .max()
+        .unwrap_or_default();
+
+    eprintln!(
+        "{:<width$}  {:>11}  {:>8}  {:>10}",
+        "macro",
+        "invocations",
+        "bytes",
+        "gas",
+        width = width
+    );
+
+    for cost in costs {
+        eprintln!(
+            "{:<width$}  {:>11}  {:>8}  {:>10}",
+            cost.name,
+            cost.invocations,
+            cost.bytes,
+            cost.gas,
+            width = width
+        );
+    }
+}
@@ -0,0 +1,398 @@
+//! An on-disk cache of assembled programs.
+//!
+//! Requires the `fs` feature.
+//!
+//! A [`BuildCache`] remembers, for each entry file, the content hash of every
+//! source read while assembling it. When none of those sources changed, and
+//! the entry is built with the same options, the cached code is reused instead
+//! of assembling the entry again.
+//!
+//! Only builds that succeed without warnings are cached, so reusing a cached
+//! build never hides a diagnostic. See [`Batch::set_cache_dir`].
+//!
+//! [`Batch::set_cache_dir`]: crate::batch::Batch::set_cache_dir
+mod error {
+    use snafu::{Backtrace, Snafu};
+
+    use std::path::PathBuf;
+
+    /// Errors that may arise while reading or writing the cache.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// An i/o error.
+        #[snafu(display("an i/o error occurred on path `{}` ({})", path.display(), message))]
+        #[non_exhaustive]
+        Io {
+            /// The underlying source of this error.
+            source: std::io::Error,
+
+            /// Extra information about the i/o error.
+            message: String,
+
+            /// The path where the error occurred.
+            path: PathBuf,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A cache entry couldn't be serialized.
+        #[snafu(display("cache entry couldn't be serialized"))]
+        #[non_exhaustive]
+        Manifest {
+            /// The underlying source of this error.
+            source: serde_json::Error,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+}
+
+pub use self::error::Error;
+
+use crate::synth::Substitution;
+
+use etk_ops::prague::{Op, Operation};
+
+use serde::{Deserialize, Serialize};
+
+use sha3::{Digest, Keccak256};
+
+use snafu::ResultExt;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The start of the name of every file written by the cache, so files of
+/// other tools in the same directory are left alone.
+const PREFIX: &str = "etk-build-";
+
+const EXTENSION: &str = "json";
+
+/// A build reused from the cache.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CachedBuild {
+    /// The assembled code.
+    pub code: Vec<u8>,
+
+    /// Pushes replaced while assembling the entry.
+    pub substitutions: Vec<Substitution>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Source {
+    path: PathBuf,
+    hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    entry: PathBuf,
+    options: String,
+    sources: Vec<Source>,
+    code: String,
+    substitutions: Vec<(String, Vec<String>)>,
+}
+
+/// A directory of cached builds, keyed by entry file and options.
+#[derive(Debug, Clone)]
+pub struct BuildCache {
+    dir: PathBuf,
+}
+
+impl BuildCache {
+    /// Use `dir` to store cached builds. The directory is created when the
+    /// first build is stored.
+    pub fn new<P>(dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self { dir: dir.into() }
+    }
+
+    /// The directory containing the cached builds.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The number of builds in the cache, including stale ones.
+    pub fn len(&self) -> Result<usize, Error> {
+        Ok(self.manifests()?.len())
+    }
+
+    /// Returns `true` if the cache contains no builds.
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Remove every build from the cache.
+    ///
+    /// Only files written by the cache are removed, so the directory may be
+    /// shared with other tools.
+    pub fn clear(&self) -> Result<(), Error> {
+        for path in self.manifests()? {
+            fs::remove_file(&path).context(error::Io {
+                message: "removing cache entry",
+                path,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the build of `entry` with `options`, if none of its sources changed
+    /// since it was stored.
+    pub(crate) fn lookup(&self, entry: &Path, options: &str) -> Option<CachedBuild> {
+        let entry = canonicalize(entry);
+        let text = fs::read(self.path(&entry, options)).ok()?;
+        let manifest: Manifest = serde_json::from_slice(&text).ok()?;
+
+        if manifest.entry != entry || manifest.options != options {
+            return None;
+        }
+
+        for source in manifest.sources.iter() {
+            let content = fs::read(&source.path).ok()?;
+            if digest(&content) != source.hash {
+                return None;
+            }
+        }
+
+        let mut substitutions = Vec::with_capacity(manifest.substitutions.len());
+        for (original, replacement) in manifest.substitutions.iter() {
+            substitutions.push(Substitution {
+                original: decode_op(original)?,
+                replacement: replacement
+                    .iter()
+                    .map(|r| decode_op(r))
+                    .collect::<Option<_>>()?,
+            });
+        }
+
+        Some(CachedBuild {
+            code: hex::decode(&manifest.code).ok()?,
+            substitutions,
+        })
+    }
+
+    /// Remember `build` as the result of assembling `entry` with `options`,
+    /// after reading `sources`.
+    pub(crate) fn store(
+        &self,
+        entry: &Path,
+        options: &str,
+        sources: &[PathBuf],
+        build: &CachedBuild,
+    ) -> Result<(), Error> {
+        let entry = canonicalize(entry);
+
+        let mut hashed = Vec::with_capacity(sources.len());
+        for path in sources {
+            let content = fs::read(path).with_context(|_| error::Io {
+                message: "hashing source",
+                path: path.clone(),
+            })?;
+
+            hashed.push(Source {
+                path: canonicalize(path),
+                hash: digest(&content),
+            });
+        }
+
+        let manifest = Manifest {
+            entry: entry.clone(),
+            options: options.to_owned(),
+            sources: hashed,
+            code: hex::encode(&build.code),
+            substitutions: build
+                .substitutions
+                .iter()
+                .map(|s| {
+                    (
+                        encode_op(&s.original),
+                        s.replacement.iter().map(encode_op).collect(),
+                    )
+                })
+                .collect(),
+        };
+
+        let text = serde_json::to_vec(&manifest).context(error::Manifest)?;
+
+        fs::create_dir_all(&self.dir).context(error::Io {
+            message: "creating cache directory",
+            path: self.dir.clone(),
+        })?;
+
+        // Write to a temporary file first, so concurrent builds never observe
+        // a partially written entry.
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = self.path(&entry, options);
+        let temporary = path.with_extension(format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        fs::write(&temporary, text).context(error::Io {
+            message: "writing cache entry",
+            path: temporary.clone(),
+        })?;
+
+        fs::rename(&temporary, &path).context(error::Io {
+            message: "renaming cache entry",
+            path,
+        })?;
+
+        Ok(())
+    }
+
+    fn path(&self, entry: &Path, options: &str) -> PathBuf {
+        let key = format!(
+            "{}\0{}\0{}",
+            env!("CARGO_PKG_VERSION"),
+            entry.display(),
+            options
+        );
+
+        self.dir
+            .join(format!("{}{}", PREFIX, digest(key.as_bytes())))
+            .with_extension(EXTENSION)
+    }
+
+    fn manifests(&self) -> Result<Vec<PathBuf>, Error> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(e) => e,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).context(error::Io {
+                    message: "listing cache directory",
+                    path: self.dir.clone(),
+                })
+            }
+        };
+
+        let mut manifests = Vec::new();
+        for entry in entries {
+            let path = entry
+                .context(error::Io {
+                    message: "listing cache directory",
+                    path: self.dir.clone(),
+                })?
+                .path();
+
+            let is_manifest = path.extension().map_or(false, |e| e == EXTENSION)
+                && path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .map_or(false, |n| n.starts_with(PREFIX));
+
+            if is_manifest {
+                manifests.push(path);
+            }
+        }
+
+        Ok(manifests)
+    }
+}
+
+fn canonicalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+pub(crate) fn digest(bytes: &[u8]) -> String {
+    let mut hasher = Keccak256::new();
+    hasher.update(bytes);
+    hex::encode(hasher.finalize())
+}
+
+fn encode_op(op: &Op<[u8]>) -> String {
+    let mut bytes = vec![op.code_byte()];
+    bytes.extend_from_slice(op.immediate().unwrap_or_default());
+    hex::encode(bytes)
+}
+
+fn decode_op(text: &str) -> Option<Op<[u8]>> {
+    Op::from_slice(&hex::decode(text).ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use etk_ops::prague::{Not, Push0, Push32};
+
+    use super::*;
+
+    fn build() -> CachedBuild {
+        CachedBuild {
+            code: vec![0x5f, 0x19],
+            substitutions: vec![Substitution {
+                original: Op::from(Push32([0xff; 32])),
+                replacement: vec![Op::from(Push0), Op::from(Not)],
+            }],
+        }
+    }
+
+    #[test]
+    fn store_then_lookup() -> Result<(), Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = dir.path().join("main.etk");
+        let lib = dir.path().join("lib.etk");
+        fs::write(&entry, "%import(\"lib.etk\")").unwrap();
+        fs::write(&lib, "push32 0xff").unwrap();
+
+        let cache = BuildCache::new(dir.path().join("cache"));
+        assert!(cache.is_empty()?);
+        assert_eq!(cache.lookup(&entry, ""), None);
+
+        cache.store(&entry, "", &[entry.clone(), lib.clone()], &build())?;
+        assert_eq!(cache.len()?, 1);
+        assert_eq!(cache.lookup(&entry, ""), Some(build()));
+
+        // Different options are a different build.
+        assert_eq!(cache.lookup(&entry, "strict"), None);
+
+        // Changing any source invalidates the build.
+        fs::write(&lib, "push32 0xfe").unwrap();
+        assert_eq!(cache.lookup(&entry, ""), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn clear() -> Result<(), Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = dir.path().join("main.etk");
+        fs::write(&entry, "push0").unwrap();
+
+        let other = dir.path().join("cache").join("notes.txt");
+        let json = dir.path().join("cache").join("package.json");
+        let cache = BuildCache::new(dir.path().join("cache"));
+        cache.store(&entry, "", &[entry.clone()], &build())?;
+        fs::write(&other, "keep me").unwrap();
+        fs::write(&json, "{}").unwrap();
+        assert_eq!(cache.len()?, 1);
+
+        cache.clear()?;
+        assert!(cache.is_empty()?);
+        assert_eq!(cache.lookup(&entry, ""), None);
+        assert!(other.exists());
+        assert!(json.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn clear_missing_directory() -> Result<(), Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = BuildCache::new(dir.path().join("missing"));
+
+        cache.clear()?;
+        assert_eq!(cache.len()?, 0);
+
+        Ok(())
+    }
+}
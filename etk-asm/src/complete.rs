@@ -0,0 +1,440 @@
+//! Completion candidates for editors and language servers.
+//!
+//! [`complete`] looks at the source around the cursor to decide what could be
+//! typed there, so editor plugins don't need to know the assembler's rules:
+//!
+//!  - At the start of a statement: instructions available on the target
+//!    fork.
+//!  - After `%`: instruction macros declared in the source, and built-in
+//!    macros.
+//!  - Anywhere else, like the operand of a push: labels visible from the
+//!    cursor (including the labels of enclosing `%scope`s without their
+//!    prefix), and expression macros.
+//!
+//! Only the source being edited is considered, so labels and macros from
+//! `%import`ed and `%include`d files aren't candidates.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_asm::complete::{complete, Kind};
+//!
+//! let src = "start:\njumpdest\npush1 st\n";
+//! let completions = complete(src, src.len() - 1, None);
+//!
+//! assert_eq!(&src[completions.range.clone()], "st");
+//! assert_eq!(completions.candidates[0].text, "start");
+//! assert_eq!(completions.candidates[0].kind, Kind::Label);
+//! ```
+use crate::ast::Node;
+use crate::info::TARGET_FORK;
+use crate::ops::{AbstractOp, ExpressionMacroDefinition, MacroDefinition, Terminal};
+use crate::parse::parse_recovering;
+use crate::profile::Profile;
+
+use etk_ops::prague::{Op, Operation};
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+/// Built-in macros, with their signatures.
+const BUILTINS: &[(&str, &str)] = &[
+    ("%import", "%import(\"path\")"),
+    ("%include", "%include(\"path\", \"namespace\")"),
+    ("%include_hex", "%include_hex(\"path\")"),
+    ("%include_bin", "%include_bin(\"path\")"),
+    (
+        "%include_sol_runtime",
+        "%include_sol_runtime(\"path\", \"contract\")",
+    ),
+    (
+        "%include_artifact",
+        "%include_artifact(\"path\", field=\"field\")",
+    ),
+    ("%bytes", "%bytes(expressions...)"),
+    ("%string", "%string(\"text\")"),
+    ("%string_padded", "%string_padded(\"text\")"),
+    ("%word", "%word(expressions...)"),
+    ("%dw", "%dw(expressions...)"),
+    ("%abi_encode", "%abi_encode(\"signature\", arguments...)"),
+    ("%jumptable", "%jumptable(labels...)"),
+    ("%dispatch", "%dispatch { selector => label, ... }"),
+    ("%extern", "%extern label"),
+    ("%push", "%push(expression)"),
+    ("%macro", "%macro name(parameters) ... %end"),
+    ("%def", "%def name(parameters) ... %end"),
+    ("%scope", "%scope name { ... }"),
+    ("%section", "%section name { ... }"),
+    ("%capture", "%capture name { ... }"),
+    ("%if", "%if condition { ... } %else { ... }"),
+    ("%ifdef", "%ifdef label { ... } %else { ... }"),
+    ("%pragma", "%pragma name \"value\""),
+];
+
+/// What a [`Candidate`] is.
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Kind {
+    /// An instruction, like `push1`.
+    Instruction,
+
+    /// A label, like `start` or `runtime.end`.
+    Label,
+
+    /// An instruction macro declared with `%macro`.
+    InstructionMacro,
+
+    /// An expression macro declared with `%def`.
+    ExpressionMacro,
+
+    /// A macro or directive built into the assembler, like `%include`.
+    Builtin,
+}
+
+/// Something that could be typed at the cursor.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Candidate {
+    /// The text replacing [`Completions::range`].
+    pub text: String,
+
+    /// What the candidate is.
+    pub kind: Kind,
+
+    /// A short description, like the signature of a macro.
+    pub detail: String,
+}
+
+/// The candidates returned by [`complete`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Completions {
+    /// The bytes of the source holding the partial word before the cursor,
+    /// which a candidate replaces.
+    pub range: Range<usize>,
+
+    /// Every candidate starting with the partial word, in order of relevance.
+    pub candidates: Vec<Candidate>,
+}
+
+/// Candidates for the word being typed in `src` at the byte offset `cursor`.
+///
+/// Instructions are those available in `profile`, or on the latest fork if
+/// there's no profile.
+pub fn complete(src: &str, cursor: usize, profile: Option<&Profile>) -> Completions {
+    let cursor = cursor.min(src.len());
+    if !src.is_char_boundary(cursor) {
+        return Completions::default();
+    }
+
+    let line_start = src[..cursor].rfind('\n').map_or(0, |i| i + 1);
+    let before = &src[line_start..cursor];
+
+    // Nothing to complete inside comments or strings.
+    if before.contains('#') || before.matches('"').count() % 2 == 1 {
+        return Completions {
+            range: cursor..cursor,
+            candidates: Vec::new(),
+        };
+    }
+
+    let word_len = before
+        .chars()
+        .rev()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.')
+        .map(char::len_utf8)
+        .sum::<usize>();
+
+    let mut start = cursor - word_len;
+    let is_macro = src[..start].ends_with('%');
+    if is_macro {
+        start -= 1;
+    }
+
+    let word = &src[start..cursor];
+    let statement = src[line_start..start]
+        .rsplit(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .is_empty();
+
+    let mut candidates = if is_macro {
+        Declarations::find(src, line_start, cursor).instruction_macros(word)
+    } else if statement {
+        instructions(word, profile)
+    } else {
+        Declarations::find(src, line_start, cursor).operands(word)
+    };
+
+    let mut seen = HashSet::new();
+    candidates.retain(|c| seen.insert((c.kind, c.text.clone())));
+
+    Completions {
+        range: start..cursor,
+        candidates,
+    }
+}
+
+fn instructions(prefix: &str, profile: Option<&Profile>) -> Vec<Candidate> {
+    let latest;
+    let profile = match profile {
+        Some(profile) => profile,
+        None => {
+            latest = Profile::builtin(TARGET_FORK).expect("target fork should have a profile");
+            &latest
+        }
+    };
+
+    (0..=u8::MAX)
+        .map(Op::<()>::from)
+        .filter(|op| !op.mnemonic().starts_with("invalid_"))
+        .filter(|op| op.mnemonic().starts_with(prefix) && profile.has(op.mnemonic()))
+        .map(|op| Candidate {
+            text: op.mnemonic().to_owned(),
+            kind: Kind::Instruction,
+            detail: format!(
+                "0x{:02x} (pops {}, pushes {})",
+                op.code_byte(),
+                op.pops(),
+                op.pushes()
+            ),
+        })
+        .collect()
+}
+
+/// Labels and macros declared in the source, and the scopes around the
+/// cursor.
+#[derive(Debug, Default)]
+struct Declarations {
+    labels: Vec<String>,
+    macros: Vec<MacroDefinition>,
+    scope: Vec<String>,
+}
+
+impl Declarations {
+    fn find(src: &str, line_start: usize, cursor: usize) -> Self {
+        // The line being edited is usually incomplete, so leave it out.
+        let line_end = src[cursor..].find('\n').map_or(src.len(), |i| cursor + i);
+        let mut text = src.to_owned();
+        text.replace_range(line_start..line_end, &" ".repeat(line_end - line_start));
+
+        let (nodes, _) = parse_recovering(&text);
+
+        let mut declarations = Self::default();
+        declarations.visit(&nodes, &[], cursor);
+        declarations
+    }
+
+    fn visit(&mut self, nodes: &[(Node, Range<usize>)], scope: &[String], cursor: usize) {
+        let qualify = |name: &str| {
+            let mut parts = scope.to_vec();
+            parts.push(name.to_owned());
+            parts.join(".")
+        };
+
+        for (node, span) in nodes {
+            match node {
+                Node::Op(AbstractOp::Label(label)) => self.labels.push(qualify(label)),
+                Node::Op(AbstractOp::MacroDefinition(defn)) => self.macros.push(defn.clone()),
+                Node::Extern(label) => self.labels.push(label.clone()),
+                Node::Scope(name, body) => {
+                    let mut inner = scope.to_vec();
+                    inner.push(name.clone());
+
+                    if span.contains(&cursor) {
+                        self.scope = inner.clone();
+                    }
+
+                    for special in ["start", "end", "size"].iter() {
+                        self.labels.push(qualify(&format!("{}.{}", name, special)));
+                    }
+
+                    self.visit(body, &inner, cursor);
+                }
+                Node::Capture(name, _) => self.macros.push(
+                    ExpressionMacroDefinition {
+                        name: name.clone(),
+                        parameters: Vec::new(),
+                        content: Terminal::Bytes(Vec::new()).into(),
+                    }
+                    .into(),
+                ),
+                Node::If(_, then, otherwise) => {
+                    self.visit(then, scope, cursor);
+                    self.visit(otherwise, scope, cursor);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Labels and expression macros, for the operand of an instruction.
+    fn operands(&self, prefix: &str) -> Vec<Candidate> {
+        let mut names = Vec::new();
+
+        // Labels of the enclosing scopes, innermost first, without their
+        // prefix.
+        for depth in (1..=self.scope.len()).rev() {
+            let prefix = format!("{}.", self.scope[..depth].join("."));
+            for label in self.labels.iter() {
+                if let Some(name) = label.strip_prefix(&prefix) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+
+        names.extend(self.labels.iter().cloned());
+
+        let labels = names
+            .into_iter()
+            .filter(|name| name.starts_with(prefix))
+            .map(|text| Candidate {
+                text,
+                kind: Kind::Label,
+                detail: "label".to_owned(),
+            });
+
+        let macros = self
+            .macros
+            .iter()
+            .filter(|m| matches!(m, MacroDefinition::Expression(_)))
+            .filter(|m| m.name().starts_with(prefix))
+            .map(|m| Candidate {
+                text: m.name().clone(),
+                kind: Kind::ExpressionMacro,
+                detail: m.to_string(),
+            });
+
+        labels.chain(macros).collect()
+    }
+
+    /// Instruction macros and built-ins, for a word starting with `%`.
+    fn instruction_macros(&self, prefix: &str) -> Vec<Candidate> {
+        let declared = self
+            .macros
+            .iter()
+            .filter(|m| matches!(m, MacroDefinition::Instruction(_)))
+            .map(|m| Candidate {
+                text: format!("%{}", m.name()),
+                kind: Kind::InstructionMacro,
+                detail: m.to_string(),
+            });
+
+        let builtins = BUILTINS.iter().map(|(text, detail)| Candidate {
+            text: (*text).to_owned(),
+            kind: Kind::Builtin,
+            detail: (*detail).to_owned(),
+        });
+
+        declared
+            .chain(builtins)
+            .filter(|c| c.text.starts_with(prefix))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(src: &str, marker: &str) -> Vec<String> {
+        let cursor = src.find(marker).unwrap();
+        let src = src.replacen(marker, "", 1);
+        complete(&src, cursor, None)
+            .candidates
+            .into_iter()
+            .map(|c| c.text)
+            .collect()
+    }
+
+    #[test]
+    fn complete_instructions() {
+        let found = texts("push1 1\ncalld|\n", "|");
+        assert_eq!(found, ["calldataload", "calldatasize", "calldatacopy"]);
+
+        let london = Profile::builtin("london").unwrap();
+        let src = "push";
+        let found: Vec<_> = complete(src, src.len(), Some(&london))
+            .candidates
+            .into_iter()
+            .map(|c| c.text)
+            .collect();
+        assert!(!found.contains(&"push0".to_owned()));
+        assert!(found.contains(&"push1".to_owned()));
+
+        assert_eq!(texts("push1 1; sel|", "|"), ["selfbalance", "selfdestruct"]);
+    }
+
+    #[test]
+    fn complete_labels_in_scopes() {
+        let src = r#"
+top:
+jumpdest
+%scope runtime {
+    loop:
+    jumpdest
+    push1 |
+}
+push1 0
+"#;
+        let found = texts(src, "|");
+        assert_eq!(
+            found,
+            [
+                "start",
+                "end",
+                "size",
+                "loop",
+                "top",
+                "runtime.start",
+                "runtime.end",
+                "runtime.size",
+                "runtime.loop",
+            ]
+        );
+
+        let src = "top:\n%scope runtime {\nloop:\n}\npush1 r|\n";
+        assert_eq!(
+            texts(src, "|"),
+            [
+                "runtime.start",
+                "runtime.end",
+                "runtime.size",
+                "runtime.loop"
+            ]
+        );
+    }
+
+    #[test]
+    fn complete_macros() {
+        let src = r#"
+%macro store(offset, value)
+    push1 $value
+    push1 $offset
+    mstore
+%end
+%def double(x)
+    x * 2
+%end
+%st|
+"#;
+        let completions = {
+            let cursor = src.find('|').unwrap();
+            complete(&src.replacen('|', "", 1), cursor, None)
+        };
+        assert_eq!(completions.candidates[0].text, "%store");
+        assert_eq!(completions.candidates[0].kind, Kind::InstructionMacro);
+        assert_eq!(completions.candidates[0].detail, "%store(offset, value)");
+        assert_eq!(completions.range.len(), 3);
+
+        let found = texts(&src.replace("%st|", "push1 do|"), "|");
+        assert_eq!(found, ["double"]);
+
+        assert_eq!(texts("%incl|", "|")[0], "%include");
+    }
+
+    #[test]
+    fn complete_nothing_in_comments() {
+        assert!(texts("push1 1 # cal|", "|").is_empty());
+        assert!(texts("%include(\"ca|", "|").is_empty());
+    }
+}
@@ -0,0 +1,510 @@
+//! Reassembling programs as their sources change.
+//!
+//! Requires the `fs` feature.
+//!
+//! An [`IngestDb`] is meant to live as long as an editor session or a watch
+//! process. It remembers the build of every root file, along with the content
+//! hash of each source read while assembling it. Asking for a build again only
+//! reassembles the root if one of those sources changed, so editing a file
+//! imported by one root leaves the builds of every other root untouched.
+//!
+//! Roots that do need reassembling reuse the parse of every source that didn't
+//! change, including the ones shared with other roots.
+//!
+//! [`IngestDb::watch`] keeps a root built, reporting each new build as its
+//! sources change, for editor integrations and continuous builds.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_asm::db::IngestDb;
+//! use etk_asm::vfs::MemoryFiles;
+//! # use etk_asm::ingest::Error;
+//!
+//! // Stands in for the files on disk.
+//! let mut saved = MemoryFiles::new();
+//! saved.insert("/src/main.etk", "%import(\"util.etk\")\n");
+//! saved.insert("/src/util.etk", "caller\n");
+//!
+//! let mut db = IngestDb::with_base(saved);
+//! assert_eq!(db.build("/src/main.etk")?.code, [0x33]);
+//!
+//! // Typing in an editor, without saving.
+//! db.set_buffer("/src/util.etk", "pc\n");
+//! assert!(!db.is_fresh("/src/main.etk"));
+//! assert_eq!(db.build("/src/main.etk")?.code, [0x58]);
+//! # Result::<(), Error>::Ok(())
+//! ```
+use crate::cache::digest;
+use crate::ingest::{Error, Ingest, ParseCache};
+use crate::vfs::{DiskFiles, FileProvider, OverlayFiles};
+use crate::warning::Warning;
+
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often [`IngestDb::watch`] checks sources for changes, by default.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The result of successfully assembling a root file.
+#[derive(Debug, Clone)]
+pub struct Build {
+    /// The assembled code.
+    pub code: Vec<u8>,
+
+    /// Warnings found while assembling the root.
+    pub warnings: Vec<Warning>,
+
+    /// Every source read while assembling the root, including the root
+    /// itself. See [`Ingest::dependencies`].
+    pub dependencies: Vec<PathBuf>,
+
+    /// The content hash of each dependency, or `None` if it couldn't be read
+    /// through the file provider (like a Solidity source compiled by `solc`.)
+    hashes: Vec<Option<String>>,
+}
+
+/// Builds of root files, kept up to date with their sources.
+///
+/// Sources are read through an [`OverlayFiles`], so unsaved buffers are
+/// assembled instead of the files they replace. See the [module
+/// documentation](self) for an example.
+#[derive(Debug)]
+pub struct IngestDb<F = DiskFiles> {
+    files: Arc<OverlayFiles<F>>,
+    parses: Arc<ParseCache>,
+    include_paths: Vec<PathBuf>,
+    strict: bool,
+    reproducible: bool,
+    builds: HashMap<PathBuf, Build>,
+    failures: HashMap<PathBuf, Vec<(PathBuf, Option<String>)>>,
+    poll_interval: Duration,
+}
+
+impl IngestDb {
+    /// Create an empty database reading from the real filesystem.
+    pub fn new() -> Self {
+        Self::with_base(DiskFiles)
+    }
+}
+
+impl Default for IngestDb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F> IngestDb<F>
+where
+    F: 'static + FileProvider + Clone,
+{
+    /// Create an empty database reading from the files of `base`.
+    pub fn with_base(base: F) -> Self {
+        Self {
+            files: Arc::new(OverlayFiles::with_base(base)),
+            parses: Arc::default(),
+            include_paths: Vec::new(),
+            strict: false,
+            reproducible: false,
+            builds: HashMap::new(),
+            failures: HashMap::new(),
+            poll_interval: POLL_INTERVAL,
+        }
+    }
+
+    /// The files that sources are read from, including unsaved buffers.
+    pub fn files(&self) -> &OverlayFiles<F> {
+        &self.files
+    }
+
+    /// Read `contents` instead of the file at `path`, until the buffer is
+    /// removed.
+    ///
+    /// See [`OverlayFiles::insert`].
+    pub fn set_buffer<P, C>(&mut self, path: P, contents: C)
+    where
+        P: AsRef<Path>,
+        C: Into<Vec<u8>>,
+    {
+        Arc::make_mut(&mut self.files).insert(path, contents);
+    }
+
+    /// Read the file at `path` from the underlying provider again, returning
+    /// the contents of the removed buffer.
+    ///
+    /// See [`OverlayFiles::remove`].
+    pub fn remove_buffer<P>(&mut self, path: P) -> Option<Vec<u8>>
+    where
+        P: AsRef<Path>,
+    {
+        Arc::make_mut(&mut self.files).remove(path)
+    }
+
+    /// Promote warnings to errors. Forgets every build.
+    ///
+    /// See [`Ingest::set_strict`].
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+        self.clear();
+    }
+
+    /// Number macro-local labels instead of randomizing them, and refuse
+    /// features that aren't reproducible. Forgets every build.
+    ///
+    /// See [`Ingest::set_reproducible`].
+    pub fn set_reproducible(&mut self, enabled: bool) {
+        self.reproducible = enabled;
+        self.clear();
+    }
+
+    /// Search the directory at `path` for included files. Forgets every
+    /// build.
+    ///
+    /// See [`Ingest::add_include_path`].
+    pub fn add_include_path<P>(&mut self, path: P)
+    where
+        P: Into<PathBuf>,
+    {
+        self.include_paths.push(path.into());
+        self.clear();
+    }
+
+    /// Returns `true` if `root` has a build, and none of its sources changed
+    /// since.
+    pub fn is_fresh<P>(&self, root: P) -> bool
+    where
+        P: AsRef<Path>,
+    {
+        match self.builds.get(root.as_ref()) {
+            Some(build) => self.fresh(build),
+            None => false,
+        }
+    }
+
+    /// Get the build of the file at `root`, assembling it again only if one
+    /// of its sources changed since the last build.
+    ///
+    /// Failed builds aren't remembered, so the next call tries again.
+    pub fn build<P>(&mut self, root: P) -> Result<&Build, Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let root = root.into();
+
+        if !self.is_fresh(&root) {
+            self.builds.remove(&root);
+            let result = self.assemble(&root);
+
+            // Prune after remembering the build, so its parses are kept.
+            match result {
+                Ok(build) => {
+                    self.builds.insert(root.clone(), build);
+                    self.prune_parses();
+                }
+                Err(err) => {
+                    self.prune_parses();
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(&self.builds[&root])
+    }
+
+    /// Build the file at `root`, then build it again every time one of its
+    /// sources changes, until `callback` breaks.
+    ///
+    /// Sources are checked for changes every poll interval (see
+    /// [`IngestDb::set_poll_interval`].) Every build, or the error that
+    /// prevented it, is passed to `callback`. A failed build isn't retried
+    /// until one of the sources read before it failed changes.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use etk_asm::db::IngestDb;
+    /// use etk_asm::vfs::MemoryFiles;
+    ///
+    /// use std::ops::ControlFlow;
+    ///
+    /// let mut files = MemoryFiles::new();
+    /// files.insert("/src/main.etk", "caller\n");
+    ///
+    /// let mut db = IngestDb::with_base(files);
+    /// db.watch("/src/main.etk", |result| {
+    ///     match result {
+    ///         Ok(build) => println!("{}", hex::encode(&build.code)),
+    ///         Err(err) => eprintln!("{}", err),
+    ///     }
+    ///
+    ///     // Keep watching with `ControlFlow::Continue(())`.
+    ///     ControlFlow::Break(())
+    /// });
+    /// ```
+    pub fn watch<P, C>(&mut self, root: P, mut callback: C)
+    where
+        P: Into<PathBuf>,
+        C: FnMut(Result<&Build, &Error>) -> ControlFlow<()>,
+    {
+        let root = root.into();
+
+        loop {
+            let failed = self
+                .failures
+                .get(&root)
+                .map_or(false, |sources| self.unchanged(sources));
+
+            if !failed && !self.is_fresh(&root) {
+                let flow = match self.build(root.clone()) {
+                    Ok(build) => callback(Ok(build)),
+                    Err(err) => callback(Err(&err)),
+                };
+
+                if let ControlFlow::Break(()) = flow {
+                    return;
+                }
+            }
+
+            thread::sleep(self.poll_interval);
+        }
+    }
+
+    /// How often [`IngestDb::watch`] checks sources for changes. Defaults to
+    /// [`POLL_INTERVAL`].
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.poll_interval = interval;
+    }
+
+    /// Forget the build of `root`, like when it's closed in an editor.
+    pub fn forget<P>(&mut self, root: P)
+    where
+        P: AsRef<Path>,
+    {
+        self.builds.remove(root.as_ref());
+        self.failures.remove(root.as_ref());
+        self.prune_parses();
+    }
+
+    fn assemble(&mut self, root: &Path) -> Result<Build, Error> {
+        self.failures.remove(root);
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_file_provider(self.files.clone());
+        ingest.set_cache(self.parses.clone());
+        ingest.set_strict(self.strict);
+        ingest.set_reproducible(self.reproducible);
+        for include_path in self.include_paths.iter() {
+            ingest.add_include_path(include_path.clone());
+        }
+
+        let result = ingest.ingest_file(root);
+
+        let warnings = ingest.warnings().to_vec();
+        let mut dependencies = ingest.dependencies().to_vec();
+        drop(ingest);
+
+        // The root isn't a dependency until it's read, so remember it for
+        // failures like the root not existing yet.
+        if dependencies.is_empty() {
+            dependencies.push(root.to_owned());
+        }
+
+        let hashes: Vec<_> = dependencies.iter().map(|p| self.hash(p)).collect();
+
+        if let Err(err) = result {
+            let sources = dependencies.into_iter().zip(hashes).collect();
+            self.failures.insert(root.to_owned(), sources);
+            return Err(err);
+        }
+
+        Ok(Build {
+            code: output,
+            warnings,
+            dependencies,
+            hashes,
+        })
+    }
+
+    /// Forget every build and failure, after an option changed.
+    fn clear(&mut self) {
+        self.builds.clear();
+        self.failures.clear();
+    }
+
+    fn hash(&self, path: &Path) -> Option<String> {
+        self.files.read(path).ok().map(|c| digest(&c))
+    }
+
+    fn fresh(&self, build: &Build) -> bool {
+        build
+            .dependencies
+            .iter()
+            .zip(build.hashes.iter())
+            .all(|(path, hash)| self.hash(path) == *hash)
+    }
+
+    fn unchanged(&self, sources: &[(PathBuf, Option<String>)]) -> bool {
+        sources.iter().all(|(path, hash)| self.hash(path) == *hash)
+    }
+
+    /// Forget the parse of every source that isn't a dependency of a
+    /// remembered build or failure, so edits don't accumulate in a long-lived database.
+    fn prune_parses(&self) {
+        let built = self.builds.values().flat_map(|b| b.hashes.iter());
+        let failed = self.failures.values().flatten().map(|(_, hash)| hash);
+
+        let live: HashSet<&str> = built.chain(failed).flatten().map(String::as_str).collect();
+
+        self.parses
+            .retain(|src| live.contains(digest(src.as_bytes()).as_str()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use crate::vfs::MemoryFiles;
+
+    use super::*;
+
+    use std::fs;
+
+    fn files() -> MemoryFiles {
+        let mut files = MemoryFiles::new();
+        files.insert(
+            "/src/lib.etk",
+            "%macro twice(x)\n push1 $x\n push1 $x\n%end\n",
+        );
+        files.insert("/src/util.etk", "caller\n");
+        files.insert("/src/a.etk", "%import(\"lib.etk\")\n%twice(1)\n");
+        files.insert(
+            "/src/b.etk",
+            "%import(\"lib.etk\")\n%include(\"util.etk\")\n",
+        );
+        files
+    }
+
+    #[test]
+    fn build_only_affected_roots() -> Result<(), Error> {
+        let mut db = IngestDb::with_base(files());
+        assert!(!db.is_fresh("/src/a.etk"));
+
+        assert_eq!(db.build("/src/a.etk")?.code, [0x60, 0x01, 0x60, 0x01]);
+        assert_eq!(db.build("/src/b.etk")?.code, [0x33]);
+        assert!(db.is_fresh("/src/a.etk"));
+        assert!(db.is_fresh("/src/b.etk"));
+
+        // `a.etk` doesn't read `util.etk`.
+        db.set_buffer("/src/util.etk", "pc\n");
+        assert!(db.is_fresh("/src/a.etk"));
+        assert!(!db.is_fresh("/src/b.etk"));
+        assert_eq!(db.build("/src/b.etk")?.code, [0x58]);
+
+        // Both roots import `lib.etk`.
+        db.set_buffer("/src/lib.etk", "%macro twice(x)\n push1 $x\n dup1\n%end\n");
+        assert!(!db.is_fresh("/src/a.etk"));
+        assert!(!db.is_fresh("/src/b.etk"));
+        assert_eq!(db.build("/src/a.etk")?.code, [0x60, 0x01, 0x80]);
+
+        // Removing the buffer restores the original build.
+        db.remove_buffer("/src/lib.etk");
+        assert_eq!(db.build("/src/a.etk")?.code, [0x60, 0x01, 0x60, 0x01]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn build_forgets_failures() -> Result<(), Error> {
+        let mut db = IngestDb::with_base(files());
+        db.build("/src/a.etk")?;
+
+        db.set_buffer("/src/a.etk", "push1 missing\n");
+        assert_matches!(db.build("/src/a.etk"), Err(Error::Assemble { .. }));
+        assert!(!db.is_fresh("/src/a.etk"));
+
+        db.set_buffer("/src/a.etk", "push1 2\n");
+        assert_eq!(db.build("/src/a.etk")?.code, [0x60, 0x02]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn build_prunes_parses() -> Result<(), Error> {
+        let mut db = IngestDb::with_base(files());
+        db.build("/src/a.etk")?;
+        assert_eq!(db.parses.len(), 2);
+
+        for n in 0..4 {
+            db.set_buffer(
+                "/src/a.etk",
+                format!("%import(\"lib.etk\")\n%twice({})\n", n),
+            );
+            db.build("/src/a.etk")?;
+        }
+        assert_eq!(db.parses.len(), 2);
+
+        db.forget("/src/a.etk");
+        assert_eq!(db.parses.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn watch_reports_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("main.etk");
+        fs::write(&root, "caller\n").unwrap();
+
+        let mut db = IngestDb::new();
+        db.set_poll_interval(Duration::from_millis(1));
+
+        let mut reports = Vec::new();
+        db.watch(&root, |result| {
+            reports.push(
+                result
+                    .map(|b| b.code.clone())
+                    .map_err(|e| matches!(e, Error::Assemble { .. })),
+            );
+
+            match reports.len() {
+                1 => fs::write(&root, "push1 missing\n").unwrap(),
+                2 => {
+                    // Replace the file while polling continues, so a failed
+                    // build being retried would show up as another report.
+                    let root = root.clone();
+                    thread::spawn(move || {
+                        thread::sleep(Duration::from_millis(50));
+                        let temporary = root.with_extension("tmp");
+                        fs::write(&temporary, "pc\n").unwrap();
+                        fs::rename(&temporary, &root).unwrap();
+                    });
+                }
+                _ => return ControlFlow::Break(()),
+            }
+
+            ControlFlow::Continue(())
+        });
+
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0], Ok(vec![0x33]));
+        assert_eq!(reports[1], Err(true));
+        assert_eq!(reports[2], Ok(vec![0x58]));
+    }
+
+    #[test]
+    fn options_forget_builds() -> Result<(), Error> {
+        let mut db = IngestDb::with_base(files());
+        db.build("/src/a.etk")?;
+
+        db.set_strict(true);
+        assert!(!db.is_fresh("/src/a.etk"));
+
+        Ok(())
+    }
+}
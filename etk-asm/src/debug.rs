@@ -0,0 +1,639 @@
+//! Debug information describing an assembled program.
+//!
+//! A [`DebugInfo`] maps positions in the assembled code back to the labels,
+//! instruction macro invocations, and source statements that produced them.
+//! With the `fs` feature, it serializes to a stable JSON format (see
+//! [`VERSION`]), so it can be written alongside the bytecode and read by
+//! debuggers and tracers that don't use this crate.
+//!
+//! The source map can also be rendered in the compressed format used by solc
+//! (see [`DebugInfo::srcmap`]), which many existing tools understand, and
+//! combined with the code into an [`Artifact`] like the ones solc writes.
+use etk_ops::prague::{Op, Operation};
+
+use indexmap::IndexMap;
+
+use num_bigint::BigUint;
+
+#[cfg(feature = "fs")]
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The version of the debug information format written by this crate.
+pub const VERSION: u32 = 1;
+
+/// A label and its position in the assembled code.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct Symbol {
+    /// The name of the label. Labels of included files are prefixed with
+    /// their namespace (like `ns.label`), and labels local to an instruction
+    /// macro have a unique suffix.
+    pub name: String,
+
+    /// Position of the label, in bytes.
+    pub offset: usize,
+}
+
+/// The contents of a symbol file, mapping every label to its position.
+///
+/// Symbol files are a subset of [`DebugInfo`], for tools that only need to
+/// resolve addresses back to names. See [`Ingest::set_symbol_file`].
+///
+/// [`Ingest::set_symbol_file`]: crate::ingest::Ingest::set_symbol_file
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct SymbolFile {
+    /// The version of the format, currently [`VERSION`].
+    pub version: u32,
+
+    /// Every label, in order of declaration.
+    pub symbols: Vec<Symbol>,
+}
+
+impl From<&DebugInfo> for SymbolFile {
+    fn from(info: &DebugInfo) -> Self {
+        Self {
+            version: info.version,
+            symbols: info.labels.clone(),
+        }
+    }
+}
+
+/// The assembled code in the JSON format of solc's combined output, so it can
+/// be used by deployment tools (like Foundry and Hardhat) that expect it.
+///
+/// See [`DebugInfo::artifact`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct Artifact {
+    /// The assembled code, hex encoded without a `0x` prefix.
+    pub object: String,
+
+    /// The source map, in the format of [`DebugInfo::srcmap`].
+    #[cfg_attr(feature = "fs", serde(rename = "sourceMap"))]
+    pub source_map: String,
+
+    /// The instructions of the code, like `PUSH1 0x80 PUSH1 0x40 MSTORE`.
+    pub opcodes: String,
+
+    /// Placeholders in the code to be filled with the address of a library
+    /// before deploying, by source file and then by name.
+    ///
+    /// Only recorded with [`Ingest::set_link_references`].
+    ///
+    /// [`Ingest::set_link_references`]: crate::ingest::Ingest::set_link_references
+    #[cfg_attr(feature = "fs", serde(rename = "linkReferences", default))]
+    pub link_references: BTreeMap<String, BTreeMap<String, Vec<LinkReference>>>,
+}
+
+/// The position of a placeholder in an [`Artifact`], in the format of solc's
+/// `linkReferences`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct LinkReference {
+    /// Position of the first byte of the placeholder in the code.
+    pub start: usize,
+
+    /// Length of the placeholder, in bytes.
+    pub length: usize,
+}
+
+/// The code generated by one invocation of an instruction macro.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct Expansion {
+    /// The name of the macro.
+    pub name: String,
+
+    /// Position of the first byte of the expansion.
+    pub start: usize,
+
+    /// Position just after the last byte of the expansion.
+    pub end: usize,
+
+    /// Static gas cost of the instructions in the expansion, excluding costs
+    /// that depend on operands or state (like memory expansion).
+    #[cfg_attr(feature = "fs", serde(default))]
+    pub gas: u64,
+
+    /// The arguments of the invocation, as written. Only recorded with
+    /// [`Ingest::set_macro_details`].
+    ///
+    /// [`Ingest::set_macro_details`]: crate::ingest::Ingest::set_macro_details
+    #[cfg_attr(feature = "fs", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub arguments: Vec<String>,
+
+    /// The instructions generated by the invocation, including those of
+    /// nested invocations, like `push1 0x01`. Only recorded with
+    /// [`Ingest::set_macro_details`].
+    ///
+    /// [`Ingest::set_macro_details`]: crate::ingest::Ingest::set_macro_details
+    #[cfg_attr(feature = "fs", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub ops: Vec<String>,
+}
+
+impl Expansion {
+    /// Returns `true` if `offset` is part of the expansion.
+    pub fn contains(&self, offset: usize) -> bool {
+        self.start <= offset && offset < self.end
+    }
+
+    /// Length of the expansion, in bytes.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns `true` if the expansion contains no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// An instruction macro declared in the program.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct MacroInfo {
+    /// The name of the macro.
+    pub name: String,
+
+    /// The names of the macro's parameters, followed by the variadic
+    /// parameter (like `rest...`), if any.
+    pub parameters: Vec<String>,
+
+    /// The statements in the body of the macro, before expansion.
+    pub contents: Vec<String>,
+}
+
+/// The total cost of every expansion of an instruction macro.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MacroCost {
+    /// The name of the macro.
+    pub name: String,
+
+    /// Number of times the macro was expanded.
+    pub invocations: usize,
+
+    /// Total length of the expansions, in bytes.
+    pub bytes: usize,
+
+    /// Total static gas cost of the expansions.
+    pub gas: u64,
+}
+
+/// A range of bytes in a source file.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct Location {
+    /// Index of the file in [`DebugInfo::sources`].
+    pub file: usize,
+
+    /// Position of the first byte, from the start of the file.
+    pub start: usize,
+
+    /// Length of the range, in bytes.
+    pub length: usize,
+}
+
+/// The source statement that produced an instruction.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct Mapping {
+    /// Position of the instruction in the assembled code.
+    pub offset: usize,
+
+    /// The statement that produced the instruction. Instructions generated by
+    /// an instruction macro map to the macro's invocation.
+    pub location: Location,
+}
+
+/// Labels, macro expansions, and the source map of an assembled program.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::ingest::Ingest;
+/// # use etk_asm::ingest::Error;
+///
+/// let text = r#"
+///     %macro twice(x)
+///         push1 $x
+///         push1 $x
+///     %end
+///
+///     push1 start
+///     start:
+///     %twice(1)
+/// "#;
+///
+/// let mut output = Vec::new();
+/// let mut ingest = Ingest::new(&mut output);
+/// ingest.ingest("./example.etk", text)?;
+///
+/// let info = ingest.debug_info();
+/// assert_eq!(info.labels[0].name, "start");
+/// assert_eq!(info.labels[0].offset, 2);
+///
+/// let expansion = info.expansions_at(4).next().unwrap();
+/// assert_eq!(expansion.name, "twice");
+/// assert_eq!((expansion.start, expansion.end), (2, 6));
+/// # Result::<(), Error>::Ok(())
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct DebugInfo {
+    /// The version of the format, currently [`VERSION`].
+    pub version: u32,
+
+    /// Files read while assembling the program.
+    pub sources: Vec<PathBuf>,
+
+    /// Every label declared in the program, in order of declaration.
+    pub labels: Vec<Symbol>,
+
+    /// Every instruction macro expansion, ordered by start position. An
+    /// expansion nested in another comes after it.
+    pub expansions: Vec<Expansion>,
+
+    /// Every instruction macro declared in the program, ordered by name. Only
+    /// recorded with [`Ingest::set_macro_details`].
+    ///
+    /// [`Ingest::set_macro_details`]: crate::ingest::Ingest::set_macro_details
+    #[cfg_attr(feature = "fs", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub macros: Vec<MacroInfo>,
+
+    /// The source of every instruction assembled from a source file, ordered
+    /// by position.
+    pub source_map: Vec<Mapping>,
+}
+
+impl Default for DebugInfo {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            sources: Vec::new(),
+            labels: Vec::new(),
+            expansions: Vec::new(),
+            macros: Vec::new(),
+            source_map: Vec::new(),
+        }
+    }
+}
+
+impl DebugInfo {
+    /// Labels declared at `offset`.
+    pub fn labels_at(&self, offset: usize) -> impl Iterator<Item = &Symbol> {
+        self.labels.iter().filter(move |s| s.offset == offset)
+    }
+
+    /// Expansions containing `offset`, from outermost to innermost.
+    pub fn expansions_at(&self, offset: usize) -> impl Iterator<Item = &Expansion> {
+        self.expansions.iter().filter(move |e| e.contains(offset))
+    }
+
+    /// The cost of each instruction macro, in order of first expansion.
+    ///
+    /// The cost of a macro includes the macros it invokes, which are also
+    /// listed on their own.
+    pub fn macro_costs(&self) -> Vec<MacroCost> {
+        let mut costs: IndexMap<&str, MacroCost> = IndexMap::new();
+
+        for expansion in self.expansions.iter() {
+            let cost = costs
+                .entry(expansion.name.as_str())
+                .or_insert_with(|| MacroCost {
+                    name: expansion.name.clone(),
+                    invocations: 0,
+                    bytes: 0,
+                    gas: 0,
+                });
+
+            cost.invocations += 1;
+            cost.bytes += expansion.len();
+            cost.gas += expansion.gas;
+        }
+
+        costs.into_iter().map(|(_, c)| c).collect()
+    }
+
+    /// The source of the instruction at `offset`, if known.
+    pub fn location_at(&self, offset: usize) -> Option<Location> {
+        let idx = self
+            .source_map
+            .binary_search_by_key(&offset, |m| m.offset)
+            .ok()?;
+        Some(self.source_map[idx].location)
+    }
+
+    /// Render the source map of `code` in solc's compressed `s:l:f:j` format.
+    ///
+    /// There is one entry for each instruction in `code`, decoded from the
+    /// start. Instructions without a known source (including data decoded as
+    /// instructions) map to `-1:-1:-1`. Since there are no functions, every
+    /// instruction is marked as a regular jump (`-`).
+    ///
+    /// See the [solc documentation] for details on the format.
+    ///
+    /// [solc documentation]: https://docs.soliditylang.org/en/latest/internals/source_mappings.html
+    pub fn srcmap(&self, code: &[u8]) -> String {
+        let mut entries = Vec::new();
+        let mut previous: Option<[String; 4]> = None;
+
+        let mut offset = 0;
+        while offset < code.len() {
+            let (start, length, file) = match self.location_at(offset) {
+                Some(l) => (
+                    l.start.to_string(),
+                    l.length.to_string(),
+                    l.file.to_string(),
+                ),
+                None => ("-1".to_owned(), "-1".to_owned(), "-1".to_owned()),
+            };
+            let fields = [start, length, file, "-".to_owned()];
+
+            // Fields equal to the previous entry's are left empty, and
+            // trailing empty fields are dropped.
+            let mut entry: Vec<&str> = fields
+                .iter()
+                .enumerate()
+                .map(|(idx, field)| match previous {
+                    Some(ref p) if p[idx] == *field => "",
+                    _ => field.as_str(),
+                })
+                .collect();
+
+            while entry.last() == Some(&"") {
+                entry.pop();
+            }
+
+            entries.push(entry.join(":"));
+            previous = Some(fields);
+            offset += Op::<()>::from(code[offset]).size();
+        }
+
+        entries.join(";")
+    }
+
+    /// Combine `code` with its source map into an [`Artifact`].
+    ///
+    /// Instructions are listed like solc does: mnemonics are uppercase, and
+    /// immediates are written as hexadecimal numbers without leading zeros.
+    /// Bytes that aren't instructions are written as their value (like
+    /// `0xC`).
+    pub fn artifact(&self, code: &[u8]) -> Artifact {
+        let mut opcodes = Vec::new();
+
+        let mut offset = 0;
+        while offset < code.len() {
+            let op = Op::<()>::from(code[offset]);
+            let end = code.len().min(offset + op.size());
+
+            if op.mnemonic().starts_with("invalid_") {
+                opcodes.push(format!("0x{:X}", op.code_byte()));
+            } else {
+                opcodes.push(op.mnemonic().to_uppercase());
+            }
+
+            if op.extra_len() > 0 {
+                let value = BigUint::from_bytes_be(&code[offset + 1..end]);
+                opcodes.push(format!("0x{:x}", value));
+            }
+
+            offset = end;
+        }
+
+        Artifact {
+            object: hex::encode(code),
+            source_map: self.srcmap(code),
+            opcodes: opcodes.join(" "),
+            link_references: BTreeMap::new(),
+        }
+    }
+
+    /// Add the labels, expansions, and source map of `other`, a program placed
+    /// `shift` bytes after the start of this one.
+    pub(crate) fn append(&mut self, other: &Self, shift: usize) {
+        self.labels.extend(other.labels.iter().map(|s| Symbol {
+            name: s.name.clone(),
+            offset: s.offset + shift,
+        }));
+
+        self.expansions
+            .extend(other.expansions.iter().map(|e| Expansion {
+                name: e.name.clone(),
+                start: e.start + shift,
+                end: e.end + shift,
+                gas: e.gas,
+                arguments: e.arguments.clone(),
+                ops: e.ops.clone(),
+            }));
+
+        self.macros.extend(other.macros.iter().cloned());
+
+        self.source_map
+            .extend(other.source_map.iter().map(|m| Mapping {
+                offset: m.offset + shift,
+                location: m.location,
+            }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expansion(name: &str, start: usize, end: usize) -> Expansion {
+        Expansion {
+            name: name.into(),
+            start,
+            end,
+            gas: 0,
+            arguments: Vec::new(),
+            ops: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn expansions_at_nested() {
+        let info = DebugInfo {
+            expansions: vec![
+                expansion("outer", 0, 6),
+                expansion("inner", 2, 4),
+                expansion("other", 6, 8),
+            ],
+            ..Default::default()
+        };
+
+        let names: Vec<_> = info.expansions_at(3).map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["outer", "inner"]);
+
+        let names: Vec<_> = info.expansions_at(6).map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["other"]);
+
+        assert_eq!(info.expansions_at(8).count(), 0);
+    }
+
+    #[test]
+    fn append_shifts() {
+        let mut info = DebugInfo::default();
+        let other = DebugInfo {
+            labels: vec![Symbol {
+                name: "a".into(),
+                offset: 1,
+            }],
+            expansions: vec![expansion("m", 0, 2)],
+            ..Default::default()
+        };
+
+        info.append(&other, 10);
+
+        assert_eq!(info.labels_at(11).next().unwrap().name, "a");
+        assert_eq!(info.expansions, [expansion("m", 10, 12)]);
+    }
+
+    #[test]
+    fn macro_costs() {
+        let info = DebugInfo {
+            expansions: vec![
+                Expansion {
+                    gas: 9,
+                    ..expansion("outer", 0, 6)
+                },
+                Expansion {
+                    gas: 3,
+                    ..expansion("inner", 2, 4)
+                },
+                Expansion {
+                    gas: 3,
+                    ..expansion("inner", 6, 8)
+                },
+            ],
+            ..Default::default()
+        };
+
+        let costs = info.macro_costs();
+        assert_eq!(
+            costs,
+            [
+                MacroCost {
+                    name: "outer".into(),
+                    invocations: 1,
+                    bytes: 6,
+                    gas: 9,
+                },
+                MacroCost {
+                    name: "inner".into(),
+                    invocations: 2,
+                    bytes: 4,
+                    gas: 6,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn srcmap_compressed() {
+        let location = Location {
+            file: 0,
+            start: 3,
+            length: 5,
+        };
+
+        let info = DebugInfo {
+            source_map: vec![
+                Mapping {
+                    offset: 0,
+                    location,
+                },
+                Mapping {
+                    offset: 2,
+                    location,
+                },
+                Mapping {
+                    offset: 5,
+                    location: Location {
+                        start: 9,
+                        ..location
+                    },
+                },
+            ],
+            ..Default::default()
+        };
+
+        // push1 1; push1 2; stop; push1 3
+        let code = [0x60, 0x01, 0x60, 0x02, 0x00, 0x60, 0x03];
+        assert_eq!(info.srcmap(&code), "3:5:0:-;;-1:-1:-1;9:5:0");
+        assert_eq!(info.srcmap(&[]), "");
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn artifact_opcodes() {
+        let info = DebugInfo::default();
+
+        // push1 0x80; push2 0x0010; mstore; invalid; 0x0c; push2 (truncated)
+        let code = [0x60, 0x80, 0x61, 0x00, 0x10, 0x52, 0xfe, 0x0c, 0x61, 0x01];
+        let artifact = info.artifact(&code);
+
+        assert_eq!(artifact.object, "608061001052fe0c6101");
+        assert_eq!(
+            artifact.opcodes,
+            "PUSH1 0x80 PUSH2 0x10 MSTORE INVALID 0xC PUSH2 0x1"
+        );
+        assert_eq!(artifact.source_map, info.srcmap(&code));
+
+        let json = serde_json::to_value(&artifact).unwrap();
+        assert_eq!(json["sourceMap"], "-1:-1:-1:-;;;;;");
+        assert_eq!(json["linkReferences"], serde_json::json!({}));
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn json_format() {
+        let info = DebugInfo {
+            sources: vec!["main.etk".into()],
+            labels: vec![Symbol {
+                name: "start".into(),
+                offset: 2,
+            }],
+            expansions: vec![expansion("twice", 2, 6)],
+            ..Default::default()
+        };
+
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(
+            json,
+            r#"{"version":1,"sources":["main.etk"],"labels":[{"name":"start","offset":2}],"expansions":[{"name":"twice","start":2,"end":6,"gas":0}],"source_map":[]}"#
+        );
+
+        assert_eq!(serde_json::from_str::<DebugInfo>(&json).unwrap(), info);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn json_macro_details() {
+        let info = DebugInfo {
+            expansions: vec![Expansion {
+                arguments: vec!["1".into()],
+                ops: vec!["push1 0x01".into(), "push1 0x01".into()],
+                ..expansion("twice", 0, 4)
+            }],
+            macros: vec![MacroInfo {
+                name: "twice".into(),
+                parameters: vec!["x".into()],
+                contents: vec!["%push($x)".into(), "%push($x)".into()],
+            }],
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["expansions"][0]["arguments"][0], "1");
+        assert_eq!(json["expansions"][0]["ops"][1], "push1 0x01");
+        assert_eq!(json["macros"][0]["parameters"][0], "x");
+
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(serde_json::from_str::<DebugInfo>(&json).unwrap(), info);
+    }
+}
@@ -0,0 +1,360 @@
+//! Assemble, deploy, and call contracts on a local development node, like
+//! [anvil], for end-to-end tests.
+//!
+//! Requires the `devnet` feature.
+//!
+//! The node must unlock its accounts (as anvil and `geth --dev` do), since
+//! contracts are deployed with `eth_sendTransaction` from the first account.
+//!
+//! [anvil]: https://book.getfoundry.sh/anvil/
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! use etk_asm::devnet::Devnet;
+//! # use etk_asm::devnet::Error;
+//!
+//! let devnet = Devnet::new(Devnet::DEFAULT_URL);
+//! let contract = devnet.deploy_str("push1 42\npush0\nmstore8\npush1 1\npush0\nreturn\n")?;
+//! let output = contract.call(&[])?;
+//! assert_eq!(output, [42]);
+//! # Result::<(), Error>::Ok(())
+//! ```
+mod error {
+    use snafu::{Backtrace, Snafu};
+
+    /// Errors that may arise while talking to a development node.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// The source couldn't be assembled.
+        #[snafu(display("assembling failed"))]
+        #[non_exhaustive]
+        Ingest {
+            /// The underlying source of this error.
+            #[snafu(backtrace)]
+            source: crate::ingest::Error,
+        },
+
+        /// The node couldn't be reached, or didn't respond with JSON.
+        #[snafu(display("request `{}` to the node failed", method))]
+        #[non_exhaustive]
+        Http {
+            /// The JSON-RPC method of the request.
+            method: String,
+
+            /// The underlying source of this error.
+            source: reqwest::Error,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The node responded to a request with an error, for example
+        /// because the call reverted.
+        #[snafu(display("`{}` failed with code {}: {}", method, code, message))]
+        #[non_exhaustive]
+        Rpc {
+            /// The JSON-RPC method of the request.
+            method: String,
+
+            /// The error code given by the node.
+            code: i64,
+
+            /// The error message given by the node.
+            message: String,
+
+            /// Extra data given by the node, like the output of a reverted
+            /// call.
+            data: Option<serde_json::Value>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The node responded with something other than what was expected.
+        #[snafu(display("`{}` returned an unexpected result: {}", method, result))]
+        #[non_exhaustive]
+        InvalidResponse {
+            /// The JSON-RPC method of the request.
+            method: String,
+
+            /// The result given by the node.
+            result: serde_json::Value,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The node has no accounts to deploy from.
+        #[snafu(display("the node has no unlocked accounts"))]
+        #[non_exhaustive]
+        NoAccounts {
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The deployment transaction wasn't mined in time.
+        #[snafu(display("deployment `{}` wasn't mined", transaction))]
+        #[non_exhaustive]
+        NotMined {
+            /// The hash of the deployment transaction.
+            transaction: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The initcode reverted or ran out of gas.
+        #[snafu(display("deployment `{}` failed", transaction))]
+        #[non_exhaustive]
+        DeploymentFailed {
+            /// The hash of the deployment transaction.
+            transaction: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+}
+
+pub use self::error::Error;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use serde_json::{json, Value};
+
+use snafu::{OptionExt, ResultExt};
+
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// How many times to check for the receipt of a deployment.
+const RECEIPT_ATTEMPTS: usize = 50;
+
+/// How long to wait between checks for the receipt of a deployment.
+const RECEIPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A connection to a development node's JSON-RPC endpoint.
+#[derive(Debug)]
+pub struct Devnet {
+    url: String,
+    client: reqwest::blocking::Client,
+    next_id: AtomicU64,
+}
+
+impl Devnet {
+    /// The endpoint anvil listens on by default.
+    pub const DEFAULT_URL: &'static str = "http://127.0.0.1:8545";
+
+    /// Connect to the node listening at `url`.
+    pub fn new<S>(url: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            url: url.into(),
+            client: reqwest::blocking::Client::new(),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// The endpoint of the node.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Assemble `src` (see [`crate::assemble_str`]) as runtime code, and
+    /// deploy it.
+    pub fn deploy_str(&self, src: &str) -> Result<Contract<'_>, Error> {
+        let runtime = crate::assemble_str(src).context(error::Ingest)?;
+        self.deploy_runtime(&runtime)
+    }
+
+    /// Deploy `runtime`, using initcode that returns it unchanged.
+    pub fn deploy_runtime(&self, runtime: &[u8]) -> Result<Contract<'_>, Error> {
+        self.deploy(&initcode(runtime))
+    }
+
+    /// Run `initcode` in a contract creation transaction, returning the
+    /// deployed contract.
+    pub fn deploy(&self, initcode: &[u8]) -> Result<Contract<'_>, Error> {
+        let accounts: Vec<String> = self.request("eth_accounts", json!([]))?;
+        let from = accounts.into_iter().next().context(error::NoAccounts)?;
+
+        let transaction: String = self.request(
+            "eth_sendTransaction",
+            json!([{ "from": from, "data": encode(initcode) }]),
+        )?;
+
+        let mut attempts = 0;
+        let receipt = loop {
+            let receipt: Option<Receipt> =
+                self.request("eth_getTransactionReceipt", json!([transaction]))?;
+
+            if let Some(receipt) = receipt {
+                break receipt;
+            }
+
+            attempts += 1;
+            if attempts == RECEIPT_ATTEMPTS {
+                return error::NotMined { transaction }.fail();
+            }
+
+            std::thread::sleep(RECEIPT_INTERVAL);
+        };
+
+        let address = match (receipt.status.as_deref(), receipt.contract_address) {
+            (Some("0x1") | None, Some(address)) => address,
+            _ => return error::DeploymentFailed { transaction }.fail(),
+        };
+
+        Ok(Contract {
+            devnet: self,
+            address,
+        })
+    }
+
+    fn request<T>(&self, method: &str, params: Value) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+
+        let response: Response = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .and_then(|r| r.json())
+            .context(error::Http { method })?;
+
+        if let Some(e) = response.error {
+            return error::Rpc {
+                method,
+                code: e.code,
+                message: e.message,
+                data: e.data,
+            }
+            .fail();
+        }
+
+        let result = response.result.unwrap_or(Value::Null);
+        match serde_json::from_value(result.clone()) {
+            Ok(value) => Ok(value),
+            Err(_) => error::InvalidResponse { method, result }.fail(),
+        }
+    }
+}
+
+/// A contract deployed by [`Devnet::deploy`].
+#[derive(Debug)]
+pub struct Contract<'a> {
+    devnet: &'a Devnet,
+    address: String,
+}
+
+impl Contract<'_> {
+    /// The address of the contract, as `0x`-prefixed hex.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Call the contract with `calldata`, without sending a transaction,
+    /// returning its output.
+    ///
+    /// Fails with [`Error::Rpc`] if the call reverts, with the revert data in
+    /// `data`.
+    pub fn call(&self, calldata: &[u8]) -> Result<Vec<u8>, Error> {
+        let method = "eth_call";
+        let output: String = self.devnet.request(
+            method,
+            json!([{ "to": self.address, "data": encode(calldata) }, "latest"]),
+        )?;
+
+        match hex::decode(output.trim_start_matches("0x")) {
+            Ok(bytes) => Ok(bytes),
+            Err(_) => error::InvalidResponse {
+                method,
+                result: Value::String(output),
+            }
+            .fail(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Receipt {
+    status: Option<String>,
+    contract_address: Option<String>,
+}
+
+fn encode(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+/// Initcode that copies `runtime` into memory and returns it.
+fn initcode(runtime: &[u8]) -> Vec<u8> {
+    const PREFIX_LEN: u8 = 12;
+
+    let len = u16::try_from(runtime.len()).expect("runtime code should fit in 64 KiB");
+    let [hi, lo] = len.to_be_bytes();
+
+    // push2 len, dup1, push1 PREFIX_LEN, push1 0, codecopy, push1 0, return
+    let mut code = vec![
+        0x61, hi, lo, 0x80, 0x60, PREFIX_LEN, 0x60, 0x00, 0x39, 0x60, 0x00, 0xf3,
+    ];
+    debug_assert_eq!(code.len(), usize::from(PREFIX_LEN));
+
+    code.extend_from_slice(runtime);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn initcode_returns_runtime() {
+        assert_eq!(
+            initcode(&hex!("602a00")),
+            hex!("61000380600c6000396000f3602a00")
+        );
+    }
+
+    #[test]
+    #[ignore = "requires a development node at 127.0.0.1:8545"]
+    fn hello_world() -> Result<(), Error> {
+        let devnet = Devnet::new(Devnet::DEFAULT_URL);
+        let contract = devnet.deploy_str(
+            "calldatasize\npush0\npush0\ncalldatacopy\ncalldatasize\npush0\nreturn\n",
+        )?;
+        assert_eq!(contract.call(&hex!("c0ffee"))?, hex!("c0ffee"));
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+//! Errors rendered with the source code they point at.
+//!
+//! A [`Diagnostic`] is a message with labelled [`Location`]s, like the two
+//! declarations of a duplicated label. Rendering it against the text of the
+//! sources shows each location's line, underlined:
+//!
+//! ```text
+//! error: label `a` declared multiple times
+//!  --> main.etk:3:1
+//!   |
+//! 3 | a:
+//!   | ^^ declared again here
+//!  ::: main.etk:1:1
+//!   |
+//! 1 | a:
+//!   | -- first declared here
+//! ```
+//!
+//! Errors from [`Ingest`](crate::ingest::Ingest) are turned into diagnostics
+//! with [`Ingest::diagnostic`](crate::ingest::Ingest::diagnostic).
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_asm::debug::Location;
+//! use etk_asm::diagnostic::{Diagnostic, Source};
+//!
+//! let sources = [Source::new("main.etk", "push1 256\n")];
+//!
+//! let location = Location { file: 0, start: 6, length: 3 };
+//! let diagnostic = Diagnostic::new("value too large").with_primary(location, "needs 2 bytes");
+//!
+//! let text = diagnostic.render(&sources).to_string();
+//! assert!(text.contains("main.etk:1:7"));
+//! ```
+use crate::debug::Location;
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// A source file, as given to [`Diagnostic::render`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Source {
+    /// The path of the file.
+    pub path: PathBuf,
+
+    /// The contents of the file.
+    pub text: String,
+}
+
+impl Source {
+    /// Create a source from its `path` and `text`.
+    pub fn new<P, S>(path: P, text: S) -> Self
+    where
+        P: Into<PathBuf>,
+        S: Into<String>,
+    {
+        Self {
+            path: path.into(),
+            text: text.into(),
+        }
+    }
+}
+
+/// A location in the source, with a note about it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Label {
+    /// The part of the source being pointed at.
+    pub location: Location,
+
+    /// A short note about the location, like `first declared here`.
+    pub message: String,
+
+    /// Whether this is where the error happened (`true`), or a related
+    /// location (`false`).
+    pub primary: bool,
+}
+
+/// An error message, with the locations in the source it refers to.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// The error message.
+    pub message: String,
+
+    /// The locations the error refers to, in the order they're shown.
+    pub labels: Vec<Label>,
+}
+
+impl Diagnostic {
+    /// Create a diagnostic with `message`, and no locations.
+    pub fn new<S>(message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            message: message.into(),
+            labels: Vec::new(),
+        }
+    }
+
+    /// Point at `location`, where the error happened.
+    pub fn with_primary<S>(self, location: Location, message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.with_label(location, message, true)
+    }
+
+    /// Point at `location`, which is related to the error.
+    pub fn with_secondary<S>(self, location: Location, message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.with_label(location, message, false)
+    }
+
+    fn with_label<S>(mut self, location: Location, message: S, primary: bool) -> Self
+    where
+        S: Into<String>,
+    {
+        self.labels.push(Label {
+            location,
+            message: message.into(),
+            primary,
+        });
+        self
+    }
+
+    /// Show the diagnostic with the source lines it points at. `sources` is
+    /// indexed by [`Location::file`].
+    pub fn render<'a>(&'a self, sources: &'a [Source]) -> Render<'a> {
+        Render {
+            diagnostic: self,
+            sources,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.render(&[]).fmt(f)
+    }
+}
+
+/// A [`Diagnostic`] with its sources, returned by [`Diagnostic::render`].
+#[derive(Debug, Clone, Copy)]
+pub struct Render<'a> {
+    diagnostic: &'a Diagnostic,
+    sources: &'a [Source],
+}
+
+/// The line of `text` containing `location`.
+struct Line<'a> {
+    /// The line number, starting from one.
+    number: usize,
+
+    /// The column of the start of the location, starting from one.
+    column: usize,
+
+    /// The text of the line, without its line break.
+    text: &'a str,
+
+    /// The width of the location, in characters, within the line.
+    width: usize,
+}
+
+impl<'a> Line<'a> {
+    fn find(text: &'a str, location: &Location) -> Option<Self> {
+        let start = location.start.min(text.len());
+        let end = (location.start + location.length).min(text.len());
+
+        let line_start = text.get(..start)?.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+
+        let before = &text[line_start..start];
+        let within = text.get(start..end.clamp(start, line_end))?;
+
+        Some(Self {
+            number: text[..line_start].matches('\n').count() + 1,
+            column: before.chars().count() + 1,
+            text: text[line_start..line_end].trim_end_matches('\r'),
+            width: within.chars().count().max(1),
+        })
+    }
+}
+
+impl fmt::Display for Render<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "error: {}", self.diagnostic.message)?;
+
+        let lines: Vec<_> = self
+            .diagnostic
+            .labels
+            .iter()
+            .map(|label| {
+                let source = self.sources.get(label.location.file);
+                let line = source.and_then(|s| Line::find(&s.text, &label.location));
+                (label, source, line)
+            })
+            .collect();
+
+        let gutter = lines
+            .iter()
+            .filter_map(|(_, _, line)| line.as_ref())
+            .map(|line| line.number.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let pad = " ".repeat(gutter);
+
+        for (label, source, line) in lines {
+            let arrow = if label.primary { "-->" } else { ":::" };
+
+            let line = match (source, line) {
+                (Some(source), Some(line)) => {
+                    let path = source.path.display();
+                    writeln!(
+                        f,
+                        "{}{} {}:{}:{}",
+                        pad, arrow, path, line.number, line.column
+                    )?;
+                    line
+                }
+                _ => {
+                    let location = label.location;
+                    write!(f, "{}{} ", pad, arrow)?;
+                    match source {
+                        Some(source) => write!(f, "{}", source.path.display())?,
+                        None => write!(f, "<file {}>", location.file)?,
+                    }
+                    write!(
+                        f,
+                        " (bytes {}..{})",
+                        location.start,
+                        location.start + location.length
+                    )?;
+                    if !label.message.is_empty() {
+                        write!(f, ": {}", label.message)?;
+                    }
+                    writeln!(f)?;
+                    continue;
+                }
+            };
+
+            let mark = if label.primary { "^" } else { "-" };
+
+            writeln!(f, "{} |", pad)?;
+            writeln!(f, "{:>width$} | {}", line.number, line.text, width = gutter)?;
+            write!(
+                f,
+                "{} | {}{}",
+                pad,
+                " ".repeat(line.column - 1),
+                mark.repeat(line.width)
+            )?;
+            if !label.message.is_empty() {
+                write!(f, " {}", label.message)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(file: usize, start: usize, length: usize) -> Location {
+        Location {
+            file,
+            start,
+            length,
+        }
+    }
+
+    #[test]
+    fn render_primary_and_secondary() {
+        let sources = [Source::new("main.etk", "a:\njumpdest\na:\n")];
+        let diagnostic = Diagnostic::new("label `a` declared multiple times")
+            .with_primary(location(0, 12, 2), "declared again here")
+            .with_secondary(location(0, 0, 2), "first declared here");
+
+        let expected = "\
+error: label `a` declared multiple times
+ --> main.etk:3:1
+  |
+3 | a:
+  | ^^ declared again here
+ ::: main.etk:1:1
+  |
+1 | a:
+  | -- first declared here
+";
+        assert_eq!(diagnostic.render(&sources).to_string(), expected);
+    }
+
+    #[test]
+    fn render_columns() {
+        let text = "\n\n\n\n\n\n\n\n\n    push1 0x0100 # too large\n";
+        let sources = [Source::new("big.etk", text)];
+        let diagnostic = Diagnostic::new("too large").with_primary(location(0, 19, 6), "");
+
+        let expected = "\
+error: too large
+  --> big.etk:10:11
+   |
+10 |     push1 0x0100 # too large
+   |           ^^^^^^
+";
+        assert_eq!(diagnostic.render(&sources).to_string(), expected);
+    }
+
+    #[test]
+    fn render_without_sources() {
+        let diagnostic = Diagnostic::new("oops").with_primary(location(1, 4, 2), "here");
+        assert_eq!(
+            diagnostic.to_string(),
+            "error: oops\n--> <file 1> (bytes 4..6): here\n"
+        );
+
+        assert_eq!(Diagnostic::new("oops").to_string(), "error: oops\n");
+    }
+}
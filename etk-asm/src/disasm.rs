@@ -2,7 +2,21 @@
 //!
 //! Converts a stream of bytes into an iterator of [`Op<[u8]>'].
 //!
-//! See the documentation for [`Disassembler`] for more information.
+//! See the documentation for [`Disassembler`] for more information. For tools
+//! that consume disassembly programmatically, see [`Disassembler::records`] and
+//! [`Disassembler::write_json`]. How immediates are written in records can be
+//! changed with [`ImmediateFormat`].
+//!
+//! Instructions are read from the Cancun table unless another is chosen, like
+//! `Disassembler::<prague::Op<[u8]>>::default()`. EOF containers should be
+//! split into sections with [`eof::Container`] before disassembling, which
+//! reads them with the Prague table.
+//!
+//! Code is often followed by data that isn't meant to be executed, like the
+//! metadata solc appends. [`data_start`] finds where it begins, and
+//! [`to_source`] writes it as data instead of instructions.
+pub mod eof;
+
 mod error {
     use snafu::{Backtrace, Snafu};
 
@@ -26,14 +40,19 @@ mod error {
 }
 
 use etk_ops::cancun::Op;
+use etk_ops::{prague, Operation};
 
 pub use self::error::Error;
 
+#[cfg(feature = "json")]
+use serde::Serialize;
+
 use snafu::ensure;
 
 use std::collections::VecDeque;
 use std::fmt;
 use std::io::{self, Write};
+use std::marker::PhantomData;
 
 /// An item with its location within a stream of bytes.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -61,21 +80,137 @@ where
     }
 }
 
+/// How the immediates of disassembled instructions are written, so the output
+/// can match what other tools expect.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::disasm::ImmediateFormat;
+///
+/// let format = ImmediateFormat {
+///     uppercase: true,
+///     prefix: false,
+///     ..Default::default()
+/// };
+///
+/// assert_eq!(format.format(&[0xab, 0xcd]), "ABCD");
+/// ```
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ImmediateFormat {
+    /// Write hexadecimal digits in uppercase.
+    pub uppercase: bool,
+
+    /// Start immediates with `0x`.
+    pub prefix: bool,
+
+    /// Separate 32 byte immediates into 4 byte groups with `_`, for example
+    /// `0x00000000_00000000_..._00000001`. The groups can't be assembled.
+    pub group_words: bool,
+}
+
+impl Default for ImmediateFormat {
+    fn default() -> Self {
+        Self {
+            uppercase: false,
+            prefix: true,
+            group_words: false,
+        }
+    }
+}
+
+impl ImmediateFormat {
+    /// Write `immediate` as hexadecimal, according to this format.
+    pub fn format(&self, immediate: &[u8]) -> String {
+        let mut text = String::with_capacity(2 + 3 * immediate.len());
+
+        if self.prefix {
+            text.push_str("0x");
+        }
+
+        let group = if self.group_words && immediate.len() == 32 {
+            4
+        } else {
+            immediate.len().max(1)
+        };
+
+        for (index, chunk) in immediate.chunks(group).enumerate() {
+            if index > 0 {
+                text.push('_');
+            }
+
+            if self.uppercase {
+                text.push_str(&hex::encode_upper(chunk));
+            } else {
+                text.push_str(&hex::encode(chunk));
+            }
+        }
+
+        text
+    }
+}
+
+/// Instructions of a fork that can be read from a stream of bytes.
+pub trait Decode: Operation<ImmediateRef = [u8]> + Sized {
+    /// Length of the instruction starting with `code`, including its
+    /// immediate, or `None` if it depends on the byte after `code`, and
+    /// `next` isn't available yet.
+    fn len(code: u8, next: Option<u8>) -> Option<usize>;
+
+    /// Read a complete instruction from `bytes`.
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! decode {
+    ($($fork:ident),*) => {
+        $(
+            impl Decode for etk_ops::$fork::Op<[u8]> {
+                fn len(code: u8, _: Option<u8>) -> Option<usize> {
+                    Some(etk_ops::$fork::Op::<()>::from(code).size())
+                }
+
+                fn decode(bytes: &[u8]) -> Option<Self> {
+                    Self::from_slice(bytes).ok()
+                }
+            }
+        )*
+    };
+}
+
+decode!(london, shanghai, cancun);
+
+impl Decode for prague::Op<[u8]> {
+    fn len(code: u8, next: Option<u8>) -> Option<usize> {
+        match prague::Op::<()>::from(code) {
+            // The table of `rjumpv` starts with its largest index, followed by
+            // a two byte offset for every index.
+            prague::Op::RJumpV(_) => next.map(|max| 2 + 2 * (usize::from(max) + 1)),
+            specifier => Some(specifier.size()),
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Self::from_slice(bytes).ok()
+    }
+}
+
 /// A [`std::iter::Iterator`] over the [`Op<[u8]>`] produced by disassembling
 /// a stream of bytes.
 #[derive(Debug)]
-pub struct Iter<'a> {
-    disassembler: &'a mut Disassembler,
+pub struct Iter<'a, O = Op<[u8]>> {
+    disassembler: &'a mut Disassembler<O>,
 }
 
-impl<'a> Iterator for Iter<'a> {
-    type Item = Offset<Op<[u8]>>;
+impl<'a, O> Iterator for Iter<'a, O>
+where
+    O: Decode,
+{
+    type Item = Offset<O>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let buffer = &mut self.disassembler.buffer;
         let front = *buffer.front()?;
-        let specifier = Op::<()>::from(front);
-        let len = specifier.size();
+        let len = O::len(front, buffer.get(1).copied())?;
         if buffer.len() < len {
             return None;
         }
@@ -84,16 +219,90 @@ impl<'a> Iterator for Iter<'a> {
         let mut instruction = std::mem::replace(&mut self.disassembler.buffer, remaining);
         let instruction = instruction.make_contiguous();
 
-        let item = Op::from_slice(instruction).ok()?;
+        let item = O::decode(instruction)?;
         let offset = self.disassembler.offset;
         self.disassembler.offset += len;
         Some(Offset::new(offset, item))
     }
 }
 
+/// A structured description of a single disassembled instruction, suitable
+/// for serialization.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Record {
+    /// The location of the instruction within the stream of bytes.
+    pub offset: usize,
+
+    /// The byte (opcode) that indicates the instruction.
+    pub opcode: u8,
+
+    /// Human-readable name of the instruction.
+    pub mnemonic: String,
+
+    /// The immediate argument, hex encoded with a `0x` prefix, if the
+    /// instruction has one.
+    pub immediate: Option<String>,
+
+    /// Total length of the instruction, including its immediate.
+    pub size: usize,
+}
+
+impl Record {
+    /// Describe the instruction in `off`, writing its immediate with
+    /// `format`.
+    pub fn with_format<O>(off: Offset<O>, format: ImmediateFormat) -> Self
+    where
+        O: Operation<ImmediateRef = [u8]>,
+    {
+        let op = off.item;
+        Self {
+            offset: off.offset,
+            opcode: op.code_byte(),
+            mnemonic: op.mnemonic().to_owned(),
+            immediate: op.immediate().map(|i| format.format(i)),
+            size: 1 + op.extra_len(),
+        }
+    }
+}
+
+impl<O> From<Offset<O>> for Record
+where
+    O: Operation<ImmediateRef = [u8]>,
+{
+    fn from(off: Offset<O>) -> Self {
+        Self::with_format(off, ImmediateFormat::default())
+    }
+}
+
+/// A [`std::iter::Iterator`] over the [`Record`] produced by disassembling a
+/// stream of bytes.
+#[derive(Debug)]
+pub struct Records<'a, O = Op<[u8]>> {
+    inner: Iter<'a, O>,
+    format: ImmediateFormat,
+}
+
+impl<'a, O> Iterator for Records<'a, O>
+where
+    O: Decode,
+{
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let format = self.format;
+        self.inner
+            .next()
+            .map(|off| Record::with_format(off, format))
+    }
+}
+
 /// A simple disassembler that converts a stream of bytes into an iterator over
 /// the disassembled [`Op<[u8]>`].
 ///
+/// Instructions are read from the Cancun table, or from the table of the fork
+/// `O`.
+///
 /// ## Example
 /// ```rust
 /// use etk_ops::cancun::{Op, GetPc, Stop};
@@ -114,13 +323,49 @@ impl<'a> Iterator for Iter<'a> {
 /// # let expected = [Offset::new(0, GetPc.into()), Offset::new(1, Stop.into())];
 /// # assert_eq!(expected, actual.as_slice());
 /// ```
-#[derive(Debug, Default)]
-pub struct Disassembler {
+///
+/// Instructions that only exist in later forks, like the relative jumps of
+/// Prague, are read with that fork's table:
+///
+/// ```rust
+/// use etk_ops::prague::{Op, RJump};
+/// use etk_asm::disasm::Disassembler;
+/// # use etk_asm::disasm::Offset;
+///
+/// use std::io::Write;
+///
+/// let input = [0xe0, 0xff, 0xfd];
+///
+/// let mut dasm = Disassembler::<Op<[u8]>>::default();
+/// dasm.write_all(&input).unwrap();
+///
+/// let actual: Vec<_> = dasm.ops().collect();
+///
+/// dasm.finish().unwrap();
+///
+/// # let expected = [Offset::new(0, RJump([0xff, 0xfd]).into())];
+/// # assert_eq!(expected, actual.as_slice());
+/// ```
+#[derive(Debug)]
+pub struct Disassembler<O = Op<[u8]>> {
     buffer: VecDeque<u8>,
     offset: usize,
+    format: ImmediateFormat,
+    fork: PhantomData<O>,
+}
+
+impl<O> Default for Disassembler<O> {
+    fn default() -> Self {
+        Self {
+            buffer: VecDeque::default(),
+            offset: 0,
+            format: ImmediateFormat::default(),
+            fork: PhantomData,
+        }
+    }
 }
 
-impl Write for Disassembler {
+impl<O> Write for Disassembler<O> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.buffer.reserve(buf.len());
         self.buffer.extend(buf);
@@ -133,16 +378,48 @@ impl Write for Disassembler {
 }
 
 impl Disassembler {
-    /// Create a new instance of `Disassembler`.
+    /// Create a new instance of `Disassembler`, reading Cancun instructions.
     pub fn new() -> Self {
         Default::default()
     }
+}
+
+impl<O> Disassembler<O>
+where
+    O: Decode,
+{
+    /// Change how immediates are written in [`Record`], including those
+    /// written by [`Disassembler::write_json`].
+    pub fn set_immediate_format(&mut self, format: ImmediateFormat) {
+        self.format = format;
+    }
 
     /// Get an iterator over the disassembled [`Op<[u8]>`].
-    pub fn ops(&mut self) -> Iter {
+    pub fn ops(&mut self) -> Iter<O> {
         Iter { disassembler: self }
     }
 
+    /// Get an iterator over the disassembled instructions, as [`Record`].
+    pub fn records(&mut self) -> Records<O> {
+        let format = self.format;
+        Records {
+            inner: self.ops(),
+            format,
+        }
+    }
+
+    /// Disassemble all the available bytes and write them to `out` as a JSON
+    /// array of [`Record`].
+    #[cfg(feature = "json")]
+    pub fn write_json<W>(&mut self, out: W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let records: Vec<_> = self.records().collect();
+        serde_json::to_writer(out, &records)?;
+        Ok(())
+    }
+
     /// Indicate that there are no further bytes to write. Returns any errors
     /// collected.
     pub fn finish(self) -> Result<(), Error> {
@@ -156,6 +433,117 @@ impl Disassembler {
     }
 }
 
+/// Position of the metadata solc appends to the end of contracts, if `code`
+/// ends with it.
+///
+/// The metadata is CBOR encoded, and followed by its length as two big-endian
+/// bytes. It's recognized as a CBOR map with an entry for the version of
+/// solc, or the hash of the source (`ipfs` or `bzzr0`/`bzzr1`).
+pub fn metadata_start(code: &[u8]) -> Option<usize> {
+    let len = match code {
+        [.., hi, lo] => usize::from(u16::from_be_bytes([*hi, *lo])),
+        _ => return None,
+    };
+
+    let start = code.len().checked_sub(len + 2)?;
+    let metadata = &code[start..code.len() - 2];
+
+    let is_map = matches!(metadata.first(), Some(0xa1..=0xa7));
+    let has_key = [&b"solc"[..], b"ipfs", b"bzzr"]
+        .iter()
+        .any(|key| metadata.windows(key.len()).any(|w| w == *key));
+
+    if is_map && has_key {
+        Some(start)
+    } else {
+        None
+    }
+}
+
+/// Position of the data at the end of `code`, or the length of `code` if
+/// there isn't any.
+///
+/// Data begins at the metadata found by [`metadata_start`], or earlier if
+/// the code before it can't be executed: bytes following an instruction
+/// that ends execution or jumps (like `stop` or `jump`), when there's no
+/// `jumpdest` after it to jump to. A push missing part of its immediate at
+/// the end of the code is data too.
+pub fn data_start(code: &[u8]) -> usize {
+    let end = metadata_start(code).unwrap_or(code.len());
+
+    let mut dasm = Disassembler::new();
+    dasm.write_all(&code[..end]).unwrap();
+
+    let mut complete = 0;
+    let mut unreachable = None;
+
+    for op in dasm.ops() {
+        complete = op.offset + op.item.size();
+
+        if op.item.is_jump_target() {
+            unreachable = None;
+        } else if unreachable.is_none() && (op.item.is_exit() || matches!(op.item, Op::Jump(_))) {
+            unreachable = Some(complete);
+        }
+    }
+
+    unreachable.unwrap_or(complete)
+}
+
+/// Disassemble `code` into source that the assembler accepts.
+///
+/// Bytes that aren't instructions, and a push at the end of `code` that's
+/// missing part of its immediate, are written with `%bytes`. With
+/// `detect_data`, the data at the end of `code` (see [`data_start`]) is
+/// written with `%bytes` too, instead of as instructions.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::disasm::to_source;
+///
+/// // push1 1; stop; (data)
+/// let code = [0x60, 0x01, 0x00, 0xaa, 0xbb];
+///
+/// assert_eq!(to_source(&code, true), "push1 0x01\nstop\n%bytes(hex(\"aabb\"))\n");
+/// ```
+pub fn to_source(code: &[u8], detect_data: bool) -> String {
+    let end = if detect_data {
+        data_start(code)
+    } else {
+        code.len()
+    };
+
+    let mut dasm = Disassembler::new();
+    dasm.write_all(&code[..end]).unwrap();
+
+    let mut source = String::new();
+
+    for record in dasm.records() {
+        if record.mnemonic.starts_with("invalid_") {
+            source.push_str(&format!("%bytes(0x{:02x})\n", record.opcode));
+            continue;
+        }
+
+        source.push_str(&record.mnemonic);
+        if let Some(immediate) = record.immediate {
+            source.push(' ');
+            source.push_str(&immediate);
+        }
+        source.push('\n');
+    }
+
+    if let Err(Error::Truncated { remaining, .. }) = dasm.finish() {
+        source.push_str(&format!("%bytes(0x{})\n", hex::encode(remaining.item)));
+    }
+
+    if end < code.len() {
+        source.push_str(&format!("%bytes(hex(\"{}\"))\n", hex::encode(&code[end..])));
+    }
+
+    source
+}
+
 #[cfg(test)]
 mod tests {
     use etk_ops::cancun::*;
@@ -202,6 +590,83 @@ mod tests {
         dasm.finish().unwrap();
     }
 
+    #[test]
+    fn records() {
+        let input = hex!("6101025b");
+        let expected = [
+            Record {
+                offset: 0,
+                opcode: 0x61,
+                mnemonic: "push2".into(),
+                immediate: Some("0x0102".into()),
+                size: 3,
+            },
+            Record {
+                offset: 3,
+                opcode: 0x5b,
+                mnemonic: "jumpdest".into(),
+                immediate: None,
+                size: 1,
+            },
+        ];
+
+        let mut dasm = Disassembler::new();
+        dasm.write_all(&input).unwrap();
+
+        let actual: Vec<_> = dasm.records().collect();
+
+        assert_eq!(expected, actual.as_slice());
+        dasm.finish().unwrap();
+    }
+
+    #[test]
+    fn immediate_format() {
+        let word = hex!("00000000000000000000000000000000000000000000000000000000000000ab");
+
+        let default = ImmediateFormat::default();
+        assert_eq!(default.format(&[0xab, 0x01]), "0xab01");
+        assert_eq!(default.format(&[]), "0x");
+
+        let format = ImmediateFormat {
+            uppercase: true,
+            prefix: false,
+            group_words: true,
+        };
+        assert_eq!(format.format(&[0xab, 0x01]), "AB01");
+        assert_eq!(
+            format.format(&word),
+            "00000000_00000000_00000000_00000000_00000000_00000000_00000000_000000AB"
+        );
+
+        let mut dasm = Disassembler::new();
+        dasm.set_immediate_format(format);
+        dasm.write_all(&hex!("61abcd")).unwrap();
+
+        let actual: Vec<_> = dasm.records().collect();
+        assert_eq!(actual[0].immediate.as_deref(), Some("ABCD"));
+        dasm.finish().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn write_json() {
+        let input = hex!("600100");
+
+        let mut dasm = Disassembler::new();
+        dasm.write_all(&input).unwrap();
+
+        let mut out = Vec::new();
+        dasm.write_json(&mut out).unwrap();
+
+        let expected = concat!(
+            r#"[{"offset":0,"opcode":96,"mnemonic":"push1","immediate":"0x01","size":2},"#,
+            r#"{"offset":2,"opcode":0,"mnemonic":"stop","immediate":null,"size":1}]"#,
+        );
+
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        dasm.finish().unwrap();
+    }
+
     #[test]
     fn push5() {
         let input = hex!("640102030405");
@@ -215,4 +680,99 @@ mod tests {
         assert_eq!(expected, actual.as_slice());
         dasm.finish().unwrap();
     }
+
+    #[test]
+    fn cancun_by_default() {
+        // An invalid instruction before Prague, instead of `rjump`.
+        let input = hex!("e0 00");
+
+        let mut dasm = Disassembler::new();
+        dasm.write_all(&input).unwrap();
+
+        let actual: Vec<_> = dasm.ops().map(|off| off.item).collect();
+
+        assert_eq!(actual, [Op::from(InvalidE0), Op::from(Stop)]);
+        dasm.finish().unwrap();
+    }
+
+    #[test]
+    fn prague_rjumpv() {
+        use etk_ops::prague::{Op, RJumpV, Stop};
+
+        // rjumpv with three offsets; stop
+        let input = hex!("e2 02 0001 0002 fffa 00");
+
+        let mut dasm = Disassembler::<Op<[u8]>>::default();
+        dasm.write_all(&input[..1]).unwrap();
+        assert!(dasm.ops().next().is_none());
+
+        dasm.write_all(&input[1..]).unwrap();
+
+        let actual: Vec<_> = dasm.ops().collect();
+        let expected = [
+            Offset::new(0, Op::from(RJumpV(hex!("02 0001 0002 fffa").to_vec()))),
+            Offset::new(8, Op::from(Stop)),
+        ];
+
+        assert_eq!(expected, actual.as_slice());
+        dasm.finish().unwrap();
+    }
+
+    #[test]
+    fn prague_partial_rjumpv() {
+        let input = hex!("e2 01 0001");
+
+        let mut dasm = Disassembler::<prague::Op<[u8]>>::default();
+        dasm.write_all(&input).unwrap();
+        assert!(dasm.ops().next().is_none());
+
+        let err = dasm.finish().unwrap_err();
+        assert!(matches!(err, Error::Truncated { remaining, .. } if remaining.offset == 0));
+    }
+
+    #[test]
+    fn metadata() {
+        let code = hex!("6080 a164736f6c6343000814 000a");
+        assert_eq!(metadata_start(&code), Some(2));
+
+        let code = hex!("6080 a2646970667358 0000 0009");
+        assert_eq!(metadata_start(&code), Some(2));
+
+        assert_eq!(metadata_start(&hex!("6080 a1 00 0002")), None);
+        assert_eq!(metadata_start(&hex!("00ff")), None);
+        assert_eq!(metadata_start(&[]), None);
+    }
+
+    #[test]
+    fn data_after_exit() {
+        // push1 0; jump; (data)
+        assert_eq!(data_start(&hex!("600056 0102")), 3);
+
+        // stop; jumpdest; stop
+        assert_eq!(data_start(&hex!("00 5b00")), 3);
+
+        // stop; (truncated push2)
+        assert_eq!(data_start(&hex!("00 6101")), 1);
+
+        // push1 1; (truncated push2)
+        assert_eq!(data_start(&hex!("6001 6101")), 2);
+
+        // stop; (data); metadata
+        let code = hex!("00 ff a164736f6c6343000814 000a");
+        assert_eq!(data_start(&code), 1);
+    }
+
+    #[test]
+    fn source_with_data() {
+        let code = hex!("6001 00 6101 a164736f6c6343000814 000a");
+
+        let source = to_source(&code, true);
+        assert_eq!(
+            source,
+            "push1 0x01\nstop\n%bytes(hex(\"6101a164736f6c6343000814000a\"))\n"
+        );
+
+        let source = to_source(&code, false);
+        assert!(source.starts_with("push1 0x01\nstop\npush2 0x01a1\n"));
+    }
 }
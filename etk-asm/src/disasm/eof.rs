@@ -0,0 +1,556 @@
+//! Parsing of EVM Object Format (EOF) containers.
+//!
+//! Decoding an EOF container as a flat stream of instructions produces
+//! nonsense, since the header and data are interleaved with code. A
+//! [`Container`] splits the input into its sections, so each code section can
+//! be disassembled on its own.
+mod error {
+    use snafu::{Backtrace, Snafu};
+
+    use super::TYPE_SIZE;
+
+    /// Errors that may arise while parsing an EOF container.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// The input does not start with the EOF magic.
+        #[snafu(display("input does not start with the EOF magic `0xef00`"))]
+        #[non_exhaustive]
+        Magic {
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The container declares a version this parser doesn't understand.
+        #[snafu(display("unsupported EOF version {}", version))]
+        #[non_exhaustive]
+        UnsupportedVersion {
+            /// The version found in the header.
+            version: u8,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The input ended before the container was complete.
+        #[snafu(display("container truncated while reading {} at 0x{:x}", what, offset))]
+        #[non_exhaustive]
+        Truncated {
+            /// The part of the container being read.
+            what: &'static str,
+
+            /// The location where the input ended.
+            offset: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The header contains a section kind that wasn't expected.
+        #[snafu(display(
+            "expected section kind 0x{:02x} at 0x{:x}, found 0x{:02x}",
+            expected,
+            offset,
+            found
+        ))]
+        #[non_exhaustive]
+        SectionKind {
+            /// The expected section kind.
+            expected: u8,
+
+            /// The section kind that was found.
+            found: u8,
+
+            /// The location of the section kind.
+            offset: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The header doesn't declare any code sections.
+        #[snafu(display("container has no code sections"))]
+        #[non_exhaustive]
+        NoCodeSections {
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The size of the type section doesn't match the number of code
+        /// sections.
+        #[snafu(display(
+            "type section is {} bytes, but {} code sections need {} bytes",
+            size,
+            sections,
+            sections * TYPE_SIZE
+        ))]
+        #[non_exhaustive]
+        TypeSize {
+            /// The declared size of the type section.
+            size: usize,
+
+            /// The number of code sections.
+            sections: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// There are more bytes after the data section than declared.
+        #[snafu(display("unexpected bytes after the data section at 0x{:x}", offset))]
+        #[non_exhaustive]
+        TrailingBytes {
+            /// The location of the first unexpected byte.
+            offset: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+}
+
+use etk_ops::prague::Op;
+
+pub use self::error::Error;
+
+#[cfg(feature = "fs")]
+use serde::Serialize;
+
+use snafu::{ensure, OptionExt};
+
+#[cfg(feature = "fs")]
+use std::io;
+use std::io::Write;
+
+use super::{Disassembler, Offset};
+#[cfg(feature = "fs")]
+use super::{ImmediateFormat, Record};
+
+/// The bytes every EOF container starts with.
+pub const MAGIC: [u8; 2] = [0xef, 0x00];
+
+/// The only EOF version currently understood.
+pub const VERSION: u8 = 0x01;
+
+const KIND_TYPES: u8 = 0x01;
+const KIND_CODE: u8 = 0x02;
+const KIND_CONTAINER: u8 = 0x03;
+const KIND_DATA: u8 = 0x04;
+const TERMINATOR: u8 = 0x00;
+
+const TYPE_SIZE: usize = 4;
+
+/// Returns `true` if `bytes` starts with the EOF magic.
+pub fn is_eof(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Stack behaviour of a code section, from the type section.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Type {
+    /// Number of stack items the section consumes.
+    pub inputs: u8,
+
+    /// Number of stack items the section returns, or `0x80` if it never
+    /// returns.
+    pub outputs: u8,
+
+    /// Maximum stack height reached while executing the section.
+    pub max_stack_height: u16,
+}
+
+/// A code section of an EOF container.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CodeSection {
+    /// Position of the section among the code sections.
+    pub index: usize,
+
+    /// Location of the first byte of the section within the container.
+    pub offset: usize,
+
+    /// Stack behaviour of the section.
+    pub ty: Type,
+
+    /// The code itself.
+    pub code: Vec<u8>,
+}
+
+impl CodeSection {
+    /// The label identifying this section in disassembly, like `code_0`.
+    pub fn label(&self) -> String {
+        format!("code_{}", self.index)
+    }
+
+    /// Disassemble the section. Offsets are relative to the start of the
+    /// section.
+    pub fn ops(&self) -> Result<Vec<Offset<Op<[u8]>>>, super::Error> {
+        let mut dasm = Disassembler::<Op<[u8]>>::default();
+        dasm.write_all(&self.code).unwrap();
+        let ops = dasm.ops().collect();
+        dasm.finish()?;
+        Ok(ops)
+    }
+}
+
+/// A parsed EOF container.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::disasm::eof::Container;
+/// # use hex_literal::hex;
+///
+/// let input = hex!("ef0001 010004 0200010001 040000 00 00800000 00");
+/// let container = Container::parse(&input).unwrap();
+///
+/// assert_eq!(container.code.len(), 1);
+/// assert_eq!(container.code[0].label(), "code_0");
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Container {
+    /// The EOF version.
+    pub version: u8,
+
+    /// The code sections, in order.
+    pub code: Vec<CodeSection>,
+
+    /// Nested containers, with their location.
+    pub containers: Vec<Offset<Vec<u8>>>,
+
+    /// The data section, with its location. May be shorter than declared in
+    /// the header, as permitted for containers that haven't been deployed.
+    pub data: Offset<Vec<u8>>,
+}
+
+impl Container {
+    /// Parse the header and sections of an EOF container.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader { bytes, offset: 0 };
+
+        ensure!(is_eof(bytes), error::Magic);
+        reader.take(MAGIC.len(), "magic")?;
+
+        let version = reader.u8("version")?;
+        ensure!(version == VERSION, error::UnsupportedVersion { version });
+
+        reader.kind(KIND_TYPES)?;
+        let type_size = usize::from(reader.u16("type section size")?);
+
+        reader.kind(KIND_CODE)?;
+        let num_code = reader.u16("number of code sections")?;
+        ensure!(num_code > 0, error::NoCodeSections);
+        let code_sizes = (0..num_code)
+            .map(|_| reader.u16("code section size").map(usize::from))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut container_sizes = Vec::new();
+        if reader.peek() == Some(KIND_CONTAINER) {
+            reader.kind(KIND_CONTAINER)?;
+            let num_containers = reader.u16("number of container sections")?;
+            for _ in 0..num_containers {
+                container_sizes.push(reader.u32("container section size")? as usize);
+            }
+        }
+
+        reader.kind(KIND_DATA)?;
+        let data_size = usize::from(reader.u16("data section size")?);
+
+        reader.kind(TERMINATOR)?;
+
+        ensure!(
+            type_size == code_sizes.len() * TYPE_SIZE,
+            error::TypeSize {
+                size: type_size,
+                sections: code_sizes.len(),
+            }
+        );
+
+        let mut types = Vec::with_capacity(code_sizes.len());
+        for _ in 0..code_sizes.len() {
+            types.push(Type {
+                inputs: reader.u8("inputs")?,
+                outputs: reader.u8("outputs")?,
+                max_stack_height: reader.u16("max stack height")?,
+            });
+        }
+
+        let mut code = Vec::with_capacity(code_sizes.len());
+        for (index, (size, ty)) in code_sizes.into_iter().zip(types).enumerate() {
+            let offset = reader.offset;
+            code.push(CodeSection {
+                index,
+                offset,
+                ty,
+                code: reader.take(size, "code section")?.to_vec(),
+            });
+        }
+
+        let mut containers = Vec::with_capacity(container_sizes.len());
+        for size in container_sizes {
+            let offset = reader.offset;
+            let bytes = reader.take(size, "container section")?;
+            containers.push(Offset::new(offset, bytes.to_vec()));
+        }
+
+        let data_offset = reader.offset;
+        let data = &bytes[data_offset..];
+        ensure!(
+            data.len() <= data_size,
+            error::TrailingBytes {
+                offset: data_offset + data_size,
+            }
+        );
+
+        Ok(Self {
+            version,
+            code,
+            containers,
+            data: Offset::new(data_offset, data.to_vec()),
+        })
+    }
+
+    /// Disassemble each code section and write them to `out` as a JSON array
+    /// of objects, each with the section's `label`, `offset`, and `records`.
+    #[cfg(feature = "fs")]
+    pub fn write_json<W>(&self, out: W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        self.write_json_with_format(out, ImmediateFormat::default())
+    }
+
+    /// Like [`Container::write_json`], but writing immediates with `format`.
+    #[cfg(feature = "fs")]
+    pub fn write_json_with_format<W>(&self, out: W, format: ImmediateFormat) -> io::Result<()>
+    where
+        W: Write,
+    {
+        #[derive(Serialize)]
+        struct Section {
+            label: String,
+            offset: usize,
+            records: Vec<Record>,
+        }
+
+        let mut sections = Vec::with_capacity(self.code.len());
+        for section in &self.code {
+            let ops = section
+                .ops()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            sections.push(Section {
+                label: section.label(),
+                offset: section.offset,
+                records: ops
+                    .into_iter()
+                    .map(|off| Record::with_format(off, format))
+                    .collect(),
+            });
+        }
+
+        serde_json::to_writer(out, &sections)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.offset).copied()
+    }
+
+    fn take(&mut self, len: usize, what: &'static str) -> Result<&'a [u8], Error> {
+        let offset = self.offset;
+        let end = offset.checked_add(len);
+        let bytes = end
+            .and_then(|end| self.bytes.get(offset..end))
+            .context(error::Truncated {
+                what,
+                offset: self.bytes.len(),
+            })?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self, what: &'static str) -> Result<u8, Error> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u16(&mut self, what: &'static str) -> Result<u16, Error> {
+        let bytes = self.take(2, what)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self, what: &'static str) -> Result<u32, Error> {
+        let bytes = self.take(4, what)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn kind(&mut self, expected: u8) -> Result<(), Error> {
+        let offset = self.offset;
+        let found = self.u8("section kind")?;
+        ensure!(
+            found == expected,
+            error::SectionKind {
+                expected,
+                found,
+                offset,
+            }
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use etk_ops::prague::*;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn not_eof() {
+        let err = Container::parse(&hex!("6000")).unwrap_err();
+        assert_matches!(err, Error::Magic { .. });
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let err = Container::parse(&hex!("ef0002")).unwrap_err();
+        assert_matches!(err, Error::UnsupportedVersion { version: 2, .. });
+    }
+
+    #[test]
+    fn truncated_header() {
+        let err = Container::parse(&hex!("ef0001 010004 02")).unwrap_err();
+        assert_matches!(err, Error::Truncated { offset: 7, .. });
+    }
+
+    #[test]
+    fn wrong_section_kind() {
+        let err = Container::parse(&hex!("ef0001 020004")).unwrap_err();
+        assert_matches!(
+            err,
+            Error::SectionKind {
+                expected: 0x01,
+                found: 0x02,
+                offset: 3,
+                ..
+            }
+        );
+    }
+
+    #[test]
+    fn type_size_mismatch() {
+        let input = hex!("ef0001 010008 0200010001 040000 00 00800000 00");
+        let err = Container::parse(&input).unwrap_err();
+        assert_matches!(
+            err,
+            Error::TypeSize {
+                size: 8,
+                sections: 1,
+                ..
+            }
+        );
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let input = hex!("ef0001 010004 0200010001 040001 00 00800000 00 aa bb");
+        let err = Container::parse(&input).unwrap_err();
+        assert_matches!(err, Error::TrailingBytes { offset: 21, .. });
+    }
+
+    #[test]
+    fn two_code_sections_with_data() {
+        let input = hex!(
+            "ef0001 010008 02000200030001 040002 00"
+            "00800001 00000000"
+            "600100"
+            "00"
+            "abcd"
+        );
+
+        let container = Container::parse(&input).unwrap();
+
+        assert_eq!(container.version, 1);
+        assert_eq!(container.code.len(), 2);
+        assert!(container.containers.is_empty());
+        assert_eq!(container.data, Offset::new(29, hex!("abcd").to_vec()));
+
+        let first = &container.code[0];
+        assert_eq!(first.label(), "code_0");
+        assert_eq!(first.offset, 25);
+        assert_eq!(
+            first.ty,
+            Type {
+                inputs: 0,
+                outputs: 0x80,
+                max_stack_height: 1,
+            }
+        );
+        assert_eq!(
+            first.ops().unwrap(),
+            [
+                Offset::new(0, Op::from(Push1(hex!("01")))),
+                Offset::new(2, Op::from(Stop)),
+            ]
+        );
+
+        let second = &container.code[1];
+        assert_eq!(second.label(), "code_1");
+        assert_eq!(second.offset, 28);
+        assert_eq!(second.ops().unwrap(), [Offset::new(0, Op::from(Stop))]);
+    }
+
+    #[test]
+    fn truncated_data() {
+        let input = hex!("ef0001 010004 0200010001 040004 00 00800000 00 aa");
+        let container = Container::parse(&input).unwrap();
+        assert_eq!(container.data, Offset::new(20, vec![0xaa]));
+    }
+
+    #[test]
+    fn nested_container() {
+        let input = hex!(
+            "ef0001 010004 0200010001 03000100000014 040000 00"
+            "00800000"
+            "00"
+            "ef0001 010004 0200010001 040000 00 00800000 00"
+        );
+
+        let container = Container::parse(&input).unwrap();
+
+        assert_eq!(container.containers.len(), 1);
+        assert_eq!(container.containers[0].offset, 27);
+
+        let nested = Container::parse(&container.containers[0].item).unwrap();
+        assert_eq!(nested.code.len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn write_json() {
+        let input = hex!("ef0001 010004 0200010001 040000 00 00800000 00");
+        let container = Container::parse(&input).unwrap();
+
+        let mut out = Vec::new();
+        container.write_json(&mut out).unwrap();
+
+        let expected = concat!(
+            r#"[{"label":"code_0","offset":19,"records":["#,
+            r#"{"offset":0,"opcode":0,"mnemonic":"stop","immediate":null,"size":1}]}]"#,
+        );
+
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
@@ -0,0 +1,528 @@
+//! Strategies for finding the function a selector is dispatched to.
+//!
+//! [`%dispatch`] can find the arm matching a selector in a few ways:
+//!
+//!  - [`Strategy::Linear`] compares the selector with every arm, one after
+//!    another.
+//!  - [`Strategy::BinarySearch`] splits the sorted selectors in half until at
+//!    most four remain, which are then compared one after another.
+//!  - [`Strategy::Mask`] finds a window of bits that is different in every
+//!    selector, and jumps straight into a table of comparisons indexed by
+//!    those bits, so a call needs a single comparison.
+//!  - [`Strategy::PerfectHash`] searches for a multiplier that hashes every
+//!    selector into a different entry of a table like the one of
+//!    [`Strategy::Mask`], which is usually smaller, at the cost of a
+//!    multiplication.
+//!
+//! Which one is cheapest depends on the selectors: comparing them one after
+//! another is both small and cheap with a few functions, while a table uses
+//! the same gas for every function, but costs sixteen bytes for each of its
+//! entries. Unless a strategy is chosen with
+//! [`Assembler::set_dispatch_strategy`], the one with the lowest [estimated
+//! cost](estimate) is used, according to the model set with
+//! [`Assembler::set_dispatch_model`].
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_asm::dispatch::{choose, estimate, Strategy};
+//! use etk_asm::synth::CostModel;
+//!
+//! let few = [0x11111111, 0x22222222];
+//! assert_eq!(choose(&few, CostModel::Gas), Strategy::Linear);
+//!
+//! let many: Vec<u32> = (0..12).map(|i| i << 8).collect();
+//! assert_eq!(choose(&many, CostModel::Gas), Strategy::Mask);
+//! assert_eq!(choose(&many, CostModel::Bytes), Strategy::Linear);
+//!
+//! // A table has at most 256 entries, so more selectors can't use a mask.
+//! let too_many: Vec<u32> = (0..257).map(|i| i << 8).collect();
+//! assert_eq!(estimate(Strategy::Mask, &too_many), None);
+//! assert_eq!(estimate(Strategy::PerfectHash, &too_many), None);
+//! ```
+//!
+//! [`%dispatch`]: https://quilt.github.io/etk/ch02-lang/ch04-macros/ch01-builtins.html
+//! [`Assembler::set_dispatch_strategy`]: crate::asm::Assembler::set_dispatch_strategy
+//! [`Assembler::set_dispatch_model`]: crate::asm::Assembler::set_dispatch_model
+use crate::synth::{Cost, CostModel};
+
+use etk_ops::prague::{
+    Add, And, Dup1, Eq, Gt, Jump, JumpDest, JumpI, Mul, Op, Operation, Push0, Push2, Shl, Shr,
+};
+
+use std::collections::HashSet;
+use std::fmt;
+
+/// How `%dispatch` finds the arm matching a selector.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum Strategy {
+    /// Compare the selector with every arm, one after another.
+    Linear,
+
+    /// Split the sorted selectors in half until at most four remain, then
+    /// compare those one after another.
+    BinarySearch,
+
+    /// Jump into a table of comparisons, indexed by a window of bits of the
+    /// selector.
+    Mask,
+
+    /// Jump into a table of comparisons, indexed by the highest bits of the
+    /// selector multiplied by a number that keeps every selector apart.
+    PerfectHash,
+}
+
+impl Strategy {
+    /// Every strategy, in the order ties between them are broken.
+    pub const ALL: &'static [Strategy] = &[
+        Strategy::Linear,
+        Strategy::BinarySearch,
+        Strategy::Mask,
+        Strategy::PerfectHash,
+    ];
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Linear => write!(f, "linear"),
+            Self::BinarySearch => write!(f, "binary search"),
+            Self::Mask => write!(f, "mask"),
+            Self::PerfectHash => write!(f, "perfect hash"),
+        }
+    }
+}
+
+/// How many selectors a binary search compares one after another, before
+/// splitting them in half.
+pub(crate) const LINEAR_LIMIT: usize = 4;
+
+/// Entries of the tables of [`Strategy::Mask`] and [`Strategy::PerfectHash`]
+/// are `1 << SLOT_BITS` bytes long, which fits a comparison with a four byte
+/// selector.
+const SLOT_BITS: u32 = 4;
+
+/// The length of an entry of a table, in bytes.
+pub(crate) const SLOT_SIZE: usize = 1 << SLOT_BITS;
+
+/// The most bits a table is indexed by, which limits it to 256 entries.
+const MAX_TABLE_BITS: u32 = 8;
+
+/// The first multiplier [`Strategy::PerfectHash`] tries. Later ones are odd
+/// multiples of it, so none are tried twice.
+const HASH_MULTIPLIER: u32 = 0x9e37_79b9;
+
+/// How many multipliers [`Strategy::PerfectHash`] tries for each size of its
+/// table.
+const HASH_TRIES: u32 = 1 << 16;
+
+/// A window of bits of the selector, used by [`Strategy::Mask`] to index its
+/// table.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Mask {
+    /// Position of the lowest bit of the window.
+    pub(crate) shift: u32,
+
+    /// Width of the window.
+    pub(crate) bits: u32,
+}
+
+impl Mask {
+    /// The narrowest window that is different in every one of `selectors`,
+    /// and the lowest one among those as wide.
+    pub(crate) fn find(selectors: &[u32]) -> Option<Self> {
+        if selectors.is_empty() {
+            return None;
+        }
+
+        let narrowest = selectors.len().next_power_of_two().trailing_zeros();
+
+        (narrowest..=MAX_TABLE_BITS).find_map(|bits| {
+            (0..=32 - bits)
+                .map(|shift| Self { shift, bits })
+                .find(|mask| {
+                    let mut seen = HashSet::new();
+                    selectors.iter().all(|s| seen.insert(mask.index(*s)))
+                })
+        })
+    }
+
+    /// The number of entries in the table.
+    pub(crate) fn len(&self) -> usize {
+        1 << self.bits
+    }
+
+    /// The entry of the table for `selector`.
+    pub(crate) fn index(&self, selector: u32) -> usize {
+        ((u64::from(selector) >> self.shift) & ((1 << self.bits) - 1)) as usize
+    }
+
+    /// Instructions computing the position of the entry for the selector on
+    /// the stack, relative to the start of the table, and leaving the
+    /// selector below it.
+    pub(crate) fn offset_ops(&self) -> Vec<Op<[u8]>> {
+        let mut ops = vec![Op::from(Dup1)];
+        let mask = (1u64 << self.bits) - 1;
+
+        // When the window is high enough, shift it only part of the way, so
+        // the entry's position doesn't need another shift.
+        let (shift, mask, scale) = if self.shift >= SLOT_BITS {
+            (self.shift - SLOT_BITS, mask << SLOT_BITS, false)
+        } else {
+            (self.shift, mask, true)
+        };
+
+        if shift > 0 {
+            ops.extend([push(shift.into()), Op::from(Shr)]);
+        }
+
+        ops.extend([push(mask), Op::from(And)]);
+
+        if scale {
+            ops.extend([push(SLOT_BITS.into()), Op::from(Shl)]);
+        }
+
+        ops
+    }
+}
+
+/// A multiplier that hashes every selector into a different entry of the
+/// table of [`Strategy::PerfectHash`], which is indexed by the highest bits of
+/// the lowest four bytes of the product.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Hash {
+    /// The number the selector is multiplied by.
+    pub(crate) multiplier: u32,
+
+    /// How many bits of the product index the table.
+    pub(crate) bits: u32,
+}
+
+impl Hash {
+    /// The first multiplier found for the smallest table that every one of
+    /// `selectors` hashes into a different entry of.
+    pub(crate) fn find(selectors: &[u32]) -> Option<Self> {
+        if selectors.is_empty() {
+            return None;
+        }
+
+        let narrowest = selectors.len().next_power_of_two().trailing_zeros();
+
+        (narrowest..=MAX_TABLE_BITS).find_map(|bits| {
+            (0..HASH_TRIES)
+                .map(|i| Self {
+                    multiplier: HASH_MULTIPLIER.wrapping_mul(2 * i + 1),
+                    bits,
+                })
+                .find(|hash| {
+                    let mut seen = [false; 1 << MAX_TABLE_BITS];
+                    selectors
+                        .iter()
+                        .all(|s| !std::mem::replace(&mut seen[hash.index(*s)], true))
+                })
+        })
+    }
+
+    /// The number of entries in the table.
+    pub(crate) fn len(&self) -> usize {
+        1 << self.bits
+    }
+
+    /// The entry of the table for `selector`.
+    pub(crate) fn index(&self, selector: u32) -> usize {
+        match self.bits {
+            0 => 0,
+            bits => (selector.wrapping_mul(self.multiplier) >> (32 - bits)) as usize,
+        }
+    }
+
+    /// Instructions computing the position of the entry for the selector on
+    /// the stack, relative to the start of the table, and leaving the
+    /// selector below it.
+    pub(crate) fn offset_ops(&self) -> Vec<Op<[u8]>> {
+        // The product is shifted only part of the way, so the entry's
+        // position doesn't need another shift, and the bits above the lowest
+        // four bytes are masked off.
+        let mask = ((1u64 << self.bits) - 1) << SLOT_BITS;
+
+        vec![
+            Op::from(Dup1),
+            push(self.multiplier.into()),
+            Op::from(Mul),
+            push((32 - self.bits - SLOT_BITS).into()),
+            Op::from(Shr),
+            push(mask),
+            Op::from(And),
+        ]
+    }
+}
+
+/// The smallest push of `value`.
+pub(crate) fn push(value: u64) -> Op<[u8]> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().take_while(|b| **b == 0).count();
+
+    if start == bytes.len() {
+        return Op::from(Push0);
+    }
+
+    let mut encoded = vec![Op::<()>::push(bytes.len() - start).unwrap().code_byte()];
+    encoded.extend(&bytes[start..]);
+    Op::from_slice(&encoded).unwrap()
+}
+
+/// A push of a label, assumed to be two bytes wide.
+fn push_label() -> Op<[u8]> {
+    Op::from(Push2([0u8; 2]))
+}
+
+fn jumpdest() -> Cost {
+    Cost::of(&[Op::from(JumpDest)])
+}
+
+/// Compare the selector with `selector`, and jump if they are equal.
+fn compare(selector: u32) -> Cost {
+    Cost::of(&[
+        Op::from(Dup1),
+        push(selector.into()),
+        Op::from(Eq),
+        push_label(),
+        Op::from(JumpI),
+    ])
+}
+
+/// The size of a dispatcher, and the total gas used to reach all of its arms.
+#[derive(Debug, Clone, Copy, Default)]
+struct Total {
+    bytes: usize,
+    gas: u64,
+}
+
+fn linear(selectors: &[u32]) -> Total {
+    let mut total = Total::default();
+    let mut reached = 0;
+
+    for selector in selectors {
+        let cost = compare(*selector);
+        reached += cost.gas;
+        total.bytes += cost.bytes;
+        total.gas += reached;
+    }
+
+    total
+}
+
+fn binary_search(selectors: &[u32]) -> Total {
+    if selectors.len() <= LINEAR_LIMIT {
+        return linear(selectors);
+    }
+
+    let (lower, upper) = selectors.split_at(selectors.len() / 2);
+    let lower_len = lower.len();
+    let split = Cost::of(&[
+        Op::from(Dup1),
+        push(upper[0].into()),
+        Op::from(Gt),
+        push_label(),
+        Op::from(JumpI),
+    ]);
+    let skip = Cost::of(&[push_label(), Op::from(Jump), Op::from(JumpDest)]);
+
+    let upper = binary_search(upper);
+    let lower = binary_search(lower);
+
+    Total {
+        bytes: split.bytes + upper.bytes + skip.bytes + lower.bytes,
+        gas: split.gas * selectors.len() as u64
+            + upper.gas
+            + lower.gas
+            + jumpdest().gas * lower_len as u64,
+    }
+}
+
+/// A table of `len` entries, whose position is computed by `offset`.
+fn table(selectors: &[u32], mut offset: Vec<Op<[u8]>>, len: usize) -> Total {
+    offset.extend([push_label(), Op::from(Add), Op::from(Jump)]);
+    let jump = Cost::of(&offset);
+
+    let gas = selectors
+        .iter()
+        .map(|s| jump.gas + jumpdest().gas + compare(*s).gas)
+        .sum();
+
+    Total {
+        bytes: jump.bytes + SLOT_SIZE * len + jumpdest().bytes,
+        gas,
+    }
+}
+
+fn mask(selectors: &[u32]) -> Option<Total> {
+    let mask = Mask::find(selectors)?;
+    Some(table(selectors, mask.offset_ops(), mask.len()))
+}
+
+fn perfect_hash(selectors: &[u32]) -> Option<Total> {
+    let hash = Hash::find(selectors)?;
+    Some(table(selectors, hash.offset_ops(), hash.len()))
+}
+
+/// Estimate the cost of dispatching `selectors` with `strategy`, or `None`
+/// if `strategy` can't dispatch them.
+///
+/// The estimated size covers the instructions after the selector is read from
+/// the call data, and the estimated gas is the average used to reach an arm.
+/// Labels are assumed to be pushed with `push2`. `selectors` must be sorted,
+/// and without duplicates.
+pub fn estimate(strategy: Strategy, selectors: &[u32]) -> Option<Cost> {
+    let total = match strategy {
+        Strategy::Linear => linear(selectors),
+        Strategy::BinarySearch => {
+            let mut total = binary_search(selectors);
+            if selectors.len() > LINEAR_LIMIT {
+                total.bytes += jumpdest().bytes;
+            }
+            total
+        }
+        Strategy::Mask => mask(selectors)?,
+        Strategy::PerfectHash => perfect_hash(selectors)?,
+    };
+
+    Some(Cost {
+        bytes: total.bytes,
+        gas: total.gas / std::cmp::max(1, selectors.len()) as u64,
+    })
+}
+
+/// Choose the cheapest strategy for dispatching `selectors`, according to
+/// `model`. `selectors` must be sorted, and without duplicates.
+pub fn choose(selectors: &[u32], model: CostModel) -> Strategy {
+    Strategy::ALL
+        .iter()
+        .filter_map(|s| estimate(*s, selectors).map(|cost| (model.key(cost), *s)))
+        .min_by_key(|(key, _)| *key)
+        .map(|(_, strategy)| strategy)
+        .unwrap_or(Strategy::Linear)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_mask() {
+        let mask = Mask::find(&[0x00, 0x10, 0x20, 0x30]).unwrap();
+        assert_eq!(mask, Mask { shift: 4, bits: 2 });
+        assert_eq!(mask.index(0x20), 2);
+
+        let mask = Mask::find(&[0x1, 0x2, 0x3]).unwrap();
+        assert_eq!(mask, Mask { shift: 0, bits: 2 });
+
+        // Three selectors never fit in one bit.
+        let mask = Mask::find(&[0x0, 0x1, 0x3]).unwrap();
+        assert_eq!(mask.bits, 2);
+
+        assert_eq!(Mask::find(&[]), None);
+        assert_eq!(Mask::find(&[7]), Some(Mask { shift: 0, bits: 0 }));
+    }
+
+    #[test]
+    fn find_hash() {
+        // No window of bits tells single bits in different bytes apart.
+        let selectors = [0x1, 0x100, 0x1_0000, 0x100_0000];
+        assert_eq!(Mask::find(&selectors), None);
+
+        let hash = Hash::find(&selectors).unwrap();
+        assert_eq!(
+            hash,
+            Hash {
+                multiplier: 0xdaa6_6d2b,
+                bits: 2
+            }
+        );
+
+        let indices: Vec<_> = selectors.iter().map(|s| hash.index(*s)).collect();
+        assert_eq!(indices, [3, 2, 1, 0]);
+
+        assert_eq!(Hash::find(&[]), None);
+        assert_eq!(
+            Hash::find(&[7]),
+            Some(Hash {
+                multiplier: HASH_MULTIPLIER,
+                bits: 0
+            })
+        );
+    }
+
+    #[test]
+    fn offset_ops() {
+        // Windows above the size of an entry are only shifted part of the way.
+        let ops = Mask { shift: 8, bits: 2 }.offset_ops();
+        assert_eq!(
+            ops,
+            [
+                Op::from(Dup1),
+                push(4),
+                Op::from(Shr),
+                push(0x30),
+                Op::from(And),
+            ]
+        );
+
+        let ops = Mask { shift: 0, bits: 3 }.offset_ops();
+        assert_eq!(
+            ops,
+            [
+                Op::from(Dup1),
+                push(7),
+                Op::from(And),
+                push(4),
+                Op::from(Shl),
+            ]
+        );
+
+        // The product is shifted so its two highest bits index the table.
+        let hash = Hash {
+            multiplier: HASH_MULTIPLIER,
+            bits: 2,
+        };
+        assert_eq!(
+            hash.offset_ops(),
+            [
+                Op::from(Dup1),
+                push(HASH_MULTIPLIER.into()),
+                Op::from(Mul),
+                push(26),
+                Op::from(Shr),
+                push(0x30),
+                Op::from(And),
+            ]
+        );
+    }
+
+    #[test]
+    fn estimates() {
+        let selectors = [0x11111111, 0x22222222];
+
+        // `dup1 push4 eq push2 jumpi`, twice.
+        let linear = estimate(Strategy::Linear, &selectors).unwrap();
+        assert_eq!(linear.bytes, 22);
+        assert_eq!(linear.gas, (22 + 44) / 2);
+
+        // Binary search doesn't split so few selectors.
+        assert_eq!(estimate(Strategy::BinarySearch, &selectors), Some(linear));
+
+        let mask = estimate(Strategy::Mask, &selectors).unwrap();
+        // `dup1 push1 and push1 shl push2 add jump`, the table, and `jumpdest`.
+        assert_eq!(mask.bytes, 12 + 2 * SLOT_SIZE + 1);
+
+        // `dup1 push4 mul push1 shr push1 and push2 add jump`, which costs
+        // more than the mask for a table as large.
+        let hash = estimate(Strategy::PerfectHash, &selectors).unwrap();
+        assert_eq!(hash.bytes, 18 + 2 * SLOT_SIZE + 1);
+        assert!(hash.gas > mask.gas);
+
+        assert_eq!(choose(&selectors, CostModel::Gas), Strategy::Linear);
+        assert_eq!(choose(&[], CostModel::Bytes), Strategy::Linear);
+    }
+}
@@ -0,0 +1,505 @@
+//! Formatting assembly sources in a canonical style.
+//!
+//! [`format`] rewrites a source without changing the program it describes, so
+//! a team can keep every file in the same style, and check for it in CI with
+//! [`is_formatted`]:
+//!
+//!  - Statements in `%macro` and `%def` bodies, and in `{ ... }` blocks, are
+//!    indented by four spaces per level. Labels are indented one level less
+//!    than the statements around them.
+//!  - Comments at the end of consecutive lines line up, at column 24 or one
+//!    space past the longest statement.
+//!  - Mnemonics are lowercase.
+//!  - Hex literals have lowercase digits, and an even number of them. The
+//!    immediate of `pushN` has exactly `N` bytes, when its value fits.
+//!  - Runs of spaces become one space, commas are followed by a space, and
+//!    consecutive blank lines become one.
+//!
+//! The text of comments is kept as written.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_asm::fmt::format;
+//! # use etk_asm::ParseError;
+//!
+//! let src = "\
+//! %macro  zero( )
+//! PUSH1 0x000 # nothing
+//! push2 0xAB   # something
+//! %end
+//! ";
+//!
+//! let expected = "\
+//! %macro zero()
+//!     push1 0x00          # nothing
+//!     push2 0x00ab        # something
+//! %end
+//! ";
+//!
+//! assert_eq!(format(src)?, expected);
+//! # Result::<(), ParseError>::Ok(())
+//! ```
+use crate::parse::parse_recovering;
+use crate::ParseError;
+
+use etk_ops::prague::{Op, Operation};
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+const INDENT: &str = "    ";
+
+/// The column comments at the end of a line start at, unless a statement is
+/// longer.
+const COMMENT_COLUMN: usize = 24;
+
+#[derive(Debug)]
+struct Line {
+    indent: usize,
+    code: String,
+    comment: Option<String>,
+}
+
+/// Format `src` in the canonical style.
+///
+/// Fails if `src` doesn't parse, after lowercasing its mnemonics.
+pub fn format(src: &str) -> Result<String, ParseError> {
+    let src = lowercase_mnemonics(src);
+
+    let (_, errors) = parse_recovering(&src);
+    if !errors.is_empty() {
+        return Err(ParseError::combine(errors));
+    }
+
+    let mut lines = Vec::new();
+    let mut depth = 0usize;
+
+    for text in src.lines() {
+        let (statements, comment) = split(text);
+        let statements: Vec<_> = statements
+            .into_iter()
+            .map(|r| normalize(&text[r]))
+            .collect();
+        let code = statements.join("; ").trim().to_owned();
+
+        let (opens, closes, leading) = nesting(&code);
+        let mut indent = depth.saturating_sub(leading);
+        if statements.first().map_or(false, |s| is_label(s)) {
+            indent = indent.saturating_sub(1);
+        }
+        depth = (depth + opens).saturating_sub(closes);
+
+        lines.push(Line {
+            indent,
+            code,
+            comment: comment.map(|c| text[c].trim_end().to_owned()),
+        });
+    }
+
+    Ok(render(&lines))
+}
+
+/// Check whether `src` is already in the canonical style.
+pub fn is_formatted(src: &str) -> Result<bool, ParseError> {
+    Ok(format(src)? == src)
+}
+
+fn render(lines: &[Line]) -> String {
+    let mut output = String::new();
+    let mut blank = false;
+    let mut column = 0;
+
+    for (idx, line) in lines.iter().enumerate() {
+        let indent = INDENT.repeat(line.indent);
+
+        if line.code.is_empty() && line.comment.is_none() {
+            blank = !output.is_empty();
+            continue;
+        }
+
+        if blank {
+            output.push('\n');
+            blank = false;
+        }
+
+        output.push_str(&indent);
+        output.push_str(&line.code);
+
+        match line.comment {
+            Some(ref comment) if line.code.is_empty() => output.push_str(comment),
+            Some(ref comment) => {
+                // Comments at the end of consecutive lines share a column.
+                let starts_run = idx == 0 || !has_trailing_comment(&lines[idx - 1]);
+                if starts_run {
+                    column = lines[idx..]
+                        .iter()
+                        .take_while(|l| has_trailing_comment(l))
+                        .map(|l| width(l) + 1)
+                        .fold(COMMENT_COLUMN, usize::max);
+                }
+
+                let padding = column - width(line);
+                output.extend(std::iter::repeat(' ').take(padding));
+                output.push_str(comment);
+            }
+            None => (),
+        }
+
+        output.push('\n');
+    }
+
+    output
+}
+
+fn has_trailing_comment(line: &Line) -> bool {
+    !line.code.is_empty() && line.comment.is_some()
+}
+
+fn width(line: &Line) -> usize {
+    INDENT.len() * line.indent + line.code.chars().count()
+}
+
+/// Split `line` into the ranges of its statements, separated by `;`, and of
+/// its comment.
+fn split(line: &str) -> (Vec<Range<usize>>, Option<Range<usize>>) {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (idx, c) in line.char_indices() {
+        if quoted {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => (),
+            }
+            continue;
+        }
+
+        match c {
+            '"' => quoted = true,
+            ';' => {
+                statements.push(start..idx);
+                start = idx + 1;
+            }
+            '#' => {
+                statements.push(start..idx);
+                return (statements, Some(idx..line.len()));
+            }
+            _ => (),
+        }
+    }
+
+    statements.push(start..line.len());
+    (statements, None)
+}
+
+fn lowercase_mnemonics(src: &str) -> String {
+    let mnemonics: HashSet<String> = (0..=u8::MAX)
+        .map(Op::<()>::from)
+        .map(|op| op.mnemonic().to_owned())
+        .filter(|m| !m.starts_with("invalid_"))
+        .collect();
+
+    let mut output = String::with_capacity(src.len());
+
+    for line in src.split_inclusive('\n') {
+        let mut line = line.to_owned();
+        let (statements, _) = split(&line);
+
+        for range in statements {
+            let text = &line[range.clone()];
+            let start = range.start + (text.len() - text.trim_start().len());
+            let end = line[start..range.end]
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .map_or(range.end, |e| start + e);
+
+            let word = &line[start..end];
+            let lower = word.to_ascii_lowercase();
+            if word != lower && mnemonics.contains(&lower) && !line[end..].starts_with(':') {
+                line.replace_range(start..end, &lower);
+            }
+        }
+
+        output.push_str(&line);
+    }
+
+    output
+}
+
+/// Collapse whitespace, space out commas, and normalize hex literals.
+fn normalize(statement: &str) -> String {
+    let mut output = String::new();
+    let mut space = false;
+    let mut chars = statement.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == ' ' || c == '\t' {
+            space = !output.is_empty();
+            continue;
+        }
+
+        if space && !matches!(c, ',' | ')') && !output.ends_with('(') {
+            output.push(' ');
+        }
+        space = false;
+
+        match c {
+            '"' => {
+                output.push(c);
+                while let Some(c) = chars.next() {
+                    output.push(c);
+                    match c {
+                        '\\' => output.extend(chars.next()),
+                        '"' => break,
+                        _ => (),
+                    }
+                }
+            }
+            ',' => {
+                output.push(c);
+                space = true;
+            }
+            c if c.is_ascii_digit() && !output.ends_with(is_word_char) => {
+                let mut number = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !is_word_char(c) {
+                        break;
+                    }
+                    number.push(c);
+                    chars.next();
+                }
+                output.push_str(&hex_literal(&number).unwrap_or(number));
+            }
+            c => output.push(c),
+        }
+    }
+
+    push_width(&output).unwrap_or(output)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$')
+}
+
+/// `number` with lowercase digits, padded to an even number of them, if it is
+/// a hex literal.
+fn hex_literal(number: &str) -> Option<String> {
+    let digits = number.strip_prefix("0x")?;
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let width = digits.len() + digits.len() % 2;
+    Some(format!("0x{:0>1$}", digits.to_ascii_lowercase(), width))
+}
+
+/// `statement` with the immediate padded or trimmed to the size of the push,
+/// if it is a push of a hex literal that fits.
+fn push_width(statement: &str) -> Option<String> {
+    let rest = statement.strip_prefix("push")?;
+    let (size, literal) = rest.split_once(' ')?;
+    let size: usize = size.parse().ok()?;
+    let digits = literal.strip_prefix("0x")?;
+
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let digits = digits.trim_start_matches('0');
+    if digits.len() > 2 * size {
+        return None;
+    }
+
+    Some(format!("push{} 0x{:0>2$}", size, digits, 2 * size))
+}
+
+fn is_label(statement: &str) -> bool {
+    match statement.strip_suffix(':') {
+        Some(label) => !label.is_empty() && label.chars().all(is_word_char),
+        None => false,
+    }
+}
+
+/// Blocks opened and closed by `code`, and how many of the closed ones are
+/// closed before anything else on the line.
+fn nesting(code: &str) -> (usize, usize, usize) {
+    let mut opens = 0;
+    let mut closes = 0;
+    let mut leading = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (idx, c) in code.char_indices() {
+        if quoted {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => (),
+            }
+            continue;
+        }
+
+        let word = |w: &str| {
+            code[idx..].starts_with(w) && !code[idx + w.len()..].starts_with(is_word_char)
+        };
+
+        let closing = match c {
+            '"' => {
+                quoted = true;
+                continue;
+            }
+            '{' => {
+                opens += 1;
+                continue;
+            }
+            '}' => true,
+            '%' if word("%macro") || word("%def") => {
+                opens += 1;
+                continue;
+            }
+            '%' => word("%end"),
+            _ => continue,
+        };
+
+        if closing {
+            closes += 1;
+            if opens == 0 && code[..idx].trim().is_empty() {
+                leading += 1;
+            }
+        }
+    }
+
+    (opens, closes, leading)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::parse_asm;
+
+    use super::*;
+
+    #[test]
+    fn preserves_program() -> Result<(), ParseError> {
+        let src = "\
+push1\t0x1f
+PUSH2 0xABC;pop
+%bytes( 0x123 , 4 )
+%push(lbl+( 1 ))
+
+lbl:
+jumpdest
+";
+
+        let formatted = format(src)?;
+        assert_eq!(
+            formatted,
+            "\
+push1 0x1f
+push2 0x0abc; pop
+%bytes(0x0123, 4)
+%push(lbl+(1))
+
+lbl:
+jumpdest
+"
+        );
+
+        let lowered = src.replace("PUSH2 0xABC", "push2 0xABC");
+        assert_eq!(parse_asm(&formatted)?, parse_asm(&lowered)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn indents_blocks() -> Result<(), ParseError> {
+        let src = "\
+%scope runtime {
+push1 done
+jump
+  done:
+jumpdest
+%if has_push0 {
+push0
+} %else {
+push1 0
+}
+}
+";
+
+        let expected = "\
+%scope runtime {
+    push1 done
+    jump
+done:
+    jumpdest
+    %if has_push0 {
+        push0
+    } %else {
+        push1 0
+    }
+}
+";
+
+        assert_eq!(format(src)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn aligns_comments() -> Result<(), ParseError> {
+        let src = "\
+# A full line comment.
+push1 0x00 # a
+push1 some_very_long_label_name # b \"quoted\"; # c
+  # Indented.
+caller # d
+
+some_very_long_label_name:
+";
+
+        let expected = "\
+# A full line comment.
+push1 0x00                      # a
+push1 some_very_long_label_name # b \"quoted\"; # c
+# Indented.
+caller                  # d
+
+some_very_long_label_name:
+";
+
+        assert_eq!(format(src)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_strings() -> Result<(), ParseError> {
+        let src = "%string(\"a  ;#  0xAB\" )\n";
+        assert_eq!(format(src)?, "%string(\"a  ;#  0xAB\")\n");
+        Ok(())
+    }
+
+    #[test]
+    fn collapses_blank_lines() -> Result<(), ParseError> {
+        let src = "\n\npush0\n\n\n\npop\n\n";
+        assert_eq!(format(src)?, "push0\n\npop\n");
+        Ok(())
+    }
+
+    #[test]
+    fn idempotent() -> Result<(), ParseError> {
+        let src = include_str!("aa.etk");
+        let formatted = format(src)?;
+        assert!(is_formatted(&formatted)?);
+        assert_eq!(parse_asm(&formatted)?, parse_asm(src)?);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(format("push1 0x01\nfoo bar\n").is_err());
+    }
+}
@@ -0,0 +1,39 @@
+//! Static gas costs of instructions.
+//!
+//! The static cost of each instruction comes from [`Operation::gas`]. It's the
+//! part of an instruction's gas that doesn't depend on its operands or on the
+//! state: memory expansion, cold account and storage accesses, copied words,
+//! and refunds aren't included.
+use etk_ops::prague::{Op, Operation};
+
+/// The static gas cost of every instruction in `code`, decoded from the start.
+pub(crate) fn static_gas_of(code: &[u8]) -> u64 {
+    let mut gas = 0;
+    let mut offset = 0;
+
+    while offset < code.len() {
+        let op = Op::<()>::from(code[offset]);
+        gas += op.gas();
+        offset += op.size();
+    }
+
+    gas
+}
+
+#[cfg(test)]
+mod tests {
+    use etk_ops::prague::{Caller, Log2, Stop};
+
+    use super::*;
+
+    #[test]
+    fn static_gas_of_code() {
+        assert_eq!(Op::<()>::from(Stop).gas(), 0);
+        assert_eq!(Op::<()>::from(Caller).gas(), 2);
+        assert_eq!(Op::<()>::from(Log2).gas(), 1125);
+
+        // push1 1; push1 2; add
+        assert_eq!(static_gas_of(&[0x60, 0x01, 0x60, 0x02, 0x01]), 9);
+        assert_eq!(static_gas_of(&[]), 0);
+    }
+}
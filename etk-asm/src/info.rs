@@ -0,0 +1,118 @@
+//! Information about how this assembler was built.
+//!
+//! Tools that store assembled code can record [`build_info`] next to it, and
+//! compare it later to find out whether the same assembler would still produce
+//! the same code.
+use etk_ops::prague::{Op, Operation};
+
+#[cfg(feature = "fs")]
+use serde::{Deserialize, Serialize};
+
+use sha3::{Digest, Keccak256};
+
+/// The fork whose instructions are assembled.
+pub const TARGET_FORK: &str = "prague";
+
+/// Every fork with an instruction table in `etk-ops`, oldest first.
+pub const FORKS: &[&str] = &["london", "shanghai", "cancun", "prague"];
+
+/// A description of the assembler, returned by [`build_info`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct BuildInfo {
+    /// Version of the `etk-asm` crate.
+    pub version: String,
+
+    /// The fork whose instructions are assembled. See [`TARGET_FORK`].
+    pub target_fork: String,
+
+    /// Every fork with an instruction table. See [`FORKS`].
+    pub forks: Vec<String>,
+
+    /// The optional cargo features this crate was built with.
+    pub features: Vec<String>,
+
+    /// Keccak-256 hash, in hexadecimal, of the instruction table of the
+    /// target fork: the opcode, mnemonic, immediate size, stack effects,
+    /// static gas, and control flow flags of every instruction.
+    pub opcodes: String,
+}
+
+/// Describe this assembler.
+///
+/// ## Example
+///
+/// ```rust
+/// let info = etk_asm::build_info();
+///
+/// assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+/// assert_eq!(info.target_fork, "prague");
+/// assert_eq!(info.opcodes.len(), 64);
+/// ```
+pub fn build_info() -> BuildInfo {
+    let mut features = Vec::new();
+
+    if cfg!(feature = "cli") {
+        features.push("cli".to_owned());
+    }
+
+    if cfg!(feature = "solc") {
+        features.push("solc".to_owned());
+    }
+
+    if cfg!(feature = "backtraces") {
+        features.push("backtraces".to_owned());
+    }
+
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        target_fork: TARGET_FORK.to_owned(),
+        forks: FORKS.iter().map(|f| f.to_string()).collect(),
+        features,
+        opcodes: opcode_table_hash(),
+    }
+}
+
+fn opcode_table_hash() -> String {
+    let mut hasher = Keccak256::new();
+
+    for code in 0..=u8::MAX {
+        let op = Op::<()>::from(code);
+
+        hasher.update([code]);
+        hasher.update(op.mnemonic().as_bytes());
+        hasher.update([0]);
+        hasher.update((op.extra_len() as u64).to_be_bytes());
+        hasher.update((op.pops() as u64).to_be_bytes());
+        hasher.update((op.pushes() as u64).to_be_bytes());
+        hasher.update(op.gas().to_be_bytes());
+        hasher.update([
+            op.is_jump() as u8,
+            op.is_jump_target() as u8,
+            op.is_exit() as u8,
+        ]);
+    }
+
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info_is_stable() {
+        let info = build_info();
+        assert_eq!(info, build_info());
+        assert!(info.forks.contains(&info.target_fork));
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn build_info_json() {
+        let info = build_info();
+        let json = serde_json::to_string(&info).unwrap();
+        let parsed: BuildInfo = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, info);
+    }
+}
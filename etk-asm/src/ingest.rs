@@ -3,8 +3,13 @@
 //! See the [`Ingest`] documentation for examples and more information.
 mod error {
     use crate::asm::Error as AssembleError;
+    use crate::ops::Expression;
+    use crate::policy;
+    use crate::profile::Violation;
     use crate::ParseError;
 
+    use etk_ops::prague::Op;
+
     use snafu::{Backtrace, Snafu};
 
     use std::path::PathBuf;
@@ -80,12 +85,137 @@ mod error {
             path: PathBuf,
 
             /// The underlying source of this error.
-            source: Box<dyn std::error::Error>,
+            source: Box<dyn std::error::Error + Send + Sync>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A name given to `%export` isn't declared by the imported file.
+        #[snafu(display(
+            "`{}` is exported, but isn't a label or macro declared in `{}`",
+            name,
+            path.display()
+        ))]
+        #[non_exhaustive]
+        UnknownExport {
+            /// The exported name.
+            name: String,
+
+            /// The imported file.
+            path: PathBuf,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// `%runtime_start` appeared more than once, or somewhere other than
+        /// the top level of the file being assembled.
+        #[snafu(display(
+            "`%runtime_start` in `{}` must appear once, at the top level of the assembled file",
+            path.display()
+        ))]
+        #[non_exhaustive]
+        MisplacedRuntimeStart {
+            /// The file containing the misplaced `%runtime_start`.
+            path: PathBuf,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// Extern labels were never declared by any enclosing scope.
+        #[snafu(display("extern labels `{:?}` were never declared", labels))]
+        #[non_exhaustive]
+        UnresolvedExterns {
+            /// The extern labels.
+            labels: Vec<String>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// An extern label left for a library isn't pushed as a 20 byte
+        /// address, so it can't be written as a library placeholder.
+        #[snafu(display(
+            "extern label `{}` must be pushed with push20 to be a library placeholder, not {}",
+            label,
+            spec
+        ))]
+        #[non_exhaustive]
+        PlaceholderWidth {
+            /// The extern label.
+            label: String,
+
+            /// The push using the label.
+            spec: Op<()>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The assembled code exceeds the limits of the chain profile.
+        #[snafu(display(
+            "code doesn't fit profile `{}`: {}",
+            profile,
+            violations
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; ")
+        ))]
+        #[non_exhaustive]
+        ProfileViolation {
+            /// The name of the profile.
+            profile: String,
+
+            /// Every limit the code exceeds.
+            violations: Vec<Violation>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The source or the assembled code breaks the rules of the
+        /// organization's policy.
+        #[snafu(display(
+            "code violates policy `{}`: {}{}",
+            policy,
+            violations
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; "),
+            url.as_ref().map(|u| format!(" (see {})", u)).unwrap_or_default()
+        ))]
+        #[non_exhaustive]
+        PolicyViolation {
+            /// The name of the policy.
+            policy: String,
+
+            /// Where the policy is documented, if it says.
+            url: Option<String>,
+
+            /// Every rule the code breaks.
+            violations: Vec<policy::Violation>,
 
             /// The location of the error.
             backtrace: Backtrace,
         },
 
+        /// The condition of an `%if` block couldn't be evaluated. Conditions
+        /// can't use labels, or expression macros declared after them.
+        #[snafu(display("the condition `{}` of `%if` is invalid", expr))]
+        #[non_exhaustive]
+        InvalidCondition {
+            /// The condition.
+            expr: Expression,
+
+            /// The reason it couldn't be evaluated.
+            #[snafu(backtrace)]
+            source: AssembleError,
+        },
+
         /// A recursion limit was reached while including or importing a file.
         #[snafu(display("too many levels of recursion/includes"))]
         #[non_exhaustive]
@@ -93,20 +223,198 @@ mod error {
             /// The location of the error.
             backtrace: Backtrace,
         },
+
+        /// An artifact included with `%include_artifact` isn't valid JSON.
+        #[cfg(feature = "fs")]
+        #[snafu(display("artifact `{}` is invalid: {}", path.to_string_lossy(), source))]
+        #[non_exhaustive]
+        InvalidArtifact {
+            /// Path to the offending file.
+            path: PathBuf,
+
+            /// The underlying source of this error.
+            source: serde_json::Error,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// An artifact included with `%include_artifact` doesn't have the
+        /// requested bytecode field.
+        #[snafu(display(
+            "artifact `{}` has no bytecode in field `{}`",
+            path.to_string_lossy(),
+            field
+        ))]
+        #[non_exhaustive]
+        ArtifactField {
+            /// Path to the offending file.
+            path: PathBuf,
+
+            /// The requested field.
+            field: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// `%include_artifact` was used without the `fs` feature.
+        #[cfg(not(feature = "fs"))]
+        #[snafu(display(
+            "including `{}` requires the `fs` feature",
+            path.to_string_lossy()
+        ))]
+        #[non_exhaustive]
+        ArtifactDisabled {
+            /// Path to the artifact.
+            path: PathBuf,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// `%include_sol_runtime` was used without the `solc` feature.
+        #[snafu(display(
+            "including `{}` requires the `solc` feature",
+            path.to_string_lossy()
+        ))]
+        #[non_exhaustive]
+        SolcDisabled {
+            /// Path to the Solidity source.
+            path: PathBuf,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The Solidity compiler failed, or its output couldn't be read.
+        #[snafu(display("compiling `{}` failed: {}", path.to_string_lossy(), message))]
+        #[non_exhaustive]
+        Solc {
+            /// Path to the Solidity source.
+            path: PathBuf,
+
+            /// The errors reported by the compiler.
+            message: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The Solidity compiler didn't produce the requested contract.
+        #[snafu(display(
+            "contract `{}` not found in `{}`",
+            contract,
+            path.to_string_lossy()
+        ))]
+        #[non_exhaustive]
+        SolcContract {
+            /// Path to the Solidity source.
+            path: PathBuf,
+
+            /// The name of the contract.
+            contract: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A feature whose output can differ between machines was used while
+        /// reproducible builds were required.
+        #[snafu(display("{} in `{}` isn't reproducible", feature, path.display()))]
+        #[non_exhaustive]
+        NotReproducible {
+            /// The feature, like `%include_sol_runtime`.
+            feature: String,
+
+            /// The file using the feature.
+            path: PathBuf,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+
+    impl Error {
+        /// Extended help for this error, describing its likely causes and how
+        /// to fix them, if there is any.
+        ///
+        /// See [`AssembleError::explanation`].
+        pub fn explanation(&self) -> Option<&'static str> {
+            match self {
+                Self::Assemble { source } => source.explanation(),
+                Self::RecursionLimit { .. } => Some(RECURSION_LIMIT),
+                _ => None,
+            }
+        }
     }
+
+    const RECURSION_LIMIT: &str = "\
+Too many files were included or imported inside each other.
+
+This almost always means a file includes or imports itself, either directly or
+through other files (for example, `a.etk` imports `b.etk`, which imports
+`a.etk`).
+
+To fix it, follow the chain of `%include` and `%import` directives starting
+from the file being assembled, and remove the one that leads back to an earlier
+file. Code shared by several files can be moved into its own file, imported by
+each of them.";
 }
 
-use crate::asm::{Assembler, RawOp};
-use crate::ast::Node;
-use crate::parse::parse_asm;
+use crate::asm::{invalid_expression, Assembler, RawOp};
+use crate::ast::{Node, CODE_END, CODE_SIZE, RUNTIME_START};
+#[cfg(feature = "fs")]
+use crate::debug::SymbolFile;
+use crate::debug::{DebugInfo, LinkReference, Location};
+use crate::diagnostic::{Diagnostic, Source};
+use crate::dispatch::Strategy;
+use crate::info::TARGET_FORK;
+#[cfg(feature = "fs")]
+use crate::link;
+use crate::ops::{AbstractOp, Context, Expression, MacroDefinition, Terminal};
+use crate::optimize::{Optimization, OptimizationLevel, Pass};
+use crate::parse::parse_asm_spanned;
+#[cfg(feature = "fs")]
+use crate::parse::parse_selectors;
+use crate::patch::Patches;
+use crate::policy::Policy;
+use crate::profile::{CodeKind, Profile};
+use crate::synth::{CostModel, Substitution};
+use crate::trace::Trace;
+use crate::verify::{Expected, Mismatch};
+#[cfg(feature = "fs")]
+use crate::vfs::DiskFiles;
+use crate::vfs::FileProvider;
+#[cfg(not(feature = "fs"))]
+use crate::vfs::MemoryFiles;
+use crate::warning::{Lint, Warning};
+use crate::ParseError;
 
 pub use self::error::Error;
 
+use indexmap::IndexMap;
+
+use num_bigint::Sign;
+
 use snafu::{ensure, ResultExt};
 
-use std::fs::{read_to_string, File};
-use std::io::{self, Read, Write};
+use etk_ops::prague::Operation;
+
+#[cfg(feature = "fs")]
+use sha3::{Digest, Keccak256};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+#[cfg(feature = "fs")]
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+#[cfg(feature = "solc")]
+use std::process::Command;
+use std::sync::Arc;
+#[cfg(feature = "fs")]
+use std::sync::Mutex;
 
 #[derive(Debug, Clone)]
 struct Root {
@@ -115,7 +423,7 @@ struct Root {
 }
 
 impl Root {
-    fn new(mut file: PathBuf) -> Result<Self, Error> {
+    fn new(files: &dyn FileProvider, mut file: PathBuf) -> Result<Self, Error> {
         // Pop the filename.
         if !file.pop() {
             return Err(io::Error::from(io::ErrorKind::NotFound)).context(error::Io {
@@ -124,20 +432,20 @@ impl Root {
             });
         }
 
-        let file = std::env::current_dir()
+        Self::directory(files, file)
+    }
+
+    fn directory(files: &dyn FileProvider, file: PathBuf) -> Result<Self, Error> {
+        let file = files
+            .current_dir()
             .context(error::Io {
                 message: "getting cwd",
                 path: None,
             })?
             .join(file);
 
-        let metadata = file.metadata().with_context(|_| error::Io {
-            message: "getting metadata",
-            path: file.clone(),
-        })?;
-
         // Root must be a directory.
-        if !metadata.is_dir() {
+        if !files.is_dir(&file) {
             let err = io::Error::from(io::ErrorKind::NotFound);
             return Err(err).context(error::Io {
                 message: "root is not directory",
@@ -145,7 +453,7 @@ impl Root {
             });
         }
 
-        let canonicalized = std::fs::canonicalize(&file).with_context(|_| error::Io {
+        let canonicalized = files.canonicalize(&file).with_context(|_| error::Io {
             message: "canonicalizing root",
             path: file.clone(),
         })?;
@@ -156,13 +464,13 @@ impl Root {
         })
     }
 
-    fn check<P>(&self, path: P) -> Result<(), Error>
+    fn check<P>(&self, files: &dyn FileProvider, path: P) -> Result<(), Error>
     where
         P: AsRef<Path>,
     {
         let path = path.as_ref();
 
-        let canonicalized = std::fs::canonicalize(path).with_context(|_| error::Io {
+        let canonicalized = files.canonicalize(path).with_context(|_| error::Io {
             message: "canonicalizing include/import",
             path: path.to_owned(),
         })?;
@@ -182,15 +490,42 @@ impl Root {
 
 #[derive(Debug)]
 struct Program {
+    files: Arc<dyn FileProvider>,
     root: Option<Root>,
     sources: Vec<PathBuf>,
+
+    /// Directories searched for included and imported files that aren't found
+    /// next to the file including them.
+    include_paths: Vec<Root>,
+
+    /// Names of the `%scope` blocks read since the start of the enclosing
+    /// include, like `runtime` or `runtime.inner`.
+    scopes: Vec<String>,
+
+    /// Names given to `%export` since the start of the enclosing import or
+    /// include.
+    exports: Vec<String>,
+
+    /// Names exported by the files imported so far, which aren't reported as
+    /// unused.
+    exported: Vec<String>,
+
+    /// Expression macros declared so far in the enclosing scope, which `%if`
+    /// conditions may use.
+    macros: HashMap<String, MacroDefinition>,
 }
 
 impl Program {
-    fn new(path: PathBuf) -> Self {
+    fn new(files: Arc<dyn FileProvider>, path: PathBuf) -> Self {
         Self {
-            root: Root::new(path.clone()).ok(),
+            root: Root::new(&*files, path.clone()).ok(),
+            files,
             sources: vec![path],
+            include_paths: Vec::new(),
+            scopes: Vec::new(),
+            exports: Vec::new(),
+            exported: Vec::new(),
+            macros: HashMap::new(),
         }
     }
 
@@ -203,13 +538,20 @@ impl Program {
                 Some(s) => s,
                 None => Path::new("./"),
             };
-            let candidate = dir.join(path);
-            root.check(&candidate)?;
+            let mut candidate = dir.join(path);
+            if self.files.exists(&candidate) {
+                self.check(root, &candidate)?;
+            } else if let Some(found) = self.search(path)? {
+                candidate = found;
+            } else {
+                // Fails with the error for the path next to the includer.
+                root.check(&*self.files, &candidate)?;
+            }
             self.sources.push(candidate.clone());
             candidate
         } else {
             assert!(self.sources.is_empty());
-            self.root = Some(Root::new(path.to_owned())?);
+            self.root = Some(Root::new(&*self.files, path.to_owned())?);
             path.clone()
         };
 
@@ -219,6 +561,73 @@ impl Program {
     fn pop_path(&mut self) {
         self.sources.pop();
     }
+
+    /// Check that `path` is in the root directory, or in one of the include
+    /// paths.
+    fn check(&self, root: &Root, path: &Path) -> Result<(), Error> {
+        match root.check(&*self.files, path) {
+            Err(Error::DirectoryTraversal { .. })
+                if self
+                    .include_paths
+                    .iter()
+                    .any(|i| i.check(&*self.files, path).is_ok()) =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Find `path` in the first include path that has it.
+    fn search(&self, path: &Path) -> Result<Option<PathBuf>, Error> {
+        for include_path in self.include_paths.iter() {
+            let candidate = include_path.original.join(path);
+            if self.files.exists(&candidate) {
+                include_path.check(&*self.files, &candidate)?;
+                return Ok(Some(candidate));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Parsed sources, shared between instances of [`Ingest`] so each distinct
+/// source is only parsed once.
+#[cfg(feature = "fs")]
+#[derive(Debug, Default)]
+pub(crate) struct ParseCache {
+    parsed: Mutex<HashMap<String, Vec<(Node, Range<usize>)>>>,
+}
+
+#[cfg(feature = "fs")]
+impl ParseCache {
+    fn parse(&self, src: &str) -> Result<Vec<(Node, Range<usize>)>, ParseError> {
+        if let Some(nodes) = self.parsed.lock().unwrap().get(src) {
+            return Ok(nodes.clone());
+        }
+
+        let nodes = parse_asm_spanned(src)?;
+        self.parsed
+            .lock()
+            .unwrap()
+            .insert(src.to_owned(), nodes.clone());
+        Ok(nodes)
+    }
+
+    /// Forget every parse whose source doesn't satisfy `keep`.
+    #[cfg(feature = "fs")]
+    pub(crate) fn retain<F>(&self, mut keep: F)
+    where
+        F: FnMut(&str) -> bool,
+    {
+        self.parsed.lock().unwrap().retain(|src, _| keep(src));
+    }
+
+    #[cfg(all(test, feature = "fs"))]
+    pub(crate) fn len(&self) -> usize {
+        self.parsed.lock().unwrap().len()
+    }
 }
 
 /// A high-level interface for assembling files into EVM bytecode.
@@ -249,416 +658,3706 @@ impl Program {
 #[derive(Debug)]
 pub struct Ingest<W> {
     output: W,
+    warnings: Vec<Warning>,
+    strict: bool,
+    promoted: Vec<Lint>,
+    synthesis: Option<CostModel>,
+    dispatch_strategy: Option<Strategy>,
+    dispatch_model: Option<CostModel>,
+    substitutions: Vec<Substitution>,
+    passes: Vec<Pass>,
+    optimizations: Vec<Optimization>,
+    macro_depth_limit: Option<usize>,
+    macro_details: bool,
+    reproducible: bool,
+    trace: Option<Trace>,
+    #[cfg(feature = "fs")]
+    trace_file: Option<PathBuf>,
+    pinned_labels: Vec<(String, usize)>,
+    #[cfg(feature = "fs")]
+    cache: Option<Arc<ParseCache>>,
+    files: Arc<dyn FileProvider>,
+    include_paths: Vec<PathBuf>,
+    dependencies: Vec<PathBuf>,
+    texts: BTreeMap<usize, String>,
+    debug_info: DebugInfo,
+    written: usize,
+    #[cfg(feature = "fs")]
+    symbol_file: Option<PathBuf>,
+    #[cfg(feature = "fs")]
+    artifact_file: Option<PathBuf>,
+    #[cfg(feature = "fs")]
+    code: Vec<u8>,
+    #[cfg(feature = "fs")]
+    hashes_file: Option<PathBuf>,
+    #[cfg(feature = "fs")]
+    hashes: BTreeMap<String, String>,
+    patches: Patches,
+    #[cfg(feature = "fs")]
+    patches_file: Option<PathBuf>,
+    runtime: Option<Vec<u8>>,
+    link_references: bool,
+    library_placeholders: bool,
+    links: BTreeMap<String, BTreeMap<String, Vec<LinkReference>>>,
+    profile: Option<Profile>,
+    code_kind: CodeKind,
+    policy: Option<Policy>,
+    #[cfg(feature = "solc")]
+    solc: PathBuf,
 }
 
 impl<W> Ingest<W> {
     /// Make a new `Ingest` that writes assembled bytes to `output`.
+    ///
+    /// Files are read from the real filesystem with the `fs` feature, and
+    /// otherwise from an empty [`MemoryFiles`](crate::vfs::MemoryFiles) until
+    /// [`Ingest::set_file_provider`] is called.
     pub fn new(output: W) -> Self {
-        Self { output }
+        Self {
+            output,
+            warnings: Vec::new(),
+            strict: false,
+            promoted: Vec::new(),
+            synthesis: None,
+            dispatch_strategy: None,
+            dispatch_model: None,
+            substitutions: Vec::new(),
+            passes: Vec::new(),
+            optimizations: Vec::new(),
+            macro_depth_limit: None,
+            macro_details: false,
+            reproducible: false,
+            trace: None,
+            #[cfg(feature = "fs")]
+            trace_file: None,
+            pinned_labels: Vec::new(),
+            #[cfg(feature = "fs")]
+            cache: None,
+            files: default_files(),
+            include_paths: Vec::new(),
+            dependencies: Vec::new(),
+            texts: BTreeMap::new(),
+            debug_info: DebugInfo::default(),
+            written: 0,
+            #[cfg(feature = "fs")]
+            symbol_file: None,
+            #[cfg(feature = "fs")]
+            artifact_file: None,
+            #[cfg(feature = "fs")]
+            code: Vec::new(),
+            #[cfg(feature = "fs")]
+            hashes_file: None,
+            #[cfg(feature = "fs")]
+            hashes: BTreeMap::new(),
+            patches: Patches::new(),
+            #[cfg(feature = "fs")]
+            patches_file: None,
+            runtime: None,
+            link_references: false,
+            library_placeholders: false,
+            links: BTreeMap::new(),
+            profile: None,
+            code_kind: CodeKind::default(),
+            policy: None,
+            #[cfg(feature = "solc")]
+            solc: PathBuf::from("solc"),
+        }
     }
-}
 
-impl<W> Ingest<W>
-where
-    W: Write,
-{
-    /// Assemble instructions from the file located at `path`.
-    pub fn ingest_file<P>(&mut self, path: P) -> Result<(), Error>
-    where
-        P: Into<PathBuf>,
-    {
-        let path = path.into();
+    /// Promote warnings to errors.
+    ///
+    /// See [`Assembler::set_strict`].
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
 
-        let mut file = File::open(&path).with_context(|_| error::Io {
-            message: "opening source",
-            path: path.clone(),
-        })?;
-        let mut text = String::new();
-        file.read_to_string(&mut text).with_context(|_| error::Io {
-            message: "reading source",
-            path: path.clone(),
-        })?;
+    /// Promote warnings of the kinds in `lints` to errors.
+    ///
+    /// See [`Assembler::set_promoted`].
+    pub fn set_promoted(&mut self, lints: &[Lint]) {
+        self.promoted = lints.to_vec();
+    }
 
-        self.ingest(path, &text)?;
-        Ok(())
+    /// Warnings collected from every program ingested so far.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
     }
 
-    /// Assemble instructions from `src` as if they were read from a file located
-    /// at `path`.
-    pub fn ingest<P>(&mut self, path: P, src: &str) -> Result<(), Error>
-    where
-        P: Into<PathBuf>,
-    {
-        let mut program = Program::new(path.into());
-        let nodes = self.preprocess(&mut program, src)?;
-        let mut asm = Assembler::new();
-        let raw = asm.assemble(&nodes)?;
+    /// Replace pushes of constants with cheaper instruction sequences, using
+    /// only the instructions of the profile (see [`Ingest::set_profile`]).
+    ///
+    /// See [`Assembler::set_synthesis`].
+    pub fn set_synthesis(&mut self, model: Option<CostModel>) {
+        self.synthesis = model;
+    }
 
-        self.output.write_all(&raw).context(error::Io {
-            message: "writing output",
-            path: None,
-        })?;
+    /// Make `%dispatch` use `strategy`, or `None` to use the cheapest.
+    ///
+    /// See [`Assembler::set_dispatch_strategy`].
+    pub fn set_dispatch_strategy(&mut self, strategy: Option<Strategy>) {
+        self.dispatch_strategy = strategy;
+    }
 
-        Ok(())
+    /// Compare the strategies `%dispatch` can use according to `model`.
+    ///
+    /// See [`Assembler::set_dispatch_model`].
+    pub fn set_dispatch_model(&mut self, model: CostModel) {
+        self.dispatch_model = Some(model);
     }
 
-    fn preprocess(&mut self, program: &mut Program, src: &str) -> Result<Vec<RawOp>, Error> {
-        let nodes = parse_asm(src).with_context(|_| error::Parse {
-            path: program.sources.last().unwrap().clone(),
-        })?;
-        let mut raws = Vec::new();
-        for node in nodes {
-            match node {
-                Node::Op(op) => {
-                    raws.push(RawOp::Op(op));
-                }
-                Node::Import(imp_path) => {
-                    let new_raws = self.resolve_and_ingest(program, imp_path)?;
-                    raws.extend(new_raws);
-                }
-                Node::Include(inc_path) => {
-                    let inc_raws = self.resolve_and_ingest(program, inc_path)?;
-                    raws.push(RawOp::Scope(inc_raws));
-                }
-                Node::IncludeHex(hex_path) => {
-                    let file = std::fs::read_to_string(&hex_path).with_context(|_| error::Io {
-                        message: "reading hex include",
-                        path: hex_path.to_owned(),
-                    })?;
+    /// Pushes replaced in every program ingested so far.
+    pub fn substitutions(&self) -> &[Substitution] {
+        &self.substitutions
+    }
 
-                    let raw = hex::decode(file.trim())
-                        .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
-                        .context(error::InvalidHex {
-                            path: hex_path.to_owned(),
-                        })?;
+    /// Run the optimization `passes` on every program ingested.
+    ///
+    /// See [`Assembler::set_optimizations`].
+    pub fn set_optimizations(&mut self, passes: &[Pass]) {
+        self.passes = passes.to_vec();
+    }
 
-                    raws.push(RawOp::Raw(raw))
-                }
-            }
-        }
+    /// Run the optimization passes enabled at `level` on every program
+    /// ingested.
+    ///
+    /// See [`Assembler::set_optimization_level`].
+    pub fn set_optimization_level(&mut self, level: OptimizationLevel) {
+        self.set_optimizations(level.passes());
+    }
 
-        Ok(raws)
+    /// Allow instruction macros to be nested up to `limit` levels deep in
+    /// every program ingested.
+    ///
+    /// See [`Assembler::set_macro_depth_limit`].
+    pub fn set_macro_depth_limit(&mut self, limit: usize) {
+        self.macro_depth_limit = Some(limit);
     }
 
-    fn resolve_and_ingest(
-        &mut self,
-        program: &mut Program,
-        path: PathBuf,
-    ) -> Result<Vec<RawOp>, Error> {
-        let source = program.push_path(&path)?;
-        let code = read_to_string(source).with_context(|_| error::Io {
-            message: "reading file before parsing",
-            path: path.to_owned(),
-        })?;
-        let new_raws = self.preprocess(program, &code)?;
-        program.pop_path();
-        Ok(new_raws)
+    /// Record the instruction macros and the contents of each expansion in
+    /// the debug info of every program ingested.
+    ///
+    /// See [`Assembler::set_macro_details`].
+    pub fn set_macro_details(&mut self, enabled: bool) {
+        self.macro_details = enabled;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use assert_matches::assert_matches;
+    /// Guarantee that every output, including debug info and artifacts, is
+    /// the same on every run and machine, failing with
+    /// [`Error::NotReproducible`] if the program uses a feature that can't
+    /// be, like `%include_sol_runtime`, whose output depends on the installed
+    /// compiler. Disabled by default.
+    ///
+    /// See [`Assembler::set_reproducible`].
+    pub fn set_reproducible(&mut self, enabled: bool) {
+        self.reproducible = enabled;
+    }
 
-    use crate::asm::Error as AsmError;
+    /// Record the decisions made while assembling every program ingested.
+    /// Disabled by default.
+    ///
+    /// See [`Assembler::set_tracing`].
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.trace = enabled.then(Trace::default);
+    }
 
-    use hex_literal::hex;
+    /// Decisions made while assembling every program ingested so far, or
+    /// `None` if tracing is disabled. Positions are relative to the start of
+    /// each program.
+    ///
+    /// See the [`trace`](crate::trace) module for details.
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
 
-    use std::fmt::Display;
-    use std::io::Write;
+    /// Write the decisions made while assembling every program ingested so
+    /// far to `path`, after each program is assembled, enabling tracing.
+    /// Disabled (`None`) by default.
+    ///
+    /// The file contains a [`Trace`] serialized as JSON, like:
+    ///
+    /// ```json
+    /// {
+    ///   "events": [
+    ///     { "event": "push_deferred", "expr": "Label(end)", "position": 0, "size": 2 },
+    ///     { "event": "label_declared", "label": "end", "position": 2 },
+    ///     { "event": "emitted", "op": "%push(Label(end))", "position": 0, "size": 2 },
+    ///     { "event": "label_resolved", "label": "end", "position": 2 }
+    ///   ]
+    /// }
+    /// ```
+    #[cfg(feature = "fs")]
+    pub fn set_trace_file(&mut self, path: Option<PathBuf>) {
+        if path.is_some() && self.trace.is_none() {
+            self.trace = Some(Trace::default());
+        }
+        self.trace_file = path;
+    }
 
-    use super::*;
+    /// Changes made by optimization passes in every program ingested so far.
+    pub fn optimizations(&self) -> &[Optimization] {
+        &self.optimizations
+    }
 
-    use tempfile::NamedTempFile;
+    /// Require `label` to be assembled at `position` in every program
+    /// ingested.
+    ///
+    /// See [`Assembler::pin_label`].
+    pub fn pin_label<S>(&mut self, label: S, position: usize)
+    where
+        S: Into<String>,
+    {
+        self.pinned_labels.push((label.into(), position));
+    }
 
-    fn new_file<S: Display>(s: S) -> (NamedTempFile, PathBuf) {
-        let mut f = NamedTempFile::new().unwrap();
-        let root = f.path().parent().unwrap().join("root.asm");
+    /// Source files of every program ingested so far, including imports and
+    /// includes, in the order they were read.
+    pub fn dependencies(&self) -> &[PathBuf] {
+        &self.dependencies
+    }
 
-        write!(f, "{}", s).unwrap();
-        (f, root)
+    /// Source files of every program ingested so far, with the text of each
+    /// file that was parsed, for rendering a [`Diagnostic`]. Indexed like
+    /// [`Ingest::dependencies`].
+    pub fn sources(&self) -> Vec<Source> {
+        self.dependencies
+            .iter()
+            .enumerate()
+            .map(|(idx, path)| {
+                let text = self.texts.get(&idx).cloned().unwrap_or_default();
+                Source::new(path.clone(), text)
+            })
+            .collect()
     }
 
-    #[test]
-    fn ingest_import() -> Result<(), Error> {
-        let (f, root) = new_file("push1 42");
+    /// Describe `err`, returned while ingesting, pointing at the statements
+    /// that caused it in [`Ingest::sources`].
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use etk_asm::ingest::Ingest;
+    ///
+    /// let mut ingest = Ingest::new(Vec::new());
+    /// let err = ingest.ingest("./example.etk", "a:\njumpdest\na:\n").unwrap_err();
+    ///
+    /// let diagnostic = ingest.diagnostic(&err);
+    /// let text = diagnostic.render(&ingest.sources()).to_string();
+    /// assert!(text.contains("./example.etk:3:1"));
+    /// assert!(text.contains("first declared here"));
+    /// ```
+    pub fn diagnostic(&self, err: &Error) -> Diagnostic {
+        match err {
+            Error::Assemble { source } => source.diagnostic(),
+            Error::Parse { source, path } => {
+                let message = match source.errors().len() {
+                    1 => source.to_string(),
+                    n => format!("found {} errors", n),
+                };
 
+                let mut diagnostic = Diagnostic::new(message);
+                let file = match self.dependencies.iter().rposition(|p| p == path) {
+                    Some(file) => file,
+                    None => return diagnostic,
+                };
+
+                for (range, message) in source.errors().iter().filter_map(|e| e.span()) {
+                    let location = Location {
+                        file,
+                        start: range.start,
+                        length: range.len(),
+                    };
+                    diagnostic = diagnostic.with_primary(location, message);
+                }
+
+                diagnostic
+            }
+            _ => Diagnostic::new(err.to_string()),
+        }
+    }
+
+    /// Labels and instruction macro expansions of every program ingested so
+    /// far, positioned relative to the start of the output.
+    ///
+    /// See the [`debug`](crate::debug) module for details.
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    /// Write the labels of every program ingested so far to `path`, after
+    /// each program is assembled. Disabled (`None`) by default.
+    ///
+    /// The file contains a [`SymbolFile`] serialized as JSON, like:
+    ///
+    /// ```json
+    /// {
+    ///   "version": 1,
+    ///   "symbols": [
+    ///     { "name": "start", "offset": 2 },
+    ///     { "name": "lib.entry", "offset": 9 }
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// Labels of files included with a namespace are prefixed with it, like
+    /// `lib.entry`.
+    #[cfg(feature = "fs")]
+    pub fn set_symbol_file(&mut self, path: Option<PathBuf>) {
+        self.symbol_file = path;
+    }
+
+    /// Write the code and source map of every program ingested so far to
+    /// `path`, after each program is assembled, in the format of solc's
+    /// combined JSON output. Disabled (`None`) by default.
+    ///
+    /// The file contains an [`Artifact`](crate::debug::Artifact) serialized
+    /// as JSON, like:
+    ///
+    /// ```json
+    /// {
+    ///   "object": "6080604052",
+    ///   "sourceMap": "0:10:0:-;11:10;22:6",
+    ///   "opcodes": "PUSH1 0x80 PUSH1 0x40 MSTORE",
+    ///   "linkReferences": {}
+    /// }
+    /// ```
+    ///
+    /// Only code ingested after the file is set is included, so it should be
+    /// set before ingesting anything.
+    #[cfg(feature = "fs")]
+    pub fn set_artifact_file(&mut self, path: Option<PathBuf>) {
+        self.artifact_file = path;
+    }
+
+    /// Write the function selectors of every program ingested so far to
+    /// `path`, after each program is assembled, in the format of solc's
+    /// `--hashes` output. Disabled (`None`) by default.
+    ///
+    /// Every signature given to `selector(...)` in the source is listed, with
+    /// its selector, like:
+    ///
+    /// ```json
+    /// {
+    ///   "balanceOf(address)": "70a08231",
+    ///   "transfer(address,uint256)": "a9059cbb"
+    /// }
+    /// ```
+    #[cfg(feature = "fs")]
+    pub fn set_hashes_file(&mut self, path: Option<PathBuf>) {
+        self.hashes_file = path;
+    }
+
+    /// Immediates of pushes of constants declared with `%patchable`, in every
+    /// program ingested so far, positioned relative to the start of the
+    /// output.
+    ///
+    /// See the [`patch`](crate::patch) module for an example.
+    pub fn patches(&self) -> &Patches {
+        &self.patches
+    }
+
+    /// The runtime code of the last program ingested, if it has a
+    /// `%runtime_start`. The output holds the whole program, which is the
+    /// initcode, ending with this runtime code.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use etk_asm::ingest::Ingest;
+    /// # use etk_asm::ingest::Error;
+    /// # use hex_literal::hex;
+    ///
+    /// let text = r#"
+    ///     push1 runtime.size
+    ///     dup1
+    ///     push1 runtime.start
+    ///     push0
+    ///     codecopy
+    ///     push0
+    ///     return
+    ///
+    ///     %runtime_start
+    ///     push1 done
+    ///     jump
+    ///     done:
+    ///     jumpdest
+    ///     stop
+    /// "#;
+    ///
+    /// let mut output = Vec::new();
+    /// let mut ingest = Ingest::new(&mut output);
+    /// ingest.ingest("./example.etk", text)?;
+    /// assert_eq!(ingest.runtime(), Some(&hex!("6003565b00")[..]));
+    /// # drop(ingest);
+    /// # assert_eq!(output, hex!("60058060095f395ff3" "6003565b00"));
+    /// # Result::<(), Error>::Ok(())
+    /// ```
+    pub fn runtime(&self) -> Option<&[u8]> {
+        self.runtime.as_deref()
+    }
+
+    /// Write the pushes of patchable constants of every program ingested so
+    /// far to `path`, after each program is assembled. Disabled (`None`) by
+    /// default.
+    ///
+    /// The file contains [`Patches`] serialized as JSON, like:
+    ///
+    /// ```json
+    /// {
+    ///   "owner": [{ "start": 1, "end": 21 }]
+    /// }
+    /// ```
+    #[cfg(feature = "fs")]
+    pub fn set_patches_file(&mut self, path: Option<PathBuf>) {
+        self.patches_file = path;
+    }
+
+    /// Leave extern labels that no enclosing scope declares as zeroed
+    /// placeholders, instead of failing with [`Error::UnresolvedExterns`].
+    /// Disabled by default.
+    ///
+    /// Each placeholder is listed in the `linkReferences` of the artifact
+    /// (see [`Ingest::set_artifact_file`]), under the path of the program
+    /// and the name of the label, so deployment tools can fill it in with
+    /// the address of a library:
+    ///
+    /// ```json
+    /// {
+    ///   "main.etk": {
+    ///     "Math": [{ "start": 1, "length": 20 }]
+    ///   }
+    /// }
+    /// ```
+    pub fn set_link_references(&mut self, enabled: bool) {
+        self.link_references = enabled;
+    }
+
+    /// Write the extern labels left as placeholders (see
+    /// [`Ingest::set_link_references`]) in the `object` of the artifact as
+    /// solc's `__$...$__` library placeholders, instead of zeros. Disabled by
+    /// default.
+    ///
+    /// Each extern label must then be pushed with `push20`. See
+    /// [`link::placeholder`] for the format, and [`link::link_libraries`] to
+    /// fill in the addresses.
+    pub fn set_library_placeholders(&mut self, enabled: bool) {
+        self.library_placeholders = enabled;
+    }
+
+    /// The placeholders left for extern labels in the programs ingested so
+    /// far, by path of the program and name of the label. Only recorded with
+    /// [`Ingest::set_link_references`].
+    pub fn link_references(&self) -> &BTreeMap<String, BTreeMap<String, Vec<LinkReference>>> {
+        &self.links
+    }
+
+    /// Read sources and included files through `files`, instead of from the
+    /// default provider (see [`Ingest::new`]).
+    ///
+    /// Outputs, like [`Ingest::set_symbol_file`], are still written to the
+    /// real filesystem, and `%include_sol_runtime` still runs the compiler
+    /// on real files. See the [`vfs`](crate::vfs) module for an example.
+    pub fn set_file_provider<F>(&mut self, files: F)
+    where
+        F: 'static + FileProvider,
+    {
+        self.files = Arc::new(files);
+    }
+
+    /// Search the directory at `path` for files passed to `%include`,
+    /// `%import`, and the other instructions that read files, when they
+    /// aren't found relative to the file containing the instruction.
+    ///
+    /// Directories are searched in the order they were added. Files found in
+    /// an include path may include other files from the same directory.
+    pub fn add_include_path<P>(&mut self, path: P)
+    where
+        P: Into<PathBuf>,
+    {
+        self.include_paths.push(path.into());
+    }
+
+    /// Check the assembled code against the limits of `profile`, failing with
+    /// [`Error::ProfileViolation`] if it exceeds any of them. Synthesis and
+    /// `%if` conditions also target the chain of the profile.
+    ///
+    /// See [`Profile::validate`].
+    pub fn set_profile(&mut self, profile: Option<Profile>) {
+        self.profile = profile;
+    }
+
+    /// Whether the assembled code is runtime code (the default) or initcode,
+    /// which decides the size limits checked by [`Ingest::set_profile`] and
+    /// [`Ingest::set_policy`].
+    ///
+    /// A program with a `%runtime_start` is checked as initcode whatever the
+    /// kind, and its [`Ingest::runtime`] code as runtime code.
+    pub fn set_code_kind(&mut self, kind: CodeKind) {
+        self.code_kind = kind;
+    }
+
+    /// Check each ingested source, and the code assembled from it, against
+    /// the rules of `policy`, failing with [`Error::PolicyViolation`] if it
+    /// breaks any of them.
+    ///
+    /// See [`Policy::validate`] and [`Policy::validate_source`].
+    pub fn set_policy(&mut self, policy: Option<Policy>) {
+        self.policy = policy;
+    }
+
+    /// Run the Solidity compiler at `path` for `%include_sol_runtime`.
+    /// Defaults to `solc`, found through `PATH`.
+    #[cfg(feature = "solc")]
+    pub fn set_solc<P>(&mut self, path: P)
+    where
+        P: Into<PathBuf>,
+    {
+        self.solc = path.into();
+    }
+
+    /// Share parsed sources with other instances using the same `cache`.
+    #[cfg(feature = "fs")]
+    pub(crate) fn set_cache(&mut self, cache: Arc<ParseCache>) {
+        self.cache = Some(cache);
+    }
+}
+
+impl<W> Ingest<W>
+where
+    W: Write,
+{
+    /// Assemble instructions from the file located at `path`.
+    pub fn ingest_file<P>(&mut self, path: P) -> Result<(), Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        let text = read_source(&*self.files, &path)?;
+        self.ingest(path, &text)?;
+        Ok(())
+    }
+
+    /// Assemble instructions from the file located at `path`, like
+    /// [`Ingest::ingest_file`], and compare the assembled code to `expected`.
+    ///
+    /// Returns the first difference, or `None` if the code matches. The
+    /// assembled code is written to the output either way.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use etk_asm::ingest::Ingest;
+    /// # use etk_asm::ingest::Error;
+    /// # use std::io::Write;
+    ///
+    /// # let mut file = tempfile::NamedTempFile::new().unwrap();
+    /// # write!(file, "push1 1\nstop\n").unwrap();
+    /// # let path = file.path();
+    /// let mut ingest = Ingest::new(Vec::new());
+    /// let mismatch = ingest.verify_against(path, &[0x60, 0x02, 0x00][..])?;
+    ///
+    /// let mismatch = mismatch.unwrap();
+    /// assert_eq!(mismatch.offset, 1);
+    /// assert_eq!(mismatch.expected[0].immediate.as_deref(), Some("0x02"));
+    /// # Result::<(), Error>::Ok(())
+    /// ```
+    pub fn verify_against<P, E>(&mut self, path: P, expected: E) -> Result<Option<Mismatch>, Error>
+    where
+        P: Into<PathBuf>,
+        E: Into<Expected>,
+    {
+        let expected = expected.into();
+        let expected = expected.code().with_context(|_| error::Io {
+            message: "reading expected code",
+            path: match expected {
+                Expected::Path(ref path) => Some(path.clone()),
+                Expected::Bytes(_) => None,
+            },
+        })?;
+
+        let path = path.into();
+        let text = read_source(&*self.files, &path)?;
+        let actual = self.assemble(path, &text)?;
+
+        Ok(Mismatch::compare(&expected, &actual))
+    }
+
+    /// Assemble instructions from `src` as if they were read from a file located
+    /// at `path`.
+    pub fn ingest<P>(&mut self, path: P, src: &str) -> Result<(), Error>
+    where
+        P: Into<PathBuf>,
+    {
+        self.assemble(path, src)?;
+        Ok(())
+    }
+
+    /// Assemble `src`, write it to the output, and return the assembled code.
+    fn assemble<P>(&mut self, path: P, src: &str) -> Result<Vec<u8>, Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        self.dependencies.push(path.clone());
+        let file = self.dependencies.len() - 1;
+
+        let mut program = Program::new(self.files.clone(), path);
+        for include_path in self.include_paths.iter() {
+            program
+                .include_paths
+                .push(Root::directory(&*self.files, include_path.clone())?);
+        }
+        let mut nodes = self.preprocess(&mut program, src, file)?;
+        finish_code_size(&mut nodes);
+        finish_scopes(&mut nodes, &program.scopes);
+        let mut asm = Assembler::new();
+        asm.set_strict(self.strict);
+        asm.set_promoted(&self.promoted);
+        asm.set_synthesis(self.synthesis);
+        asm.set_target(self.profile.clone());
+        asm.set_dispatch_strategy(self.dispatch_strategy);
+        if let Some(model) = self.dispatch_model {
+            asm.set_dispatch_model(model);
+        }
+        asm.set_optimizations(&self.passes);
+        if let Some(limit) = self.macro_depth_limit {
+            asm.set_macro_depth_limit(limit);
+        }
+        asm.set_macro_details(self.macro_details);
+        asm.set_reproducible(self.reproducible);
+        asm.set_tracing(self.trace.is_some());
+        for (label, position) in self.pinned_labels.iter() {
+            asm.pin_label(label.clone(), *position);
+        }
+        for label in program.exports.iter().chain(program.exported.iter()) {
+            asm.export_label(label.clone());
+        }
+        let result = asm.assemble(&nodes);
+        self.warnings.extend_from_slice(asm.warnings());
+        self.substitutions.extend_from_slice(asm.substitutions());
+        self.optimizations.extend_from_slice(asm.optimizations());
+        if let (Some(trace), Some(events)) = (&mut self.trace, asm.trace()) {
+            trace.events.extend_from_slice(&events.events);
+        }
+        let raw = result?;
+
+        let mut externs: Vec<_> = asm
+            .extern_references()
+            .iter()
+            .map(|r| r.label.clone())
+            .collect();
+        externs.sort();
+        externs.dedup();
+        ensure!(
+            externs.is_empty() || self.link_references,
+            error::UnresolvedExterns { labels: externs }
+        );
+
+        if self.library_placeholders {
+            let wrong = asm
+                .extern_references()
+                .iter()
+                .find(|r| r.spec.extra_len() != 20);
+
+            if let Some(reference) = wrong {
+                return error::PlaceholderWidth {
+                    label: &reference.label,
+                    spec: reference.spec,
+                }
+                .fail();
+            }
+        }
+
+        // A program with runtime code is initcode.
+        let runtime = asm.label(RUNTIME_START).map(|start| raw[start..].to_vec());
+        let code_kind = match runtime {
+            Some(_) => CodeKind::Initcode,
+            None => self.code_kind,
+        };
+
+        if let Some(ref profile) = self.profile {
+            let mut violations = profile.validate(&raw, code_kind);
+            if let Some(ref runtime) = runtime {
+                violations.extend(profile.validate(runtime, CodeKind::Runtime));
+            }
+            ensure!(
+                violations.is_empty(),
+                error::ProfileViolation {
+                    profile: profile.name.clone(),
+                    violations,
+                }
+            );
+        }
+
+        if let Some(ref policy) = self.policy {
+            let mut violations = policy.validate_source(src);
+            violations.extend(policy.validate(&raw, code_kind));
+            if let Some(ref runtime) = runtime {
+                violations.extend(policy.validate(runtime, CodeKind::Runtime));
+            }
+            ensure!(
+                violations.is_empty(),
+                error::PolicyViolation {
+                    policy: policy.name.clone(),
+                    url: policy.url.clone(),
+                    violations,
+                }
+            );
+        }
+
+        self.output.write_all(&raw).context(error::Io {
+            message: "writing output",
+            path: None,
+        })?;
+
+        self.debug_info.append(asm.debug_info(), self.written);
+        self.debug_info.sources = self.dependencies.clone();
+
+        let source = self.dependencies[file].display().to_string();
+        for reference in asm.extern_references() {
+            self.links
+                .entry(source.clone())
+                .or_default()
+                .entry(reference.label.clone())
+                .or_default()
+                .push(LinkReference {
+                    start: self.written + reference.offset,
+                    length: reference.spec.extra_len(),
+                });
+        }
+
+        self.patches.append(asm.patches(), self.written);
+        self.runtime = runtime;
+        self.written += raw.len();
+
+        #[cfg(feature = "fs")]
+        self.write_outputs(&raw)?;
+
+        Ok(raw)
+    }
+
+    /// Write the symbol file, artifact, and other outputs that were asked for,
+    /// after assembling `raw`.
+    #[cfg(feature = "fs")]
+    fn write_outputs(&mut self, raw: &[u8]) -> Result<(), Error> {
+        if let Some(ref path) = self.symbol_file {
+            write_json(path, &SymbolFile::from(&self.debug_info), "symbol file")?;
+        }
+
+        if let Some(ref path) = self.artifact_file {
+            self.code.extend_from_slice(raw);
+            let mut artifact = self.debug_info.artifact(&self.code);
+            artifact.link_references = self.links.clone();
+            if self.library_placeholders {
+                artifact.object = link::placeholder_hex(&self.code, &self.links)
+                    .expect("library placeholders should be 20 byte pushes");
+            }
+            write_json(path, &artifact, "artifact")?;
+        }
+
+        if let Some(ref path) = self.hashes_file {
+            write_json(path, &self.hashes, "hashes file")?;
+        }
+
+        if let Some(ref path) = self.patches_file {
+            write_json(path, &self.patches, "patches file")?;
+        }
+
+        if let (Some(path), Some(trace)) = (&self.trace_file, &self.trace) {
+            write_json(path, trace, "trace")?;
+        }
+
+        Ok(())
+    }
+
+    fn preprocess(
+        &mut self,
+        program: &mut Program,
+        src: &str,
+        file: usize,
+    ) -> Result<Vec<RawOp>, Error> {
+        let mut nodes = self.parse_file(program, src, file)?;
+
+        // Only the file being assembled can be split into initcode and
+        // runtime code.
+        let start = match program.sources.len() {
+            1 => nodes.iter().position(|(n, _)| *n == Node::RuntimeStart),
+            _ => None,
+        };
+
+        let runtime = start.map(|start| {
+            let runtime = nodes.split_off(start + 1);
+            nodes.pop();
+            runtime
+        });
+
+        let raws = self.preprocess_nodes(program, nodes, file)?;
+        match runtime {
+            Some(runtime) => self.preprocess_runtime(program, raws, runtime, file),
+            None => Ok(raws),
+        }
+    }
+
+    /// Parse `src`, the text of `file`, recording the selectors it hashes.
+    fn parse_file(
+        &mut self,
+        program: &Program,
+        src: &str,
+        file: usize,
+    ) -> Result<Vec<(Node, Range<usize>)>, Error> {
+        self.texts.insert(file, src.to_owned());
+
+        let nodes = self.parse(src).with_context(|_| error::Parse {
+            path: program.sources.last().unwrap().clone(),
+        })?;
+
+        #[cfg(feature = "fs")]
+        if self.hashes_file.is_some() {
+            // The source already parsed, so it's known to be valid here.
+            for signature in parse_selectors(src).unwrap() {
+                let hash = Keccak256::digest(signature.as_bytes());
+                self.hashes.insert(signature, hex::encode(&hash[..4]));
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    /// Append `nodes`, the runtime code following `%runtime_start`, to
+    /// `initcode` in a scope named `runtime`, so its labels are relative to
+    /// its own start. Macros declared in the initcode can be used in both.
+    fn preprocess_runtime(
+        &mut self,
+        program: &mut Program,
+        mut initcode: Vec<RawOp>,
+        nodes: Vec<(Node, Range<usize>)>,
+        file: usize,
+    ) -> Result<Vec<RawOp>, Error> {
+        let mut runtime: Vec<RawOp> = initcode
+            .iter()
+            .filter(|raw| matches!(raw, RawOp::Op(AbstractOp::MacroDefinition(_))))
+            .cloned()
+            .collect();
+
+        let outer = program.scopes.len();
+        runtime.extend(self.preprocess_nodes(program, nodes, file)?);
+        finish_code_size(&mut runtime);
+        finish_scopes(&mut runtime, &program.scopes.split_off(outer));
+
+        initcode.push(AbstractOp::Label(RUNTIME_START.to_owned()).into());
+        initcode.push(AbstractOp::Label("runtime.start".to_owned()).into());
+        initcode.push(RawOp::NamedScope("runtime".to_owned(), runtime));
+        initcode.push(AbstractOp::Label("runtime.end".to_owned()).into());
+        program.scopes.push("runtime".to_owned());
+
+        Ok(initcode)
+    }
+
+    /// Evaluate the condition of an `%if` block, where capability flags
+    /// describe the profile (or the latest fork, if there isn't one).
+    fn condition(
+        &self,
+        expr: &Expression,
+        macros: &HashMap<String, MacroDefinition>,
+    ) -> Result<bool, Error> {
+        let latest;
+        let target = match self.profile {
+            Some(ref profile) => profile,
+            None => {
+                latest = Profile::builtin(TARGET_FORK).expect("target fork should have a profile");
+                &latest
+            }
+        };
+
+        let labels = IndexMap::new();
+        let ctx = Context::from((&labels, macros)).with_target(target);
+        let value = expr
+            .eval_with_context(ctx)
+            .map_err(|e| invalid_expression(e, expr))
+            .with_context(|_| error::InvalidCondition { expr: expr.clone() })?;
+
+        Ok(value.sign() != Sign::NoSign)
+    }
+
+    fn preprocess_nodes(
+        &mut self,
+        program: &mut Program,
+        nodes: Vec<(Node, Range<usize>)>,
+        file: usize,
+    ) -> Result<Vec<RawOp>, Error> {
+        let mut raws = Vec::new();
+        for (node, span) in nodes {
+            // Every arm yields a `Result` checked once below, so each level of
+            // a deeply nested import only holds one error on the stack.
+            let result = match node {
+                Node::Import(path) => self.import(program, &mut raws, path),
+                Node::Include(path, namespace) => self.include(program, &mut raws, path, namespace),
+                Node::Scope(name, nodes) => self.scope(program, &mut raws, name, nodes, file),
+                Node::Capture(name, nodes) => {
+                    raws.push(RawOp::Location(Location {
+                        file,
+                        start: span.start,
+                        length: span.len(),
+                    }));
+                    self.capture(program, &mut raws, name, nodes, file)
+                }
+                Node::If(condition, then, otherwise) => {
+                    self.branch(program, &mut raws, condition, (then, otherwise), file)
+                }
+                node => self.preprocess_node(program, &mut raws, node, span, file),
+            };
+            result?;
+        }
+
+        Ok(raws)
+    }
+
+    /// Preprocess the file at `path`, imported into the current scope, hiding
+    /// the names it doesn't export.
+    fn import(
+        &mut self,
+        program: &mut Program,
+        raws: &mut Vec<RawOp>,
+        path: PathBuf,
+    ) -> Result<(), Error> {
+        let exports = program.exports.len();
+        let scopes = program.scopes.len();
+        let file = self.dependencies.len();
+        let imported = self.resolve_and_ingest(program, path.clone())?;
+        hide_imported(program, imported, (exports, scopes), &path, file).map(|r| raws.extend(r))
+    }
+
+    /// Preprocess the file at `path` in its own scope, named `namespace` if
+    /// given.
+    fn include(
+        &mut self,
+        program: &mut Program,
+        raws: &mut Vec<RawOp>,
+        path: PathBuf,
+        namespace: Option<String>,
+    ) -> Result<(), Error> {
+        let included = self.resolve_and_include(program, path)?;
+        raws.push(match namespace {
+            Some(namespace) => RawOp::NamedScope(namespace, included),
+            None => RawOp::Scope(included),
+        });
+        Ok(())
+    }
+
+    /// Preprocess `nodes`, the contents of `%scope name`.
+    fn scope(
+        &mut self,
+        program: &mut Program,
+        raws: &mut Vec<RawOp>,
+        name: String,
+        nodes: Vec<(Node, Range<usize>)>,
+        file: usize,
+    ) -> Result<(), Error> {
+        let nested = program.scopes.len();
+        let macros = std::mem::take(&mut program.macros);
+        let scope_raws = self.preprocess_nodes(program, nodes, file)?;
+        program.macros = macros;
+
+        raws.extend(prefix_labels(&name, scope_raws, &program.scopes[nested..]));
+        for scope in program.scopes[nested..].iter_mut() {
+            *scope = format!("{}.{}", name, scope);
+        }
+        program.scopes.push(name);
+
+        Ok(())
+    }
+
+    /// Preprocess `nodes`, the region of `%capture name`.
+    fn capture(
+        &mut self,
+        program: &mut Program,
+        raws: &mut Vec<RawOp>,
+        name: String,
+        nodes: Vec<(Node, Range<usize>)>,
+        file: usize,
+    ) -> Result<(), Error> {
+        let outer = program.scopes.len();
+        let macros = std::mem::take(&mut program.macros);
+        let mut region = self.preprocess_nodes(program, nodes, file)?;
+        program.macros = macros;
+        finish_code_size(&mut region);
+        finish_scopes(&mut region, &program.scopes.split_off(outer));
+
+        raws.push(RawOp::Capture(name, region));
+        Ok(())
+    }
+
+    /// Preprocess the `then` or `otherwise` nodes of `%if condition`,
+    /// depending on whether the condition holds.
+    fn branch(
+        &mut self,
+        program: &mut Program,
+        raws: &mut Vec<RawOp>,
+        condition: Expression,
+        (then, otherwise): (Vec<(Node, Range<usize>)>, Vec<(Node, Range<usize>)>),
+        file: usize,
+    ) -> Result<(), Error> {
+        let nodes = if self.condition(&condition, &program.macros)? {
+            then
+        } else {
+            otherwise
+        };
+        raws.extend(self.preprocess_nodes(program, nodes, file)?);
+        Ok(())
+    }
+
+    /// Preprocess `node`, which doesn't contain other nodes, appending to
+    /// `raws`.
+    ///
+    /// Kept apart from [`Ingest::preprocess_nodes`], which recurses for every
+    /// imported file, so the stack it needs for each one stays small.
+    fn preprocess_node(
+        &mut self,
+        program: &mut Program,
+        raws: &mut Vec<RawOp>,
+        node: Node,
+        span: Range<usize>,
+        file: usize,
+    ) -> Result<(), Error> {
+        match node {
+            Node::Op(op) => {
+                if let AbstractOp::MacroDefinition(defn @ MacroDefinition::Expression(_)) = &op {
+                    program.macros.insert(defn.name().to_owned(), defn.clone());
+                }
+
+                raws.push(RawOp::Location(Location {
+                    file,
+                    start: span.start,
+                    length: span.len(),
+                }));
+                raws.push(RawOp::Op(op));
+            }
+            Node::IncludeHex(hex_path) => {
+                let file = self
+                    .files
+                    .read_to_string(&hex_path)
+                    .with_context(|_| error::Io {
+                        message: "reading hex include",
+                        path: hex_path.to_owned(),
+                    })?;
+                self.dependencies.push(hex_path.clone());
+
+                let raw = hex::decode(file.trim())
+                    .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+                    .context(error::InvalidHex {
+                        path: hex_path.to_owned(),
+                    })?;
+
+                raws.push(RawOp::Raw(raw))
+            }
+            Node::Bytes(expr) => raws.push(RawOp::Bytes(expr)),
+            Node::AbiEncode(types, values) => raws.push(RawOp::AbiEncode(types, values)),
+            Node::JumpTable(entries) => raws.push(RawOp::JumpTable(entries)),
+            Node::RJumpV(entries) => raws.push(RawOp::RJumpV(entries)),
+            Node::Data(size, entries) => raws.push(RawOp::Data(size, entries)),
+            Node::Extern(label) => raws.push(RawOp::Extern(label)),
+            Node::Patchable(name) => raws.push(RawOp::Patchable(name)),
+            Node::Export(name) => program.exports.push(name),
+            Node::RuntimeStart => {
+                return error::MisplacedRuntimeStart {
+                    path: program.sources.last().unwrap().clone(),
+                }
+                .fail()
+            }
+            Node::Fallthrough => raws.push(RawOp::Fallthrough),
+            Node::Keep(slots) => raws.push(RawOp::Keep(slots)),
+            Node::IncludeBin(bin_path) => {
+                let raw = self.files.read(&bin_path).with_context(|_| error::Io {
+                    message: "reading binary include",
+                    path: bin_path.to_owned(),
+                })?;
+                self.dependencies.push(bin_path);
+
+                raws.push(RawOp::Raw(raw))
+            }
+            Node::IncludeSolRuntime(sol_path, contract) => {
+                ensure!(
+                    !self.reproducible,
+                    error::NotReproducible {
+                        feature: "`%include_sol_runtime`",
+                        path: program.sources.last().unwrap(),
+                    }
+                );
+
+                let raw = self.compile_runtime(&sol_path, &contract)?;
+                self.dependencies.push(sol_path);
+
+                raws.push(RawOp::Raw(raw))
+            }
+            Node::IncludeArtifact(artifact_path, field) => {
+                let raw = read_artifact(&*self.files, &artifact_path, &field)?;
+                self.dependencies.push(artifact_path);
+
+                raws.push(RawOp::Raw(raw))
+            }
+            Node::Opcode(code, immediate) => raws.push(RawOp::Opcode(code, immediate)),
+            Node::Import(_)
+            | Node::Include(..)
+            | Node::Scope(..)
+            | Node::Capture(..)
+            | Node::If(..) => {
+                unreachable!("nested nodes are preprocessed by preprocess_nodes")
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compile the Solidity source at `path`, returning the runtime code of
+    /// `contract`.
+    #[cfg(feature = "solc")]
+    fn compile_runtime(&self, path: &Path, contract: &str) -> Result<Vec<u8>, Error> {
+        let output = Command::new(&self.solc)
+            .arg("--combined-json")
+            .arg("bin-runtime")
+            .arg(path)
+            .output()
+            .with_context(|_| error::Io {
+                message: "running solc",
+                path: self.solc.clone(),
+            })?;
+
+        ensure!(
+            output.status.success(),
+            error::Solc {
+                path,
+                message: String::from_utf8_lossy(&output.stderr).trim(),
+            }
+        );
+
+        let json: serde_json::Value = match serde_json::from_slice(&output.stdout) {
+            Ok(json) => json,
+            Err(e) => {
+                return error::Solc {
+                    path,
+                    message: e.to_string(),
+                }
+                .fail()
+            }
+        };
+
+        // Contracts are keyed by `path:Name`, where `path` is as given to
+        // solc, so contracts from imported sources may have the same name.
+        let contracts = json["contracts"].as_object();
+        let key = format!("{}:{}", path.display(), contract);
+        let found = contracts.and_then(|c| {
+            c.get(&key).or_else(|| {
+                c.iter()
+                    .find(|(k, _)| k.rsplit(':').next() == Some(contract))
+                    .map(|(_, v)| v)
+            })
+        });
+
+        let code = match found.and_then(|c| c["bin-runtime"].as_str()) {
+            Some(code) => code,
+            None => return error::SolcContract { path, contract }.fail(),
+        };
+
+        hex::decode(code)
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+            .context(error::InvalidHex { path })
+    }
+
+    #[cfg(not(feature = "solc"))]
+    fn compile_runtime(&self, path: &Path, _: &str) -> Result<Vec<u8>, Error> {
+        error::SolcDisabled { path }.fail()
+    }
+
+    /// Parse `src`, reusing the parse from the shared cache if there is one.
+    #[cfg(feature = "fs")]
+    fn parse(&self, src: &str) -> Result<Vec<(Node, Range<usize>)>, ParseError> {
+        match self.cache {
+            Some(ref cache) => cache.parse(src),
+            None => parse_asm_spanned(src),
+        }
+    }
+
+    #[cfg(not(feature = "fs"))]
+    fn parse(&self, src: &str) -> Result<Vec<(Node, Range<usize>)>, ParseError> {
+        parse_asm_spanned(src)
+    }
+
+    /// Like `resolve_and_ingest`, for a file assembled in its own scope.
+    fn resolve_and_include(
+        &mut self,
+        program: &mut Program,
+        path: PathBuf,
+    ) -> Result<Vec<RawOp>, Error> {
+        let outer = program.scopes.len();
+        let exports = program.exports.len();
+        let macros = std::mem::take(&mut program.macros);
+        let mut raws = self.resolve_and_ingest(program, path)?;
+        program.macros = macros;
+        finish_code_size(&mut raws);
+        finish_scopes(&mut raws, &program.scopes.split_off(outer));
+
+        // Names in an included file are private anyway.
+        program.exports.truncate(exports);
+        Ok(raws)
+    }
+
+    fn resolve_and_ingest(
+        &mut self,
+        program: &mut Program,
+        path: PathBuf,
+    ) -> Result<Vec<RawOp>, Error> {
+        // Only the file being assembled can have runtime code, so there's no
+        // need for the rest of `preprocess`.
+        let (nodes, file) = self.resolve(program, &path)?;
+        let new_raws = self.preprocess_nodes(program, nodes, file)?;
+        program.pop_path();
+        Ok(new_raws)
+    }
+
+    /// Read and parse the file at `path`, relative to the file being
+    /// preprocessed, returning its nodes and its index in the dependencies.
+    fn resolve(
+        &mut self,
+        program: &mut Program,
+        path: &PathBuf,
+    ) -> Result<(Vec<(Node, Range<usize>)>, usize), Error> {
+        let source = program.push_path(path)?;
+        let code = self
+            .files
+            .read_to_string(&source)
+            .with_context(|_| error::Io {
+                message: "reading file before parsing",
+                path: path.to_owned(),
+            })?;
+        self.dependencies.push(source);
+
+        let file = self.dependencies.len() - 1;
+        let nodes = self.parse_file(program, &code, file)?;
+        Ok((nodes, file))
+    }
+}
+
+/// The files read by a new [`Ingest`].
+#[cfg(feature = "fs")]
+fn default_files() -> Arc<dyn FileProvider> {
+    Arc::new(DiskFiles)
+}
+
+#[cfg(not(feature = "fs"))]
+fn default_files() -> Arc<dyn FileProvider> {
+    Arc::new(MemoryFiles::new())
+}
+
+/// Read the source file at `path`.
+fn read_source(files: &dyn FileProvider, path: &Path) -> Result<String, Error> {
+    files.read_to_string(path).with_context(|_| error::Io {
+        message: "reading source",
+        path: path.to_owned(),
+    })
+}
+
+/// Write `value` as JSON to the file at `path`, describing it as `what` in
+/// errors.
+#[cfg(feature = "fs")]
+fn write_json<T>(path: &Path, value: &T, what: &str) -> Result<(), Error>
+where
+    T: serde::Serialize,
+{
+    let file = File::create(path).with_context(|_| error::Io {
+        message: format!("creating {}", what),
+        path: path.to_owned(),
+    })?;
+
+    serde_json::to_writer_pretty(file, value)
+        .map_err(io::Error::from)
+        .with_context(|_| error::Io {
+            message: format!("writing {}", what),
+            path: path.to_owned(),
+        })
+}
+
+/// Read the bytecode in `field` of the compiler artifact at `path`.
+///
+/// `field` may be nested (like `evm.deployedBytecode`), and may hold the hex
+/// encoded bytecode directly, or an object with the bytecode in `object`, as
+/// written by different compilers.
+#[cfg(feature = "fs")]
+fn read_artifact(files: &dyn FileProvider, path: &Path, field: &str) -> Result<Vec<u8>, Error> {
+    let text = files.read_to_string(path).with_context(|_| error::Io {
+        message: "reading artifact",
+        path: path.to_owned(),
+    })?;
+    let json: serde_json::Value =
+        serde_json::from_str(&text).context(error::InvalidArtifact { path })?;
+
+    let value = field
+        .split('.')
+        .try_fold(&json, |value, key| value.get(key));
+    let code = match value.map(|v| v.get("object").unwrap_or(v)) {
+        Some(serde_json::Value::String(code)) => code,
+        _ => return error::ArtifactField { path, field }.fail(),
+    };
+
+    let code = code.strip_prefix("0x").unwrap_or(code);
+    hex::decode(code)
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        .context(error::InvalidHex { path })
+}
+
+#[cfg(not(feature = "fs"))]
+fn read_artifact(_: &dyn FileProvider, path: &Path, _: &str) -> Result<Vec<u8>, Error> {
+    error::ArtifactDisabled { path }.fail()
+}
+
+/// The expressions used by `raw`, not counting those of nested scopes.
+fn exprs_mut(raw: &mut RawOp) -> Vec<&mut Expression> {
+    match raw {
+        RawOp::Op(op) => op_exprs_mut(op),
+        RawOp::Bytes(expr) => vec![expr],
+        RawOp::AbiEncode(_, exprs)
+        | RawOp::JumpTable(exprs)
+        | RawOp::RJumpV(exprs)
+        | RawOp::Data(_, exprs) => exprs.iter_mut().collect(),
+        RawOp::Opcode(code, Some((size, value))) => vec![code, size, value],
+        RawOp::Opcode(code, None) => vec![code],
+        _ => Vec::new(),
+    }
+}
+
+/// The expressions in an instruction, including the arguments of an
+/// instruction macro.
+fn op_exprs_mut(op: &mut AbstractOp) -> Vec<&mut Expression> {
+    match op {
+        AbstractOp::Macro(invc) => invc.parameters.iter_mut().collect(),
+        op => op.expr_mut().into_iter().collect(),
+    }
+}
+
+/// The expression macros declared in `raws`, outside of any scope.
+fn expression_macros(raws: &[RawOp]) -> impl Iterator<Item = &MacroDefinition> {
+    raws.iter().filter_map(|raw| match raw {
+        RawOp::Op(AbstractOp::MacroDefinition(defn @ MacroDefinition::Expression(_))) => Some(defn),
+        _ => None,
+    })
+}
+
+/// Hide the names of `raws`, imported from `path`, that the file doesn't
+/// export, where `first` holds the number of exports and scopes before the
+/// import.
+fn hide_imported(
+    program: &mut Program,
+    mut raws: Vec<RawOp>,
+    first: (usize, usize),
+    path: &Path,
+    file: usize,
+) -> Result<Vec<RawOp>, Error> {
+    let (exports, scopes) = first;
+    let exports = program.exports.split_off(exports);
+    program.exported.extend(exports.iter().cloned());
+    if !exports.is_empty() {
+        for defn in expression_macros(&raws) {
+            program.macros.remove(defn.name());
+        }
+
+        let scopes = &program.scopes[scopes..];
+        hide_unexported(&mut raws, &exports, scopes, path, file)?;
+
+        for defn in expression_macros(&raws) {
+            program.macros.insert(defn.name().to_owned(), defn.clone());
+        }
+    }
+
+    Ok(raws)
+}
+
+/// Rename the labels and macros declared in `raws`, the code of an imported
+/// file, except those in `exports` and the labels of `scopes`, so they can't
+/// collide with names in the importing file. Hidden names end with `:file`,
+/// which can't be written in a source.
+///
+/// Fails with the first export that isn't declared in `raws`, read from
+/// `path`.
+fn hide_unexported(
+    raws: &mut [RawOp],
+    exports: &[String],
+    scopes: &[String],
+    path: &Path,
+    file: usize,
+) -> Result<(), Error> {
+    let mut labels = HashSet::new();
+    let mut macros = HashSet::new();
+    for raw in raws.iter() {
+        match raw {
+            RawOp::Op(AbstractOp::Label(label)) => {
+                labels.insert(label.clone());
+            }
+            RawOp::Op(AbstractOp::MacroDefinition(defn)) => {
+                macros.insert(defn.name().clone());
+            }
+            RawOp::Capture(name, _) => {
+                macros.insert(name.clone());
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(name) = exports
+        .iter()
+        .find(|name| !labels.contains(*name) && !macros.contains(*name))
+    {
+        return error::UnknownExport {
+            name: name.clone(),
+            path: path.to_owned(),
+        }
+        .fail();
+    }
+
+    // `name.size` is resolved from the bounds of the scope later.
+    let in_scope = |label: &String| {
+        scopes
+            .iter()
+            .any(|scope| label.starts_with(&format!("{}.", scope)))
+    };
+    labels.retain(|label| !exports.contains(label) && !in_scope(label));
+    macros.retain(|name| !exports.contains(name));
+
+    let hidden = |name: &str| format!("{}:{}", name, file);
+    let hide = |expr: &mut Expression, locals: &HashSet<String>| {
+        for label in labels.difference(locals) {
+            expr.replace_label(label, &hidden(label));
+        }
+        for name in macros.iter() {
+            expr.replace_macro(name, &hidden(name));
+        }
+    };
+    let hide_op = |op: &mut AbstractOp, locals: &HashSet<String>| match op {
+        AbstractOp::Label(label) if labels.contains(label) && !locals.contains(label) => {
+            *label = hidden(label);
+        }
+        AbstractOp::Macro(invc) if macros.contains(&invc.name) => {
+            invc.name = hidden(&invc.name);
+        }
+        _ => (),
+    };
+
+    let none = HashSet::new();
+    for raw in raws.iter_mut() {
+        match raw {
+            RawOp::Op(AbstractOp::MacroDefinition(MacroDefinition::Instruction(defn))) => {
+                if macros.contains(&defn.name) {
+                    defn.name = hidden(&defn.name);
+                }
+
+                // Labels declared in the macro shadow the file's labels.
+                let locals: HashSet<_> = defn
+                    .contents
+                    .iter()
+                    .filter_map(|op| match op {
+                        AbstractOp::Label(label) => Some(label.clone()),
+                        _ => None,
+                    })
+                    .collect();
+
+                for op in defn.contents.iter_mut() {
+                    hide_op(op, &locals);
+                    for expr in op_exprs_mut(op) {
+                        hide(expr, &locals);
+                    }
+                }
+                for expr in defn.defaults.iter_mut() {
+                    hide(expr, &locals);
+                }
+            }
+            RawOp::Op(AbstractOp::MacroDefinition(MacroDefinition::Expression(defn))) => {
+                if macros.contains(&defn.name) {
+                    defn.name = hidden(&defn.name);
+                }
+                hide(&mut defn.content.tree, &none);
+            }
+            RawOp::Op(op) => hide_op(op, &none),
+            RawOp::Patchable(name) if macros.contains(name) => *name = hidden(name),
+            RawOp::Capture(name, region) => {
+                if macros.contains(name) {
+                    *name = hidden(name);
+                }
+                hide_macros(region, &macros, file);
+            }
+            _ => (),
+        }
+
+        for expr in exprs_mut(raw) {
+            hide(expr, &none);
+        }
+    }
+
+    Ok(())
+}
+
+/// Rename the uses of `macros` in `region`, the code of a region captured in
+/// an imported file, like [`hide_unexported`] does. The region's labels are
+/// its own, so they are left alone.
+fn hide_macros(region: &mut [RawOp], macros: &HashSet<String>, file: usize) {
+    let hidden = |name: &str| format!("{}:{}", name, file);
+    let hide = |expr: &mut Expression| {
+        for name in macros.iter() {
+            expr.replace_macro(name, &hidden(name));
+        }
+    };
+    let hide_op = |op: &mut AbstractOp| {
+        if let AbstractOp::Macro(invc) = op {
+            if macros.contains(&invc.name) {
+                invc.name = hidden(&invc.name);
+            }
+        }
+        for expr in op_exprs_mut(op) {
+            hide(expr);
+        }
+    };
+
+    for raw in region.iter_mut() {
+        match raw {
+            RawOp::Op(AbstractOp::MacroDefinition(MacroDefinition::Instruction(defn))) => {
+                defn.contents.iter_mut().for_each(&hide_op);
+                defn.defaults.iter_mut().for_each(&hide);
+            }
+            RawOp::Op(AbstractOp::MacroDefinition(MacroDefinition::Expression(defn))) => {
+                hide(&mut defn.content.tree);
+            }
+            RawOp::Op(op) => hide_op(op),
+            RawOp::Capture(_, nested) => hide_macros(nested, macros, file),
+            _ => (),
+        }
+
+        for expr in exprs_mut(raw) {
+            hide(expr);
+        }
+    }
+}
+
+/// Prefix the labels declared in `raws`, the contents of `%scope name`, and
+/// their uses with `name.`. The scope's bounds are marked with the labels
+/// `name.start` and `name.end`.
+///
+/// `nested` are the scopes declared in `raws`, whose sizes are resolved later.
+fn prefix_labels(name: &str, raws: Vec<RawOp>, nested: &[String]) -> Vec<RawOp> {
+    let prefixed = |label: &str| format!("{}.{}", name, label);
+
+    let mut labels = vec!["start".to_owned(), "end".to_owned(), "size".to_owned()];
+    labels.extend(nested.iter().map(|scope| format!("{}.size", scope)));
+    labels.extend(raws.iter().filter_map(|raw| match raw {
+        RawOp::Op(AbstractOp::Label(label)) => Some(label.clone()),
+        _ => None,
+    }));
+
+    let mut output = Vec::with_capacity(raws.len() + 2);
+    output.push(AbstractOp::Label(prefixed("start")).into());
+
+    for mut raw in raws {
+        if let RawOp::Op(AbstractOp::Label(ref mut label)) = raw {
+            *label = prefixed(label);
+        }
+
+        for expr in exprs_mut(&mut raw) {
+            for label in labels.iter() {
+                expr.replace_label(label, &prefixed(label));
+            }
+            expr.replace_label(CODE_SIZE, &prefixed("size"));
+        }
+
+        output.push(raw);
+    }
+
+    output.push(AbstractOp::Label(prefixed("end")).into());
+    output
+}
+
+/// Replace `codesize()` outside of any scope with the size of all of `raws`,
+/// the code of a file, marking its end with a label when it's used.
+fn finish_code_size(raws: &mut Vec<RawOp>) {
+    let mut used = false;
+    for raw in raws.iter_mut() {
+        for expr in exprs_mut(raw) {
+            if expr.uses_label(CODE_SIZE) {
+                expr.replace_label(CODE_SIZE, CODE_END);
+                used = true;
+            }
+        }
+    }
+
+    if used {
+        raws.push(AbstractOp::Label(CODE_END.to_owned()).into());
+    }
+}
+
+/// Replace `name.size` with the distance between the bounds of each scope in
+/// `scopes`.
+///
+/// The bounds are kept even when they aren't used, so the sections of the code
+/// can be found from its labels, like [`redeploy`](crate::redeploy) does.
+fn finish_scopes(raws: &mut [RawOp], scopes: &[String]) {
+    if scopes.is_empty() {
+        return;
+    }
+
+    for raw in raws.iter_mut() {
+        for expr in exprs_mut(raw) {
+            for scope in scopes {
+                let size = Expression::Minus(
+                    Terminal::Label(format!("{}.end", scope)).into(),
+                    Terminal::Label(format!("{}.start", scope)).into(),
+                );
+                expr.replace_label_with(&format!("{}.size", scope), &size);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use crate::asm::Error as AsmError;
+
+    use hex_literal::hex;
+
+    use sha3::{Digest, Keccak256};
+
+    #[cfg(feature = "fs")]
+    use std::fmt::Display;
+    #[cfg(feature = "fs")]
+    use std::io::Write;
+
+    use super::*;
+
+    #[cfg(feature = "fs")]
+    use tempfile::NamedTempFile;
+
+    #[cfg(feature = "fs")]
+    fn new_file<S: Display>(s: S) -> (NamedTempFile, PathBuf) {
+        let mut f = NamedTempFile::new().unwrap();
+        let root = f.path().parent().unwrap().join("root.asm");
+
+        write!(f, "{}", s).unwrap();
+        (f, root)
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_import() -> Result<(), Error> {
+        let (f, root) = new_file("push1 42");
+
+        let text = format!(
+            r#"
+            push1 1
+            %import("{}")
+            push1 2
+        "#,
+            f.path().display()
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(&root, &text)?;
+        assert_eq!(ingest.dependencies(), [root, f.path().to_owned()]);
+        assert_eq!(output, hex!("6001602a6002"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_include() -> Result<(), Error> {
+        let (f, root) = new_file(
+            r#"
+                a:
+                jumpdest
+                pc
+                push1 a
+                jump
+            "#,
+        );
+
+        let text = format!(
+            r#"
+            push1 1
+            %include("{}")
+            push1 2
+        "#,
+            f.path().display()
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+
+        assert_eq!(output, hex!("60015b586000566002"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_include_extern() -> Result<(), Error> {
+        let (f, root) = new_file(
+            r#"
+                %extern done
+                push1 done
+                jump
+            "#,
+        );
+
+        let text = format!(
+            r#"
+            %include("{}")
+            done:
+            jumpdest
+        "#,
+            f.path().display()
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+
+        assert_eq!(output, hex!("6003565b"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_unresolved_extern() {
+        let text = r#"
+            %extern done
+            push1 done
+            jump
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let err = ingest.ingest("./root.etk", text).unwrap_err();
+
+        assert_matches!(err, Error::UnresolvedExterns { labels, .. } if labels == ["done"]);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_include_namespace() -> Result<(), Error> {
+        let (f, root) = new_file(
+            r#"
+                pc
+                a:
+                jumpdest
+                push1 a
+                jump
+            "#,
+        );
+
+        let text = format!(
+            r#"
+            push1 first.a
+            push1 second.a
+            %include("{0}", "first")
+            %include("{0}", "second")
+        "#,
+            f.path().display()
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+
+        assert_eq!(output, hex!("6005600a585b600156585b600156"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_include_same_namespace_twice() {
+        let (f, root) = new_file("a:\njumpdest");
+
+        let text = format!(
+            r#"
+            %include("{0}", "lib")
+            %include("{0}", "lib")
+        "#,
+            f.path().display()
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let err = ingest.ingest(root, &text).unwrap_err();
+
+        assert_matches!(
+            err,
+            Error::Assemble {
+                source: AsmError::DuplicateLabel { label, ..}
+            } if label == "lib.a"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_import_twice() {
+        let (f, root) = new_file(
+            r#"
+                a:
+                jumpdest
+                push1 a
+            "#,
+        );
+
+        let text = format!(
+            r#"
+                push1 1
+                %import("{0}")
+                %import("{0}")
+                push1 2
+            "#,
+            f.path().display()
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let err = ingest.ingest(root, &text).unwrap_err();
+
+        assert_matches!(
+            err,
+            Error::Assemble {
+                source: AsmError::DuplicateLabel { label, ..}
+            } if label == "a"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_import_exports() -> Result<(), Error> {
+        let (f, root) = new_file(
+            r#"
+                %export push_twice
+
+                %def double(x)
+                    $x * 2
+                %end
+
+                %macro push_one(x)
+                    push1 double($x)
+                %end
+
+                %macro push_twice(x)
+                    %push_one($x)
+                    %push_one($x)
+                %end
+
+                a:
+                jumpdest
+            "#,
+        );
+
+        let text = format!(
+            r#"
+                %import("{}")
+
+                %def double(x)
+                    $x * 3
+                %end
+
+                %macro push_one(x)
+                    push1 double($x)
+                %end
+
+                %push_twice(1)
+                %push_one(1)
+                a:
+                jumpdest
+            "#,
+            f.path().display()
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+
+        assert_eq!(output, hex!("5b 6002 6002 6003 5b"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_import_unknown_export() {
+        let (f, root) = new_file("%export missing\npush1 1\n");
+        let text = format!(r#"%import("{}")"#, f.path().display());
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let err = ingest.ingest(root, &text).unwrap_err();
+
+        assert_matches!(err, Error::UnknownExport { name, .. } if name == "missing");
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_duplicate_label_diagnostic() {
+        let (f, root) = new_file("a:\njumpdest\n");
+
+        let text = format!("%import(\"{}\")\n\na:\n", f.path().display());
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let err = ingest.ingest(root, &text).unwrap_err();
+
+        let diagnostic = ingest.diagnostic(&err);
+        assert_eq!(diagnostic.labels.len(), 2);
+
+        let again = diagnostic.labels[0].location;
+        assert!(diagnostic.labels[0].primary);
+        assert_eq!(
+            (again.file, again.start),
+            (0, text.find("\na:").unwrap() + 1)
+        );
+
+        let first = diagnostic.labels[1].location;
+        assert!(!diagnostic.labels[1].primary);
+        assert_eq!((first.file, first.start), (1, 0));
+
+        let rendered = diagnostic.render(&ingest.sources()).to_string();
+        assert!(rendered.contains(":3:1"), "{}", rendered);
+        assert!(rendered.contains("first declared here"), "{}", rendered);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_include_hex() -> Result<(), Error> {
+        let (f, root) = new_file("deadbeef0102f6");
+
+        let text = format!(
+            r#"
+                push1 1
+                %include_hex("{}")
+                push1 2
+            "#,
+            f.path().display(),
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+        assert_eq!(output, hex!("6001deadbeef0102f66002"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_include_hex_label() -> Result<(), Error> {
+        let (f, root) = new_file("deadbeef0102f6");
+
+        let text = format!(
+            r#"
+                push1 1
+                %include_hex("{}")
+                a:
+                jumpdest
+                push1 a
+                push1 0xff
+            "#,
+            f.path().display(),
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+        assert_eq!(output, hex!("6001deadbeef0102f65b600960ff"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_include_bin() -> Result<(), Error> {
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(&hex!("deadbeef0a0d")).unwrap();
+        let root = f.path().parent().unwrap().join("root.asm");
+
+        let text = format!(
+            r#"
+                push1 1
+                %include_bin("{}")
+                a:
+                jumpdest
+                push1 a
+            "#,
+            f.path().display(),
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(&root, &text)?;
+        assert_eq!(ingest.dependencies(), [root, f.path().to_owned()]);
+        assert_eq!(output, hex!("6001deadbeef0a0d5b6008"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_bytes() -> Result<(), Error> {
+        let text = r#"
+            %def greeting()
+                "hi"
+            %end
+            %def width()
+                0x0102
+            %end
+
+            push1 1
+            %bytes(greeting())
+            %bytes(width() + 1)
+            a:
+            jumpdest
+            push1 a
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.asm", text)?;
+        assert_eq!(output, hex!("6001686901035b6006"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_string_builtins() -> Result<(), Error> {
+        let text = r#"
+            %def table()
+                concat(hex("a9059cbb"), selector("approve(address,uint256)"))
+            %end
+
+            push1 len(table())
+            %bytes(table())
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.asm", text)?;
+        assert_eq!(output, hex!("6008a9059cbb095ea7b3"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_jump_table() -> Result<(), Error> {
+        let text = r#"
+            push1 end
+            %jumptable(first, second, second + 1)
+            end:
+            first:
+            jumpdest
+            second:
+            jumpdest
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.asm", text)?;
+        assert_eq!(output, hex!("6008 0008 0009 000a 5b 5b"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_data() -> Result<(), Error> {
+        let text = r#"
+            push1 table
+            %bytes("hi", 0x01, hex("0203"))
+            table:
+            %dw(first, table)
+            %word(first)
+            first:
+            jumpdest
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.asm", text)?;
+
+        let mut expected = hex!("6007 6869010203 002b 0007").to_vec();
+        expected.extend([0; 31]);
+        expected.extend(hex!("2b 5b"));
+        assert_eq!(output, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_dispatch() -> Result<(), Error> {
+        let text = r#"
+            %dispatch {
+                selector("transfer(address,uint256)") => two,
+                0x11111111 => one  # comments are allowed
+            }
+            stop
+            one:
+            jumpdest
+            two:
+            jumpdest
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.asm", text)?;
+        assert_eq!(
+            output,
+            hex!(
+                "5f3560e01c"
+                "80631111111114601a57"
+                "8063a9059cbb14601b57"
+                "005b5b"
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_abi_encode() -> Result<(), Error> {
+        let text = r#"
+            %def supply()
+                1000
+            %end
+
+            stop
+            %abi_encode("(uint256,address,bytes2[2])", supply(), 0x11, "hi", 0x01)
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.asm", text)?;
+        assert_eq!(
+            output,
+            hex!(
+                "00"
+                "00000000000000000000000000000000000000000000000000000000000003e8"
+                "0000000000000000000000000000000000000000000000000000000000000011"
+                "6869000000000000000000000000000000000000000000000000000000000000"
+                "0001000000000000000000000000000000000000000000000000000000000000"
+            )
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let err = ingest
+            .ingest("./test.asm", r#"%abi_encode("uint8", 256)"#)
+            .unwrap_err();
+        assert_matches!(
+            err,
+            Error::Assemble {
+                source: AsmError::AbiEncode { .. },
+                ..
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_builtin_shadowed() -> Result<(), Error> {
+        let text = r#"
+            %def len(x)
+                7
+            %end
+
+            push1 len("abc")
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.asm", text)?;
+        assert_eq!(output, hex!("6007"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_nested_macros() -> Result<(), Error> {
+        let text = r#"
+            %def double(x)
+                $x * 2
+            %end
+
+            %def quadruple(x)
+                double(double($x))
+            %end
+
+            %macro inner(a)
+                push1 quadruple($a)
+            %end
+
+            %macro outer(b, rest...)
+                %inner($b + 1)
+                %push(done)
+                %outer_rest($rest)
+                done:
+            %end
+
+            %macro outer_rest(xs...)
+                push1 $xs
+            %end
+
+            %outer(1, 5, 6)
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.asm", text)?;
+        assert_eq!(output, hex!("6008600860056006"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_recursive_macro() {
+        let text = r#"
+            %macro a()
+                %b()
+            %end
+
+            %macro b()
+                caller
+                %a()
+            %end
+
+            %a()
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let err = ingest.ingest("./test.asm", text).unwrap_err();
+
+        assert_matches!(
+            err,
+            Error::Assemble {
+                source: AsmError::RecursiveMacro { name, cycle, .. }
+            } if name == "a" && cycle == ["a", "b", "a"]
+        );
+    }
+
+    #[test]
+    fn ingest_recursive_expression_macro() -> Result<(), Error> {
+        let text = r#"
+            %def forever(x)
+                forever($x + 1)
+            %end
+
+            %def odd(x)
+                even($x)
+            %end
+
+            %def even(x)
+                odd($x)
+            %end
+
+            %def double(x)
+                $x * 2
+            %end
+
+            %def twice(x)
+                double($x) + double(double(1))
+            %end
+        "#;
+
+        // A macro given to itself as an argument isn't a cycle.
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(
+            "./test.asm",
+            &format!("{}\npush1 double(double(1))\npush1 twice(double(1))", text),
+        )?;
+        assert_eq!(output, hex!("6004 6008"));
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let err = ingest
+            .ingest("./test.asm", &format!("{}\npush1 forever(1)", text))
+            .unwrap_err();
+
+        assert_matches!(
+            err,
+            Error::Assemble {
+                source: AsmError::RecursiveMacro { name, cycle, .. }
+            } if name == "forever" && cycle == ["forever", "forever"]
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let err = ingest
+            .ingest(
+                "./test.asm",
+                &format!("{}\n%def x()\n1 + odd(2)\n%end\npush1 x()", text),
+            )
+            .unwrap_err();
+
+        assert_matches!(
+            err,
+            Error::Assemble {
+                source: AsmError::RecursiveMacro { name, cycle, .. }
+            } if name == "odd" && cycle == ["odd", "even", "odd"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_macro_depth_limit() {
+        let text = r#"
+            %macro inner()
+                caller
+            %end
+
+            %macro outer()
+                %inner()
+            %end
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_macro_depth_limit(1);
+        let err = ingest
+            .ingest("./test.asm", &format!("{}\n%outer()", text))
+            .unwrap_err();
+
+        assert_matches!(
+            err,
+            Error::Assemble {
+                source: AsmError::MacroDepthLimit { name, limit: 1, .. }
+            } if name == "inner"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_pending_then_raw() -> Result<(), Error> {
+        let (f, root) = new_file("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+
+        let text = format!(
+            r#"
+                push2 lbl
+                %include_hex("{}")
+                lbl:
+                jumpdest
+            "#,
+            f.path().display(),
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+
+        let expected = hex!("61001caaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa5b");
+        assert_eq!(output, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_import_in_import() -> Result<(), Error> {
+        let (end, _) = new_file(
+            r#"
+                end:
+                jumpdest
+                push1 start
+                push1 middle
+            "#,
+        );
+
+        let (middle, root) = new_file(format!(
+            r#"
+                %import("{}")
+                middle:
+                jumpdest
+                push2 start
+                push2 end
+            "#,
+            end.path().display(),
+        ));
+
+        let text = format!(
+            r#"
+                push3 end
+                push3 middle
+                start:
+                jumpdest
+                %import("{}")
+            "#,
+            middle.path().display(),
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+
+        let expected = hex!("620000096200000e5b5b6008600e5b610008610009");
+        assert_eq!(output, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_import_in_include() -> Result<(), Error> {
+        let (end, _) = new_file(
+            r#"
+                included:
+                jumpdest
+                push2 backward
+                push2 forward
+            "#,
+        );
+
+        let (middle, root) = new_file(format!(
+            r#"
+                pc
+                push1 backward
+                forward:
+                jumpdest
+                %import("{}")
+                backward:
+                jumpdest
+                push1 forward
+                push1 included
+            "#,
+            end.path().display(),
+        ));
+
+        let text = format!(
+            r#"
+                push3 backward
+                forward:
+                jumpdest
+                %include("{}")
+                backward:
+                jumpdest
+                push3 forward
+            "#,
+            middle.path().display(),
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+
+        let expected = hex!("620000155b58600b5b5b61000b6100035b600360045b62000004");
+        assert_eq!(output, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_directory_traversal() {
+        let (f, _) = new_file("pc");
+
+        let text = format!(
+            r#"
+                %include("{}")
+            "#,
+            f.path().display(),
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let root = std::env::current_exe().unwrap();
+        let err = ingest.ingest(root, &text).unwrap_err();
+
+        assert_matches!(err, Error::DirectoryTraversal { .. });
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_include_path() -> Result<(), Error> {
+        let lib = tempfile::tempdir().unwrap();
+        std::fs::write(
+            lib.path().join("util.etk"),
+            "%include(\"inner.etk\")\npush1 42\n",
+        )
+        .unwrap();
+        std::fs::write(lib.path().join("inner.etk"), "caller\n").unwrap();
+
+        let project = tempfile::tempdir().unwrap();
+        let root = project.path().join("main.etk");
+        let text = r#"
+            %import("util.etk")
+            pc
+        "#;
+
+        let mut ingest = Ingest::new(Vec::new());
+        let err = ingest.ingest(&root, text).unwrap_err();
+        assert_matches!(err, Error::Io { .. });
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.add_include_path(lib.path());
+        ingest.ingest(&root, text)?;
+        assert_eq!(ingest.dependencies()[1], lib.path().join("util.etk"));
+        assert_eq!(ingest.dependencies()[2], lib.path().join("inner.etk"));
+
+        drop(ingest);
+        assert_eq!(output, hex!("33602a58"));
+
+        // Files next to the including file take precedence.
+        std::fs::write(project.path().join("util.etk"), "push1 1\n").unwrap();
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.add_include_path(lib.path());
+        ingest.ingest(&root, text)?;
+
+        drop(ingest);
+        assert_eq!(output, hex!("600158"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_file_provider() -> Result<(), Error> {
+        use crate::vfs::MemoryFiles;
+
+        let mut files = MemoryFiles::new();
+        files.insert("/project/main.etk", "%include_hex(\"/project/code.hex\")\n");
+        files.insert("/project/code.hex", "5858");
+        files.insert("/project/escape.etk", "%import(\"../secret.etk\")\n");
+        files.insert("/secret.etk", "pc\n");
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_file_provider(files);
+        ingest.ingest_file("/project/main.etk")?;
+
+        let err = ingest.ingest_file("/project/escape.etk").unwrap_err();
+        assert_matches!(err, Error::DirectoryTraversal { .. });
+
+        let err = ingest.ingest_file("/project/missing.etk").unwrap_err();
+        assert_matches!(err, Error::Io { .. });
+
+        drop(ingest);
+        assert_eq!(output, hex!("5858"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_unsaved_buffers() -> Result<(), Error> {
+        use crate::vfs::OverlayFiles;
+
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("main.etk");
+        std::fs::write(dir.path().join("lib.etk"), "caller\n").unwrap();
+
+        // The main file has never been saved, but imports one that has.
+        let mut files = OverlayFiles::new();
+        files.insert(&main, "%import(\"lib.etk\")\npc\n");
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_file_provider(files.clone());
+        ingest.ingest_file(&main)?;
+        drop(ingest);
+        assert_eq!(output, hex!("3358"));
+
+        // Diagnostics point at the edited text of an imported buffer.
+        files.insert(dir.path().join("lib.etk"), "a:\njumpdest\na:\n");
+
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.set_file_provider(files);
+        let err = ingest.ingest_file(&main).unwrap_err();
+
+        let text = ingest
+            .diagnostic(&err)
+            .render(&ingest.sources())
+            .to_string();
+        assert!(text.contains("lib.etk:3:1"), "{}", text);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_recursive() {
+        let (mut f, root) = new_file("");
+        let path = f.path().display().to_string();
+        write!(f, r#"%import("{}")"#, path).unwrap();
+
+        let text = format!(
+            r#"
+                %import("{}")
+            "#,
+            path,
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let err = ingest.ingest(root, &text).unwrap_err();
+
+        assert_matches!(err, Error::RecursionLimit { .. });
+        assert!(err.explanation().is_some());
+    }
+
+    #[test]
+    fn ingest_warnings() -> Result<(), Error> {
+        let text = r#"
+            push1 1
+            stop
+            pc
+            unused:
+            jumpdest
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./example.etk", text)?;
+
+        assert_eq!(ingest.warnings().len(), 2);
+        assert_matches!(ingest.warnings()[0], Warning::UnreachableCode { .. });
+        assert_matches!(
+            &ingest.warnings()[1],
+            Warning::UnusedLabel { label, .. } if label == "unused"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_fallthrough_comment() -> Result<(), Error> {
+        let text = r#"
+            caller
+            push1 a
+            jumpi
+            # fallthrough
+            a:
+            jumpdest
+            caller
+            push1 b
+            jumpi # fallthrough
+            b:
+            jumpdest
+            caller
+            push1 c
+            jumpi
+            # falls through, but without the pragma
+            c:
+            jumpdest
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./example.etk", text)?;
+
+        assert_matches!(
+            ingest.warnings(),
+            [Warning::Fallthrough { label, .. }] if label == "c"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_keep_comment() -> Result<(), Error> {
+        let text = r#"
+            caller
+            callvalue           # keep: value, from
+            swap1               # keep: from, value
+            dup2
+            # keep: from
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./example.etk", text)?;
+
+        assert_matches!(
+            ingest.warnings(),
+            [Warning::KeepMismatch { name, expected: 1, found: Some(2), offset: 4, .. }] if name == "from"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_strict() {
+        let text = r#"
+            unused:
+            jumpdest
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_strict(true);
+        let err = ingest.ingest("./example.etk", text).unwrap_err();
+
+        assert_matches!(
+            err,
+            Error::Assemble {
+                source: AsmError::PromotedWarning { .. },
+                ..
+            }
+        );
+        assert_eq!(ingest.warnings().len(), 1);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_strict_exported_labels() -> Result<(), Error> {
+        let (f, root) = new_file("%export entry\nentry:\njumpdest\n");
+
+        // Neither label is used, but one is exported and the other marks a
+        // place in code deployed elsewhere.
         let text = format!(
             r#"
-            push1 1
-            %import("{}")
-            push1 2
-        "#,
+                %import("{}")
+
+                %capture runtime {{
+                    marker:
+                    stop
+                }}
+
+                %bytes(runtime())
+            "#,
+            f.path().display(),
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_strict(true);
+        ingest.ingest(&root, &text)?;
+
+        assert!(ingest.warnings().is_empty());
+        drop(ingest);
+        assert_eq!(output, hex!("5b00"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_source_map() -> Result<(), Error> {
+        let text = "%macro twice(x)\n push1 $x\n push1 $x\n%end\ncaller\n%twice(2)\n";
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./main.etk", text)?;
+
+        let info = ingest.debug_info().clone();
+        assert_eq!(output, hex!("3360026002"));
+        assert_eq!(info.srcmap(&output), "41:6:0:-;48:9;");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_source_map_import() -> Result<(), Error> {
+        let (f, root) = new_file("caller");
+
+        let import = format!("%import(\"{}\")", f.path().display());
+        let text = format!("{}\npush1 1\n", import);
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+
+        let info = ingest.debug_info().clone();
+        assert_eq!(info.sources[1], f.path());
+
+        let expected = format!("0:6:1:-;{}:7:0", import.len() + 1);
+        assert_eq!(info.srcmap(&output), expected);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_macro_details() -> Result<(), Error> {
+        let (f, root) = new_file("%macro one()\n push1 1\n%end\n%one()\n");
+
+        let text = format!(
+            "%macro twice(x)\n push1 $x\n push1 $x\n%end\n%twice(2)\n%include(\"{}\")\n",
+            f.path().display()
+        );
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_macro_details(true);
+        ingest.ingest(root, &text)?;
+
+        let info = ingest.debug_info().clone();
+        assert_eq!(output, hex!("600260026001"));
+
+        let macros: Vec<_> = info.macros.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(macros, ["twice", "one"]);
+
+        let ops: Vec<_> = info.expansions.iter().map(|e| e.ops.clone()).collect();
+        assert_eq!(ops, [vec!["push1 0x02", "push1 0x02"], vec!["push1 0x01"]]);
+        assert_eq!(info.expansions[0].arguments, ["2"]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_reproducible() -> Result<(), Error> {
+        let (f, root) = new_file("%macro spin()\n a:\n jumpdest\n push1 a\n jump\n%end\n%spin()\n");
+
+        let text = format!("%include(\"{}\")\n%back()\n", f.path().display());
+        let text = format!("%macro back()\n a:\n push1 a\n%end\n{}", text);
+
+        let ingest = || -> Result<DebugInfo, Error> {
+            let mut output = Vec::new();
+            let mut ingest = Ingest::new(&mut output);
+            ingest.set_reproducible(true);
+            ingest.ingest(&root, &text)?;
+            let info = ingest.debug_info().clone();
+            assert_eq!(output, hex!("5b600056 6004"));
+            Ok(info)
+        };
+
+        let first = ingest()?;
+        assert_eq!(first, ingest()?);
+
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.set_reproducible(true);
+        let text = r#"%include_sol_runtime("Token.sol", "Token")"#;
+        let err = ingest.ingest("./example.etk", text).unwrap_err();
+        assert_matches!(err, Error::NotReproducible { feature, .. } if feature == "`%include_sol_runtime`");
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_scopes() -> Result<(), Error> {
+        let text = r#"
+            %scope init {
+                push1 runtime.size
+                dup1
+                push1 runtime.start
+                push0
+                codecopy
+                push0
+                return
+            }
+
+            %scope runtime {
+                push1 a
+                jump
+                a:
+                jumpdest
+                stop
+            }
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./example.etk", text)?;
+        assert!(ingest.warnings().is_empty());
+
+        let labels: Vec<_> = ingest
+            .debug_info()
+            .labels
+            .iter()
+            .map(|s| (s.name.clone(), s.offset))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                ("init.start".to_owned(), 0),
+                ("init.end".to_owned(), 9),
+                ("runtime.start".to_owned(), 9),
+                ("runtime.a".to_owned(), 12),
+                ("runtime.end".to_owned(), 14),
+            ]
+        );
+
+        drop(ingest);
+        assert_eq!(output, hex!("60058060095f395ff3" "600c565b00"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_runtime_start() -> Result<(), Error> {
+        let text = r#"
+            %def word_size()
+                32
+            %end
+
+            %macro store(value)
+                push1 $value
+                push0
+                mstore
+            %end
+
+            push1 runtime.size
+            dup1
+            push1 runtime.start
+            push0
+            codecopy
+            push0
+            return
+
+            %runtime_start
+            %store(word_size())
+            push1 done
+            jump
+            done:
+            jumpdest
+            push1 $$
+            stop
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./example.etk", text)?;
+        assert!(ingest.warnings().is_empty());
+        assert_eq!(
+            ingest.runtime(),
+            Some(&hex!("60205f52 6007565b 600b00")[..])
+        );
+
+        drop(ingest);
+        assert_eq!(
+            output,
+            hex!("600b8060095f395ff3" "60205f52 6007565b 600b00")
+        );
+
+        // The whole program is initcode, ending with the runtime code.
+        let mut profile = Profile::builtin("cancun").unwrap();
+        profile.max_code_size = Some(11);
+        profile.max_initcode_size = Some(20);
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.set_profile(Some(profile.clone()));
+        ingest.ingest("./example.etk", text)?;
+
+        profile.max_code_size = Some(10);
+        ingest.set_profile(Some(profile));
+        let err = ingest.ingest("./example.etk", text).unwrap_err();
+        assert_matches!(err, Error::ProfileViolation { violations, .. } if violations.len() == 1);
+
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.ingest("./example.etk", "caller\n")?;
+        assert_eq!(ingest.runtime(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_runtime_start_misplaced() {
+        let twice = "%runtime_start\ncaller\n%runtime_start\n";
+        let scoped = "%scope init {\n%runtime_start\n}\n";
+
+        for text in [twice, scoped] {
+            let mut ingest = Ingest::new(Vec::new());
+            let err = ingest.ingest("./example.etk", text).unwrap_err();
+            assert_matches!(err, Error::MisplacedRuntimeStart { .. });
+        }
+
+        let (f, root) = new_file("%runtime_start\ncaller\n");
+        let text = format!("%import(\"{}\")\n", f.path().display());
+
+        let mut ingest = Ingest::new(Vec::new());
+        let err = ingest.ingest(root, &text).unwrap_err();
+        assert_matches!(
+            err,
+            Error::MisplacedRuntimeStart { path, .. }
+                if path.file_name() == f.path().file_name()
+        );
+    }
+
+    #[test]
+    fn ingest_capture() -> Result<(), Error> {
+        let text = r#"
+            %macro store()
+                push0
+                sstore
+            %end
+
+            %capture runtime {
+                caller
+                %store()
+                push1 done
+                jump
+                done:
+                jumpdest
+                push1 codesize()
+                stop
+            }
+
+            push1 len(runtime())
+            push32 keccak256(runtime())
+            %bytes(runtime())
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./example.etk", text)?;
+        drop(ingest);
+
+        let runtime = hex!("335f55600656" "5b600a00");
+        let mut expected = hex!("600a7f").to_vec();
+        expected.extend(Keccak256::digest(runtime));
+        expected.extend(runtime);
+        assert_eq!(output, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_hash_leading_zero() -> Result<(), Error> {
+        // The hash of `f490()` starts with a zero byte, which is kept.
+        let text = r#"
+            %bytes(selector("f490()"))
+            %bytes(keccak256("f490()"))
+            push1 len(topic("f490()"))
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./example.etk", text)?;
+
+        let digest = hex!("00a965e5bf32711cc87800474a3fb9a13e52062489ec28acf5996fa6b37057b1");
+        let mut expected = digest[..4].to_vec();
+        expected.extend(digest);
+        expected.extend(hex!("6020"));
+        assert_eq!(output, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_capture_import() -> Result<(), Error> {
+        let (f, root) =
+            new_file("%def one()\n1\n%end\n%capture blob {\npush1 one()\n}\n%export blob\n");
+        let text = format!(
+            "%import(\"{}\")\n%def one()\n2\n%end\npush1 one()\n%bytes(blob())\n",
+            f.path().display(),
+        );
+
+        // The imported file's `one` is private, even in its captured region.
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest(root, &text)?;
+        drop(ingest);
+        assert_eq!(output, hex!("6002" "6001"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_nested_scopes() -> Result<(), Error> {
+        let text = r#"
+            %scope outer {
+                push1 inner.size
+                %scope inner {
+                    a:
+                    push1 a
+                }
+                push1 size
+            }
+            push1 outer.inner.a
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./example.etk", text)?;
+
+        assert_eq!(output, hex!("6002" "6002" "6006" "6002"));
+
+        let text = "%scope s {\nstart:\npush1 start\n}\npush1 s.size\n";
+        let err = Ingest::new(Vec::new())
+            .ingest("./example.etk", text)
+            .unwrap_err();
+        assert_matches!(
+            err,
+            Error::Assemble {
+                source: AsmError::DuplicateLabel { label, .. },
+                ..
+            } if label == "s.start"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_artifact() -> Result<(), Error> {
+        let artifact = r#"{
+            "bytecode": { "object": "0x6002" },
+            "deployedBytecode": "0x6001",
+            "evm": { "deployedBytecode": { "object": "33" } }
+        }"#;
+        let (f, root) = new_file(artifact);
+
+        let text = format!(
+            r#"
+            %include_artifact("{0}")
+            %include_artifact("{0}", field="bytecode")
+            %include_artifact("{0}", "evm.deployedBytecode")
+            "#,
             f.path().display()
         );
 
         let mut output = Vec::new();
         let mut ingest = Ingest::new(&mut output);
-        ingest.ingest(root, &text)?;
-        assert_eq!(output, hex!("6001602a6002"));
+        ingest.ingest(&root, &text)?;
+        assert_eq!(ingest.dependencies()[1], f.path());
+
+        drop(ingest);
+        assert_eq!(output, hex!("6001" "6002" "33"));
+
+        let text = format!(r#"%include_artifact("{}", "abi")"#, f.path().display());
+        let err = Ingest::new(Vec::new()).ingest(&root, &text).unwrap_err();
+        assert_matches!(err, Error::ArtifactField { field, .. } if field == "abi");
+
+        let (f, root) = new_file("{");
+        let text = format!(r#"%include_artifact("{}")"#, f.path().display());
+        let err = Ingest::new(Vec::new()).ingest(&root, &text).unwrap_err();
+        assert_matches!(err, Error::InvalidArtifact { .. });
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_verify_against() -> Result<(), Error> {
+        let (f, _) = new_file("push1 1\npush1 2\nstop\n");
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        let mismatch = ingest.verify_against(f.path(), hex!("6001600200").to_vec())?;
+        assert_eq!(mismatch, None);
+        drop(ingest);
+        assert_eq!(output, hex!("6001600200"));
+
+        let mut ingest = Ingest::new(Vec::new());
+        let mismatch = ingest
+            .verify_against(f.path(), &hex!("60016003")[..])?
+            .unwrap();
+        assert_eq!(mismatch.offset, 3);
+        assert_eq!((mismatch.expected_len, mismatch.actual_len), (4, 5));
+        assert_eq!(mismatch.actual[1].immediate.as_deref(), Some("0x02"));
+        assert_eq!(mismatch.actual[2].mnemonic, "stop");
+
+        let (expected, _) = new_file("0x6001600200\n");
+        let mismatch = Ingest::new(Vec::new()).verify_against(f.path(), expected.path())?;
+        assert_eq!(mismatch, None);
+
+        let missing = f.path().with_extension("missing");
+        let err = Ingest::new(Vec::new())
+            .verify_against(f.path(), missing)
+            .unwrap_err();
+        assert_matches!(err, Error::Io { message, .. } if message == "reading expected code");
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_profile() -> Result<(), Error> {
+        let text = "push0\npush1 1\n";
+
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.set_profile(Profile::builtin("shanghai"));
+        ingest.ingest("./test.etk", text)?;
+
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.set_profile(Profile::builtin("london"));
+        let err = ingest.ingest("./test.etk", text).unwrap_err();
+        assert_matches!(
+            err,
+            Error::ProfileViolation { profile, violations, .. }
+            if profile == "london" && violations.len() == 1
+        );
+
+        let mut profile = Profile::builtin("cancun").unwrap();
+        profile.max_code_size = Some(2);
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.set_profile(Some(profile));
+        assert!(ingest.ingest("./test.etk", text).is_err());
+        ingest.set_code_kind(CodeKind::Initcode);
+        ingest.ingest("./test.etk", text)?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_policy() -> Result<(), Error> {
+        let policy = Policy::from_toml(
+            r#"
+            name = "acme"
+            url = "https://security.acme.example/evm"
+
+            [[rule]]
+            id = "SEC-1"
+            ban_ops = ["selfdestruct"]
+
+            [[rule]]
+            id = "SRC-1"
+            require_pragmas = ["etk"]
+            "#,
+        )
+        .unwrap();
+
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.set_policy(Some(policy.clone()));
+        ingest.ingest("./test.etk", "%pragma etk \">=0.1\"\ncaller\n")?;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_policy(Some(policy));
+        let err = ingest
+            .ingest("./test.etk", "caller\nselfdestruct\n")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "code violates policy `acme`: SRC-1: the source must declare `%pragma etk`; \
+             SEC-1: `selfdestruct` at 0x1 is banned (see https://security.acme.example/evm)"
+        );
+        assert_matches!(
+            err,
+            Error::PolicyViolation { policy, violations, .. }
+            if policy == "acme" && violations.len() == 2
+        );
+        assert!(output.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn ingest_include() -> Result<(), Error> {
-        let (f, root) = new_file(
-            r#"
-                a:
-                jumpdest
-                pc
-                push1 a
-                jump
-            "#,
-        );
-
+    fn ingest_label_difference() -> Result<(), Error> {
+        // The push between `a` and `b` only grows to two bytes once `far` is
+        // placed, which moves `b` after the difference was first computed.
         let text = format!(
-            r#"
-            push1 1
-            %include("{}")
-            push1 2
-        "#,
-            f.path().display()
+            "%push(b - a)\na:\n%push(far)\nb:\n{}far:\njumpdest\n",
+            "pc\n".repeat(300)
         );
 
         let mut output = Vec::new();
         let mut ingest = Ingest::new(&mut output);
-        ingest.ingest(root, &text)?;
+        ingest.ingest("./test.etk", &text)?;
+        assert_eq!(output[..5], hex!("6003 610131"));
 
-        assert_eq!(output, hex!("60015b586000566002"));
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_code_size() -> Result<(), Error> {
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.etk", "push1 codesize()\npush1 $$ - 1\n")?;
+        assert_eq!(output, hex!("6004 6003"));
+
+        let text = r#"
+            %scope blob {
+                push1 codesize()
+                push1 0
+            }
+            push1 $$
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.etk", text)?;
+        assert_eq!(output, hex!("6004 6000 6006"));
 
         Ok(())
     }
 
     #[test]
-    fn ingest_import_twice() {
-        let (f, root) = new_file(
-            r#"
-                a:
-                jumpdest
-                push1 a
-            "#,
-        );
+    fn ingest_conditional() -> Result<(), Error> {
+        let text = "%if has_push0 {\nzero:\npush0\n} %else {\nzero:\npush1 0\n}\npush1 zero\n";
 
-        let text = format!(
-            r#"
-                push1 1
-                %import("{0}")
-                %import("{0}")
-                push1 2
-            "#,
-            f.path().display()
-        );
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.ingest("./test.etk", text)?;
+        assert_eq!(output, hex!("5f 6000"));
 
         let mut output = Vec::new();
         let mut ingest = Ingest::new(&mut output);
-        let err = ingest.ingest(root, &text).unwrap_err();
+        ingest.set_profile(Profile::builtin("london"));
+        ingest.ingest("./test.etk", text)?;
+        assert_eq!(output, hex!("6000 6000"));
+
+        let mut ingest = Ingest::new(Vec::new());
+        let err = ingest
+            .ingest("./test.etk", "%if has_nope { caller }")
+            .unwrap_err();
+        assert_matches!(
+            err,
+            Error::InvalidCondition {
+                source: AsmError::UnknownCapability { .. },
+                ..
+            }
+        );
+
+        let err = ingest
+            .ingest("./test.etk", "a:\n%if a { caller }")
+            .unwrap_err();
+        assert_matches!(err, Error::InvalidCondition { .. });
 
+        let err = ingest.ingest("./test.etk", "push1 has_push0").unwrap_err();
         assert_matches!(
             err,
             Error::Assemble {
-                source: AsmError::DuplicateLabel { label, ..}
-            } if label == "a"
+                source: AsmError::UndeclaredLabels { labels, .. },
+            } if labels == vec!["has_push0".to_string()]
         );
+
+        Ok(())
     }
 
     #[test]
-    fn ingest_include_hex() -> Result<(), Error> {
-        let (f, root) = new_file("deadbeef0102f6");
-
-        let text = format!(
-            r#"
-                push1 1
-                %include_hex("{}")
-                push1 2
-            "#,
-            f.path().display(),
-        );
+    fn ingest_label_named_like_capability() -> Result<(), Error> {
+        // Outside of conditions, `has_..` is a label like any other.
+        let text = "push1 has_push0
+jumpdest
+has_push0:
+has_value:
+push1 has_value
+";
 
         let mut output = Vec::new();
         let mut ingest = Ingest::new(&mut output);
-        ingest.ingest(root, &text)?;
-        assert_eq!(output, hex!("6001deadbeef0102f66002"));
+        ingest.set_profile(Profile::builtin("london"));
+        ingest.ingest("./test.etk", text)?;
+        assert_eq!(output, hex!("6003 5b 6003"));
 
         Ok(())
     }
 
     #[test]
-    fn ingest_include_hex_label() -> Result<(), Error> {
-        let (f, root) = new_file("deadbeef0102f6");
+    fn ingest_conditional_eof() -> Result<(), Error> {
+        let text = "%if has_rjump {\nrjump 0\n} %else {\ncaller\n}\n";
 
-        let text = format!(
-            r#"
-                push1 1
-                %include_hex("{}")
-                a:
-                jumpdest
-                push1 a
-                push1 0xff
-            "#,
-            f.path().display(),
-        );
+        for profile in [None, Profile::builtin("prague")] {
+            let mut output = Vec::new();
+            let mut ingest = Ingest::new(&mut output);
+            ingest.set_profile(profile);
+            ingest.ingest("./test.etk", text)?;
+            assert_eq!(output, hex!("33"));
+        }
 
         let mut output = Vec::new();
         let mut ingest = Ingest::new(&mut output);
-        ingest.ingest(root, &text)?;
-        assert_eq!(output, hex!("6001deadbeef0102f65b600960ff"));
+        ingest.set_profile(Profile::builtin("eof"));
+        ingest.ingest("./test.etk", text)?;
+        assert_eq!(output, hex!("e00000"));
 
         Ok(())
     }
 
     #[test]
-    fn ingest_pending_then_raw() -> Result<(), Error> {
-        let (f, root) = new_file("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+    fn ingest_conditional_macro() -> Result<(), Error> {
+        let text = r#"
+            %def both(a, b)
+                $a & $b
+            %end
 
-        let text = format!(
-            r#"
-                push2 lbl
-                %include_hex("{}")
-                lbl:
-                jumpdest
-            "#,
-            f.path().display(),
-        );
+            %def modern()
+                both(has_push0, has_tload)
+            %end
+
+            %if modern() {
+                push0
+            } %else {
+                push1 0
+            }
+        "#;
 
         let mut output = Vec::new();
         let mut ingest = Ingest::new(&mut output);
-        ingest.ingest(root, &text)?;
+        ingest.ingest("./test.etk", text)?;
+        assert_eq!(output, hex!("5f"));
 
-        let expected = hex!("61001caaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa5b");
-        assert_eq!(output, expected);
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_profile(Profile::builtin("shanghai"));
+        ingest.ingest("./test.etk", text)?;
+        assert_eq!(output, hex!("6000"));
+
+        let mut ingest = Ingest::new(Vec::new());
+        let err = ingest
+            .ingest(
+                "./test.etk",
+                "%if later() { caller }\n%def later()\n1\n%end",
+            )
+            .unwrap_err();
+        assert_matches!(
+            err,
+            Error::InvalidCondition {
+                source: AsmError::UndeclaredExpressionMacro { .. },
+                ..
+            }
+        );
 
         Ok(())
     }
 
     #[test]
-    fn ingest_import_in_import() -> Result<(), Error> {
-        let (end, _) = new_file(
-            r#"
-                end:
-                jumpdest
-                push1 start
-                push1 middle
-            "#,
-        );
+    #[cfg(not(feature = "solc"))]
+    fn ingest_sol_runtime_disabled() {
+        let text = r#"%include_sol_runtime("Token.sol", "Token")"#;
+        let err = Ingest::new(Vec::new())
+            .ingest("./example.etk", text)
+            .unwrap_err();
+        assert_matches!(err, Error::SolcDisabled { .. });
+    }
 
-        let (middle, root) = new_file(format!(
-            r#"
-                %import("{}")
-                middle:
-                jumpdest
-                push2 start
-                push2 end
-            "#,
-            end.path().display(),
-        ));
+    #[test]
+    #[cfg(all(unix, feature = "solc"))]
+    fn ingest_sol_runtime() -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for solc, printing the output of `--combined-json`.
+        let dir = tempfile::tempdir().unwrap();
+        let solc = dir.path().join("solc");
+        let script = r#"#!/bin/sh
+if [ "$3" = "Broken.sol" ]; then
+    echo "Error: expected ';'" >&2
+    exit 1
+fi
+echo '{"contracts":{"Lib.sol:Lib":{"bin-runtime":"00"},"'"$3"':Token":{"bin-runtime":"6001"}}}'
+"#;
+        std::fs::write(&solc, script).unwrap();
+        std::fs::set_permissions(&solc, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let text = r#"
+            caller
+            %include_sol_runtime("Token.sol", "Token")
+        "#;
+
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_solc(&solc);
+        ingest.ingest("./example.etk", text)?;
+        assert_eq!(ingest.dependencies()[1], Path::new("Token.sol"));
+
+        drop(ingest);
+        assert_eq!(output, hex!("336001"));
+
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.set_solc(&solc);
+        let text = r#"%include_sol_runtime("Token.sol", "Other")"#;
+        let err = ingest.ingest("./example.etk", text).unwrap_err();
+        assert_matches!(err, Error::SolcContract { contract, .. } if contract == "Other");
+
+        let text = r#"%include_sol_runtime("Broken.sol", "Token")"#;
+        let err = ingest.ingest("./example.etk", text).unwrap_err();
+        assert_matches!(err, Error::Solc { message, .. } if message == "Error: expected ';'");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_symbol_file() -> Result<(), Error> {
+        let (f, root) = new_file("inner:\njumpdest\n");
+        let sym = f.path().with_extension("sym");
 
         let text = format!(
-            r#"
-                push3 end
-                push3 middle
-                start:
-                jumpdest
-                %import("{}")
-            "#,
-            middle.path().display(),
+            "push1 lib.inner\nstart:\njumpdest\n%include(\"{}\", \"lib\")\n",
+            f.path().display()
         );
 
         let mut output = Vec::new();
         let mut ingest = Ingest::new(&mut output);
+        ingest.set_symbol_file(Some(sym.clone()));
         ingest.ingest(root, &text)?;
 
-        let expected = hex!("620000096200000e5b5b6008600e5b610008610009");
-        assert_eq!(output, expected);
+        let file: SymbolFile = serde_json::from_slice(&std::fs::read(&sym).unwrap()).unwrap();
+        std::fs::remove_file(&sym).unwrap();
+
+        let symbols: Vec<_> = file
+            .symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.offset))
+            .collect();
+        assert_eq!(file.version, crate::debug::VERSION);
+        assert_eq!(symbols, [("start", 2), ("lib.inner", 3)]);
 
         Ok(())
     }
-
     #[test]
-    fn ingest_import_in_include() -> Result<(), Error> {
-        let (end, _) = new_file(
-            r#"
-                included:
-                jumpdest
-                push2 backward
-                push2 forward
-            "#,
-        );
+    #[cfg(feature = "fs")]
+    fn ingest_link_references() -> Result<(), Error> {
+        let (f, root) = new_file("");
+        let artifact = f.path().with_extension("json");
 
-        let (middle, root) = new_file(format!(
-            r#"
-                pc
-                push1 backward
-                forward:
-                jumpdest
-                %import("{}")
-                backward:
-                jumpdest
-                push1 forward
-                push1 included
-            "#,
-            end.path().display(),
-        ));
+        let text = r#"
+            %extern Math
+            push1 0
+            push20 Math
+            push20 Math
+        "#;
 
-        let text = format!(
-            r#"
-                push3 backward
-                forward:
-                jumpdest
-                %include("{}")
-                backward:
-                jumpdest
-                push3 forward
-            "#,
-            middle.path().display(),
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_artifact_file(Some(artifact.clone()));
+        ingest.set_link_references(true);
+        ingest.ingest(&root, "caller\n")?;
+        ingest.ingest(&root, text)?;
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&artifact).unwrap()).unwrap();
+        std::fs::remove_file(&artifact).unwrap();
+
+        let source = root.display().to_string();
+        assert_eq!(
+            json["linkReferences"],
+            serde_json::json!({
+                source: {
+                    "Math": [
+                        { "start": 4, "length": 20 },
+                        { "start": 25, "length": 20 },
+                    ],
+                },
+            })
         );
 
+        drop(ingest);
+        assert_eq!(output.len(), 45);
+        assert_eq!(output[..4], hex!("33600073"));
+        assert_eq!(output[4..24], [0; 20]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_library_placeholders() -> Result<(), Error> {
+        let (f, root) = new_file("");
+        let artifact = f.path().with_extension("json");
+
         let mut output = Vec::new();
         let mut ingest = Ingest::new(&mut output);
-        ingest.ingest(root, &text)?;
+        ingest.set_artifact_file(Some(artifact.clone()));
+        ingest.set_link_references(true);
+        ingest.set_library_placeholders(true);
+        ingest.ingest(&root, "%extern Math\npush20 Math\n")?;
 
-        let expected = hex!("620000155b58600b5b5b61000b6100035b600360045b62000004");
-        assert_eq!(output, expected);
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&artifact).unwrap()).unwrap();
+        std::fs::remove_file(&artifact).unwrap();
+
+        let name = format!("{}:Math", root.display());
+        assert_eq!(
+            json["object"],
+            format!("73{}", crate::link::placeholder(&name))
+        );
+
+        let err = ingest
+            .ingest(&root, "%extern Math\npush2 Math\n")
+            .unwrap_err();
+        assert_matches!(err, Error::PlaceholderWidth { label, .. } if label == "Math");
 
         Ok(())
     }
 
     #[test]
-    fn ingest_directory_traversal() {
-        let (f, _) = new_file("pc");
+    #[cfg(feature = "fs")]
+    fn ingest_hashes_file() -> Result<(), Error> {
+        let (f, root) = new_file(r#"push4 selector("transfer(address,uint256)")"#);
+        let hashes = f.path().with_extension("hashes.json");
 
         let text = format!(
             r#"
-                %include("{}")
+            push4 selector("balanceOf(address)")
+            %import("{}")
+            push32 topic("Transfer(address,address,uint256)")
             "#,
             f.path().display(),
         );
 
-        let mut output = Vec::new();
-        let mut ingest = Ingest::new(&mut output);
-        let root = std::env::current_exe().unwrap();
-        let err = ingest.ingest(root, &text).unwrap_err();
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.set_hashes_file(Some(hashes.clone()));
+        ingest.ingest(&root, &text)?;
 
-        assert_matches!(err, Error::DirectoryTraversal { .. });
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&hashes).unwrap()).unwrap();
+        std::fs::remove_file(&hashes).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "balanceOf(address)": "70a08231",
+                "transfer(address,uint256)": "a9059cbb",
+            })
+        );
+
+        Ok(())
     }
 
     #[test]
-    fn ingest_recursive() {
-        let (mut f, root) = new_file("");
-        let path = f.path().display().to_string();
-        write!(f, r#"%import("{}")"#, path).unwrap();
+    #[cfg(feature = "fs")]
+    fn ingest_patches_file() -> Result<(), Error> {
+        let (f, root) = new_file(
+            r#"
+            %def owner()
+                0
+            %end
+            %patchable owner
+            push20 owner()
+            "#,
+        );
+        let patches = f.path().with_extension("patches.json");
 
         let text = format!(
             r#"
-                %import("{}")
+            caller
+            %import("{}")
             "#,
-            path,
+            f.path().display(),
+        );
+
+        let mut ingest = Ingest::new(Vec::new());
+        ingest.set_patches_file(Some(patches.clone()));
+        ingest.ingest(&root, &text)?;
+        ingest.ingest(&root, &text)?;
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&patches).unwrap()).unwrap();
+        std::fs::remove_file(&patches).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "owner": [{ "start": 2, "end": 22 }, { "start": 24, "end": 44 }],
+            })
         );
 
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn ingest_artifact_file() -> Result<(), Error> {
+        let (f, root) = new_file("");
+        let artifact = f.path().with_extension("json");
+
         let mut output = Vec::new();
         let mut ingest = Ingest::new(&mut output);
-        let err = ingest.ingest(root, &text).unwrap_err();
+        ingest.set_artifact_file(Some(artifact.clone()));
+        ingest.ingest(&root, "push1 0x40\n")?;
+        ingest.ingest(&root, "mstore\n")?;
 
-        assert_matches!(err, Error::RecursionLimit { .. });
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&artifact).unwrap()).unwrap();
+        std::fs::remove_file(&artifact).unwrap();
+
+        assert_eq!(json["object"], "604052");
+        assert_eq!(json["sourceMap"], "0:10:0:-;:6:1");
+        assert_eq!(json["opcodes"], "PUSH1 0x40 MSTORE");
+
+        Ok(())
     }
 }
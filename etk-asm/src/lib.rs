@@ -6,16 +6,98 @@
 //! The [`ingest`] module is high-level and similar to the command-line interface.
 //!
 //! The [`mod@asm`] module provides low-level access to the internals of the assembler.
+//!
+//! [`assemble_str`] assembles a single source without touching the filesystem,
+//! and works in WebAssembly (`wasm32-unknown-unknown`) when built without the
+//! default features.
+//!
+//! ## Features
+//!
+//! Without default features, the crate is the assembler itself, [`ops`] and
+//! [`mod@asm`], along with the [`ingest`] module reading sources through a
+//! [`FileProvider`](vfs::FileProvider). The rest is opt-in:
+//!
+//! - `rand` (default): random suffixes for labels declared in instruction
+//!   macros, instead of counting up.
+//! - `fs` (default): reading from and writing to the real filesystem, like
+//!   symbol files and artifacts, the on-disk build cache of [`batch`], and the
+//!   long-lived [`db`]. It also brings in the JSON, TOML, and version
+//!   requirement parsers, for `%include_artifact`, `%pragma etk`,
+//!   [`Policy::from_toml`](policy::Policy::from_toml), and
+//!   [`Profile::from_json`](profile::Profile::from_json).
+//! - `backtraces`: capture a backtrace in every error.
+//! - `solc`: compiling Solidity with `%include_sol_runtime`.
+//! - `devnet`: deploying to a development network.
+//! - `cli`: the `eas` command-line tool.
 #![deny(unsafe_code)]
 #![deny(missing_docs)]
 #![deny(unreachable_pub)]
 #![deny(missing_debug_implementations)]
 
+pub mod aa;
+pub mod abi;
+pub mod analysis;
 pub mod asm;
 mod ast;
+#[cfg(feature = "fs")]
+pub mod batch;
+#[cfg(feature = "fs")]
+pub mod cache;
+pub mod complete;
+#[cfg(feature = "fs")]
+pub mod db;
+pub mod debug;
+#[cfg(feature = "devnet")]
+pub mod devnet;
+pub mod diagnostic;
 pub mod disasm;
+pub mod dispatch;
+pub mod fmt;
+mod gas;
+pub mod info;
 pub mod ingest;
+pub mod link;
+pub mod object;
 pub mod ops;
+pub mod optimize;
 mod parse;
+pub mod patch;
+pub mod policy;
+pub mod profile;
+pub mod redeploy;
+pub mod stream;
+pub mod synth;
+pub mod trace;
+pub mod verify;
+pub mod vfs;
+pub mod warning;
 
+pub use self::info::build_info;
 pub use self::parse::error::ParseError;
+
+use self::ingest::Ingest;
+use self::vfs::MemoryFiles;
+
+/// Assemble `src`, returning the assembled code.
+///
+/// Nothing is read from the filesystem, so `%include`, `%import`, and similar
+/// instructions fail. Use [`Ingest`] with [`Ingest::set_file_provider`] to
+/// provide other files.
+///
+/// ## Example
+///
+/// ```rust
+/// # use etk_asm::ingest::Error;
+/// # use hex_literal::hex;
+/// let code = etk_asm::assemble_str("push1 42\nstop\n")?;
+/// assert_eq!(code, hex!("602a00"));
+/// # Result::<(), Error>::Ok(())
+/// ```
+pub fn assemble_str(src: &str) -> Result<Vec<u8>, ingest::Error> {
+    let mut output = Vec::new();
+    let mut ingest = Ingest::new(&mut output);
+    ingest.set_file_provider(MemoryFiles::new());
+    ingest.ingest("/main.etk", src)?;
+    drop(ingest);
+    Ok(output)
+}
@@ -0,0 +1,465 @@
+//! Linking separately assembled objects into one program.
+//!
+//! Large projects can assemble each file on its own, into a relocatable
+//! object (a [`Segment`]). Labels a file uses but doesn't declare are marked
+//! with `%extern`, and left as [`RelocationKind::External`] relocations.
+//! [`link`] then finds the object declaring each of those labels, lays the
+//! objects out one after another, and patches every relocation.
+//!
+//! Extern labels can also be left for deployment tools to fill in with the
+//! address of a library, like solc does. [`placeholder_hex`] writes each one
+//! as a `__$...$__` [`placeholder`], and [`link_libraries`] replaces those
+//! placeholders with addresses.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_asm::link::{assemble, link};
+//! # use etk_asm::link::Error;
+//! # use hex_literal::hex;
+//!
+//! let main = assemble("main", "%extern helper\npush1 helper\njump\n").unwrap();
+//! let lib = assemble("lib", "helper:\njumpdest\nstop\n").unwrap();
+//!
+//! let code = link(&[main, lib])?;
+//! assert_eq!(code, hex!("6003 56 5b 00"));
+//! # Result::<(), Error>::Ok(())
+//! ```
+mod error {
+    use snafu::{Backtrace, Snafu};
+
+    /// Errors that may arise while linking objects.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// No object declares a label used by another.
+        #[snafu(display("label `{}` used in `{}` isn't declared by any object", label, segment))]
+        #[non_exhaustive]
+        UnresolvedLabel {
+            /// The object using the label.
+            segment: String,
+
+            /// The name of the label.
+            label: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// More than one object declares a label used by another.
+        #[snafu(display(
+            "label `{}` used in `{}` is declared by several objects: {}",
+            label,
+            segment,
+            declared_by.join(", ")
+        ))]
+        #[non_exhaustive]
+        AmbiguousLabel {
+            /// The object using the label.
+            segment: String,
+
+            /// The name of the label.
+            label: String,
+
+            /// The objects declaring the label.
+            declared_by: Vec<String>,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A link reference isn't a 20 byte address inside the code, so it
+        /// can't be written as a library placeholder.
+        #[snafu(display(
+            "the link reference for `{}` at {} ({} bytes) isn't a 20 byte address inside the code",
+            label,
+            start,
+            length
+        ))]
+        #[non_exhaustive]
+        InvalidReference {
+            /// The name of the library.
+            label: String,
+
+            /// Position of the reference in the code.
+            start: usize,
+
+            /// Length of the reference, in bytes.
+            length: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// No address was given for a library placeholder.
+        #[snafu(display("no address given for the library placeholder `{}`", placeholder))]
+        #[non_exhaustive]
+        UnlinkedLibrary {
+            /// The placeholder.
+            placeholder: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The code isn't valid hex, once placeholders are replaced.
+        #[snafu(display("the linked code isn't valid hex"))]
+        #[non_exhaustive]
+        InvalidHex {
+            /// The underlying source of this error.
+            source: hex::FromHexError,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The objects couldn't be laid out.
+        #[snafu(display("the linked objects couldn't be laid out"))]
+        #[non_exhaustive]
+        Layout {
+            /// The underlying source of this error.
+            #[snafu(backtrace)]
+            source: crate::object::Error,
+        },
+    }
+}
+
+pub use self::error::Error;
+
+use crate::debug::LinkReference;
+use crate::ingest::{self, Ingest};
+use crate::object::{self, Relocation, RelocationKind, Segment};
+use crate::vfs::MemoryFiles;
+
+use sha3::{Digest, Keccak256};
+
+use snafu::{ensure, OptionExt, ResultExt};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// Length of a library placeholder, in hex digits.
+const PLACEHOLDER_LEN: usize = 40;
+
+/// Build an object named `name` from the labels and link references recorded
+/// by `ingest`, without its code.
+fn from_ingest<W>(name: String, ingest: &Ingest<W>) -> Segment {
+    let labels = ingest
+        .debug_info()
+        .labels
+        .iter()
+        .map(|s| (s.name.clone(), s.offset))
+        .collect();
+
+    let relocations = ingest
+        .link_references()
+        .values()
+        .flatten()
+        .flat_map(|(label, references)| {
+            references.iter().map(move |r| Relocation {
+                offset: r.start,
+                width: r.length,
+                kind: RelocationKind::External,
+                segment: String::new(),
+                label: Some(label.clone()),
+            })
+        })
+        .collect();
+
+    Segment {
+        name,
+        code: Vec::new(),
+        labels,
+        relocations,
+    }
+}
+
+/// Assemble `src` into an object named `name`, without touching the
+/// filesystem.
+///
+/// Extern labels that aren't declared in `src` are left as relocations, to
+/// be resolved by [`link`].
+pub fn assemble<S>(name: S, src: &str) -> Result<Segment, ingest::Error>
+where
+    S: Into<String>,
+{
+    let name = name.into();
+    let mut code = Vec::new();
+    let mut ingest = Ingest::new(&mut code);
+    ingest.set_file_provider(MemoryFiles::new());
+    ingest.set_link_references(true);
+    ingest.ingest(format!("/{}.etk", name), src)?;
+
+    let mut object = from_ingest(name, &ingest);
+    drop(ingest);
+    object.code = code;
+    Ok(object)
+}
+
+/// Assemble the file at `path` into an object, named after the path, like
+/// [`assemble`].
+pub fn assemble_file<P>(path: P) -> Result<Segment, ingest::Error>
+where
+    P: Into<PathBuf>,
+{
+    let path = path.into();
+    let mut code = Vec::new();
+    let mut ingest = Ingest::new(&mut code);
+    ingest.set_link_references(true);
+    ingest.ingest_file(path.clone())?;
+
+    let mut object = from_ingest(path.display().to_string(), &ingest);
+    drop(ingest);
+    object.code = code;
+    Ok(object)
+}
+
+/// Point every [`RelocationKind::External`] relocation of `objects` at the
+/// object declaring its label.
+///
+/// Each label must be declared by exactly one object, other than the one
+/// using it.
+pub fn resolve(objects: &[Segment]) -> Result<Vec<Segment>, Error> {
+    let mut resolved = objects.to_vec();
+
+    for object in resolved.iter_mut() {
+        let name = &object.name;
+
+        for relocation in object.relocations.iter_mut() {
+            if relocation.kind != RelocationKind::External {
+                continue;
+            }
+
+            let label = relocation.label.as_deref().unwrap_or_default();
+            let declared_by: Vec<_> = objects
+                .iter()
+                .filter(|o| &o.name != name && o.labels.contains_key(label))
+                .map(|o| o.name.clone())
+                .collect();
+
+            ensure!(
+                !declared_by.is_empty(),
+                error::UnresolvedLabel {
+                    segment: name,
+                    label,
+                }
+            );
+            ensure!(
+                declared_by.len() == 1,
+                error::AmbiguousLabel {
+                    segment: name,
+                    label,
+                    declared_by,
+                }
+            );
+
+            relocation.kind = RelocationKind::Absolute;
+            relocation.segment = declared_by.into_iter().next().unwrap();
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Combine `objects` into a program, placing them one after another, in
+/// order.
+///
+/// Labels used by one object and declared by another are resolved with
+/// [`resolve`], then the objects are laid out with
+/// [`layout`](crate::object::layout).
+pub fn link(objects: &[Segment]) -> Result<Vec<u8>, Error> {
+    let resolved = resolve(objects)?;
+    object::layout(&resolved).context(error::Layout)
+}
+
+/// The placeholder solc leaves in hex encoded code for the address of the
+/// library `name`: `__$`, the first 34 hex digits of the keccak256 hash of
+/// `name`, and `$__`.
+///
+/// `name` is the fully qualified name of the library. For an extern label,
+/// that's the file and label it's listed under in `linkReferences`, like
+/// `main.etk:Math`.
+pub fn placeholder(name: &str) -> String {
+    let hash = hex::encode(Keccak256::digest(name.as_bytes()));
+    format!("__${}$__", &hash[..34])
+}
+
+/// Hex encode `code`, writing the [`placeholder`] of each link reference in
+/// place of its zeroed bytes, like solc does for libraries.
+///
+/// `references` is listed by file and then by label, like in
+/// [`Ingest::link_references`]. Every reference must be 20 bytes long.
+pub fn placeholder_hex(
+    code: &[u8],
+    references: &BTreeMap<String, BTreeMap<String, Vec<LinkReference>>>,
+) -> Result<String, Error> {
+    let mut output = hex::encode(code);
+
+    for (file, labels) in references {
+        for (label, references) in labels {
+            let placeholder = placeholder(&format!("{}:{}", file, label));
+
+            for reference in references {
+                ensure!(
+                    reference.length == PLACEHOLDER_LEN / 2
+                        && reference.start + reference.length <= code.len(),
+                    error::InvalidReference {
+                        label,
+                        start: reference.start,
+                        length: reference.length,
+                    }
+                );
+
+                let start = 2 * reference.start;
+                output.replace_range(start..start + PLACEHOLDER_LEN, &placeholder);
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Replace the library placeholders in the hex encoded `code` with the
+/// addresses of the libraries, given by fully qualified name (see
+/// [`placeholder`]), and decode it.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::link::{link_libraries, placeholder};
+/// # use etk_asm::link::Error;
+/// # use std::collections::BTreeMap;
+///
+/// let code = format!("73{}ff", placeholder("main.etk:Math"));
+///
+/// let mut addresses = BTreeMap::new();
+/// addresses.insert("main.etk:Math".to_owned(), [0x11; 20]);
+///
+/// let linked = link_libraries(&code, &addresses)?;
+/// assert_eq!(linked[..2], [0x73, 0x11]);
+/// # Result::<(), Error>::Ok(())
+/// ```
+pub fn link_libraries(
+    code: &str,
+    addresses: &BTreeMap<String, [u8; 20]>,
+) -> Result<Vec<u8>, Error> {
+    let addresses: HashMap<_, _> = addresses
+        .iter()
+        .map(|(name, address)| (placeholder(name), hex::encode(address)))
+        .collect();
+
+    let mut code = code.strip_prefix("0x").unwrap_or(code).to_owned();
+
+    while let Some(start) = code.find("__$") {
+        let end = code.len().min(start + PLACEHOLDER_LEN);
+        let found = &code[start..end];
+        let address = addresses
+            .get(found)
+            .context(error::UnlinkedLibrary { placeholder: found })?;
+        code.replace_range(start..end, address);
+    }
+
+    hex::decode(code).context(error::InvalidHex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_matches::assert_matches;
+
+    use hex_literal::hex;
+
+    #[test]
+    fn link_objects() -> Result<(), Error> {
+        let main = assemble("main", "%extern a\n%extern b\npush1 a\npush2 b\n").unwrap();
+        assert_eq!(main.relocations.len(), 2);
+
+        let lib_a = assemble("a", "a:\njumpdest\n").unwrap();
+        let lib_b = assemble("b", "stop\nb:\njumpdest\n").unwrap();
+
+        let code = link(&[main.clone(), lib_a.clone(), lib_b.clone()])?;
+        assert_eq!(code, hex!("6005 610007 5b 005b"));
+
+        let code = link(&[lib_b, main, lib_a])?;
+        assert_eq!(code, hex!("005b 6007 610001 5b"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn link_errors() {
+        let main = assemble("main", "%extern a\npush1 a\n").unwrap();
+        let lib = assemble("lib", "a:\njumpdest\n").unwrap();
+        let other = assemble("other", "a:\njumpdest\n").unwrap();
+
+        let err = link(&[main.clone()]).unwrap_err();
+        assert_matches!(err, Error::UnresolvedLabel { segment, label, .. } if segment == "main" && label == "a");
+
+        let err = link(&[main.clone(), lib.clone(), other]).unwrap_err();
+        assert_matches!(err, Error::AmbiguousLabel { declared_by, .. } if declared_by == ["lib", "other"]);
+
+        let err = link(&[main, lib, Segment::new("main")]).unwrap_err();
+        assert_matches!(
+            err,
+            Error::Layout {
+                source: object::Error::DuplicateSegment { .. },
+            }
+        );
+    }
+
+    #[test]
+    fn library_placeholders() -> Result<(), Error> {
+        assert_eq!(
+            placeholder("main.etk:Math"),
+            "__$f9fa1533d8c2847791eca88b16af553ecb$__"
+        );
+
+        let mut code = vec![0x73];
+        code.extend_from_slice(&[0; 20]);
+        code.push(0xff);
+
+        let reference = LinkReference {
+            start: 1,
+            length: 20,
+        };
+        let mut references = BTreeMap::new();
+        references
+            .entry("main.etk".to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert("Math".to_owned(), vec![reference]);
+
+        let hex = placeholder_hex(&code, &references)?;
+        assert_eq!(hex, format!("73{}ff", placeholder("main.etk:Math")));
+
+        let mut addresses = BTreeMap::new();
+        addresses.insert("main.etk:Math".to_owned(), [0x11; 20]);
+        let linked = link_libraries(&format!("0x{}", hex), &addresses)?;
+        assert_eq!(linked[1..21], [0x11; 20]);
+        assert_eq!(linked.len(), code.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn library_placeholder_errors() {
+        let code = format!("73{}ff", placeholder("main.etk:Math"));
+        let err = link_libraries(&code, &BTreeMap::new()).unwrap_err();
+        assert_matches!(err, Error::UnlinkedLibrary { placeholder: p, .. } if p == placeholder("main.etk:Math"));
+
+        let mut references = BTreeMap::new();
+        references
+            .entry("main.etk".to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert(
+                "Math".to_owned(),
+                vec![LinkReference {
+                    start: 1,
+                    length: 2,
+                }],
+            );
+        let err = placeholder_hex(&hex!("610000"), &references).unwrap_err();
+        assert_matches!(err, Error::InvalidReference { length: 2, .. });
+    }
+}
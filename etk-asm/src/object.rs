@@ -0,0 +1,449 @@
+//! Relocatable segments of code, and laying them out into a program.
+//!
+//! A [`Segment`] is a block of assembled code whose final position isn't
+//! known yet. Immediates that depend on the layout (for example, a push of a
+//! label in another segment) are left as placeholders, and described by a
+//! [`Relocation`]. [`layout`] places segments one after another and patches
+//! every relocation, so segments can be rearranged without reassembling them.
+mod error {
+    use snafu::{Backtrace, Snafu};
+
+    /// Errors that may arise while laying out segments.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// Two segments have the same name.
+        #[snafu(display("segment `{}` declared multiple times", segment))]
+        #[non_exhaustive]
+        DuplicateSegment {
+            /// The name of the conflicting segment.
+            segment: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A relocation refers to a segment that doesn't exist.
+        #[snafu(display("segment `{}` was never defined", segment))]
+        #[non_exhaustive]
+        UndeclaredSegment {
+            /// The name of the missing segment.
+            segment: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A relocation refers to a label that its segment doesn't define.
+        #[snafu(display("label `{}` was never defined in segment `{}`", label, segment))]
+        #[non_exhaustive]
+        UndeclaredLabel {
+            /// The segment that was searched.
+            segment: String,
+
+            /// The name of the missing label.
+            label: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A relocation refers to a label of another object, and must be
+        /// linked before the segments are laid out.
+        #[snafu(display(
+            "label `{}` used in segment `{}` must be resolved by linking",
+            label,
+            segment
+        ))]
+        #[non_exhaustive]
+        UnlinkedRelocation {
+            /// The segment containing the relocation.
+            segment: String,
+
+            /// The name of the label.
+            label: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A relocation doesn't fit inside the code of its segment.
+        #[snafu(display(
+            "relocation at 0x{:x} ({} bytes) is outside of segment `{}`",
+            offset,
+            width,
+            segment
+        ))]
+        #[non_exhaustive]
+        RelocationOutOfBounds {
+            /// The segment containing the relocation.
+            segment: String,
+
+            /// Position of the relocation in its segment.
+            offset: usize,
+
+            /// Width of the relocation.
+            width: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The value of a relocation is too large for its placeholder.
+        #[snafu(display(
+            "value 0x{:x} is too large for the {} byte relocation at 0x{:x} in segment `{}`",
+            value,
+            width,
+            offset,
+            segment
+        ))]
+        #[non_exhaustive]
+        RelocationTooLarge {
+            /// The segment containing the relocation.
+            segment: String,
+
+            /// Position of the relocation in its segment.
+            offset: usize,
+
+            /// Width of the relocation.
+            width: usize,
+
+            /// The value that didn't fit.
+            value: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+}
+
+pub use self::error::Error;
+
+use indexmap::IndexMap;
+
+use snafu::{ensure, OptionExt};
+
+use std::collections::HashMap;
+
+/// How the value of a [`Relocation`] is computed from the layout.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum RelocationKind {
+    /// The position of the label in the laid out program.
+    Absolute,
+
+    /// The position of the label relative to the start of its segment, which
+    /// doesn't depend on where the segment is placed.
+    SegmentRelative,
+
+    /// The length of the segment, in bytes. The relocation's label is ignored.
+    SegmentSize,
+
+    /// The position of a label declared by another segment, which is found
+    /// when [linking](crate::link). The relocation's segment is ignored, and
+    /// [`layout`] can't patch it.
+    External,
+}
+
+/// A placeholder in a segment's code, to be filled in once the layout is known.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Relocation {
+    /// Position of the placeholder (usually the immediate of a push) in the
+    /// code of the segment containing it.
+    pub offset: usize,
+
+    /// Length of the placeholder, in bytes.
+    pub width: usize,
+
+    /// How to compute the value written into the placeholder.
+    pub kind: RelocationKind,
+
+    /// The segment the relocation refers to.
+    pub segment: String,
+
+    /// The label the relocation refers to, if any.
+    pub label: Option<String>,
+}
+
+/// A block of assembled code, with its labels and relocations.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Segment {
+    /// The name identifying the segment.
+    pub name: String,
+
+    /// The assembled code, with zeros in place of each relocation.
+    pub code: Vec<u8>,
+
+    /// Labels declared in the segment, and their positions relative to its
+    /// start.
+    pub labels: IndexMap<String, usize>,
+
+    /// Placeholders to patch once the layout is known.
+    pub relocations: Vec<Relocation>,
+}
+
+impl Segment {
+    /// Create an empty segment named `name`.
+    pub fn new<S>(name: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Place `segments` one after another, in order, and patch their relocations.
+///
+/// Returns the code of the laid out program.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::object::{layout, Relocation, RelocationKind, Segment};
+/// # use etk_asm::object::Error;
+/// # use hex_literal::hex;
+///
+/// let mut main = Segment::new("main");
+/// main.code = hex!("6000 56").to_vec(); // push1 lib.start; jump
+/// main.relocations.push(Relocation {
+///     offset: 1,
+///     width: 1,
+///     kind: RelocationKind::Absolute,
+///     segment: "lib".into(),
+///     label: Some("start".into()),
+/// });
+///
+/// let mut lib = Segment::new("lib");
+/// lib.code = hex!("5b 00").to_vec(); // jumpdest; stop
+/// lib.labels.insert("start".into(), 0);
+///
+/// let code = layout(&[main, lib])?;
+/// assert_eq!(code, hex!("6003 56 5b 00"));
+/// # Result::<(), Error>::Ok(())
+/// ```
+pub fn layout(segments: &[Segment]) -> Result<Vec<u8>, Error> {
+    let mut bases = HashMap::new();
+    let mut position = 0;
+    for segment in segments {
+        let old = bases.insert(segment.name.as_str(), (position, segment));
+        ensure!(
+            old.is_none(),
+            error::DuplicateSegment {
+                segment: &segment.name
+            }
+        );
+        position += segment.code.len();
+    }
+
+    let mut output = Vec::with_capacity(position);
+
+    for segment in segments {
+        let mut code = segment.code.clone();
+
+        for relocation in segment.relocations.iter() {
+            ensure!(
+                relocation.kind != RelocationKind::External,
+                error::UnlinkedRelocation {
+                    segment: &segment.name,
+                    label: relocation.label.as_deref().unwrap_or_default(),
+                }
+            );
+
+            let (base, target) =
+                bases
+                    .get(relocation.segment.as_str())
+                    .context(error::UndeclaredSegment {
+                        segment: &relocation.segment,
+                    })?;
+
+            let value = match relocation.kind {
+                RelocationKind::SegmentSize => target.code.len(),
+                RelocationKind::Absolute
+                | RelocationKind::SegmentRelative
+                | RelocationKind::External => {
+                    let label = relocation.label.as_deref().unwrap_or_default();
+                    let offset =
+                        target
+                            .labels
+                            .get(label)
+                            .copied()
+                            .context(error::UndeclaredLabel {
+                                segment: &target.name,
+                                label,
+                            })?;
+
+                    match relocation.kind {
+                        RelocationKind::Absolute => base + offset,
+                        _ => offset,
+                    }
+                }
+            };
+
+            let placeholder = code
+                .get_mut(relocation.offset..)
+                .and_then(|c| c.get_mut(..relocation.width))
+                .context(error::RelocationOutOfBounds {
+                    segment: &segment.name,
+                    offset: relocation.offset,
+                    width: relocation.width,
+                })?;
+
+            let bytes = value.to_be_bytes();
+            let zeros = bytes.iter().take_while(|b| **b == 0).count();
+            let bytes = &bytes[zeros..];
+
+            ensure!(
+                bytes.len() <= placeholder.len(),
+                error::RelocationTooLarge {
+                    segment: &segment.name,
+                    offset: relocation.offset,
+                    width: relocation.width,
+                    value,
+                }
+            );
+
+            let start = placeholder.len() - bytes.len();
+            placeholder[..start].fill(0);
+            placeholder[start..].copy_from_slice(bytes);
+        }
+
+        output.extend(code);
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn relocation(offset: usize, kind: RelocationKind, segment: &str, label: &str) -> Relocation {
+        Relocation {
+            offset,
+            width: 1,
+            kind,
+            segment: segment.into(),
+            label: Some(label.into()),
+        }
+    }
+
+    fn segments() -> Vec<Segment> {
+        // push1 data.value; push1 data.value; push1 size(data)
+        let mut code = Segment::new("code");
+        code.code = hex!("6000 6000 6000").to_vec();
+        code.relocations = vec![
+            relocation(1, RelocationKind::Absolute, "data", "value"),
+            relocation(3, RelocationKind::SegmentRelative, "data", "value"),
+            relocation(5, RelocationKind::SegmentSize, "data", ""),
+        ];
+
+        let mut data = Segment::new("data");
+        data.code = hex!("aabbccdd").to_vec();
+        data.labels.insert("value".into(), 2);
+
+        vec![code, data]
+    }
+
+    #[test]
+    fn layout_in_order() -> Result<(), Error> {
+        let code = layout(&segments())?;
+        assert_eq!(code, hex!("6008 6002 6004 aabbccdd"));
+        Ok(())
+    }
+
+    #[test]
+    fn layout_rearranged() -> Result<(), Error> {
+        let mut segments = segments();
+        segments.reverse();
+
+        let code = layout(&segments)?;
+        assert_eq!(code, hex!("aabbccdd 6002 6002 6004"));
+        Ok(())
+    }
+
+    #[test]
+    fn layout_wide_placeholder() -> Result<(), Error> {
+        let mut segments = segments();
+        segments[0].code = hex!("61ffff").to_vec();
+        segments[0].relocations = vec![Relocation {
+            width: 2,
+            ..relocation(1, RelocationKind::Absolute, "data", "value")
+        }];
+
+        let code = layout(&segments)?;
+        assert_eq!(code, hex!("610005 aabbccdd"));
+        Ok(())
+    }
+
+    #[test]
+    fn layout_too_large() {
+        let mut segments = segments();
+        segments.insert(0, Segment::new("padding"));
+        segments[0].code = vec![0; 0x100];
+
+        let err = layout(&segments).unwrap_err();
+        assert_matches!(
+            err,
+            Error::RelocationTooLarge {
+                offset: 1,
+                width: 1,
+                ..
+            }
+        );
+    }
+
+    #[test]
+    fn layout_undeclared_label() {
+        let mut segments = segments();
+        segments[1].labels.clear();
+
+        let err = layout(&segments).unwrap_err();
+        assert_matches!(
+            err,
+            Error::UndeclaredLabel { segment, label, .. } if segment == "data" && label == "value"
+        );
+    }
+
+    #[test]
+    fn layout_undeclared_segment() {
+        let mut segments = segments();
+        segments.pop();
+
+        let err = layout(&segments).unwrap_err();
+        assert_matches!(err, Error::UndeclaredSegment { segment, .. } if segment == "data");
+    }
+
+    #[test]
+    fn layout_duplicate_segment() {
+        let mut segments = segments();
+        segments[1].name = "code".into();
+
+        let err = layout(&segments).unwrap_err();
+        assert_matches!(err, Error::DuplicateSegment { segment, .. } if segment == "code");
+    }
+
+    #[test]
+    fn layout_unlinked() {
+        let mut segments = segments();
+        segments[0].relocations[0].kind = RelocationKind::External;
+
+        let err = layout(&segments).unwrap_err();
+        assert_matches!(err, Error::UnlinkedRelocation { label, .. } if label == "value");
+    }
+
+    #[test]
+    fn layout_out_of_bounds() {
+        let mut segments = segments();
+        segments[0].relocations[2].offset = 6;
+
+        let err = layout(&segments).unwrap_err();
+        assert_matches!(err, Error::RelocationOutOfBounds { offset: 6, .. });
+    }
+}
@@ -2,7 +2,7 @@
 
 mod error {
     use super::expression;
-    use etk_ops::cancun::Op;
+    use etk_ops::prague::Op;
     use num_bigint::BigInt;
     use snafu::{Backtrace, Snafu};
 
@@ -23,6 +23,11 @@ mod error {
             value: BigInt,
             backtrace: Backtrace,
         },
+        OffsetOutOfRange {
+            value: BigInt,
+            spec: Op<()>,
+            backtrace: Backtrace,
+        },
     }
 
     /// The error that can arise while parsing a specifier from a string.
@@ -43,7 +48,8 @@ mod types;
 
 pub(crate) use self::error::Error;
 
-use etk_ops::cancun::{Op, Operation, Push32};
+use etk_ops::cancun;
+use etk_ops::prague::{Op, Operation, Push32};
 
 pub use self::error::UnknownSpecifierError;
 pub use self::expression::{Context, Expression, Terminal};
@@ -55,12 +61,43 @@ pub use self::macros::{
 };
 pub use self::types::Abstract;
 
+pub(crate) use self::macros::CallKind;
+
 use std::cmp::{Eq, PartialEq};
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
 
 use snafu::{ensure, ResultExt};
 
+/// Returns true if `spec` pushes its immediate argument onto the stack.
+pub(crate) fn is_push(spec: &Op<()>) -> bool {
+    matches!(spec.code_byte(), 0x60..=0x7f)
+}
+
+/// Returns true if the immediate argument of `spec` is a signed offset from
+/// the end of the instruction.
+pub(crate) fn is_relative_jump(spec: &Op<()>) -> bool {
+    matches!(spec, Op::RJump(_) | Op::RJumpI(_))
+}
+
+/// Returns true if `spec` is an instruction of EOF, which is only available in
+/// code in an EOF container.
+pub(crate) fn is_eof(spec: &Op<()>) -> bool {
+    matches!(
+        spec,
+        Op::DataLoad(_)
+            | Op::DataLoadN(_)
+            | Op::DataSize(_)
+            | Op::DataCopy(_)
+            | Op::RJump(_)
+            | Op::RJumpI(_)
+            | Op::RJumpV(_)
+            | Op::DupN(_)
+            | Op::SwapN(_)
+            | Op::Exchange(_)
+    )
+}
+
 pub(crate) trait Assemble {
     fn assemble(&self, buf: &mut Vec<u8>);
 }
@@ -99,10 +136,32 @@ impl Concretize for Op<Abstract> {
 
         let (sign, mut bytes) = value.to_bytes_be();
 
-        ensure!(
-            sign != num_bigint::Sign::Minus,
-            error::ExpressionNegative { value }
-        );
+        if is_relative_jump(&self.code()) {
+            // A number may be the encoded offset (like `0xfffd` for -3), but
+            // a computed offset, like `rel(label)`, must fit in an `i16`.
+            let encoded = sign != num_bigint::Sign::Minus
+                && matches!(expr, Expression::Terminal(Terminal::Number(_)));
+
+            if !encoded {
+                // Negative offsets are encoded in two's complement.
+                let offset = match i16::try_from(&value) {
+                    Ok(offset) => offset,
+                    Err(_) => {
+                        return error::OffsetOutOfRange {
+                            value,
+                            spec: self.code(),
+                        }
+                        .fail()
+                    }
+                };
+                bytes = offset.to_be_bytes().to_vec();
+            }
+        } else {
+            ensure!(
+                sign != num_bigint::Sign::Minus,
+                error::ExpressionNegative { value }
+            );
+        }
 
         if bytes.len() < self.extra_len() {
             let mut new = vec![0u8; self.extra_len() - bytes.len()];
@@ -188,12 +247,21 @@ pub enum AbstractOp {
 }
 
 impl AbstractOp {
-    /// Construct a new `AbstractOp` from an `Operation`.
+    /// Construct a new `AbstractOp` from an `Operation` of Cancun.
+    ///
+    /// The instructions of EOF aren't part of Cancun, so they're built with
+    /// [`AbstractOp::Op`] instead.
     pub fn new<O>(op: O) -> Self
     where
-        O: Into<Op<Abstract>>,
+        O: Into<cancun::Op<Abstract>>,
     {
-        Self::Op(op.into())
+        let op = op.into();
+        let code = Op::<()>::from(op.code_byte());
+        let op = match op.into_immediate() {
+            Some(imm) => code.with(imm).unwrap(),
+            None => Op::new(code).unwrap(),
+        };
+        Self::Op(op)
     }
 
     pub(crate) fn concretize(self, ctx: Context) -> Result<Op<[u8]>, error::Error> {
@@ -227,7 +295,7 @@ impl AbstractOp {
                 let spec = Op::<()>::push(size.try_into().unwrap()).unwrap();
 
                 let start = bytes.len() + 1 - spec.size();
-                AbstractOp::new(spec.with(&bytes[start..]).unwrap()).concretize(ctx)
+                AbstractOp::Op(spec.with(&bytes[start..]).unwrap()).concretize(ctx)
             }
             Self::Label(_) => panic!("labels cannot be concretized"),
             Self::Macro(_) => panic!("macros cannot be concretized"),
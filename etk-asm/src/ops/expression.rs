@@ -1,9 +1,13 @@
 use crate::asm::LabelDef;
+use crate::profile::Profile;
 
 use super::macros::{ExpressionMacroInvocation, MacroDefinition};
+use etk_ops::prague::Op;
 use indexmap::IndexMap;
-use num_bigint::BigInt;
-use snafu::OptionExt;
+use num_bigint::{BigInt, Sign};
+use num_traits::{ToPrimitive, Zero};
+use sha3::{Digest, Keccak256};
+use snafu::{ensure, OptionExt};
 use snafu::{Backtrace, Snafu};
 use std::collections::HashMap;
 use std::fmt::{self, Debug};
@@ -23,18 +27,124 @@ pub enum Error {
     #[snafu(display("undefined macro variable `{}`", name))]
     #[non_exhaustive]
     UndefinedVariable { name: String, backtrace: Backtrace },
+
+    #[snafu(display("`{}` is not a valid operand of `{}`", value, operation))]
+    #[non_exhaustive]
+    OutOfDomain {
+        operation: String,
+        value: BigInt,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "function `{}` called with {} argument(s), but expected {}",
+        name,
+        got,
+        expected
+    ))]
+    #[non_exhaustive]
+    ArgumentCount {
+        name: String,
+        expected: usize,
+        got: usize,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("`{}` is not valid hexadecimal", text))]
+    #[non_exhaustive]
+    InvalidHex { text: String, backtrace: Backtrace },
+
+    #[snafu(display(
+        "expression macro `{}` is nested more than {} levels deep",
+        name,
+        limit
+    ))]
+    #[non_exhaustive]
+    RecursionLimit {
+        name: String,
+        limit: usize,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "expression macro `{}` invokes itself (`{}`)",
+        name,
+        cycle.join("` -> `")
+    ))]
+    #[non_exhaustive]
+    RecursiveMacro {
+        name: String,
+        cycle: Vec<String>,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("`rel(..)` only has a value in the immediate of an instruction"))]
+    #[non_exhaustive]
+    UnknownPosition { backtrace: Backtrace },
+
+    #[snafu(display(
+        "`has_{}` isn't a capability, since `{}` isn't an instruction",
+        name,
+        name
+    ))]
+    #[non_exhaustive]
+    UnknownCapability { name: String, backtrace: Backtrace },
+
+    #[snafu(display("capability flag `has_{}` can only be used in `%if` conditions", name))]
+    #[non_exhaustive]
+    UnknownTarget { name: String, backtrace: Backtrace },
 }
 
+/// How many expression macros can be expanded inside each other. Expression
+/// macros can't stop recursing, so cycles are reported as soon as they're
+/// found, and this only limits long chains of different macros.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
 type LabelsMap = IndexMap<String, Option<LabelDef>>;
 type VariablesMap = HashMap<String, Expression>;
 type MacrosMap = HashMap<String, MacroDefinition>;
 
+/// An expression macro being expanded, with the arguments it was invoked
+/// with, and the context they were given in.
+#[derive(Debug)]
+struct Expansion<'a> {
+    name: &'a str,
+    parameters: &'a [String],
+    arguments: &'a [Expression],
+    caller: Context<'a>,
+}
+
+impl<'a> Expansion<'a> {
+    /// The names of the macros expanded from the first expansion of `name`
+    /// down to this one, followed by `name` again, if `name` is being
+    /// expanded.
+    fn cycle(&self, name: &str) -> Option<Vec<String>> {
+        let mut cycle = vec![name.to_owned()];
+        let mut expansion = Some(self);
+
+        while let Some(current) = expansion {
+            cycle.push(current.name.to_owned());
+            if current.name == name {
+                cycle.reverse();
+                return Some(cycle);
+            }
+            expansion = current.caller.expansion;
+        }
+
+        None
+    }
+}
+
 /// Evaluation context for `Expression`.
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Context<'a> {
     labels: Option<&'a LabelsMap>,
     macros: Option<&'a MacrosMap>,
     variables: Option<&'a VariablesMap>,
+    position: Option<usize>,
+    target: Option<&'a Profile>,
+    depth: usize,
+    expansion: Option<&'a Expansion<'a>>,
 }
 
 impl<'a> Context<'a> {
@@ -56,10 +166,35 @@ impl<'a> Context<'a> {
 
     /// Looks up a variable in the current context.
     pub fn get_variable(&self, key: &str) -> Option<&Expression> {
-        match self.variables {
-            Some(variables) => variables.get(key),
-            None => None,
+        self.variable(key).map(|(value, _)| value)
+    }
+
+    /// Looks up a variable, and the context its value is evaluated in.
+    ///
+    /// Inside an expression macro, only its parameters are visible, and
+    /// they're evaluated where the macro was invoked.
+    fn variable(&self, key: &str) -> Option<(&'a Expression, Self)> {
+        if let Some(expansion) = self.expansion {
+            let index = expansion.parameters.iter().position(|p| p == key)?;
+            let value = expansion.arguments.get(index)?;
+            return Some((value, expansion.caller));
         }
+
+        let value = self.variables?.get(key)?;
+        Some((value, *self))
+    }
+
+    /// Sets the offset that `rel(..)` is measured from, which is the end of
+    /// the instruction being assembled.
+    pub fn with_position(mut self, position: usize) -> Self {
+        self.position = Some(position);
+        self
+    }
+
+    /// Sets the chain that capability flags, like `has_push0`, describe.
+    pub fn with_target(mut self, target: &'a Profile) -> Self {
+        self.target = Some(target);
+        self
     }
 }
 
@@ -69,6 +204,10 @@ impl<'a> From<&'a LabelsMap> for Context<'a> {
             labels: Some(labels),
             macros: None,
             variables: None,
+            position: None,
+            target: None,
+            depth: 0,
+            expansion: None,
         }
     }
 }
@@ -79,6 +218,10 @@ impl<'a> From<(&'a LabelsMap, &'a MacrosMap)> for Context<'a> {
             labels: Some(x.0),
             macros: Some(x.1),
             variables: None,
+            position: None,
+            target: None,
+            depth: 0,
+            expansion: None,
         }
     }
 }
@@ -89,6 +232,10 @@ impl<'a> From<(&'a LabelsMap, &'a MacrosMap, &'a VariablesMap)> for Context<'a>
             labels: Some(x.0),
             macros: Some(x.1),
             variables: Some(x.2),
+            position: None,
+            target: None,
+            depth: 0,
+            expansion: None,
         }
     }
 }
@@ -116,6 +263,233 @@ pub enum Expression {
 
     /// A division operation.
     Divide(Box<Self>, Box<Self>),
+
+    /// A remainder operation, with the sign of the dividend.
+    Modulo(Box<Self>, Box<Self>),
+
+    /// A bitwise and operation, in two's complement.
+    And(Box<Self>, Box<Self>),
+
+    /// A bitwise or operation, in two's complement.
+    Or(Box<Self>, Box<Self>),
+
+    /// A bitwise exclusive or operation, in two's complement.
+    Xor(Box<Self>, Box<Self>),
+
+    /// A left shift, truncated to 256 bits like `shl`.
+    ShiftLeft(Box<Self>, Box<Self>),
+
+    /// A logical right shift of a 256-bit word, like `shr`.
+    ShiftRight(Box<Self>, Box<Self>),
+}
+
+/// Number of bits in an EVM word.
+const WORD_BITS: u64 = 256;
+
+pub(super) fn word_mask() -> BigInt {
+    (BigInt::from(1) << WORD_BITS) - 1
+}
+
+/// Check that `value` can be used to divide.
+fn divisor(operation: &str, value: BigInt) -> Result<BigInt, Error> {
+    ensure!(!value.is_zero(), OutOfDomain { operation, value });
+    Ok(value)
+}
+
+/// Get the amount of a shift, or `None` if it shifts every bit out of a word.
+fn shift_amount(operation: &str, amount: BigInt) -> Result<Option<u64>, Error> {
+    ensure!(
+        amount >= BigInt::zero(),
+        OutOfDomain {
+            operation,
+            value: amount
+        }
+    );
+
+    Ok(amount.to_u64().filter(|a| *a < WORD_BITS))
+}
+
+/// Evaluate the body of the expression macro invoked by `invc` with `f`, in
+/// a context where the macro's parameters are bound to the invocation's
+/// arguments.
+fn expand<T, F>(invc: &ExpressionMacroInvocation, ctx: Context, f: F) -> Result<T, Error>
+where
+    F: FnOnce(&Expression, Context) -> Result<T, Error>,
+{
+    let defn = ctx.get_macro(&invc.name).context(UnknownMacro {
+        name: invc.name.clone(),
+    })?;
+
+    if let Some(cycle) = ctx.expansion.and_then(|e| e.cycle(&invc.name)) {
+        return RecursiveMacro {
+            name: invc.name.clone(),
+            cycle,
+        }
+        .fail();
+    }
+
+    ensure!(
+        ctx.depth < MAX_EXPANSION_DEPTH,
+        RecursionLimit {
+            name: invc.name.clone(),
+            limit: MAX_EXPANSION_DEPTH,
+        }
+    );
+
+    // Arguments are evaluated where the macro is invoked, so they may use the
+    // variables of the enclosing macro, which aren't visible inside this one.
+    let expansion = Expansion {
+        name: &invc.name,
+        parameters: defn.parameters(),
+        arguments: &invc.parameters,
+        caller: ctx,
+    };
+
+    let mut ctx = ctx;
+    ctx.depth += 1;
+    ctx.expansion = Some(&expansion);
+
+    f(&defn.unwrap_expression().content.tree, ctx)
+}
+
+/// The expression functions provided by the assembler, which are used when
+/// no expression macro with the same name is declared, and how many arguments
+/// they take.
+const BUILTINS: &[(&str, Arity)] = &[
+    ("concat", Arity::AtLeast(0)),
+    ("len", Arity::Exactly(1)),
+    ("keccak256", Arity::Exactly(1)),
+    ("hex", Arity::Exactly(1)),
+    ("min", Arity::AtLeast(1)),
+    ("max", Arity::AtLeast(1)),
+    ("pow", Arity::Exactly(2)),
+    ("log2", Arity::Exactly(1)),
+    ("ceil_div", Arity::Exactly(2)),
+    ("rel", Arity::Exactly(1)),
+];
+
+#[derive(Debug, Clone, Copy)]
+enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+fn is_builtin(name: &str) -> bool {
+    BUILTINS.iter().any(|(n, _)| *n == name)
+}
+
+/// Evaluate the built-in function invoked by `invc`, or return `None` if an
+/// expression macro with the same name is declared.
+fn builtin(invc: &ExpressionMacroInvocation, ctx: Context) -> Result<Option<Terminal>, Error> {
+    let name = invc.name.as_str();
+    let arity = match BUILTINS.iter().find(|(n, _)| *n == name) {
+        Some((_, arity)) if ctx.get_macro(name).is_none() => *arity,
+        _ => return Ok(None),
+    };
+
+    let args = &invc.parameters;
+    let (valid, expected) = match arity {
+        Arity::Exactly(n) => (args.len() == n, n),
+        Arity::AtLeast(n) => (args.len() >= n, n),
+    };
+    ensure!(
+        valid,
+        ArgumentCount {
+            name,
+            expected,
+            got: args.len(),
+        }
+    );
+
+    let numbers = || -> Result<Vec<BigInt>, Error> {
+        args.iter().map(|a| a.eval_with_context(ctx)).collect()
+    };
+
+    let ret = match name {
+        "concat" => {
+            let mut bytes = Vec::new();
+            for arg in args {
+                bytes.extend(arg.eval_bytes_with_context(ctx)?);
+            }
+            Terminal::Bytes(bytes)
+        }
+        "len" => {
+            let len = args[0].eval_bytes_with_context(ctx)?.len();
+            Terminal::Number(len.into())
+        }
+        "keccak256" => {
+            let bytes = args[0].eval_bytes_with_context(ctx)?;
+            Terminal::Bytes(Keccak256::digest(&bytes).to_vec())
+        }
+        "hex" => {
+            let bytes = args[0].eval_bytes_with_context(ctx)?;
+            let text = String::from_utf8_lossy(&bytes);
+            let digits = text.strip_prefix("0x").unwrap_or(&text);
+            match hex::decode(digits) {
+                Ok(decoded) => Terminal::Bytes(decoded),
+                Err(_) => {
+                    return InvalidHex {
+                        text: text.into_owned(),
+                    }
+                    .fail()
+                }
+            }
+        }
+        "min" => Terminal::Number(numbers()?.into_iter().min().unwrap()),
+        "max" => Terminal::Number(numbers()?.into_iter().max().unwrap()),
+        "pow" => {
+            let mut numbers = numbers()?.into_iter();
+            let base = numbers.next().unwrap();
+            let exponent = numbers.next().unwrap();
+
+            // Larger exponents can't produce a value that fits in a word,
+            // unless the base is -1, 0, or 1.
+            let exponent = exponent
+                .to_u32()
+                .filter(|e| u64::from(*e) <= WORD_BITS)
+                .context(OutOfDomain {
+                    operation: name,
+                    value: exponent,
+                })?;
+
+            Terminal::Number(base.pow(exponent))
+        }
+        "log2" => {
+            let value = numbers()?.pop().unwrap();
+            ensure!(
+                value > BigInt::zero(),
+                OutOfDomain {
+                    operation: name,
+                    value
+                }
+            );
+            Terminal::Number((value.bits() - 1).into())
+        }
+        "ceil_div" => {
+            let mut numbers = numbers()?.into_iter();
+            let dividend = numbers.next().unwrap();
+            let rhs = divisor(name, numbers.next().unwrap())?;
+
+            let quotient = &dividend / &rhs;
+            let remainder = &dividend % &rhs;
+
+            // Division truncates towards zero, so round up only when the
+            // exact quotient is positive.
+            if !remainder.is_zero() && remainder.sign() == rhs.sign() {
+                Terminal::Number(quotient + 1)
+            } else {
+                Terminal::Number(quotient)
+            }
+        }
+        "rel" => {
+            let target = numbers()?.pop().unwrap();
+            let position = ctx.position.context(UnknownPosition)?;
+            Terminal::Number(target - position)
+        }
+        _ => unreachable!(),
+    };
+
+    Ok(Some(ret))
 }
 
 impl Debug for Expression {
@@ -130,6 +504,18 @@ impl Debug for Expression {
             Expression::Divide(lhs, rhs) => {
                 write!(f, r#"Expression::Divide({:?}, {:?})"#, lhs, rhs)
             }
+            Expression::Modulo(lhs, rhs) => {
+                write!(f, r#"Expression::Modulo({:?}, {:?})"#, lhs, rhs)
+            }
+            Expression::And(lhs, rhs) => write!(f, r#"Expression::And({:?}, {:?})"#, lhs, rhs),
+            Expression::Or(lhs, rhs) => write!(f, r#"Expression::Or({:?}, {:?})"#, lhs, rhs),
+            Expression::Xor(lhs, rhs) => write!(f, r#"Expression::Xor({:?}, {:?})"#, lhs, rhs),
+            Expression::ShiftLeft(lhs, rhs) => {
+                write!(f, r#"Expression::ShiftLeft({:?}, {:?})"#, lhs, rhs)
+            }
+            Expression::ShiftRight(lhs, rhs) => {
+                write!(f, r#"Expression::ShiftRight({:?}, {:?})"#, lhs, rhs)
+            }
         }
     }
 }
@@ -144,6 +530,12 @@ impl fmt::Display for Expression {
             Expression::Minus(lhs, rhs) => write!(f, r#"{}-{}"#, lhs, rhs),
             Expression::Times(lhs, rhs) => write!(f, r#"{}*{}"#, lhs, rhs),
             Expression::Divide(lhs, rhs) => write!(f, r#"{}/{}"#, lhs, rhs),
+            Expression::Modulo(lhs, rhs) => write!(f, r#"{}%{}"#, lhs, rhs),
+            Expression::And(lhs, rhs) => write!(f, r#"{}&{}"#, lhs, rhs),
+            Expression::Or(lhs, rhs) => write!(f, r#"{}|{}"#, lhs, rhs),
+            Expression::Xor(lhs, rhs) => write!(f, r#"{}^{}"#, lhs, rhs),
+            Expression::ShiftLeft(lhs, rhs) => write!(f, r#"{}<<{}"#, lhs, rhs),
+            Expression::ShiftRight(lhs, rhs) => write!(f, r#"{}>>{}"#, lhs, rhs),
         }
     }
 }
@@ -159,6 +551,14 @@ pub enum Terminal {
 
     /// A macro variable.
     Variable(String),
+
+    /// A sequence of bytes, like a string literal. When used as an integer,
+    /// the bytes are read in big-endian order.
+    Bytes(Vec<u8>),
+
+    /// Whether the target has the instruction with this mnemonic, written as
+    /// `has_<mnemonic>`. One if it does, and zero if it doesn't.
+    Capability(String),
 }
 
 impl Terminal {
@@ -171,16 +571,28 @@ impl Terminal {
     pub fn eval_with_context(&self, ctx: Context) -> Result<BigInt, Error> {
         let ret = match self {
             Terminal::Number(n) => n.clone(),
-            Terminal::Label(label) => ctx
-                .get_label(label)
-                .context(UnknownLabel { label })?
-                .context(UnknownLabel { label })?
-                .position()
-                .into(),
-            Terminal::Variable(name) => ctx
-                .get_variable(name)
-                .context(UndefinedVariable { name })?
-                .eval_with_context(ctx)?,
+            Terminal::Label(label) => match ctx.get_label(label) {
+                Some(Some(def)) => def.position().into(),
+
+                // Only conditions have a target. A capability flag in the body
+                // of an expression macro is parsed as a label, since the
+                // macro could be used anywhere.
+                _ if ctx.target.is_some() && label.starts_with("has_") => {
+                    let name = label.strip_prefix("has_").unwrap();
+                    Terminal::Capability(name.to_owned()).eval_with_context(ctx)?
+                }
+                _ => return UnknownLabel { label }.fail(),
+            },
+            Terminal::Variable(name) => {
+                let (value, ctx) = ctx.variable(name).context(UndefinedVariable { name })?;
+                value.eval_with_context(ctx)?
+            }
+            Terminal::Bytes(bytes) => BigInt::from_bytes_be(Sign::Plus, bytes),
+            Terminal::Capability(name) => {
+                ensure!(name.parse::<Op<()>>().is_ok(), UnknownCapability { name });
+                let target = ctx.target.context(UnknownTarget { name })?;
+                u8::from(target.has(name)).into()
+            }
         };
 
         Ok(ret)
@@ -198,31 +610,33 @@ impl Expression {
         fn eval(e: &Expression, ctx: Context) -> Result<BigInt, Error> {
             let ret = match e {
                 Expression::Expression(expr) => eval(expr, ctx)?,
-                Expression::Macro(invc) => {
-                    let defn = ctx.get_macro(&invc.name).context(UnknownMacro {
-                        name: invc.name.clone(),
-                    })?;
-
-                    let vars = defn
-                        .parameters()
-                        .iter()
-                        .cloned()
-                        .zip(invc.parameters.iter().cloned())
-                        .collect();
-
-                    let mut ctx = ctx;
-                    ctx.variables = Some(&vars);
-
-                    defn.unwrap_expression()
-                        .content
-                        .tree
-                        .eval_with_context(ctx)?
-                }
+                Expression::Macro(invc) => match builtin(invc, ctx)? {
+                    Some(terminal) => terminal.eval_with_context(ctx)?,
+                    None => expand(invc, ctx, |tree, ctx| tree.eval_with_context(ctx))?,
+                },
                 Expression::Terminal(term) => term.eval_with_context(ctx)?,
                 Expression::Plus(lhs, rhs) => eval(lhs, ctx)? + eval(rhs, ctx)?,
                 Expression::Minus(lhs, rhs) => eval(lhs, ctx)? - eval(rhs, ctx)?,
                 Expression::Times(lhs, rhs) => eval(lhs, ctx)? * eval(rhs, ctx)?,
-                Expression::Divide(lhs, rhs) => eval(lhs, ctx)? / eval(rhs, ctx)?,
+                Expression::Divide(lhs, rhs) => eval(lhs, ctx)? / divisor("/", eval(rhs, ctx)?)?,
+                Expression::Modulo(lhs, rhs) => eval(lhs, ctx)? % divisor("%", eval(rhs, ctx)?)?,
+                Expression::And(lhs, rhs) => eval(lhs, ctx)? & eval(rhs, ctx)?,
+                Expression::Or(lhs, rhs) => eval(lhs, ctx)? | eval(rhs, ctx)?,
+                Expression::Xor(lhs, rhs) => eval(lhs, ctx)? ^ eval(rhs, ctx)?,
+                Expression::ShiftLeft(lhs, rhs) => {
+                    let value = eval(lhs, ctx)?;
+                    match shift_amount("<<", eval(rhs, ctx)?)? {
+                        Some(amount) => (value << amount) & word_mask(),
+                        None => BigInt::zero(),
+                    }
+                }
+                Expression::ShiftRight(lhs, rhs) => {
+                    let value = eval(lhs, ctx)? & word_mask();
+                    match shift_amount(">>", eval(rhs, ctx)?)? {
+                        Some(amount) => value >> amount,
+                        None => BigInt::zero(),
+                    }
+                }
             };
 
             Ok(ret)
@@ -232,40 +646,113 @@ impl Expression {
         eval(self, ctx)
     }
 
+    /// Evaluates the expression into a sequence of bytes, given a certain
+    /// `Context`.
+    ///
+    /// String literals, and expression macros and variables producing them,
+    /// evaluate to their bytes. Any other expression is evaluated as an
+    /// integer, and encoded big-endian in as few bytes as possible.
+    pub fn eval_bytes_with_context(&self, ctx: Context) -> Result<Vec<u8>, Error> {
+        match self {
+            Expression::Expression(expr) => expr.eval_bytes_with_context(ctx),
+            Expression::Macro(invc) => match builtin(invc, ctx)? {
+                Some(terminal) => Expression::from(terminal).eval_bytes_with_context(ctx),
+                None => expand(invc, ctx, |tree, ctx| tree.eval_bytes_with_context(ctx)),
+            },
+            Expression::Terminal(Terminal::Bytes(bytes)) => Ok(bytes.clone()),
+            Expression::Terminal(Terminal::Variable(name)) => {
+                let (value, ctx) = ctx.variable(name).context(UndefinedVariable { name })?;
+                value.eval_bytes_with_context(ctx)
+            }
+            _ => {
+                let value = self.eval_with_context(ctx)?;
+                let (sign, bytes) = value.to_bytes_be();
+                ensure!(
+                    sign != Sign::Minus,
+                    OutOfDomain {
+                        operation: "bytes",
+                        value
+                    }
+                );
+                Ok(bytes)
+            }
+        }
+    }
+
     /// Returns a list of all labels used in the expression.
     pub fn labels(&self, macros: &MacrosMap) -> Result<Vec<String>, Error> {
-        fn dfs(x: &Expression, m: &MacrosMap) -> Result<Vec<String>, Error> {
+        // `stack` holds the names of the macros being expanded, outermost
+        // first.
+        fn dfs<'a>(
+            x: &'a Expression,
+            m: &'a MacrosMap,
+            stack: &mut Vec<&'a str>,
+        ) -> Result<Vec<String>, Error> {
             match x {
-                Expression::Expression(e) => dfs(e, m),
-                Expression::Macro(macro_invocation) => m
-                    .get(&macro_invocation.name)
-                    .context(UnknownMacro {
-                        name: macro_invocation.name.clone(),
-                    })?
-                    .unwrap_expression()
-                    .content
-                    .tree
-                    .labels(m),
+                Expression::Expression(e) => dfs(e, m, stack),
+                Expression::Macro(macro_invocation) => {
+                    let name = &macro_invocation.name;
+                    let mut labels = Vec::new();
+                    for parameter in macro_invocation.parameters.iter() {
+                        labels.extend(dfs(parameter, m, stack)?);
+                    }
+
+                    match m.get(name) {
+                        Some(defn) => {
+                            if let Some(start) = stack.iter().position(|n| *n == name.as_str()) {
+                                let mut cycle: Vec<_> =
+                                    stack[start..].iter().map(|n| n.to_string()).collect();
+                                cycle.push(name.clone());
+                                return RecursiveMacro { name, cycle }.fail();
+                            }
+
+                            ensure!(
+                                stack.len() < MAX_EXPANSION_DEPTH,
+                                RecursionLimit {
+                                    name,
+                                    limit: MAX_EXPANSION_DEPTH,
+                                }
+                            );
+
+                            stack.push(name);
+                            let tree = &defn.unwrap_expression().content.tree;
+                            labels.extend(dfs(tree, m, stack)?);
+                            stack.pop();
+                        }
+                        None if is_builtin(name) => (),
+                        None => return UnknownMacro { name }.fail(),
+                    }
+
+                    Ok(labels)
+                }
                 Expression::Terminal(Terminal::Label(label)) => Ok(vec![label.clone()]),
                 Expression::Terminal(_) => Ok(vec![]),
                 Expression::Plus(lhs, rhs)
                 | Expression::Minus(lhs, rhs)
                 | Expression::Times(lhs, rhs)
-                | Expression::Divide(lhs, rhs) => dfs(lhs, m).and_then(|x: Vec<String>| {
-                    let ret = x.into_iter().chain(dfs(rhs, m)?).collect();
-                    Ok(ret)
-                }),
+                | Expression::Divide(lhs, rhs)
+                | Expression::Modulo(lhs, rhs)
+                | Expression::And(lhs, rhs)
+                | Expression::Or(lhs, rhs)
+                | Expression::Xor(lhs, rhs)
+                | Expression::ShiftLeft(lhs, rhs)
+                | Expression::ShiftRight(lhs, rhs) => {
+                    dfs(lhs, m, stack).and_then(|x: Vec<String>| {
+                        let ret = x.into_iter().chain(dfs(rhs, m, stack)?).collect();
+                        Ok(ret)
+                    })
+                }
             }
         }
 
-        dfs(self, macros)
+        dfs(self, macros, &mut Vec::new())
     }
 
     /// Replaces all instances of `old` with `new` in the expression.
     pub fn replace_label(&mut self, old: &str, new: &str) {
         fn dfs(x: &mut Expression, old: &str, new: &str) {
             match x {
-                Expression::Expression(e) => dfs(e, new, old),
+                Expression::Expression(e) => dfs(e, old, new),
                 Expression::Terminal(Terminal::Label(ref mut label)) => {
                     if *label == old {
                         *label = new.to_string();
@@ -274,17 +761,156 @@ impl Expression {
                 Expression::Plus(lhs, rhs)
                 | Expression::Minus(lhs, rhs)
                 | Expression::Times(lhs, rhs)
-                | Expression::Divide(lhs, rhs) => {
-                    dfs(lhs, new, old);
-                    dfs(rhs, new, old);
+                | Expression::Divide(lhs, rhs)
+                | Expression::Modulo(lhs, rhs)
+                | Expression::And(lhs, rhs)
+                | Expression::Or(lhs, rhs)
+                | Expression::Xor(lhs, rhs)
+                | Expression::ShiftLeft(lhs, rhs)
+                | Expression::ShiftRight(lhs, rhs) => {
+                    dfs(lhs, old, new);
+                    dfs(rhs, old, new);
+                }
+                Expression::Macro(invc) => {
+                    for param in invc.parameters.iter_mut() {
+                        dfs(param, old, new);
+                    }
                 }
-                Expression::Macro(_) | Expression::Terminal(_) => (),
+                Expression::Terminal(_) => (),
             }
         }
 
         dfs(self, old, new)
     }
 
+    /// Replaces all instances of the label `old` with `expr` in the expression.
+    pub fn replace_label_with(&mut self, old: &str, expr: &Expression) {
+        fn dfs(x: &mut Expression, old: &str, expr: &Expression) {
+            match x {
+                Expression::Terminal(Terminal::Label(label)) => {
+                    if old == label {
+                        *x = expr.clone();
+                    }
+                }
+                Expression::Expression(e) => dfs(e, old, expr),
+                Expression::Plus(lhs, rhs)
+                | Expression::Minus(lhs, rhs)
+                | Expression::Times(lhs, rhs)
+                | Expression::Divide(lhs, rhs)
+                | Expression::Modulo(lhs, rhs)
+                | Expression::And(lhs, rhs)
+                | Expression::Or(lhs, rhs)
+                | Expression::Xor(lhs, rhs)
+                | Expression::ShiftLeft(lhs, rhs)
+                | Expression::ShiftRight(lhs, rhs) => {
+                    dfs(lhs, old, expr);
+                    dfs(rhs, old, expr);
+                }
+                Expression::Macro(invc) => {
+                    for param in invc.parameters.iter_mut() {
+                        dfs(param, old, expr);
+                    }
+                }
+                Expression::Terminal(_) => (),
+            }
+        }
+
+        dfs(self, old, expr)
+    }
+
+    /// Replaces all invocations of the expression macro `old` with
+    /// invocations of `new`, keeping their arguments.
+    pub fn replace_macro(&mut self, old: &str, new: &str) {
+        match self {
+            Expression::Macro(invc) => {
+                if invc.name == old {
+                    invc.name = new.to_string();
+                }
+                for param in invc.parameters.iter_mut() {
+                    param.replace_macro(old, new);
+                }
+            }
+            Expression::Expression(e) => e.replace_macro(old, new),
+            Expression::Plus(lhs, rhs)
+            | Expression::Minus(lhs, rhs)
+            | Expression::Times(lhs, rhs)
+            | Expression::Divide(lhs, rhs)
+            | Expression::Modulo(lhs, rhs)
+            | Expression::And(lhs, rhs)
+            | Expression::Or(lhs, rhs)
+            | Expression::Xor(lhs, rhs)
+            | Expression::ShiftLeft(lhs, rhs)
+            | Expression::ShiftRight(lhs, rhs) => {
+                lhs.replace_macro(old, new);
+                rhs.replace_macro(old, new);
+            }
+            Expression::Terminal(_) => (),
+        }
+    }
+
+    /// Returns whether the macro variable `var` appears in the expression.
+    pub fn uses_variable(&self, var: &str) -> bool {
+        match self {
+            Expression::Terminal(Terminal::Variable(name)) => var == name,
+            Expression::Expression(e) => e.uses_variable(var),
+            Expression::Plus(lhs, rhs)
+            | Expression::Minus(lhs, rhs)
+            | Expression::Times(lhs, rhs)
+            | Expression::Divide(lhs, rhs)
+            | Expression::Modulo(lhs, rhs)
+            | Expression::And(lhs, rhs)
+            | Expression::Or(lhs, rhs)
+            | Expression::Xor(lhs, rhs)
+            | Expression::ShiftLeft(lhs, rhs)
+            | Expression::ShiftRight(lhs, rhs) => lhs.uses_variable(var) || rhs.uses_variable(var),
+            Expression::Macro(invc) => invc.parameters.iter().any(|p| p.uses_variable(var)),
+            Expression::Terminal(_) => false,
+        }
+    }
+
+    /// Returns whether the label `label` appears in the expression, outside
+    /// of expression macro definitions.
+    pub fn uses_label(&self, label: &str) -> bool {
+        match self {
+            Expression::Terminal(Terminal::Label(name)) => label == name,
+            Expression::Expression(e) => e.uses_label(label),
+            Expression::Plus(lhs, rhs)
+            | Expression::Minus(lhs, rhs)
+            | Expression::Times(lhs, rhs)
+            | Expression::Divide(lhs, rhs)
+            | Expression::Modulo(lhs, rhs)
+            | Expression::And(lhs, rhs)
+            | Expression::Or(lhs, rhs)
+            | Expression::Xor(lhs, rhs)
+            | Expression::ShiftLeft(lhs, rhs)
+            | Expression::ShiftRight(lhs, rhs) => lhs.uses_label(label) || rhs.uses_label(label),
+            Expression::Macro(invc) => invc.parameters.iter().any(|p| p.uses_label(label)),
+            Expression::Terminal(_) => false,
+        }
+    }
+
+    /// Returns whether the expression macro `name` is invoked in the
+    /// expression, outside of expression macro definitions.
+    pub fn uses_macro(&self, name: &str) -> bool {
+        match self {
+            Expression::Macro(invc) => {
+                invc.name == name || invc.parameters.iter().any(|p| p.uses_macro(name))
+            }
+            Expression::Expression(e) => e.uses_macro(name),
+            Expression::Plus(lhs, rhs)
+            | Expression::Minus(lhs, rhs)
+            | Expression::Times(lhs, rhs)
+            | Expression::Divide(lhs, rhs)
+            | Expression::Modulo(lhs, rhs)
+            | Expression::And(lhs, rhs)
+            | Expression::Or(lhs, rhs)
+            | Expression::Xor(lhs, rhs)
+            | Expression::ShiftLeft(lhs, rhs)
+            | Expression::ShiftRight(lhs, rhs) => lhs.uses_macro(name) || rhs.uses_macro(name),
+            Expression::Terminal(_) => false,
+        }
+    }
+
     /// Replaces all instances of `var` with `expr` in the expression.
     pub fn fill_variable(&mut self, var: &str, expr: &Expression) {
         fn dfs(x: &mut Expression, var: &str, expr: &Expression) {
@@ -298,11 +924,22 @@ impl Expression {
                 Expression::Plus(lhs, rhs)
                 | Expression::Minus(lhs, rhs)
                 | Expression::Times(lhs, rhs)
-                | Expression::Divide(lhs, rhs) => {
+                | Expression::Divide(lhs, rhs)
+                | Expression::Modulo(lhs, rhs)
+                | Expression::And(lhs, rhs)
+                | Expression::Or(lhs, rhs)
+                | Expression::Xor(lhs, rhs)
+                | Expression::ShiftLeft(lhs, rhs)
+                | Expression::ShiftRight(lhs, rhs) => {
                     dfs(lhs, var, expr);
                     dfs(rhs, var, expr);
                 }
-                Expression::Macro(_) | Expression::Terminal(_) => (),
+                Expression::Macro(invc) => {
+                    for param in invc.parameters.iter_mut() {
+                        dfs(param, var, expr);
+                    }
+                }
+                Expression::Terminal(_) => (),
             }
         }
 
@@ -316,6 +953,8 @@ impl Debug for Terminal {
             Terminal::Label(l) => write!(f, r#"Terminal::Label({})"#, l),
             Terminal::Number(n) => write!(f, r#"Terminal::Number({})"#, n),
             Terminal::Variable(v) => write!(f, r#"Terminal::Variable({})"#, v),
+            Terminal::Bytes(b) => write!(f, r#"Terminal::Bytes(0x{})"#, hex::encode(b)),
+            Terminal::Capability(c) => write!(f, r#"Terminal::Capability({})"#, c),
         }
     }
 }
@@ -326,6 +965,11 @@ impl fmt::Display for Terminal {
             Terminal::Label(l) => write!(f, r#"Label({})"#, l),
             Terminal::Number(n) => write!(f, r#"{}"#, n),
             Terminal::Variable(v) => write!(f, r#"Variable({})"#, v),
+            Terminal::Bytes(b) => match std::str::from_utf8(b) {
+                Ok(text) => write!(f, r#"{:?}"#, text),
+                Err(_) => write!(f, r#"Bytes(0x{})"#, hex::encode(b)),
+            },
+            Terminal::Capability(c) => write!(f, r#"has_{}"#, c),
         }
     }
 }
@@ -369,7 +1013,9 @@ impl From<BigInt> for Expression {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ops::{ExpressionMacroDefinition, Imm};
     use assert_matches::assert_matches;
+    use hex_literal::hex;
 
     #[test]
     fn expr_simple() {
@@ -414,4 +1060,321 @@ mod tests {
         let err = expr.eval_with_context(Context::from(&labels)).unwrap_err();
         assert_matches!(err, Error::UnknownLabel { label, .. } if label == "foo");
     }
+
+    #[test]
+    fn expr_replace_label() {
+        // (foo + 1) * bar(foo)
+        let mut expr = Expression::Times(
+            Expression::Expression(
+                Expression::Plus(Terminal::Label(String::from("foo")).into(), 1.into()).into(),
+            )
+            .into(),
+            Expression::Macro(ExpressionMacroInvocation {
+                name: "bar".into(),
+                parameters: vec![Terminal::Label(String::from("foo")).into()],
+            })
+            .into(),
+        );
+
+        expr.replace_label("foo", "baz");
+
+        let labels = expr.labels(&HashMap::new()).unwrap_err();
+        assert_matches!(labels, Error::UnknownMacro { name, .. } if name == "bar");
+
+        let mut macros = HashMap::new();
+        macros.insert(
+            "bar".to_string(),
+            ExpressionMacroDefinition {
+                name: "bar".into(),
+                parameters: vec!["x".into()],
+                content: Imm::with_variable("x"),
+            }
+            .into(),
+        );
+        assert_eq!(expr.labels(&macros).unwrap(), ["baz", "baz"]);
+    }
+
+    #[test]
+    fn expr_replace_label_with() {
+        // foo * 2, with foo = end - start
+        let mut expr = Expression::Times(Terminal::Label(String::from("foo")).into(), 2.into());
+        let size = Expression::Minus(
+            Terminal::Label(String::from("end")).into(),
+            Terminal::Label(String::from("start")).into(),
+        );
+
+        expr.replace_label_with("foo", &size);
+        assert_eq!(expr.labels(&HashMap::new()).unwrap(), ["end", "start"]);
+
+        let labels: IndexMap<_, _> = vec![
+            ("start".to_string(), Some(LabelDef::new(4))),
+            ("end".to_string(), Some(LabelDef::new(10))),
+        ]
+        .into_iter()
+        .collect();
+        let out = expr.eval_with_context(Context::from(&labels)).unwrap();
+        assert_eq!(out, BigInt::from(12));
+    }
+
+    #[test]
+    fn expr_bitwise() {
+        // 0b1100 & 0b1010 = 0b1000
+        let expr = Expression::And(0b1100u64.into(), 0b1010u64.into());
+        assert_eq!(expr.eval().unwrap(), BigInt::from(0b1000));
+
+        // 0b1100 | 0b1010 = 0b1110
+        let expr = Expression::Or(0b1100u64.into(), 0b1010u64.into());
+        assert_eq!(expr.eval().unwrap(), BigInt::from(0b1110));
+
+        // 0b1100 ^ 0b1010 = 0b0110
+        let expr = Expression::Xor(0b1100u64.into(), 0b1010u64.into());
+        assert_eq!(expr.eval().unwrap(), BigInt::from(0b0110));
+
+        // -1 & 0xff = 0xff
+        let expr = Expression::And(BigInt::from(-1).into(), 0xffu64.into());
+        assert_eq!(expr.eval().unwrap(), BigInt::from(0xff));
+
+        // 7 % 3 = 1
+        let expr = Expression::Modulo(7u64.into(), 3u64.into());
+        assert_eq!(expr.eval().unwrap(), BigInt::from(1));
+    }
+
+    #[test]
+    fn expr_shifts() {
+        // 1 << 255 fits in a word, 1 << 256 doesn't.
+        let expr = Expression::ShiftLeft(1u64.into(), 255u64.into());
+        assert_eq!(expr.eval().unwrap(), BigInt::from(1) << 255);
+
+        let expr = Expression::ShiftLeft(1u64.into(), 256u64.into());
+        assert_eq!(expr.eval().unwrap(), BigInt::from(0));
+
+        // 0xff << 252 loses its high bits.
+        let expr = Expression::ShiftLeft(0xffu64.into(), 252u64.into());
+        assert_eq!(expr.eval().unwrap(), BigInt::from(0xf) << 252);
+
+        // -1 >> 248 shifts the 256-bit word of all ones.
+        let expr = Expression::ShiftRight(BigInt::from(-1).into(), 248u64.into());
+        assert_eq!(expr.eval().unwrap(), BigInt::from(0xff));
+
+        let expr = Expression::ShiftRight(0x100u64.into(), 1000u64.into());
+        assert_eq!(expr.eval().unwrap(), BigInt::from(0));
+    }
+
+    #[test]
+    fn expr_bytes() {
+        let expr = Expression::from(Terminal::Bytes(vec![0x01, 0x02]));
+        assert_eq!(expr.eval().unwrap(), BigInt::from(0x0102));
+        assert_eq!(
+            expr.eval_bytes_with_context(Context::default()).unwrap(),
+            [0x01, 0x02]
+        );
+
+        // Leading zero bytes are kept, unless evaluated as an integer.
+        let expr = Expression::from(Terminal::Bytes(vec![0x00, 0x01]));
+        assert_eq!(
+            expr.eval_bytes_with_context(Context::default()).unwrap(),
+            [0x00, 0x01]
+        );
+
+        let expr = Expression::Plus(Terminal::Bytes(vec![0x00, 0x01]).into(), 1u64.into());
+        assert_eq!(
+            expr.eval_bytes_with_context(Context::default()).unwrap(),
+            [0x02]
+        );
+
+        let expr = Expression::from(BigInt::from(-1));
+        let err = expr
+            .eval_bytes_with_context(Context::default())
+            .unwrap_err();
+        assert_matches!(err, Error::OutOfDomain { operation, .. } if operation == "bytes");
+    }
+
+    fn call(name: &str, parameters: Vec<Expression>) -> Expression {
+        Expression::Macro(ExpressionMacroInvocation {
+            name: name.into(),
+            parameters,
+        })
+    }
+
+    #[test]
+    fn expr_builtins() {
+        let ctx = Context::default();
+
+        let expr = call(
+            "concat",
+            vec![
+                Terminal::Bytes(b"ab".to_vec()).into(),
+                BigInt::from(0x0102).into(),
+                call("hex", vec![Terminal::Bytes(b"0x00ff".to_vec()).into()]),
+            ],
+        );
+        assert_eq!(
+            expr.eval_bytes_with_context(ctx).unwrap(),
+            [b'a', b'b', 0x01, 0x02, 0x00, 0xff]
+        );
+
+        let expr = call("len", vec![expr]);
+        assert_eq!(expr.eval_with_context(ctx).unwrap(), BigInt::from(6));
+
+        let expr = call("len", vec![]);
+        let err = expr.eval_with_context(ctx).unwrap_err();
+        assert_matches!(
+            err,
+            Error::ArgumentCount { name, expected: 1, got: 0, .. } if name == "len"
+        );
+
+        let expr = call("hex", vec![Terminal::Bytes(b"0g".to_vec()).into()]);
+        let err = expr.eval_with_context(ctx).unwrap_err();
+        assert_matches!(err, Error::InvalidHex { text, .. } if text == "0g");
+    }
+
+    #[test]
+    fn expr_keccak256() {
+        let ctx = Context::default();
+
+        let expr = call("keccak256", vec![Terminal::Bytes(b"hello".to_vec()).into()]);
+        assert_eq!(
+            expr.eval_bytes_with_context(ctx).unwrap(),
+            hex!("1c8aff950685c2ed4bc3174f3472287b56d9517b9c948127319a09a7a36deac8")
+        );
+
+        // The digest of code captured as bytes, or of any other bytes value.
+        let expr = call(
+            "keccak256",
+            vec![call(
+                "concat",
+                vec![
+                    Terminal::Bytes(b"hel".to_vec()).into(),
+                    Terminal::Bytes(b"lo".to_vec()).into(),
+                ],
+            )],
+        );
+        assert_eq!(
+            expr.eval_bytes_with_context(ctx).unwrap(),
+            hex!("1c8aff950685c2ed4bc3174f3472287b56d9517b9c948127319a09a7a36deac8")
+        );
+
+        let expr = call("keccak256", vec![Terminal::Bytes(vec![]).into()]);
+        assert_eq!(
+            expr.eval_bytes_with_context(ctx).unwrap(),
+            hex!("c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470")
+        );
+
+        let expr = call("keccak256", vec![]);
+        let err = expr.eval_with_context(ctx).unwrap_err();
+        assert_matches!(
+            err,
+            Error::ArgumentCount { name, expected: 1, got: 0, .. } if name == "keccak256"
+        );
+    }
+
+    #[test]
+    fn expr_math_builtins() {
+        let eval = |name: &str, args: &[i64]| {
+            let args = args.iter().map(|a| BigInt::from(*a).into()).collect();
+            call(name, args).eval()
+        };
+
+        assert_eq!(eval("min", &[3, -1, 2]).unwrap(), BigInt::from(-1));
+        assert_eq!(eval("max", &[3, -1, 2]).unwrap(), BigInt::from(3));
+        assert_eq!(eval("pow", &[2, 10]).unwrap(), BigInt::from(1024));
+        assert_eq!(eval("log2", &[1]).unwrap(), BigInt::from(0));
+        assert_eq!(eval("log2", &[1025]).unwrap(), BigInt::from(10));
+        assert_eq!(eval("ceil_div", &[7, 2]).unwrap(), BigInt::from(4));
+        assert_eq!(eval("ceil_div", &[8, 2]).unwrap(), BigInt::from(4));
+        assert_eq!(eval("ceil_div", &[-7, 2]).unwrap(), BigInt::from(-3));
+
+        assert_matches!(
+            eval("log2", &[0]),
+            Err(Error::OutOfDomain { operation, .. }) if operation == "log2"
+        );
+        assert_matches!(
+            eval("pow", &[2, -1]),
+            Err(Error::OutOfDomain { operation, .. }) if operation == "pow"
+        );
+        assert_matches!(
+            eval("pow", &[2, 257]),
+            Err(Error::OutOfDomain { operation, .. }) if operation == "pow"
+        );
+        assert_matches!(
+            eval("ceil_div", &[1, 0]),
+            Err(Error::OutOfDomain { operation, .. }) if operation == "ceil_div"
+        );
+        assert_matches!(
+            eval("min", &[]),
+            Err(Error::ArgumentCount {
+                expected: 1,
+                got: 0,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn expr_relative() {
+        let labels: IndexMap<_, _> = vec![("foo".to_string(), Some(LabelDef::new(10)))]
+            .into_iter()
+            .collect();
+
+        let expr = call("rel", vec![Terminal::Label("foo".into()).into()]);
+        let ctx = Context::from(&labels);
+        assert_eq!(
+            expr.eval_with_context(ctx.with_position(4)).unwrap(),
+            BigInt::from(6)
+        );
+        assert_eq!(
+            expr.eval_with_context(ctx.with_position(13)).unwrap(),
+            BigInt::from(-3)
+        );
+        assert_eq!(expr.labels(&HashMap::new()).unwrap(), vec!["foo"]);
+
+        let err = expr.eval_with_context(ctx).unwrap_err();
+        assert_matches!(err, Error::UnknownPosition { .. });
+    }
+
+    #[test]
+    fn expr_capability() {
+        let london = Profile::builtin("london").unwrap();
+        let cancun = Profile::builtin("cancun").unwrap();
+
+        let expr = Expression::from(Terminal::Capability("push0".into()));
+        let ctx = Context::default();
+        assert_eq!(
+            expr.eval_with_context(ctx.with_target(&london)).unwrap(),
+            0.into()
+        );
+        assert_eq!(
+            expr.eval_with_context(ctx.with_target(&cancun)).unwrap(),
+            1.into()
+        );
+        assert_eq!(expr.to_string(), "has_push0");
+
+        let err = expr.eval().unwrap_err();
+        assert_matches!(err, Error::UnknownTarget { name, .. } if name == "push0");
+
+        let expr = Expression::from(Terminal::Capability("nope".into()));
+        let err = expr
+            .eval_with_context(ctx.with_target(&cancun))
+            .unwrap_err();
+        assert_matches!(err, Error::UnknownCapability { name, .. } if name == "nope");
+    }
+
+    #[test]
+    fn expr_out_of_domain() {
+        let expr = Expression::Divide(1u64.into(), 0u64.into());
+        let err = expr.eval().unwrap_err();
+        assert_matches!(err, Error::OutOfDomain { operation, .. } if operation == "/");
+
+        let expr = Expression::Modulo(1u64.into(), 0u64.into());
+        let err = expr.eval().unwrap_err();
+        assert_matches!(err, Error::OutOfDomain { operation, .. } if operation == "%");
+
+        let expr = Expression::ShiftLeft(1u64.into(), BigInt::from(-1).into());
+        let err = expr.eval().unwrap_err();
+        assert_matches!(
+            err,
+            Error::OutOfDomain { operation, value, .. }
+                if operation == "<<" && value == BigInt::from(-1)
+        );
+    }
 }
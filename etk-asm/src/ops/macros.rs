@@ -1,4 +1,12 @@
-use super::{AbstractOp, Expression, Imm};
+use super::expression::word_mask;
+use super::{AbstractOp, Expression, Imm, Terminal};
+use crate::dispatch::{self, Hash, Mask, Strategy};
+use etk_ops::cancun::{
+    Add, Call, CallDataCopy, CallDataLoad, CallDataSize, CallValue, DelegateCall, Dup1, Eq, Gas,
+    GetPc, Gt, Jump, JumpDest, JumpI, Push0, Push1, Push2, Return, ReturnDataCopy, ReturnDataSize,
+    Revert, Shr, StaticCall, Stop,
+};
+use etk_ops::prague::Op;
 use std::convert::From;
 use std::fmt;
 
@@ -62,16 +70,340 @@ impl From<ExpressionMacroDefinition> for MacroDefinition {
 }
 
 /// Instruction macro definition op fields.
+///
+/// New kinds of parameters may be added, so definitions are built with
+/// [`InstructionMacroDefinition::new`] instead of a struct expression.
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[non_exhaustive]
 pub struct InstructionMacroDefinition {
     /// The name that identifies the macro.
     pub name: String,
     /// The name identifiers for the macro's parameters.
     pub parameters: Vec<String>,
+    /// Default values for the last `defaults.len()` parameters, used when an
+    /// invocation omits them.
+    pub defaults: Vec<Expression>,
+    /// The name of the parameter collecting any remaining arguments, if any.
+    ///
+    /// Each statement in `contents` that uses the variadic parameter is
+    /// repeated once per remaining argument.
+    pub variadic: Option<String>,
     /// The body of the macro.
     pub contents: Vec<AbstractOp>,
 }
 
+impl InstructionMacroDefinition {
+    /// A macro called `name`, taking the required `parameters`, that expands
+    /// to `contents`.
+    pub fn new(name: String, parameters: Vec<String>, contents: Vec<AbstractOp>) -> Self {
+        Self {
+            name,
+            parameters,
+            contents,
+            ..Default::default()
+        }
+    }
+
+    /// Give the last `defaults.len()` parameters default values.
+    pub fn with_defaults(mut self, defaults: Vec<Expression>) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Collect the arguments after the other parameters into `variadic`.
+    pub fn with_variadic(mut self, variadic: Option<String>) -> Self {
+        self.variadic = variadic;
+        self
+    }
+
+    /// Look up a macro provided by the assembler, which is used when no macro
+    /// with the same name is declared.
+    pub(crate) fn builtin(name: &str) -> Option<Self> {
+        let defn = match name {
+            // Push the current address of `target`, computed from the program
+            // counter, so it stays correct if the code runs from a different
+            // offset than it was assembled at. The distance to a target before
+            // the anchor is pushed in two's complement, so `add` wraps around.
+            "pc_relative" => Self {
+                name: name.to_owned(),
+                parameters: vec!["target".to_owned()],
+                contents: vec![
+                    AbstractOp::Label("anchor".to_owned()),
+                    AbstractOp::new(GetPc),
+                    AbstractOp::Push(
+                        Expression::And(
+                            Expression::Minus(
+                                Terminal::Variable("target".to_owned()).into(),
+                                Terminal::Label("anchor".to_owned()).into(),
+                            )
+                            .into(),
+                            Terminal::Number(word_mask()).into(),
+                        )
+                        .into(),
+                    ),
+                    AbstractOp::new(Add),
+                ],
+                ..Default::default()
+            },
+            _ => return None,
+        };
+
+        Some(defn)
+    }
+
+    /// Build the body of `%dispatch`, which jumps to the target of the arm
+    /// whose selector matches the first four bytes of the call data, leaving
+    /// the selector on the stack. If no selector matches, execution continues
+    /// after the expansion.
+    ///
+    /// `arms` must be sorted by selector, and with [`Strategy::Mask`] or
+    /// [`Strategy::PerfectHash`], their selectors must fit a [`Mask`] or a
+    /// [`Hash`].
+    pub(crate) fn dispatch(arms: &[(u32, Expression)], strategy: Strategy) -> Self {
+        let mut contents = vec![
+            AbstractOp::new(Push0),
+            AbstractOp::new(CallDataLoad),
+            AbstractOp::new(Push1(Imm::from(0xe0u8))),
+            AbstractOp::new(Shr),
+        ];
+
+        let end = match strategy {
+            Strategy::Linear => {
+                dispatch_linear(&mut contents, arms);
+                false
+            }
+            Strategy::BinarySearch => {
+                let mut splits = 0;
+                dispatch_search(&mut contents, arms, &mut splits);
+                splits > 0
+            }
+            Strategy::Mask => {
+                let selectors: Vec<_> = arms.iter().map(|(selector, _)| *selector).collect();
+                let mask = Mask::find(&selectors).expect("selectors should fit a mask");
+                let offset = mask.offset_ops();
+                dispatch_table(&mut contents, arms, offset, mask.len(), |s| mask.index(s));
+                true
+            }
+            Strategy::PerfectHash => {
+                let selectors: Vec<_> = arms.iter().map(|(selector, _)| *selector).collect();
+                let hash = Hash::find(&selectors).expect("selectors should fit a hash");
+                let offset = hash.offset_ops();
+                dispatch_table(&mut contents, arms, offset, hash.len(), |s| hash.index(s));
+                true
+            }
+        };
+
+        if end {
+            contents.push(AbstractOp::Label("end".to_owned()));
+            contents.push(AbstractOp::new(JumpDest));
+        }
+
+        Self {
+            name: "dispatch".to_owned(),
+            contents,
+            ..Default::default()
+        }
+    }
+}
+
+/// The instruction a trampoline forwards calls with.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum CallKind {
+    Call,
+    DelegateCall,
+    StaticCall,
+}
+
+impl CallKind {
+    /// The kind of call made by the trampoline macro called `name`, if there
+    /// is one.
+    pub(crate) fn from_trampoline(name: &str) -> Option<Self> {
+        match name {
+            "call_trampoline" => Some(Self::Call),
+            "delegatecall_trampoline" => Some(Self::DelegateCall),
+            "staticcall_trampoline" => Some(Self::StaticCall),
+            _ => None,
+        }
+    }
+}
+
+impl InstructionMacroDefinition {
+    /// Build the body of a trampoline, which forwards the call data to
+    /// `target` with a `kind` call, and ends execution.
+    ///
+    /// The call gets `gas`, or all the remaining gas if it's `None`. With
+    /// `bubble`, the trampoline returns (or reverts with) the data returned
+    /// by the call. Otherwise it stops, or reverts without data.
+    pub(crate) fn trampoline(
+        name: &str,
+        kind: CallKind,
+        target: Expression,
+        gas: Option<Expression>,
+        bubble: bool,
+    ) -> Self {
+        let mut contents = vec![
+            AbstractOp::new(CallDataSize),
+            AbstractOp::new(Push0),
+            AbstractOp::new(Push0),
+            AbstractOp::new(CallDataCopy),
+            AbstractOp::new(Push0),
+            AbstractOp::new(Push0),
+            AbstractOp::new(CallDataSize),
+            AbstractOp::new(Push0),
+        ];
+
+        if kind == CallKind::Call {
+            contents.push(AbstractOp::new(CallValue));
+        }
+
+        contents.push(AbstractOp::Push(target.into()));
+        contents.push(match gas {
+            Some(gas) => AbstractOp::Push(gas.into()),
+            None => AbstractOp::new(Gas),
+        });
+        contents.push(match kind {
+            CallKind::Call => AbstractOp::new(Call),
+            CallKind::DelegateCall => AbstractOp::new(DelegateCall),
+            CallKind::StaticCall => AbstractOp::new(StaticCall),
+        });
+
+        let ok = || Terminal::Label("ok".to_owned()).into();
+
+        if bubble {
+            contents.extend([
+                AbstractOp::new(ReturnDataSize),
+                AbstractOp::new(Push0),
+                AbstractOp::new(Push0),
+                AbstractOp::new(ReturnDataCopy),
+                AbstractOp::Push(ok()),
+                AbstractOp::new(JumpI),
+                AbstractOp::new(ReturnDataSize),
+                AbstractOp::new(Push0),
+                AbstractOp::new(Revert),
+                AbstractOp::Label("ok".to_owned()),
+                AbstractOp::new(JumpDest),
+                AbstractOp::new(ReturnDataSize),
+                AbstractOp::new(Push0),
+                AbstractOp::new(Return),
+            ]);
+        } else {
+            contents.extend([
+                AbstractOp::Push(ok()),
+                AbstractOp::new(JumpI),
+                AbstractOp::new(Push0),
+                AbstractOp::new(Push0),
+                AbstractOp::new(Revert),
+                AbstractOp::Label("ok".to_owned()),
+                AbstractOp::new(JumpDest),
+                AbstractOp::new(Stop),
+            ]);
+        }
+
+        Self {
+            name: name.to_owned(),
+            contents,
+            ..Default::default()
+        }
+    }
+}
+
+fn dispatch_linear(contents: &mut Vec<AbstractOp>, arms: &[(u32, Expression)]) {
+    for (selector, target) in arms {
+        contents.extend([
+            AbstractOp::new(Dup1),
+            AbstractOp::Push(Terminal::Number((*selector).into()).into()),
+            AbstractOp::new(Eq),
+            AbstractOp::Push(target.clone().into()),
+            AbstractOp::new(JumpI),
+        ]);
+    }
+}
+
+fn dispatch_search(contents: &mut Vec<AbstractOp>, arms: &[(u32, Expression)], splits: &mut usize) {
+    if arms.len() <= dispatch::LINEAR_LIMIT {
+        dispatch_linear(contents, arms);
+        return;
+    }
+
+    // Selectors below the pivot are searched after the ones above it, which
+    // jump to the end when nothing matches.
+    let (lower, upper) = arms.split_at(arms.len() / 2);
+    let label = format!("lower{}", splits);
+    *splits += 1;
+
+    contents.extend([
+        AbstractOp::new(Dup1),
+        AbstractOp::Push(Terminal::Number(upper[0].0.into()).into()),
+        AbstractOp::new(Gt),
+        AbstractOp::Push(Terminal::Label(label.clone()).into()),
+        AbstractOp::new(JumpI),
+    ]);
+
+    dispatch_search(contents, upper, splits);
+
+    contents.extend([
+        AbstractOp::Push(Terminal::Label("end".to_owned()).into()),
+        AbstractOp::new(Jump),
+        AbstractOp::Label(label),
+        AbstractOp::new(JumpDest),
+    ]);
+
+    dispatch_search(contents, lower, splits);
+}
+
+fn dispatch_table<F>(
+    contents: &mut Vec<AbstractOp>,
+    arms: &[(u32, Expression)],
+    offset: Vec<Op<[u8]>>,
+    len: usize,
+    index: F,
+) where
+    F: Fn(u32) -> usize,
+{
+    contents.extend(offset.into_iter().map(AbstractOp::from));
+    contents.extend([
+        AbstractOp::Push(Terminal::Label("table".to_owned()).into()),
+        AbstractOp::new(Add),
+        AbstractOp::new(Jump),
+        AbstractOp::Label("table".to_owned()),
+    ]);
+
+    let mut slots = vec![None; len];
+    for (selector, target) in arms {
+        slots[index(*selector)] = Some((*selector, target));
+    }
+
+    // Every entry of the table has the same size, so it can be found by its
+    // index. Entries are padded with `jumpdest` before jumping to the end,
+    // since nothing falls through into the end.
+    for slot in slots {
+        let mut entry = vec![AbstractOp::new(JumpDest)];
+        let mut size = 1;
+
+        if let Some((selector, target)) = slot {
+            let push = dispatch::push(selector.into());
+            size += push.size() + 6;
+
+            entry.extend([
+                AbstractOp::new(Dup1),
+                AbstractOp::from(push),
+                AbstractOp::new(Eq),
+                AbstractOp::new(Push2(Imm::from(target.clone()))),
+                AbstractOp::new(JumpI),
+            ]);
+        }
+
+        size += 4;
+        entry.extend((size..dispatch::SLOT_SIZE).map(|_| AbstractOp::new(JumpDest)));
+        entry.extend([
+            AbstractOp::new(Push2(Imm::with_label("end"))),
+            AbstractOp::new(Jump),
+        ]);
+
+        contents.extend(entry);
+    }
+}
+
 /// Instruction macro invocation op.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct InstructionMacroInvocation {
@@ -1,7 +1,8 @@
-use etk_ops::Immediates;
+use etk_ops::{Immediates, Void};
 
 use super::imm::Imm;
 
+use std::borrow::{Borrow, BorrowMut};
 use std::fmt::Debug;
 
 /// Marker type for instructions which may accept labels, variables, or constants
@@ -45,4 +46,25 @@ impl Immediates for Abstract {
     type P30 = Imm;
     type P31 = Imm;
     type P32 = Imm;
+
+    // Tables are assembled separately, from `RawOp::RJumpV`.
+    type Table = Void;
+}
+
+impl Borrow<Imm> for Void {
+    fn borrow(&self) -> &Imm {
+        match *self {}
+    }
+}
+
+impl BorrowMut<Imm> for Void {
+    fn borrow_mut(&mut self) -> &mut Imm {
+        match *self {}
+    }
+}
+
+impl From<Void> for Imm {
+    fn from(v: Void) -> Self {
+        match v {}
+    }
 }
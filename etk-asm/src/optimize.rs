@@ -0,0 +1,190 @@
+//! Optional passes that make assembled programs smaller.
+//!
+//! Optimizations are opt-in (see [`Assembler::set_optimizations`] and
+//! [`Assembler::set_optimization_level`]), and every change they make is
+//! reported as an [`Optimization`], so the effect of each pass can be
+//! reviewed.
+//!
+//! Passes run while instructions are fed into the assembler, before the
+//! positions of later labels are known, so labels always point at the
+//! optimized code.
+//!
+//! [`Assembler::set_optimizations`]: crate::asm::Assembler::set_optimizations
+//! [`Assembler::set_optimization_level`]: crate::asm::Assembler::set_optimization_level
+use crate::ops::{is_push, AbstractOp};
+
+use etk_ops::prague::{Dup1, Op, Operation};
+
+use num_bigint::BigUint;
+
+use std::fmt;
+
+/// An optimization pass.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum Pass {
+    /// Remove instructions between an unconditional exit or jump (like
+    /// `stop`, `return`, `revert`, or `jump`) and the next label or
+    /// `jumpdest`, since they can never be executed.
+    ///
+    /// Raw data (for example from `%include_hex`) is never removed. Code that
+    /// is only read as data (for example with `codecopy`) must start with a
+    /// label, or it will be removed.
+    DeadCode,
+
+    /// Rewrite short sequences of instructions into cheaper equivalents:
+    ///
+    ///  - `push X; pop`, `dup1; pop`, and `swap1; swap1` are removed.
+    ///  - A push of the same constant as the previous push becomes `dup1`.
+    ///
+    /// Only pushes of constants are rewritten, and never across a label, raw
+    /// data, or the boundary of an instruction macro expansion.
+    Peephole,
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DeadCode => write!(f, "dead code elimination"),
+            Self::Peephole => write!(f, "peephole optimization"),
+        }
+    }
+}
+
+/// A preset selection of optimization passes.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum OptimizationLevel {
+    /// No optimizations.
+    None,
+
+    /// Optimizations that never change the behaviour of a program:
+    /// [`Pass::Peephole`].
+    Basic,
+
+    /// Every optimization, including [`Pass::DeadCode`], which assumes code
+    /// that is only read as data starts with a label.
+    Full,
+}
+
+impl OptimizationLevel {
+    /// The passes enabled at this level.
+    pub fn passes(self) -> &'static [Pass] {
+        match self {
+            Self::None => &[],
+            Self::Basic => &[Pass::Peephole],
+            Self::Full => &[Pass::Peephole, Pass::DeadCode],
+        }
+    }
+}
+
+impl Default for OptimizationLevel {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// A change made to a program by an optimization pass.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Optimization {
+    /// The pass that made the change.
+    pub pass: Pass,
+
+    /// The instruction ending the reachable code, for [`Pass::DeadCode`].
+    pub after: Option<Op<()>>,
+
+    /// The instructions that were removed.
+    pub removed: Vec<AbstractOp>,
+
+    /// The instructions that replaced the removed ones, if any.
+    pub replacement: Vec<AbstractOp>,
+
+    /// Number of bytes saved by the change.
+    pub bytes_saved: usize,
+}
+
+impl fmt::Display for Optimization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.replacement.is_empty() {
+            write!(f, "removed `")?;
+        } else {
+            write!(f, "replaced `")?;
+        }
+
+        write_ops(f, &self.removed)?;
+        write!(f, "`")?;
+
+        if !self.replacement.is_empty() {
+            write!(f, " with `")?;
+            write_ops(f, &self.replacement)?;
+            write!(f, "`")?;
+        }
+
+        if let Some(after) = self.after {
+            write!(f, ", which is unreachable after `{}`", after)?;
+        }
+
+        write!(f, " (saved {} byte(s))", self.bytes_saved)
+    }
+}
+
+fn write_ops(f: &mut fmt::Formatter, ops: &[AbstractOp]) -> fmt::Result {
+    for (idx, op) in ops.iter().enumerate() {
+        if idx != 0 {
+            write!(f, "; ")?;
+        }
+        write!(f, "{}", op)?;
+    }
+    Ok(())
+}
+
+/// The constant pushed by `op`, if it is a push.
+fn pushed(op: &Op<[u8]>) -> Option<BigUint> {
+    match op {
+        Op::Push0(_) => Some(BigUint::from(0u8)),
+        _ if is_push(&op.code()) => op.immediate().map(BigUint::from_bytes_be),
+        _ => None,
+    }
+}
+
+/// Find a cheaper replacement for `prev` followed by `next`, for
+/// [`Pass::Peephole`].
+pub(crate) fn peephole(prev: &Op<[u8]>, next: &Op<[u8]>) -> Option<Vec<Op<[u8]>>> {
+    match (prev, next) {
+        (Op::Dup1(_), Op::Pop(_)) | (Op::Swap1(_), Op::Swap1(_)) => Some(vec![]),
+        (_, Op::Pop(_)) if pushed(prev).is_some() => Some(vec![]),
+        // `push0` is already as small as `dup1`, and uses less gas.
+        (_, Op::Push0(_)) => None,
+        _ => match (pushed(prev), pushed(next)) {
+            (Some(a), Some(b)) if a == b => Some(vec![prev.clone(), Op::from(Dup1)]),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etk_ops::prague::{Caller, Pop, Push0, Push1, Push2, Swap1};
+
+    use super::*;
+
+    #[test]
+    fn peephole_rewrites() {
+        let push1: Op<[u8]> = Op::from(Push1([1u8]));
+        let push2: Op<[u8]> = Op::from(Push2([0u8, 1]));
+        let pop = Op::from(Pop);
+
+        assert_eq!(peephole(&push1, &pop), Some(vec![]));
+        assert_eq!(peephole(&Op::from(Push0), &pop), Some(vec![]));
+        assert_eq!(peephole(&Op::from(Dup1), &pop), Some(vec![]));
+        assert_eq!(peephole(&Op::from(Swap1), &Op::from(Swap1)), Some(vec![]));
+        assert_eq!(
+            peephole(&push1, &push2),
+            Some(vec![push1.clone(), Op::from(Dup1)])
+        );
+
+        assert_eq!(peephole(&Op::from(Push0), &Op::from(Push0)), None);
+        assert_eq!(peephole(&push1, &Op::from(Push1([2u8]))), None);
+        assert_eq!(peephole(&Op::from(Caller), &pop), None);
+    }
+}
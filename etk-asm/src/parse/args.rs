@@ -1,10 +1,30 @@
 use super::{error, ParseError, Rule};
+use crate::ops::Expression;
 use pest::iterators::{Pair, Pairs};
 use snafu::{ensure, OptionExt};
 use std::path::PathBuf;
 
 pub(super) trait FromPair: Sized {
     fn from_pair(pair: Pair<Rule>) -> Result<Self, ParseError>;
+
+    /// The value to use when the argument is omitted, or `None` if the
+    /// argument is required.
+    fn missing() -> Option<Self> {
+        None
+    }
+}
+
+impl<T> FromPair for Option<T>
+where
+    T: FromPair,
+{
+    fn from_pair(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        T::from_pair(pair).map(Some)
+    }
+
+    fn missing() -> Option<Self> {
+        Some(None)
+    }
 }
 
 impl FromPair for PathBuf {
@@ -21,6 +41,36 @@ impl FromPair for PathBuf {
     }
 }
 
+impl FromPair for String {
+    fn from_pair(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        ensure!(pair.as_rule() == Rule::string, error::ArgumentType);
+
+        let txt = pair.as_str();
+        Ok(unescape(&txt[1..txt.len() - 1]))
+    }
+}
+
+/// Remove the backslashes escaping `"` and `\` in a string literal.
+fn unescape(txt: &str) -> String {
+    let mut out = String::with_capacity(txt.len());
+    let mut chars = txt.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+impl FromPair for Expression {
+    fn from_pair(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        super::expression::parse(pair)
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub(super) struct Label(pub(super) String);
 
@@ -40,10 +90,15 @@ fn arg<T>(pairs: &mut Pairs<Rule>, expected: usize, got: &mut usize) -> Result<T
 where
     T: FromPair,
 {
-    let pair = pairs.next().context(error::MissingArgument {
-        got: *got,
-        expected,
-    })?;
+    let pair = match pairs.next() {
+        Some(pair) => pair,
+        None => {
+            return T::missing().context(error::MissingArgument {
+                got: *got,
+                expected,
+            })
+        }
+    };
     *got += 1;
     T::from_pair(pair)
 }
@@ -77,3 +132,26 @@ where
         }
     }
 }
+
+impl<T, U> Signature for (T, U)
+where
+    T: FromPair + std::fmt::Debug,
+    U: FromPair + std::fmt::Debug,
+{
+    type Output = Self;
+
+    fn parse_arguments(mut pairs: Pairs<Rule>) -> Result<Self, ParseError> {
+        let expected = 2;
+        let mut got = 0;
+
+        let result = (
+            arg::<T>(&mut pairs, expected, &mut got)?,
+            arg::<U>(&mut pairs, expected, &mut got)?,
+        );
+
+        match pairs.next() {
+            Some(_) => error::ExtraArgument { expected }.fail(),
+            None => Ok(result),
+        }
+    }
+}
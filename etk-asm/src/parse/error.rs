@@ -1,18 +1,39 @@
-use pest::error::Error;
+use etk_ops::prague::Op;
+
+use num_bigint::BigInt;
+
+use pest::error::{Error, ErrorVariant, InputLocation};
 
 use snafu::{Backtrace, IntoError, Snafu};
 
 use super::Rule;
 
+use std::ops::Range;
+
 /// Type for errors that may arise while parsing assembly source code.
 #[derive(Snafu, Debug)]
 #[snafu(context(suffix(false)), visibility(pub(super)))]
 #[non_exhaustive]
 pub enum ParseError {
     /// An immediate value was too large for the given opcode.
-    #[snafu(display("an immediate value was too large for the given opcode"))]
+    #[snafu(display(
+        "the immediate value {} was too large for {} (it needs {} bytes)",
+        value,
+        spec,
+        needed
+    ))]
     #[non_exhaustive]
     ImmediateTooLarge {
+        /// The value of the immediate.
+        value: BigInt,
+
+        /// The opcode given the immediate.
+        spec: Op<()>,
+
+        /// The size in bytes of the smallest immediate that would fit the
+        /// value.
+        needed: usize,
+
         /// The location of the error.
         backtrace: Backtrace,
     },
@@ -22,7 +43,7 @@ pub enum ParseError {
     #[non_exhaustive]
     Lexer {
         /// The underlying source of this error.
-        source: Box<dyn std::error::Error>,
+        source: Box<dyn std::error::Error + Send + Sync>,
 
         /// The location of this error.
         backtrace: Backtrace,
@@ -53,6 +74,82 @@ pub enum ParseError {
         backtrace: Backtrace,
     },
 
+    /// A macro parameter was declared out of order.
+    #[snafu(display(
+        "parameter `{}` is out of order (parameters without defaults come \
+        first, then parameters with defaults, then one variadic parameter)",
+        parameter
+    ))]
+    #[non_exhaustive]
+    ParameterOrder {
+        /// The name of the misplaced parameter.
+        parameter: String,
+
+        /// Location of the error.
+        backtrace: Backtrace,
+    },
+
+    /// The source requires a version of the assembler other than this one,
+    /// with `%pragma etk`.
+    #[snafu(display(
+        "the source requires etk `{}`, but this is etk {}",
+        requirement,
+        version
+    ))]
+    #[non_exhaustive]
+    UnsupportedVersion {
+        /// The version requirement given in the source.
+        requirement: String,
+
+        /// The version of the assembler.
+        version: String,
+
+        /// The location of the error.
+        backtrace: Backtrace,
+    },
+
+    /// The version requirement of `%pragma etk` couldn't be parsed.
+    #[cfg(feature = "fs")]
+    #[snafu(display("`{}` isn't a valid version requirement", requirement))]
+    #[non_exhaustive]
+    InvalidVersionRequirement {
+        /// The version requirement given in the source.
+        requirement: String,
+
+        /// The underlying source of this error.
+        source: semver::Error,
+
+        /// The location of the error.
+        backtrace: Backtrace,
+    },
+
+    /// The source requires a version of the assembler with `%pragma etk`, but
+    /// requirements can only be checked with the `fs` feature.
+    #[cfg(not(feature = "fs"))]
+    #[snafu(display(
+        "checking the requirement etk `{}` needs the `fs` feature",
+        requirement
+    ))]
+    #[non_exhaustive]
+    UncheckedVersion {
+        /// The version requirement given in the source.
+        requirement: String,
+
+        /// The location of the error.
+        backtrace: Backtrace,
+    },
+
+    /// A `%pragma` names something the assembler doesn't know about.
+    #[snafu(display("unknown pragma `{}`", name))]
+    #[non_exhaustive]
+    UnknownPragma {
+        /// The name following `%pragma`.
+        name: String,
+
+        /// The location of the error.
+        backtrace: Backtrace,
+    },
+
     /// An argument provided to a macro was of the wrong type.
     #[snafu(display("incorrect argument type"))]
     #[non_exhaustive]
@@ -60,6 +157,90 @@ pub enum ParseError {
         /// The location of the error.
         backtrace: Backtrace,
     },
+
+    /// More than one error was found in the same source.
+    #[snafu(display("{}", describe(errors)))]
+    #[non_exhaustive]
+    Multiple {
+        /// Every error found, in the order they appear in the source.
+        errors: Vec<ParseError>,
+
+        /// The location of the error.
+        backtrace: Backtrace,
+    },
+}
+
+/// Describe each of `errors`, with the errors that caused it.
+fn describe(errors: &[ParseError]) -> String {
+    let mut text = format!("found {} errors", errors.len());
+
+    for (idx, err) in errors.iter().enumerate() {
+        text.push_str(&format!("\n\nerror {}: {}", idx + 1, err));
+
+        let mut current = std::error::Error::source(err);
+        while let Some(cause) = current {
+            text.push_str(&format!("\n{}", cause));
+            current = cause.source();
+        }
+    }
+
+    text
+}
+
+impl ParseError {
+    /// Combine `errors`, found in the same source, into one error.
+    ///
+    /// Panics if `errors` is empty.
+    pub(crate) fn combine(errors: Vec<ParseError>) -> Self {
+        let mut flat = Vec::with_capacity(errors.len());
+        for err in errors {
+            match err {
+                Self::Multiple { errors, .. } => flat.extend(errors),
+                err => flat.push(err),
+            }
+        }
+
+        assert!(!flat.is_empty(), "there should be at least one error");
+        if flat.len() == 1 {
+            flat.pop().unwrap()
+        } else {
+            Multiple { errors: flat }.build()
+        }
+    }
+
+    /// Every error found in the source: the errors of [`ParseError::Multiple`],
+    /// or just this error.
+    pub fn errors(&self) -> &[ParseError] {
+        match self {
+            Self::Multiple { errors, .. } => errors,
+            err => std::slice::from_ref(err),
+        }
+    }
+
+    /// The bytes of the source where lexing failed, and what was expected
+    /// there, if known.
+    pub(crate) fn span(&self) -> Option<(Range<usize>, String)> {
+        let err = match self {
+            Self::Lexer { source, .. } => source.downcast_ref::<Error<Rule>>()?,
+            _ => return None,
+        };
+
+        let range = match err.location {
+            InputLocation::Pos(pos) => pos..pos,
+            InputLocation::Span((start, end)) => start..end,
+        };
+
+        let message = match err.variant {
+            ErrorVariant::ParsingError { ref positives, .. } if !positives.is_empty() => {
+                let rules: Vec<_> = positives.iter().map(|r| format!("{:?}", r)).collect();
+                format!("expected {}", rules.join(", "))
+            }
+            ErrorVariant::ParsingError { .. } => "unexpected input".to_owned(),
+            ErrorVariant::CustomError { ref message } => message.clone(),
+        };
+
+        Some((range, message))
+    }
 }
 
 impl From<Error<Rule>> for ParseError {
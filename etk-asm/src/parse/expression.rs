@@ -1,6 +1,8 @@
+use super::args::FromPair;
 use super::error::ParseError;
 use super::macros;
 use super::parser::Rule;
+use crate::ast::CODE_SIZE;
 use crate::ops::{Expression, Terminal};
 use num_bigint::{BigInt, Sign};
 use pest::{
@@ -10,9 +12,17 @@ use pest::{
 use sha3::{Digest, Keccak256};
 
 pub(crate) fn parse(pair: Pair<Rule>) -> Result<Expression, ParseError> {
+    // From lowest to highest precedence, like in C.
     let climber = PrecClimber::new(vec![
+        Operator::new(Rule::or, Assoc::Left),
+        Operator::new(Rule::xor, Assoc::Left),
+        Operator::new(Rule::and, Assoc::Left),
+        Operator::new(Rule::shift_left, Assoc::Left)
+            | Operator::new(Rule::shift_right, Assoc::Left),
         Operator::new(Rule::plus, Assoc::Left) | Operator::new(Rule::minus, Assoc::Left),
-        Operator::new(Rule::times, Assoc::Left) | Operator::new(Rule::divide, Assoc::Left),
+        Operator::new(Rule::times, Assoc::Left)
+            | Operator::new(Rule::divide, Assoc::Left)
+            | Operator::new(Rule::modulo, Assoc::Left),
     ]);
 
     fn consume(pair: Pair<Rule>, climber: &PrecClimber<Rule>) -> Expression {
@@ -22,13 +32,19 @@ pub(crate) fn parse(pair: Pair<Rule>) -> Result<Expression, ParseError> {
             Rule::minus => Expression::Minus(Box::new(lhs), Box::new(rhs)),
             Rule::times => Expression::Times(Box::new(lhs), Box::new(rhs)),
             Rule::divide => Expression::Divide(Box::new(lhs), Box::new(rhs)),
+            Rule::modulo => Expression::Modulo(Box::new(lhs), Box::new(rhs)),
+            Rule::and => Expression::And(Box::new(lhs), Box::new(rhs)),
+            Rule::or => Expression::Or(Box::new(lhs), Box::new(rhs)),
+            Rule::xor => Expression::Xor(Box::new(lhs), Box::new(rhs)),
+            Rule::shift_left => Expression::ShiftLeft(Box::new(lhs), Box::new(rhs)),
+            Rule::shift_right => Expression::ShiftRight(Box::new(lhs), Box::new(rhs)),
             _ => unreachable!(),
         };
 
         let txt = pair.as_str();
 
         match pair.as_rule() {
-            Rule::expression => climber.climb(pair.into_inner(), primary, infix),
+            Rule::expression | Rule::condition => climber.climb(pair.into_inner(), primary, infix),
             Rule::binary => parse_radix_str(&txt[2..], 2),
             Rule::octal => parse_radix_str(&txt[2..], 8),
             Rule::hex => parse_radix_str(&txt[2..], 16),
@@ -40,8 +56,14 @@ pub(crate) fn parse(pair: Pair<Rule>) -> Result<Expression, ParseError> {
                     .into()
             }
             Rule::label => Terminal::Label(txt.to_string()).into(),
+            Rule::code_size => Terminal::Label(CODE_SIZE.to_owned()).into(),
+            Rule::capability => {
+                let name = txt.strip_prefix("has_").unwrap();
+                Terminal::Capability(name.to_string()).into()
+            }
             Rule::selector => parse_selector(pair, 4),
             Rule::topic => parse_selector(pair, 32),
+            Rule::string => Terminal::Bytes(String::from_pair(pair).unwrap().into_bytes()).into(),
             Rule::expression_macro => macros::parse_expression_macro(pair).unwrap(),
             Rule::instruction_macro_variable => {
                 let variable = txt.strip_prefix('$').unwrap();
@@ -66,9 +88,11 @@ fn parse_radix_str(s: &str, radix: u32) -> Expression {
         .into()
 }
 
+/// The first `size` bytes of the hash of a signature, kept as bytes so leading
+/// zeros aren't dropped where the width matters, like in `%bytes(..)`.
 fn parse_selector(pair: Pair<Rule>, size: usize) -> Expression {
     let raw = pair.into_inner().next().unwrap().as_str();
     let mut hasher = Keccak256::new();
     hasher.update(raw.as_bytes());
-    BigInt::from_bytes_be(Sign::Plus, &hasher.finalize()[0..size]).into()
+    Terminal::Bytes(hasher.finalize()[0..size].to_vec()).into()
 }
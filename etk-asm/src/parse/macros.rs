@@ -1,13 +1,14 @@
-use super::args::Signature;
-use super::error::ParseError;
+use super::args::{FromPair, Signature};
+use super::error::{self, ParseError};
 use super::expression;
 use super::parser::Rule;
 use crate::ast::Node;
 use crate::ops::{
     AbstractOp, Expression, ExpressionMacroDefinition, ExpressionMacroInvocation,
-    InstructionMacroDefinition, InstructionMacroInvocation,
+    InstructionMacroDefinition, InstructionMacroInvocation, Terminal,
 };
 use pest::iterators::Pair;
+use snafu::ensure;
 use std::path::PathBuf;
 
 pub(crate) fn parse(pair: Pair<Rule>) -> Result<AbstractOp, ParseError> {
@@ -34,13 +35,111 @@ pub(crate) fn parse_builtin(pair: Pair<Rule>) -> Result<Node, ParseError> {
             Node::Import(args.0)
         }
         Rule::include => {
-            let args = <(PathBuf,)>::parse_arguments(pair.into_inner())?;
-            Node::Include(args.0)
+            let args = <(PathBuf, Option<String>)>::parse_arguments(pair.into_inner())?;
+            Node::Include(args.0, args.1)
         }
         Rule::include_hex => {
             let args = <(PathBuf,)>::parse_arguments(pair.into_inner())?;
             Node::IncludeHex(args.0)
         }
+        Rule::include_bin => {
+            let args = <(PathBuf,)>::parse_arguments(pair.into_inner())?;
+            Node::IncludeBin(args.0)
+        }
+        Rule::include_sol_runtime => {
+            let args = <(PathBuf, String)>::parse_arguments(pair.into_inner())?;
+            Node::IncludeSolRuntime(args.0, args.1)
+        }
+        Rule::include_artifact => {
+            let args = <(PathBuf, Option<String>)>::parse_arguments(pair.into_inner())?;
+            let field = args.1.unwrap_or_else(|| "deployedBytecode".to_owned());
+            Node::IncludeArtifact(args.0, field)
+        }
+        Rule::bytes => {
+            let mut args = pair
+                .into_inner()
+                .map(Expression::from_pair)
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // Several arguments are included one after the other.
+            let expr = match args.len() {
+                0 => {
+                    return error::MissingArgument {
+                        got: 0usize,
+                        expected: 1usize,
+                    }
+                    .fail()
+                }
+                1 => args.pop().unwrap(),
+                _ => Expression::Macro(ExpressionMacroInvocation {
+                    name: "concat".to_owned(),
+                    parameters: args,
+                }),
+            };
+
+            Node::Bytes(expr)
+        }
+        Rule::string_data => {
+            let args = <(String,)>::parse_arguments(pair.into_inner())?;
+            Node::Bytes(Terminal::Bytes(args.0.into_bytes()).into())
+        }
+        Rule::string_padded => {
+            let args = <(String,)>::parse_arguments(pair.into_inner())?;
+            let mut bytes = args.0.into_bytes();
+
+            // Padded to a whole number of words, like strings in the ABI.
+            let padded = (bytes.len() + 31) / 32 * 32;
+            bytes.resize(padded.max(32), 0);
+
+            Node::Bytes(Terminal::Bytes(bytes).into())
+        }
+        Rule::word => Node::Data(32, parse_entries(pair)?),
+        Rule::dw => Node::Data(2, parse_entries(pair)?),
+        Rule::abi_encode => {
+            let mut args = pair.into_inner();
+            let types = match args.next() {
+                Some(types) => String::from_pair(types)?,
+                None => {
+                    return error::MissingArgument {
+                        got: 0usize,
+                        expected: 1usize,
+                    }
+                    .fail()
+                }
+            };
+
+            let values = args.map(expression::parse).collect::<Result<_, _>>()?;
+            Node::AbiEncode(types, values)
+        }
+        Rule::jumptable => Node::JumpTable(parse_entries(pair)?),
+        Rule::dispatch => {
+            let mut parameters = Vec::new();
+            for arm in pair.into_inner() {
+                let mut arm = arm.into_inner();
+                parameters.push(expression::parse(arm.next().unwrap())?);
+
+                let target = arm.next().unwrap().as_str().to_owned();
+                parameters.push(Terminal::Label(target).into());
+            }
+
+            Node::Op(AbstractOp::Macro(InstructionMacroInvocation {
+                name: "dispatch".to_owned(),
+                parameters,
+            }))
+        }
+        Rule::extern_label => {
+            let label = pair.into_inner().next().unwrap();
+            Node::Extern(label.as_str().to_string())
+        }
+        Rule::patchable => {
+            let name = pair.into_inner().next().unwrap();
+            Node::Patchable(name.as_str().to_string())
+        }
+        Rule::export => {
+            let name = pair.into_inner().next().unwrap();
+            Node::Export(name.as_str().to_string())
+        }
+        Rule::runtime_start => Node::RuntimeStart,
         Rule::push_macro => {
             let expr = expression::parse(pair.into_inner().next().unwrap())?;
             Node::Op(AbstractOp::Push(expr.into()))
@@ -51,6 +150,17 @@ pub(crate) fn parse_builtin(pair: Pair<Rule>) -> Result<Node, ParseError> {
     Ok(node)
 }
 
+/// Parse the arguments of a table, like `%jumptable`, which must all be
+/// expressions.
+fn parse_entries(pair: Pair<Rule>) -> Result<Vec<Expression>, ParseError> {
+    pair.into_inner()
+        .map(|arg| {
+            ensure!(arg.as_rule() == Rule::expression, error::ArgumentType);
+            expression::parse(arg)
+        })
+        .collect()
+}
+
 fn parse_instruction_macro_defn(pair: Pair<Rule>) -> Result<AbstractOp, ParseError> {
     let mut pairs = pair.into_inner();
 
@@ -58,23 +168,49 @@ fn parse_instruction_macro_defn(pair: Pair<Rule>) -> Result<AbstractOp, ParseErr
     let name = macro_defn.next().unwrap();
 
     let mut parameters = Vec::<String>::new();
+    let mut defaults = Vec::<Expression>::new();
+    let mut variadic = None;
     for pair in macro_defn {
-        parameters.push(pair.as_str().into());
+        let rule = pair.as_rule();
+        let mut inner = pair.clone().into_inner();
+        let parameter = match rule {
+            Rule::function_parameter => pair.as_str().to_string(),
+            _ => inner.next().unwrap().as_str().to_string(),
+        };
+
+        let in_order =
+            variadic.is_none() && (rule != Rule::function_parameter || defaults.is_empty());
+        ensure!(in_order, error::ParameterOrder { parameter });
+
+        match rule {
+            Rule::variadic_parameter => variadic = Some(parameter),
+            Rule::default_parameter => {
+                defaults.push(expression::parse(inner.next().unwrap())?);
+                parameters.push(parameter);
+            }
+            _ => parameters.push(parameter),
+        }
     }
 
     let mut contents = Vec::<AbstractOp>::new();
     for pair in pairs {
-        if pair.as_rule() == Rule::push_macro {
-            let expr = expression::parse(pair.into_inner().next().unwrap())?;
-            contents.push(AbstractOp::Push(expr.into()));
-        } else {
-            contents.push(super::parse_abstract_op(pair)?);
+        match pair.as_rule() {
+            Rule::push_macro => {
+                let expr = expression::parse(pair.into_inner().next().unwrap())?;
+                contents.push(AbstractOp::Push(expr.into()));
+            }
+            // Labels inside a macro aren't checked for fall through, and the
+            // stack isn't checked against `# keep` comments.
+            Rule::fallthrough | Rule::keep => (),
+            _ => contents.push(super::parse_abstract_op(pair)?),
         }
     }
 
     let defn = InstructionMacroDefinition {
         name: name.as_str().to_string(),
         parameters,
+        defaults,
+        variadic,
         contents,
     };
 
@@ -13,33 +13,276 @@ mod parser {
     pub(super) struct AsmParser;
 }
 
+use std::borrow::Cow;
 use std::convert::TryInto;
+use std::ops::Range;
 
 use self::{
+    args::FromPair,
     error::ParseError,
     parser::{AsmParser, Rule},
 };
 
 use crate::ast::Node;
 use crate::ops::AbstractOp;
-use etk_ops::cancun::Op;
+use etk_ops::prague::{Op, Operation};
 use num_bigint::BigInt;
-use pest::{iterators::Pair, Parser};
+use pest::{
+    error::{Error as PestError, InputLocation},
+    iterators::{Pair, Pairs},
+    Parser,
+};
+#[cfg(feature = "fs")]
+use semver::{Prerelease, Version, VersionReq};
+#[cfg(feature = "fs")]
+use snafu::{ensure, ResultExt};
 
+#[cfg(test)]
 pub(crate) fn parse_asm(asm: &str) -> Result<Vec<Node>, ParseError> {
-    let mut program: Vec<Node> = Vec::new();
+    let program = parse_asm_spanned(asm)?;
+    Ok(program.into_iter().map(|(node, _)| node).collect())
+}
+
+/// Parse `asm`, returning each node with the range of bytes it was parsed from.
+///
+/// Parsing continues past syntax errors, so every error in `asm` is returned
+/// at once (as [`ParseError::Multiple`], if there's more than one.)
+pub(crate) fn parse_asm_spanned(asm: &str) -> Result<Vec<(Node, Range<usize>)>, ParseError> {
+    check_pragmas(asm)?;
+
+    let (program, errors) = parse_recovering(asm);
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(ParseError::combine(errors))
+    }
+}
 
+/// Parse `asm`, skipping lines with syntax errors and invalid statements,
+/// returning the nodes that could be parsed and every error found.
+pub(crate) fn parse_recovering(asm: &str) -> (Vec<(Node, Range<usize>)>, Vec<ParseError>) {
+    let mut errors = Vec::new();
+    let mut source = Cow::Borrowed(asm);
+
+    loop {
+        let err = match AsmParser::parse(Rule::program, &source) {
+            Ok(pairs) => {
+                let program = parse_stmts_into(pairs, &mut errors);
+                return (program, errors);
+            }
+            Err(e) => e,
+        };
+
+        let recovered = blank_line(source.to_mut(), &err);
+        errors.push(ParseError::from(err));
+
+        if !recovered {
+            return (Vec::new(), errors);
+        }
+    }
+}
+
+/// Replace the line containing the syntax error `err` with spaces, so the
+/// rest of `source` can be parsed without moving anything. Returns `false`
+/// if the line was already blank, so parsing can't go any further.
+fn blank_line(source: &mut String, err: &PestError<Rule>) -> bool {
+    let pos = match err.location {
+        InputLocation::Pos(pos) => pos,
+        InputLocation::Span((start, _)) => start,
+    };
+    let pos = pos.min(source.len());
+
+    let start = source[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let end = source[pos..].find('\n').map_or(source.len(), |i| pos + i);
+
+    if source[start..end].trim().is_empty() {
+        return false;
+    }
+
+    // Spaces are one byte each, so every other span stays where it was.
+    let blank = " ".repeat(end - start);
+    source.replace_range(start..end, &blank);
+    true
+}
+
+/// Check every `%pragma` in `asm`, before parsing the rest of it, so sources
+/// written for a newer assembler fail with a clear message instead of a
+/// syntax error.
+fn check_pragmas(asm: &str) -> Result<(), ParseError> {
+    for line in asm.lines() {
+        let line = line.trim_start();
+        if !line.starts_with("%pragma") {
+            continue;
+        }
+
+        // Malformed pragmas are reported by the full parse.
+        let mut pairs = match AsmParser::parse(Rule::pragma, line) {
+            Ok(mut p) => p.next().unwrap().into_inner(),
+            Err(_) => continue,
+        };
+
+        let name = pairs.next().unwrap().as_str();
+        let value = String::from_pair(pairs.next().unwrap())?;
+
+        match name {
+            "etk" => check_version(&value)?,
+            _ => return error::UnknownPragma { name }.fail(),
+        }
+    }
+
+    Ok(())
+}
+
+/// Names of every `%pragma` in `asm`, in order of appearance.
+pub(crate) fn pragma_names(asm: &str) -> Vec<String> {
+    asm.lines()
+        .filter_map(|line| AsmParser::parse(Rule::pragma, line.trim_start()).ok())
+        .map(|mut pairs| {
+            let pragma = pairs.next().unwrap();
+            pragma.into_inner().next().unwrap().as_str().to_owned()
+        })
+        .collect()
+}
+
+/// Check that this version of the crate satisfies `requirement`, like `>=0.4`.
+#[cfg(feature = "fs")]
+fn check_version(requirement: &str) -> Result<(), ParseError> {
+    let req =
+        VersionReq::parse(requirement).context(error::InvalidVersionRequirement { requirement })?;
+
+    // Development builds satisfy the requirements of their release, which
+    // semver's rules for pre-releases wouldn't allow.
+    let mut version = Version::parse(env!("CARGO_PKG_VERSION")).unwrap();
+    version.pre = Prerelease::EMPTY;
+
+    ensure!(
+        req.matches(&version),
+        error::UnsupportedVersion {
+            requirement,
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    );
+
+    Ok(())
+}
+
+/// Without `semver`, requirements can't be checked, so they are refused
+/// instead of being ignored.
+#[cfg(not(feature = "fs"))]
+fn check_version(requirement: &str) -> Result<(), ParseError> {
+    error::UncheckedVersion { requirement }.fail()
+}
+
+/// Signatures of every `selector(...)` in `asm`, in order of appearance.
+#[cfg(feature = "fs")]
+pub(crate) fn parse_selectors(asm: &str) -> Result<Vec<String>, ParseError> {
     let pairs = AsmParser::parse(Rule::program, asm)?;
+    let signatures = pairs
+        .flatten()
+        .filter(|pair| pair.as_rule() == Rule::selector)
+        .map(|pair| pair.into_inner().next().unwrap().as_str().to_owned())
+        .collect();
+    Ok(signatures)
+}
+
+fn parse_stmts(pairs: Pairs<Rule>) -> Result<Vec<(Node, Range<usize>)>, ParseError> {
+    let mut errors = Vec::new();
+    let program = parse_stmts_into(pairs, &mut errors);
+
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(ParseError::combine(errors))
+    }
+}
+
+/// Parse every valid statement of `pairs`, adding the errors of the others to
+/// `errors`.
+fn parse_stmts_into(pairs: Pairs<Rule>, errors: &mut Vec<ParseError>) -> Vec<(Node, Range<usize>)> {
+    let mut program = Vec::new();
+
     for pair in pairs {
+        let span = pair.as_span();
+        let span = span.start()..span.end();
+
         let node = match pair.as_rule() {
-            Rule::builtin => macros::parse_builtin(pair)?,
+            Rule::builtin => macros::parse_builtin(pair),
+            Rule::scope => parse_scope(pair),
+            Rule::capture => parse_capture(pair),
+            Rule::conditional => parse_conditional(pair),
+            Rule::fallthrough => Ok(Node::Fallthrough),
+            Rule::keep => Ok(parse_keep(pair)),
+            Rule::pragma => continue,
+            Rule::raw_op => parse_raw_op(pair),
+            Rule::rjumpv => parse_rjumpv(pair),
             Rule::EOI => continue,
-            _ => parse_abstract_op(pair)?.into(),
+            _ => parse_abstract_op(pair).map(Node::from),
         };
-        program.push(node);
+
+        // Keep going after an invalid statement, to report the others too.
+        match node {
+            Ok(node) => program.push((node, span)),
+            Err(e) => errors.push(e),
+        }
     }
 
-    Ok(program)
+    program
+}
+
+fn parse_scope(pair: Pair<Rule>) -> Result<Node, ParseError> {
+    let mut body = pair.into_inner().next().unwrap().into_inner();
+    let name = body.next().unwrap().as_str().to_owned();
+    Ok(Node::Scope(name, parse_stmts(body)?))
+}
+
+fn parse_capture(pair: Pair<Rule>) -> Result<Node, ParseError> {
+    let mut body = pair.into_inner().next().unwrap().into_inner();
+    let name = body.next().unwrap().as_str().to_owned();
+    Ok(Node::Capture(name, parse_stmts(body)?))
+}
+
+fn parse_conditional(pair: Pair<Rule>) -> Result<Node, ParseError> {
+    let mut body = pair.into_inner().next().unwrap().into_inner();
+    let condition = expression::parse(body.next().unwrap())?;
+    let then = parse_stmts(body.next().unwrap().into_inner())?;
+    let otherwise = match body.next() {
+        Some(block) => parse_stmts(block.into_inner())?,
+        None => Vec::new(),
+    };
+    Ok(Node::If(condition, then, otherwise))
+}
+
+fn parse_keep(pair: Pair<Rule>) -> Node {
+    let slots = pair
+        .into_inner()
+        .map(|slot| match slot.as_str() {
+            "_" => None,
+            name => Some(name.to_owned()),
+        })
+        .collect();
+    Node::Keep(slots)
+}
+
+fn parse_raw_op(pair: Pair<Rule>) -> Result<Node, ParseError> {
+    let mut args = pair.into_inner();
+    let code = expression::parse(args.next().unwrap())?;
+
+    let immediate = match args.next() {
+        Some(size) => {
+            let size = expression::parse(size)?;
+            let value = expression::parse(args.next().unwrap())?;
+            Some((size, value))
+        }
+        None => None,
+    };
+
+    Ok(Node::Opcode(code, immediate))
+}
+
+fn parse_rjumpv(pair: Pair<Rule>) -> Result<Node, ParseError> {
+    let entries = pair.into_inner().next().unwrap().into_inner();
+    let entries = entries.map(expression::parse).collect::<Result<_, _>>()?;
+    Ok(Node::RJumpV(entries))
 }
 
 fn parse_abstract_op(pair: Pair<Rule>) -> Result<AbstractOp, ParseError> {
@@ -49,6 +292,7 @@ fn parse_abstract_op(pair: Pair<Rule>) -> Result<AbstractOp, ParseError> {
             AbstractOp::Label(pair.into_inner().next().unwrap().as_str().to_string())
         }
         Rule::push => parse_push(pair)?,
+        Rule::immediate_op => parse_immediate_op(pair)?,
         Rule::op => {
             let spec: Op<()> = pair.as_str().parse().unwrap();
             let op = Op::new(spec).unwrap();
@@ -67,12 +311,30 @@ fn parse_push(pair: Pair<Rule>) -> Result<AbstractOp, ParseError> {
     let operand = pair.next().unwrap();
 
     let spec = Op::<()>::push(size).unwrap();
+    parse_immediate(spec, operand)
+}
+
+fn parse_immediate_op(pair: Pair<Rule>) -> Result<AbstractOp, ParseError> {
+    let mut pair = pair.into_inner();
+    let spec: Op<()> = pair.next().unwrap().as_str().parse().unwrap();
+    let operand = pair.next().unwrap();
+
+    parse_immediate(spec, operand)
+}
+
+fn parse_immediate(spec: Op<()>, operand: Pair<Rule>) -> Result<AbstractOp, ParseError> {
+    let size = spec.extra_len();
     let expr = expression::parse(operand)?;
 
     if let Ok(val) = expr.eval() {
         let max = BigInt::pow(&BigInt::from(2u32), (8 * size).try_into().unwrap());
         if val >= max {
-            return error::ImmediateTooLarge.fail();
+            return error::ImmediateTooLarge {
+                needed: crate::asm::push_width(&val),
+                value: val,
+                spec,
+            }
+            .fail();
         }
     }
 
@@ -87,7 +349,8 @@ mod tests {
         InstructionMacroDefinition, InstructionMacroInvocation, Terminal,
     };
     use assert_matches::assert_matches;
-    use etk_ops::cancun::*;
+    use etk_ops::cancun;
+    use etk_ops::prague::*;
     use hex_literal::hex;
     use num_bigint::Sign;
     use std::path::PathBuf;
@@ -117,6 +380,59 @@ mod tests {
         assert_matches!(parse_asm(asm), Ok(e) if e == expected);
     }
 
+    #[test]
+    fn parse_cancun_ops() {
+        let asm = r#"
+            tload
+            tstore
+            mcopy
+            blobhash
+            blobbasefee
+        "#;
+        let expected = nodes![
+            Op::from(TLoad),
+            Op::from(TStore),
+            Op::from(MCopy),
+            Op::from(BlobHash),
+            Op::from(BlobBaseFee)
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+    }
+
+    #[test]
+    fn parse_eof_ops() {
+        let asm = r#"
+            dataload
+            dataloadn 0x0102
+            datasize
+            datacopy
+            rjump 0xfffd
+            rjumpi lbl
+            rjump rel(lbl)
+            dupn 1
+            swapn 2
+            exchange 0x12
+        "#;
+        let expected = nodes![
+            Op::from(DataLoad),
+            Op::from(DataLoadN(Imm::from([1, 2]))),
+            Op::from(DataSize),
+            Op::from(DataCopy),
+            Op::from(RJump(Imm::from([0xff, 0xfd]))),
+            Op::from(RJumpI(Imm::with_label("lbl"))),
+            Op::from(RJump(Imm::with_expression(Expression::Macro(
+                ExpressionMacroInvocation {
+                    name: "rel".into(),
+                    parameters: vec![Terminal::Label("lbl".into()).into()],
+                }
+            )))),
+            Op::from(DupN(Imm::from([1]))),
+            Op::from(SwapN(Imm::from([2]))),
+            Op::from(Exchange(Imm::from([0x12])))
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+    }
+
     #[test]
     fn parse_single_line() {
         let asm = r#"
@@ -232,7 +548,10 @@ mod tests {
         assert_matches!(parse_asm(asm), Ok(e) if e == expected);
 
         let asm = "push2 0x010203";
-        assert_matches!(parse_asm(asm), Err(ParseError::ImmediateTooLarge { .. }));
+        assert_matches!(
+            parse_asm(asm),
+            Err(ParseError::ImmediateTooLarge { needed: 3, .. })
+        );
     }
 
     #[test]
@@ -287,6 +606,22 @@ mod tests {
         assert_matches!(parse_asm(asm), Ok(e) if e == expected);
     }
 
+    #[test]
+    fn parse_code_size() {
+        let asm = r#"
+            push2 codesize()
+            push2 $$
+        "#;
+        let expected = nodes![
+            Op::from(Push2(Imm::with_label(crate::ast::CODE_SIZE))),
+            Op::from(Push2(Imm::with_label(crate::ast::CODE_SIZE))),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        // The label standing in for the size can't be declared in a source.
+        assert_matches!(parse_asm("%size:"), Err(ParseError::Lexer { .. }));
+    }
+
     #[test]
     fn parse_push_op_as_label() {
         let asm = r#"
@@ -311,12 +646,13 @@ mod tests {
             push4 selector("approve(address,uint256)")
             push32 topic("transfer(address,uint256)")
         "#;
+        let bytes = |b: &[u8]| Imm::with_expression(Terminal::Bytes(b.to_vec()).into());
         let expected = nodes![
-            Op::from(Push4(Imm::from(hex!("06fdde03")))),
-            Op::from(Push4(Imm::from(hex!("70a08231")))),
-            Op::from(Push4(Imm::from(hex!("a9059cbb")))),
-            Op::from(Push4(Imm::from(hex!("095ea7b3")))),
-            Op::from(Push32(Imm::from(hex!(
+            Op::from(Push4(bytes(&hex!("06fdde03")))),
+            Op::from(Push4(bytes(&hex!("70a08231")))),
+            Op::from(Push4(bytes(&hex!("a9059cbb")))),
+            Op::from(Push4(bytes(&hex!("095ea7b3")))),
+            Op::from(Push32(bytes(&hex!(
                 "a9059cbb2ab09eb219583f4a59a5d0623ade346d962bcd4e46b11da047c9049b"
             )))),
         ];
@@ -324,6 +660,57 @@ mod tests {
         assert_matches!(parse_asm(asm), Ok(e) if e == expected);
     }
 
+    #[test]
+    #[cfg(feature = "fs")]
+    fn parse_selector_signatures() {
+        let asm = r#"
+            push4 selector("name()")
+            %macro twice()
+                push4 selector("transfer(address,uint256)")
+            %end
+            push32 topic("Transfer(address,address,uint256)")
+            push4 selector("name()")
+        "#;
+        assert_eq!(
+            parse_selectors(asm).unwrap(),
+            ["name()", "transfer(address,uint256)", "name()"],
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn parse_pragma() {
+        let asm = r#"
+            %pragma etk ">=0.4"
+            %pragma etk "0.4.*" # comment
+            pc
+        "#;
+        let expected = nodes![Op::from(GetPc)];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        // The version is checked before the rest of the source is parsed.
+        let asm = r#"
+            %pragma etk ">=99"
+            %future_syntax(1)
+        "#;
+        assert_matches!(
+            parse_asm(asm),
+            Err(ParseError::UnsupportedVersion { requirement, .. }) if requirement == ">=99"
+        );
+
+        let asm = r#"%pragma etk "latest""#;
+        assert_matches!(
+            parse_asm(asm),
+            Err(ParseError::InvalidVersionRequirement { .. })
+        );
+
+        let asm = r#"%pragma solidity "^0.8.0""#;
+        assert_matches!(
+            parse_asm(asm),
+            Err(ParseError::UnknownPragma { name, .. }) if name == "solidity"
+        );
+    }
+
     #[test]
     fn parse_selector_with_spaces() {
         let asm = r#"
@@ -332,6 +719,25 @@ mod tests {
         assert_matches!(parse_asm(asm), Err(ParseError::Lexer { .. }));
     }
 
+    #[test]
+    fn parse_keccak256() {
+        let asm = r#"
+            push32 keccak256("hello")
+            push32 keccak256("a\"b")
+        "#;
+        let keccak256 = |text: &[u8]| {
+            Imm::with_expression(Expression::Macro(ExpressionMacroInvocation {
+                name: "keccak256".into(),
+                parameters: vec![Terminal::Bytes(text.to_vec()).into()],
+            }))
+        };
+        let expected = nodes![
+            Op::from(Push32(keccak256(b"hello"))),
+            Op::from(Push32(keccak256(b"a\"b"))),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+    }
+
     #[test]
     fn parse_include() {
         let asm = format!(
@@ -343,12 +749,34 @@ mod tests {
         );
         let expected = nodes![
             Op::from(Push1(Imm::from(1u8))),
-            Node::Include(PathBuf::from("foo.asm")),
+            Node::Include(PathBuf::from("foo.asm"), None),
             Op::from(Push1(Imm::from(2u8))),
         ];
         assert_matches!(parse_asm(&asm), Ok(e) if e == expected)
     }
 
+    #[test]
+    fn parse_include_namespace() {
+        let asm = r#"
+            %include("foo.asm", "lib")
+            push2 lib.start
+            "#;
+        let expected = nodes![
+            Node::Include(PathBuf::from("foo.asm"), Some("lib".into())),
+            Op::from(Push2(Imm::with_label("lib.start"))),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected)
+    }
+
+    #[test]
+    fn parse_include_extra_argument() {
+        let asm = r#"%include("foo.asm", "lib", "extra")"#;
+        assert_matches!(
+            parse_asm(asm),
+            Err(ParseError::ExtraArgument { expected: 2, .. })
+        );
+    }
+
     #[test]
     fn parse_include_hex() {
         let asm = format!(
@@ -366,6 +794,486 @@ mod tests {
         assert_matches!(parse_asm(&asm), Ok(e) if e == expected)
     }
 
+    #[test]
+    fn parse_include_bin() {
+        let asm = r#"%include_bin("foo.bin")"#;
+        let expected = nodes![Node::IncludeBin(PathBuf::from("foo.bin"))];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected)
+    }
+
+    #[test]
+    fn parse_include_sol_runtime() {
+        let asm = r#"%include_sol_runtime("Token.sol", "Token")"#;
+        let expected = nodes![Node::IncludeSolRuntime(
+            PathBuf::from("Token.sol"),
+            "Token".into()
+        )];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        let asm = r#"%include_sol_runtime("Token.sol")"#;
+        assert_matches!(parse_asm(asm), Err(ParseError::MissingArgument { .. }));
+    }
+
+    #[test]
+    fn parse_include_artifact() {
+        let asm = r#"
+            %include_artifact("out/A.json")
+            %include_artifact("out/A.json", field="bytecode")
+            %include_artifact("out/A.json", "evm.bytecode")
+        "#;
+        let expected = nodes![
+            Node::IncludeArtifact(PathBuf::from("out/A.json"), "deployedBytecode".into()),
+            Node::IncludeArtifact(PathBuf::from("out/A.json"), "bytecode".into()),
+            Node::IncludeArtifact(PathBuf::from("out/A.json"), "evm.bytecode".into()),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        let asm = r#"%include_artifact("out/A.json", field=1)"#;
+        assert_matches!(parse_asm(asm), Err(ParseError::Lexer { .. }));
+    }
+
+    #[test]
+    fn parse_bytes() {
+        let asm = r#"
+            %bytes("hi")
+            %bytes("hi" + 1)
+            %bytes("hi", 0x01)
+            push2 "hi"
+        "#;
+        let expected = nodes![
+            Node::Bytes(Terminal::Bytes(b"hi".to_vec()).into()),
+            Node::Bytes(Expression::Plus(
+                Terminal::Bytes(b"hi".to_vec()).into(),
+                1u64.into()
+            )),
+            Node::Bytes(Expression::Macro(ExpressionMacroInvocation {
+                name: "concat".into(),
+                parameters: vec![
+                    Terminal::Bytes(b"hi".to_vec()).into(),
+                    Terminal::from(1u64).into()
+                ],
+            })),
+            Op::from(Push2(Imm::from(Terminal::Bytes(b"hi".to_vec())))),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        assert_matches!(
+            parse_asm("%bytes()"),
+            Err(ParseError::MissingArgument { .. })
+        );
+    }
+
+    #[test]
+    fn parse_string() {
+        let asm = r#"
+            %string("hi \"you\"")
+            %string_padded("hi")
+            %string_padded("")
+        "#;
+
+        let mut padded = b"hi".to_vec();
+        padded.resize(32, 0);
+
+        let expected = nodes![
+            Node::Bytes(Terminal::Bytes(b"hi \"you\"".to_vec()).into()),
+            Node::Bytes(Terminal::Bytes(padded).into()),
+            Node::Bytes(Terminal::Bytes(vec![0; 32]).into()),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        let long = "a".repeat(33);
+        let nodes = parse_asm(&format!("%string_padded(\"{}\")", long)).unwrap();
+        assert_matches!(&nodes[0], Node::Bytes(Expression::Terminal(Terminal::Bytes(b))) if b.len() == 64);
+
+        assert_matches!(
+            parse_asm("%string(1)"),
+            Err(ParseError::ArgumentType { .. })
+        );
+    }
+
+    #[test]
+    fn parse_data() {
+        let asm = r#"
+            %word(a, 1)
+            %dw(b + 1)
+        "#;
+        let expected = nodes![
+            Node::Data(
+                32,
+                vec![
+                    Terminal::Label("a".into()).into(),
+                    Terminal::from(1u64).into()
+                ]
+            ),
+            Node::Data(
+                2,
+                vec![Expression::Plus(
+                    Terminal::Label("b".into()).into(),
+                    Terminal::Number(1.into()).into(),
+                )]
+            ),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        assert_matches!(
+            parse_asm(r#"%dw("a")"#),
+            Err(ParseError::ArgumentType { .. })
+        );
+    }
+
+    #[test]
+    fn parse_jump_table() {
+        let asm = r#"
+            %jumptable(a, b + 1)
+            %jumptable()
+        "#;
+        let expected = nodes![
+            Node::JumpTable(vec![
+                Terminal::Label("a".into()).into(),
+                Expression::Plus(
+                    Terminal::Label("b".into()).into(),
+                    Terminal::Number(1.into()).into(),
+                ),
+            ]),
+            Node::JumpTable(vec![]),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        assert_matches!(
+            parse_asm(r#"%jumptable("a")"#),
+            Err(ParseError::ArgumentType { .. })
+        );
+    }
+
+    #[test]
+    fn parse_raw_op() {
+        let asm = r#"
+            op(0xfe)
+            op(0xe9, 2, a + 1)
+        "#;
+        let expected = nodes![
+            Node::Opcode(Terminal::Number(0xfe.into()).into(), None),
+            Node::Opcode(
+                Terminal::Number(0xe9.into()).into(),
+                Some((
+                    Terminal::Number(2.into()).into(),
+                    Expression::Plus(
+                        Terminal::Label("a".into()).into(),
+                        Terminal::Number(1.into()).into(),
+                    ),
+                )),
+            ),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        assert_matches!(parse_asm("op(0xe9, 2)"), Err(ParseError::Lexer { .. }));
+    }
+
+    #[test]
+    fn parse_rjumpv() {
+        let asm = r#"
+            rjumpv a, b + 1
+            rjumpv 0x10
+        "#;
+        let expected = nodes![
+            Node::RJumpV(vec![
+                Terminal::Label("a".into()).into(),
+                Expression::Plus(
+                    Terminal::Label("b".into()).into(),
+                    Terminal::Number(1.into()).into(),
+                ),
+            ]),
+            Node::RJumpV(vec![Terminal::Number(0x10.into()).into()]),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        assert_matches!(parse_asm("rjumpv"), Err(ParseError::Lexer { .. }));
+    }
+
+    #[test]
+    fn parse_dispatch() {
+        let asm = r#"
+            %dispatch {
+                0x01 => a, 2 => b
+                0x03 => c
+            }
+            %dispatch {}
+        "#;
+        let expected = nodes![
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "dispatch".into(),
+                parameters: vec![
+                    Terminal::Number(1.into()).into(),
+                    Terminal::Label("a".into()).into(),
+                    Terminal::Number(2.into()).into(),
+                    Terminal::Label("b".into()).into(),
+                    Terminal::Number(3.into()).into(),
+                    Terminal::Label("c".into()).into(),
+                ],
+            }),
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "dispatch".into(),
+                parameters: vec![],
+            }),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+    }
+
+    #[test]
+    fn parse_abi_encode() {
+        let asm = r#"%abi_encode("uint8,bytes2", 1, "hi")"#;
+        let expected = nodes![Node::AbiEncode(
+            "uint8,bytes2".into(),
+            vec![
+                Terminal::Number(1.into()).into(),
+                Terminal::Bytes(b"hi".to_vec()).into(),
+            ],
+        )];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        assert_matches!(
+            parse_asm("%abi_encode()"),
+            Err(ParseError::MissingArgument { .. })
+        );
+        assert_matches!(
+            parse_asm("%abi_encode(1)"),
+            Err(ParseError::ArgumentType { .. })
+        );
+    }
+
+    #[test]
+    fn parse_extern() {
+        let asm = r#"
+            %extern foo.bar
+            push2 foo.bar
+        "#;
+        let expected = nodes![
+            Node::Extern("foo.bar".into()),
+            Op::from(Push2(Imm::with_label("foo.bar"))),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected)
+    }
+
+    #[test]
+    fn parse_patchable() {
+        let asm = r#"
+            %patchable owner
+            push20 owner()
+        "#;
+        let expected = nodes![
+            Node::Patchable("owner".into()),
+            Op::from(Push20(Imm::with_macro(ExpressionMacroInvocation {
+                name: "owner".into(),
+                parameters: vec![]
+            }))),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected)
+    }
+
+    #[test]
+    fn parse_export() {
+        let asm = r#"
+            %export push_owner
+            %export _helper
+            %export lib.entry
+        "#;
+        let expected = nodes![
+            Node::Export("push_owner".into()),
+            Node::Export("_helper".into()),
+            Node::Export("lib.entry".into()),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected)
+    }
+
+    #[test]
+    fn parse_runtime_start() {
+        let asm = r#"
+            push0
+            %runtime_start
+            caller
+        "#;
+        let expected = nodes![Op::from(Push0), Node::RuntimeStart, Op::from(Caller),];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        assert!(parse_asm("%runtime_started").is_err());
+    }
+
+    #[test]
+    fn parse_fallthrough() {
+        let asm = r#"
+            jumpi # fallthrough
+            # just a comment
+            # fallthrough
+            a:
+            jumpdest # fallthrough, but not a pragma
+        "#;
+        let expected = nodes![
+            Op::from(JumpI),
+            Node::Fallthrough,
+            Node::Fallthrough,
+            AbstractOp::Label("a".into()),
+            Op::from(JumpDest),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected)
+    }
+
+    #[test]
+    fn parse_keep() {
+        let asm = r#"
+            caller # keep: from
+            callvalue
+            # keep: value, _, from
+            # keep the value for later
+        "#;
+        let expected = nodes![
+            Op::from(Caller),
+            Node::Keep(vec![Some("from".into())]),
+            Op::from(CallValue),
+            Node::Keep(vec![Some("value".into()), None, Some("from".into())]),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected)
+    }
+
+    #[test]
+    fn parse_scope_block() {
+        let asm = r#"
+            %scope runtime {
+                a:
+                push1 1 # one
+                %scope inner { caller }
+            }
+            push1 runtime.size
+        "#;
+        let nodes = parse_asm(asm).unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(
+            nodes[1],
+            Node::from(Op::from(Push1(Imm::with_label("runtime.size"))))
+        );
+
+        let (name, inner) = match nodes[0] {
+            Node::Scope(ref name, ref inner) => (name, inner),
+            ref other => panic!("expected a scope, got {:?}", other),
+        };
+        assert_eq!(name, "runtime");
+
+        let inner: Vec<_> = inner.iter().map(|(node, _)| node.clone()).collect();
+        assert_eq!(
+            inner[..2],
+            nodes![
+                AbstractOp::Label("a".into()),
+                Op::from(Push1(Imm::from(1u8)))
+            ][..]
+        );
+        assert_matches!(
+            inner[2],
+            Node::Scope(ref name, ref nodes) if name == "inner" && nodes.len() == 1
+        );
+
+        assert_matches!(
+            parse_asm("%scope { caller }"),
+            Err(ParseError::Lexer { .. })
+        );
+        assert_matches!(
+            parse_asm("%scope a { caller"),
+            Err(ParseError::Lexer { .. })
+        );
+    }
+
+    #[test]
+    fn parse_section_block() {
+        let nodes = parse_asm("%section data {\na:\ncaller\n}").unwrap();
+        assert_matches!(
+            nodes.as_slice(),
+            [Node::Scope(name, inner)] if name == "data" && inner.len() == 2
+        );
+
+        assert_matches!(
+            parse_asm("%sectiondata { caller }"),
+            Err(ParseError::Lexer { .. })
+        );
+    }
+
+    #[test]
+    fn parse_capture_block() {
+        let nodes = parse_asm("%capture code {\na:\ncaller\n}\n%bytes(code())").unwrap();
+        assert_matches!(
+            nodes.as_slice(),
+            [Node::Capture(name, inner), Node::Bytes(_)] if name == "code" && inner.len() == 2
+        );
+
+        assert_matches!(
+            parse_asm("%capture { caller }"),
+            Err(ParseError::Lexer { .. })
+        );
+    }
+
+    #[test]
+    fn parse_conditional() {
+        let asm = r#"
+            %if has_push0 | has_lbl_x {
+                push0
+            } %else {
+                push1 0
+            }
+            %ifdef has_tstore {
+                tstore
+            }
+        "#;
+        let nodes = parse_asm(asm).unwrap();
+        assert_eq!(nodes.len(), 2);
+
+        let (condition, then, otherwise) = match nodes[0] {
+            Node::If(ref condition, ref then, ref otherwise) => (condition, then, otherwise),
+            ref other => panic!("expected a conditional, got {:?}", other),
+        };
+        assert_eq!(
+            *condition,
+            Expression::Or(
+                Terminal::Capability("push0".into()).into(),
+                Terminal::Label("has_lbl_x".into()).into(),
+            )
+        );
+        assert_eq!(then[0].0, Node::from(Op::from(Push0)));
+        assert_eq!(otherwise[0].0, Node::from(Op::from(Push1(Imm::from(0u8)))));
+
+        assert_matches!(
+            nodes[1],
+            Node::If(Expression::Terminal(Terminal::Capability(ref name)), _, ref otherwise)
+                if name == "tstore" && otherwise.is_empty()
+        );
+
+        assert_matches!(
+            parse_asm("%if has_push0 { push0"),
+            Err(ParseError::Lexer { .. })
+        );
+    }
+
+    #[test]
+    fn parse_capability_only_in_conditions() {
+        let asm = r#"
+            has_push0:
+            push1 has_push0
+            push2 has_push0 + (has_tstore)
+        "#;
+        let expected = nodes![
+            AbstractOp::Label("has_push0".into()),
+            Op::from(Push1(Imm::with_label("has_push0"))),
+            Op::from(Push2(Imm::with_expression(Expression::Plus(
+                Terminal::Label("has_push0".into()).into(),
+                Terminal::Label("has_tstore".into()).into(),
+            )))),
+        ];
+        assert_matches!(parse_asm(asm), Ok(e) if e == expected);
+
+        let nodes = parse_asm("%if (has_push0) + 1 { }").unwrap();
+        assert_matches!(
+            nodes[0],
+            Node::If(Expression::Plus(ref lhs, _), _, _)
+                if **lhs == Terminal::Capability("push0".into()).into()
+        );
+    }
+
     #[test]
     fn parse_import() {
         let asm = format!(
@@ -476,13 +1384,13 @@ mod tests {
         );
         let expected = nodes![
             AbstractOp::MacroDefinition(
-                InstructionMacroDefinition {
-                    name: "my_macro".into(),
-                    parameters: vec!["foo".into(), "bar".into()],
-                    contents: vec![
-                        AbstractOp::new(GasPrice),
-                        AbstractOp::new(Pop),
-                        AbstractOp::new(Push1(
+                InstructionMacroDefinition::new(
+                    "my_macro".into(),
+                    vec!["foo".into(), "bar".into()],
+                    vec![
+                        AbstractOp::new(cancun::GasPrice),
+                        AbstractOp::new(cancun::Pop),
+                        AbstractOp::new(cancun::Push1(
                             Expression::Plus(
                                 Terminal::Variable("foo".to_string()).into(),
                                 Terminal::Variable("bar".to_string()).into()
@@ -494,8 +1402,8 @@ mod tests {
                             name: "another_macro".into(),
                             parameters: vec![]
                         })
-                    ]
-                }
+                    ],
+                )
                 .into()
             ),
             AbstractOp::Macro(InstructionMacroInvocation {
@@ -514,6 +1422,52 @@ mod tests {
         assert_eq!(parse_asm(&asm).unwrap(), expected)
     }
 
+    #[test]
+    fn parse_instruction_macro_defaults_and_variadic() {
+        let asm = r#"
+            %macro push_all(first, second = 2, rest...)
+                push1 $first
+                %push($rest)
+            %end
+        "#;
+        let expected = nodes![AbstractOp::MacroDefinition(
+            InstructionMacroDefinition::new(
+                "push_all".into(),
+                vec!["first".into(), "second".into()],
+                vec![
+                    AbstractOp::new(cancun::Push1(Imm::with_variable("first"))),
+                    AbstractOp::Push(Imm::with_variable("rest")),
+                ],
+            )
+            .with_defaults(vec![Terminal::Number(2.into()).into()])
+            .with_variadic(Some("rest".into()))
+            .into()
+        )];
+
+        assert_eq!(parse_asm(asm).unwrap(), expected)
+    }
+
+    #[test]
+    fn parse_instruction_macro_parameter_order() {
+        let asm = r#"
+            %macro bad(first = 1, second)
+            %end
+        "#;
+        assert_matches!(
+            parse_asm(asm),
+            Err(ParseError::ParameterOrder { parameter, .. }) if parameter == "second"
+        );
+
+        let asm = r#"
+            %macro bad(rest..., last = 1)
+            %end
+        "#;
+        assert_matches!(
+            parse_asm(asm),
+            Err(ParseError::ParameterOrder { parameter, .. }) if parameter == "last"
+        );
+    }
+
     #[test]
     fn parse_expression() {
         let asm = format!(
@@ -557,6 +1511,28 @@ mod tests {
         assert_eq!(parse_asm(&asm).unwrap(), expected)
     }
 
+    #[test]
+    fn parse_expression_bitwise() {
+        let asm = r#"
+            push2 1<<8|0xff&0x0f
+            push1 7%4^3>>1
+        "#;
+        let expected = nodes![
+            Op::from(Push2(Imm::with_expression(Expression::Or(
+                Box::new(Expression::ShiftLeft(1u64.into(), 8u64.into())),
+                Box::new(Expression::And(
+                    Terminal::Number(0xff.into()).into(),
+                    Terminal::Number(0x0f.into()).into()
+                ))
+            )))),
+            Op::from(Push1(Imm::with_expression(Expression::Xor(
+                Box::new(Expression::Modulo(7u64.into(), 4u64.into())),
+                Box::new(Expression::ShiftRight(3u64.into(), 1u64.into()))
+            ))))
+        ];
+        assert_eq!(parse_asm(asm).unwrap(), expected)
+    }
+
     #[test]
     fn parse_push_macro_with_expression() {
         let asm = format!(
@@ -597,4 +1573,38 @@ mod tests {
         ];
         assert_eq!(parse_asm(&asm).unwrap(), expected);
     }
+
+    #[test]
+    fn parse_recovers_after_syntax_error() {
+        let asm = "push1 1\n@@ @@\npush1 2\n$$\npush1 3\n";
+
+        let err = parse_asm(asm).unwrap_err();
+        let lines: Vec<_> = err
+            .errors()
+            .iter()
+            .map(|e| asm[..e.span().unwrap().0.start].matches('\n').count())
+            .collect();
+        assert_eq!(lines, [1, 3]);
+        assert!(err.to_string().starts_with("found 2 errors"));
+    }
+
+    #[test]
+    fn parse_reports_every_invalid_statement() {
+        let asm = "push1 256\npush1 1\n%%\npush2 65536\n";
+
+        let err = parse_asm(asm).unwrap_err();
+        assert_matches!(
+            err.errors(),
+            [
+                ParseError::Lexer { .. },
+                ParseError::ImmediateTooLarge { .. },
+                ParseError::ImmediateTooLarge { .. },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_single_error_is_not_combined() {
+        assert_matches!(parse_asm("push1 1\n%%\n"), Err(ParseError::Lexer { .. }));
+    }
 }
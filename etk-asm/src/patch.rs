@@ -0,0 +1,641 @@
+//! Patching assembled code, without rebuilding it from source.
+//!
+//! ## Dispatchers
+//!
+//! Most contracts begin with a dispatcher comparing the function selector
+//! against each selector they implement, and jumping to the matching
+//! function, like [`%dispatch`] does:
+//!
+//! ```text
+//! dup1
+//! push4 0xa9059cbb
+//! eq
+//! push2 transfer
+//! jumpi
+//! ```
+//!
+//! [`dispatch_entries`] finds these comparisons in runtime code, and
+//! [`Patch`] reroutes a selector to a different destination, for example
+//! code appended with [`Patch::append_asm`], without rebuilding the contract
+//! from source:
+//!
+//! ```rust
+//! # use etk_asm::patch::{Patch, Error};
+//! # use hex_literal::hex;
+//! let code = etk_asm::assemble_str(
+//!     r#"
+//!     %dispatch { 0x11111111 => broken }
+//!     stop
+//!     broken:
+//!     jumpdest
+//!     invalid
+//!     "#,
+//! )
+//! .unwrap();
+//!
+//! let mut patch = Patch::new(code);
+//! let fixed = patch.append_asm("jumpdest\nstop\n")?;
+//! patch.reroute(0x11111111, fixed)?;
+//!
+//! assert_eq!(patch.entries()[0].target, fixed);
+//! # Result::<(), Error>::Ok(())
+//! ```
+//!
+//! Only the destination pushed by the matching comparison is changed, so the
+//! size and the rest of the code stay the same, except for anything
+//! appended.
+//!
+//! ## Patchable Constants
+//!
+//! Values only known when deploying, like the address of an owner or a chain
+//! id, can be declared patchable with `%patchable NAME`, where `NAME` is an
+//! expression macro without parameters. Each push of exactly `NAME()` is
+//! kept as written, and listed in [`Patches`], so its immediate can be
+//! replaced:
+//!
+//! ```rust
+//! # use etk_asm::patch::Error;
+//! # use hex_literal::hex;
+//! let mut output = Vec::new();
+//! let mut ingest = etk_asm::ingest::Ingest::new(&mut output);
+//! ingest
+//!     .ingest(
+//!         file!(),
+//!         r#"
+//!         %def owner()
+//!             0
+//!         %end
+//!         %patchable owner
+//!
+//!         push20 owner()
+//!         caller
+//!         eq
+//!         "#,
+//!     )
+//!     .unwrap();
+//! let patches = ingest.patches().clone();
+//! drop(ingest);
+//!
+//! assert_eq!(patches.ranges("owner"), [1..21]);
+//!
+//! patches.apply(&mut output, "owner", &[0x11; 20])?;
+//! assert_eq!(&output[1..21], &[0x11; 20]);
+//! # Result::<(), Error>::Ok(())
+//! ```
+//!
+//! [`%dispatch`]: https://quilt.github.io/etk/ch02-lang/ch04-macros/ch01-builtins.html
+mod error {
+    use snafu::{Backtrace, Snafu};
+
+    /// Errors that may arise while patching code.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// The dispatcher doesn't compare against the selector.
+        #[snafu(display("no dispatcher entry for the selector 0x{:08x}", selector))]
+        #[non_exhaustive]
+        SelectorNotFound {
+            /// The selector that wasn't found.
+            selector: u32,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The new destination doesn't fit in the push of the entry.
+        #[snafu(display(
+            "the destination {} doesn't fit in the {} byte push for 0x{:08x}",
+            target,
+            size,
+            selector
+        ))]
+        #[non_exhaustive]
+        TargetTooLarge {
+            /// The selector being rerouted.
+            selector: u32,
+
+            /// The new destination.
+            target: usize,
+
+            /// The size of the pushed destination, in bytes.
+            size: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The new destination isn't a `jumpdest` instruction, so jumping to
+        /// it would fail.
+        #[snafu(display("the destination {} isn't a jumpdest", target))]
+        #[non_exhaustive]
+        NotJumpDest {
+            /// The new destination.
+            target: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// No push of the constant was recorded.
+        #[snafu(display("`{}` isn't a patchable constant", name))]
+        #[non_exhaustive]
+        UnknownConstant {
+            /// The name of the constant.
+            name: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The new value doesn't fit in a push of the constant.
+        #[snafu(display(
+            "the new value of `{}` is {} bytes long, but it's pushed with {} bytes",
+            name,
+            len,
+            size
+        ))]
+        #[non_exhaustive]
+        ValueTooLarge {
+            /// The name of the constant.
+            name: String,
+
+            /// The length of the new value, without leading zeros.
+            len: usize,
+
+            /// The size of the push, in bytes.
+            size: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A push of the constant is past the end of the code being patched.
+        #[snafu(display(
+            "`{}` is pushed at {}..{}, past the end of the code ({} bytes)",
+            name,
+            start,
+            end,
+            len
+        ))]
+        #[non_exhaustive]
+        OutOfBounds {
+            /// The name of the constant.
+            name: String,
+
+            /// The start of the immediate of the push.
+            start: usize,
+
+            /// The end of the immediate of the push.
+            end: usize,
+
+            /// The length of the code.
+            len: usize,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The code to append couldn't be assembled.
+        #[snafu(display("the appended code couldn't be assembled"))]
+        #[non_exhaustive]
+        Assemble {
+            /// The underlying source of this error.
+            #[snafu(backtrace)]
+            source: crate::ingest::Error,
+        },
+    }
+}
+
+pub use self::error::Error;
+
+use crate::disasm::{Disassembler, Offset};
+use crate::ingest::Ingest;
+use crate::ops::is_push;
+use crate::vfs::MemoryFiles;
+
+use etk_ops::prague::{Op, Operation};
+
+#[cfg(feature = "fs")]
+use serde::{Deserialize, Serialize};
+
+use snafu::{ensure, OptionExt, ResultExt};
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::ops::Range;
+
+/// A comparison in a dispatcher, jumping to `target` when the function
+/// selector is `selector`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DispatchEntry {
+    /// The selector compared against.
+    pub selector: u32,
+
+    /// Position of the pushed destination (the immediate of the push) in the
+    /// code.
+    pub offset: usize,
+
+    /// Size of the pushed destination, in bytes.
+    pub size: usize,
+
+    /// The destination jumped to when the selector matches.
+    pub target: usize,
+}
+
+/// Disassemble `code`, returning the complete instructions.
+fn disassemble(code: &[u8]) -> Vec<Offset<Op<[u8]>>> {
+    let mut dasm = Disassembler::<Op<[u8]>>::default();
+    dasm.write_all(code).unwrap();
+    dasm.ops().collect()
+}
+
+/// The immediate of `op`, if it's a push of at most `max` bytes.
+fn pushed(op: &Op<[u8]>, max: usize) -> Option<&[u8]> {
+    if !is_push(&op.code()) {
+        return None;
+    }
+
+    op.immediate().filter(|imm| imm.len() <= max)
+}
+
+fn to_usize(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |acc, b| (acc << 8) | usize::from(*b))
+}
+
+/// Find the comparisons of a dispatcher in `code`.
+///
+/// Entries are recognized as a push of the selector, an `eq` (optionally
+/// preceded by `dup2`, as older versions of solc emit), and a push of the
+/// destination followed by `jumpi`. Other ways of dispatching, like binary
+/// searches on the selector, are found as long as their final comparisons
+/// have this shape.
+pub fn dispatch_entries(code: &[u8]) -> Vec<DispatchEntry> {
+    let ops = disassemble(code);
+    let mut entries = Vec::new();
+
+    for (idx, op) in ops.iter().enumerate() {
+        let selector = match pushed(&op.item, 4) {
+            Some(imm) => imm,
+            None => continue,
+        };
+
+        let mut next = idx + 1;
+        if matches!(ops.get(next).map(|o| &o.item), Some(Op::Dup2(_))) {
+            next += 1;
+        }
+
+        let rest: Vec<_> = ops[next.min(ops.len())..].iter().take(3).collect();
+        let (push, target) = match rest.as_slice() {
+            [eq, push, jumpi]
+                if matches!(eq.item, Op::Eq(_)) && matches!(jumpi.item, Op::JumpI(_)) =>
+            {
+                match pushed(&push.item, std::mem::size_of::<usize>()) {
+                    Some(target) => (push, target),
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+
+        entries.push(DispatchEntry {
+            selector: to_usize(selector) as u32,
+            offset: push.offset + 1,
+            size: target.len(),
+            target: to_usize(target),
+        });
+    }
+
+    entries
+}
+
+/// Code being patched. See the [module documentation](self) for an example.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Patch {
+    code: Vec<u8>,
+}
+
+impl Patch {
+    /// Begin patching `code`, usually the runtime code of a contract.
+    pub fn new(code: Vec<u8>) -> Self {
+        Self { code }
+    }
+
+    /// The patched code so far.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Finish patching, returning the patched code.
+    pub fn into_code(self) -> Vec<u8> {
+        self.code
+    }
+
+    /// The comparisons of the dispatcher in the patched code. See
+    /// [`dispatch_entries`].
+    pub fn entries(&self) -> Vec<DispatchEntry> {
+        dispatch_entries(&self.code)
+    }
+
+    /// Append `code`, returning its position in the patched code.
+    ///
+    /// If the patched code ends in the middle of a push (for example, in the
+    /// metadata solc appends), it's first padded with zeros, so the appended
+    /// instructions aren't mistaken for the pushed value.
+    pub fn append(&mut self, code: &[u8]) -> usize {
+        let complete: usize = disassemble(&self.code)
+            .iter()
+            .map(|op| op.item.size())
+            .sum();
+
+        if let Some(first) = self.code.get(complete) {
+            let size = Op::<()>::from(*first).size();
+            self.code.resize(complete + size, 0);
+        }
+
+        let position = self.code.len();
+        self.code.extend_from_slice(code);
+        position
+    }
+
+    /// Assemble `src` and append it, returning its position in the patched
+    /// code, like [`Patch::append`].
+    ///
+    /// Labels in `src` are positioned after the code it's appended to, so
+    /// they can be jumped to. Nothing is read from the filesystem, as with
+    /// [`assemble_str`](crate::assemble_str).
+    pub fn append_asm(&mut self, src: &str) -> Result<usize, Error> {
+        let position = self.append(&[]);
+
+        let prefixed = format!("%bytes(hex(\"{}\"))\n{}", hex::encode(&self.code), src);
+        let mut output = Vec::new();
+        let mut ingest = Ingest::new(&mut output);
+        ingest.set_file_provider(MemoryFiles::new());
+        ingest
+            .ingest("/patch.etk", &prefixed)
+            .context(error::Assemble)?;
+        drop(ingest);
+
+        self.code.extend_from_slice(&output[position..]);
+        Ok(position)
+    }
+
+    /// Make the dispatcher jump to `target` for `selector`, returning the
+    /// entry as it was before.
+    ///
+    /// `target` must be a `jumpdest` in the patched code, and fit in the
+    /// push of the entry it replaces.
+    pub fn reroute(&mut self, selector: u32, target: usize) -> Result<DispatchEntry, Error> {
+        let entry = self
+            .entries()
+            .into_iter()
+            .find(|e| e.selector == selector)
+            .context(error::SelectorNotFound { selector })?;
+
+        let is_jumpdest = disassemble(&self.code)
+            .iter()
+            .any(|op| op.offset == target && matches!(op.item, Op::JumpDest(_)));
+        ensure!(is_jumpdest, error::NotJumpDest { target });
+
+        let bytes = target.to_be_bytes();
+        let (padding, value) = bytes.split_at(bytes.len() - entry.size);
+        ensure!(
+            padding.iter().all(|b| *b == 0),
+            error::TargetTooLarge {
+                selector,
+                target,
+                size: entry.size,
+            }
+        );
+
+        self.code[entry.offset..entry.offset + entry.size].copy_from_slice(value);
+        Ok(entry)
+    }
+}
+
+/// Reroute `selector` in the dispatcher of `code` to `target`, returning the
+/// patched code. See [`Patch::reroute`].
+pub fn reroute(code: &[u8], selector: u32, target: usize) -> Result<Vec<u8>, Error> {
+    let mut patch = Patch::new(code.to_vec());
+    patch.reroute(selector, target)?;
+    Ok(patch.into_code())
+}
+
+/// The immediates of pushes of patchable constants, declared with
+/// `%patchable NAME`. See the [module documentation](self) for an example.
+///
+/// Serialized as JSON, each constant is listed with the range of bytes of
+/// every push, like:
+///
+/// ```json
+/// {
+///   "owner": [{ "start": 1, "end": 21 }]
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "fs", serde(transparent))]
+pub struct Patches {
+    constants: BTreeMap<String, Vec<Range<usize>>>,
+}
+
+impl Patches {
+    /// Make an empty `Patches`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a push of `name`, whose immediate is at `range`.
+    pub fn insert<S>(&mut self, name: S, range: Range<usize>)
+    where
+        S: Into<String>,
+    {
+        self.constants.entry(name.into()).or_default().push(range);
+    }
+
+    /// Record every push of `other`, moved forward by `offset` bytes.
+    pub(crate) fn append(&mut self, other: &Self, offset: usize) {
+        for (name, ranges) in &other.constants {
+            for range in ranges {
+                self.insert(name.clone(), range.start + offset..range.end + offset);
+            }
+        }
+    }
+
+    /// The names of the constants, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.constants.keys().map(String::as_str)
+    }
+
+    /// The ranges of bytes of every push of `name`, in order, or nothing if
+    /// it isn't pushed.
+    pub fn ranges(&self, name: &str) -> &[Range<usize>] {
+        self.constants
+            .get(name)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Whether no constant is pushed.
+    pub fn is_empty(&self) -> bool {
+        self.constants.is_empty()
+    }
+
+    /// Replace every push of `name` in `code` with `value`, a big-endian
+    /// number padded with zeros to the size of each push.
+    ///
+    /// Nothing is changed if any push can't hold `value`.
+    pub fn apply(&self, code: &mut [u8], name: &str, value: &[u8]) -> Result<(), Error> {
+        let ranges = self
+            .constants
+            .get(name)
+            .context(error::UnknownConstant { name })?;
+
+        let start = value.iter().take_while(|b| **b == 0).count();
+        let value = &value[start..];
+
+        for range in ranges {
+            ensure!(
+                range.end <= code.len(),
+                error::OutOfBounds {
+                    name,
+                    start: range.start,
+                    end: range.end,
+                    len: code.len(),
+                }
+            );
+            ensure!(
+                value.len() <= range.len(),
+                error::ValueTooLarge {
+                    name,
+                    len: value.len(),
+                    size: range.len(),
+                }
+            );
+        }
+
+        for range in ranges {
+            let (padding, immediate) = code[range.clone()].split_at_mut(range.len() - value.len());
+            padding.fill(0);
+            immediate.copy_from_slice(value);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_matches::assert_matches;
+
+    use hex_literal::hex;
+
+    #[test]
+    fn find_entries() {
+        // dup1 push4 sel eq push2 tag jumpi, then push4 sel dup2 eq push1 tag jumpi.
+        let code = hex!("8063a9059cbb1461001157 6311111111811460105700 5b00");
+        let entries = dispatch_entries(&code);
+
+        assert_eq!(
+            entries,
+            vec![
+                DispatchEntry {
+                    selector: 0xa9059cbb,
+                    offset: 8,
+                    size: 2,
+                    target: 0x11,
+                },
+                DispatchEntry {
+                    selector: 0x11111111,
+                    offset: 19,
+                    size: 1,
+                    target: 0x10,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reroute_entry() -> Result<(), Error> {
+        let code = hex!("8063a9059cbb1461000c57 00 5b00 5b00");
+        let patched = reroute(&code, 0xa9059cbb, 0x0e)?;
+        assert_eq!(patched, hex!("8063a9059cbb1461000e57 00 5b00 5b00"));
+        Ok(())
+    }
+
+    #[test]
+    fn reroute_errors() {
+        let code = hex!("8063a9059cbb1460 0c 57 00 5b00");
+
+        assert_matches!(
+            reroute(&code, 0x11111111, 0x0c),
+            Err(Error::SelectorNotFound {
+                selector: 0x11111111,
+                ..
+            })
+        );
+        assert_matches!(
+            reroute(&code, 0xa9059cbb, 0x0d),
+            Err(Error::NotJumpDest { target: 0x0d, .. })
+        );
+
+        let mut patch = Patch::new(code.to_vec());
+        patch.append(&[0; 0x100]);
+        let far = patch.append(&hex!("5b"));
+        assert_matches!(
+            patch.reroute(0xa9059cbb, far),
+            Err(Error::TargetTooLarge { size: 1, .. })
+        );
+    }
+
+    #[test]
+    fn apply_patches() -> Result<(), Error> {
+        let mut patches = Patches::new();
+        patches.insert("chain", 1..3);
+        patches.insert("chain", 4..5);
+
+        let mut code = hex!("61ffff 60ff").to_vec();
+        patches.apply(&mut code, "chain", &hex!("0001"))?;
+        assert_eq!(code, hex!("610001 6001"));
+
+        assert_matches!(
+            patches.apply(&mut code, "chain", &hex!("0100")),
+            Err(Error::ValueTooLarge {
+                len: 2,
+                size: 1,
+                ..
+            })
+        );
+        assert_eq!(code, hex!("610001 6001"));
+
+        assert_matches!(
+            patches.apply(&mut code, "owner", &[]),
+            Err(Error::UnknownConstant { .. })
+        );
+        assert_matches!(
+            patches.apply(&mut code[..4], "chain", &[]),
+            Err(Error::OutOfBounds { end: 5, len: 4, .. })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn append_after_truncated_push() {
+        let mut patch = Patch::new(hex!("00 62ffff").to_vec());
+        let position = patch.append(&hex!("5b"));
+        assert_eq!(position, 5);
+        assert_eq!(patch.code(), hex!("00 62ffff00 5b"));
+    }
+
+    #[test]
+    fn append_asm_positions_labels() -> Result<(), Error> {
+        let mut patch = Patch::new(hex!("6003565b").to_vec());
+        let position = patch.append_asm("jumpdest\npush1 here\nhere:\njumpdest\n")?;
+        assert_eq!(position, 4);
+        assert_eq!(patch.code(), hex!("6003565b 5b6007 5b"));
+        Ok(())
+    }
+}
@@ -0,0 +1,525 @@
+//! Organization-wide restrictions on the code a project may assemble.
+//!
+//! Where a [`Profile`](crate::profile::Profile) describes what a chain
+//! accepts, a [`Policy`] describes what an organization allows: instructions
+//! and sequences of instructions its contracts must not use, how large they
+//! may be, and which `%pragma`s every source must declare. Policies are read
+//! from TOML, so a security team can keep one file for every project:
+//!
+//! ```toml
+//! name = "acme"
+//! url = "https://security.acme.example/evm"
+//!
+//! [[rule]]
+//! id = "SEC-1"
+//! reason = "contracts must not be destroyable"
+//! ban_ops = ["selfdestruct", "callcode"]
+//!
+//! [[rule]]
+//! id = "SEC-2"
+//! reason = "authenticate with `caller`, not `origin`"
+//! ban_pattern = ["origin", "eq"]
+//!
+//! [[rule]]
+//! id = "SIZE-1"
+//! max_code_size = 12288
+//!
+//! [[rule]]
+//! id = "SRC-1"
+//! reason = "sources must pin the assembler version"
+//! require_pragmas = ["etk"]
+//! ```
+//!
+//! Every violation names the rule it breaks, so the error points back to the
+//! policy. The policy is checked after assembling when given to
+//! [`Ingest::set_policy`].
+//!
+//! [`Ingest::set_policy`]: crate::ingest::Ingest::set_policy
+mod error {
+    #[cfg(feature = "fs")]
+    use snafu::Backtrace;
+    use snafu::Snafu;
+
+    #[cfg(feature = "fs")]
+    use std::path::PathBuf;
+
+    /// Errors that may arise while reading a policy.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// The policy couldn't be parsed as TOML.
+        #[cfg(feature = "fs")]
+        #[snafu(display("the policy isn't valid TOML"))]
+        #[non_exhaustive]
+        Toml {
+            /// The underlying source of this error.
+            source: toml::de::Error,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A rule names an instruction that doesn't exist.
+        #[cfg(feature = "fs")]
+        #[snafu(display("rule `{}` names unknown instruction `{}`", rule, mnemonic))]
+        #[non_exhaustive]
+        UnknownOp {
+            /// The identifier of the rule.
+            rule: String,
+
+            /// The mnemonic that isn't an instruction.
+            mnemonic: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The policy file couldn't be read.
+        #[cfg(feature = "fs")]
+        #[snafu(display("couldn't read the policy `{}`", path.display()))]
+        #[non_exhaustive]
+        Io {
+            /// The underlying source of this error.
+            source: std::io::Error,
+
+            /// The path of the policy.
+            path: PathBuf,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+}
+
+use crate::disasm::Disassembler;
+use crate::parse::pragma_names;
+use crate::profile::CodeKind;
+
+pub use self::error::Error;
+
+use etk_ops::prague::{Op, Operation};
+
+#[cfg(feature = "fs")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "fs")]
+use snafu::ResultExt;
+
+use std::fmt;
+use std::io::Write;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+/// One restriction of a [`Policy`]. A rule may combine several checks, which
+/// all share its `id` and `reason`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "fs", serde(deny_unknown_fields))]
+pub struct Rule {
+    /// Identifies the rule in violations, like `SEC-1`.
+    pub id: String,
+
+    /// Why the rule exists, shown with its violations.
+    #[cfg_attr(
+        feature = "fs",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub reason: Option<String>,
+
+    /// Mnemonics of instructions that may not appear in the code.
+    #[cfg_attr(feature = "fs", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub ban_ops: Vec<String>,
+
+    /// Mnemonics of a sequence of instructions that may not appear, one
+    /// after the other, in the code.
+    #[cfg_attr(feature = "fs", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub ban_pattern: Vec<String>,
+
+    /// Maximum size of runtime code, in bytes.
+    #[cfg_attr(
+        feature = "fs",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub max_code_size: Option<usize>,
+
+    /// Maximum size of initcode, in bytes.
+    #[cfg_attr(
+        feature = "fs",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub max_initcode_size: Option<usize>,
+
+    /// Names of the `%pragma`s every source must declare, like `etk`.
+    #[cfg_attr(feature = "fs", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub require_pragmas: Vec<String>,
+}
+
+/// The restrictions an organization places on assembled code.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::policy::Policy;
+/// use etk_asm::profile::CodeKind;
+///
+/// let policy = Policy::from_toml(r#"
+///     name = "acme"
+///
+///     [[rule]]
+///     id = "SEC-1"
+///     ban_ops = ["selfdestruct"]
+/// "#).unwrap();
+///
+/// // caller; selfdestruct
+/// let violations = policy.validate(&[0x33, 0xff], CodeKind::Runtime);
+/// assert_eq!(violations[0].to_string(), "SEC-1: `selfdestruct` at 0x1 is banned");
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "fs", serde(deny_unknown_fields))]
+pub struct Policy {
+    /// The name of the policy, used in diagnostics.
+    pub name: String,
+
+    /// Where the policy is documented, used in diagnostics.
+    #[cfg_attr(
+        feature = "fs",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub url: Option<String>,
+
+    /// Every rule of the policy.
+    #[cfg_attr(
+        feature = "fs",
+        serde(default, rename = "rule", skip_serializing_if = "Vec::is_empty")
+    )]
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Read a policy from its TOML description.
+    #[cfg(feature = "fs")]
+    pub fn from_toml(text: &str) -> Result<Self, Error> {
+        let policy: Self = toml::from_str(text).context(error::Toml)?;
+
+        for rule in policy.rules.iter() {
+            for mnemonic in rule.ban_ops.iter().chain(rule.ban_pattern.iter()) {
+                if mnemonic.parse::<Op<()>>().is_err() {
+                    return error::UnknownOp {
+                        rule: rule.id.clone(),
+                        mnemonic: mnemonic.clone(),
+                    }
+                    .fail();
+                }
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Read the policy in the file at `path`.
+    #[cfg(feature = "fs")]
+    pub fn load<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).context(error::Io { path })?;
+        Self::from_toml(&text)
+    }
+
+    /// Check assembled `code` against every rule of this policy.
+    pub fn validate(&self, code: &[u8], kind: CodeKind) -> Vec<Violation> {
+        let mut dasm = Disassembler::<Op<[u8]>>::default();
+        dasm.write_all(code).unwrap();
+        let ops: Vec<_> = dasm.ops().collect();
+
+        let mut violations = Vec::new();
+
+        for rule in self.rules.iter() {
+            let max_size = match kind {
+                CodeKind::Runtime => rule.max_code_size,
+                CodeKind::Initcode => rule.max_initcode_size,
+            };
+
+            if let Some(limit) = max_size.filter(|limit| code.len() > *limit) {
+                violations.push(Violation::new(
+                    rule,
+                    Problem::CodeSize {
+                        kind,
+                        size: code.len(),
+                        limit,
+                    },
+                ));
+            }
+
+            for op in ops.iter() {
+                if rule.ban_ops.iter().any(|b| b == op.item.mnemonic()) {
+                    violations.push(Violation::new(
+                        rule,
+                        Problem::BannedOp {
+                            offset: op.offset,
+                            op: op.item.code(),
+                        },
+                    ));
+                }
+            }
+
+            if rule.ban_pattern.is_empty() {
+                continue;
+            }
+
+            for window in ops.windows(rule.ban_pattern.len()) {
+                let matches = window
+                    .iter()
+                    .zip(rule.ban_pattern.iter())
+                    .all(|(op, banned)| op.item.mnemonic() == banned);
+
+                if matches {
+                    violations.push(Violation::new(
+                        rule,
+                        Problem::BannedPattern {
+                            offset: window[0].offset,
+                            pattern: rule.ban_pattern.clone(),
+                        },
+                    ));
+                }
+            }
+        }
+
+        violations
+    }
+
+    /// Check the `%pragma`s of the source `src` against every rule of this
+    /// policy.
+    pub fn validate_source(&self, src: &str) -> Vec<Violation> {
+        let declared = pragma_names(src);
+        let mut violations = Vec::new();
+
+        for rule in self.rules.iter() {
+            for name in rule.require_pragmas.iter() {
+                if !declared.contains(name) {
+                    violations.push(Violation::new(
+                        rule,
+                        Problem::MissingPragma { name: name.clone() },
+                    ));
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+/// What's wrong with code that breaks a [`Rule`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Problem {
+    /// The code uses a banned instruction.
+    #[non_exhaustive]
+    BannedOp {
+        /// Location of the instruction in the code.
+        offset: usize,
+
+        /// The instruction.
+        op: Op<()>,
+    },
+
+    /// The code uses a banned sequence of instructions.
+    #[non_exhaustive]
+    BannedPattern {
+        /// Location of the first instruction of the sequence in the code.
+        offset: usize,
+
+        /// Mnemonics of the sequence.
+        pattern: Vec<String>,
+    },
+
+    /// The code is larger than allowed.
+    #[non_exhaustive]
+    CodeSize {
+        /// Whether the code is runtime code or initcode.
+        kind: CodeKind,
+
+        /// The size of the code, in bytes.
+        size: usize,
+
+        /// The maximum size, in bytes.
+        limit: usize,
+    },
+
+    /// The source doesn't declare a required `%pragma`.
+    #[non_exhaustive]
+    MissingPragma {
+        /// The name of the pragma.
+        name: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::BannedOp { offset, op } => {
+                write!(f, "`{}` at 0x{:x} is banned", op, offset)
+            }
+            Self::BannedPattern { offset, pattern } => {
+                write!(f, "`{}` at 0x{:x} is banned", pattern.join("; "), offset)
+            }
+            Self::CodeSize { kind, size, limit } => write!(
+                f,
+                "{} is {} bytes, more than the limit of {}",
+                kind, size, limit
+            ),
+            Self::MissingPragma { name } => {
+                write!(f, "the source must declare `%pragma {}`", name)
+            }
+        }
+    }
+}
+
+/// A [`Rule`] of a [`Policy`] that code breaks.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Violation {
+    /// The identifier of the rule.
+    pub rule: String,
+
+    /// Why the rule exists, if the policy says.
+    pub reason: Option<String>,
+
+    /// What's wrong with the code.
+    pub problem: Problem,
+}
+
+impl Violation {
+    fn new(rule: &Rule, problem: Problem) -> Self {
+        Self {
+            rule: rule.id.clone(),
+            reason: rule.reason.clone(),
+            problem,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.rule, self.problem)?;
+        if let Some(ref reason) = self.reason {
+            write!(f, " ({})", reason)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    const POLICY: &str = r#"
+        name = "acme"
+        url = "https://security.acme.example/evm"
+
+        [[rule]]
+        id = "SEC-1"
+        reason = "contracts must not be destroyable"
+        ban_ops = ["selfdestruct"]
+
+        [[rule]]
+        id = "SEC-2"
+        ban_pattern = ["origin", "eq"]
+
+        [[rule]]
+        id = "SIZE-1"
+        max_code_size = 4
+
+        [[rule]]
+        id = "SRC-1"
+        require_pragmas = ["etk"]
+    "#;
+
+    #[test]
+    fn validate_code() {
+        let policy = Policy::from_toml(POLICY).unwrap();
+        assert_eq!(
+            policy.url.as_deref(),
+            Some("https://security.acme.example/evm")
+        );
+
+        // caller; origin; eq; pc; origin; selfdestruct
+        let violations = policy.validate(&hex!("3332145832ff"), CodeKind::Runtime);
+        assert_eq!(
+            violations,
+            [
+                Violation {
+                    rule: "SEC-1".into(),
+                    reason: Some("contracts must not be destroyable".into()),
+                    problem: Problem::BannedOp {
+                        offset: 5,
+                        op: "selfdestruct".parse().unwrap(),
+                    },
+                },
+                Violation {
+                    rule: "SEC-2".into(),
+                    reason: None,
+                    problem: Problem::BannedPattern {
+                        offset: 1,
+                        pattern: vec!["origin".into(), "eq".into()],
+                    },
+                },
+                Violation {
+                    rule: "SIZE-1".into(),
+                    reason: None,
+                    problem: Problem::CodeSize {
+                        kind: CodeKind::Runtime,
+                        size: 6,
+                        limit: 4,
+                    },
+                },
+            ]
+        );
+
+        assert_eq!(
+            violations[0].to_string(),
+            "SEC-1: `selfdestruct` at 0x5 is banned (contracts must not be destroyable)"
+        );
+        assert_eq!(
+            violations[1].to_string(),
+            "SEC-2: `origin; eq` at 0x1 is banned"
+        );
+
+        assert!(policy.validate(&hex!("3314"), CodeKind::Runtime).is_empty());
+        assert!(policy
+            .validate(&hex!("3333333333"), CodeKind::Initcode)
+            .is_empty());
+    }
+
+    #[test]
+    fn validate_source() {
+        let policy = Policy::from_toml(POLICY).unwrap();
+
+        let violations = policy.validate_source("push1 1\n");
+        assert_eq!(violations.len(), 1);
+        assert_eq!(
+            violations[0].to_string(),
+            "SRC-1: the source must declare `%pragma etk`"
+        );
+
+        assert!(policy
+            .validate_source("%pragma etk \">=0.1\"\npush1 1\n")
+            .is_empty());
+    }
+
+    #[test]
+    fn invalid_policy() {
+        let err = Policy::from_toml("name = \"x\"\n[[rule]]\nid = \"A\"\nban_ops = [\"nope\"]\n")
+            .unwrap_err();
+        assert_matches!(err, Error::UnknownOp { rule, mnemonic, .. } if rule == "A" && mnemonic == "nope");
+
+        let err = Policy::from_toml("name = \"x\"\nmax_gas = 1\n").unwrap_err();
+        assert_matches!(err, Error::Toml { .. });
+    }
+}
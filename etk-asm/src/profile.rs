@@ -0,0 +1,549 @@
+//! Structural limits of the chains code is deployed to.
+//!
+//! A [`Profile`] describes what a chain accepts: how large code may be, how
+//! many instructions it may contain, how deep the stack may grow, and which
+//! instructions are unavailable. Profiles are plain data, read from JSON (with
+//! the `fs` feature), so supporting a new chain only needs a new profile:
+//!
+//! ```json
+//! {
+//!   "name": "example",
+//!   "max_code_size": 24576,
+//!   "max_initcode_size": 49152,
+//!   "max_instructions": 8192,
+//!   "max_stack": 1024,
+//!   "banned_ops": ["selfdestruct"]
+//! }
+//! ```
+//!
+//! Limits that are left out aren't checked. [`Profile::builtin`] returns the
+//! profiles that ship with the assembler.
+//!
+//! The instructions of EOF, like `rjump` and `dupn`, are only available to
+//! profiles that set `"eof": true`, like the built-in `eof` profile, which
+//! bans the legacy instructions EOF containers can't use instead.
+//!
+//! [`Profile::validate`] checks code against a profile. The same check runs
+//! after assembling when a profile is given to [`Ingest::set_profile`].
+//!
+//! [`Ingest::set_profile`]: crate::ingest::Ingest::set_profile
+mod error {
+    use snafu::{Backtrace, Snafu};
+
+    #[cfg(feature = "fs")]
+    use std::path::PathBuf;
+
+    /// Errors that may arise while reading a profile.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// The profile couldn't be parsed as JSON.
+        #[cfg(feature = "fs")]
+        #[snafu(display("the profile isn't valid JSON"))]
+        #[non_exhaustive]
+        Json {
+            /// The underlying source of this error.
+            source: serde_json::Error,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The profile bans an instruction that doesn't exist.
+        #[snafu(display("profile `{}` bans unknown instruction `{}`", profile, mnemonic))]
+        #[non_exhaustive]
+        UnknownOp {
+            /// The name of the profile.
+            profile: String,
+
+            /// The mnemonic that isn't an instruction.
+            mnemonic: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// The profile isn't built in, and couldn't be read from a file.
+        #[cfg(feature = "fs")]
+        #[snafu(display(
+            "`{}` isn't a built-in profile, and couldn't be read as a file",
+            path.display()
+        ))]
+        #[non_exhaustive]
+        Io {
+            /// The underlying source of this error.
+            source: std::io::Error,
+
+            /// The name or path of the profile.
+            path: PathBuf,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+}
+
+use crate::analysis::check_stack_limit;
+use crate::disasm::Disassembler;
+use crate::ops::is_eof;
+use crate::warning::Warning;
+
+pub use self::error::Error;
+
+use etk_ops::prague::{Op, Operation};
+
+#[cfg(feature = "fs")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "fs")]
+use snafu::ResultExt;
+
+use std::fmt;
+use std::io::Write;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+/// Instructions of legacy code that EOF containers can't use.
+const LEGACY_ONLY: &[&str] = &[
+    "jump",
+    "jumpi",
+    "pc",
+    "codesize",
+    "codecopy",
+    "extcodesize",
+    "extcodecopy",
+    "extcodehash",
+    "gas",
+    "create",
+    "create2",
+    "call",
+    "callcode",
+    "delegatecall",
+    "staticcall",
+    "selfdestruct",
+];
+
+/// Instructions introduced by Cancun.
+const CANCUN: &[&str] = &["tload", "tstore", "mcopy", "blobhash", "blobbasefee"];
+
+/// Instructions introduced by Shanghai.
+const SHANGHAI: &[&str] = &["push0"];
+
+/// What the code being validated is used for.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CodeKind {
+    /// Code stored in an account, and run when the account is called.
+    Runtime,
+
+    /// Code run once to create a contract, returning the runtime code.
+    Initcode,
+}
+
+impl Default for CodeKind {
+    fn default() -> Self {
+        Self::Runtime
+    }
+}
+
+impl fmt::Display for CodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Runtime => write!(f, "runtime code"),
+            Self::Initcode => write!(f, "initcode"),
+        }
+    }
+}
+
+/// The structural limits of a chain.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::profile::{CodeKind, Profile, Violation};
+///
+/// let london = Profile::builtin("london").unwrap();
+///
+/// // push0; stop
+/// let violations = london.validate(&[0x5f, 0x00], CodeKind::Runtime);
+/// assert_eq!(violations.len(), 1);
+/// assert_eq!(violations[0].to_string(), "`push0` at 0x0 isn't available");
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "fs", serde(deny_unknown_fields))]
+pub struct Profile {
+    /// The name of the profile, used in diagnostics.
+    pub name: String,
+
+    /// Maximum size of runtime code, in bytes.
+    #[cfg_attr(
+        feature = "fs",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub max_code_size: Option<usize>,
+
+    /// Maximum size of initcode, in bytes.
+    #[cfg_attr(
+        feature = "fs",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub max_initcode_size: Option<usize>,
+
+    /// Maximum number of instructions in the code.
+    #[cfg_attr(
+        feature = "fs",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub max_instructions: Option<usize>,
+
+    /// Maximum number of items on the stack.
+    #[cfg_attr(
+        feature = "fs",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub max_stack: Option<usize>,
+
+    /// Mnemonics of the instructions that aren't available.
+    #[cfg_attr(feature = "fs", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub banned_ops: Vec<String>,
+
+    /// Whether the code is in an EOF container. The instructions of EOF, like
+    /// `rjump` and `dupn`, are only available in EOF containers.
+    #[cfg_attr(feature = "fs", serde(default))]
+    pub eof: bool,
+}
+
+impl Profile {
+    /// The profiles that ship with the assembler.
+    pub fn builtins() -> Vec<Self> {
+        let fork = |name: &str, max_initcode_size, banned: &[&[&str]]| Self {
+            name: name.into(),
+            max_code_size: Some(24576),
+            max_initcode_size,
+            max_instructions: None,
+            max_stack: Some(1024),
+            banned_ops: banned
+                .iter()
+                .flat_map(|ops| ops.iter())
+                .map(|op| op.to_string())
+                .collect(),
+            eof: false,
+        };
+
+        vec![
+            fork("prague", Some(49152), &[]),
+            fork("cancun", Some(49152), &[]),
+            fork("shanghai", Some(49152), &[CANCUN]),
+            fork("london", None, &[CANCUN, SHANGHAI]),
+            Self {
+                eof: true,
+                ..fork("eof", Some(49152), &[LEGACY_ONLY])
+            },
+        ]
+    }
+
+    /// The built-in profile called `name`, if there is one.
+    pub fn builtin(name: &str) -> Option<Self> {
+        Self::builtins().into_iter().find(|p| p.name == name)
+    }
+
+    /// Read a profile from its JSON description.
+    #[cfg(feature = "fs")]
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let profile: Self = serde_json::from_str(json).context(error::Json)?;
+
+        for mnemonic in profile.banned_ops.iter() {
+            if mnemonic.parse::<Op<()>>().is_err() {
+                return error::UnknownOp {
+                    profile: profile.name.clone(),
+                    mnemonic: mnemonic.clone(),
+                }
+                .fail();
+            }
+        }
+
+        Ok(profile)
+    }
+
+    /// The built-in profile called `name_or_path`, or else the profile in the
+    /// file at that path.
+    #[cfg(feature = "fs")]
+    pub fn load(name_or_path: &str) -> Result<Self, Error> {
+        if let Some(profile) = Self::builtin(name_or_path) {
+            return Ok(profile);
+        }
+
+        let path = Path::new(name_or_path);
+        let json = std::fs::read_to_string(path).context(error::Io { path })?;
+        Self::from_json(&json)
+    }
+
+    /// Whether the instruction `mnemonic` is available, which is the value of
+    /// the capability flag `has_<mnemonic>` in `%if` conditions.
+    pub fn has(&self, mnemonic: &str) -> bool {
+        let eof = mnemonic.parse().map_or(false, |op| is_eof(&op));
+        (self.eof || !eof) && !self.banned_ops.iter().any(|b| b == mnemonic)
+    }
+
+    /// Check `code` against every limit of this profile.
+    pub fn validate(&self, code: &[u8], kind: CodeKind) -> Vec<Violation> {
+        let mut violations = Vec::new();
+
+        let max_size = match kind {
+            CodeKind::Runtime => self.max_code_size,
+            CodeKind::Initcode => self.max_initcode_size,
+        };
+
+        if let Some(limit) = max_size.filter(|limit| code.len() > *limit) {
+            violations.push(Violation::CodeSize {
+                kind,
+                size: code.len(),
+                limit,
+            });
+        }
+
+        let mut dasm = Disassembler::<Op<[u8]>>::default();
+        dasm.write_all(code).unwrap();
+        let ops: Vec<_> = dasm.ops().collect();
+
+        if let Some(limit) = self.max_instructions.filter(|limit| ops.len() > *limit) {
+            violations.push(Violation::InstructionCount {
+                count: ops.len(),
+                limit,
+            });
+        }
+
+        for op in ops.iter() {
+            if !self.has(op.item.mnemonic()) {
+                violations.push(Violation::BannedOp {
+                    offset: op.offset,
+                    op: op.item.code(),
+                });
+            }
+        }
+
+        if let Some(limit) = self.max_stack {
+            for warning in check_stack_limit(&ops, limit) {
+                if let Warning::StackOverflow { offset, op } = warning {
+                    violations.push(Violation::StackOverflow { offset, op, limit });
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+/// A limit of a [`Profile`] that code exceeds.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Violation {
+    /// The code is larger than allowed.
+    #[non_exhaustive]
+    CodeSize {
+        /// Whether the code is runtime code or initcode.
+        kind: CodeKind,
+
+        /// The size of the code, in bytes.
+        size: usize,
+
+        /// The maximum size, in bytes.
+        limit: usize,
+    },
+
+    /// The code contains more instructions than allowed.
+    #[non_exhaustive]
+    InstructionCount {
+        /// The number of instructions in the code.
+        count: usize,
+
+        /// The maximum number of instructions.
+        limit: usize,
+    },
+
+    /// The code uses an instruction that isn't available.
+    #[non_exhaustive]
+    BannedOp {
+        /// Location of the instruction in the code.
+        offset: usize,
+
+        /// The instruction.
+        op: Op<()>,
+    },
+
+    /// An instruction grows the stack past its limit on some path.
+    #[non_exhaustive]
+    StackOverflow {
+        /// Location of the instruction in the code.
+        offset: usize,
+
+        /// The instruction.
+        op: Op<()>,
+
+        /// The maximum number of items on the stack.
+        limit: usize,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::CodeSize { kind, size, limit } => write!(
+                f,
+                "{} is {} bytes, more than the limit of {}",
+                kind, size, limit
+            ),
+            Self::InstructionCount { count, limit } => write!(
+                f,
+                "code has {} instructions, more than the limit of {}",
+                count, limit
+            ),
+            Self::BannedOp { offset, op } => {
+                write!(f, "`{}` at 0x{:x} isn't available", op, offset)
+            }
+            Self::StackOverflow { offset, op, limit } => write!(
+                f,
+                "`{}` at 0x{:x} may grow the stack past {} items",
+                op, offset, limit
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "fs")]
+    use assert_matches::assert_matches;
+
+    #[cfg(feature = "fs")]
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn builtins_ban_known_ops() {
+        for profile in Profile::builtins() {
+            for mnemonic in profile.banned_ops.iter() {
+                assert!(mnemonic.parse::<Op<()>>().is_ok(), "{}", mnemonic);
+            }
+        }
+
+        let london = Profile::builtin("london").unwrap();
+        assert_eq!(london.max_initcode_size, None);
+        assert!(!london.has("push0"));
+        assert!(!Profile::builtin("prague").unwrap().has("rjumpv"));
+
+        let eof = Profile::builtin("eof").unwrap();
+        assert!(eof.has("rjumpv"));
+        assert!(!eof.has("jump"));
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn builtins_are_valid() {
+        for profile in Profile::builtins() {
+            let json = serde_json::to_string(&profile).unwrap();
+            assert_eq!(Profile::from_json(&json).unwrap(), profile);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn validate_sizes() {
+        let profile = Profile::from_json(
+            r#"{"name": "tiny", "max_code_size": 2, "max_initcode_size": 3, "max_instructions": 2}"#,
+        )
+        .unwrap();
+
+        let code = hex!("585858");
+        assert_eq!(
+            profile.validate(&code, CodeKind::Initcode),
+            [Violation::InstructionCount { count: 3, limit: 2 }]
+        );
+
+        let violations = profile.validate(&code, CodeKind::Runtime);
+        assert_eq!(
+            violations,
+            [
+                Violation::CodeSize {
+                    kind: CodeKind::Runtime,
+                    size: 3,
+                    limit: 2
+                },
+                Violation::InstructionCount { count: 3, limit: 2 },
+            ]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            "runtime code is 3 bytes, more than the limit of 2"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn validate_stack() {
+        let profile = Profile::from_json(r#"{"name": "shallow", "max_stack": 2}"#).unwrap();
+
+        // pc; pc; pc
+        let violations = profile.validate(&hex!("585858"), CodeKind::Runtime);
+        assert_matches!(
+            violations.as_slice(),
+            [Violation::StackOverflow {
+                offset: 2,
+                limit: 2,
+                ..
+            }]
+        );
+
+        assert!(profile
+            .validate(&hex!("5858"), CodeKind::Runtime)
+            .is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn validate_eof() {
+        // rjump 0; stop
+        let code = hex!("e0000000");
+
+        let prague = Profile::builtin("prague").unwrap();
+        assert_eq!(
+            prague.validate(&code, CodeKind::Runtime),
+            [Violation::BannedOp {
+                offset: 0,
+                op: "rjump".parse().unwrap(),
+            }]
+        );
+
+        let eof = Profile::builtin("eof").unwrap();
+        assert!(eof.validate(&code, CodeKind::Runtime).is_empty());
+
+        let profile = Profile::from_json(r#"{"name": "x", "eof": true}"#).unwrap();
+        assert!(profile.has("dupn"));
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn unknown_op() {
+        let err = Profile::from_json(r#"{"name": "x", "banned_ops": ["nope"]}"#).unwrap_err();
+        assert_matches!(err, Error::UnknownOp { mnemonic, .. } if mnemonic == "nope");
+
+        let err = Profile::from_json(r#"{"name": "x", "max_gas": 1}"#).unwrap_err();
+        assert_matches!(err, Error::Json { .. });
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn load() {
+        assert_eq!(Profile::load("cancun").unwrap().name, "cancun");
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, r#"{{"name": "custom", "max_code_size": 1}}"#).unwrap();
+        let profile = Profile::load(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(profile.max_code_size, Some(1));
+
+        let err = Profile::load("no-such-profile").unwrap_err();
+        assert_matches!(err, Error::Io { .. });
+    }
+}
@@ -0,0 +1,368 @@
+//! Finding which contracts changed between two versions of a project.
+//!
+//! Deploy pipelines only need to redeploy contracts whose code changed.
+//! [`compare`] takes the contracts built from two versions of a project, and
+//! reports which of them changed, were added, or were removed. Within each
+//! contract, every `%section` (or `%scope`) is compared on its own, so a
+//! report can point at the parts that changed.
+//!
+//! Code is [normalized](normalize) before it's compared, so differences that
+//! don't change behaviour (like the metadata solc appends to contracts
+//! included with `%include_sol_runtime`) don't cause a redeploy.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_asm::redeploy::{compare, Contract, Status};
+//! # use etk_asm::ingest::Error;
+//!
+//! let before = [
+//!     Contract::assemble("token", "push1 1\nstop\n")?,
+//!     Contract::assemble("vault", "push1 2\nstop\n")?,
+//! ];
+//! let after = [
+//!     Contract::assemble("token", "push1 1\nstop\n")?,
+//!     Contract::assemble("vault", "push1 3\nstop\n")?,
+//! ];
+//!
+//! let report = compare(&before, &after);
+//! assert_eq!(report.contracts[0].status, Status::Unchanged);
+//! assert_eq!(report.redeploy().collect::<Vec<_>>(), ["vault"]);
+//! # Result::<(), Error>::Ok(())
+//! ```
+use crate::debug::Symbol;
+use crate::disasm;
+use crate::ingest::{Error, Ingest};
+use crate::vfs::MemoryFiles;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The code of one contract of a project, with the positions of its labels.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Contract {
+    /// The name identifying the contract in both versions, like the path of
+    /// its entry file.
+    pub name: String,
+
+    /// The assembled code.
+    pub code: Vec<u8>,
+
+    /// The labels of the code, used to find its sections. See
+    /// [`DebugInfo::labels`](crate::debug::DebugInfo::labels).
+    pub symbols: Vec<Symbol>,
+}
+
+impl Contract {
+    /// A contract with `code`, and no known sections.
+    pub fn new<S>(name: S, code: Vec<u8>) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            name: name.into(),
+            code,
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Assemble the contract from `src`, without touching the filesystem,
+    /// keeping the positions of its labels.
+    pub fn assemble<S>(name: S, src: &str) -> Result<Self, Error>
+    where
+        S: Into<String>,
+    {
+        let name = name.into();
+        let mut code = Vec::new();
+        let mut ingest = Ingest::new(&mut code);
+        ingest.set_file_provider(MemoryFiles::new());
+        ingest.ingest(format!("/{}.etk", name), src)?;
+        let symbols = ingest.debug_info().labels.clone();
+        drop(ingest);
+
+        Ok(Self {
+            name,
+            code,
+            symbols,
+        })
+    }
+
+    /// Assemble the contract from the file at `path`, which also names it.
+    pub fn assemble_file<P>(path: P) -> Result<Self, Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        let mut code = Vec::new();
+        let mut ingest = Ingest::new(&mut code);
+        ingest.ingest_file(path.clone())?;
+        let symbols = ingest.debug_info().labels.clone();
+        drop(ingest);
+
+        Ok(Self {
+            name: path.display().to_string(),
+            code,
+            symbols,
+        })
+    }
+
+    /// The sections of the contract, by name, with their range of bytes.
+    ///
+    /// Sections are found from their `name.start` and `name.end` labels.
+    fn sections(&self) -> BTreeMap<&str, (usize, usize)> {
+        let offsets: BTreeMap<&str, usize> = self
+            .symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.offset))
+            .collect();
+
+        offsets
+            .iter()
+            .filter_map(|(name, start)| {
+                let section = name.strip_suffix(".start")?;
+                let end = offsets.get(format!("{}.end", section).as_str())?;
+                Some((section, (*start, *end)))
+            })
+            .filter(|(_, (start, end))| start <= end && *end <= self.code.len())
+            .collect()
+    }
+}
+
+/// Whether something changed between the two versions.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Status {
+    /// Only in the new version.
+    Added,
+
+    /// Only in the old version.
+    Removed,
+
+    /// In both versions, with different code.
+    Changed,
+
+    /// In both versions, with the same code.
+    Unchanged,
+}
+
+/// How a section of a contract changed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SectionChange {
+    /// The name of the section, like `lib.inner` for nested sections.
+    pub name: String,
+
+    /// Whether the code of the section changed.
+    pub status: Status,
+
+    /// Whether the section starts at a different position. Moved sections
+    /// can be unchanged, when only the code before them changed.
+    pub moved: bool,
+}
+
+/// How a contract changed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ContractChange {
+    /// The name of the contract.
+    pub name: String,
+
+    /// Whether the code of the contract changed, after normalizing it.
+    pub status: Status,
+
+    /// How each section changed, ordered by name. Empty if the sections of
+    /// the contract aren't known.
+    pub sections: Vec<SectionChange>,
+}
+
+/// The changes between two versions of a project. See [`compare`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Report {
+    /// Every contract of either version: first those of the old version, in
+    /// order, then those added by the new one.
+    pub contracts: Vec<ContractChange>,
+}
+
+impl Report {
+    /// Names of the contracts that need to be deployed: those that were
+    /// added or changed.
+    pub fn redeploy(&self) -> impl Iterator<Item = &str> {
+        self.contracts
+            .iter()
+            .filter(|c| matches!(c.status, Status::Added | Status::Changed))
+            .map(|c| c.name.as_str())
+    }
+}
+
+/// Remove the parts of `code` that don't affect its behaviour: the CBOR
+/// metadata solc appends. See [`disasm::metadata_start`].
+pub fn normalize(code: &[u8]) -> &[u8] {
+    match disasm::metadata_start(code) {
+        Some(start) => &code[..start],
+        None => code,
+    }
+}
+
+fn status(before: &[u8], after: &[u8]) -> Status {
+    if before == after {
+        Status::Unchanged
+    } else {
+        Status::Changed
+    }
+}
+
+fn compare_contract(before: &Contract, after: &Contract) -> ContractChange {
+    let old = before.sections();
+    let new = after.sections();
+
+    let mut sections = Vec::new();
+    for (name, (start, end)) in &old {
+        let change = match new.get(name) {
+            Some((new_start, new_end)) => SectionChange {
+                name: (*name).to_owned(),
+                status: status(
+                    &before.code[*start..*end],
+                    &after.code[*new_start..*new_end],
+                ),
+                moved: start != new_start,
+            },
+            None => SectionChange {
+                name: (*name).to_owned(),
+                status: Status::Removed,
+                moved: false,
+            },
+        };
+        sections.push(change);
+    }
+
+    for name in new.keys().filter(|name| !old.contains_key(*name)) {
+        sections.push(SectionChange {
+            name: (*name).to_owned(),
+            status: Status::Added,
+            moved: false,
+        });
+    }
+
+    sections.sort_by(|a, b| a.name.cmp(&b.name));
+
+    ContractChange {
+        name: before.name.clone(),
+        status: status(normalize(&before.code), normalize(&after.code)),
+        sections,
+    }
+}
+
+/// Compare the contracts of two versions of a project, matching them by
+/// name.
+pub fn compare(before: &[Contract], after: &[Contract]) -> Report {
+    let mut contracts = Vec::new();
+
+    for old in before {
+        let change = match after.iter().find(|c| c.name == old.name) {
+            Some(new) => compare_contract(old, new),
+            None => ContractChange {
+                name: old.name.clone(),
+                status: Status::Removed,
+                sections: Vec::new(),
+            },
+        };
+        contracts.push(change);
+    }
+
+    for new in after {
+        if before.iter().any(|c| c.name == new.name) {
+            continue;
+        }
+
+        contracts.push(ContractChange {
+            name: new.name.clone(),
+            status: Status::Added,
+            sections: Vec::new(),
+        });
+    }
+
+    Report { contracts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hex_literal::hex;
+
+    #[test]
+    fn normalize_strips_metadata() {
+        let mut code = hex!("6080604052").to_vec();
+        code.extend(hex!("a164736f6c6343000814"));
+        code.extend(hex!("000a"));
+        assert_eq!(normalize(&code), hex!("6080604052"));
+
+        // Without the solc version, trailing bytes are kept.
+        let code = hex!("6080 a1 00 0002");
+        assert_eq!(normalize(&code), code);
+
+        assert_eq!(normalize(&hex!("00")), hex!("00"));
+    }
+
+    #[test]
+    fn compare_sections() -> Result<(), Error> {
+        let before = Contract::assemble(
+            "main",
+            r#"
+            push1 1
+            %section a { push1 2 }
+            %section b { push1 3 }
+            %section c { stop }
+            "#,
+        )?;
+        let after = Contract::assemble(
+            "main",
+            r#"
+            push2 1
+            %section a { push1 2 }
+            %section b { push1 4 }
+            %section d { stop }
+            "#,
+        )?;
+
+        let report = compare(&[before], &[after]);
+        let change = &report.contracts[0];
+        assert_eq!(change.status, Status::Changed);
+
+        let sections: Vec<_> = change
+            .sections
+            .iter()
+            .map(|s| (s.name.as_str(), s.status, s.moved))
+            .collect();
+        assert_eq!(
+            sections,
+            [
+                ("a", Status::Unchanged, true),
+                ("b", Status::Changed, true),
+                ("c", Status::Removed, false),
+                ("d", Status::Added, false),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_contracts() {
+        let before = [Contract::new("a", vec![0]), Contract::new("b", vec![0])];
+        let after = [Contract::new("b", vec![1]), Contract::new("c", vec![0])];
+
+        let report = compare(&before, &after);
+        let statuses: Vec<_> = report
+            .contracts
+            .iter()
+            .map(|c| (c.name.as_str(), c.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("a", Status::Removed),
+                ("b", Status::Changed),
+                ("c", Status::Added),
+            ]
+        );
+        assert_eq!(report.redeploy().collect::<Vec<_>>(), ["b", "c"]);
+    }
+}
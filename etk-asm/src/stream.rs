@@ -0,0 +1,540 @@
+//! Assembling programs as their instructions arrive.
+//!
+//! A [`Stream`] wraps an iterator of [`AbstractOp`] and yields the assembled
+//! code in chunks, each as soon as every label its instructions refer to has
+//! a position. Only the instructions waiting on a forward reference, along
+//! with the positions of labels and the macro definitions seen so far, are
+//! kept in memory, so generated programs can be assembled straight into a
+//! file or socket with [`std::io::copy`].
+//!
+//! Chunks concatenate to the code [`Assembler::assemble`] produces for the
+//! same instructions. Warnings aren't reported, since the `Assembler` can only
+//! find most of them once it has seen the whole program.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_asm::ops::{AbstractOp, Imm};
+//! use etk_asm::stream::{Error, Stream};
+//! use etk_ops::cancun::{Jump, JumpDest, Push1};
+//!
+//! let ops = vec![
+//!     AbstractOp::new(JumpDest),
+//!     AbstractOp::new(Push1(Imm::with_label("end"))),
+//!     AbstractOp::new(Jump),
+//!     AbstractOp::Label("end".into()),
+//!     AbstractOp::new(JumpDest),
+//! ];
+//!
+//! let chunks: Vec<_> = Stream::new(ops).collect::<Result<_, Error>>()?;
+//!
+//! // The push waits for `end`, so it comes out together with the jump.
+//! assert_eq!(chunks, [vec![0x5b], vec![0x60, 0x04, 0x56], vec![0x5b]]);
+//! # Result::<(), Error>::Ok(())
+//! ```
+mod error {
+    use crate::asm::Error as AssembleError;
+
+    use snafu::{Backtrace, Snafu};
+
+    /// Errors that may arise while streaming a program.
+    #[derive(Debug, Snafu)]
+    #[snafu(context(suffix(false)), visibility(pub(super)))]
+    #[non_exhaustive]
+    pub enum Error {
+        /// Assembling a chunk of the program failed.
+        #[snafu(display("assembling failed"))]
+        #[non_exhaustive]
+        Assemble {
+            /// The underlying source of this error.
+            #[snafu(backtrace)]
+            source: AssembleError,
+        },
+
+        /// A label was declared again after the code declaring it was
+        /// yielded.
+        #[snafu(display("label `{}` declared multiple times", label))]
+        #[non_exhaustive]
+        DuplicateLabel {
+            /// The name of the conflicting label.
+            label: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+
+        /// A macro was defined more than once.
+        #[snafu(display("macro `{}` defined multiple times", name))]
+        #[non_exhaustive]
+        DuplicateMacro {
+            /// The name of the conflicting macro.
+            name: String,
+
+            /// The location of the error.
+            backtrace: Backtrace,
+        },
+    }
+}
+
+pub use self::error::Error;
+
+use crate::asm::Assembler;
+use crate::ops::{AbstractOp, CallKind, InstructionMacroDefinition, MacroDefinition};
+use crate::profile::Profile;
+
+use snafu::{ensure, ResultExt};
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+
+/// An iterator over the assembled code of a program, in chunks.
+///
+/// Also implements [`Read`], for copying the code into a writer.
+///
+/// See the [module-level documentation](self) for details.
+#[derive(Debug)]
+pub struct Stream<I> {
+    ops: I,
+
+    /// Instructions waiting to be assembled.
+    pending: Vec<AbstractOp>,
+
+    /// Labels declared in `pending`.
+    declared: HashSet<String>,
+
+    /// Labels `pending` refers to that haven't been declared yet.
+    unresolved: HashSet<String>,
+
+    /// Indices into `pending` of macro invocations whose labels can't be
+    /// known until more macros are defined.
+    unknown: Vec<usize>,
+
+    /// Positions of the labels in the code already yielded.
+    labels: HashMap<String, usize>,
+
+    /// Every macro defined so far.
+    macros: HashMap<String, MacroDefinition>,
+
+    /// Number of bytes yielded so far.
+    position: usize,
+
+    /// Code yielded by the iterator but not read yet.
+    unread: io::Cursor<Vec<u8>>,
+
+    /// Whether the stream has ended, either at the end of the program or
+    /// after an error.
+    done: bool,
+
+    /// The chain the code is assembled for.
+    target: Option<Profile>,
+}
+
+impl<I> Stream<I>
+where
+    I: Iterator<Item = AbstractOp>,
+{
+    /// Assemble the instructions of `ops`, as they are needed.
+    pub fn new<T>(ops: T) -> Self
+    where
+        T: IntoIterator<Item = AbstractOp, IntoIter = I>,
+    {
+        Self {
+            ops: ops.into_iter(),
+            pending: Vec::new(),
+            declared: HashSet::new(),
+            unresolved: HashSet::new(),
+            unknown: Vec::new(),
+            labels: HashMap::new(),
+            macros: HashMap::new(),
+            position: 0,
+            unread: io::Cursor::new(Vec::new()),
+            done: false,
+            target: None,
+        }
+    }
+
+    /// Assemble for the chain described by `profile`, like
+    /// [`Assembler::set_target`].
+    pub fn set_target(&mut self, profile: Option<Profile>) {
+        self.target = profile;
+    }
+
+    /// Number of bytes yielded so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// The position of `label`, if the code declaring it was yielded.
+    pub fn label(&self, label: &str) -> Option<usize> {
+        self.labels.get(label).copied()
+    }
+
+    fn add(&mut self, op: AbstractOp) -> Result<(), Error> {
+        match op {
+            AbstractOp::MacroDefinition(defn) => {
+                let name = defn.name().to_owned();
+                ensure!(
+                    !self.macros.contains_key(&name),
+                    error::DuplicateMacro { name }
+                );
+                self.macros.insert(name, defn);
+
+                // The new macro may be the one an invocation was waiting on.
+                let unknown = std::mem::take(&mut self.unknown);
+                for index in unknown {
+                    self.refer(index);
+                }
+
+                return Ok(());
+            }
+            AbstractOp::Label(ref label) => {
+                ensure!(
+                    !self.labels.contains_key(label),
+                    error::DuplicateLabel {
+                        label: label.to_owned()
+                    }
+                );
+                self.unresolved.remove(label);
+                self.declared.insert(label.to_owned());
+            }
+            _ => (),
+        }
+
+        self.pending.push(op);
+        self.refer(self.pending.len() - 1);
+        Ok(())
+    }
+
+    /// Record the labels the instruction at `index` into `pending` refers to.
+    fn refer(&mut self, index: usize) {
+        let labels = match self.uses(&self.pending[index], &mut Vec::new()) {
+            Some(l) => l,
+            None => {
+                self.unknown.push(index);
+                return;
+            }
+        };
+
+        for label in labels {
+            if !self.labels.contains_key(&label) && !self.declared.contains(&label) {
+                self.unresolved.insert(label);
+            }
+        }
+    }
+
+    /// Labels `op` refers to, or `None` if they depend on a macro that isn't
+    /// defined yet.
+    fn uses(&self, op: &AbstractOp, stack: &mut Vec<String>) -> Option<Vec<String>> {
+        let invocation = match op {
+            AbstractOp::Macro(m) => m,
+            _ => {
+                return op
+                    .expr()
+                    .map_or(Some(Vec::new()), |e| e.labels(&self.macros).ok())
+            }
+        };
+
+        let mut labels = Vec::new();
+        for parameter in invocation.parameters.iter() {
+            labels.extend(parameter.labels(&self.macros).ok()?);
+        }
+
+        let name = &invocation.name;
+        let defn = match self.macros.get(name) {
+            Some(MacroDefinition::Instruction(defn)) => Cow::Borrowed(defn),
+            Some(MacroDefinition::Expression(_)) => return None,
+            None if name == "dispatch" || CallKind::from_trampoline(name).is_some() => {
+                // Generated code only refers to its arguments and its own
+                // labels.
+                return Some(labels);
+            }
+            None => Cow::Owned(InstructionMacroDefinition::builtin(name)?),
+        };
+
+        // Leave recursion for the `Assembler` to report.
+        if stack.contains(name) {
+            return None;
+        }
+
+        for default in defn.defaults.iter() {
+            labels.extend(default.labels(&self.macros).ok()?);
+        }
+
+        stack.push(name.to_owned());
+        let mut body = Vec::new();
+        for op in defn.contents.iter() {
+            body.extend(self.uses(op, stack)?);
+        }
+        stack.pop();
+
+        // Labels declared in the body are local to each expansion.
+        let local: HashSet<_> = defn
+            .contents
+            .iter()
+            .filter_map(|op| match op {
+                AbstractOp::Label(label) => Some(label.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        labels.extend(body.into_iter().filter(|l| !local.contains(l.as_str())));
+        Some(labels)
+    }
+
+    fn closed(&self) -> bool {
+        self.unresolved.is_empty() && self.unknown.is_empty()
+    }
+
+    /// Assemble the pending instructions, after the code already yielded.
+    fn flush(&mut self) -> Result<Vec<u8>, Error> {
+        let mut asm = Assembler::new();
+        asm.set_origin(self.position);
+        asm.set_target(self.target.clone());
+
+        // Only the labels of earlier chunks that these instructions use.
+        for op in self.pending.iter() {
+            for label in self.uses(op, &mut Vec::new()).into_iter().flatten() {
+                if let Some(position) = self.labels.get(&label) {
+                    asm.declare_position(label, *position);
+                }
+            }
+        }
+
+        let mut ops: Vec<_> = self
+            .macros
+            .values()
+            .cloned()
+            .map(AbstractOp::MacroDefinition)
+            .collect();
+        ops.append(&mut self.pending);
+        self.unresolved.clear();
+        self.unknown.clear();
+
+        let code = asm.assemble(&ops).context(error::Assemble)?;
+
+        for label in self.declared.drain() {
+            let position = asm.label(&label).expect("declared labels have positions");
+            self.labels.insert(label, position);
+        }
+
+        self.position += code.len();
+        Ok(code)
+    }
+}
+
+impl<I> Iterator for Stream<I>
+where
+    I: Iterator<Item = AbstractOp>,
+{
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let result = match self.ops.next() {
+                Some(op) => match self.add(op) {
+                    Ok(()) if self.closed() => self.flush(),
+                    Ok(()) => continue,
+                    Err(e) => Err(e),
+                },
+                None => {
+                    self.done = true;
+                    self.flush()
+                }
+            };
+
+            match result {
+                Ok(code) if code.is_empty() => continue,
+                Ok(code) => return Some(Ok(code)),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl<I> Read for Stream<I>
+where
+    I: Iterator<Item = AbstractOp>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.unread.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match self.next() {
+                Some(Ok(code)) => self.unread = io::Cursor::new(code),
+                Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ops::{
+        Expression, ExpressionMacroInvocation, Imm, InstructionMacroInvocation, Terminal,
+    };
+
+    use etk_ops::cancun::{Caller, GetPc, Jump, JumpDest, Push1};
+    use etk_ops::prague::RJump;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn assemble(ops: &[AbstractOp]) -> Vec<u8> {
+        Assembler::new().assemble(ops).unwrap()
+    }
+
+    #[test]
+    fn chunks_when_labels_resolve() -> Result<(), Error> {
+        let ops = vec![
+            AbstractOp::Label("start".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push1(Imm::with_label("end"))),
+            AbstractOp::new(Jump),
+            AbstractOp::Label("end".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::new(Push1(Imm::with_label("start"))),
+            AbstractOp::new(Jump),
+        ];
+
+        let mut stream = Stream::new(ops.clone());
+        let chunks: Vec<_> = Iterator::by_ref(&mut stream).collect::<Result<_, _>>()?;
+        assert_eq!(
+            chunks,
+            [
+                hex!("5b").to_vec(),
+                hex!("600456").to_vec(),
+                hex!("5b").to_vec(),
+                hex!("6000").to_vec(),
+                hex!("56").to_vec(),
+            ]
+        );
+        assert_eq!(chunks.concat(), assemble(&ops));
+        assert_eq!(stream.position(), 8);
+        assert_eq!(stream.label("end"), Some(4));
+
+        Ok(())
+    }
+
+    #[test]
+    fn relative_expressions() -> Result<(), Error> {
+        let rel = Imm::with_expression(Expression::Macro(ExpressionMacroInvocation {
+            name: "rel".into(),
+            parameters: vec![Terminal::Label("top".into()).into()],
+        }));
+
+        let ops = vec![
+            AbstractOp::new(GetPc),
+            AbstractOp::Label("top".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::Op(RJump(rel).into()),
+        ];
+
+        // Offsets count from the start of the program, not the chunk.
+        let mut stream = Stream::new(ops.clone());
+        stream.set_target(Profile::builtin("eof"));
+        let chunks: Vec<_> = stream.collect::<Result<_, _>>()?;
+        assert_eq!(
+            chunks,
+            [
+                hex!("58").to_vec(),
+                hex!("5b").to_vec(),
+                hex!("e0fffc").to_vec()
+            ]
+        );
+
+        let mut asm = Assembler::new();
+        asm.set_target(Profile::builtin("eof"));
+        assert_eq!(chunks.concat(), asm.assemble(&ops).unwrap());
+
+        Ok(())
+    }
+
+    #[test]
+    fn waits_for_macro_definitions() -> Result<(), Error> {
+        let defn = InstructionMacroDefinition::new(
+            "go".into(),
+            vec![],
+            vec![
+                AbstractOp::new(Push1(Imm::with_label("target"))),
+                AbstractOp::new(Jump),
+            ],
+        );
+
+        let ops = vec![
+            AbstractOp::Macro(InstructionMacroInvocation {
+                name: "go".into(),
+                parameters: vec![],
+            }),
+            AbstractOp::new(Caller),
+            AbstractOp::MacroDefinition(defn.into()),
+            AbstractOp::Label("target".into()),
+            AbstractOp::new(JumpDest),
+        ];
+
+        let chunks: Vec<_> = Stream::new(ops.clone()).collect::<Result<_, _>>()?;
+        assert_eq!(chunks, [hex!("60045633").to_vec(), hex!("5b").to_vec()]);
+        assert_eq!(chunks.concat(), assemble(&ops));
+
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_label() {
+        let ops = vec![
+            AbstractOp::Label("a".into()),
+            AbstractOp::new(JumpDest),
+            AbstractOp::Label("a".into()),
+            AbstractOp::new(Caller),
+        ];
+
+        let mut stream = Stream::new(ops);
+        assert_eq!(stream.next().unwrap().unwrap(), hex!("5b"));
+        assert!(matches!(
+            stream.next(),
+            Some(Err(Error::DuplicateLabel { .. }))
+        ));
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn undeclared_label() {
+        let ops = vec![
+            AbstractOp::new(Caller),
+            AbstractOp::new(Push1(Imm::with_label("missing"))),
+        ];
+
+        let mut stream = Stream::new(ops);
+        assert_eq!(stream.next().unwrap().unwrap(), hex!("33"));
+        assert!(matches!(stream.next(), Some(Err(Error::Assemble { .. }))));
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn read() {
+        let ops = vec![
+            AbstractOp::new(Push1(Imm::with_label("end"))),
+            AbstractOp::new(Jump),
+            AbstractOp::Label("end".into()),
+            AbstractOp::new(JumpDest),
+        ];
+
+        let mut code = Vec::new();
+        io::copy(&mut Stream::new(ops), &mut code).unwrap();
+        assert_eq!(code, hex!("6003565b"));
+
+        let ops = vec![AbstractOp::new(Push1(Imm::with_label("missing")))];
+        let err = io::copy(&mut Stream::new(ops), &mut io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+}
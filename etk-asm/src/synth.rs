@@ -0,0 +1,409 @@
+//! Synthesis of constants with instruction sequences cheaper than a push.
+//!
+//! Wide constants are often cheaper to build from a narrow push and one or two
+//! arithmetic instructions. For example, `push32 0xff..ff` (33 bytes) computes
+//! the same value as `push0; not` (2 bytes).
+//!
+//! Synthesis is opt-in (see [`Assembler::set_synthesis`]), and only replaces
+//! pushes of constants, since the value of a label may change as code moves.
+//! Every candidate sequence is evaluated before it is used, so a substitution
+//! never changes the value left on the stack. Candidates only use instructions
+//! the target allows, so code for chains before Shanghai pushes zero with
+//! `push1 0` instead of `push0`.
+//!
+//! [`Assembler::set_synthesis`]: crate::asm::Assembler::set_synthesis
+use crate::ops::is_push;
+use crate::profile::Profile;
+
+use etk_ops::prague::{Not, Op, Operation, Push0, Shl, Shr};
+
+use num_bigint::BigUint;
+
+use std::fmt;
+
+const WORD_BITS: u64 = 256;
+
+/// How to compare the cost of instruction sequences.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CostModel {
+    /// Prefer the fewest bytes, breaking ties with execution gas.
+    Bytes,
+
+    /// Prefer the least execution gas, breaking ties with bytes.
+    Gas,
+}
+
+impl CostModel {
+    pub(crate) fn key(self, cost: Cost) -> (u64, u64) {
+        let bytes = cost.bytes as u64;
+        match self {
+            Self::Bytes => (bytes, cost.gas),
+            Self::Gas => (cost.gas, bytes),
+        }
+    }
+}
+
+/// The size and execution gas of an instruction sequence.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Cost {
+    /// Encoded length, in bytes.
+    pub bytes: usize,
+
+    /// Gas used to execute the sequence.
+    pub gas: u64,
+}
+
+impl Cost {
+    /// Compute the cost of `ops`.
+    pub fn of(ops: &[Op<[u8]>]) -> Self {
+        Self {
+            bytes: ops.iter().map(Op::size).sum(),
+            gas: ops.iter().map(Operation::gas).sum(),
+        }
+    }
+}
+
+/// A push replaced by a cheaper sequence of instructions.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Substitution {
+    /// The replaced push.
+    pub original: Op<[u8]>,
+
+    /// The instructions computing the same value.
+    pub replacement: Vec<Op<[u8]>>,
+}
+
+impl fmt::Display for Substitution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let before = Cost::of(std::slice::from_ref(&self.original));
+        let after = Cost::of(&self.replacement);
+
+        write!(f, "replaced `")?;
+        write_op(f, &self.original)?;
+        write!(f, "` with `")?;
+        for (idx, op) in self.replacement.iter().enumerate() {
+            if idx != 0 {
+                write!(f, "; ")?;
+            }
+            write_op(f, op)?;
+        }
+        write!(
+            f,
+            "` ({} -> {} bytes, {} -> {} gas)",
+            before.bytes, after.bytes, before.gas, after.gas
+        )
+    }
+}
+
+fn write_op(f: &mut fmt::Formatter, op: &Op<[u8]>) -> fmt::Result {
+    write!(f, "{}", op.code())?;
+    if let Some(imm) = op.immediate() {
+        write!(f, " 0x{}", hex::encode(imm))?;
+    }
+    Ok(())
+}
+
+/// Find the cheapest sequence computing the same value as the push `original`,
+/// according to `model`, using only instructions available on `target`.
+///
+/// Returns `None` if `original` isn't a push, or nothing is cheaper.
+pub fn synthesize(
+    original: &Op<[u8]>,
+    model: CostModel,
+    target: &Profile,
+) -> Option<Vec<Op<[u8]>>> {
+    if !is_push(&original.code()) {
+        return None;
+    }
+
+    let value = BigUint::from_bytes_be(original.immediate()?);
+    let baseline = model.key(Cost::of(std::slice::from_ref(original)));
+
+    candidates(&value, target.has("push0"))
+        .into_iter()
+        .filter(|c| evaluate(c).as_ref() == Some(&value))
+        .map(|c| (model.key(Cost::of(&c)), c))
+        .filter(|(key, _)| *key < baseline)
+        .min_by_key(|(key, _)| *key)
+        .map(|(_, c)| c)
+}
+
+fn candidates(value: &BigUint, push0: bool) -> Vec<Vec<Op<[u8]>>> {
+    let push = |value: &BigUint| push(value, push0);
+    let mut candidates = vec![vec![push(value)]];
+
+    let inverted = not(value);
+    candidates.push(vec![push(&inverted), Op::from(Not)]);
+
+    // `x << k`
+    if let Some(shift) = value.trailing_zeros().filter(|s| *s >= 8) {
+        candidates.push(vec![
+            push(&(value >> shift)),
+            push(&BigUint::from(shift)),
+            Op::from(Shl),
+        ]);
+    }
+
+    // `~(x << k)`
+    if let Some(shift) = inverted.trailing_zeros().filter(|s| *s >= 8) {
+        candidates.push(vec![
+            push(&(&inverted >> shift)),
+            push(&BigUint::from(shift)),
+            Op::from(Shl),
+            Op::from(Not),
+        ]);
+    }
+
+    // `2**k - 1`, as all ones shifted right.
+    let bits = value.bits();
+    if bits > 0 && value.count_ones() == bits {
+        candidates.push(vec![
+            push(&BigUint::from(0u8)),
+            Op::from(Not),
+            push(&BigUint::from(WORD_BITS - bits)),
+            Op::from(Shr),
+        ]);
+    }
+
+    candidates
+}
+
+/// Create the smallest push of `value`, using `push0` for zero if it's
+/// available.
+fn push(value: &BigUint, push0: bool) -> Op<[u8]> {
+    if value.bits() == 0 && push0 {
+        return Op::from(Push0);
+    }
+
+    // `to_bytes_be` gives `[0]` for zero, so it's pushed with `push1`.
+    let bytes = value.to_bytes_be();
+    let mut encoded = vec![Op::<()>::push(bytes.len()).unwrap().code_byte()];
+    encoded.extend(bytes);
+    Op::from_slice(&encoded).unwrap()
+}
+
+fn mask() -> BigUint {
+    (BigUint::from(1u8) << WORD_BITS) - 1u8
+}
+
+fn not(value: &BigUint) -> BigUint {
+    value ^ mask()
+}
+
+/// Compute the value a sequence leaves on an empty stack, or `None` if it
+/// doesn't leave exactly one value, or uses an unsupported instruction.
+fn evaluate(ops: &[Op<[u8]>]) -> Option<BigUint> {
+    let mut stack = Vec::new();
+
+    for op in ops {
+        let result = match op {
+            Op::Push0(_) => BigUint::from(0u8),
+            Op::Not(_) => not(&stack.pop()?),
+            Op::Shl(_) | Op::Shr(_) => {
+                let shift = stack.pop()?;
+                let value = stack.pop()?;
+
+                if shift >= BigUint::from(WORD_BITS) {
+                    BigUint::from(0u8)
+                } else {
+                    let shift = shift.iter_u64_digits().next().unwrap_or(0);
+                    match op {
+                        Op::Shl(_) => (value << shift) & mask(),
+                        _ => value >> shift,
+                    }
+                }
+            }
+            _ => BigUint::from_bytes_be(op.immediate()?),
+        };
+
+        stack.push(result);
+    }
+
+    match stack.len() {
+        1 => stack.pop(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etk_ops::prague::*;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn latest() -> Profile {
+        Profile::builtin("prague").unwrap()
+    }
+
+    #[test]
+    fn all_ones() {
+        let original = Op::from(Push32(hex!(
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
+        )));
+
+        let expected = vec![Op::from(Push0), Op::from(Not)];
+        assert_eq!(
+            synthesize(&original, CostModel::Bytes, &latest()),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn inverted_small() {
+        let original = Op::from(Push32(hex!(
+            "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe"
+        )));
+
+        let expected = vec![Op::from(Push1(hex!("01"))), Op::from(Not)];
+        assert_eq!(
+            synthesize(&original, CostModel::Bytes, &latest()),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn shifted() {
+        let original = Op::from(Push20(hex!("0100000000000000000000000000000000000000")));
+
+        let expected = vec![
+            Op::from(Push1(hex!("01"))),
+            Op::from(Push1(hex!("98"))),
+            Op::from(Shl),
+        ];
+        assert_eq!(
+            synthesize(&original, CostModel::Bytes, &latest()),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn low_bits_set() {
+        let original = Op::from(Push20(hex!("ffffffffffffffffffffffffffffffffffffffff")));
+
+        let expected = vec![
+            Op::from(Push0),
+            Op::from(Not),
+            Op::from(Push1(hex!("60"))),
+            Op::from(Shr),
+        ];
+        assert_eq!(
+            synthesize(&original, CostModel::Bytes, &latest()),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn zero() {
+        let original = Op::from(Push1(hex!("00")));
+        let expected = vec![Op::from(Push0)];
+
+        assert_eq!(
+            synthesize(&original, CostModel::Bytes, &latest()),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            synthesize(&original, CostModel::Gas, &latest()),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn before_shanghai() {
+        let london = Profile::builtin("london").unwrap();
+
+        let original = Op::from(Push32(hex!(
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
+        )));
+        let expected = vec![Op::from(Push1(hex!("00"))), Op::from(Not)];
+        assert_eq!(
+            synthesize(&original, CostModel::Bytes, &london),
+            Some(expected)
+        );
+
+        let original = Op::from(Push20(hex!("ffffffffffffffffffffffffffffffffffffffff")));
+        let expected = vec![
+            Op::from(Push1(hex!("00"))),
+            Op::from(Not),
+            Op::from(Push1(hex!("60"))),
+            Op::from(Shr),
+        ];
+        assert_eq!(
+            synthesize(&original, CostModel::Bytes, &london),
+            Some(expected)
+        );
+
+        let original = Op::from(Push1(hex!("00")));
+        assert_eq!(synthesize(&original, CostModel::Bytes, &london), None);
+        assert_eq!(synthesize(&original, CostModel::Gas, &london), None);
+    }
+
+    #[test]
+    fn oversized() {
+        let original = Op::from(Push4(hex!("00000102")));
+        let expected = vec![Op::from(Push2(hex!("0102")))];
+
+        assert_eq!(
+            synthesize(&original, CostModel::Bytes, &latest()),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn already_optimal() {
+        let original = Op::from(Push2(hex!("1234")));
+        assert_eq!(synthesize(&original, CostModel::Bytes, &latest()), None);
+        assert_eq!(synthesize(&original, CostModel::Gas, &latest()), None);
+    }
+
+    #[test]
+    fn not_a_push() {
+        assert_eq!(
+            synthesize(&Op::from(Caller), CostModel::Bytes, &latest()),
+            None
+        );
+    }
+
+    #[test]
+    fn models_disagree() {
+        let original = Op::from(Push32(hex!(
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
+        )));
+
+        // `push0; not` is 31 bytes smaller, but costs two more gas.
+        assert!(synthesize(&original, CostModel::Bytes, &latest()).is_some());
+        assert_eq!(synthesize(&original, CostModel::Gas, &latest()), None);
+    }
+
+    #[test]
+    fn evaluate_sequences() {
+        let ops = [Op::from(Push0), Op::from(Not)];
+        assert_eq!(evaluate(&ops), Some(mask()));
+
+        let ops = [
+            Op::from(Push1(hex!("03"))),
+            Op::from(Push2(hex!("0100"))),
+            Op::from(Shl),
+        ];
+        assert_eq!(evaluate(&ops), Some(BigUint::from(0u8)));
+
+        let ops = [Op::from(Push0), Op::from(Push0)];
+        assert_eq!(evaluate(&ops), None);
+
+        assert_eq!(evaluate(&[Op::from(Caller)]), None);
+    }
+
+    #[test]
+    fn display() {
+        let substitution = Substitution {
+            original: Op::from(Push1(hex!("00"))),
+            replacement: vec![Op::from(Push0)],
+        };
+
+        assert_eq!(
+            substitution.to_string(),
+            "replaced `push1 0x00` with `push0` (2 -> 1 bytes, 3 -> 2 gas)"
+        );
+    }
+}
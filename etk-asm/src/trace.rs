@@ -0,0 +1,408 @@
+//! A log of the decisions the assembler makes, for debugging the assembler
+//! itself.
+//!
+//! When the code assembled from a source isn't what it should be (for
+//! example, a push is larger than expected), the [`Trace`] recorded by
+//! [`Assembler::set_tracing`] shows which labels were declared where, which
+//! pushes had their size guessed, and which guesses moved other labels. A
+//! trace serializes to JSON, so it can be attached to a bug report, and two
+//! traces can be compared with [`Trace::diff`].
+//!
+//! [`Assembler::set_tracing`]: crate::asm::Assembler::set_tracing
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_asm::ingest::Ingest;
+//! # use etk_asm::ingest::Error;
+//!
+//! let mut ingest = Ingest::new(Vec::new());
+//! ingest.set_tracing(true);
+//! ingest.ingest("./example.etk", "push1 end\nend:\njumpdest\n")?;
+//!
+//! let trace = ingest.trace().unwrap();
+//! assert_eq!(trace.labels_at(trace.events.len())["end"], 2);
+//! # Result::<(), Error>::Ok(())
+//! ```
+#[cfg(feature = "fs")]
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// One decision made by the assembler. Positions are relative to the start of
+/// the code being assembled.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "fs", serde(tag = "event", rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum Event {
+    /// A label was declared at a tentative position, which may still move.
+    LabelDeclared {
+        /// The name of the label.
+        label: String,
+
+        /// The position of the label when it was declared.
+        position: usize,
+    },
+
+    /// The size of a push was guessed, because its expression uses labels
+    /// that weren't declared yet.
+    PushDeferred {
+        /// The expression of the push.
+        expr: String,
+
+        /// The position of the push.
+        position: usize,
+
+        /// The guessed size of the push, in bytes.
+        size: usize,
+    },
+
+    /// A deferred push turned out larger than guessed, moving labels after
+    /// it.
+    PushGrown {
+        /// The expression of the push.
+        expr: String,
+
+        /// The final size of the push, in bytes.
+        size: usize,
+
+        /// How many bytes the labels moved by.
+        shift: usize,
+    },
+
+    /// An instruction macro was expanded.
+    MacroExpanded {
+        /// The name of the macro.
+        name: String,
+
+        /// The arguments of the expansion.
+        arguments: Vec<String>,
+
+        /// The position of the first instruction of the expansion.
+        position: usize,
+    },
+
+    /// A scope, like an `%include`d file, was assembled on its own.
+    ScopeAssembled {
+        /// The namespace of the scope's labels, if any.
+        namespace: Option<String>,
+
+        /// The position of the scope's code.
+        position: usize,
+
+        /// The decisions made while assembling the scope, positioned relative
+        /// to the start of the scope.
+        trace: Trace,
+    },
+
+    /// An instruction with an immediate was written to the output.
+    Emitted {
+        /// The instruction, as written in the source, like `push2 label`.
+        op: String,
+
+        /// The position of the instruction.
+        position: usize,
+
+        /// The size of the instruction, including its immediate, in bytes.
+        size: usize,
+    },
+
+    /// A label's position became final.
+    LabelResolved {
+        /// The name of the label.
+        label: String,
+
+        /// The final position of the label.
+        position: usize,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::LabelDeclared { label, position } => {
+                write!(f, "declare label `{}` at 0x{:x}", label, position)
+            }
+            Self::PushDeferred {
+                expr,
+                position,
+                size,
+            } => write!(
+                f,
+                "defer push of `{}` at 0x{:x} (guessed {} bytes)",
+                expr, position, size
+            ),
+            Self::PushGrown { expr, size, shift } => write!(
+                f,
+                "grow push of `{}` to {} bytes (labels move by {})",
+                expr, size, shift
+            ),
+            Self::MacroExpanded {
+                name,
+                arguments,
+                position,
+            } => write!(
+                f,
+                "expand `%{}({})` at 0x{:x}",
+                name,
+                arguments.join(", "),
+                position
+            ),
+            Self::ScopeAssembled {
+                namespace,
+                position,
+                trace,
+            } => {
+                write!(f, "assemble scope")?;
+                if let Some(namespace) = namespace {
+                    write!(f, " `{}`", namespace)?;
+                }
+                write!(f, " at 0x{:x} ({} events)", position, trace.events.len())
+            }
+            Self::Emitted { op, position, size } => {
+                write!(f, "emit `{}` at 0x{:x} ({} bytes)", op, position, size)
+            }
+            Self::LabelResolved { label, position } => {
+                write!(f, "resolve label `{}` to 0x{:x}", label, position)
+            }
+        }
+    }
+}
+
+/// The decisions made by the assembler, in the order they were made.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "fs", derive(Serialize, Deserialize))]
+pub struct Trace {
+    /// Every decision, in order.
+    pub events: Vec<Event>,
+}
+
+impl Trace {
+    /// Replay the first `count` events, returning the position of every label
+    /// at that point.
+    pub fn labels_at(&self, count: usize) -> BTreeMap<String, usize> {
+        let mut labels = BTreeMap::new();
+
+        for event in self.events.iter().take(count) {
+            match event {
+                Event::LabelDeclared { label, position }
+                | Event::LabelResolved { label, position } => {
+                    labels.insert(label.clone(), *position);
+                }
+                Event::PushGrown { shift, .. } => {
+                    // Growing a push moves every label declared so far.
+                    for position in labels.values_mut() {
+                        *position += shift;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        labels
+    }
+
+    /// Compare this trace to `other`, returning the events that only appear
+    /// in one of them.
+    ///
+    /// The result displays as a unified diff, with events only in `self`
+    /// marked with `-`, and events only in `other` with `+`.
+    pub fn diff<'a>(&'a self, other: &'a Trace) -> Diff<'a> {
+        let (before, after) = (&self.events[..], &other.events[..]);
+
+        let prefix = before.iter().zip(after).take_while(|(a, b)| a == b).count();
+
+        let suffix = before[prefix..]
+            .iter()
+            .rev()
+            .zip(after[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let before = &before[prefix..before.len() - suffix];
+        let after = &after[prefix..after.len() - suffix];
+
+        // Longest common subsequence of what's left, which is usually short.
+        let mut lengths = vec![vec![0usize; after.len() + 1]; before.len() + 1];
+        for (i, a) in before.iter().enumerate().rev() {
+            for (j, b) in after.iter().enumerate().rev() {
+                lengths[i][j] = if a == b {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+
+        let mut changes = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < before.len() || j < after.len() {
+            if i < before.len() && j < after.len() && before[i] == after[j] {
+                i += 1;
+                j += 1;
+            } else if j == after.len()
+                || (i < before.len() && lengths[i + 1][j] >= lengths[i][j + 1])
+            {
+                changes.push(Change::Removed {
+                    index: prefix + i,
+                    event: &before[i],
+                });
+                i += 1;
+            } else {
+                changes.push(Change::Added {
+                    index: prefix + j,
+                    event: &after[j],
+                });
+                j += 1;
+            }
+        }
+
+        Diff { changes }
+    }
+}
+
+/// An event that only appears in one of the traces given to [`Trace::diff`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Change<'a> {
+    /// The event only appears in the first trace, at `index`.
+    Removed {
+        /// The position of the event in the first trace.
+        index: usize,
+
+        /// The event.
+        event: &'a Event,
+    },
+
+    /// The event only appears in the second trace, at `index`.
+    Added {
+        /// The position of the event in the second trace.
+        index: usize,
+
+        /// The event.
+        event: &'a Event,
+    },
+}
+
+/// The differences between two traces, returned by [`Trace::diff`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Diff<'a> {
+    /// Every event that only appears in one trace, in order.
+    pub changes: Vec<Change<'a>>,
+}
+
+impl Diff<'_> {
+    /// Return `true` if the traces are the same.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for Diff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for change in self.changes.iter() {
+            match change {
+                Change::Removed { index, event } => writeln!(f, "-{:>6}: {}", index, event)?,
+                Change::Added { index, event } => writeln!(f, "+{:>6}: {}", index, event)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn declared(label: &str, position: usize) -> Event {
+        Event::LabelDeclared {
+            label: label.into(),
+            position,
+        }
+    }
+
+    fn grown(shift: usize) -> Event {
+        Event::PushGrown {
+            expr: "a".into(),
+            size: 1 + shift + 1,
+            shift,
+        }
+    }
+
+    #[test]
+    fn labels_at() {
+        let trace = Trace {
+            events: vec![declared("a", 2), grown(1), declared("b", 5)],
+        };
+
+        assert!(trace.labels_at(0).is_empty());
+        assert_eq!(trace.labels_at(1)["a"], 2);
+        assert_eq!(trace.labels_at(2)["a"], 3);
+
+        let labels = trace.labels_at(3);
+        assert_eq!(labels["a"], 3);
+        assert_eq!(labels["b"], 5);
+    }
+
+    #[test]
+    fn diff() {
+        let before = Trace {
+            events: vec![
+                declared("a", 2),
+                grown(1),
+                declared("b", 5),
+                declared("c", 6),
+            ],
+        };
+        let after = Trace {
+            events: vec![declared("a", 2), declared("b", 4), declared("c", 6)],
+        };
+
+        assert!(before.diff(&before).is_empty());
+
+        let diff = before.diff(&after);
+        assert_eq!(
+            diff.changes,
+            [
+                Change::Removed {
+                    index: 1,
+                    event: &before.events[1],
+                },
+                Change::Removed {
+                    index: 2,
+                    event: &before.events[2],
+                },
+                Change::Added {
+                    index: 1,
+                    event: &after.events[1],
+                },
+            ]
+        );
+
+        assert_eq!(
+            diff.to_string(),
+            "-     1: grow push of `a` to 3 bytes (labels move by 1)\n\
+             -     2: declare label `b` at 0x5\n\
+             +     1: declare label `b` at 0x4\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn json_format() {
+        let trace = Trace {
+            events: vec![declared("a", 2)],
+        };
+
+        let json = serde_json::to_string(&trace).unwrap();
+        assert_eq!(
+            json,
+            r#"{"events":[{"event":"label_declared","label":"a","position":2}]}"#
+        );
+
+        let parsed: Trace = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, trace);
+    }
+}
@@ -0,0 +1,330 @@
+//! Comparing assembled code to code built earlier.
+//!
+//! See [`Ingest::verify_against`] for checking that a source still assembles
+//! to a known artifact, for example the code of a deployed contract.
+//!
+//! [`verify_roundtrip`] checks that disassembling code and assembling the
+//! result gives back the same code, which keeps the disassembler and the
+//! assembler in agreement about every instruction.
+//!
+//! [`Ingest::verify_against`]: crate::ingest::Ingest::verify_against
+use crate::disasm::{self, Disassembler, Record};
+use crate::ingest::{Error, Ingest};
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Number of instructions shown before and after the first difference.
+pub const CONTEXT: usize = 3;
+
+/// Code to compare the assembled code against.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Expected {
+    /// A file containing the code, either hex encoded (like the output of
+    /// `eas`, with or without a `0x` prefix) or as raw bytes.
+    Path(PathBuf),
+
+    /// The code itself.
+    Bytes(Vec<u8>),
+}
+
+impl Expected {
+    /// The expected code, reading it from a file if needed.
+    pub fn code(&self) -> io::Result<Vec<u8>> {
+        let bytes = match self {
+            Self::Bytes(bytes) => return Ok(bytes.clone()),
+            Self::Path(path) => std::fs::read(path)?,
+        };
+
+        let hex = std::str::from_utf8(&bytes)
+            .ok()
+            .map(str::trim)
+            .map(|text| text.strip_prefix("0x").unwrap_or(text))
+            .and_then(|text| hex::decode(text).ok());
+
+        Ok(hex.unwrap_or(bytes))
+    }
+}
+
+impl From<PathBuf> for Expected {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl From<&Path> for Expected {
+    fn from(path: &Path) -> Self {
+        Self::Path(path.to_owned())
+    }
+}
+
+impl From<Vec<u8>> for Expected {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Expected {
+    fn from(bytes: &[u8]) -> Self {
+        Self::Bytes(bytes.to_vec())
+    }
+}
+
+/// The first difference between the assembled code and the expected code.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::verify::Mismatch;
+///
+/// // push1 1; push1 2; stop
+/// let expected = [0x60, 0x01, 0x60, 0x02, 0x00];
+/// let actual = [0x60, 0x01, 0x60, 0x03, 0x00];
+///
+/// let mismatch = Mismatch::compare(&expected, &actual).unwrap();
+/// assert_eq!(mismatch.offset, 3);
+/// assert_eq!(mismatch.expected[1].immediate.as_deref(), Some("0x02"));
+/// assert_eq!(mismatch.actual[1].immediate.as_deref(), Some("0x03"));
+///
+/// assert_eq!(Mismatch::compare(&expected, &expected), None);
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Mismatch {
+    /// Position of the first byte that differs. If one code is the start of
+    /// the other, this is the length of the shorter one.
+    pub offset: usize,
+
+    /// Length of the expected code, in bytes.
+    pub expected_len: usize,
+
+    /// Length of the assembled code, in bytes.
+    pub actual_len: usize,
+
+    /// Instructions of the expected code around `offset`, up to [`CONTEXT`]
+    /// on each side of the instruction containing it.
+    pub expected: Vec<Record>,
+
+    /// Instructions of the assembled code around `offset`.
+    pub actual: Vec<Record>,
+}
+
+impl Mismatch {
+    /// Find the first difference between `expected` and `actual`, or `None`
+    /// if they're identical.
+    pub fn compare(expected: &[u8], actual: &[u8]) -> Option<Self> {
+        let offset = expected
+            .iter()
+            .zip(actual)
+            .position(|(e, a)| e != a)
+            .unwrap_or_else(|| expected.len().min(actual.len()));
+
+        if offset == expected.len() && offset == actual.len() {
+            return None;
+        }
+
+        Some(Self {
+            offset,
+            expected_len: expected.len(),
+            actual_len: actual.len(),
+            expected: context(expected, offset),
+            actual: context(actual, offset),
+        })
+    }
+}
+
+/// The instructions of `code` around `offset`.
+fn context(code: &[u8], offset: usize) -> Vec<Record> {
+    let mut dasm = Disassembler::new();
+    dasm.write_all(code).unwrap();
+    let records: Vec<_> = dasm.records().collect();
+
+    let idx = records
+        .iter()
+        .position(|r| offset < r.offset + r.size)
+        .unwrap_or(records.len());
+
+    let start = idx.saturating_sub(CONTEXT);
+    let end = records.len().min(idx + CONTEXT + 1);
+    records[start..end].to_vec()
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "code differs at offset {:#x} (expected {} bytes, got {})",
+            self.offset, self.expected_len, self.actual_len
+        )?;
+
+        for (title, records) in [("expected", &self.expected), ("actual", &self.actual)] {
+            write!(f, "\n{}:", title)?;
+
+            for record in records {
+                let marker =
+                    if record.offset <= self.offset && self.offset < record.offset + record.size {
+                        '>'
+                    } else {
+                        ' '
+                    };
+
+                write!(
+                    f,
+                    "\n  {} {:>4x}:   {}",
+                    marker, record.offset, record.mnemonic
+                )?;
+                if let Some(ref immediate) = record.immediate {
+                    write!(f, " {}", immediate)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Disassemble `code`, assemble the disassembly, and check that the result is
+/// `code` again.
+///
+/// Bytes that aren't instructions, and a push at the end of `code` that's
+/// missing part of its immediate, are written with `%bytes`.
+///
+/// ## Example
+///
+/// ```rust
+/// use etk_asm::verify::verify_roundtrip;
+///
+/// // push1 1; push2 2; invalid; (undefined opcode 0x0c); push4 (truncated)
+/// verify_roundtrip(&[0x60, 0x01, 0x61, 0x00, 0x02, 0xfe, 0x0c, 0x63, 0xaa]).unwrap();
+/// ```
+pub fn verify_roundtrip(code: &[u8]) -> Result<(), Diff> {
+    let source = disasm::to_source(code, false);
+
+    let mut actual = Vec::new();
+    if let Err(error) = Ingest::new(&mut actual).ingest("roundtrip.etk", &source) {
+        return Err(Diff::Assemble { source, error });
+    }
+
+    match Mismatch::compare(code, &actual) {
+        None => Ok(()),
+        Some(mismatch) => Err(Diff::Changed { source, mismatch }),
+    }
+}
+
+/// How code changed when disassembled and assembled again.
+///
+/// See [`verify_roundtrip`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Diff {
+    /// The disassembly couldn't be assembled.
+    #[non_exhaustive]
+    Assemble {
+        /// The disassembly.
+        source: String,
+
+        /// Why assembling failed.
+        error: Error,
+    },
+
+    /// Assembling the disassembly gave different code.
+    #[non_exhaustive]
+    Changed {
+        /// The disassembly.
+        source: String,
+
+        /// The first difference, with the original code as the expected code.
+        mismatch: Mismatch,
+    },
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Assemble { source, error } => write!(
+                f,
+                "disassembly couldn't be assembled ({})\n{}",
+                error, source
+            ),
+            Self::Changed { mismatch, .. } => write!(f, "{}", mismatch),
+        }
+    }
+}
+
+impl std::error::Error for Diff {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_prefix() {
+        let mismatch = Mismatch::compare(&[0x33, 0x33], &[0x33]).unwrap();
+        assert_eq!(mismatch.offset, 1);
+        assert_eq!((mismatch.expected_len, mismatch.actual_len), (2, 1));
+        assert_eq!(mismatch.expected.len(), 2);
+        assert_eq!(mismatch.actual.len(), 1);
+    }
+
+    #[test]
+    fn context_is_limited() {
+        let expected = [0x58; 10];
+        let mut actual = expected;
+        actual[5] = 0x33;
+
+        let mismatch = Mismatch::compare(&expected, &actual).unwrap();
+        let offsets: Vec<_> = mismatch.actual.iter().map(|r| r.offset).collect();
+        assert_eq!(offsets, [2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn display() {
+        // push1 1; stop
+        let mismatch = Mismatch::compare(&[0x60, 0x01, 0x00], &[0x60, 0x02, 0x00]).unwrap();
+        let expected = "\
+code differs at offset 0x1 (expected 3 bytes, got 3)
+expected:
+  >    0:   push1 0x01
+       2:   stop
+actual:
+  >    0:   push1 0x02
+       2:   stop";
+        assert_eq!(mismatch.to_string(), expected);
+    }
+
+    #[test]
+    fn roundtrip_every_opcode() {
+        for opcode in 0..=u8::MAX {
+            let mut code = vec![opcode];
+            code.extend_from_slice(&[0xab; 32]);
+            code.push(0x00);
+
+            if let Err(diff) = verify_roundtrip(&code) {
+                panic!("opcode 0x{:02x}: {}", opcode, diff);
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_truncated() {
+        assert_eq!(
+            disasm::to_source(&[0x00, 0x7f, 0x01], false),
+            "stop\n%bytes(0x7f01)\n"
+        );
+        verify_roundtrip(&[0x00, 0x7f, 0x01]).unwrap();
+        verify_roundtrip(&[]).unwrap();
+    }
+
+    #[test]
+    fn expected_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let hex = dir.path().join("code.hex");
+        std::fs::write(&hex, "0x6001\n").unwrap();
+        assert_eq!(Expected::from(hex).code().unwrap(), [0x60, 0x01]);
+
+        let bin = dir.path().join("code.bin");
+        std::fs::write(&bin, [0x60, 0x01]).unwrap();
+        assert_eq!(Expected::from(bin).code().unwrap(), [0x60, 0x01]);
+    }
+}
@@ -0,0 +1,454 @@
+//! Access to the files read while assembling, so they can come from somewhere
+//! other than the real filesystem.
+//!
+//! [`Ingest`](crate::ingest::Ingest) reads every source, `%include`, and
+//! `%import` through a [`FileProvider`]. By default that's [`DiskFiles`] (or,
+//! without the `fs` feature, an empty [`MemoryFiles`]), but embedders (like
+//! web playgrounds or build systems) can serve files from memory with
+//! [`MemoryFiles`], or from anywhere else by implementing the trait. Editors
+//! can layer their unsaved buffers over the files on disk with
+//! [`OverlayFiles`], so diagnostics match what's on screen.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_asm::ingest::Ingest;
+//! use etk_asm::vfs::MemoryFiles;
+//! # use etk_asm::ingest::Error;
+//! # use hex_literal::hex;
+//!
+//! let mut files = MemoryFiles::new();
+//! files.insert("/src/main.etk", "%import(\"lib/util.etk\")\npc\n");
+//! files.insert("/src/lib/util.etk", "caller\n");
+//!
+//! let mut output = Vec::new();
+//! let mut ingest = Ingest::new(&mut output);
+//! ingest.set_file_provider(files);
+//! ingest.ingest_file("/src/main.etk")?;
+//! # drop(ingest);
+//! # assert_eq!(output, hex!("3358"));
+//! # Result::<(), Error>::Ok(())
+//! ```
+//!
+//! ## Unsaved Buffers
+//!
+//! ```rust
+//! use etk_asm::ingest::Ingest;
+//! use etk_asm::vfs::{MemoryFiles, OverlayFiles};
+//! # use etk_asm::ingest::Error;
+//! # use hex_literal::hex;
+//!
+//! // Stands in for the files on disk.
+//! let mut saved = MemoryFiles::new();
+//! saved.insert("/src/main.etk", "%import(\"util.etk\")\n");
+//! saved.insert("/src/util.etk", "caller\n");
+//!
+//! let mut files = OverlayFiles::with_base(saved);
+//! files.insert("/src/main.etk", "%import(\"util.etk\")\n%import(\"new.etk\")\n");
+//! files.insert("/src/new.etk", "pc\n");
+//!
+//! let mut output = Vec::new();
+//! let mut ingest = Ingest::new(&mut output);
+//! ingest.set_file_provider(files);
+//! ingest.ingest_file("/src/main.etk")?;
+//! # drop(ingest);
+//! # assert_eq!(output, hex!("3358"));
+//! # Result::<(), Error>::Ok(())
+//! ```
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// A source of files for [`Ingest`](crate::ingest::Ingest).
+///
+/// Relative paths are resolved against [`FileProvider::current_dir`].
+/// Including files is restricted to the directory of the first file (and the
+/// include paths) by comparing canonicalized paths, so implementations must
+/// canonicalize consistently.
+pub trait FileProvider: fmt::Debug + Send + Sync {
+    /// Read the entire contents of the file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Read the entire contents of the file at `path` as UTF-8.
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// The absolute form of `path`, with every `.` and `..` resolved. Fails
+    /// if there's nothing at `path`.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Return `true` if `path` is a directory.
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Return `true` if there's a file or directory at `path`.
+    fn exists(&self, path: &Path) -> bool {
+        self.canonicalize(path).is_ok()
+    }
+
+    /// The directory that relative paths are resolved against.
+    fn current_dir(&self) -> io::Result<PathBuf>;
+}
+
+/// Files on the real filesystem, through [`std::fs`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskFiles;
+
+impl FileProvider for DiskFiles {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        std::fs::canonicalize(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn current_dir(&self) -> io::Result<PathBuf> {
+        std::env::current_dir()
+    }
+}
+
+/// Files held in memory, keyed by their path.
+///
+/// Relative paths are relative to the filesystem root (`/`), which always
+/// exists. Other directories exist implicitly when they contain a file.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFiles {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl MemoryFiles {
+    /// Create an empty set of files.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file at `path` holding `contents`, replacing any file already
+    /// there.
+    pub fn insert<P, C>(&mut self, path: P, contents: C)
+    where
+        P: AsRef<Path>,
+        C: Into<Vec<u8>>,
+    {
+        self.files.insert(normalize(path.as_ref()), contents.into());
+    }
+
+    /// Remove the file at `path`, returning its contents.
+    pub fn remove<P>(&mut self, path: P) -> Option<Vec<u8>>
+    where
+        P: AsRef<Path>,
+    {
+        self.files.remove(&normalize(path.as_ref()))
+    }
+}
+
+impl FileProvider for MemoryFiles {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.files.get(&normalize(path)) {
+            Some(contents) => Ok(contents.clone()),
+            None if self.is_dir(path) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "is a directory, not a file",
+            )),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = normalize(path);
+        if self.files.contains_key(&path) || self.is_dir(&path) {
+            Ok(path)
+        } else {
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        }
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        path.parent().is_none()
+            || self
+                .files
+                .keys()
+                .any(|file| file != &path && file.starts_with(&path))
+    }
+
+    fn current_dir(&self) -> io::Result<PathBuf> {
+        Ok(PathBuf::from("/"))
+    }
+}
+
+/// Unsaved buffers layered over the files of another provider, like an
+/// editor's open files over the real filesystem.
+///
+/// A buffer replaces the file at the same path, if there is one, and may be
+/// `%include`d or `%import`ed before it's ever saved. Every other path is
+/// read from the underlying provider.
+///
+/// Buffers are keyed by their absolute path, with the directory canonicalized
+/// by the underlying provider when it exists there, so a buffer is found
+/// however the path to it is written.
+#[derive(Debug, Clone, Default)]
+pub struct OverlayFiles<F = DiskFiles> {
+    base: F,
+    buffers: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl OverlayFiles {
+    /// Create an empty set of buffers over the real filesystem.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<F> OverlayFiles<F>
+where
+    F: FileProvider,
+{
+    /// Create an empty set of buffers over the files of `base`.
+    pub fn with_base(base: F) -> Self {
+        Self {
+            base,
+            buffers: BTreeMap::new(),
+        }
+    }
+
+    /// The provider that paths without a buffer are read from.
+    pub fn base(&self) -> &F {
+        &self.base
+    }
+
+    /// Add a buffer at `path` holding `contents`, replacing any buffer
+    /// already there.
+    pub fn insert<P, C>(&mut self, path: P, contents: C)
+    where
+        P: AsRef<Path>,
+        C: Into<Vec<u8>>,
+    {
+        let key = self.key(path.as_ref());
+        self.buffers.insert(key, contents.into());
+    }
+
+    /// Remove the buffer at `path`, like when it's saved or closed without
+    /// saving, returning its contents.
+    pub fn remove<P>(&mut self, path: P) -> Option<Vec<u8>>
+    where
+        P: AsRef<Path>,
+    {
+        let key = self.key(path.as_ref());
+        self.buffers.remove(&key)
+    }
+
+    /// The path that a buffer at `path` is stored under.
+    fn key(&self, path: &Path) -> PathBuf {
+        let path = match self.base.current_dir() {
+            Ok(dir) => normalize(&dir.join(path)),
+            Err(_) => normalize(path),
+        };
+
+        let dir = path.parent().and_then(|d| self.base.canonicalize(d).ok());
+        match (dir, path.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => path,
+        }
+    }
+
+    /// Return `true` if `key` is a directory holding a buffer.
+    fn holds_buffers(&self, key: &Path) -> bool {
+        self.buffers
+            .keys()
+            .any(|buffer| buffer != key && buffer.starts_with(key))
+    }
+}
+
+impl<F> FileProvider for OverlayFiles<F>
+where
+    F: FileProvider,
+{
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.buffers.get(&self.key(path)) {
+            Some(contents) => Ok(contents.clone()),
+            None => self.base.read(path),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let key = self.key(path);
+        if self.buffers.contains_key(&key) || self.holds_buffers(&key) {
+            Ok(key)
+        } else {
+            self.base.canonicalize(path)
+        }
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        let key = self.key(path);
+        !self.buffers.contains_key(&key) && (self.holds_buffers(&key) || self.base.is_dir(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let key = self.key(path);
+        self.buffers.contains_key(&key) || self.holds_buffers(&key) || self.base.exists(path)
+    }
+
+    fn current_dir(&self) -> io::Result<PathBuf> {
+        self.base.current_dir()
+    }
+}
+
+impl<F> FileProvider for Arc<F>
+where
+    F: FileProvider + ?Sized,
+{
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        (**self).read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        (**self).read_to_string(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        (**self).canonicalize(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        (**self).is_dir(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        (**self).exists(path)
+    }
+
+    fn current_dir(&self) -> io::Result<PathBuf> {
+        (**self).current_dir()
+    }
+}
+
+/// Make `path` absolute (relative to `/`), and resolve every `.` and `..`
+/// without consulting any filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(name) => normalized.push(name),
+        }
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_normalize() {
+        let mut files = MemoryFiles::new();
+        files.insert("src/main.etk", "pc");
+
+        let expected = Path::new("/src/main.etk");
+        assert_eq!(
+            files.canonicalize(Path::new("/src/main.etk")).unwrap(),
+            expected
+        );
+        assert_eq!(
+            files.canonicalize(Path::new("./src/./main.etk")).unwrap(),
+            expected
+        );
+        assert_eq!(
+            files
+                .canonicalize(Path::new("/lib/../src/main.etk"))
+                .unwrap(),
+            expected
+        );
+        assert_eq!(files.read(Path::new("/../src/main.etk")).unwrap(), b"pc");
+    }
+
+    #[test]
+    fn memory_directories() {
+        let mut files = MemoryFiles::new();
+        files.insert("/src/lib/util.etk", "pc");
+
+        assert!(files.is_dir(Path::new("/")));
+        assert!(files.is_dir(Path::new("/src")));
+        assert!(files.is_dir(Path::new("/src/lib/")));
+        assert!(MemoryFiles::new().is_dir(Path::new("/")));
+        assert!(!files.is_dir(Path::new("/src/lib/util.etk")));
+        assert!(!files.is_dir(Path::new("/src/li")));
+
+        assert!(files.exists(Path::new("/src/lib")));
+        assert!(!files.exists(Path::new("/src/main.etk")));
+
+        let err = files.read(Path::new("/src/main.etk")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(files.read(Path::new("/src")).is_err());
+    }
+
+    #[test]
+    fn overlay_buffers() {
+        let mut saved = MemoryFiles::new();
+        saved.insert("/src/main.etk", "pc");
+        saved.insert("/src/lib/util.etk", "caller");
+
+        let mut files = OverlayFiles::with_base(saved);
+        files.insert("src/main.etk", "push0");
+        files.insert("/src/new/../new.etk", "gas");
+        files.insert("/tests/case.etk", "origin");
+
+        assert_eq!(files.read(Path::new("/src/main.etk")).unwrap(), b"push0");
+        assert_eq!(files.read(Path::new("/src/./new.etk")).unwrap(), b"gas");
+        assert_eq!(
+            files.read(Path::new("/src/lib/util.etk")).unwrap(),
+            b"caller"
+        );
+        assert_eq!(
+            files.base().read(Path::new("/src/main.etk")).unwrap(),
+            b"pc"
+        );
+
+        assert_eq!(
+            files
+                .canonicalize(Path::new("/tests/../src/new.etk"))
+                .unwrap(),
+            Path::new("/src/new.etk")
+        );
+        assert!(files.exists(Path::new("/tests/case.etk")));
+        assert!(files.is_dir(Path::new("/tests")));
+        assert!(files.is_dir(Path::new("/src/lib")));
+        assert!(!files.is_dir(Path::new("/src/new.etk")));
+        assert!(!files.exists(Path::new("/tests/other.etk")));
+
+        assert_eq!(files.remove("/src/main.etk").unwrap(), b"push0");
+        assert_eq!(files.read(Path::new("/src/main.etk")).unwrap(), b"pc");
+        assert!(files.remove("/src/main.etk").is_none());
+    }
+
+    #[test]
+    fn memory_invalid_utf8() {
+        let mut files = MemoryFiles::new();
+        files.insert("/main.etk", vec![0xff]);
+
+        let err = files.read_to_string(Path::new("/main.etk")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,288 @@
+//! Non-fatal diagnostics reported while assembling.
+//!
+//! Warnings are collected by [`Assembler::warnings`] and
+//! [`Ingest::warnings`]. In strict mode (see [`Assembler::set_strict`]), the
+//! first warning is returned as an error instead. Warnings of some kinds
+//! only, identified by their [`Lint`], can be promoted with
+//! [`Assembler::set_promoted`].
+//!
+//! [`Assembler::warnings`]: crate::asm::Assembler::warnings
+//! [`Assembler::set_strict`]: crate::asm::Assembler::set_strict
+//! [`Assembler::set_promoted`]: crate::asm::Assembler::set_promoted
+//! [`Ingest::warnings`]: crate::ingest::Ingest::warnings
+use crate::analysis::STACK_LIMIT;
+use crate::ops::AbstractOp;
+
+use etk_ops::prague::{Op, Operation};
+
+use num_bigint::BigInt;
+
+use std::fmt;
+
+/// A problem in the source that does not prevent it from being assembled.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Warning {
+    /// A label was declared but never referenced.
+    #[non_exhaustive]
+    UnusedLabel {
+        /// The name of the unused label.
+        label: String,
+    },
+
+    /// An instruction follows an unconditional exit or jump without an
+    /// intervening label or `jumpdest`, so it can never be executed.
+    #[non_exhaustive]
+    UnreachableCode {
+        /// The instruction that ends the reachable code.
+        after: Op<()>,
+
+        /// The first unreachable instruction.
+        op: AbstractOp,
+    },
+
+    /// A push instruction is wider than its constant value requires.
+    #[non_exhaustive]
+    OversizedPush {
+        /// The specifier of the push instruction.
+        spec: Op<()>,
+
+        /// The value being pushed.
+        value: BigInt,
+
+        /// The smallest push instruction that can hold `value`.
+        minimum: Op<()>,
+    },
+
+    /// An instruction pops more items than the stack can hold on some path.
+    #[non_exhaustive]
+    StackUnderflow {
+        /// Location of the instruction in the assembled code.
+        offset: usize,
+
+        /// The instruction.
+        op: Op<()>,
+
+        /// Height of the stack when the instruction is reached.
+        height: usize,
+    },
+
+    /// An instruction grows the stack past its limit on some path.
+    #[non_exhaustive]
+    StackOverflow {
+        /// Location of the instruction in the assembled code.
+        offset: usize,
+
+        /// The instruction.
+        op: Op<()>,
+    },
+
+    /// A label on a `jumpdest` that is the destination of a jump can also be
+    /// reached by falling through from the instructions before it, without
+    /// a `# fallthrough` comment marking that as intended.
+    #[non_exhaustive]
+    Fallthrough {
+        /// The name of the label.
+        label: String,
+    },
+
+    /// A `# keep` comment names a stack item that the instructions before it
+    /// moved or removed.
+    #[non_exhaustive]
+    KeepMismatch {
+        /// Location of the comment in the assembled code.
+        offset: usize,
+
+        /// The name of the item.
+        name: String,
+
+        /// Where the comment claims the item is, counting from 1 at the top
+        /// of the stack.
+        expected: usize,
+
+        /// Where the item is, counting from 1 at the top of the stack, or
+        /// `None` if it was removed.
+        found: Option<usize>,
+    },
+
+    /// An instruction is written by its opcode (`op(..)`), so the assembler
+    /// can't check how it's used.
+    #[non_exhaustive]
+    RawOpcode {
+        /// The opcode of the instruction.
+        opcode: u8,
+    },
+}
+
+impl Warning {
+    /// The kind of the warning.
+    pub fn lint(&self) -> Lint {
+        match self {
+            Self::UnusedLabel { .. } => Lint::UnusedLabel,
+            Self::UnreachableCode { .. } => Lint::UnreachableCode,
+            Self::OversizedPush { .. } => Lint::OversizedPush,
+            Self::StackUnderflow { .. } | Self::StackOverflow { .. } => Lint::StackHeight,
+            Self::Fallthrough { .. } => Lint::Fallthrough,
+            Self::KeepMismatch { .. } => Lint::KeepMismatch,
+            Self::RawOpcode { .. } => Lint::RawOpcode,
+        }
+    }
+}
+
+/// A kind of [`Warning`], which can be promoted to an error on its own.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum Lint {
+    /// See [`Warning::UnusedLabel`].
+    UnusedLabel,
+
+    /// See [`Warning::UnreachableCode`].
+    UnreachableCode,
+
+    /// See [`Warning::OversizedPush`].
+    OversizedPush,
+
+    /// See [`Warning::StackUnderflow`] and [`Warning::StackOverflow`].
+    StackHeight,
+
+    /// See [`Warning::Fallthrough`].
+    Fallthrough,
+
+    /// See [`Warning::KeepMismatch`].
+    KeepMismatch,
+
+    /// See [`Warning::RawOpcode`].
+    RawOpcode,
+}
+
+impl Lint {
+    /// Every kind of warning.
+    pub const ALL: &'static [Lint] = &[
+        Lint::UnusedLabel,
+        Lint::UnreachableCode,
+        Lint::OversizedPush,
+        Lint::StackHeight,
+        Lint::Fallthrough,
+        Lint::KeepMismatch,
+        Lint::RawOpcode,
+    ];
+
+    /// The name of the lint, like `unused-label`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::UnusedLabel => "unused-label",
+            Self::UnreachableCode => "unreachable-code",
+            Self::OversizedPush => "oversized-push",
+            Self::StackHeight => "stack-height",
+            Self::Fallthrough => "fallthrough",
+            Self::KeepMismatch => "keep-mismatch",
+            Self::RawOpcode => "raw-opcode",
+        }
+    }
+
+    /// The lint called `name`, if there is one.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|lint| lint.name() == name)
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnusedLabel { label } => write!(f, "label `{}` is never used", label),
+            Self::UnreachableCode { after, op } => write!(
+                f,
+                "`{}` can never be executed because it follows `{}`",
+                op, after
+            ),
+            Self::OversizedPush {
+                spec,
+                value,
+                minimum,
+            } => write!(
+                f,
+                "`{}` is wider than needed for `{}` (`{}` would suffice)",
+                spec, value, minimum
+            ),
+            Self::StackUnderflow { offset, op, height } => write!(
+                f,
+                "`{}` at 0x{:x} pops {} stack item(s), but only {} may be available",
+                op,
+                offset,
+                op.pops(),
+                height
+            ),
+            Self::StackOverflow { offset, op } => write!(
+                f,
+                "`{}` at 0x{:x} may grow the stack past {} items",
+                op, offset, STACK_LIMIT
+            ),
+            Self::Fallthrough { label } => write!(
+                f,
+                "execution falls through into `{}`, which is also a jump destination \
+                 (add `# fallthrough` before the label if this is intended)",
+                label
+            ),
+            Self::KeepMismatch {
+                offset,
+                name,
+                expected,
+                found: Some(found),
+            } => write!(
+                f,
+                "`{}` should be stack item {} at 0x{:x}, but it's item {}",
+                name, expected, offset, found
+            ),
+            Self::KeepMismatch {
+                offset,
+                name,
+                expected,
+                found: None,
+            } => write!(
+                f,
+                "`{}` should be stack item {} at 0x{:x}, but it's no longer on the stack",
+                name, expected, offset
+            ),
+            Self::RawOpcode { opcode } => {
+                let op = Op::<()>::from(*opcode);
+                if op.mnemonic().starts_with("invalid_") {
+                    write!(
+                        f,
+                        "`op(0x{:02x})` isn't a known instruction, and is assembled without checks",
+                        opcode
+                    )
+                } else {
+                    write!(
+                        f,
+                        "`op(0x{:02x})` is assembled without checks (`{}` is the same instruction)",
+                        opcode, op
+                    )
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lint_names() {
+        for lint in Lint::ALL {
+            assert_eq!(Lint::from_name(lint.name()), Some(*lint));
+        }
+
+        assert_eq!(Lint::from_name("unused"), None);
+        assert_eq!(
+            Warning::UnusedLabel { label: "a".into() }.lint(),
+            Lint::UnusedLabel
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use assert_matches::assert_matches;
 
 use etk_asm::ingest::{Error, Ingest};
+use etk_asm::profile::Profile;
 
 use hex_literal::hex;
 
@@ -293,6 +294,42 @@ fn every_op() -> Result<(), Error> {
     Ok(())
 }
 
+#[test]
+fn every_eof_op() -> Result<(), Error> {
+    let mut output = Vec::new();
+    let mut ingester = Ingest::new(&mut output);
+    ingester.set_profile(Profile::builtin("eof"));
+    ingester.ingest_file(source(&["every-eof-op", "main.etk"]))?;
+
+    assert_eq!(
+        output,
+        hex!(
+            "
+        d0
+        d1 0102
+        d2
+        d3
+
+        e0 0003
+        e1 fffd
+
+        e6 01
+        e7 02
+        e8 12
+        "
+        )
+    );
+
+    let mut output = Vec::new();
+    let mut ingester = Ingest::new(&mut output);
+    let err = ingester
+        .ingest_file(source(&["every-eof-op", "main.etk"]))
+        .unwrap_err();
+    assert_matches!(err, Error::Assemble { .. });
+
+    Ok(())
+}
+
 #[test]
 fn test_variable_sized_push_and_include() -> Result<(), Error> {
     let mut output = Vec::new();
@@ -323,3 +360,25 @@ fn test_variable_sized_push2() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn assemble_str() -> Result<(), Error> {
+    let code = etk_asm::assemble_str(
+        r#"
+        %macro spin()
+            again:
+            jumpdest
+            push1 again
+            jump
+        %end
+        %spin()
+        %spin()
+        "#,
+    )?;
+    assert_eq!(code, hex!("5b600056 5b600456"));
+
+    let err = etk_asm::assemble_str(r#"%import("other.etk")"#).unwrap_err();
+    assert_matches!(err, Error::Io { .. });
+
+    Ok(())
+}
@@ -6,16 +6,22 @@ mod selectors;
 use crate::opts::Opts;
 use crate::selectors::DisplayOp;
 
-use etk_asm::disasm::{Disassembler, Offset};
+use etk_asm::abi::{self, Argument, Constructor};
+use etk_asm::disasm::eof::{self, Container};
+use etk_asm::disasm::{self, Decode, Disassembler, ImmediateFormat, Offset};
+use etk_asm::profile::{self, CodeKind, Profile, Violation};
 
 use etk_cli::errors::WithSources;
 
 use etk_dasm::blocks::basic::Separator;
+use etk_dasm::vectors;
 
 use snafu::{Backtrace, Snafu};
 
+use etk_ops::{prague, Operation};
+
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 
 #[derive(Debug, Snafu)]
 enum Error {
@@ -24,6 +30,32 @@ enum Error {
         source: std::io::Error,
         backtrace: Backtrace,
     },
+
+    #[snafu(context(false))]
+    Eof { source: eof::Error },
+
+    #[snafu(context(false))]
+    Disassemble { source: etk_asm::disasm::Error },
+
+    #[snafu(context(false))]
+    Abi { source: abi::Error },
+
+    #[snafu(context(false))]
+    LoadProfile { source: profile::Error },
+
+    #[snafu(display(
+        "code doesn't fit profile `{}`:\n{}",
+        profile,
+        violations
+            .iter()
+            .map(|v| format!("  {}", v))
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))]
+    ProfileViolation {
+        profile: String,
+        violations: Vec<Violation>,
+    },
 }
 
 fn main() {
@@ -42,26 +74,186 @@ fn run() -> Result<(), Error> {
     let opts: Opts = clap::Parser::parse();
 
     let mut input = opts.src.open()?;
-    let mut disasm = Disassembler::new();
-
-    std::io::copy(&mut input, &mut disasm)?;
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
 
     let mut out: Box<dyn Write> = match opts.out_file {
         Some(path) => Box::new(File::create(path)?),
         None => Box::new(std::io::stdout()),
     };
 
-    let mut separator = Separator::new();
+    let profile = opts.profile.as_deref().map(Profile::load).transpose()?;
+
+    let (code, args) = match opts.constructor_abi {
+        Some(path) => {
+            let json = std::fs::read_to_string(path)?;
+            let constructor = Constructor::from_json(&json)?;
+            let (code, args) = constructor.split(&bytes, opts.code_len)?;
+            (code, Some(constructor.decode(args)?))
+        }
+        None => (bytes.as_slice(), None),
+    };
+
+    let format = ImmediateFormat {
+        uppercase: opts.uppercase,
+        prefix: !opts.no_prefix,
+        group_words: opts.group_words,
+    };
+
+    // Code is read with the Cancun instructions, unless the profile is for
+    // code in an EOF container, which has the instructions of Prague.
+    let prague = profile.as_ref().map(|p| p.eof).unwrap_or_default();
+
+    if opts.test_vectors {
+        write_vectors(&mut out, code)?;
+    } else if prague {
+        let disasm = Disassembler::<prague::Op<[u8]>>::default();
+        write_code(&mut out, disasm, code, opts.json, opts.detect_data, format)?;
+    } else {
+        let disasm = Disassembler::new();
+        write_code(&mut out, disasm, code, opts.json, opts.detect_data, format)?;
+    }
+
+    if let Some(args) = args {
+        write_args(&mut out, code.len(), &args)?;
+    }
+
+    if let Some(profile) = profile {
+        let kind = if opts.initcode {
+            CodeKind::Initcode
+        } else {
+            CodeKind::Runtime
+        };
+
+        let violations = profile.validate(code, kind);
+        if !violations.is_empty() {
+            return ProfileViolationSnafu {
+                profile: profile.name,
+                violations,
+            }
+            .fail();
+        }
+    }
+
+    Ok(())
+}
+
+fn write_code<W, O>(
+    out: &mut W,
+    mut disasm: Disassembler<O>,
+    bytes: &[u8],
+    json: bool,
+    detect_data: bool,
+    format: ImmediateFormat,
+) -> Result<(), Error>
+where
+    W: Write,
+    O: Decode,
+{
+    if eof::is_eof(bytes) {
+        return write_eof(out, bytes, json, format);
+    }
+
+    let end = if detect_data {
+        disasm::data_start(bytes)
+    } else {
+        bytes.len()
+    };
+
+    disasm.set_immediate_format(format);
+    disasm.write_all(&bytes[..end])?;
+
+    if json {
+        disasm.write_json(&mut *out)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    write_blocks(out, disasm.ops(), format)?;
+
+    if end < bytes.len() {
+        writeln!(out, "data:  ; offset 0x{:x}", end)?;
+        writeln!(out, "%bytes(hex(\"{}\"))", hex::encode(&bytes[end..]))?;
+    }
+
+    Ok(())
+}
+
+fn write_vectors<W: Write>(out: &mut W, bytes: &[u8]) -> Result<(), Error> {
+    for function in vectors::functions(bytes) {
+        writeln!(out, "0x{}", hex::encode(function.calldata()))?;
+    }
+
+    Ok(())
+}
+
+fn write_args<W: Write>(out: &mut W, offset: usize, args: &[Argument]) -> Result<(), Error> {
+    writeln!(out, "constructor_args:  ; offset 0x{:x}", offset)?;
+
+    for arg in args {
+        writeln!(out, "{}", arg)?;
+    }
+
+    Ok(())
+}
+
+fn write_eof<W: Write>(
+    out: &mut W,
+    bytes: &[u8],
+    json: bool,
+    format: ImmediateFormat,
+) -> Result<(), Error> {
+    let container = Container::parse(bytes)?;
+
+    if json {
+        container.write_json_with_format(&mut *out, format)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    for section in &container.code {
+        writeln!(
+            out,
+            "{}:  ; offset 0x{:x}, inputs {}, outputs {}, max stack height {}",
+            section.label(),
+            section.offset,
+            section.ty.inputs,
+            section.ty.outputs,
+            section.ty.max_stack_height,
+        )?;
+
+        write_blocks(out, section.ops()?, format)?;
+    }
+
+    for (index, nested) in container.containers.iter().enumerate() {
+        writeln!(out, "container_{}:  ; offset 0x{:x}", index, nested.offset)?;
+        writeln!(out, "{}", hex::encode(&nested.item))?;
+        writeln!(out)?;
+    }
+
+    writeln!(out, "data:  ; offset 0x{:x}", container.data.offset)?;
+    writeln!(out, "{}", hex::encode(&container.data.item))?;
+
+    Ok(())
+}
+
+fn write_blocks<W, I, O>(out: &mut W, ops: I, format: ImmediateFormat) -> Result<(), Error>
+where
+    W: Write,
+    I: IntoIterator<Item = Offset<O>>,
+    O: Operation<ImmediateRef = [u8]>,
+{
+    let mut separator = Separator::default();
 
-    separator.push_all(disasm.ops());
+    separator.push_all(ops);
 
     let basic_blocks = separator.take().into_iter().chain(separator.finish());
 
     for block in basic_blocks {
         let mut offset = block.offset;
         for op in block.ops {
-            let len = op.size();
-            let off = Offset::new(offset, DisplayOp(op));
+            let len = 1 + op.extra_len();
+            let off = Offset::new(offset, DisplayOp(op, format));
             offset += len;
 
             writeln!(out, "{}", off)?;
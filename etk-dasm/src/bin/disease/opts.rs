@@ -15,4 +15,63 @@ pub struct Opts {
         help = "path to output file (defaults to stdout)"
     )]
     pub out_file: Option<PathBuf>,
+
+    #[structopt(long = "json", help = "emit the disassembly as JSON records")]
+    pub json: bool,
+
+    #[structopt(
+        long = "uppercase",
+        help = "write immediates with uppercase hex digits"
+    )]
+    pub uppercase: bool,
+
+    #[structopt(long = "no-prefix", help = "write immediates without a `0x` prefix")]
+    pub no_prefix: bool,
+
+    #[structopt(
+        long = "group-words",
+        help = "separate 32 byte immediates into 4 byte groups"
+    )]
+    pub group_words: bool,
+
+    #[structopt(
+        long = "detect-data",
+        help = "write solc metadata and unreachable bytes at the end of the code as data",
+        conflicts_with = "json"
+    )]
+    pub detect_data: bool,
+
+    #[structopt(
+        long = "test-vectors",
+        help = "write calldata with zeroed arguments for each function in the dispatcher, instead of disassembling",
+        conflicts_with_all = &["json", "detect-data"]
+    )]
+    pub test_vectors: bool,
+
+    #[structopt(
+        long = "constructor-abi",
+        help = "treat the input as creation transaction data, and decode the constructor arguments using this ABI",
+        conflicts_with = "json"
+    )]
+    pub constructor_abi: Option<PathBuf>,
+
+    #[structopt(
+        long = "code-len",
+        help = "length in bytes of the creation code, before the constructor arguments",
+        requires = "constructor-abi"
+    )]
+    pub code_len: Option<usize>,
+
+    #[structopt(
+        long = "profile",
+        help = "check the code against the limits of a chain (a built-in profile or a JSON file)"
+    )]
+    pub profile: Option<String>,
+
+    #[structopt(
+        long = "initcode",
+        help = "check the profile's initcode limits instead",
+        requires = "profile"
+    )]
+    pub initcode: bool,
 }
@@ -1,13 +1,19 @@
 use etk_4byte::reverse_selector;
 
-use etk_ops::cancun::{Op, Operation};
+use etk_asm::disasm::ImmediateFormat;
+
+use etk_ops::cancun::Op;
+use etk_ops::Operation;
 
 use std::fmt;
 
 #[derive(Debug)]
-pub struct DisplayOp(pub Op<[u8]>);
+pub struct DisplayOp<O = Op<[u8]>>(pub O, pub ImmediateFormat);
 
-impl DisplayOp {
+impl<O> DisplayOp<O>
+where
+    O: Operation<ImmediateRef = [u8]>,
+{
     fn reverse_selector(&self) -> Vec<&'static str> {
         self.selector()
             .map(|s| reverse_selector(s).collect())
@@ -32,16 +38,19 @@ impl DisplayOp {
     }
 }
 
-impl fmt::Display for DisplayOp {
+impl<O> fmt::Display for DisplayOp<O>
+where
+    O: Operation<ImmediateRef = [u8]>,
+{
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0.code())?;
+        write!(f, "{}", self.0.mnemonic())?;
 
         let imm = match self.0.immediate() {
             Some(i) => i,
             None => return Ok(()),
         };
 
-        write!(f, " 0x{}", hex::encode(imm))?;
+        write!(f, " {}", self.1.format(imm))?;
 
         let selectors = self.reverse_selector();
 
@@ -80,7 +89,7 @@ mod tests {
         let bin = hex!("b6");
 
         let op = Push1(bin).into();
-        let txt = DisplayOp(op).to_string();
+        let txt = DisplayOp(op, ImmediateFormat::default()).to_string();
 
         assert_eq!(
             txt,
@@ -93,7 +102,7 @@ mod tests {
         let bin = hex!("00000000000000000000000000000000000000000000000000000000000000b6");
 
         let op = Push32(bin).into();
-        let txt = DisplayOp(op).to_string();
+        let txt = DisplayOp(op, ImmediateFormat::default()).to_string();
 
         let expected = concat!(
             "push32 ",
@@ -104,12 +113,26 @@ mod tests {
         assert_eq!(txt, expected);
     }
 
+    #[test]
+    fn format_uppercase_without_prefix() {
+        let op = Push2(hex!("abcd")).into();
+        let format = ImmediateFormat {
+            uppercase: true,
+            prefix: false,
+            ..Default::default()
+        };
+
+        let txt = DisplayOp(op, format).to_string();
+
+        assert!(txt.starts_with("push2 ABCD"), "{}", txt);
+    }
+
     #[test]
     fn format_selector_push1_zero() {
         let bin = hex!("00");
 
         let op = Push1(bin).into();
-        let txt = DisplayOp(op).to_string();
+        let txt = DisplayOp(op, ImmediateFormat::default()).to_string();
 
         let expected = concat!(
             "push1 0x00 # ",
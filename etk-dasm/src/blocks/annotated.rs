@@ -120,6 +120,10 @@ pub struct AnnotatedBlock {
 
     /// Length of this block.
     pub size: usize,
+
+    /// The largest number of items this block adds to the stack at any point
+    /// during its execution, relative to the height of the stack on entry.
+    pub max_stack_growth: usize,
 }
 
 impl AnnotatedBlock {
@@ -134,6 +138,14 @@ impl AnnotatedBlock {
         let mut annotator = Annotator::new(basic);
         let exit = annotator.annotate();
 
+        let depth = annotator.stacks[0].len();
+        let peak = annotator
+            .stacks
+            .iter()
+            .map(VecDeque::len)
+            .max()
+            .unwrap_or(depth);
+
         let mut stacks = annotator.stacks.into_iter();
         let stack_inputs = stacks
             .next()
@@ -154,6 +166,7 @@ impl AnnotatedBlock {
                 stack: stack_inputs,
             },
             exit,
+            max_stack_growth: peak - depth,
         }
     }
 }
@@ -453,6 +466,11 @@ impl<'a> Annotator<'a> {
             Op::ChainId(_) => stack.push(Expr::chain_id()),
             Op::SelfBalance(_) => stack.push(Expr::self_balance()),
             Op::BaseFee(_) => stack.push(Expr::base_fee()),
+            Op::BlobHash(_) => {
+                let index = stack.pop();
+                stack.push(Expr::blob_hash(&index));
+            }
+            Op::BlobBaseFee(_) => stack.push(Expr::blob_base_fee()),
 
             Op::MSize(_) => stack.push(Expr::m_size()),
             Op::Gas(_) => stack.push(Expr::gas()),
@@ -522,6 +540,15 @@ impl<'a> Annotator<'a> {
             Op::JumpDest(_) => {
                 // No-op
             }
+            Op::TLoad(_) => {
+                let addr = stack.pop();
+                stack.push(addr.t_load());
+            }
+            Op::TStore(_) => {
+                let _key = stack.pop();
+                let _value = stack.pop();
+                // TODO: set transient storage
+            }
             Op::MCopy(_) => {
                 let _dest_offset = stack.pop();
                 let _offset = stack.pop();
@@ -812,15 +839,11 @@ impl<'a> Annotator<'a> {
             | Op::Invalid2d(_)
             | Op::Invalid2e(_)
             | Op::Invalid2f(_)
-            | Op::Invalid49(_)
-            | Op::Invalid4a(_)
             | Op::Invalid4b(_)
             | Op::Invalid4c(_)
             | Op::Invalid4d(_)
             | Op::Invalid4e(_)
             | Op::Invalid4f(_)
-            | Op::Invalid5c(_)
-            | Op::Invalid5d(_)
             | Op::InvalidA5(_)
             | Op::InvalidA6(_)
             | Op::InvalidA7(_)
@@ -1039,6 +1062,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn annotate_max_stack_growth() {
+        let basic = BasicBlock {
+            offset: 0,
+            ops: vec![
+                Op::from(Pop),
+                Op::from(Push1([1])),
+                Op::from(Push1([2])),
+                Op::from(Add),
+                Op::from(Stop),
+            ],
+        };
+
+        let block = AnnotatedBlock::annotate(&basic);
+        assert_eq!(block.inputs.stack.len(), 1);
+        assert_eq!(block.max_stack_growth, 1);
+    }
+
     #[test]
     fn annotate_stop() {
         AnnotateTest {
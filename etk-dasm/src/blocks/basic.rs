@@ -1,44 +1,65 @@
 //! A list of EVM instructions with a single point of entry and a single exit.
 use etk_asm::disasm::Offset;
 
-use etk_ops::cancun::{Op, Operation};
+use etk_ops::cancun::Op;
+use etk_ops::Operation;
 
 /// A list of EVM instructions with a single point of entry and a single exit.
+///
+/// Instructions are from the Cancun table, unless another fork's `O` is
+/// chosen.
 #[derive(Debug, Eq, PartialEq)]
-pub struct BasicBlock {
+pub struct BasicBlock<O = Op<[u8]>> {
     /// Position of the first instruction of this block in the entire program.
     pub offset: usize,
 
     /// List of instructions contained in the block.
-    pub ops: Vec<Op<[u8]>>,
+    pub ops: Vec<O>,
 }
 
-impl BasicBlock {
+impl<O> BasicBlock<O>
+where
+    O: Operation,
+{
     /// Sum of the length of every instruction in this block.
     pub fn size(&self) -> usize {
-        self.ops.iter().map(Op::size).sum()
+        self.ops.iter().map(|op| 1 + op.extra_len()).sum()
     }
 }
 
 /// Separate a sequence of [`Op<[u8]>`] into [`BasicBlock`].
-#[derive(Debug, Default)]
-pub struct Separator {
-    complete_blocks: Vec<BasicBlock>,
-    in_progress: Option<BasicBlock>,
+#[derive(Debug)]
+pub struct Separator<O = Op<[u8]>> {
+    complete_blocks: Vec<BasicBlock<O>>,
+    in_progress: Option<BasicBlock<O>>,
+}
+
+impl<O> Default for Separator<O> {
+    fn default() -> Self {
+        Self {
+            complete_blocks: Vec::new(),
+            in_progress: None,
+        }
+    }
 }
 
 impl Separator {
-    /// Create a default instance.
+    /// Create a default instance, separating Cancun instructions.
     pub fn new() -> Self {
         Self::default()
     }
+}
 
+impl<O> Separator<O>
+where
+    O: Operation,
+{
     /// Read instructions from `iter` until it is empty.
     ///
     /// Returns `true` if any [`BasicBlock`] are ready.
     pub fn push_all<I>(&mut self, iter: I) -> bool
     where
-        I: IntoIterator<Item = Offset<Op<[u8]>>>,
+        I: IntoIterator<Item = Offset<O>>,
     {
         let mut available = false;
         for item in iter.into_iter() {
@@ -49,7 +70,7 @@ impl Separator {
 
     /// Push a single instruction, returns `true` if a [`BasicBlock`] has been
     /// completed.
-    pub fn push(&mut self, off: Offset<Op<[u8]>>) -> bool {
+    pub fn push(&mut self, off: Offset<O>) -> bool {
         if off.item.is_jump_target() {
             // If we receive a jumpdest, start a new block beginning with it.
             let completed = self.in_progress.replace(BasicBlock {
@@ -89,14 +110,14 @@ impl Separator {
     }
 
     /// Remove all completed [`BasicBlock`].
-    pub fn take(&mut self) -> Vec<BasicBlock> {
+    pub fn take(&mut self) -> Vec<BasicBlock<O>> {
         std::mem::take(&mut self.complete_blocks)
     }
 
     /// Retrieve the last [`BasicBlock`] after all instructions have been
     /// consumed.
     #[must_use]
-    pub fn finish(&mut self) -> Option<BasicBlock> {
+    pub fn finish(&mut self) -> Option<BasicBlock<O>> {
         if self.complete_blocks.is_empty() {
             self.in_progress.take()
         } else {
@@ -9,3 +9,4 @@
 
 pub mod blocks;
 pub mod sym;
+pub mod vectors;
@@ -169,6 +169,13 @@ impl Expr {
         }
     }
 
+    /// Create an [`Expr`] representing `blobbasefee` (`0x4a`).
+    pub fn blob_base_fee() -> Self {
+        Self {
+            ops: vec![Sym::BlobBaseFee],
+        }
+    }
+
     /// Create an [`Expr`] representing `pc` (`0x58`).
     pub fn pc(offset: u16) -> Self {
         Self {
@@ -397,6 +404,11 @@ impl Expr {
         Self::concat(Sym::BlockHash, &[self])
     }
 
+    /// Create an [`Expr`] representing `blobhash` (`0x49`).
+    pub fn blob_hash(&self) -> Self {
+        Self::concat(Sym::BlobHash, &[self])
+    }
+
     /// Create an [`Expr`] representing `balance` (`0x31`).
     pub fn balance(&self) -> Self {
         Self::concat(Sym::Balance, &[self])
@@ -427,6 +439,11 @@ impl Expr {
         Self::concat(Sym::SLoad, &[self])
     }
 
+    /// Create an [`Expr`] representing `tload` (`0x5c`).
+    pub fn t_load(&self) -> Self {
+        Self::concat(Sym::TLoad, &[self])
+    }
+
     /// If this expression represents a single [`Var`] instance, return it.
     /// Otherwise return `None`.
     pub fn as_var(&self) -> Option<Var> {
@@ -535,6 +552,7 @@ impl<'a, 'b> Visit for DisplayVisit<'a, 'b> {
             Sym::ExtCodeHash => write!(self.0, "extcodehash("),
             Sym::MLoad => write!(self.0, "mload("),
             Sym::SLoad => write!(self.0, "sload("),
+            Sym::TLoad => write!(self.0, "tload("),
             Sym::Address => write!(self.0, "address("),
             Sym::Balance => write!(self.0, "balance("),
             Sym::Origin => write!(self.0, "origin("),
@@ -545,6 +563,7 @@ impl<'a, 'b> Visit for DisplayVisit<'a, 'b> {
             Sym::GasPrice => write!(self.0, "gasprice("),
             Sym::ReturnDataSize => write!(self.0, "returndatasize("),
             Sym::BlockHash => write!(self.0, "blockhash("),
+            Sym::BlobHash => write!(self.0, "blobhash("),
             Sym::Coinbase => write!(self.0, "coinbase("),
             Sym::Timestamp => write!(self.0, "timestamp("),
             Sym::Number => write!(self.0, "number("),
@@ -553,6 +572,7 @@ impl<'a, 'b> Visit for DisplayVisit<'a, 'b> {
             Sym::ChainId => write!(self.0, "chainid("),
             Sym::SelfBalance => write!(self.0, "selfbalance("),
             Sym::BaseFee => write!(self.0, "basefee("),
+            Sym::BlobBaseFee => write!(self.0, "blobbasefee("),
             Sym::GetPc(pc) => write!(self.0, "pc({}", pc),
             Sym::MSize => write!(self.0, "msize("),
             Sym::Gas => write!(self.0, "gas("),
@@ -744,12 +764,18 @@ pub enum Sym {
     /// An `sload` (`0x54`) operation.
     SLoad,
 
+    /// A `tload` (`0x5c`) operation.
+    TLoad,
+
     /// A `balance` (`0x31`) operation.
     Balance,
 
     /// A `blockhash` (`0x40`) operation.
     BlockHash,
 
+    /// A `blobhash` (`0x49`) operation.
+    BlobHash,
+
     /// An `address` (`0x30`) operation.
     Address,
 
@@ -798,6 +824,9 @@ pub enum Sym {
     /// A `basefee` (`0x48`) operation.
     BaseFee,
 
+    /// A `blobbasefee` (`0x4a`) operation.
+    BlobBaseFee,
+
     /// A `pc` (`0x58`) operation.
     GetPc(u16),
 
@@ -858,9 +887,11 @@ impl Sym {
             | Sym::ExtCodeSize
             | Sym::ExtCodeHash
             | Sym::BlockHash
+            | Sym::BlobHash
             | Sym::Balance
             | Sym::MLoad
-            | Sym::SLoad => 1,
+            | Sym::SLoad
+            | Sym::TLoad => 1,
 
             Sym::Address
             | Sym::Origin
@@ -878,6 +909,7 @@ impl Sym {
             | Sym::ChainId
             | Sym::SelfBalance
             | Sym::BaseFee
+            | Sym::BlobBaseFee
             | Sym::GetPc(_)
             | Sym::MSize
             | Sym::Gas
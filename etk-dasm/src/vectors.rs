@@ -0,0 +1,218 @@
+//! Skeleton test vectors for the functions of a contract.
+//!
+//! The functions of a contract are recovered from its dispatcher (see
+//! [`dispatch_entries`]), then the code reachable from each function is
+//! searched for `calldataload` instructions with a constant offset, like
+//! `push1 0x24 calldataload`. The largest offset read gives the number of
+//! 32 byte arguments the function takes, so a call can be built with the
+//! selector followed by that many zeroed arguments, as a starting point for
+//! fuzzing.
+//!
+//! Arguments read in other ways (like with `calldatacopy`, or with offsets
+//! computed at runtime) aren't found, so the vectors are only a skeleton.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use etk_dasm::vectors::functions;
+//! # use hex_literal::hex;
+//!
+//! // push4 0x11111111 dup2 eq push1 0x0c jumpi stop
+//! // jumpdest push1 0x24 calldataload stop
+//! let code = hex!("6311111111 81 14 600c 57 00 00 5b 6024 35 00");
+//! let found = functions(&code);
+//!
+//! assert_eq!(found[0].selector, 0x11111111);
+//! assert_eq!(found[0].arguments(), 2);
+//! assert_eq!(found[0].calldata().len(), 4 + 2 * 32);
+//! ```
+use etk_asm::disasm::{Disassembler, Offset};
+use etk_asm::patch::dispatch_entries;
+
+use etk_ops::cancun::{Op, Operation};
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+/// A function found in the dispatcher of a contract.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Function {
+    /// The selector of the function.
+    pub selector: u32,
+
+    /// Position of the code the dispatcher jumps to for the function.
+    pub entry: usize,
+
+    /// Constant offsets given to `calldataload` by the function, in the order
+    /// they were found, without duplicates.
+    pub loads: Vec<usize>,
+}
+
+impl Function {
+    /// The number of 32 byte arguments the function reads, after the
+    /// selector.
+    pub fn arguments(&self) -> usize {
+        self.loads
+            .iter()
+            .filter(|offset| **offset >= 4)
+            .map(|offset| (offset - 4) / 32 + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Calldata calling the function, with every argument set to zero.
+    pub fn calldata(&self) -> Vec<u8> {
+        let mut calldata = self.selector.to_be_bytes().to_vec();
+        calldata.resize(4 + 32 * self.arguments(), 0);
+        calldata
+    }
+}
+
+/// The value pushed by `op`, if it's a push that fits in a `usize`.
+fn pushed(op: &Op<[u8]>) -> Option<usize> {
+    if !matches!(op.code_byte(), 0x60..=0x7f) {
+        return None;
+    }
+
+    let imm = op.immediate()?;
+    let start = imm.iter().take_while(|b| **b == 0).count();
+    let imm = &imm[start..];
+
+    if imm.len() > std::mem::size_of::<usize>() {
+        return None;
+    }
+
+    Some(imm.iter().fold(0, |acc, b| (acc << 8) | usize::from(*b)))
+}
+
+/// Follow the code starting at `entry`, through jumps with constant
+/// destinations, and collect the constant offsets given to `calldataload`.
+fn loads(ops: &[Offset<Op<[u8]>>], positions: &HashMap<usize, usize>, entry: usize) -> Vec<usize> {
+    let mut loads = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![entry];
+
+    while let Some(start) = pending.pop() {
+        let mut idx = match positions.get(&start) {
+            Some(idx) => *idx,
+            None => continue,
+        };
+
+        while let Some(op) = ops.get(idx) {
+            if !visited.insert(idx) {
+                break;
+            }
+
+            let previous = idx.checked_sub(1).and_then(|i| pushed(&ops[i].item));
+
+            match op.item {
+                Op::CallDataLoad(_) => {
+                    if let Some(offset) = previous {
+                        if !loads.contains(&offset) {
+                            loads.push(offset);
+                        }
+                    }
+                }
+                Op::JumpI(_) => pending.extend(previous),
+                Op::Jump(_) => {
+                    pending.extend(previous);
+                    break;
+                }
+                _ if op.item.is_exit() => break,
+                _ => (),
+            }
+
+            idx += 1;
+        }
+    }
+
+    loads
+}
+
+/// Find the functions in the dispatcher of `code`, and the arguments each
+/// reads.
+///
+/// Selectors compared more than once (for example, by a dispatcher doing a
+/// binary search) are only listed the first time.
+pub fn functions(code: &[u8]) -> Vec<Function> {
+    let mut dasm = Disassembler::new();
+    dasm.write_all(code).unwrap();
+    let ops: Vec<_> = dasm.ops().collect();
+
+    let positions: HashMap<_, _> = ops
+        .iter()
+        .enumerate()
+        .map(|(idx, op)| (op.offset, idx))
+        .collect();
+
+    let mut functions: Vec<Function> = Vec::new();
+
+    for entry in dispatch_entries(code) {
+        if functions.iter().any(|f| f.selector == entry.selector) {
+            continue;
+        }
+
+        functions.push(Function {
+            selector: entry.selector,
+            entry: entry.target,
+            loads: loads(&ops, &positions, entry.target),
+        });
+    }
+
+    functions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hex_literal::hex;
+
+    #[test]
+    fn follows_jumps() {
+        // dup1 push4 0xa9059cbb eq push1 0x15 jumpi
+        // dup1 push4 0x70a08231 eq push1 0x1c jumpi stop
+        // 0x15: jumpdest push1 0x04 calldataload push1 0x21 jump
+        // 0x1c: jumpdest push1 0x04 calldataload stop
+        // 0x21: jumpdest push1 0x24 calldataload stop
+        let code = hex!(
+            "8063a9059cbb14601557 806370a0823114601c57 00"
+            "5b600435602156 5b60043500 5b60243500"
+        );
+
+        let found = functions(&code);
+        assert_eq!(
+            found,
+            [
+                Function {
+                    selector: 0xa9059cbb,
+                    entry: 0x15,
+                    loads: vec![4, 0x24],
+                },
+                Function {
+                    selector: 0x70a08231,
+                    entry: 0x1c,
+                    loads: vec![4],
+                },
+            ]
+        );
+
+        assert_eq!(found[0].arguments(), 2);
+
+        let mut calldata = hex!("70a08231").to_vec();
+        calldata.extend_from_slice(&[0; 32]);
+        assert_eq!(found[1].calldata(), calldata);
+    }
+
+    #[test]
+    fn no_arguments() {
+        let function = Function {
+            selector: 0x12345678,
+            entry: 0,
+            loads: vec![0],
+        };
+
+        assert_eq!(function.arguments(), 0);
+        assert_eq!(function.calldata(), hex!("12345678"));
+    }
+}
@@ -33,6 +33,7 @@ struct Op {
     mnemonic: String,
     pushes: u8,
     pops: u8,
+    gas: u64,
 
     #[serde(default)]
     extra_len: u8,
@@ -45,6 +46,9 @@ struct Op {
 
     #[serde(default)]
     jump_target: bool,
+
+    #[serde(default)]
+    table: bool,
 }
 
 fn read_fork(name: &str) -> Result<[(String, Op); 256], Error> {
@@ -69,9 +73,11 @@ fn read_fork(name: &str) -> Result<[(String, Op); 256], Error> {
                 extra_len: 0,
                 pushes: 0,
                 pops: 0,
+                gas: 0,
                 exits: true,
                 jump: false,
                 jump_target: false,
+                table: false,
             };
             (name, op)
         })
@@ -97,62 +103,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
     let ops = read_fork(fork_name)?;
 
     let mut tokens = quote! {
-        /// Trait for types that represent an EVM instruction.
-        pub trait Operation {
-            /// The return type of [`Operation::code`].
-            type Code: Operation<Code = Self::Code> + Into<u8>;
-
-            /// The return root type of [`Operation::immediate_mut`] and
-            /// [`Operation::immediate`].
-            type ImmediateRef: ?Sized;
-
-            /// The type of the immediate argument for this operation.
-            type Immediate:
-                std::borrow::Borrow<Self::ImmediateRef> + std::borrow::BorrowMut<Self::ImmediateRef>;
-
-            /// Get a shared reference to the immediate argument of this operation,
-            /// if one exists.
-            fn immediate(&self) -> Option<&Self::ImmediateRef>;
-
-            /// Get a mutable reference to the immediate argument of this operation,
-            /// if one exists.
-            fn immediate_mut(&mut self) -> Option<&mut Self::ImmediateRef>;
-
-            /// Consume this operation and return its immediate argument, if one
-            /// exists.
-            fn into_immediate(self) -> Option<Self::Immediate>;
-
-            /// Length of immediate argument.
-            fn extra_len(&self) -> usize;
-
-            /// The action (opcode) of this operation, without any immediates.
-            fn code(&self) -> Self::Code;
-
-            /// The byte (opcode) that indicates this operation.
-            fn code_byte(&self) -> u8 {
-                self.code().into()
-            }
-
-            /// Human-readable name for this operation.
-            fn mnemonic(&self) -> &str;
-
-            /// Returns true if the current instruction changes the program counter (other
-            /// than incrementing it.)
-            fn is_jump(&self) -> bool;
-
-            /// Returns true if the current instruction is a valid destination for jumps.
-            fn is_jump_target(&self) -> bool;
-
-            /// Returns true if the current instruction causes the EVM to stop executing
-            /// the contract.
-            fn is_exit(&self) -> bool;
-
-            /// How many stack elements this instruction pops.
-            fn pops(&self) -> usize;
-
-            /// How many stack elements this instruction pushes.
-            fn pushes(&self) -> usize;
-        }
+        pub use super::Operation;
     };
 
     let mut code_matches = quote! {};
@@ -166,6 +117,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
     let mut immediate_matches = quote! {};
     let mut immediate_mut_matches = quote! {};
     let mut into_immediate_matches = quote! {};
+    let mut with_matches = quote! {};
     let names: Vec<_> = ops.iter().map(|(n, _)| format_ident!("{}", n)).collect();
 
     for (name, op) in &ops {
@@ -177,6 +129,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
         let jump_target = op.jump_target;
         let pops = op.pops;
         let pushes = op.pushes;
+        let gas = op.gas;
         let exit = op.exits;
 
         let generics;
@@ -191,8 +144,80 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
         let code_type;
         let code_impl;
         let from_impl;
+        let extra_len_impl;
+
+        if op.table {
+            immediate_type = quote! { I };
+            generics = quote! { <#immediate_type> };
+            where_clause = quote! { where #immediate_type: super::Table };
+            variant_generics = quote! { <T::Table> };
+            code_generics = quote! { <()> };
+
+            struct_ = quote! {
+                pub struct #name #generics (
+                    #[doc = "The immediate argument for this operation."]
+                    pub #immediate_type
+                ) #where_clause;
+            };
+
+            immediate = quote! { Some(&self.0) };
+            immediate_mut = quote! { Some(&mut self.0) };
+            immediate_into = quote! { Some(self.0) };
+            code_type = quote! { #name<()> };
+            code_impl = quote! { #name(()) };
+            extra_len_impl = quote! { super::Table::size(&self.0) };
+
+            from_impl = quote! {
+                impl <#immediate_type, T> From<#name<#immediate_type>> for Op<T> where
+                    #immediate_type: super::Table,
+                    T: ?Sized + super::Immediates<Table=#immediate_type>,
+                {
+                    fn from(op: #name<#immediate_type>) -> Self {
+                        Self::#name(op)
+                    }
+                }
+            };
 
-        if extra_len > 0 {
+            code_matches.extend(quote! {
+                Self::#name(_) => Op::#name(#name(())),
+            });
+
+            display_matches.extend(quote! {
+                Self::#name(v) => v.mnemonic(),
+            });
+
+            new_matches.extend(quote! {
+                Op::#name(_) => None,
+            });
+
+            from_u8_matches.extend(quote! {
+                #code => Self::#name(#name(())),
+            });
+
+            from_str_matches.extend(quote! {
+                #mnemonic => Self::#name(#name(())),
+            });
+
+            immediate_matches.extend(quote! {
+                Self::#name(v) => v.immediate().map(core::borrow::Borrow::borrow),
+            });
+
+            immediate_mut_matches.extend(quote! {
+                Self::#name(v) => v.immediate_mut().map(core::borrow::BorrowMut::borrow_mut),
+            });
+
+            into_immediate_matches.extend(quote! {
+                Self::#name(v) => v.into_immediate().map(Into::into),
+            });
+
+            from_slice_matches.extend(quote! {
+                #code => Self::#name(#name(bytes[1..].into())),
+            });
+
+            with_matches.extend(quote! {
+                Self::#name(_) => panic!("operations with a table can't be combined with an immediate"),
+            });
+        } else if extra_len > 0 {
             immediate_type = quote! { I };
             generics = quote! { <#immediate_type> };
             where_clause = quote! { where #immediate_type: super::Immediate<#extra_len> };
@@ -213,6 +238,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
             immediate_into = quote! { Some(self.0) };
             code_type = quote! { #name<()> };
             code_impl = quote! { #name(()) };
+            extra_len_impl = quote! { #extra_len };
 
             from_impl = quote! {
                 impl <#immediate_type, T> From<#name<#immediate_type>> for Op<T> where
@@ -246,11 +272,11 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
             });
 
             immediate_matches.extend(quote! {
-                Self::#name(v) => v.immediate().map(std::borrow::Borrow::borrow),
+                Self::#name(v) => v.immediate().map(core::borrow::Borrow::borrow),
             });
 
             immediate_mut_matches.extend(quote! {
-                Self::#name(v) => v.immediate_mut().map(std::borrow::BorrowMut::borrow_mut),
+                Self::#name(v) => v.immediate_mut().map(core::borrow::BorrowMut::borrow_mut),
             });
 
             into_immediate_matches.extend(quote! {
@@ -260,6 +286,10 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
             from_slice_matches.extend(quote! {
                 #code => Self::#name(#name(bytes[1..].try_into()?)),
             });
+
+            with_matches.extend(quote! {
+                Self::#name(_) => Op::#name(#name(immediate.try_into()?)),
+            });
         } else {
             where_clause = quote! {};
             generics = quote! {};
@@ -272,6 +302,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
             code_generics = quote! {};
             code_type = quote! { Self };
             code_impl = quote! { #name };
+            extra_len_impl = quote! { 0 };
 
             from_impl = quote! {
                 impl<T> From<#name> for Op<T> where
@@ -340,7 +371,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
 
                 fn code(&self) -> Self::Code { #code_impl }
 
-                fn extra_len(&self) -> usize { #extra_len }
+                fn extra_len(&self) -> usize { #extra_len_impl }
 
                 fn mnemonic(&self) -> &str { #mnemonic }
 
@@ -349,6 +380,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
                 fn is_exit(&self) -> bool { #exit }
                 fn pops(&self) -> usize { #pops as usize }
                 fn pushes(&self) -> usize { #pushes as usize}
+                fn gas(&self) -> u64 { #gas }
             }
 
             impl From<#name #code_generics> for u8 {
@@ -379,7 +411,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
         let ident = format_ident!("P{}", ii);
 
         debug_bound.extend(quote! {
-            T::#ident: std::fmt::Debug,
+            T::#ident: core::fmt::Debug,
         });
 
         clone_bound.extend(quote! {
@@ -387,28 +419,45 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
         });
 
         partial_eq_bound.extend(quote! {
-            T::#ident: std::cmp::PartialEq,
+            T::#ident: core::cmp::PartialEq,
         });
 
         eq_bound.extend(quote! {
-            T::#ident: std::cmp::Eq,
+            T::#ident: core::cmp::Eq,
         });
 
         ord_bound.extend(quote! {
-            T::#ident: std::cmp::Ord,
+            T::#ident: core::cmp::Ord,
         });
 
         partial_ord_bound.extend(quote! {
-            T::#ident: std::cmp::PartialOrd,
+            T::#ident: core::cmp::PartialOrd,
         });
 
         hash_bound.extend(quote! {
-            T::#ident: std::hash::Hash,
+            T::#ident: core::hash::Hash,
         });
 
         bounds.push(quote! { #ident });
     }
 
+    // Only forks with an instruction that has a table, like `rjumpv`, need
+    // bounds on the table's type, so the others stay `Copy`.
+    let mut table_bounds = quote! {};
+    let mut copy_bounds = bounds.clone();
+
+    if ops.iter().any(|(_, op)| op.table) {
+        debug_bound.extend(quote! { T::Table: core::fmt::Debug, });
+        clone_bound.extend(quote! { T::Table: Clone, });
+        partial_eq_bound.extend(quote! { T::Table: core::cmp::PartialEq, });
+        eq_bound.extend(quote! { T::Table: core::cmp::Eq, });
+        ord_bound.extend(quote! { T::Table: core::cmp::Ord, });
+        partial_ord_bound.extend(quote! { T::Table: core::cmp::PartialOrd, });
+        hash_bound.extend(quote! { T::Table: core::hash::Hash, });
+        table_bounds = quote! { for <'a> &'a [u8]: Into<T::Table>, };
+        copy_bounds.push(quote! { Table });
+    }
+
     let debug_bound = debug_bound.to_string();
     let clone_bound = clone_bound.to_string();
     let partial_eq_bound = partial_eq_bound.to_string();
@@ -437,7 +486,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
         impl<T> Copy for Op<T>
         where
             T: super::Immediates + ?Sized,
-            #(T::#bounds: Copy,)*
+            #(T::#copy_bounds: Copy,)*
         {
         }
 
@@ -527,6 +576,14 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
                     )*
                 }
             }
+
+            fn gas(&self) -> u64 {
+                match self {
+                    #(
+                    Self::#names(n) => n.gas(),
+                    )*
+                }
+            }
         }
 
         impl From<Op<()>> for u8 {
@@ -570,15 +627,17 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
 
         impl<T, E> Op<T> where
             T: super::Immediates + ?Sized,
-            E: 'static + std::fmt::Display + std::error::Error,
+            E: 'static + core::fmt::Display + snafu::Error,
             #( for <'a> &'a [u8]: TryInto<T::#bounds, Error = E>,)*
+            #table_bounds
         {
             /// Parse a byte slice into an `Op`, with its immediate.
             ///
             /// Returns an error if [`TryInto::try_into`] fails, or if the byte
             /// slice contains an immediate for an opcode that does not take one.
             /// Errors usually occur when the byte slice is the wrong length for
-            /// the given instruction.
+            /// the given instruction. An instruction with a table, like `rjumpv`,
+            /// takes the rest of the slice as its table.
             pub fn from_slice(bytes: &[u8]) -> Result<Self, super::FromSliceError<E>> {
                 let result = match bytes[0] {
                     #from_slice_matches
@@ -590,8 +649,8 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
             }
         }
 
-        impl std::fmt::Display for Op<()> {
-            fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        impl core::fmt::Display for Op<()> {
+            fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
                 let mnemonic = match self {
                     #display_matches
                 };
@@ -608,7 +667,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
             }
         }
 
-        impl std::str::FromStr for Op<()> {
+        impl core::str::FromStr for Op<()> {
             type Err = super::FromStrError;
 
             fn from_str(mnemonic: &str) -> Result<Self, Self::Err> {
@@ -625,7 +684,7 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
             /// Create the smallest push instruction capable of representing `n`.
             pub fn push_for(n: u128) -> Option<Self> {
                 let bits = 0u128.leading_zeros() - n.leading_zeros();
-                let bytes = std::cmp::max(1, (bits + 8 - 1) / 8);
+                let bytes = core::cmp::max(1, (bits + 8 - 1) / 8);
                 Self::push(bytes.try_into().unwrap())
             }
 
@@ -673,48 +732,17 @@ fn generate_fork(fork_name: &str) -> Result<(), Error> {
 
             /// Join this opcode with an immediate argument.
             ///
-            /// Panics if this opcode does not take an immediate argument. See
-            /// [`Op::new`].
+            /// Panics if this opcode does not take an immediate argument, or
+            /// takes a table, like `rjumpv`. See [`Op::new`] and
+            /// [`Op::from_slice`].
             pub fn with<T, I, E>(self, immediate: I) -> Result<Op<T>, E>
             where
                 T: ?Sized + super::Immediates,
                 #(I: TryInto<T::#bounds, Error = E>,)*
             {
-                // TODO: Automate generating these?
                 let result = match self {
-                    Self::Push1(_) => Op::Push1(Push1(immediate.try_into()?)),
-                    Self::Push2(_) => Op::Push2(Push2(immediate.try_into()?)),
-                    Self::Push3(_) => Op::Push3(Push3(immediate.try_into()?)),
-                    Self::Push4(_) => Op::Push4(Push4(immediate.try_into()?)),
-                    Self::Push5(_) => Op::Push5(Push5(immediate.try_into()?)),
-                    Self::Push6(_) => Op::Push6(Push6(immediate.try_into()?)),
-                    Self::Push7(_) => Op::Push7(Push7(immediate.try_into()?)),
-                    Self::Push8(_) => Op::Push8(Push8(immediate.try_into()?)),
-                    Self::Push9(_) => Op::Push9(Push9(immediate.try_into()?)),
-                    Self::Push10(_) => Op::Push10(Push10(immediate.try_into()?)),
-                    Self::Push11(_) => Op::Push11(Push11(immediate.try_into()?)),
-                    Self::Push12(_) => Op::Push12(Push12(immediate.try_into()?)),
-                    Self::Push13(_) => Op::Push13(Push13(immediate.try_into()?)),
-                    Self::Push14(_) => Op::Push14(Push14(immediate.try_into()?)),
-                    Self::Push15(_) => Op::Push15(Push15(immediate.try_into()?)),
-                    Self::Push16(_) => Op::Push16(Push16(immediate.try_into()?)),
-                    Self::Push17(_) => Op::Push17(Push17(immediate.try_into()?)),
-                    Self::Push18(_) => Op::Push18(Push18(immediate.try_into()?)),
-                    Self::Push19(_) => Op::Push19(Push19(immediate.try_into()?)),
-                    Self::Push20(_) => Op::Push20(Push20(immediate.try_into()?)),
-                    Self::Push21(_) => Op::Push21(Push21(immediate.try_into()?)),
-                    Self::Push22(_) => Op::Push22(Push22(immediate.try_into()?)),
-                    Self::Push23(_) => Op::Push23(Push23(immediate.try_into()?)),
-                    Self::Push24(_) => Op::Push24(Push24(immediate.try_into()?)),
-                    Self::Push25(_) => Op::Push25(Push25(immediate.try_into()?)),
-                    Self::Push26(_) => Op::Push26(Push26(immediate.try_into()?)),
-                    Self::Push27(_) => Op::Push27(Push27(immediate.try_into()?)),
-                    Self::Push28(_) => Op::Push28(Push28(immediate.try_into()?)),
-                    Self::Push29(_) => Op::Push29(Push29(immediate.try_into()?)),
-                    Self::Push30(_) => Op::Push30(Push30(immediate.try_into()?)),
-                    Self::Push31(_) => Op::Push31(Push31(immediate.try_into()?)),
-                    Self::Push32(_) => Op::Push32(Push32(immediate.try_into()?)),
-                    _ => panic!("only push operations can be combined"),
+                    #with_matches
+                    _ => panic!("only operations with an immediate can be combined"),
                 };
 
                 Ok(result)
@@ -852,4 +880,5 @@ fn main() {
     generate_fork("london").unwrap();
     generate_fork("shanghai").unwrap();
     generate_fork("cancun").unwrap();
+    generate_fork("prague").unwrap();
 }
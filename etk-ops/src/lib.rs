@@ -5,14 +5,91 @@
 //!
 //! This crate defines Rust types for all the instructions in the Ethereum
 //! Virtual Machine (EVM.)
+//!
+//! ## `no_std`
+//!
+//! Disabling the default `std` feature builds this crate with only `core` and
+//! `alloc`, for embedded tooling and circuit generators. Errors then implement
+//! [`snafu::Error`] instead of `std::error::Error`, and never capture
+//! backtraces.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![deny(unsafe_code)]
 #![deny(missing_docs)]
 #![deny(unreachable_pub)]
 #![deny(missing_debug_implementations)]
 
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use core::borrow::{Borrow, BorrowMut};
+
 use snafu::{Backtrace, Snafu};
 
-use std::borrow::{Borrow, BorrowMut};
+/// Trait for types that represent an EVM instruction.
+///
+/// Shared by the instructions of every fork, so code can work with more than
+/// one fork, like a disassembler choosing the fork at runtime.
+pub trait Operation {
+    /// The return type of [`Operation::code`].
+    type Code: Operation<Code = Self::Code> + Into<u8>;
+
+    /// The return root type of [`Operation::immediate_mut`] and
+    /// [`Operation::immediate`].
+    type ImmediateRef: ?Sized;
+
+    /// The type of the immediate argument for this operation.
+    type Immediate: Borrow<Self::ImmediateRef> + BorrowMut<Self::ImmediateRef>;
+
+    /// Get a shared reference to the immediate argument of this operation,
+    /// if one exists.
+    fn immediate(&self) -> Option<&Self::ImmediateRef>;
+
+    /// Get a mutable reference to the immediate argument of this operation,
+    /// if one exists.
+    fn immediate_mut(&mut self) -> Option<&mut Self::ImmediateRef>;
+
+    /// Consume this operation and return its immediate argument, if one
+    /// exists.
+    fn into_immediate(self) -> Option<Self::Immediate>;
+
+    /// Length of immediate argument.
+    fn extra_len(&self) -> usize;
+
+    /// The action (opcode) of this operation, without any immediates.
+    fn code(&self) -> Self::Code;
+
+    /// The byte (opcode) that indicates this operation.
+    fn code_byte(&self) -> u8 {
+        self.code().into()
+    }
+
+    /// Human-readable name for this operation.
+    fn mnemonic(&self) -> &str;
+
+    /// Returns true if the current instruction changes the program counter (other
+    /// than incrementing it.)
+    fn is_jump(&self) -> bool;
+
+    /// Returns true if the current instruction is a valid destination for jumps.
+    fn is_jump_target(&self) -> bool;
+
+    /// Returns true if the current instruction causes the EVM to stop executing
+    /// the contract.
+    fn is_exit(&self) -> bool;
+
+    /// How many stack elements this instruction pops.
+    fn pops(&self) -> usize;
+
+    /// How many stack elements this instruction pushes.
+    fn pushes(&self) -> usize;
+
+    /// The static gas cost of this instruction: the part that doesn't
+    /// depend on its operands or on the state. Memory expansion, cold
+    /// accesses, copied words, and refunds aren't included.
+    fn gas(&self) -> u64;
+}
 
 pub mod london {
     //! Instructions available in the London hard fork.
@@ -29,6 +106,25 @@ pub mod cancun {
     include!(concat!(env!("OUT_DIR"), "/cancun.rs"));
 }
 
+pub mod prague {
+    //! Instructions available in the Prague hard fork.
+    //!
+    //! Adds the instructions of the EVM Object Format (EOF): `dataload`,
+    //! `dataloadn`, `datasize`, and `datacopy` ([EIP-7480]), the relative jumps
+    //! `rjump` and `rjumpi` ([EIP-4200]), and `dupn`, `swapn`, and `exchange`
+    //! ([EIP-663]). The immediate of a relative jump is a signed 16-bit offset
+    //! from the end of the instruction.
+    //!
+    //! The immediate of `rjumpv` is a table: its largest index, followed by a
+    //! two byte offset for every index, so its length depends on its first
+    //! byte. See [`Table`](super::Table).
+    //!
+    //! [EIP-663]: https://eips.ethereum.org/EIPS/eip-663
+    //! [EIP-4200]: https://eips.ethereum.org/EIPS/eip-4200
+    //! [EIP-7480]: https://eips.ethereum.org/EIPS/eip-7480
+    include!(concat!(env!("OUT_DIR"), "/prague.rs"));
+}
+
 /// Error that can occur when parsing an operation from a string.
 #[derive(Debug, Snafu)]
 pub struct FromStrError {
@@ -40,7 +136,7 @@ pub struct FromStrError {
 #[derive(Debug, Snafu)]
 pub enum FromSliceError<E>
 where
-    E: 'static + std::fmt::Display + std::error::Error,
+    E: 'static + core::fmt::Display + snafu::Error,
 {
     /// Converting the byte slice into an immediate failed.
     ///
@@ -74,6 +170,33 @@ pub enum Void {}
 
 impl<const N: usize> Immediate<N> for Void {}
 
+/// Trait for types that contain a variable-length immediate argument, like the
+/// table of `rjumpv`: its largest index, followed by a two byte offset for
+/// every index.
+pub trait Table {
+    /// Length of the immediate argument, in bytes.
+    fn size(&self) -> usize;
+}
+
+impl Table for Vec<u8> {
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Without a table, the length of the smallest one, with a single offset.
+impl Table for () {
+    fn size(&self) -> usize {
+        3
+    }
+}
+
+impl Table for Void {
+    fn size(&self) -> usize {
+        match *self {}
+    }
+}
+
 /// Trait for describing the types of immediate arguments for operation enums.
 pub trait Immediates {
     /// A reference type common to all immediate types ([`Self::P1`], [`Self::P2`], ...)
@@ -280,6 +403,12 @@ pub trait Immediates {
         + Borrow<Self::ImmediateRef>
         + BorrowMut<Self::ImmediateRef>
         + Into<Self::Immediate>;
+
+    /// The type of variable-length immediates, used by `rjumpv`.
+    type Table: Table
+        + Borrow<Self::ImmediateRef>
+        + BorrowMut<Self::ImmediateRef>
+        + Into<Self::Immediate>;
 }
 
 impl Immediates for () {
@@ -318,6 +447,8 @@ impl Immediates for () {
     type P30 = ();
     type P31 = ();
     type P32 = ();
+
+    type Table = ();
 }
 
 impl Immediates for [u8] {
@@ -356,4 +487,6 @@ impl Immediates for [u8] {
     type P30 = [u8; 30];
     type P31 = [u8; 31];
     type P32 = [u8; 32];
+
+    type Table = Vec<u8>;
 }